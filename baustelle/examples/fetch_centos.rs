@@ -26,7 +26,7 @@ async fn main() {
     info!("Fetching a centos image");
     let current_dir = std::env::current_dir().unwrap();
     let storage = TestStorage::new(current_dir).unwrap();
-    let builder = Builder::new("amd64".into(), vec!["linux".into()], storage)
+    let builder = Builder::new("amd64".into(), vec!["linux".into()], &storage)
         .expect("Failed to build the image builder");
 
     builder
@@ -1,43 +1,67 @@
 pub mod entry;
 pub mod resource;
+pub mod writer;
 
-use std::ffi::OsString;
-use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::path::Path;
 
 use anyhow::{Error, Result};
+use registratur::v2::domain::media_type::Compression;
 
 use resource::ArchiveResource;
-
-pub struct Archive<'a> {
-    content: &'a [u8],
+pub use resource::ExtractFlags;
+pub use writer::ArchiveWriter;
+
+/// Tarball, read in a single pass. Content is decompressed
+/// as it's read, so it isn't held in memory.
+pub struct Archive<R: Read> {
+    content: R,
+    compression: Compression,
+    flags: ExtractFlags,
 }
 
-impl<'a> Archive<'a> {
-    pub fn new(content: &'a [u8]) -> Self {
-        Self { content }
+impl<R: Read> Archive<R> {
+    pub fn new(content: R, compression: Compression) -> Self {
+        Self {
+            content,
+            compression,
+            flags: ExtractFlags::default(),
+        }
+    }
+
+    /// Overrides what's restored on extraction, see
+    /// [`ExtractFlags::default`].
+    pub fn with_flags(self, flags: ExtractFlags) -> Self {
+        Self { flags, ..self }
     }
 
+    /// Paths of the entries, extraction doesn't need them
+    /// ahead.
+    #[cfg(test)]
     #[fehler::throws]
-    pub fn entries(&self) -> impl Iterator<Item = Result<PathBuf>> {
+    pub fn entries(self) -> impl Iterator<Item = Result<std::path::PathBuf>> {
         self.resource()?.map_entries(|entry, _| {
-            let os_string: OsString = entry.pathname().into();
+            let os_string: std::ffi::OsString = entry.pathname().into();
 
             os_string.into()
         })?
     }
 
+    /// Extracts entries to `path`, except for the ones
+    /// `ignore` returns `true` for. `ignore` is called with
+    /// entry paths prefixed by `path`, in archive order.
     #[fehler::throws]
     pub fn extract(
-        &self,
+        self,
         path: impl AsRef<Path>,
-        ignore: impl Fn(String) -> bool,
+        ignore: impl FnMut(String) -> Result<bool>,
     ) {
         self.resource()?.extract(path, ignore)?;
     }
 
     #[fehler::throws]
-    fn resource(&self) -> ArchiveResource {
-        ArchiveResource::new(&self.content)?
+    fn resource(self) -> ArchiveResource<R> {
+        ArchiveResource::new(self.content, self.compression, self.flags)?
     }
 }
 
@@ -49,7 +73,7 @@ mod tests {
     fn test_list_content() {
         let content = test_helpers::bytes_fixture!("foo.tar.gz");
 
-        let archive = Archive::new(content);
+        let archive = Archive::new(content, Compression::Gzip);
         let expected = test_helpers::code_fixture!("foo_archive_entries");
 
         let actual = archive
@@ -65,12 +89,12 @@ mod tests {
     fn test_extract() {
         let content = test_helpers::bytes_fixture!("foo.tar.gz");
 
-        let archive = Archive::new(content);
+        let archive = Archive::new(content, Compression::Gzip);
         let dir =
             tempfile::tempdir().expect("failed to create a tmp directory");
 
         archive
-            .extract(dir.path(), |_| false)
+            .extract(dir.path(), |_| Ok(false))
             .expect("failed to extract archive");
 
         let link = std::fs::read_link(dir.path().join("foo/bis"))
@@ -78,4 +102,109 @@ mod tests {
 
         assert_eq!("bad/bad", link.to_string_lossy());
     }
+
+    #[test]
+    fn test_extract_metadata() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let content = test_helpers::bytes_fixture!("fidelity.tar.gz");
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+
+        Archive::new(content, Compression::Gzip)
+            .extract(dir.path(), |_| Ok(false))
+            .expect("failed to extract archive");
+
+        let ping = std::fs::metadata(dir.path().join("bin/ping")).unwrap();
+        let ping6 = std::fs::metadata(dir.path().join("bin/ping6")).unwrap();
+        let bin = std::fs::metadata(dir.path().join("bin")).unwrap();
+
+        assert_eq!(ping.ino(), ping6.ino());
+        assert_eq!(ping.mtime(), 1_590_000_000);
+        assert_eq!(bin.mtime(), 1_590_000_000);
+        assert_eq!(bin.permissions().mode() & 0o7777, 0o755);
+
+        // Setuid bit requires the ownership to be restored
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!(ping.uid(), 0);
+            assert_eq!(ping.permissions().mode() & 0o7777, 0o4755);
+        }
+    }
+
+    #[test]
+    fn test_extract_zstd() {
+        let content = test_helpers::bytes_fixture!("foo.tar.zst");
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+
+        Archive::new(content, Compression::Zstd)
+            .extract(dir.path(), |_| Ok(false))
+            .expect("failed to extract archive");
+
+        assert!(dir.path().join("foo/bis").symlink_metadata().is_ok());
+    }
+
+    #[test]
+    fn test_compression_mismatch() {
+        let content = test_helpers::bytes_fixture!("foo.tar.gz");
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+
+        let result = Archive::new(content, Compression::Zstd)
+            .extract(dir.path(), |_| Ok(false));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_write() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let extracted =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+
+        std::fs::create_dir(dir.path().join("etc")).unwrap();
+        std::fs::write(dir.path().join("etc/motd"), "Welcome").unwrap();
+
+        let mut content = vec![];
+        let mut writer = ArchiveWriter::new(&mut content).unwrap();
+
+        for name in &["etc", "etc/motd"] {
+            writer
+                .append(&dir.path().join(name), Path::new(name))
+                .unwrap();
+        }
+
+        writer.append_whiteout(Path::new("etc/hosts")).unwrap();
+        writer.finish().expect("failed to write archive");
+
+        Archive::new(&content[..], Compression::None)
+            .extract(extracted.path(), |_| Ok(false))
+            .expect("failed to extract archive");
+
+        let motd = extracted.path().join("etc/motd");
+        let whiteout = extracted.path().join("etc/.wh.hosts");
+
+        assert_eq!(std::fs::read_to_string(motd).unwrap(), "Welcome");
+        assert_eq!(std::fs::metadata(whiteout).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_read_error() {
+        struct Failing;
+
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(std::io::ErrorKind::Other, "boom"))
+            }
+        }
+
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let error = Archive::new(Failing, Compression::None)
+            .extract(dir.path(), |_| Ok(false))
+            .unwrap_err();
+
+        assert!(error.to_string().contains("boom"));
+    }
 }
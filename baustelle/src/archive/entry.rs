@@ -11,6 +11,11 @@ extern "C" {
         entry: *const c_void,
         pathname: *const c_char,
     );
+    fn archive_entry_hardlink(entry: *const c_void) -> *const c_char;
+    fn archive_entry_set_hardlink(
+        entry: *const c_void,
+        hardlink: *const c_char,
+    );
 }
 
 pub struct ArchiveEntry;
@@ -24,14 +29,24 @@ impl ArchiveEntry {
         }
     }
 
+    /// Target of the hard link, if the entry is one.
+    pub fn hardlink(&self) -> Option<String> {
+        unsafe {
+            let string = archive_entry_hardlink(self as *const _ as _);
+
+            if string.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(string).to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// Prefixes the pathname, as well as the hard link
+    /// target, which is relative to the archive root too.
     #[fehler::throws]
     pub fn set_pathname(&self, path: impl AsRef<Path>) {
-        let pathname = path
-            .as_ref()
-            .join(self.pathname())
-            .into_os_string()
-            .into_string()
-            .map_err(|err| anyhow!("Couldn't convert {:?} to string", err))?;
+        let pathname = prefixed(&path, &self.pathname())?;
 
         unsafe {
             let pathname_raw = CString::from_vec_unchecked(pathname.into());
@@ -41,5 +56,25 @@ impl ArchiveEntry {
                 pathname_raw.into_raw(),
             );
         }
+
+        if let Some(hardlink) = self.hardlink() {
+            let hardlink = CString::new(prefixed(&path, &hardlink)?)?;
+
+            unsafe {
+                archive_entry_set_hardlink(
+                    self as *const _ as _,
+                    hardlink.as_ptr(),
+                );
+            }
+        }
     }
 }
+
+#[fehler::throws]
+fn prefixed(path: impl AsRef<Path>, name: &str) -> String {
+    path.as_ref()
+        .join(name)
+        .into_os_string()
+        .into_string()
+        .map_err(|err| anyhow!("Couldn't convert {:?} to string", err))?
+}
@@ -1,26 +1,44 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::ops::BitOr;
 use std::path::Path;
 
 use anyhow::{anyhow, Error, Result};
 use itertools::unfold;
-use libc::{c_char, c_int, c_void, size_t};
+use libc::{c_char, c_int, c_void, size_t, ssize_t};
+use registratur::v2::domain::media_type::Compression;
 
 use super::entry::ArchiveEntry;
 
 const ARCHIVE_EOF: c_int = 1;
 const ARCHIVE_OK: c_int = 0;
+const ARCHIVE_WARN: c_int = -20;
+pub(super) const ARCHIVE_FATAL: ssize_t = -30;
+/// Size of chunks the content is read by.
+pub(super) const BLOCK_SIZE: usize = 64 * 1024;
+
+type ReadCallback = extern "C" fn(
+    archive: *const c_void,
+    client_data: *mut c_void,
+    buffer: *mut *const c_void,
+) -> ssize_t;
 
 #[link(name = "archive")]
 extern "C" {
     fn archive_read_new() -> *const c_void;
     fn archive_read_close(archive: *const c_void);
     fn archive_read_free(archive: *const c_void);
+    fn archive_read_support_filter_none(archive: *const c_void);
     fn archive_read_support_filter_gzip(archive: *const c_void);
+    fn archive_read_support_filter_zstd(archive: *const c_void);
+    fn archive_read_support_filter_xz(archive: *const c_void);
     fn archive_read_support_format_tar(archive: *const c_void);
-    fn archive_read_open_memory(
+    fn archive_read_open(
         archive: *const c_void,
-        buffer: *const c_void,
-        size: size_t,
+        client_data: *mut c_void,
+        open_callback: *const c_void,
+        read_callback: ReadCallback,
+        close_callback: *const c_void,
     ) -> c_int;
     fn archive_read_next_header(
         archive: *const c_void,
@@ -36,7 +54,11 @@ extern "C" {
     fn archive_write_disk_new() -> *const c_void;
     fn archive_write_disk_set_standard_lookup(archive: *const c_void)
         -> c_int;
-    fn archive_write_close(archive: *const c_void);
+    fn archive_write_disk_set_options(
+        archive: *const c_void,
+        flags: c_int,
+    ) -> c_int;
+    fn archive_write_close(archive: *const c_void) -> c_int;
     fn archive_write_free(archive: *const c_void);
     fn archive_write_header(
         archive: *const c_void,
@@ -49,26 +71,93 @@ extern "C" {
         offest: i64,
     ) -> c_int;
     fn archive_error_string(archive: *const c_void) -> *const c_char;
+    fn archive_set_error(
+        archive: *const c_void,
+        errno: c_int,
+        format: *const c_char,
+        ...
+    );
+}
+
+/// What's restored on extraction besides the content, see
+/// `archive_write_disk_set_options(3)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractFlags(c_int);
+
+impl ExtractFlags {
+    /// User and group, which is required for setuid and
+    /// setgid bits to be restored.
+    pub const OWNER: Self = Self(0x0001);
+    /// Permissions, umask is ignored.
+    pub const PERM: Self = Self(0x0002);
+    /// Modification and access times.
+    pub const TIME: Self = Self(0x0004);
+    pub const ACL: Self = Self(0x0020);
+    /// File flags, i.e. `schg`.
+    pub const FFLAGS: Self = Self(0x0040);
+    pub const XATTR: Self = Self(0x0080);
 }
 
-pub struct ArchiveResource {
+impl BitOr for ExtractFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Full fidelity, except for ownership when not running as
+/// root, since it can't be restored anyway.
+impl Default for ExtractFlags {
+    fn default() -> Self {
+        let flags =
+            Self::PERM | Self::TIME | Self::ACL | Self::FFLAGS | Self::XATTR;
+
+        if unsafe { libc::geteuid() } == 0 {
+            flags | Self::OWNER
+        } else {
+            flags
+        }
+    }
+}
+
+/// Content libarchive reads by blocks via `read_callback`.
+struct Source<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+pub struct ArchiveResource<R: Read> {
     reader: *const c_void,
     writer: *const c_void,
+    /// Referred to by `reader`, hence boxed and freed after
+    /// it.
+    _source: Box<Source<R>>,
 }
 
-impl ArchiveResource {
+impl<R: Read> ArchiveResource<R> {
     #[fehler::throws]
-    pub fn new(content: &[u8]) -> Self {
+    pub fn new(
+        content: R,
+        compression: Compression,
+        flags: ExtractFlags,
+    ) -> Self {
+        let mut source = Box::new(Source {
+            reader: content,
+            buffer: vec![0; BLOCK_SIZE],
+        });
+
         Self {
-            reader: Self::init_reader(content)?,
-            writer: Self::init_writer()?,
+            reader: Self::init_reader(&mut source, compression)?,
+            writer: Self::init_writer(flags)?,
+            _source: source,
         }
     }
 
     #[fehler::throws]
     pub fn map_entries<T, F>(self, mut f: F) -> impl Iterator<Item = Result<T>>
     where
-        F: FnMut(&mut ArchiveEntry, &ArchiveResource) -> T,
+        F: FnMut(&mut ArchiveEntry, &ArchiveResource<R>) -> T,
     {
         unfold(ArchiveEntry, move |entry| {
             let result = unsafe {
@@ -77,7 +166,13 @@ impl ArchiveResource {
 
             match result {
                 ARCHIVE_OK => Some(Ok(f(entry, &self))),
-                ARCHIVE_EOF => None,
+                // Permissions and times of directories are
+                // restored on close
+                ARCHIVE_EOF => check_write(self.writer, unsafe {
+                    archive_write_close(self.writer)
+                })
+                .err()
+                .map(Err),
                 _ => Some(Err(report_error(self.reader))),
             }
         })
@@ -87,12 +182,12 @@ impl ArchiveResource {
     pub fn extract(
         self,
         path: impl AsRef<Path>,
-        ignore: impl Fn(String) -> bool,
+        mut ignore: impl FnMut(String) -> Result<bool>,
     ) {
         self.map_entries::<Result<()>, _>(|entry, resource| {
             entry.set_pathname(&path)?;
 
-            if !ignore(entry.pathname()) {
+            if !ignore(entry.pathname())? {
                 resource.extract_entry(entry)
             } else {
                 Ok(())
@@ -107,11 +202,9 @@ impl ArchiveResource {
         let mut size = 0;
         let mut offset = 0;
 
-        if unsafe { archive_write_header(self.writer, entry as *const _ as _) }
-            != ARCHIVE_OK
-        {
-            fehler::throw!(report_error(self.writer));
-        };
+        check_write(self.writer, unsafe {
+            archive_write_header(self.writer, entry as *const _ as _)
+        })?;
 
         loop {
             match self.read_data_block(&mut buff, &mut size, &mut offset) {
@@ -145,18 +238,16 @@ impl ArchiveResource {
         size: size_t,
         offset: i64,
     ) -> Result<()> {
-        let result = unsafe {
+        check_write(self.writer, unsafe {
             archive_write_data_block(self.writer, buff, size, offset)
-        };
-
-        match result {
-            ARCHIVE_OK => Ok(()),
-            _ => Err(report_error(self.writer)),
-        }
+        })
     }
 
     #[fehler::throws]
-    fn init_reader(content: &[u8]) -> *const c_void {
+    fn init_reader(
+        source: &mut Source<R>,
+        compression: Compression,
+    ) -> *const c_void {
         let reader = unsafe { archive_read_new() };
 
         if reader.is_null() {
@@ -164,12 +255,19 @@ impl ArchiveResource {
         }
 
         if unsafe {
-            archive_read_support_filter_gzip(reader);
+            match compression {
+                Compression::None => archive_read_support_filter_none(reader),
+                Compression::Gzip => archive_read_support_filter_gzip(reader),
+                Compression::Zstd => archive_read_support_filter_zstd(reader),
+                Compression::Xz => archive_read_support_filter_xz(reader),
+            }
             archive_read_support_format_tar(reader);
-            archive_read_open_memory(
+            archive_read_open(
                 reader,
-                content.as_ptr() as _,
-                content.len(),
+                source as *mut Source<R> as _,
+                std::ptr::null(),
+                read_callback::<R>,
+                std::ptr::null(),
             )
         } != ARCHIVE_OK
         {
@@ -180,7 +278,7 @@ impl ArchiveResource {
     }
 
     #[fehler::throws]
-    fn init_writer() -> *const c_void {
+    fn init_writer(flags: ExtractFlags) -> *const c_void {
         let writer = unsafe { archive_write_disk_new() };
 
         if writer.is_null() {
@@ -193,11 +291,17 @@ impl ArchiveResource {
             fehler::throw!(report_error(writer));
         }
 
+        if unsafe { archive_write_disk_set_options(writer, flags.0) }
+            != ARCHIVE_OK
+        {
+            fehler::throw!(report_error(writer));
+        }
+
         writer
     }
 }
 
-impl Drop for ArchiveResource {
+impl<R: Read> Drop for ArchiveResource<R> {
     fn drop(&mut self) {
         unsafe {
             archive_read_close(self.reader);
@@ -208,7 +312,61 @@ impl Drop for ArchiveResource {
     }
 }
 
-fn report_error(archive: *const c_void) -> Error {
+/// Reads the next block of the content, see
+/// `archive_read_callback(3)`.
+extern "C" fn read_callback<R: Read>(
+    archive: *const c_void,
+    client_data: *mut c_void,
+    buffer: *mut *const c_void,
+) -> ssize_t {
+    let source = unsafe { &mut *(client_data as *mut Source<R>) };
+
+    loop {
+        match source.reader.read(&mut source.buffer) {
+            Ok(size) => {
+                unsafe { *buffer = source.buffer.as_ptr() as _ };
+
+                return size as ssize_t;
+            }
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => {
+                set_error(archive, &error);
+
+                return ARCHIVE_FATAL;
+            }
+        }
+    }
+}
+
+/// Reports the I/O error of a callback to libarchive.
+pub(super) fn set_error(archive: *const c_void, error: &IoError) {
+    let message = CString::new(error.to_string()).unwrap_or_default();
+
+    unsafe {
+        archive_set_error(
+            archive,
+            error.raw_os_error().unwrap_or(libc::EIO),
+            b"%s\0".as_ptr() as _,
+            message.as_ptr(),
+        );
+    }
+}
+
+/// Fails on writer errors. Warnings, i.e. on metadata,
+/// which can't be restored, are logged only.
+pub(super) fn check_write(writer: *const c_void, result: c_int) -> Result<()> {
+    match result {
+        ARCHIVE_OK => Ok(()),
+        ARCHIVE_WARN => {
+            log::warn!("{}", report_error(writer));
+
+            Ok(())
+        }
+        _ => Err(report_error(writer)),
+    }
+}
+
+pub(super) fn report_error(archive: *const c_void) -> Error {
     let error_string = unsafe {
         let string = archive_error_string(archive);
         CStr::from_ptr(string)
@@ -0,0 +1,279 @@
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use libc::{c_char, c_int, c_uint, c_void, mode_t, size_t, ssize_t};
+
+use super::resource::{
+    check_write, report_error, set_error, ARCHIVE_FATAL, BLOCK_SIZE,
+};
+use crate::unpacker::WHITEOUT_PREFIX;
+
+/// Regular file type, see `archive_entry_set_filetype(3)`.
+const AE_IFREG: c_uint = 0o100000;
+const WHITEOUT_PERMISSIONS: mode_t = 0o644;
+
+type WriteCallback = extern "C" fn(
+    archive: *const c_void,
+    client_data: *mut c_void,
+    buffer: *const c_void,
+    length: size_t,
+) -> ssize_t;
+
+#[link(name = "archive")]
+extern "C" {
+    fn archive_write_new() -> *const c_void;
+    fn archive_write_set_format_pax_restricted(
+        archive: *const c_void,
+    ) -> c_int;
+    fn archive_write_open(
+        archive: *const c_void,
+        client_data: *mut c_void,
+        open_callback: *const c_void,
+        write_callback: WriteCallback,
+        close_callback: *const c_void,
+    ) -> c_int;
+    fn archive_write_header(
+        archive: *const c_void,
+        entry: *const c_void,
+    ) -> c_int;
+    fn archive_write_data(
+        archive: *const c_void,
+        buff: *const c_void,
+        size: size_t,
+    ) -> ssize_t;
+    fn archive_write_close(archive: *const c_void) -> c_int;
+    fn archive_write_free(archive: *const c_void);
+
+    fn archive_read_disk_new() -> *const c_void;
+    fn archive_read_disk_set_standard_lookup(archive: *const c_void) -> c_int;
+    fn archive_read_disk_set_symlink_physical(archive: *const c_void)
+        -> c_int;
+    fn archive_read_disk_entry_from_file(
+        archive: *const c_void,
+        entry: *const c_void,
+        fd: c_int,
+        stat: *const c_void,
+    ) -> c_int;
+    fn archive_read_free(archive: *const c_void);
+
+    fn archive_entry_new() -> *const c_void;
+    fn archive_entry_free(entry: *const c_void);
+    fn archive_entry_copy_sourcepath(
+        entry: *const c_void,
+        path: *const c_char,
+    );
+    fn archive_entry_copy_pathname(entry: *const c_void, path: *const c_char);
+    fn archive_entry_filetype(entry: *const c_void) -> mode_t;
+    fn archive_entry_set_filetype(entry: *const c_void, filetype: c_uint);
+    fn archive_entry_set_perm(entry: *const c_void, permissions: mode_t);
+    fn archive_entry_size(entry: *const c_void) -> i64;
+    fn archive_entry_set_size(entry: *const c_void, size: i64);
+}
+
+/// Uncompressed tarball of files read from the disk, along
+/// with their ownership, times, flags, ACLs and extended
+/// attributes. Entries are written as they're appended.
+pub struct ArchiveWriter<'a, W: Write> {
+    writer: *const c_void,
+    disk: *const c_void,
+    buffer: Vec<u8>,
+    /// Referred to by `writer`.
+    _destination: PhantomData<&'a mut W>,
+}
+
+/// Entry, which is freed once it's written.
+struct Entry(*const c_void);
+
+impl<'a, W: Write> ArchiveWriter<'a, W> {
+    #[fehler::throws]
+    pub fn new(destination: &'a mut W) -> Self {
+        let archive = Self {
+            writer: unsafe { archive_write_new() },
+            disk: unsafe { archive_read_disk_new() },
+            buffer: vec![0; BLOCK_SIZE],
+            _destination: PhantomData,
+        };
+
+        if archive.writer.is_null() || archive.disk.is_null() {
+            fehler::throw!(anyhow!("Failed to allocate the archiver"));
+        }
+
+        check_write(archive.disk, unsafe {
+            archive_read_disk_set_standard_lookup(archive.disk)
+        })?;
+        check_write(archive.disk, unsafe {
+            archive_read_disk_set_symlink_physical(archive.disk)
+        })?;
+        check_write(archive.writer, unsafe {
+            archive_write_set_format_pax_restricted(archive.writer)
+        })?;
+        check_write(archive.writer, unsafe {
+            archive_write_open(
+                archive.writer,
+                destination as *mut W as _,
+                std::ptr::null(),
+                write_callback::<W>,
+                std::ptr::null(),
+            )
+        })?;
+
+        archive
+    }
+
+    /// Appends the file at `source` as `name`, which is
+    /// relative to the archive root. Directories are
+    /// appended without their content, symlinks aren't
+    /// followed.
+    #[fehler::throws]
+    pub fn append(&mut self, source: &Path, name: &Path) {
+        let entry = Entry::new()?;
+        let source_raw = CString::new(source.as_os_str().as_bytes())?;
+        let name_raw = CString::new(name.as_os_str().as_bytes())?;
+
+        unsafe {
+            archive_entry_copy_sourcepath(entry.0, source_raw.as_ptr());
+            archive_entry_copy_pathname(entry.0, name_raw.as_ptr());
+        }
+
+        check_write(self.disk, unsafe {
+            archive_read_disk_entry_from_file(
+                self.disk,
+                entry.0,
+                -1,
+                std::ptr::null(),
+            )
+        })?;
+        check_write(self.writer, unsafe {
+            archive_write_header(self.writer, entry.0)
+        })?;
+
+        let is_file =
+            unsafe { archive_entry_filetype(entry.0) } as c_uint == AE_IFREG;
+
+        // Hard links are stored as files, along with content
+        if is_file && unsafe { archive_entry_size(entry.0) } > 0 {
+            self.append_content(source)?;
+        }
+    }
+
+    /// Appends the whiteout of `name`, which hides the file
+    /// or directory of lower layers.
+    #[fehler::throws]
+    pub fn append_whiteout(&mut self, name: &Path) {
+        let file_name = name
+            .file_name()
+            .ok_or_else(|| anyhow!("Can't white out {:?}", name))?;
+        let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+
+        whiteout.push(file_name);
+
+        let entry = Entry::new()?;
+        let name_raw = CString::new(
+            name.with_file_name(whiteout).as_os_str().as_bytes(),
+        )?;
+
+        unsafe {
+            archive_entry_copy_pathname(entry.0, name_raw.as_ptr());
+            archive_entry_set_filetype(entry.0, AE_IFREG);
+            archive_entry_set_perm(entry.0, WHITEOUT_PERMISSIONS);
+            archive_entry_set_size(entry.0, 0);
+        }
+
+        check_write(self.writer, unsafe {
+            archive_write_header(self.writer, entry.0)
+        })?;
+    }
+
+    /// Writes the end of the archive. Dropping the writer
+    /// unfinished does so as well, but errors are lost then.
+    #[fehler::throws]
+    pub fn finish(self) {
+        check_write(self.writer, unsafe { archive_write_close(self.writer) })?;
+    }
+
+    #[fehler::throws]
+    fn append_content(&mut self, source: &Path) {
+        let mut file = File::open(source)?;
+
+        loop {
+            let size = match file.read(&mut self.buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(error) => fehler::throw!(error),
+            };
+            let written = unsafe {
+                archive_write_data(
+                    self.writer,
+                    self.buffer.as_ptr() as _,
+                    size,
+                )
+            };
+
+            if written < 0 {
+                fehler::throw!(report_error(self.writer));
+            }
+        }
+    }
+}
+
+impl<'a, W: Write> Drop for ArchiveWriter<'a, W> {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.writer.is_null() {
+                archive_write_free(self.writer);
+            }
+            if !self.disk.is_null() {
+                archive_read_free(self.disk);
+            }
+        }
+    }
+}
+
+impl Entry {
+    #[fehler::throws]
+    fn new() -> Self {
+        let entry = unsafe { archive_entry_new() };
+
+        if entry.is_null() {
+            fehler::throw!(anyhow!("Failed to allocate the archive entry"));
+        }
+
+        Self(entry)
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        unsafe { archive_entry_free(self.0) }
+    }
+}
+
+/// Writes the next block of the archive, see
+/// `archive_write_callback(3)`.
+extern "C" fn write_callback<W: Write>(
+    archive: *const c_void,
+    client_data: *mut c_void,
+    buffer: *const c_void,
+    length: size_t,
+) -> ssize_t {
+    let destination = unsafe { &mut *(client_data as *mut W) };
+    let block =
+        unsafe { std::slice::from_raw_parts(buffer as *const u8, length) };
+
+    match destination.write_all(block) {
+        Ok(()) => length as ssize_t,
+        Err(error) => {
+            set_error(archive, &error);
+
+            ARCHIVE_FATAL
+        }
+    }
+}
@@ -0,0 +1,183 @@
+/// Artifacts attached to images, i.e. SBOMs and provenance
+/// attestations, found via the referrers API. Their blobs
+/// are kept in the blob store, as long as the image they
+/// are attached to is, so that deployed images are audited
+/// without the registry.
+use std::{collections::BTreeMap, io::Write};
+
+use anyhow::{anyhow, Context, Error};
+use registratur::v2::{
+    domain::{layer::Layer, manifest::Manifest, referrers::Referrers},
+    reference::Reference,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    containerfile::build_client,
+    image_store::ImageStore,
+    storage::{Storage, StorageEngine, ATTACHMENTS_STORAGE_KEY},
+};
+
+/// Artifact types of SPDX and CycloneDX documents.
+pub const SBOM_TYPES: [&str; 3] = [
+    "application/spdx+json",
+    "text/spdx",
+    "application/vnd.cyclonedx+json",
+];
+/// Artifact type of in-toto attestations, i.e. SLSA
+/// provenance.
+pub const ATTESTATION_TYPE: &str = "application/vnd.in-toto+json";
+
+/// Artifact attached to the image manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Attachment {
+    /// Digest of the artifact manifest.
+    pub digest: String,
+    pub artifact_type: String,
+    pub annotations: BTreeMap<String, String>,
+    /// Digests of the documents, i.e. of the SBOM.
+    pub blobs: Vec<String>,
+}
+
+impl Attachment {
+    pub fn is_sbom(&self) -> bool {
+        SBOM_TYPES.contains(&self.artifact_type.as_str())
+    }
+
+    pub fn is_attestation(&self) -> bool {
+        self.artifact_type == ATTESTATION_TYPE
+    }
+}
+
+pub struct Attachments<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+}
+
+impl<'a, T: StorageEngine> Attachments<'a, T> {
+    pub fn new(storage: &'a Storage<T>) -> Self {
+        Self { storage }
+    }
+
+    /// Stored artifacts attached to the image manifest
+    /// `digest`.
+    #[fehler::throws]
+    pub fn list(&self, digest: &str) -> Vec<Attachment> {
+        self.storage
+            .get(ATTACHMENTS_STORAGE_KEY, digest)?
+            .unwrap_or_default()
+    }
+
+    /// Fetches artifacts attached to the pulled image from
+    /// the registry the `reference` names, replacing the
+    /// stored ones. Artifacts are attached to the manifest
+    /// of the image platform, rather than to the index.
+    #[fehler::throws]
+    pub async fn fetch(&self, reference: &Reference) -> Vec<Attachment> {
+        let digest = ImageStore::new(self.storage).resolve(reference)?;
+        let registry_url = reference.registry_url();
+        let client = build_client(&registry_url, None)?;
+        let image_name = &reference.repository;
+        let referrers =
+            Referrers::pull(&client, image_name, &digest, None).await?;
+        let mut attachments = vec![];
+
+        for referrer in referrers {
+            let manifest_digest = referrer.descriptor.digest;
+            let manifest =
+                Manifest::pull(&client, image_name, &manifest_digest)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to fetch artifact {}", manifest_digest)
+                    })?;
+            // Artifacts predating `artifactType` are typed by
+            // their config
+            let artifact_type =
+                referrer.artifact_type.unwrap_or(manifest.config.media_type);
+            let mut blobs = vec![];
+
+            for layer in manifest.layers {
+                let digest = layer.digest;
+                let store = self.storage.blobs();
+
+                if !store.exists(&digest)? {
+                    let content =
+                        Layer::pull(&client, image_name, &digest, |_| {})
+                            .await?;
+                    let mut writer = store.writer(&digest)?;
+
+                    writer.file().write_all(&content)?;
+                    writer.commit()?;
+                }
+
+                blobs.push(digest);
+            }
+
+            attachments.push(Attachment {
+                digest: manifest_digest,
+                artifact_type,
+                annotations: referrer
+                    .annotations
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+                blobs,
+            });
+        }
+
+        log::info!(
+            "Fetched {} artifacts attached to {}",
+            attachments.len(),
+            reference
+        );
+
+        self.storage
+            .put(ATTACHMENTS_STORAGE_KEY, &digest, &attachments)?;
+
+        attachments
+    }
+
+    /// Content of the attached document.
+    #[fehler::throws]
+    pub fn read(&self, blob: &str) -> Vec<u8> {
+        let path = self.storage.blobs().path(blob)?;
+
+        if !path.is_file() {
+            fehler::throw!(anyhow!("Attached blob {} is not stored", blob));
+        }
+
+        std::fs::read(path)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TestStorage;
+
+    #[test]
+    fn test_read() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let attachment = Attachment {
+            digest: "sha256:a".into(),
+            artifact_type: "application/spdx+json".into(),
+            annotations: BTreeMap::new(),
+            blobs: vec!["sha256:b".into()],
+        };
+
+        let mut writer = storage.blobs().writer("sha256:b").unwrap();
+        writer.file().write_all(b"{}").unwrap();
+        writer.commit().unwrap();
+        storage
+            .put(ATTACHMENTS_STORAGE_KEY, "sha256:m", vec![&attachment])
+            .unwrap();
+
+        let attachments = Attachments::new(&storage);
+
+        assert_eq!(attachments.list("sha256:m").unwrap(), vec![attachment]);
+        assert!(attachments.list("sha256:n").unwrap().is_empty());
+        assert_eq!(attachments.read("sha256:b").unwrap(), b"{}");
+        assert!(attachments.read("sha256:c").is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::{Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context, Error};
+use registratur::{
+    v2::domain::{
+        config::{Config, Container, RootFs},
+        media_type::Compression,
+    },
+    verify_reader,
+};
+use uuid::Uuid;
+
+use crate::{
+    archive::Archive,
+    runtime_config::RuntimeConfig,
+    storage::{
+        Storage, StorageEngine, CONTAINERS_FOLDER, CONTAINERS_STORAGE_KEY,
+    },
+};
+
+pub const DEFAULT_MIRROR: &str = "https://download.freebsd.org/ftp";
+const BASE_SET: &str = "base.txz";
+const PATH: &str = "PATH=/sbin:/bin:/usr/sbin:/usr/bin:/usr/local/sbin:\
+                    /usr/local/bin";
+
+/// Builds a FreeBSD container from the base distribution
+/// set, rather than from a registry image. The set is
+/// verified against the checksum of the distribution
+/// `MANIFEST` and kept in the blob store, so that it's
+/// downloaded once.
+pub struct FreeBsdBootstrap<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+    version: String,
+    architecture: String,
+    mirror: String,
+}
+
+impl<'a, T: StorageEngine> FreeBsdBootstrap<'a, T> {
+    /// `version` is either a release, i.e. `14.1`, or a
+    /// snapshot, i.e. `15.0-CURRENT`.
+    pub fn new(storage: &'a Storage<T>, version: impl Into<String>) -> Self {
+        Self {
+            storage,
+            version: version.into(),
+            architecture: "amd64".into(),
+            mirror: DEFAULT_MIRROR.into(),
+        }
+    }
+
+    pub fn with_architecture(self, architecture: impl Into<String>) -> Self {
+        Self {
+            architecture: architecture.into(),
+            ..self
+        }
+    }
+
+    pub fn with_mirror(self, mirror: impl Into<String>) -> Self {
+        Self {
+            mirror: mirror.into(),
+            ..self
+        }
+    }
+
+    /// Unpacks the base set into a new container folder,
+    /// along with the OCI runtime config. Returns the
+    /// folder, i.e. the bundle.
+    #[fehler::throws]
+    pub async fn bootstrap(&self) -> PathBuf {
+        let url = self.distribution_url()?;
+        let client = reqwest::Client::new();
+        let manifest = client
+            .get(&format!("{}/MANIFEST", url))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+            .with_context(|| format!("Failed to fetch {}/MANIFEST", url))?;
+        let digest = format!("sha256:{}", base_checksum(&manifest)?);
+
+        if !self.storage.blobs().exists(&digest)? {
+            let url = format!("{}/{}", url, BASE_SET);
+
+            self.download(&client, &url, &digest)
+                .await
+                .with_context(|| format!("Failed to fetch {}", url))?;
+        }
+
+        let container_uuid = Uuid::new_v4().to_string();
+        let folder = self
+            .storage
+            .folder()
+            .join(CONTAINERS_FOLDER)
+            .join(&container_uuid);
+        let rootfs = folder.join("rootfs");
+
+        fs::create_dir_all(&rootfs)?;
+
+        // The set is kept while the container exists, see
+        // `gc::prune`
+        self.storage
+            .put(CONTAINERS_STORAGE_KEY, &container_uuid, &digest)?;
+
+        let base =
+            self.storage.blobs().open(&digest)?.context(
+                "Base set was not found. Possible storage corruption",
+            )?;
+
+        Archive::new(base, Compression::Xz).extract(&rootfs, |_| Ok(false))?;
+
+        let runtime_config =
+            RuntimeConfig::try_from((self.config(), rootfs.as_path()))?;
+
+        runtime_config.create_volumes()?;
+
+        serde_json::to_writer(
+            File::create(folder.join("config.json"))?,
+            &runtime_config,
+        )?;
+
+        folder
+    }
+
+    /// Downloads the set to the blob store. Partial sets are
+    /// downloaded anew.
+    #[fehler::throws]
+    async fn download(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        digest: &str,
+    ) {
+        let mut writer = self.storage.blobs().writer(digest)?;
+        let mut response = client.get(url).send().await?.error_for_status()?;
+
+        writer.file().set_len(0)?;
+
+        while let Some(chunk) = response.chunk().await? {
+            writer.file().write_all(&chunk)?;
+        }
+
+        writer.file().seek(SeekFrom::Start(0))?;
+
+        if let Err(error) = verify_reader(writer.file(), digest) {
+            drop(writer);
+            self.storage.blobs().remove_partial(digest)?;
+
+            fehler::throw!(error);
+        }
+
+        writer.commit()?;
+    }
+
+    /// Folder of the distribution sets on the mirror.
+    #[fehler::throws]
+    fn distribution_url(&self) -> String {
+        let valid = !self.version.is_empty()
+            && self
+                .version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+
+        if !valid {
+            fehler::throw!(anyhow!("Invalid version {}", self.version));
+        }
+
+        let version = if self.version.contains('-') {
+            self.version.to_uppercase()
+        } else {
+            format!("{}-RELEASE", self.version)
+        };
+        let kind =
+            if version.ends_with("-CURRENT") || version.ends_with("-STABLE") {
+                "snapshots"
+            } else {
+                "releases"
+            };
+        // Sets of other platforms are under `machine/arch`
+        let platform = match self.architecture.as_str() {
+            "amd64" | "i386" => self.architecture.clone(),
+            "arm64" | "aarch64" => "arm64/aarch64".into(),
+            architecture => format!("{0}/{0}", architecture),
+        };
+
+        format!(
+            "{}/{}/{}/{}",
+            self.mirror.trim_end_matches('/'),
+            kind,
+            platform,
+            version
+        )
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            created: None,
+            author: None,
+            architecture: self.architecture.clone(),
+            os: "freebsd".into(),
+            config: Some(Container {
+                user: None,
+                exposed_ports: None,
+                env: Some(vec![PATH.into()]),
+                entrypoint: None,
+                cmd: Some(vec!["/bin/sh".into()]),
+                volumes: None,
+                working_dir: "/".into(),
+                labels: None,
+                stop_signal: None,
+            }),
+            rootfs: RootFs {
+                r#type: "layers".into(),
+                diff_ids: vec![],
+            },
+            history: vec![],
+        }
+    }
+}
+
+/// SHA256 of the base set, listed in the distribution
+/// `MANIFEST` as `base.txz <sha256> <files> base ...`.
+#[fehler::throws]
+fn base_checksum(manifest: &str) -> String {
+    manifest
+        .lines()
+        .map(|line| line.split('\t').collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&BASE_SET))
+        .and_then(|fields| fields.get(1).copied())
+        .map(str::trim)
+        .filter(|checksum| {
+            checksum.len() == 64
+                && checksum.chars().all(|c| c.is_ascii_hexdigit())
+        })
+        .ok_or_else(|| anyhow!("MANIFEST lacks {} checksum", BASE_SET))?
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TestStorage;
+
+    #[test]
+    fn test_base_checksum() {
+        let checksum = "a".repeat(64);
+        let manifest = format!(
+            "base-dbg.txz\t{}\t10\tbase_dbg\t\"Base\"\toff\n\
+             base.txz\t{}\t20\tbase\t\"Base system\"\ton\n",
+            "b".repeat(64),
+            checksum
+        );
+
+        assert_eq!(base_checksum(&manifest).unwrap(), checksum);
+        assert!(base_checksum("base.txz\tnot-a-checksum").is_err());
+        assert!(base_checksum("").is_err());
+    }
+
+    #[test]
+    fn test_distribution_url() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let url = |version: &str, architecture: &str| {
+            FreeBsdBootstrap::new(&storage, version)
+                .with_architecture(architecture)
+                .with_mirror("https://mirror/")
+                .distribution_url()
+        };
+
+        assert_eq!(
+            url("14.1", "amd64").unwrap(),
+            "https://mirror/releases/amd64/14.1-RELEASE"
+        );
+        assert_eq!(
+            url("15.0-current", "arm64").unwrap(),
+            "https://mirror/snapshots/arm64/aarch64/15.0-CURRENT"
+        );
+        assert_eq!(
+            url("14.2-RC1", "riscv64").unwrap(),
+            "https://mirror/releases/riscv64/riscv64/14.2-RC1"
+        );
+        assert!(url("../14.1", "amd64").is_err());
+    }
+}
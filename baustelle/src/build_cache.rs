@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+
+use anyhow::Error;
+use registratur::v2::domain::manifest::Manifest;
+use ring::digest::{Context, SHA256};
+
+use crate::storage::{
+    Storage, StorageEngine, BLOBS_STORAGE_KEY, BUILD_CACHE_STORAGE_KEY,
+};
+
+/// Results of Containerfile steps, so that unchanged steps
+/// aren't redone. Steps are keyed by the result of the
+/// parent step, the step itself and hashes of the context
+/// files it uses.
+///
+/// Only `FROM` steps are executed by the builder so far,
+/// their result is the manifest digest of the image.
+pub struct BuildCache<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+}
+
+impl<'a, T: StorageEngine> BuildCache<'a, T> {
+    pub fn new(storage: &'a Storage<T>) -> Self {
+        Self { storage }
+    }
+
+    /// Result of the step, if it's cached and the image it
+    /// resulted in is still stored.
+    #[fehler::throws]
+    pub fn get(&self, key: &str) -> Option<String> {
+        let digest: Option<String> =
+            self.storage.get(BUILD_CACHE_STORAGE_KEY, key)?;
+
+        match digest {
+            Some(digest) if self.is_stored(&digest)? => Some(digest),
+            _ => None,
+        }
+    }
+
+    #[fehler::throws]
+    pub fn put(&self, key: &str, digest: &str) {
+        self.storage.put(BUILD_CACHE_STORAGE_KEY, key, digest)?;
+    }
+
+    /// Removes steps, which images aren't `reachable`, see
+    /// `gc::prune`. Returns keys of removed (or, on dry run,
+    /// removable) steps.
+    #[fehler::throws]
+    pub fn prune(
+        &self,
+        reachable: &HashSet<String>,
+        dry_run: bool,
+    ) -> Vec<String> {
+        let mut result = vec![];
+
+        for key in self.storage.keys(BUILD_CACHE_STORAGE_KEY)? {
+            let digest: Option<String> =
+                self.storage.get(BUILD_CACHE_STORAGE_KEY, &key)?;
+
+            if digest.map_or(false, |digest| reachable.contains(&digest)) {
+                continue;
+            }
+
+            if !dry_run {
+                self.storage.remove(BUILD_CACHE_STORAGE_KEY, &key)?;
+            }
+
+            result.push(String::from_utf8_lossy(&key).into_owned());
+        }
+
+        result
+    }
+
+    #[fehler::throws]
+    fn is_stored(&self, digest: &str) -> bool {
+        let manifest: Option<Manifest> =
+            self.storage.get(BLOBS_STORAGE_KEY, digest)?;
+
+        manifest.is_some()
+    }
+}
+
+/// Cache key of the step. `context` holds hashes of the
+/// context files, in the order the step uses them.
+pub fn key(parent: Option<&str>, step: &str, context: &[&str]) -> String {
+    let mut hasher = Context::new(&SHA256);
+
+    for part in parent.iter().chain(&[step]).chain(context) {
+        hasher.update(part.as_bytes());
+        // Parts are separated, so that they can't be shifted
+        hasher.update(&[0]);
+    }
+
+    format!("sha256:{}", hex::encode(hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TestStorage;
+
+    #[test]
+    fn test_cache() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let manifest: Manifest = serde_json::from_str(test_helpers::fixture!(
+            "server_mocks/basic/manifest.json"
+        ))
+        .unwrap();
+        let cache = BuildCache::new(&storage);
+        let key = key(None, "FROM nginx", &[]);
+        let reachable: HashSet<_> =
+            vec!["sha256:m".into()].into_iter().collect();
+
+        assert_ne!(key, super::key(Some("FROM nginx"), "", &[]));
+
+        storage
+            .put(BLOBS_STORAGE_KEY, "sha256:m", manifest)
+            .unwrap();
+        cache.put(&key, "sha256:m").unwrap();
+        assert_eq!(cache.get(&key).unwrap(), Some("sha256:m".into()));
+        assert!(cache.prune(&reachable, false).unwrap().is_empty());
+
+        storage.remove(BLOBS_STORAGE_KEY, "sha256:m").unwrap();
+        assert_eq!(cache.get(&key).unwrap(), None);
+
+        let reachable = HashSet::new();
+        assert_eq!(cache.prune(&reachable, true).unwrap(), vec![key.clone()]);
+        assert_eq!(cache.prune(&reachable, false).unwrap(), vec![key]);
+        assert!(cache.prune(&reachable, false).unwrap().is_empty());
+    }
+}
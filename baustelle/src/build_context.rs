@@ -0,0 +1,307 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Error};
+
+/// Ignore files, in order of precedence. Only the first one
+/// found is used.
+const IGNORE_FILES: [&str; 2] = [".containerignore", ".dockerignore"];
+
+/// Folder, which `COPY` and `ADD` sources are collected
+/// from. Files matching patterns of the ignore file are
+/// left out, see
+/// https://docs.docker.com/engine/reference/builder/#dockerignore-file
+pub struct BuildContext {
+    root: PathBuf,
+    rules: Vec<Rule>,
+}
+
+/// Pattern of the ignore file, split into path components.
+#[derive(Debug)]
+struct Rule {
+    pattern: Vec<String>,
+    negated: bool,
+}
+
+impl BuildContext {
+    #[fehler::throws]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        let mut rules = vec![];
+
+        for name in &IGNORE_FILES {
+            let path = root.join(name);
+
+            if path.is_file() {
+                rules = parse_rules(&fs::read_to_string(path)?);
+
+                break;
+            }
+        }
+
+        Self { root, rules }
+    }
+
+    /// Whether the path, relative to the context root, is
+    /// ignored. Paths within ignored folders are ignored too,
+    /// unless a later `!` pattern matches them. The last
+    /// matching pattern wins.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let components = components(path);
+        let mut ignored = false;
+
+        for rule in &self.rules {
+            let matched = (1..=components.len())
+                .any(|length| matches(&rule.pattern, &components[..length]));
+
+            if matched {
+                ignored = !rule.negated;
+            }
+        }
+
+        ignored
+    }
+
+    /// Files matching the `source` of `COPY` or `ADD`, which
+    /// aren't ignored. Source might contain wildcards, folders
+    /// are copied with their contents. Paths are relative to
+    /// the context root, in ascending order.
+    #[fehler::throws]
+    pub fn collect(&self, source: &str) -> Vec<PathBuf> {
+        let pattern = components(Path::new(source));
+
+        if Path::new(source)
+            .components()
+            .any(|component| component == Component::ParentDir)
+        {
+            fehler::throw!(anyhow!("{} is outside of the context", source));
+        }
+
+        let mut files = vec![];
+
+        self.walk(PathBuf::new(), &mut files)?;
+
+        let mut result: Vec<_> = files
+            .into_iter()
+            .filter(|file| {
+                let components = components(file);
+
+                // Empty source, i.e. `.`, matches the whole context
+                (0..=components.len())
+                    .any(|length| matches(&pattern, &components[..length]))
+            })
+            .filter(|file| !self.is_ignored(file))
+            .collect();
+
+        if result.is_empty() {
+            fehler::throw!(anyhow!("No files match the source {}", source));
+        }
+
+        result.sort();
+
+        result
+    }
+
+    /// Collects files and symlinks under the `folder`,
+    /// relative to the context root. Symlinks aren't followed.
+    #[fehler::throws]
+    fn walk(&self, folder: PathBuf, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(self.root.join(&folder))? {
+            let entry = entry?;
+            let path = folder.join(entry.file_name());
+
+            if entry.file_type()?.is_dir() {
+                self.walk(path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+    }
+}
+
+fn parse_rules(content: &str) -> Vec<Rule> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let negated = line.starts_with('!');
+            let pattern = components(Path::new(line.trim_start_matches('!')));
+
+            if pattern.is_empty() {
+                return None;
+            }
+
+            Some(Rule { pattern, negated })
+        })
+        .collect()
+}
+
+/// Normal components of the path, i.e. leading `/` and `.`
+/// components are dropped.
+fn components(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into()),
+            Component::ParentDir => Some("..".into()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether the path matches the pattern, component-wise.
+/// `**` matches any number of components.
+fn matches(pattern: &[String], path: &[String]) -> bool {
+    match (pattern.first(), path.first()) {
+        (Some(head), _) if head == "**" => {
+            matches(&pattern[1..], path)
+                || (!path.is_empty() && matches(pattern, &path[1..]))
+        }
+        (Some(head), Some(name)) => {
+            let pattern_chars: Vec<_> = head.chars().collect();
+            let name_chars: Vec<_> = name.chars().collect();
+
+            matches_name(&pattern_chars, &name_chars)
+                && matches(&pattern[1..], &path[1..])
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Matches the name against a shell pattern: `*`, `?`,
+/// `[a-z]`, `[!a-z]` and `\` escapes.
+fn matches_name(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len())
+            .any(|skip| matches_name(&pattern[1..], &name[skip..])),
+        Some('?') => {
+            !name.is_empty() && matches_name(&pattern[1..], &name[1..])
+        }
+        Some('[') => match (name.first(), class(&pattern[1..])) {
+            (Some(&c), Some((matched, length))) => {
+                matched(c) && matches_name(&pattern[length + 1..], &name[1..])
+            }
+            // Unterminated class is matched literally
+            (Some('['), None) => matches_name(&pattern[1..], &name[1..]),
+            _ => false,
+        },
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1])
+                && matches_name(&pattern[2..], &name[1..])
+        }
+        Some(c) => {
+            name.first() == Some(c) && matches_name(&pattern[1..], &name[1..])
+        }
+    }
+}
+
+/// Parses the character class following `[`. Returns the
+/// predicate and the length of the class, including `]`.
+fn class(pattern: &[char]) -> Option<(impl Fn(char) -> bool + '_, usize)> {
+    let negated = matches!(pattern.first(), Some('!') | Some('^'));
+    let start = if negated { 1 } else { 0 };
+    // `]` right after `[` is a member of the class
+    let end = pattern
+        .iter()
+        .skip(start + 1)
+        .position(|&c| c == ']')
+        .map(|position| position + start + 1)?;
+    let members = &pattern[start..end];
+
+    let predicate = move |c: char| {
+        let mut matched = false;
+        let mut index = 0;
+
+        while index < members.len() {
+            if index + 2 < members.len() && members[index + 1] == '-' {
+                matched |= members[index] <= c && c <= members[index + 2];
+                index += 3;
+            } else {
+                matched |= members[index] == c;
+                index += 1;
+            }
+        }
+
+        matched != negated
+    };
+
+    Some((predicate, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<_> = pattern.chars().collect();
+        let name: Vec<_> = name.chars().collect();
+
+        matches_name(&pattern, &name)
+    }
+
+    #[test]
+    fn test_matches_name() {
+        assert!(name("*.md", "README.md"));
+        assert!(!name("*.md", "README.txt"));
+        assert!(name("file?.txt", "file1.txt"));
+        assert!(name("[a-c]at", "bat"));
+        assert!(!name("[!a-c]at", "bat"));
+        assert!(name("[]]", "]"));
+        assert!(name("\\*", "*"));
+        assert!(!name("\\*", "a"));
+        assert!(name("[", "["));
+    }
+
+    #[test]
+    fn test_collect() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let root = tempdir.path();
+
+        for file in &[
+            "src/main.rs",
+            "src/secret.key",
+            "target/debug/app",
+            "docs/README.md",
+            "docs/guide.md",
+            "docs/internal/notes.md",
+            ".env",
+        ] {
+            let path = root.join(file);
+
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+
+        fs::write(root.join(".dockerignore"), "ignored by .containerignore")
+            .unwrap();
+        fs::write(
+            root.join(".containerignore"),
+            "# Comment\n\n/target\n**/*.key\n.env\n\
+             docs/*.md\n!docs/README.md\n",
+        )
+        .unwrap();
+
+        let context = BuildContext::new(root).unwrap();
+        let collect = |source: &str| context.collect(source).unwrap();
+        let paths = |paths: &[&str]| {
+            paths.iter().map(PathBuf::from).collect::<Vec<_>>()
+        };
+
+        assert_eq!(collect("src"), paths(&["src/main.rs"]));
+        assert_eq!(
+            collect("docs"),
+            paths(&["docs/README.md", "docs/internal/notes.md"])
+        );
+        assert_eq!(collect("./src/*.rs"), paths(&["src/main.rs"]));
+        assert_eq!(collect(".").len(), 4);
+        assert!(context.is_ignored(Path::new("target/debug/app")));
+        assert!(context.is_ignored(Path::new(".env")));
+        assert!(!context.is_ignored(Path::new("docs/README.md")));
+        assert!(context.collect("target").is_err());
+        assert!(context.collect("../etc").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fmt, future::Future, sync::Arc};
+
+use anyhow::Error;
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use tokio::sync::watch;
+
+/// Aborts pulls and builds, which are given a clone of the
+/// token. Once cancelled, the token stays cancelled.
+#[derive(Clone, Debug)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+/// Error of operations aborted via [`CancellationToken`].
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn cancel(&self) {
+        // Receiver of the token itself is always there
+        let _ = self.sender.send(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Runs the future until it completes, or fails with
+/// [`Cancelled`] once the token is cancelled. The future is
+/// dropped then, cleaning up is the caller's responsibility.
+#[fehler::throws]
+pub(crate) async fn cancellable<R>(
+    token: Option<&CancellationToken>,
+    future: impl Future<Output = Result<R, Error>>,
+) -> R {
+    let token = match token {
+        Some(token) => token,
+        None => return future.await?,
+    };
+
+    let cancelled = token.cancelled();
+
+    pin_mut!(future, cancelled);
+
+    match future::select(future, cancelled).await {
+        Either::Left((result, _)) => result?,
+        Either::Right(_) => fehler::throw!(Cancelled),
+    }
+}
+
+/// Fails with [`Cancelled`], if the token is cancelled.
+#[fehler::throws]
+pub(crate) fn check(token: Option<&CancellationToken>) {
+    if token.map_or(false, CancellationToken::is_cancelled) {
+        fehler::throw!(Cancelled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let token = CancellationToken::new();
+        let result = cancellable(Some(&token), async { Ok(42) }).await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert!(check(Some(&token)).is_ok());
+
+        let clone = token.clone();
+        let result = cancellable(Some(&token), async move {
+            clone.cancel();
+            future::pending::<Result<(), Error>>().await
+        })
+        .await;
+
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert!(token.is_cancelled());
+        assert!(check(Some(&token)).unwrap_err().is::<Cancelled>());
+        assert!(check(None).is_ok());
+    }
+}
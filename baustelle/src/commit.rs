@@ -0,0 +1,639 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context as _, Error};
+use chrono::Local;
+use registratur::v2::{
+    domain::{
+        config::{Config, Container, HistoryItem, RootFs},
+        descriptor::Descriptor,
+        manifest::Manifest,
+        media_type,
+    },
+    reference::Reference,
+};
+use ring::digest::{Context, SHA256};
+use uuid::Uuid;
+
+use crate::{
+    archive::ArchiveWriter,
+    pull::{Image, UnpackOptions},
+    runtime_config::{RuntimeConfig, OS_ANNOTATION},
+    storage::{
+        Storage, StorageEngine, BLOBS_STORAGE_KEY, CONTAINERS_STORAGE_KEY,
+        HEALTHCHECKS_STORAGE_KEY, IMAGES_INDEX_STORAGE_KEY,
+    },
+};
+
+/// Commits are staged in this subfolder of the storage.
+const COMMITS_FOLDER: &str = "commits";
+
+/// Commits root filesystems of containers to images, i.e.
+/// to build golden images interactively, or to keep the
+/// state of a container for debugging.
+///
+/// The root filesystem is compared to the image the
+/// container is created from, which is unpacked anew, and
+/// the changes make up an uncompressed layer on top of the
+/// image layers. Files are compared by metadata, the way
+/// Docker compares them. Containers, which aren't created
+/// from pulled images, i.e. bootstrapped ones, are committed
+/// as a whole.
+pub struct Committer<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+    architecture: String,
+    author: Option<String>,
+    message: Option<String>,
+}
+
+/// Change of the root filesystem, compared to the image.
+#[derive(Debug, PartialEq)]
+enum Change {
+    /// Added or modified file, directory or link.
+    Added(PathBuf),
+    Deleted(PathBuf),
+}
+
+/// Layer written to the staging folder.
+struct Layer {
+    file: File,
+    digest: String,
+    size: usize,
+}
+
+/// Writer, which hashes the content passing through it.
+struct Digester<W> {
+    writer: W,
+    context: Context,
+    size: usize,
+}
+
+impl<'a, T: StorageEngine> Committer<'a, T> {
+    pub fn new(storage: &'a Storage<T>) -> Self {
+        Self {
+            storage,
+            architecture: "amd64".into(),
+            author: None,
+            message: None,
+        }
+    }
+
+    /// Architecture of images, which are committed as a
+    /// whole. Others keep the one of the image.
+    pub fn with_architecture(self, architecture: impl Into<String>) -> Self {
+        Self {
+            architecture: architecture.into(),
+            ..self
+        }
+    }
+
+    pub fn with_author(self, author: impl Into<String>) -> Self {
+        Self {
+            author: Some(author.into()),
+            ..self
+        }
+    }
+
+    /// Comment of the layer in the image history.
+    pub fn with_message(self, message: impl Into<String>) -> Self {
+        Self {
+            message: Some(message.into()),
+            ..self
+        }
+    }
+
+    /// Commits the root filesystem of the container created
+    /// with the runtime `config` to the image `reference`
+    /// refers to then. Returns the manifest digest.
+    #[fehler::throws]
+    pub fn commit(
+        &self,
+        config: &RuntimeConfig,
+        reference: &Reference,
+    ) -> String {
+        if reference.digest.is_some() {
+            fehler::throw!(anyhow!(
+                "Can't commit to {}, it's pinned",
+                reference
+            ));
+        }
+
+        let rootfs = config
+            .root
+            .as_ref()
+            .map(|root| root.path.clone())
+            .context("Runtime config: root field must be set")?;
+        let staging = self
+            .storage
+            .folder()
+            .join(COMMITS_FOLDER)
+            .join(Uuid::new_v4().to_string());
+
+        fs::create_dir_all(&staging)?;
+
+        let result = self.stage(config, &rootfs, &staging);
+
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        let digest = result?;
+
+        self.storage.put(
+            IMAGES_INDEX_STORAGE_KEY,
+            reference.to_string(),
+            &digest,
+        )?;
+
+        digest
+    }
+
+    /// Writes the layer, then stores it along with the
+    /// config and the manifest.
+    #[fehler::throws]
+    fn stage(
+        &self,
+        config: &RuntimeConfig,
+        rootfs: &Path,
+        staging: &Path,
+    ) -> String {
+        let base = self.base(rootfs)?;
+        let lower = staging.join("rootfs");
+
+        fs::create_dir_all(&lower)?;
+
+        if let Some(base) = &base {
+            base.unpack(&lower, UnpackOptions::default())?;
+        }
+
+        let changes = changes(&lower, rootfs)?;
+        let mut layer = write_layer(rootfs, &changes, staging)?;
+        let mut writer = self.storage.blobs().writer(&layer.digest)?;
+
+        writer.file().set_len(0)?;
+        io::copy(&mut layer.file, writer.file())?;
+        writer.commit()?;
+
+        let layer = Descriptor {
+            media_type: media_type::OCI_LAYER.into(),
+            digest: layer.digest,
+            size: layer.size,
+            urls: None,
+        };
+
+        self.store(base.as_ref(), config, layer)?
+    }
+
+    /// Image the container is created from, if it's pulled.
+    #[fehler::throws]
+    fn base(&self, rootfs: &Path) -> Option<Image<'a, T>> {
+        let container_uuid = rootfs
+            .parent()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned());
+        let digest: Option<String> = match container_uuid {
+            Some(container_uuid) => {
+                self.storage.get(CONTAINERS_STORAGE_KEY, &container_uuid)?
+            }
+            None => None,
+        };
+
+        match digest {
+            // Bootstrapped containers refer to the base set
+            Some(digest)
+                if self.storage.exists(BLOBS_STORAGE_KEY, &digest)? =>
+            {
+                Some(Image::from_digest(self.storage, digest)?)
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores the config and the manifest of the image with
+    /// the `layer` on top. Returns the manifest digest.
+    #[fehler::throws]
+    fn store(
+        &self,
+        base: Option<&Image<'a, T>>,
+        runtime_config: &RuntimeConfig,
+        layer: Descriptor,
+    ) -> String {
+        let now = Local::now();
+        let mut config = match base {
+            Some(base) => base.config().clone(),
+            None => self.config(runtime_config),
+        };
+
+        config.created = Some(now);
+        config.author = self.author.clone().or(config.author);
+        config.rootfs.diff_ids.push(layer.digest.clone());
+        config.history.push(HistoryItem {
+            created: Some(now),
+            author: self.author.clone(),
+            created_by: None,
+            comment: self.message.clone(),
+            empty_layer: None,
+        });
+
+        let content = serde_json::to_vec(&config)?;
+        let config_digest = digest(&content);
+        let mut layers: Vec<_> = base
+            .map(|base| {
+                base.manifest()
+                    .layers
+                    .iter()
+                    .map(|layer| Descriptor {
+                        media_type: layer.media_type.clone(),
+                        digest: layer.digest.clone(),
+                        size: layer.size,
+                        urls: layer.urls.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        layers.push(layer);
+
+        let manifest = Manifest::new(
+            Descriptor {
+                media_type: media_type::OCI_CONFIG.into(),
+                digest: config_digest.clone(),
+                size: content.len(),
+                urls: None,
+            },
+            layers,
+        );
+        let manifest_digest = digest(&serde_json::to_vec(&manifest)?);
+
+        if let Some(healthcheck) =
+            base.map(Image::healthcheck).transpose()?.flatten()
+        {
+            self.storage.put(
+                HEALTHCHECKS_STORAGE_KEY,
+                &config_digest,
+                &healthcheck,
+            )?;
+        }
+
+        self.storage
+            .put(BLOBS_STORAGE_KEY, &config_digest, &config)?;
+        self.storage
+            .put(BLOBS_STORAGE_KEY, &manifest_digest, &manifest)?;
+
+        manifest_digest
+    }
+
+    /// Config of the image without a base, the process of
+    /// the container makes up its command.
+    fn config(&self, runtime_config: &RuntimeConfig) -> Config {
+        let os = runtime_config
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(OS_ANNOTATION))
+            .cloned()
+            .unwrap_or_else(|| "freebsd".into());
+        let container =
+            runtime_config.process.as_ref().map(|process| Container {
+                user: None,
+                exposed_ports: None,
+                env: process.env.clone(),
+                entrypoint: None,
+                cmd: process.args.clone(),
+                volumes: None,
+                working_dir: process.cwd.clone(),
+                labels: None,
+                stop_signal: None,
+            });
+
+        Config {
+            created: None,
+            author: None,
+            architecture: self.architecture.clone(),
+            os,
+            config: container,
+            rootfs: RootFs {
+                r#type: "layers".into(),
+                diff_ids: vec![],
+            },
+            history: vec![],
+        }
+    }
+}
+
+impl<W: Write> Write for Digester<W> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buffer)?;
+
+        self.context.update(&buffer[..written]);
+        self.size += written;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Changes of the `upper` tree compared to the `lower` one,
+/// parents are listed before their entries. Filesystems
+/// mounted in the `upper` tree, i.e. devfs of running
+/// containers, are skipped.
+#[fehler::throws]
+fn changes(lower: &Path, upper: &Path) -> Vec<Change> {
+    let mut changes = vec![];
+    let device = fs::symlink_metadata(upper)?.dev();
+
+    compare(lower, upper, device, Path::new(""), true, &mut changes)?;
+
+    changes
+}
+
+/// Compares the `relative` directory of both trees. The
+/// lower tree is only looked into `in_lower`, i.e. while
+/// its path is a directory too, so that links aren't
+/// followed.
+#[fehler::throws]
+fn compare(
+    lower: &Path,
+    upper: &Path,
+    device: u64,
+    relative: &Path,
+    in_lower: bool,
+    changes: &mut Vec<Change>,
+) {
+    let names = |root: &Path| -> io::Result<Vec<_>> {
+        let mut names = fs::read_dir(root.join(relative))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        names.sort();
+
+        Ok(names)
+    };
+    let upper_names = names(upper)?;
+
+    if in_lower {
+        for name in names(lower)? {
+            if !upper_names.contains(&name) {
+                changes.push(Change::Deleted(relative.join(name)));
+            }
+        }
+    }
+
+    for name in upper_names {
+        let path = relative.join(name);
+        let metadata = fs::symlink_metadata(upper.join(&path))?;
+
+        if metadata.dev() != device {
+            continue;
+        }
+
+        let original = if in_lower {
+            fs::symlink_metadata(lower.join(&path)).ok()
+        } else {
+            None
+        };
+        let same_type = original.as_ref().map_or(false, |original| {
+            original.file_type() == metadata.file_type()
+        });
+        let modified = match &original {
+            Some(original) if same_type => is_modified(
+                original,
+                &metadata,
+                &lower.join(&path),
+                &upper.join(&path),
+            )?,
+            _ => true,
+        };
+
+        if modified {
+            changes.push(Change::Added(path.clone()));
+        }
+
+        if metadata.is_dir() {
+            compare(lower, upper, device, &path, same_type, changes)?;
+        }
+    }
+}
+
+/// Whether the entry of the same type differs.
+#[fehler::throws(io::Error)]
+fn is_modified(
+    lower: &Metadata,
+    upper: &Metadata,
+    lower_path: &Path,
+    upper_path: &Path,
+) -> bool {
+    let metadata_differs = lower.mode() != upper.mode()
+        || lower.uid() != upper.uid()
+        || lower.gid() != upper.gid()
+        || lower.mtime() != upper.mtime()
+        || lower.mtime_nsec() != upper.mtime_nsec()
+        || lower.rdev() != upper.rdev();
+
+    if metadata_differs {
+        return true;
+    }
+
+    if upper.file_type().is_symlink() {
+        fs::read_link(lower_path)? != fs::read_link(upper_path)?
+    } else {
+        !upper.is_dir() && lower.size() != upper.size()
+    }
+}
+
+/// Writes the changes of `rootfs` to the staging folder.
+/// Parents of the changes are written as well, so that
+/// their metadata is kept on unpacking.
+#[fehler::throws]
+fn write_layer(rootfs: &Path, changes: &[Change], staging: &Path) -> Layer {
+    let mut digester = Digester {
+        writer: OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(staging.join("layer.tar"))?,
+        context: Context::new(&SHA256),
+        size: 0,
+    };
+    let mut archive = ArchiveWriter::new(&mut digester)?;
+    let mut written = HashSet::new();
+
+    for change in changes {
+        let path = match change {
+            Change::Added(path) | Change::Deleted(path) => path,
+        };
+        let mut parents: Vec<_> = path
+            .ancestors()
+            .skip(1)
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .collect();
+
+        parents.reverse();
+
+        for parent in parents {
+            if written.insert(parent.to_path_buf()) {
+                archive.append(&rootfs.join(parent), parent)?;
+            }
+        }
+
+        match change {
+            Change::Added(path) => {
+                if written.insert(path.clone()) {
+                    archive.append(&rootfs.join(path), path)?;
+                }
+            }
+            Change::Deleted(path) => archive.append_whiteout(path)?,
+        }
+    }
+
+    archive.finish()?;
+
+    let Digester {
+        writer: mut file,
+        context,
+        size,
+    } = digester;
+
+    file.seek(SeekFrom::Start(0))?;
+
+    Layer {
+        file,
+        digest: format!("sha256:{}", hex::encode(context.finish())),
+        size,
+    }
+}
+
+fn digest(content: &[u8]) -> String {
+    let mut context = Context::new(&SHA256);
+
+    context.update(content);
+
+    format!("sha256:{}", hex::encode(context.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TestStorage;
+    use std::process::Command;
+
+    #[test]
+    fn test_changes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let lower = tempdir.path().join("lower");
+        let upper = tempdir.path().join("upper");
+
+        fs::create_dir_all(lower.join("etc")).unwrap();
+        fs::write(lower.join("etc/hosts"), "localhost").unwrap();
+        fs::write(lower.join("etc/motd"), "Welcome").unwrap();
+        fs::write(lower.join("etc/rc.conf"), "").unwrap();
+        std::os::unix::fs::symlink("etc/motd", lower.join("motd")).unwrap();
+
+        Command::new("cp")
+            .arg("-Rp")
+            .arg(&lower)
+            .arg(&upper)
+            .status()
+            .expect("failed to copy the tree");
+
+        assert_eq!(changes(&lower, &upper).unwrap(), vec![]);
+
+        fs::write(upper.join("etc/motd"), "Welcome to knast").unwrap();
+        fs::remove_file(upper.join("etc/hosts")).unwrap();
+        fs::remove_file(upper.join("motd")).unwrap();
+        std::os::unix::fs::symlink("etc/rc.conf", upper.join("motd")).unwrap();
+        fs::create_dir_all(upper.join("root")).unwrap();
+        fs::write(upper.join("root/.profile"), "").unwrap();
+
+        let added = |path: &str| Change::Added(path.into());
+
+        assert_eq!(
+            changes(&lower, &upper).unwrap(),
+            vec![
+                added("etc"),
+                Change::Deleted("etc/hosts".into()),
+                added("etc/motd"),
+                added("motd"),
+                added("root"),
+                added("root/.profile"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replaced_directory() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let lower = tempdir.path().join("lower");
+        let upper = tempdir.path().join("upper");
+
+        fs::create_dir_all(lower.join("usr/lib")).unwrap();
+        fs::create_dir_all(upper.join("usr/lib")).unwrap();
+        std::os::unix::fs::symlink("usr/lib", lower.join("lib")).unwrap();
+        fs::create_dir_all(upper.join("lib")).unwrap();
+        fs::write(upper.join("lib/libc.so"), "").unwrap();
+
+        let changes = changes(&lower, &upper).unwrap();
+
+        // The link isn't followed
+        assert!(changes.contains(&Change::Added("lib".into())));
+        assert!(changes.contains(&Change::Added("lib/libc.so".into())));
+    }
+
+    #[test]
+    fn test_deleted_files_stay_deleted() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let committer = Committer::new(&storage);
+        let bootstrapped = tempdir.path().join("bootstrapped/rootfs");
+
+        fs::create_dir_all(bootstrapped.join("etc")).unwrap();
+        fs::write(bootstrapped.join("etc/motd"), "Welcome").unwrap();
+        fs::write(bootstrapped.join("etc/hosts"), "localhost").unwrap();
+
+        let base = committer
+            .commit(&config(&bootstrapped), &"knast/base:1".parse().unwrap())
+            .expect("failed to commit the base");
+
+        // Container of the base image, see `Committer::base`
+        let container_uuid = Uuid::new_v4().to_string();
+        let rootfs = tempdir.path().join(&container_uuid).join("rootfs");
+
+        storage
+            .put(CONTAINERS_STORAGE_KEY, &container_uuid, &base)
+            .unwrap();
+        Image::from_digest(&storage, base)
+            .unwrap()
+            .unpack(&rootfs, UnpackOptions::default())
+            .expect("failed to unpack the base");
+        fs::remove_file(rootfs.join("etc/hosts")).unwrap();
+
+        let committed = committer
+            .commit(&config(&rootfs), &"knast/committed:1".parse().unwrap())
+            .expect("failed to commit the container");
+        let unpacked = tempdir.path().join("unpacked");
+
+        Image::from_digest(&storage, committed)
+            .unwrap()
+            .unpack(&unpacked, UnpackOptions::default())
+            .expect("failed to unpack the committed image");
+
+        assert!(unpacked.join("etc/motd").exists());
+        assert!(!unpacked.join("etc/hosts").exists());
+    }
+
+    fn config(rootfs: &Path) -> RuntimeConfig {
+        RuntimeConfig {
+            root: Some(rootfs.into()),
+            ..RuntimeConfig::spec()
+        }
+    }
+}
@@ -1,41 +1,105 @@
-use std::{convert::TryFrom, fs, io::Read, path::PathBuf};
+mod variables;
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Error};
 use dockerfile_parser::{
-    Dockerfile as Containerfile, FromInstruction,
+    Dockerfile as Containerfile,
     Instruction::{self, *},
+    Span,
 };
 
 use futures::{
     channel::mpsc::{unbounded, SendError, UnboundedSender},
     future::{self, Future},
     stream::Stream,
-    SinkExt, TryFutureExt,
+    SinkExt,
 };
 
 use uuid::Uuid;
 
 use registratur::v2::{
-    client::Client,
-    domain::{config::Config, manifest::Manifest},
+    client::{Client, Registries},
+    domain::{
+        config::{Config, Healthcheck},
+        manifest::Manifest,
+    },
+    reference::{Reference, DEFAULT_REGISTRY},
 };
 
+use variables::Variables;
+
 use crate::{
-    fetcher::{Fetcher, LayerDownloadStatus},
+    archive::ExtractFlags,
+    build_cache::{self, BuildCache},
+    cancellation::{cancellable, CancellationToken, Cancelled},
+    fetcher::{DownloadOptions, Fetcher, LayerDownloadStatus},
+    platform::{display_list, Platform},
     runtime_config::RuntimeConfig,
-    storage::{Storage, StorageEngine, BLOBS_STORAGE_KEY},
+    signatures::SignaturePolicy,
+    storage::{
+        Storage, StorageEngine, BLOBS_STORAGE_KEY, CONTAINERS_FOLDER,
+        CONTAINERS_STORAGE_KEY, HEALTHCHECKS_STORAGE_KEY,
+    },
     unpacker::Unpacker,
 };
 
 #[derive(Clone, Debug)]
 pub enum EvaluationUpdate {
     From(LayerDownloadStatus),
+    /// Instruction is being executed. Instructions are
+    /// indexed across all the stages, in order.
+    StepStarted {
+        index: usize,
+        instruction: String,
+    },
+    StepFinished {
+        index: usize,
+        instruction: String,
+    },
+    /// Image layers are being unpacked to the root filesystem.
+    UnpackStarted {
+        layers: usize,
+    },
+    /// Layer `digest` is unpacked, `unpacked` of `layers`
+    /// so far.
+    UnpackProgress {
+        digest: String,
+        unpacked: usize,
+        layers: usize,
+    },
 }
 
 pub struct Builder<'a, T: StorageEngine> {
     fetcher: Fetcher<'a, T>,
     storage: &'a Storage<T>,
+    container_uuid: String,
     container_folder: PathBuf,
+    platforms: Vec<Platform>,
+    download_options: DownloadOptions,
+    registries: Option<&'a Registries>,
+    verify_on_read: bool,
+    unpack_parallelism: usize,
+    extract_flags: ExtractFlags,
+    no_cache: bool,
+    cancellation: Option<CancellationToken>,
+    build_args: HashMap<String, String>,
+    signature_policy: Option<&'a SignaturePolicy>,
+}
+
+/// Instruction, which variables are substituted.
+enum Step {
+    /// `FROM` instruction, along with the image.
+    From(String),
+    /// Instruction, which only declares variables.
+    Declaration,
+    Unhandled(Instruction),
 }
 
 impl<'a, T: StorageEngine> Builder<'a, T> {
@@ -45,58 +109,221 @@ impl<'a, T: StorageEngine> Builder<'a, T> {
         architecture: String,
         os: Vec<String>,
         storage: &'a Storage<T>,
+        download_options: DownloadOptions,
+        registries: Option<&'a Registries>,
     ) -> Self {
-        let client = Client::build(registry_url)?;
-        let fetcher = Fetcher::new(storage, client, architecture, os);
+        let client = build_client(registry_url, registries)?;
+        let platforms = os
+            .iter()
+            .map(|os| Platform::new(os, architecture.as_str()))
+            .collect();
+        let fetcher = Fetcher::new(storage, client, architecture, os)
+            .with_download_options(download_options);
         let container_uuid = format!("{}", Uuid::new_v4());
-        let container_folder =
-            storage.folder().join("containers").join(&container_uuid);
+        let container_folder = storage
+            .folder()
+            .join(CONTAINERS_FOLDER)
+            .join(&container_uuid);
         fs::create_dir_all(&container_folder)?;
 
         Self {
             fetcher,
+            container_uuid,
             container_folder,
             storage,
+            platforms,
+            download_options,
+            registries,
+            verify_on_read: false,
+            unpack_parallelism: 1,
+            extract_flags: ExtractFlags::default(),
+            no_cache: false,
+            cancellation: None,
+            build_args: HashMap::new(),
+            signature_policy: None,
+        }
+    }
+
+    /// See [`Fetcher::with_platforms`].
+    pub fn with_platforms(self, platforms: Vec<Platform>) -> Self {
+        Self {
+            fetcher: self.fetcher.with_platforms(platforms.clone()),
+            platforms,
+            ..self
+        }
+    }
+
+    /// See [`Unpacker::with_verify_on_read`].
+    pub fn with_verify_on_read(self, verify_on_read: bool) -> Self {
+        Self {
+            verify_on_read,
+            ..self
+        }
+    }
+
+    /// See [`Unpacker::with_parallelism`].
+    pub fn with_unpack_parallelism(self, unpack_parallelism: usize) -> Self {
+        Self {
+            unpack_parallelism,
+            ..self
+        }
+    }
+
+    /// See [`Unpacker::with_extract_flags`].
+    pub fn with_extract_flags(self, extract_flags: ExtractFlags) -> Self {
+        Self {
+            extract_flags,
+            ..self
+        }
+    }
+
+    /// Redoes all the steps, ignoring the build cache.
+    /// References are resolved against the registry again.
+    pub fn with_no_cache(self, no_cache: bool) -> Self {
+        Self {
+            fetcher: self.fetcher.with_refresh(no_cache),
+            no_cache,
+            ..self
+        }
+    }
+
+    /// Aborts the build, once the token is cancelled. The
+    /// container folder is removed then.
+    pub fn with_cancellation(self, token: CancellationToken) -> Self {
+        Self {
+            fetcher: self.fetcher.with_cancellation(token.clone()),
+            cancellation: Some(token),
+            ..self
+        }
+    }
+
+    /// Values of `ARG`s, which take precedence over their
+    /// defaults.
+    pub fn with_build_args(self, build_args: HashMap<String, String>) -> Self {
+        Self { build_args, ..self }
+    }
+
+    /// Verifies signatures of `FROM` images, see
+    /// [`Fetcher::with_signature_policy`]. Cached results of
+    /// `FROM`s are ignored then.
+    pub fn with_signature_policy(self, policy: &'a SignaturePolicy) -> Self {
+        let signature_policy = Some(policy).filter(|item| item.is_enabled());
+
+        Self {
+            fetcher: self.fetcher.with_signature_policy(policy),
+            signature_policy,
+            ..self
         }
     }
 
     #[fehler::throws]
     pub fn interpret(
         &self,
-        file: impl Read,
+        mut file: impl Read,
     ) -> (
         impl Stream<Item = EvaluationUpdate>,
         impl Future<Output = Result<PathBuf, Error>> + '_,
     ) {
         let (sender, receiver) = unbounded();
 
-        let containerfile = Containerfile::from_reader(file)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
 
-        let result = containerfile.iter_stages().flat_map(|stage| {
-            stage.instructions.into_iter().map(|instruction| {
-                self.execute_instruction(instruction.clone(), sender.clone())
-            })
-        });
+        let containerfile = Containerfile::parse(&content)?;
+
+        let mut variables = Variables::new(&self.build_args);
+        let mut result = vec![];
+
+        // Variables are substituted upfront, since steps are
+        // executed concurrently
+        let instructions = containerfile.instructions.iter().enumerate();
+
+        for (index, instruction) in instructions {
+            let text = instruction_text(&content, instruction);
+            let arguments = text
+                .splitn(2, char::is_whitespace)
+                .nth(1)
+                .unwrap_or_default();
+            let step = match instruction {
+                From(_) => Step::From(variables.from(arguments)?),
+                Arg(_) => {
+                    variables.arg(arguments)?;
+
+                    Step::Declaration
+                }
+                Env(_) => {
+                    variables.env(arguments)?;
+
+                    Step::Unhandled(instruction.clone())
+                }
+                _ => Step::Unhandled(instruction.clone()),
+            };
+
+            result.push(self.execute_step(index, text, step, sender.clone()));
+        }
+
+        for name in variables.unused() {
+            log::warn!("Build arg {} is not declared by any ARG", name);
+        }
 
         let folder = self.container_folder.clone();
 
-        let completion_future =
-            future::try_join_all(result).and_then(|_| future::ok(folder));
+        let steps = future::try_join_all(result);
+
+        let completion_future = async move {
+            let result = cancellable(self.cancellation.as_ref(), steps).await;
+
+            match result {
+                Ok(_) => Ok(folder),
+                Err(error) => {
+                    if error.is::<Cancelled>() {
+                        remove_container_folder(&folder);
+                    }
+
+                    Err(error)
+                }
+            }
+        };
 
         (receiver, completion_future)
     }
 
+    /// Executes the instruction, reporting when it starts and
+    /// finishes.
+    #[fehler::throws]
+    async fn execute_step(
+        &self,
+        index: usize,
+        text: String,
+        step: Step,
+        sender: UnboundedSender<EvaluationUpdate>,
+    ) {
+        // Updates are dropped, if no one listens to them
+        let _ = sender.unbounded_send(EvaluationUpdate::StepStarted {
+            index,
+            instruction: text.clone(),
+        });
+
+        self.execute_instruction(step, sender.clone()).await?;
+
+        let _ = sender.unbounded_send(EvaluationUpdate::StepFinished {
+            index,
+            instruction: text,
+        });
+    }
+
     #[fehler::throws]
     async fn execute_instruction(
         &self,
-        instruction: Instruction,
+        step: Step,
         sender: UnboundedSender<EvaluationUpdate>,
     ) {
-        match instruction {
-            From(instruction) => {
-                self.execute_from_instruction(instruction, sender).await?;
+        match step {
+            Step::From(image) => {
+                self.execute_from_instruction(&image, sender).await?;
             }
-            _ => {
+            Step::Declaration => (),
+            Step::Unhandled(instruction) => {
                 log::warn!(
                     "Unhandled containerfile instruction {:?}",
                     instruction
@@ -108,25 +335,77 @@ impl<'a, T: StorageEngine> Builder<'a, T> {
     #[fehler::throws]
     async fn execute_from_instruction(
         &self,
-        instruction: FromInstruction,
+        image: &str,
         sender: UnboundedSender<EvaluationUpdate>,
     ) {
-        let image = &instruction.image_parsed;
+        let reference: Reference = image
+            .parse()
+            .with_context(|| format!("Invalid FROM image {}", image))?;
+        let step =
+            format!("FROM {} {}", reference, display_list(&self.platforms));
+        let cache_key = build_cache::key(None, &step, &[]);
+        let cache = BuildCache::new(self.storage);
+        let updates = sender.clone();
 
         let sender = sender.with(|val| {
             future::ok::<_, SendError>(EvaluationUpdate::From(val))
         });
 
-        let default_tag = String::from("latest");
-        let tag = image.tag.as_ref().unwrap_or(&default_tag);
+        // Images naming a registry explicitly are pulled from
+        // it, rather than from the one the builder is set up
+        // with.
+        let cached = if self.no_cache || self.signature_policy.is_some() {
+            None
+        } else {
+            cache.get(&cache_key)?
+        };
+
+        let digest = if let Some(digest) = cached {
+            log::info!("Using cached result of {}", step);
+
+            digest
+        } else if reference.registry == DEFAULT_REGISTRY {
+            self.fetcher.fetch(&reference, sender).await?
+        } else {
+            let registry_url = reference.registry_url();
+            let client = build_client(&registry_url, self.registries)?;
+
+            // Platforms override the architecture and OSes
+            let mut fetcher =
+                Fetcher::new(self.storage, client, String::new(), vec![])
+                    .with_platforms(self.platforms.clone())
+                    .with_download_options(self.download_options)
+                    .with_refresh(self.no_cache);
+
+            if let Some(token) = &self.cancellation {
+                fetcher = fetcher.with_cancellation(token.clone());
+            }
 
-        let digest = self.fetcher.fetch(&image.image, &tag, sender).await?;
+            if let Some(policy) = self.signature_policy {
+                fetcher = fetcher.with_signature_policy(policy);
+            }
+
+            fetcher.fetch(&reference, sender).await?
+        };
+
+        cache.put(&cache_key, &digest)?;
+
+        // Blobs of the image are kept while the container
+        // exists, see `gc::prune`
+        self.storage.put(
+            CONTAINERS_STORAGE_KEY,
+            &self.container_uuid,
+            &digest,
+        )?;
 
         let manifest: Manifest =
             self.storage.get(BLOBS_STORAGE_KEY, &digest)?.context(
                 "Fetched manifest was not found. Possible storage corruption",
             )?;
 
+        let healthcheck: Option<Healthcheck> = self
+            .storage
+            .get(HEALTHCHECKS_STORAGE_KEY, &manifest.config.digest)?;
         let config: Config = self
             .storage
             .get(BLOBS_STORAGE_KEY, manifest.config.digest)?
@@ -135,13 +414,35 @@ impl<'a, T: StorageEngine> Builder<'a, T> {
             )?;
 
         let destination = self.container_folder.join("rootfs");
-
-        let unpacker = Unpacker::new(&self.storage, &destination);
+        let layers = manifest.layers.len();
+
+        let _ =
+            updates.unbounded_send(EvaluationUpdate::UnpackStarted { layers });
+
+        let mut unpacker = Unpacker::new(&self.storage, &destination)
+            .with_verify_on_read(self.verify_on_read)
+            .with_parallelism(self.unpack_parallelism)
+            .with_extract_flags(self.extract_flags)
+            .with_progress(|unpacked, digest| {
+                let _ =
+                    updates.unbounded_send(EvaluationUpdate::UnpackProgress {
+                        digest: digest.into(),
+                        unpacked,
+                        layers,
+                    });
+            });
+
+        if let Some(token) = &self.cancellation {
+            unpacker = unpacker.with_cancellation(token.clone());
+        }
 
         unpacker.unpack(digest)?;
 
         let runtime_config =
-            RuntimeConfig::try_from((config, destination.as_path()))?;
+            RuntimeConfig::try_from((config, destination.as_path()))?
+                .with_healthcheck(healthcheck.as_ref())?;
+
+        runtime_config.create_volumes()?;
 
         serde_json::to_writer(
             fs::File::create(&self.container_folder.join("config.json"))?,
@@ -150,6 +451,51 @@ impl<'a, T: StorageEngine> Builder<'a, T> {
     }
 }
 
+/// Registry client, with `registries` settings if they are
+/// set explicitly.
+#[fehler::throws]
+pub(crate) fn build_client<'a>(
+    registry_url: &'a str,
+    registries: Option<&Registries>,
+) -> Client<'a> {
+    let client = Client::build(registry_url)?;
+
+    match registries {
+        Some(registries) => client.with_registries(registries)?,
+        None => client,
+    }
+}
+
+/// Removes the folder of the aborted build. Failures are
+/// only logged, since the build has failed anyway.
+fn remove_container_folder(folder: &Path) {
+    if let Err(error) = fs::remove_dir_all(folder) {
+        log::warn!(
+            "Failed to remove container folder {}: {}",
+            folder.display(),
+            error
+        );
+    }
+}
+
+/// Source text of the instruction, as it's written in the
+/// containerfile.
+fn instruction_text(content: &str, instruction: &Instruction) -> String {
+    let Span { start, end } = match instruction {
+        From(instruction) => instruction.span,
+        Arg(instruction) => instruction.span,
+        Label(instruction) => instruction.span,
+        Run(instruction) => instruction.span,
+        Entrypoint(instruction) => instruction.span,
+        Cmd(instruction) => instruction.span,
+        Copy(instruction) => instruction.span,
+        Env(instruction) => instruction.span,
+        Misc(instruction) => instruction.span,
+    };
+
+    content.get(start..end).unwrap_or_default().trim().into()
+}
+
 #[cfg(test)]
 mod tests {
     use futures::StreamExt;
@@ -169,16 +515,27 @@ mod tests {
         let storage =
             Storage::new(tempdir.path()).expect("Unable to initialize cache");
 
-        let builder =
-            Builder::new(&url, "amd64".into(), vec!["linux".into()], &storage)
-                .expect("failed to initialize the builder");
+        let builder = Builder::new(
+            &url,
+            "amd64".into(),
+            vec!["linux".into()],
+            &storage,
+            DownloadOptions::default(),
+            None,
+        )
+        .expect("failed to initialize the builder")
+        .with_build_args(
+            vec![("VERSION".into(), "1.17.10".into())]
+                .into_iter()
+                .collect(),
+        );
 
         let containerfile = test_helpers::fixture!("containerfile");
 
         let (updates, complete_future) =
             builder.interpret(containerfile.as_bytes()).unwrap();
 
-        let (_, result) =
+        let (updates, result) =
             future::join(updates.collect::<Vec<_>>(), complete_future).await;
 
         let container_folder =
@@ -195,5 +552,35 @@ mod tests {
         let command = config.process.unwrap().args.unwrap().join(" ");
 
         assert_eq!(command, "nginx -g daemon off;");
+
+        let mut steps: Vec<_> = updates
+            .iter()
+            .filter_map(|update| match update {
+                EvaluationUpdate::StepFinished { index, instruction } => {
+                    Some((*index, instruction.as_str()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        // Steps of a stage run concurrently
+        steps.sort();
+
+        assert_eq!(
+            steps,
+            vec![
+                (0, "ARG VERSION=latest"),
+                (1, "FROM nginx:${VERSION}"),
+                (2, "ENV FOO=/bar"),
+                (3, "WORKDIR ${FOO}"),
+                (4, "CMD /bin/sleep 42"),
+            ]
+        );
+
+        let unpacked = updates.iter().filter(|update| {
+            matches!(update, EvaluationUpdate::UnpackProgress { .. })
+        });
+
+        assert!(unpacked.count() > 0);
     }
 }
@@ -0,0 +1,326 @@
+use std::{
+    collections::{HashMap, HashSet},
+    iter::Peekable,
+    str::Chars,
+};
+
+use anyhow::{anyhow, Error};
+
+type Scope = HashMap<String, String>;
+
+/// Variables in scope of containerfile instructions, see
+/// https://docs.docker.com/engine/reference/builder/#environment-replacement
+///
+/// `ARG`s declared before the first `FROM` are in scope of
+/// `FROM` instructions only. Stages redeclare them to use
+/// them, `ENV`s take precedence over `ARG`s of the stage.
+pub(super) struct Variables<'a> {
+    build_args: &'a HashMap<String, String>,
+    global: Scope,
+    stage: Option<Stage>,
+    /// Build args, which no `ARG` declares so far.
+    unused: HashSet<&'a str>,
+}
+
+#[derive(Default)]
+struct Stage {
+    args: Scope,
+    env: Scope,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new(build_args: &'a HashMap<String, String>) -> Self {
+        Self {
+            build_args,
+            global: Scope::new(),
+            stage: None,
+            unused: build_args.keys().map(String::as_str).collect(),
+        }
+    }
+
+    /// Starts a new stage. Returns the image of the `FROM`
+    /// instruction, given its `arguments`.
+    #[fehler::throws]
+    pub fn from(&mut self, arguments: &str) -> String {
+        let words = expand_words(arguments, &self.global)?;
+
+        self.stage = Some(Stage::default());
+
+        words
+            .into_iter()
+            .find(|word| !word.starts_with("--"))
+            .ok_or_else(|| anyhow!("FROM {} lacks the image", arguments))?
+    }
+
+    /// Declares variables of `ARG` instruction, given its
+    /// `arguments`. Build args take precedence over defaults.
+    #[fehler::throws]
+    pub fn arg(&mut self, arguments: &str) {
+        for word in expand_words(arguments, &self.scope())? {
+            let (name, default): (String, Option<String>) =
+                match word.find('=') {
+                    Some(index) => {
+                        (word[..index].into(), Some(word[index + 1..].into()))
+                    }
+                    None => (word, None),
+                };
+            let value = match self.build_args.get(&name) {
+                Some(value) => Some(value.clone()),
+                // Stages inherit defaults of global args
+                None if self.stage.is_some() => {
+                    default.or_else(|| self.global.get(&name).cloned())
+                }
+                None => default,
+            };
+
+            self.unused.remove(name.as_str());
+
+            if let Some(value) = value {
+                match &mut self.stage {
+                    Some(stage) => stage.args.insert(name, value),
+                    None => self.global.insert(name, value),
+                };
+            }
+        }
+    }
+
+    /// Sets variables of `ENV` instruction, given its
+    /// `arguments`. Both `ENV KEY=value ...` and legacy
+    /// `ENV KEY value` forms are supported.
+    #[fehler::throws]
+    pub fn env(&mut self, arguments: &str) {
+        let mut words = expand_words(arguments, &self.scope())?.into_iter();
+        let stage = self
+            .stage
+            .as_mut()
+            .ok_or_else(|| anyhow!("ENV {} precedes FROM", arguments))?;
+
+        match words.next() {
+            Some(word) if !word.contains('=') => {
+                let value = words.collect::<Vec<_>>().join(" ");
+
+                stage.env.insert(word, value);
+            }
+            Some(word) => {
+                for word in Some(word).into_iter().chain(words) {
+                    let index = word.find('=').unwrap_or_else(|| word.len());
+                    let value = word.get(index + 1..).unwrap_or_default();
+
+                    stage.env.insert(word[..index].into(), value.into());
+                }
+            }
+            None => fehler::throw!(anyhow!("ENV lacks variables")),
+        }
+    }
+
+    /// Build args, which no `ARG` declares.
+    pub fn unused(&self) -> Vec<&str> {
+        let mut result: Vec<_> = self.unused.iter().copied().collect();
+
+        result.sort();
+
+        result
+    }
+
+    fn scope(&self) -> Scope {
+        match &self.stage {
+            Some(stage) => {
+                let mut scope = stage.args.clone();
+
+                scope.extend(stage.env.clone());
+
+                scope
+            }
+            None => self.global.clone(),
+        }
+    }
+}
+
+/// Splits `text` into words, substituting variables and
+/// removing quotes, the way shell does. Variables aren't
+/// substituted within single quotes.
+#[fehler::throws]
+fn expand_words(text: &str, scope: &Scope) -> Vec<String> {
+    let text = text.replace("\\\n", "");
+    let mut chars = text.chars().peekable();
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\'', None) | ('"', None) => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (c, Some(quoted)) if c == quoted => quote = None,
+            ('\\', Some('\'')) => word.push(c),
+            ('\\', _) => {
+                word.extend(chars.next());
+                in_word = true;
+            }
+            ('$', quoted) if quoted != Some('\'') => {
+                word.push_str(&substitute(&mut chars, scope)?);
+                in_word = true;
+            }
+            (c, None) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (c, _) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        fehler::throw!(anyhow!("Unterminated quote in {}", text));
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Value of the variable following `$`, either `$NAME` or
+/// `${NAME}`, `${NAME:-default}`, `${NAME:+alternative}`.
+#[fehler::throws]
+fn substitute(chars: &mut Peekable<Chars<'_>>, scope: &Scope) -> String {
+    if chars.peek() != Some(&'{') {
+        let mut name = String::new();
+
+        while let Some(&c) = chars.peek() {
+            if !is_name_char(c) {
+                break;
+            }
+
+            name.push(c);
+            chars.next();
+        }
+
+        if name.is_empty() {
+            return "$".into();
+        }
+
+        return scope.get(&name).cloned().unwrap_or_default();
+    }
+
+    chars.next();
+
+    let mut expression = String::new();
+    let mut depth = 1;
+
+    loop {
+        let c = chars.next().ok_or_else(|| {
+            anyhow!("Unterminated substitution ${{{}", expression)
+        })?;
+
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => break,
+            '}' => depth -= 1,
+            _ => (),
+        }
+
+        expression.push(c);
+    }
+
+    let (name, modifier) = match expression.find(':') {
+        Some(index) => expression.split_at(index),
+        None => (expression.as_str(), ""),
+    };
+
+    if name.is_empty() || !name.chars().all(is_name_char) {
+        fehler::throw!(anyhow!("Bad substitution ${{{}}}", expression));
+    }
+
+    let value = scope.get(name).filter(|value| !value.is_empty());
+
+    if modifier.is_empty() {
+        return value.cloned().unwrap_or_default();
+    }
+
+    let word = modifier.get(2..).unwrap_or_default();
+
+    match (modifier.get(..2).unwrap_or_default(), value) {
+        (":-", Some(value)) => value.clone(),
+        (":-", None) => expand_words(word, scope)?.join(" "),
+        (":+", Some(_)) => expand_words(word, scope)?.join(" "),
+        (":+", None) => String::new(),
+        _ => fehler::throw!(anyhow!("Bad substitution ${{{}}}", expression)),
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_args(args: &[(&str, &str)]) -> HashMap<String, String> {
+        args.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_expand_words() {
+        let scope = build_args(&[("TAG", "1.17"), ("EMPTY", "")]);
+        let expand = |text: &str| expand_words(text, &scope).unwrap();
+
+        assert_eq!(expand("nginx:$TAG"), vec!["nginx:1.17"]);
+        assert_eq!(expand("nginx:${TAG}-alpine"), vec!["nginx:1.17-alpine"]);
+        assert_eq!(expand("${MISSING:-a b} c"), vec!["a b", "c"]);
+        assert_eq!(expand("${EMPTY:-${TAG}}"), vec!["1.17"]);
+        assert_eq!(expand("${TAG:+set}${MISSING:+set}"), vec!["set"]);
+        assert_eq!(
+            expand(r#"'$TAG' "$TAG x" \$TAG"#),
+            vec!["$TAG", "1.17 x", "$TAG"]
+        );
+        assert_eq!(expand("a \\\n b"), vec!["a", "b"]);
+        assert!(expand_words("${TAG", &scope).is_err());
+        assert!(expand_words("${TAG:?error}", &scope).is_err());
+        assert!(expand_words("'$TAG", &scope).is_err());
+    }
+
+    #[test]
+    fn test_scopes() {
+        let args = build_args(&[("TAG", "1.17"), ("UNUSED", "")]);
+        let mut variables = Variables::new(&args);
+
+        variables.arg("TAG=latest IMAGE=nginx").unwrap();
+        variables.arg("VERSION").unwrap();
+        assert_eq!(
+            variables
+                .from("--platform=linux $IMAGE:$TAG AS base")
+                .unwrap(),
+            "nginx:1.17"
+        );
+
+        // Global args are redeclared to be used in the stage
+        assert!(variables.scope().is_empty());
+        variables.arg("IMAGE").unwrap();
+        variables.arg("DIR=/${IMAGE}").unwrap();
+        variables.env("DIR=$DIR/html PORT=80").unwrap();
+        variables.arg("PORT=8080").unwrap();
+        variables.env("GREETING hello $IMAGE").unwrap();
+
+        let scope = variables.scope();
+        assert_eq!(scope["IMAGE"], "nginx");
+        assert_eq!(scope["DIR"], "/nginx/html");
+        assert_eq!(scope["PORT"], "80");
+        assert_eq!(scope["GREETING"], "hello nginx");
+        assert_eq!(variables.unused(), vec!["UNUSED"]);
+
+        assert_eq!(variables.from("${IMAGE}").unwrap(), "nginx");
+        assert!(variables.scope().is_empty());
+    }
+}
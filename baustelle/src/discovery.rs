@@ -0,0 +1,37 @@
+//! Image discovery helpers, i.e. for listing tags of an
+//! image or searching a registry.
+//!
+//! Helpers take the client of the registry, so that the
+//! configured mirrors, TLS settings and credentials apply,
+//! see [`Client::with_registries`] and
+//! [`Client::with_credentials`].
+use anyhow::Error;
+use registratur::v2::{
+    client::Client,
+    domain::{catalog::Catalog, tags::TagList},
+    reference::Reference,
+};
+
+/// Tags and repositories are listed in pages of this size.
+const PAGE_SIZE: usize = 100;
+
+/// Lists tags of the image repository. The `client` is
+/// expected to be the one of the registry the `reference`
+/// names.
+#[fehler::throws]
+pub async fn tags(client: &Client<'_>, reference: &Reference) -> Vec<String> {
+    TagList::pull(client, &reference.repository, Some(PAGE_SIZE)).await?
+}
+
+/// Lists repositories of the `client`'s registry containing
+/// `query` in their name. Registries might not expose the
+/// catalog, or expose only repositories the credentials
+/// grant access to. Docker Hub doesn't expose it at all.
+#[fehler::throws]
+pub async fn search(client: &Client<'_>, query: &str) -> Vec<String> {
+    Catalog::pull(client, Some(PAGE_SIZE))
+        .await?
+        .into_iter()
+        .filter(|repository| repository.contains(query))
+        .collect()
+}
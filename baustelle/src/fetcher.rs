@@ -1,25 +1,35 @@
 use std::sync::Arc;
 
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
+use common_lib::metrics;
 use futures::{
     executor::block_on,
-    future::{self, TryFutureExt},
+    future,
     sink::{Sink, SinkExt},
-    stream::{FuturesUnordered, TryStreamExt},
+    stream::{self, StreamExt, TryStreamExt},
 };
 use registratur::v2::{
     client::Client,
     domain::{
         config::Config,
-        layer::Layer,
+        image_manifest::ImageManifest,
+        layer::{Layer, LayerSink},
         manifest::Manifest,
-        manifest_index::{ManifestIndex, Platform},
+        manifest_index::ManifestIndex,
+        media_type,
     },
+    reference::Reference,
 };
 
+use super::cancellation::{cancellable, CancellationToken, Cancelled};
+use super::platform::{display_list, Platform};
+use super::signatures::SignaturePolicy;
 use super::storage::{
-    Storage, StorageEngine, BLOBS_STORAGE_KEY, IMAGES_INDEX_STORAGE_KEY,
+    audit::{self, Action},
+    Storage, StorageEngine, BLOBS_STORAGE_KEY, HEALTHCHECKS_STORAGE_KEY,
+    IMAGES_INDEX_STORAGE_KEY,
 };
+use super::throttle::Throttle;
 
 /// Represents layer download update.
 #[derive(Clone, Debug)]
@@ -28,12 +38,39 @@ pub enum LayerDownloadStatus {
     InProgress(Arc<String>, usize, usize),
 }
 
+/// Indexes might refer to other indexes, but not
+/// indefinitely.
+const MAX_INDEX_DEPTH: usize = 4;
+
+/// Limits on layer downloads.
+#[derive(Clone, Copy, Debug)]
+pub struct DownloadOptions {
+    /// Layers downloaded concurrently.
+    pub max_parallel_downloads: usize,
+    /// Total download rate, unlimited if not set.
+    pub bytes_per_second: Option<u64>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            max_parallel_downloads: 3,
+            bytes_per_second: None,
+        }
+    }
+}
+
 pub struct Fetcher<'a, T: StorageEngine> {
     storage: &'a Storage<T>,
     client: Client<'a>,
-    architecture: String,
-    os: Vec<String>, /* We support Linux & FreeBSD containers running
-                      * alongside */
+    /// Platforms acceptable, most preferred first. We
+    /// support Linux & FreeBSD containers running alongside.
+    platforms: Vec<Platform>,
+    max_parallel_downloads: usize,
+    throttle: Option<Throttle>,
+    refresh: bool,
+    cancellation: Option<CancellationToken>,
+    signature_policy: Option<&'a SignaturePolicy>,
 }
 
 impl<'a, T: StorageEngine> Fetcher<'a, T> {
@@ -46,8 +83,58 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
         Self {
             storage,
             client,
-            architecture,
-            os,
+            platforms: os
+                .iter()
+                .map(|os| Platform::new(os, architecture.as_str()))
+                .collect(),
+            max_parallel_downloads: DownloadOptions::default()
+                .max_parallel_downloads,
+            throttle: None,
+            refresh: false,
+            cancellation: None,
+            signature_policy: None,
+        }
+    }
+
+    /// Aborts the fetch, once the token is cancelled. Partial
+    /// layers of the image are removed then, rather than
+    /// kept to be resumed.
+    pub fn with_cancellation(self, token: CancellationToken) -> Self {
+        Self {
+            cancellation: Some(token),
+            ..self
+        }
+    }
+
+    /// Overrides the platforms derived from the architecture
+    /// and OSes, i.e. to pull amd64 images on arm64 hosts.
+    /// Platforms are tried in order, the first one available
+    /// in the image index wins.
+    pub fn with_platforms(self, platforms: Vec<Platform>) -> Self {
+        Self { platforms, ..self }
+    }
+
+    /// Resolves references against the registry, even if
+    /// the image is pulled already. Blobs are still reused.
+    pub fn with_refresh(self, refresh: bool) -> Self {
+        Self { refresh, ..self }
+    }
+
+    /// Verifies image signatures against the policy, before
+    /// any layer is pulled. Pulled images are resolved again
+    /// then, so that their signatures are verified each time.
+    pub fn with_signature_policy(self, policy: &'a SignaturePolicy) -> Self {
+        Self {
+            signature_policy: Some(policy).filter(|item| item.is_enabled()),
+            ..self
+        }
+    }
+
+    pub fn with_download_options(self, options: DownloadOptions) -> Self {
+        Self {
+            max_parallel_downloads: options.max_parallel_downloads.max(1),
+            throttle: options.bytes_per_second.map(Throttle::new),
+            ..self
         }
     }
 
@@ -61,7 +148,7 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
     ///
     /// ```rust,no_run
     /// use futures::{future, stream::StreamExt};
-    /// use registratur::v2::client::Client;
+    /// use registratur::v2::{client::Client, reference::Reference};
     /// use baustelle::{fetcher::{Fetcher, LayerDownloadStatus::*}, storage::Storage};
     ///
     /// let storage =
@@ -73,9 +160,10 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
     /// let os = vec!["linux".into(), "freebsd".into()];
     /// let fetcher = Fetcher::new(&storage, client, architecture.into(), os);
     /// let (tx, rx) = futures::channel::mpsc::channel(1);
+    /// let reference: Reference = "nginx:1.17.10".parse().unwrap();
     ///
     /// async {
-    ///     let digest_fut = fetcher.fetch("nginx", "1.17.10", tx);
+    ///     let digest_fut = fetcher.fetch(&reference, tx);
     ///     let updates_fut = rx.collect::<Vec<_>>();
     ///
     ///     let (digest, updates) = future::join(digest_fut, updates_fut).await;
@@ -92,83 +180,190 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
     #[fehler::throws]
     pub async fn fetch(
         &self,
-        image: &str,
-        tag: &str,
+        reference: &Reference,
         updates_sub: impl Sink<LayerDownloadStatus> + Clone + Unpin + Send,
     ) -> String {
-        let image_name = normalize_image_name(image);
-        let cache_key = &format!("{}:{}", image_name, tag)[..];
+        let _timer = metrics::timer("knast_image_pull_duration_seconds", &[]);
+        let result = cancellable(
+            self.cancellation.as_ref(),
+            self.do_fetch(reference, updates_sub),
+        )
+        .await;
+        let status = match &result {
+            Ok(_) => "success",
+            Err(error) if error.is::<Cancelled>() => "cancelled",
+            Err(_) => "failure",
+        };
 
-        if let Some(digest) =
-            self.storage.get(IMAGES_INDEX_STORAGE_KEY, cache_key)?
+        metrics::increment("knast_image_pulls_total", &[("result", status)]);
+
+        let error = result.as_ref().err().map(ToString::to_string);
+        let target = reference.to_string();
+
+        if let Err(error) =
+            audit::record(self.storage, Action::ImagePull, &target, error)
         {
-            return digest;
-        };
+            log::error!("Failed to audit the pull of {}: {}", target, error);
+        }
 
-        let digest = self.resolve_manifest_digest(&image_name, tag).await?;
-
-        self.fetch_manifest(&image_name, &digest)
-            .and_then(|manifest| {
-                let layers: FuturesUnordered<_> = manifest
-                    .layers
-                    .into_iter()
-                    .map(|layer| {
-                        self.fetch_layer(
-                            &image_name,
-                            layer.digest,
-                            layer.size,
-                            updates_sub.clone(),
-                        )
-                    })
-                    .collect();
+        result?
+    }
 
-                let config =
-                    self.fetch_config(&image_name, manifest.config.digest);
+    #[fehler::throws]
+    async fn do_fetch(
+        &self,
+        reference: &Reference,
+        updates_sub: impl Sink<LayerDownloadStatus> + Clone + Unpin + Send,
+    ) -> String {
+        let image_name = &reference.repository;
+        let cache_key = reference.to_string();
+
+        if !self.refresh && self.signature_policy.is_none() {
+            if let Some(digest) =
+                self.storage.get(IMAGES_INDEX_STORAGE_KEY, &cache_key)?
+            {
+                return digest;
+            };
+        }
 
-                future::try_join(config, layers.try_collect::<Vec<_>>())
-            })
+        let (digest, manifest) = self
+            .resolve_manifest(image_name, reference.reference())
             .await?;
+        let manifest = match manifest {
+            // Served right away, i.e. the reference is pinned
+            // to the manifest digest
+            Some(manifest) => self.store_manifest(&digest, manifest)?,
+            None => self.fetch_manifest(image_name, &digest).await?,
+        };
+
+        let digests: Vec<_> = manifest
+            .layers
+            .iter()
+            .map(|layer| layer.digest.clone())
+            .collect();
+        let layers = stream::iter(manifest.layers)
+            .map(|layer| {
+                self.fetch_layer(
+                    image_name,
+                    layer.digest,
+                    layer.size,
+                    updates_sub.clone(),
+                )
+            })
+            .buffer_unordered(self.max_parallel_downloads);
+        let config = self.fetch_config(image_name, manifest.config.digest);
+
+        let downloads =
+            future::try_join(config, layers.try_collect::<Vec<_>>());
+
+        if let Err(error) =
+            cancellable(self.cancellation.as_ref(), downloads).await
+        {
+            if error.is::<Cancelled>() {
+                for digest in &digests {
+                    self.storage.blobs().remove_partial(digest)?;
+                }
+            }
+
+            fehler::throw!(error);
+        }
 
         self.storage
-            .put(IMAGES_INDEX_STORAGE_KEY, cache_key, &digest)?;
+            .put(IMAGES_INDEX_STORAGE_KEY, &cache_key, &digest)?;
         self.storage.flush().await?;
 
         digest
     }
 
+    /// Resolves the tag or digest to the digest of the
+    /// manifest for our platform. Reference might point
+    /// either to a manifest or to an index, which in turn
+    /// might point to other indexes. The manifest is
+    /// returned too, if it's been pulled along the way.
     #[fehler::throws]
-    async fn resolve_manifest_digest(
+    async fn resolve_manifest(
         &self,
         image_name: &str,
-        tag: &str,
-    ) -> String {
-        let Self {
-            client,
-            architecture,
-            os,
-            ..
-        } = self;
+        reference: &str,
+    ) -> (String, Option<Manifest>) {
+        let (mut image_manifest, mut digest) =
+            ImageManifest::pull(&self.client, image_name, reference)
+                .await
+                .context(format!("Failed to fetch manifest {}", image_name))?;
+
+        // Images are signed by the digest of the top-level
+        // manifest or index
+        if let Some(policy) = self.signature_policy {
+            policy.verify(&self.client, image_name, &digest).await?;
+        }
 
-        let manifests = ManifestIndex::pull(client, image_name, tag)
+        for _ in 0..MAX_INDEX_DEPTH {
+            let index = match image_manifest {
+                ImageManifest::Manifest(manifest) => {
+                    return (digest, Some(manifest))
+                }
+                ImageManifest::Index(index) => index,
+            };
+            let (platform_digest, nested) = self.select_manifest(&index)?;
+
+            if !nested {
+                return (platform_digest, None);
+            }
+
+            let (nested_manifest, nested_digest) = ImageManifest::pull(
+                &self.client,
+                image_name,
+                &platform_digest,
+            )
             .await
-            .context(format!("Failed to fetch manifest index {}", image_name))?
-            .manifests;
+            .context(format!(
+                "Failed to fetch manifest index {}",
+                platform_digest
+            ))?;
+
+            image_manifest = nested_manifest;
+            digest = nested_digest;
+        }
 
-        manifests
+        fehler::throw!(anyhow!("Too deeply nested index {}", image_name));
+    }
+
+    /// Finds the manifest for the most preferred platform
+    /// in the index. Returns its digest and whether it is an
+    /// index itself.
+    #[fehler::throws]
+    fn select_manifest(&self, index: &ManifestIndex) -> (String, bool) {
+        let manifest = self
+            .platforms
             .iter()
-            .find(|ref manifest| match &manifest.platform {
-                Some(Platform {
-                    architecture: img_arch,
-                    os: img_os,
-                    ..
-                }) => architecture == img_arch && os.contains(&img_os),
-                None => false,
+            .find_map(|platform| {
+                index.manifests.iter().find(|manifest| {
+                    manifest
+                        .platform
+                        .as_ref()
+                        .map_or(false, |other| platform.matches(other))
+                })
             })
-            .map(|manifest| manifest.descriptor.digest.clone())
-            .context(format!(
-                "Could not find the appropriate manifest for: {} ({:?})",
-                architecture, os,
-            ))?
+            .ok_or_else(|| {
+                let available: Vec<_> = index
+                    .manifests
+                    .iter()
+                    .filter_map(|manifest| manifest.platform.as_ref())
+                    .map(|platform| Platform::from(platform).to_string())
+                    .collect();
+
+                anyhow!(
+                    "Could not find the appropriate manifest for: {}. \
+                     Available platforms: {}",
+                    display_list(&self.platforms),
+                    available.join(", ")
+                )
+            })?;
+
+        (
+            manifest.descriptor.digest.clone(),
+            media_type::is_index(&manifest.descriptor.media_type),
+        )
     }
 
     #[fehler::throws]
@@ -179,13 +374,25 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
     ) -> Manifest {
         Manifest::pull(&self.client, image_name, digest)
             .await
-            .and_then(|item| self.storage.put(BLOBS_STORAGE_KEY, digest, item))
+            .and_then(|item| self.store_manifest(digest, item))
             .context(format!(
                 "Failed to fetch manifest {} {}",
                 image_name, digest
             ))?
     }
 
+    #[fehler::throws]
+    fn store_manifest(&self, digest: &str, manifest: Manifest) -> Manifest {
+        // Artifacts other than images share the format
+        let config_type = manifest.config.media_type.as_str();
+
+        if !media_type::CONFIGS.contains(&config_type) {
+            anyhow::bail!("{} is not a container image", config_type);
+        }
+
+        self.storage.put(BLOBS_STORAGE_KEY, digest, manifest)?
+    }
+
     #[fehler::throws]
     async fn fetch_layer(
         &self,
@@ -196,7 +403,10 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
     ) {
         let digest_arc = Arc::new(digest.clone());
 
-        if self.storage.exists(BLOBS_STORAGE_KEY, &digest)? {
+        let cached = self.storage.blobs().exists(&digest)?
+            || self.storage.exists(BLOBS_STORAGE_KEY, &digest)?;
+
+        if cached {
             // This may fail for various reason, but we don't care,
             // since it is a UI code and UI does not handle
             // the progress retrieval failures.
@@ -204,11 +414,22 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
                 updates_sub
                     .send(LayerDownloadStatus::Cached(digest_arc.clone())),
             );
+            metrics::increment(
+                "knast_layer_downloads_total",
+                &[("cached", "true")],
+            );
 
             return;
         }
 
-        let updates_handler = move |x| {
+        let throttle = self.throttle.as_ref();
+        let mut downloaded = 0;
+        let mut updates_handler = move |x: usize| {
+            if let Some(throttle) = throttle {
+                throttle.consume(x.saturating_sub(downloaded) as u64);
+            }
+            downloaded = x;
+
             // This may fail for various reason, but we don't care,
             // since it is a UI code and UI does not handle
             // the progress retrieval failures.
@@ -217,31 +438,85 @@ impl<'a, T: StorageEngine> Fetcher<'a, T> {
             ));
         };
 
-        Layer::pull(&self.client, &image_name, &digest, updates_handler)
+        self.download_layer(image_name, &digest, &mut updates_handler)
             .await
-            .and_then(|item| {
-                self.storage.put(BLOBS_STORAGE_KEY, &digest, item)
-            })
             .context(format!("Failed to fetch layer {}", digest))?;
+        metrics::increment(
+            "knast_layer_downloads_total",
+            &[("cached", "false")],
+        );
+    }
+
+    /// Downloads the layer to the blob store, resuming
+    /// interrupted downloads. Partial layer is persisted
+    /// between attempts, so that it survives failed pulls
+    /// too.
+    #[fehler::throws]
+    async fn download_layer(
+        &self,
+        image_name: &str,
+        digest: &str,
+        updates_handler: &mut (impl FnMut(usize) + Send),
+    ) {
+        let retry_policy = self.client.retry_policy();
+        let mut writer = self.storage.blobs().writer(digest)?;
+        let partial = writer.file();
+        let mut retry = 0;
+
+        loop {
+            let offset = partial.offset()?;
+            let result = Layer::resume(
+                &self.client,
+                image_name,
+                digest,
+                &mut *partial,
+                &mut *updates_handler,
+            )
+            .await;
+            let downloaded = partial.offset()?;
+
+            match result {
+                Ok(()) => break,
+                Err(error) if retry + 1 < retry_policy.attempts => {
+                    log::warn!(
+                        "Layer {} download failed at {} bytes: {}",
+                        digest,
+                        downloaded,
+                        error
+                    );
+                }
+                Err(error) => fehler::throw!(error),
+            }
+
+            // Don't back off if the download has progressed
+            if downloaded <= offset {
+                tokio::time::sleep(retry_policy.backoff(retry)).await;
+                retry += 1;
+            }
+        }
+
+        writer.commit()?;
     }
 
     #[fehler::throws]
     async fn fetch_config(&self, image_name: &str, digest: String) {
-        Config::pull(&self.client, &image_name, &digest)
+        Config::pull_with_healthcheck(&self.client, &image_name, &digest)
             .await
-            .and_then(|item| {
+            .and_then(|(item, healthcheck)| {
+                if let Some(healthcheck) = healthcheck {
+                    self.storage.put(
+                        HEALTHCHECKS_STORAGE_KEY,
+                        &digest,
+                        healthcheck,
+                    )?;
+                }
+
                 self.storage.put(BLOBS_STORAGE_KEY, &digest, item)
             })
             .context(format!("Failed to fetch image config {}", digest))?;
     }
 }
 
-fn normalize_image_name(image: &str) -> String {
-    let prefix = if image.contains('/') { "" } else { "library/" };
-
-    format!("{}{}", prefix, image)
-}
-
 #[cfg(test)]
 mod test {
     use futures::stream::StreamExt;
@@ -274,7 +549,15 @@ mod test {
         };
     }
 
-    use registratur::v2::{client::Client, domain::manifest::Manifest};
+    use registratur::v2::{
+        client::Client, domain::manifest::Manifest, reference::Reference,
+    };
+
+    const NGINX: &str = "docker.io/library/nginx:1.17.10";
+
+    fn nginx() -> Reference {
+        NGINX.parse().expect("Failed to parse reference")
+    }
 
     fn get_manifest_from_storage(storage: &Storage, key: &str) -> Manifest {
         let image_digest: String =
@@ -293,15 +576,14 @@ mod test {
         let (tx, _) = futures::channel::mpsc::channel(1);
 
         fetcher
-            .fetch("nginx", "1.17.10", tx)
+            .fetch(&nginx(), tx)
             .await
             .expect("Failed to fetch image");
 
         let storage =
             Storage::new(dir.path()).expect("Unable to initialize cache");
 
-        let manifest =
-            get_manifest_from_storage(&storage, "library/nginx:1.17.10");
+        let manifest = get_manifest_from_storage(&storage, NGINX);
 
         let config_digest = manifest.config.digest;
 
@@ -320,7 +602,7 @@ mod test {
         let (tx, rx) = futures::channel::mpsc::channel(100);
 
         let progress_future = rx.collect::<Vec<_>>();
-        let fetcher_future = fetcher.fetch("nginx", "1.17.10", tx);
+        let fetcher_future = fetcher.fetch(&nginx(), tx);
 
         let (image, progress_items) =
             future::join(fetcher_future, progress_future).await;
@@ -341,16 +623,78 @@ mod test {
             },
         );
 
-        let mut stored_layers =
-            get_manifest_from_storage(&storage, "library/nginx:1.17.10")
-                .layers
-                .into_iter()
-                .map(|layer| layer.digest)
-                .collect::<Vec<_>>();
+        let mut stored_layers = get_manifest_from_storage(&storage, NGINX)
+            .layers
+            .into_iter()
+            .map(|layer| layer.digest)
+            .collect::<Vec<_>>();
 
         downloaded_layers.sort();
         stored_layers.sort();
 
         assert_eq!(stored_layers, downloaded_layers);
     }
+
+    #[test]
+    fn test_select_manifest() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage =
+            Storage::new(dir.path()).expect("Unable to initialize cache");
+        let client = Client::build("https://registry.invalid")
+            .expect("failed to build the client");
+        let entry = |digest: &str, platform: &str| {
+            let parts: Vec<_> = platform.split('/').collect();
+
+            serde_json::json!({
+                "mediaType": media_type::OCI_MANIFEST,
+                "digest": digest,
+                "size": 1,
+                "platform": {
+                    "os": parts[0],
+                    "architecture": parts[1],
+                    "variant": parts.get(2),
+                },
+            })
+        };
+        let index: ManifestIndex = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [
+                entry("sha256:linux", "linux/amd64"),
+                entry("sha256:arm", "linux/arm64/v8"),
+                entry("sha256:freebsd", "freebsd/amd64"),
+            ],
+        }))
+        .expect("Failed to parse the index");
+        let fetcher = Fetcher::new(
+            &storage,
+            client,
+            "amd64".into(),
+            vec!["freebsd".into(), "linux".into()],
+        );
+
+        // OSes are preferred in the order given
+        assert_eq!(
+            fetcher.select_manifest(&index).unwrap(),
+            ("sha256:freebsd".into(), false)
+        );
+
+        let fetcher = fetcher.with_platforms(vec![
+            Platform::new("linux", "arm64").with_variant("v7"),
+            Platform::new("linux", "arm64"),
+        ]);
+
+        assert_eq!(
+            fetcher.select_manifest(&index).unwrap(),
+            ("sha256:arm".into(), false)
+        );
+
+        let fetcher =
+            fetcher.with_platforms(vec![Platform::new("linux", "riscv64")]);
+        let error = fetcher.select_manifest(&index).unwrap_err();
+
+        assert!(error.to_string().contains(
+            "Available platforms: linux/amd64, linux/arm64/v8, freebsd/amd64"
+        ));
+    }
 }
@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+
+use anyhow::Error;
+use registratur::v2::domain::manifest::Manifest;
+use serde::Serialize;
+
+use crate::attachments::Attachments;
+use crate::build_cache::BuildCache;
+use crate::storage::{
+    Storage, StorageEngine, ATTACHMENTS_STORAGE_KEY, BLOBS_STORAGE_KEY,
+    CONTAINERS_FOLDER, CONTAINERS_STORAGE_KEY, HEALTHCHECKS_STORAGE_KEY,
+    IMAGES_INDEX_STORAGE_KEY, PARTIAL_BLOBS_STORAGE_KEY,
+};
+use crate::unpacker::LayerCache;
+
+#[derive(Serialize, Debug, Default)]
+pub struct PruneReport {
+    /// Digests of removed (or, on dry run, removable) blobs.
+    pub blobs: Vec<String>,
+    /// Partial downloads, which are removed regardless.
+    pub partial_blobs: Vec<String>,
+    /// Digests of removed (or removable) unpacked layers.
+    pub unpacked_layers: Vec<String>,
+    /// Keys of removed (or removable) build cache entries.
+    pub build_cache: Vec<String>,
+    /// Manifest digests of removed images, whose attached
+    /// artifacts are forgotten.
+    pub attachments: Vec<String>,
+    /// Number of removed expired values, see
+    /// [`Storage::reap_expired`]. None are counted on dry
+    /// run.
+    pub expired: usize,
+}
+
+/// Removes blobs unreachable from the images index and
+/// from existing containers, and layers unpacked from them,
+/// along with partial downloads and build cache entries of
+/// removed images. Nothing is removed on `dry_run`.
+///
+/// Pulls mustn't run concurrently: their blobs are stored
+/// before the image is indexed.
+#[fehler::throws]
+pub fn prune<T: StorageEngine>(
+    storage: &Storage<T>,
+    dry_run: bool,
+) -> PruneReport {
+    let reachable = mark(storage, dry_run)?;
+    let mut report = PruneReport::default();
+
+    for key in storage.keys(BLOBS_STORAGE_KEY)? {
+        let digest = String::from_utf8_lossy(&key).into_owned();
+
+        if reachable.contains(&digest) {
+            continue;
+        }
+
+        if !dry_run {
+            storage.remove(BLOBS_STORAGE_KEY, &key)?;
+        }
+
+        report.blobs.push(digest);
+    }
+
+    for digest in storage.blobs().digests()? {
+        if reachable.contains(&digest) {
+            continue;
+        }
+
+        if !dry_run {
+            storage.blobs().remove(&digest)?;
+        }
+
+        report.blobs.push(digest);
+    }
+
+    for digest in storage.blobs().partial_digests()? {
+        if !dry_run {
+            storage.blobs().remove_partial(&digest)?;
+        }
+
+        report.partial_blobs.push(digest);
+    }
+
+    for key in storage.keys(PARTIAL_BLOBS_STORAGE_KEY)? {
+        if !dry_run {
+            storage.remove(PARTIAL_BLOBS_STORAGE_KEY, &key)?;
+        }
+
+        report
+            .partial_blobs
+            .push(String::from_utf8_lossy(&key).into_owned());
+    }
+
+    let layers = LayerCache::new(&storage.folder());
+
+    for digest in layers.digests()? {
+        if reachable.contains(&digest) {
+            continue;
+        }
+
+        if !dry_run {
+            layers.remove(&digest)?;
+        }
+
+        report.unpacked_layers.push(digest);
+    }
+
+    if !dry_run {
+        layers.remove_partials()?;
+    }
+
+    report.build_cache =
+        BuildCache::new(storage).prune(&reachable, dry_run)?;
+
+    for key in storage.keys(ATTACHMENTS_STORAGE_KEY)? {
+        let digest = String::from_utf8_lossy(&key).into_owned();
+
+        if reachable.contains(&digest) {
+            continue;
+        }
+
+        if !dry_run {
+            storage.remove(ATTACHMENTS_STORAGE_KEY, &key)?;
+        }
+
+        report.attachments.push(digest);
+    }
+
+    // Healthchecks go along with their configs, which are
+    // reported as blobs
+    for key in storage.keys(HEALTHCHECKS_STORAGE_KEY)? {
+        let digest = String::from_utf8_lossy(&key);
+
+        if !reachable.contains(digest.as_ref()) && !dry_run {
+            storage.remove(HEALTHCHECKS_STORAGE_KEY, &key)?;
+        }
+    }
+
+    if !dry_run {
+        report.expired = storage.reap_expired()?;
+    }
+
+    log::info!(
+        "Pruned {} blobs, {} partial blobs, {} unpacked layers, \
+         {} build cache entries, attachments of {} images and {} expired \
+         values",
+        report.blobs.len(),
+        report.partial_blobs.len(),
+        report.unpacked_layers.len(),
+        report.build_cache.len(),
+        report.attachments.len(),
+        report.expired
+    );
+
+    report
+}
+
+/// Digests of manifests, configs and layers of images,
+/// which are either indexed or used by containers, along
+/// with blobs of the artifacts attached to them. Entries
+/// of removed containers are dropped along the way.
+#[fehler::throws]
+fn mark<T: StorageEngine>(
+    storage: &Storage<T>,
+    dry_run: bool,
+) -> HashSet<String> {
+    let containers_folder = storage.folder().join(CONTAINERS_FOLDER);
+    let mut roots = vec![];
+
+    for key in storage.keys(IMAGES_INDEX_STORAGE_KEY)? {
+        let digest: Option<String> =
+            storage.get(IMAGES_INDEX_STORAGE_KEY, &key)?;
+
+        roots.extend(digest);
+    }
+
+    for key in storage.keys(CONTAINERS_STORAGE_KEY)? {
+        let folder = String::from_utf8_lossy(&key).into_owned();
+
+        if containers_folder.join(folder).exists() {
+            let digest: Option<String> =
+                storage.get(CONTAINERS_STORAGE_KEY, &key)?;
+
+            roots.extend(digest);
+        } else if !dry_run {
+            storage.remove(CONTAINERS_STORAGE_KEY, &key)?;
+        }
+    }
+
+    let mut reachable = HashSet::new();
+    let attachments = Attachments::new(storage);
+
+    for digest in roots {
+        for attachment in attachments.list(&digest)? {
+            reachable.extend(attachment.blobs);
+        }
+
+        let manifest: Option<Manifest> =
+            storage.get(BLOBS_STORAGE_KEY, &digest)?;
+
+        if let Some(manifest) = manifest {
+            reachable.insert(manifest.config.digest);
+            reachable
+                .extend(manifest.layers.into_iter().map(|layer| layer.digest));
+        }
+
+        reachable.insert(digest);
+    }
+
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachments::Attachment;
+    use crate::storage::TestStorage;
+
+    #[test]
+    fn test_prune() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let manifest: Manifest = serde_json::from_str(test_helpers::fixture!(
+            "server_mocks/basic/manifest.json"
+        ))
+        .unwrap();
+        let layer = manifest.layers[0].digest.clone();
+        let config = manifest.config.digest.clone();
+
+        storage
+            .put(BLOBS_STORAGE_KEY, "sha256:m", manifest)
+            .unwrap();
+        storage.put(BLOBS_STORAGE_KEY, &config, vec![0_u8]).unwrap();
+        storage
+            .put(BLOBS_STORAGE_KEY, "sha256:0fa", vec![0_u8])
+            .unwrap();
+
+        let blobs = storage.blobs();
+        blobs.writer(&layer).unwrap().commit().unwrap();
+        blobs.writer("sha256:0fb").unwrap().commit().unwrap();
+        blobs.writer("sha256:0fc").unwrap();
+        blobs.writer("sha256:0fd").unwrap().commit().unwrap();
+        let sbom = Attachment {
+            digest: "sha256:0fe".into(),
+            artifact_type: "application/spdx+json".into(),
+            annotations: Default::default(),
+            blobs: vec!["sha256:0fd".into()],
+        };
+
+        storage
+            .put(ATTACHMENTS_STORAGE_KEY, "sha256:m", vec![&sbom])
+            .unwrap();
+        // Image was removed
+        storage
+            .put(ATTACHMENTS_STORAGE_KEY, "sha256:0ff", vec![&sbom])
+            .unwrap();
+        let reference = "docker.io/library/nginx";
+
+        storage
+            .put(IMAGES_INDEX_STORAGE_KEY, reference, "sha256:m")
+            .unwrap();
+        // Container was removed
+        storage
+            .put(CONTAINERS_STORAGE_KEY, "removed", "sha256:0fa")
+            .unwrap();
+
+        let report = prune(&storage, true).expect("Failed to prune");
+        assert_eq!(report.blobs, vec!["sha256:0fa", "sha256:0fb"]);
+        assert_eq!(report.partial_blobs, vec!["sha256:0fc"]);
+        assert_eq!(report.attachments, vec!["sha256:0ff"]);
+        assert!(storage.exists(BLOBS_STORAGE_KEY, "sha256:0fa").unwrap());
+        assert!(blobs.exists("sha256:0fb").unwrap());
+
+        prune(&storage, false).expect("Failed to prune");
+        assert!(!storage.exists(BLOBS_STORAGE_KEY, "sha256:0fa").unwrap());
+        assert!(!blobs.exists("sha256:0fb").unwrap());
+        assert!(blobs.partial_digests().unwrap().is_empty());
+        assert!(blobs.exists(&layer).unwrap());
+        assert!(storage.exists(BLOBS_STORAGE_KEY, &config).unwrap());
+        assert!(storage.exists(BLOBS_STORAGE_KEY, "sha256:m").unwrap());
+        assert!(!storage.exists(CONTAINERS_STORAGE_KEY, "removed").unwrap());
+        assert!(blobs.exists("sha256:0fd").unwrap());
+        assert!(!storage
+            .exists(ATTACHMENTS_STORAGE_KEY, "sha256:0ff")
+            .unwrap());
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Error};
+use registratur::v2::{domain::manifest::Manifest, reference::Reference};
+use serde::Serialize;
+
+use crate::storage::{
+    Storage, StorageEngine, BLOBS_STORAGE_KEY, IMAGES_INDEX_STORAGE_KEY,
+};
+
+/// Images pulled to the storage, that is the images index
+/// mapping references to manifest digests. Blobs are left
+/// intact by the operations.
+pub struct ImageStore<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImageSummary {
+    pub reference: String,
+    pub digest: String,
+    /// Compressed size, configuration included.
+    pub size: usize,
+}
+
+impl<'a, T: StorageEngine> ImageStore<'a, T> {
+    pub fn new(storage: &'a Storage<T>) -> Self {
+        Self { storage }
+    }
+
+    /// Lists pulled images, ordered by reference.
+    #[fehler::throws]
+    pub fn list(&self) -> Vec<ImageSummary> {
+        let mut result = vec![];
+
+        for key in self.storage.keys(IMAGES_INDEX_STORAGE_KEY)? {
+            let digest: Option<String> =
+                self.storage.get(IMAGES_INDEX_STORAGE_KEY, &key)?;
+            // Removed concurrently
+            let digest = match digest {
+                Some(digest) => digest,
+                None => continue,
+            };
+            let manifest: Option<Manifest> =
+                self.storage.get(BLOBS_STORAGE_KEY, &digest)?;
+
+            result.push(ImageSummary {
+                reference: String::from_utf8_lossy(&key).into_owned(),
+                digest,
+                size: manifest.as_ref().map_or(0, image_size),
+            });
+        }
+
+        result
+    }
+
+    /// Manifest digest of the image `reference` points to.
+    /// Images pinned by digest are found even if pulled by
+    /// another reference.
+    #[fehler::throws]
+    pub fn resolve(&self, reference: &Reference) -> String {
+        let indexed: Option<String> = self
+            .storage
+            .get(IMAGES_INDEX_STORAGE_KEY, reference.to_string())?;
+        let pinned = reference.digest.as_ref().filter(|digest| {
+            self.storage
+                .exists(BLOBS_STORAGE_KEY, digest)
+                .unwrap_or_default()
+        });
+
+        indexed
+            .or_else(|| pinned.cloned())
+            .ok_or_else(|| anyhow!("Image {} is not pulled", reference))?
+    }
+
+    /// Makes `target` refer to the image `source` refers to.
+    #[fehler::throws]
+    pub fn tag(&self, source: &Reference, target: &Reference) {
+        if target.digest.is_some() {
+            fehler::throw!(anyhow!("Can't tag {}, it's pinned", target));
+        }
+
+        let digest = self.resolve(source)?;
+
+        self.storage.put(
+            IMAGES_INDEX_STORAGE_KEY,
+            target.to_string(),
+            digest,
+        )?;
+    }
+
+    /// Removes the reference, returning the digest it
+    /// referred to.
+    #[fehler::throws]
+    pub fn remove(&self, reference: &Reference) -> String {
+        let key = reference.to_string();
+        let digest: String = self
+            .storage
+            .get(IMAGES_INDEX_STORAGE_KEY, &key)?
+            .ok_or_else(|| anyhow!("Image {} is not pulled", reference))?;
+
+        self.storage.remove(IMAGES_INDEX_STORAGE_KEY, &key)?;
+
+        digest
+    }
+}
+
+/// Compressed size of the image, configuration included.
+pub(crate) fn image_size(manifest: &Manifest) -> usize {
+    manifest.config.size
+        + manifest
+            .layers
+            .iter()
+            .map(|layer| layer.size)
+            .sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TestStorage;
+
+    #[test]
+    fn test_operations() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let store = ImageStore::new(&storage);
+        let parse = |input: &str| input.parse::<Reference>().unwrap();
+
+        storage
+            .put(
+                IMAGES_INDEX_STORAGE_KEY,
+                "docker.io/library/nginx:1.17.10",
+                "sha256:abc",
+            )
+            .unwrap();
+
+        store
+            .tag(&parse("nginx:1.17.10"), &parse("nginx:stable"))
+            .expect("Failed to tag the image");
+
+        let images = store.list().expect("Failed to list images");
+        let references: Vec<_> = images
+            .iter()
+            .map(|image| image.reference.as_str())
+            .collect();
+        assert_eq!(
+            references,
+            vec![
+                "docker.io/library/nginx:1.17.10",
+                "docker.io/library/nginx:stable"
+            ]
+        );
+        assert!(images.iter().all(|image| image.digest == "sha256:abc"));
+
+        let digest = store
+            .remove(&parse("nginx:1.17.10"))
+            .expect("Failed to remove the image");
+        assert_eq!(digest, "sha256:abc");
+        assert!(store.resolve(&parse("nginx:1.17.10")).is_err());
+        assert!(store.remove(&parse("nginx:1.17.10")).is_err());
+        assert!(store.tag(&parse("nginx:1.17.10"), &parse("nginx")).is_err());
+    }
+}
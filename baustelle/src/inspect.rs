@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Error};
+use registratur::v2::{
+    domain::{config::Config, manifest::Manifest},
+    reference::Reference,
+};
+use serde::Serialize;
+
+use crate::{
+    image_store::{image_size, ImageStore},
+    storage::{Storage, StorageEngine, BLOBS_STORAGE_KEY},
+};
+
+/// Merged view of the stored image manifest and
+/// configuration.
+#[derive(Serialize, Debug)]
+pub struct ImageInspection {
+    pub reference: String,
+    /// Manifest digest.
+    pub digest: String,
+    pub created: Option<String>,
+    pub platform: Platform,
+    /// Compressed size, configuration included.
+    pub size: usize,
+    pub layers: Vec<LayerInspection>,
+    pub env: Vec<String>,
+    pub entrypoint: Vec<String>,
+    pub cmd: Vec<String>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+    pub exposed_ports: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LayerInspection {
+    pub digest: String,
+    pub media_type: String,
+    pub size: usize,
+}
+
+/// Inspects the image pulled by `reference`, see
+/// [`ImageStore::resolve`].
+#[fehler::throws]
+pub fn inspect<T: StorageEngine>(
+    storage: &Storage<T>,
+    reference: &Reference,
+) -> ImageInspection {
+    let digest = ImageStore::new(storage).resolve(reference)?;
+    let manifest: Manifest = storage
+        .get(BLOBS_STORAGE_KEY, &digest)?
+        .ok_or_else(|| anyhow!("Image {} is not pulled", reference))?;
+    let config: Config = storage
+        .get(BLOBS_STORAGE_KEY, &manifest.config.digest)?
+        .context("Image config was not found. Possible storage corruption")?;
+
+    let size = image_size(&manifest);
+    let layers = manifest
+        .layers
+        .into_iter()
+        .map(|layer| LayerInspection {
+            digest: layer.digest,
+            media_type: layer.media_type,
+            size: layer.size,
+        })
+        .collect();
+    let container = config.config;
+    let mut inspection = ImageInspection {
+        reference: reference.to_string(),
+        digest,
+        created: config.created.map(|created| created.to_rfc3339()),
+        platform: Platform {
+            architecture: config.architecture,
+            os: config.os,
+        },
+        size,
+        layers,
+        env: vec![],
+        entrypoint: vec![],
+        cmd: vec![],
+        working_dir: None,
+        user: None,
+        exposed_ports: vec![],
+        labels: BTreeMap::new(),
+    };
+
+    if let Some(container) = container {
+        let non_empty =
+            |value: Option<String>| value.filter(|value| !value.is_empty());
+        let mut exposed_ports: Vec<_> = container
+            .exposed_ports
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(port, _)| port)
+            .collect();
+        exposed_ports.sort();
+
+        inspection.env = container.env.unwrap_or_default();
+        inspection.entrypoint = container.entrypoint.unwrap_or_default();
+        inspection.cmd = container.cmd.unwrap_or_default();
+        inspection.working_dir = non_empty(Some(container.working_dir));
+        inspection.user = non_empty(container.user);
+        inspection.exposed_ports = exposed_ports;
+        inspection.labels =
+            container.labels.unwrap_or_default().into_iter().collect();
+    }
+
+    inspection
+}
+
+#[cfg(test)]
+mod tests {
+    use registratur::v2::client::Client;
+
+    use super::*;
+    use crate::{fetcher::Fetcher, storage::TestStorage};
+
+    #[tokio::test]
+    async fn test_inspect() {
+        #[cfg(feature = "integration_testing")]
+        let (url, _mocks) = ("https://registry-1.docker.io", ());
+        #[cfg(not(feature = "integration_testing"))]
+        let (url, _mocks) = test_helpers::mock_server!("basic.yml");
+
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let reference = "nginx:1.17.10".parse().unwrap();
+
+        let digest = {
+            let client =
+                Client::build(&url).expect("failed to build the client");
+            let os = vec!["linux".into(), "freebsd".into()];
+            let fetcher = Fetcher::new(&storage, client, "amd64".into(), os);
+            let (tx, _) = futures::channel::mpsc::channel(1);
+
+            fetcher
+                .fetch(&reference, tx)
+                .await
+                .expect("Failed to fetch the image")
+        };
+
+        let inspection =
+            inspect(&storage, &reference).expect("Failed to inspect image");
+
+        assert_eq!(inspection.digest, digest);
+        assert_eq!(inspection.platform.architecture, "amd64");
+        assert_eq!(inspection.cmd, vec!["nginx", "-g", "daemon off;"]);
+        assert_eq!(inspection.exposed_ports, vec!["80/tcp"]);
+        assert_eq!(inspection.working_dir, None);
+        assert!(!inspection.layers.is_empty());
+    }
+}
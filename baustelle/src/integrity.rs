@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use registratur::{
+    v2::domain::{config::Config, manifest::Manifest},
+    verify_digest, verify_reader,
+};
+use serde::Serialize;
+
+use crate::storage::{
+    Storage, StorageEngine, BLOBS_STORAGE_KEY, IMAGES_INDEX_STORAGE_KEY,
+};
+
+#[derive(Serialize, Debug, Default)]
+pub struct IntegrityReport {
+    /// Number of blobs checked.
+    pub checked: usize,
+    /// Digests of corrupt or missing blobs.
+    pub corrupt_blobs: Vec<String>,
+    /// References of images having corrupt blobs.
+    pub corrupt_images: Vec<String>,
+}
+
+/// Checks blobs of indexed images. Layers are rehashed
+/// against their digests, manifests and configs, which are
+/// stored parsed, are checked to decode.
+///
+/// On `repair` corrupt blobs are removed along with the
+/// references of images they belong to, so that the next
+/// pull downloads them again.
+#[fehler::throws]
+pub fn check<T: StorageEngine>(
+    storage: &Storage<T>,
+    repair: bool,
+) -> IntegrityReport {
+    let mut report = IntegrityReport::default();
+    // Configs and layers are shared by images, hence checked
+    // once
+    let mut checked = HashMap::new();
+
+    for key in storage.keys(IMAGES_INDEX_STORAGE_KEY)? {
+        let digest: Option<String> =
+            storage.get(IMAGES_INDEX_STORAGE_KEY, &key)?;
+        let digest = match digest {
+            Some(digest) => digest,
+            None => continue,
+        };
+        let mut blobs = vec![];
+        let manifest = check_blob(&mut checked, &digest, || {
+            storage.get::<Manifest>(BLOBS_STORAGE_KEY, &digest)
+        });
+
+        if let Some(manifest) = &manifest {
+            let config = &manifest.config.digest;
+
+            if !checked.contains_key(config) {
+                check_blob(&mut checked, config, || {
+                    storage.get::<Config>(BLOBS_STORAGE_KEY, config)
+                });
+            }
+
+            blobs.push(config.clone());
+
+            for layer in &manifest.layers {
+                let layer = &layer.digest;
+
+                if !checked.contains_key(layer) {
+                    check_blob(&mut checked, layer, || {
+                        if let Some(file) = storage.blobs().open(layer)? {
+                            return verify_reader(file, layer).map(Some);
+                        }
+
+                        let content: Option<Vec<u8>> =
+                            storage.get(BLOBS_STORAGE_KEY, layer)?;
+
+                        content
+                            .map(|content| verify_digest(&content, layer))
+                            .transpose()
+                    });
+                }
+
+                blobs.push(layer.clone());
+            }
+        }
+
+        blobs.push(digest);
+
+        let corrupt =
+            blobs.iter().any(|blob| checked.get(blob) == Some(&false));
+
+        if !corrupt {
+            continue;
+        }
+
+        let reference = String::from_utf8_lossy(&key).into_owned();
+        log::warn!("Image {} is corrupt", reference);
+
+        if repair {
+            storage.remove(IMAGES_INDEX_STORAGE_KEY, &key)?;
+        }
+
+        report.corrupt_images.push(reference);
+    }
+
+    let mut corrupt_blobs: Vec<_> = checked
+        .iter()
+        .filter(|(_, valid)| !**valid)
+        .map(|(digest, _)| digest.clone())
+        .collect();
+    corrupt_blobs.sort();
+
+    if repair {
+        for digest in &corrupt_blobs {
+            storage.remove(BLOBS_STORAGE_KEY, digest)?;
+            storage.blobs().remove(digest)?;
+        }
+    }
+
+    report.checked = checked.len();
+    report.corrupt_blobs = corrupt_blobs;
+
+    report
+}
+
+/// Runs the check of the blob, remembering the result. Blob
+/// is valid if the check returns a value.
+fn check_blob<V>(
+    checked: &mut HashMap<String, bool>,
+    digest: &str,
+    check: impl FnOnce() -> Result<Option<V>, Error>,
+) -> Option<V> {
+    let result = check();
+    let valid = matches!(result, Ok(Some(_)));
+
+    if !valid {
+        log::warn!("Blob {} is corrupt: {:?}", digest, result.as_ref().err());
+    }
+
+    checked.insert(digest.to_string(), valid);
+
+    result.ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::*;
+    use crate::storage::TestStorage;
+
+    #[test]
+    fn test_check() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage = TestStorage::new(tempdir.path())
+            .expect("Unable to initialize cache");
+        let manifest: Manifest = serde_json::from_str(test_helpers::fixture!(
+            "server_mocks/basic/manifest.json"
+        ))
+        .unwrap();
+        let config: Config = serde_json::from_str(test_helpers::fixture!(
+            "server_mocks/basic/config.json"
+        ))
+        .unwrap();
+        let layer_content =
+            test_helpers::bytes_fixture!("server_mocks/basic/layer1").to_vec();
+        let layer = manifest.layers[0].digest.clone();
+        let reference = "docker.io/library/nginx";
+
+        storage
+            .put(BLOBS_STORAGE_KEY, &manifest.config.digest, config)
+            .unwrap();
+        storage
+            .put(BLOBS_STORAGE_KEY, "sha256:m", manifest)
+            .unwrap();
+        let mut writer = storage.blobs().writer(&layer).unwrap();
+        writer.file().write_all(&layer_content).unwrap();
+        writer.commit().unwrap();
+        storage
+            .put(IMAGES_INDEX_STORAGE_KEY, reference, "sha256:m")
+            .unwrap();
+
+        let report = check(&storage, false).expect("Failed to check");
+        assert_eq!(report.checked, 3);
+        assert!(report.corrupt_blobs.is_empty());
+
+        fs::write(storage.blobs().path(&layer).unwrap(), [0_u8]).unwrap();
+
+        let report = check(&storage, true).expect("Failed to check");
+        assert_eq!(report.corrupt_blobs, vec![layer.clone()]);
+        assert_eq!(report.corrupt_images, vec![reference]);
+        assert!(!storage.blobs().exists(&layer).unwrap());
+        assert!(!storage.exists(IMAGES_INDEX_STORAGE_KEY, reference).unwrap());
+    }
+}
@@ -1,39 +1,177 @@
 mod fetcher;
 pub mod runtime_config;
 mod storage;
+mod throttle;
 mod unpacker;
 
+pub mod attachments;
+pub mod bootstrap;
+pub mod build_cache;
+pub mod build_context;
+pub mod commit;
 mod containerfile;
+pub mod discovery;
+pub mod gc;
+pub mod image_store;
+pub mod inspect;
+pub mod integrity;
+pub mod platform;
+pub mod pull;
+pub mod signatures;
 
 mod archive;
+mod cancellation;
 
-use std::{io::Read, path::PathBuf};
+use std::{collections::HashMap, io::Read, path::PathBuf};
 
 use anyhow::Error;
 use futures::{future, StreamExt};
+use registratur::v2::client::Registries;
 
-use crate::storage::{Storage, StorageEngine};
+use crate::{
+    platform::Platform,
+    storage::{Storage, StorageEngine},
+};
+pub use archive::ExtractFlags;
+pub use cancellation::{CancellationToken, Cancelled};
 use containerfile::Builder as ContainerfileBuilder;
 pub use containerfile::EvaluationUpdate;
-pub use fetcher::LayerDownloadStatus;
+pub use fetcher::{DownloadOptions, LayerDownloadStatus};
+pub use pull::{Image, ImagePuller, UnpackOptions};
+pub use registratur::v2::reference::Reference;
+pub use signatures::SignaturePolicy;
 
-pub struct Builder<T: StorageEngine> {
+/// Builds images from containerfiles. The storage is
+/// borrowed, so that the one instance backs images along
+/// with containers and networks.
+pub struct Builder<'a, T: StorageEngine> {
     architecture: String,
     os: Vec<String>,
-    storage: Storage<T>,
+    storage: &'a Storage<T>,
+    download_options: DownloadOptions,
+    registries: Option<Registries>,
+    verify_on_read: bool,
+    unpack_parallelism: usize,
+    extract_flags: ExtractFlags,
+    no_cache: bool,
+    cancellation: Option<CancellationToken>,
+    build_args: HashMap<String, String>,
+    platforms: Option<Vec<Platform>>,
+    signature_policy: Option<SignaturePolicy>,
 }
 
-impl<T: StorageEngine> Builder<T> {
+impl<'a, T: StorageEngine> Builder<'a, T> {
     #[fehler::throws]
     pub fn new(
         architecture: String,
         os: Vec<String>,
-        storage: Storage<T>,
+        storage: &'a Storage<T>,
     ) -> Self {
         Self {
             architecture,
             os,
             storage,
+            download_options: DownloadOptions::default(),
+            registries: None,
+            verify_on_read: false,
+            unpack_parallelism: 1,
+            extract_flags: ExtractFlags::default(),
+            no_cache: false,
+            cancellation: None,
+            build_args: HashMap::new(),
+            platforms: None,
+            signature_policy: None,
+        }
+    }
+
+    /// Limits parallelism and bandwidth of layer downloads.
+    pub fn with_download_options(
+        self,
+        download_options: DownloadOptions,
+    ) -> Self {
+        Self {
+            download_options,
+            ..self
+        }
+    }
+
+    /// Rehashes layers before unpacking them, for
+    /// deployments which can't trust the storage.
+    pub fn with_verify_on_read(self, verify_on_read: bool) -> Self {
+        Self {
+            verify_on_read,
+            ..self
+        }
+    }
+
+    /// Extracts up to `unpack_parallelism` layers at once.
+    /// Speeds up unpacking of large images at the cost of
+    /// staging space.
+    pub fn with_unpack_parallelism(self, unpack_parallelism: usize) -> Self {
+        Self {
+            unpack_parallelism,
+            ..self
+        }
+    }
+
+    /// Overrides the metadata restored when unpacking, which
+    /// is everything by default, ownership included when
+    /// running as root.
+    pub fn with_extract_flags(self, extract_flags: ExtractFlags) -> Self {
+        Self {
+            extract_flags,
+            ..self
+        }
+    }
+
+    /// Redoes all the steps, ignoring the build cache, see
+    /// [`build_cache::BuildCache`]. Images are pulled even
+    /// if they are pulled already.
+    pub fn with_no_cache(self, no_cache: bool) -> Self {
+        Self { no_cache, ..self }
+    }
+
+    /// Values of containerfile `ARG`s, i.e. `--build-arg`s.
+    pub fn with_build_args(self, build_args: HashMap<String, String>) -> Self {
+        Self { build_args, ..self }
+    }
+
+    /// Platforms images are pulled for, most preferred first.
+    /// Overrides the architecture and OSes, i.e. to pull
+    /// amd64 images on arm64 hosts knowingly.
+    pub fn with_platforms(self, platforms: Vec<Platform>) -> Self {
+        Self {
+            platforms: Some(platforms),
+            ..self
+        }
+    }
+
+    /// Aborts builds, once the token is cancelled. Builds
+    /// fail with [`Cancelled`] then, and their partial
+    /// downloads and container folders are removed.
+    pub fn with_cancellation(self, token: CancellationToken) -> Self {
+        Self {
+            cancellation: Some(token),
+            ..self
+        }
+    }
+
+    /// Overrides registry mirrors and TLS settings, which
+    /// are otherwise loaded from the default location.
+    pub fn with_registries(self, registries: Registries) -> Self {
+        Self {
+            registries: Some(registries),
+            ..self
+        }
+    }
+
+    /// Verifies signatures of pulled images, see
+    /// [`signatures::SignaturePolicy`]. Nothing is verified
+    /// by default.
+    pub fn with_signature_policy(self, policy: SignaturePolicy) -> Self {
+        Self {
+            signature_policy: Some(policy),
+            ..self
         }
     }
 
@@ -48,14 +186,43 @@ impl<T: StorageEngine> Builder<T> {
             architecture,
             os,
             storage,
+            download_options,
+            registries,
+            verify_on_read,
+            unpack_parallelism,
+            extract_flags,
+            no_cache,
+            cancellation,
+            build_args,
+            platforms,
+            signature_policy,
         } = self;
 
-        let builder = ContainerfileBuilder::new(
+        let mut builder = ContainerfileBuilder::new(
             registry,
             architecture.into(),
             os.to_vec(),
-            &storage,
-        )?;
+            storage,
+            *download_options,
+            registries.as_ref(),
+        )?
+        .with_verify_on_read(*verify_on_read)
+        .with_unpack_parallelism(*unpack_parallelism)
+        .with_extract_flags(*extract_flags)
+        .with_no_cache(*no_cache)
+        .with_build_args(build_args.clone());
+
+        if let Some(token) = cancellation {
+            builder = builder.with_cancellation(token.clone());
+        }
+
+        if let Some(platforms) = platforms {
+            builder = builder.with_platforms(platforms.clone());
+        }
+
+        if let Some(policy) = signature_policy {
+            builder = builder.with_signature_policy(policy);
+        }
 
         let (updates, future) = builder.interpret(containerfile)?;
 
@@ -69,18 +236,32 @@ impl<T: StorageEngine> Builder<T> {
 
         result?
     }
+
+    /// Checks blobs of pulled images, see
+    /// [`integrity::check`].
+    #[fehler::throws]
+    pub fn check(&self, repair: bool) -> integrity::IntegrityReport {
+        integrity::check(self.storage, repair)?
+    }
+
+    /// Reclaims space taken by blobs of removed images, see
+    /// [`gc::prune`].
+    #[fehler::throws]
+    pub fn prune(&self, dry_run: bool) -> gc::PruneReport {
+        gc::prune(self.storage, dry_run)?
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use tempfile::TempDir;
-
     use super::*;
     use crate::storage::{StorageEngine, TestStorage as Storage};
 
     #[test]
     fn test_image_build_initializer() {
-        let builder = construct_builder();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(tmpdir.path()).unwrap();
+        let builder = construct_builder(&storage);
 
         assert!(builder.is_ok(), "Failed to create builder")
     }
@@ -92,8 +273,15 @@ mod tests {
         #[cfg(not(feature = "integration_testing"))]
         let (url, _mocks) = test_helpers::mock_server!("unix.yml");
 
-        let (builder, _path) =
-            construct_builder().expect("failed to create builder");
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(tmpdir.path()).unwrap();
+        let builder = construct_builder(&storage)
+            .expect("failed to create builder")
+            .with_build_args(
+                vec![("VERSION".into(), "1.17.10".into())]
+                    .into_iter()
+                    .collect(),
+            );
 
         let containerfile = test_helpers::fixture!("containerfile");
         let container_folder = builder
@@ -105,13 +293,9 @@ mod tests {
     }
 
     #[fehler::throws]
-    fn construct_builder() -> (Builder<impl StorageEngine>, TempDir) {
-        let tmpdir = tempfile::tempdir().unwrap();
-        let storage = Storage::new(tmpdir.path()).unwrap();
-
-        (
-            Builder::new("amd64".into(), vec!["linux".into()], storage)?,
-            tmpdir,
-        )
+    fn construct_builder(
+        storage: &Storage,
+    ) -> Builder<'_, impl StorageEngine> {
+        Builder::new("amd64".into(), vec!["linux".into()], storage)?
     }
 }
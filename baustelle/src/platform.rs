@@ -0,0 +1,161 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use registratur::v2::domain::manifest_index;
+
+/// Platform images are pulled for, i.e. `linux/arm64/v8`.
+/// Platforms without a variant match any variant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    pub fn new(
+        os: impl Into<String>,
+        architecture: impl Into<String>,
+    ) -> Self {
+        Self {
+            os: os.into(),
+            architecture: architecture.into(),
+            variant: None,
+        }
+    }
+
+    pub fn with_variant(self, variant: impl Into<String>) -> Self {
+        Self {
+            variant: Some(variant.into()),
+            ..self
+        }
+    }
+
+    /// Whether the platform of the index entry matches.
+    pub fn matches(&self, platform: &manifest_index::Platform) -> bool {
+        self.os == platform.os
+            && self.architecture == platform.architecture
+            && (self.variant.is_none() || self.variant == platform.variant)
+    }
+
+    /// Parses comma-separated list of platforms, most
+    /// preferred first, i.e. `freebsd/amd64,linux/amd64`.
+    #[fehler::throws]
+    pub fn parse_list(list: &str) -> Vec<Self> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|platform| !platform.is_empty())
+            .map(str::parse::<Self>)
+            .collect::<Result<_, _>>()?
+    }
+}
+
+/// Comma-separated list of platforms, the way
+/// [`Platform::parse_list`] expects it.
+pub fn display_list(platforms: &[Platform]) -> String {
+    platforms
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl From<&manifest_index::Platform> for Platform {
+    fn from(platform: &manifest_index::Platform) -> Self {
+        Self {
+            os: platform.os.clone(),
+            architecture: platform.architecture.clone(),
+            variant: platform.variant.clone(),
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    #[fehler::throws]
+    fn from_str(platform: &str) -> Self {
+        let parts: Vec<_> = platform.split('/').collect();
+
+        if parts.iter().any(|part| part.is_empty()) {
+            fehler::throw!(anyhow!("Invalid platform {}", platform));
+        }
+
+        match parts[..] {
+            [os, architecture] => Self::new(os, architecture),
+            [os, architecture, variant] => {
+                Self::new(os, architecture).with_variant(variant)
+            }
+            _ => fehler::throw!(anyhow!(
+                "Invalid platform {}, expected os/arch[/variant]",
+                platform
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index_platform(
+        os: &str,
+        architecture: &str,
+        variant: Option<&str>,
+    ) -> manifest_index::Platform {
+        manifest_index::Platform {
+            architecture: architecture.into(),
+            os: os.into(),
+            os_version: None,
+            os_features: None,
+            variant: variant.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let platforms =
+            Platform::parse_list("freebsd/amd64, linux/arm64/v8").unwrap();
+
+        assert_eq!(
+            platforms,
+            vec![
+                Platform::new("freebsd", "amd64"),
+                Platform::new("linux", "arm64").with_variant("v8"),
+            ]
+        );
+        assert_eq!(display_list(&platforms), "freebsd/amd64,linux/arm64/v8");
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux//v8".parse::<Platform>().is_err());
+        assert!("linux/arm/v7/extra".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let arm64 = index_platform("linux", "arm64", Some("v8"));
+
+        assert!(Platform::new("linux", "arm64").matches(&arm64));
+        assert!(Platform::new("linux", "arm64")
+            .with_variant("v8")
+            .matches(&arm64));
+        assert!(!Platform::new("linux", "arm64")
+            .with_variant("v7")
+            .matches(&arm64));
+        assert!(!Platform::new("freebsd", "arm64").matches(&arm64));
+        assert!(!Platform::new("linux", "arm64")
+            .with_variant("v8")
+            .matches(&index_platform("linux", "arm64", None)));
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+use futures::sink::Sink;
+use registratur::v2::{
+    client::Registries,
+    domain::{
+        config::{Config, Healthcheck},
+        manifest::Manifest,
+    },
+    reference::{Reference, DEFAULT_REGISTRY},
+};
+use uuid::Uuid;
+
+use crate::{
+    archive::ExtractFlags,
+    cancellation::CancellationToken,
+    containerfile::build_client,
+    fetcher::{DownloadOptions, Fetcher, LayerDownloadStatus},
+    image_store::ImageStore,
+    platform::Platform,
+    runtime_config::RuntimeConfig,
+    signatures::SignaturePolicy,
+    storage::{
+        Storage, StorageEngine, BLOBS_STORAGE_KEY, CONTAINERS_FOLDER,
+        CONTAINERS_STORAGE_KEY, HEALTHCHECKS_STORAGE_KEY,
+    },
+    unpacker::{unshare, Unpacker},
+};
+
+/// Pulls images by reference, rather than building them
+/// from a containerfile. Images are pulled from the registry
+/// their reference names.
+pub struct ImagePuller<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+    default_registry_url: Option<&'a str>,
+    platforms: Vec<Platform>,
+    download_options: DownloadOptions,
+    registries: Option<&'a Registries>,
+    refresh: bool,
+    cancellation: Option<CancellationToken>,
+    signature_policy: Option<&'a SignaturePolicy>,
+}
+
+/// Image pulled to the storage.
+pub struct Image<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+    digest: String,
+    manifest: Manifest,
+    config: Config,
+}
+
+/// See [`Unpacker`] options.
+#[derive(Clone, Copy, Debug)]
+pub struct UnpackOptions {
+    pub verify_on_read: bool,
+    pub parallelism: usize,
+    pub extract_flags: ExtractFlags,
+    pub layer_cache: bool,
+}
+
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        Self {
+            verify_on_read: false,
+            parallelism: 1,
+            extract_flags: ExtractFlags::default(),
+            layer_cache: false,
+        }
+    }
+}
+
+impl<'a, T: StorageEngine> ImagePuller<'a, T> {
+    /// `platforms` are tried in order, see
+    /// [`Fetcher::with_platforms`].
+    pub fn new(storage: &'a Storage<T>, platforms: Vec<Platform>) -> Self {
+        Self {
+            storage,
+            default_registry_url: None,
+            platforms,
+            download_options: DownloadOptions::default(),
+            registries: None,
+            refresh: false,
+            cancellation: None,
+            signature_policy: None,
+        }
+    }
+
+    /// Registry images of `docker.io` are pulled from,
+    /// rather than Docker Hub.
+    pub fn with_default_registry(self, url: &'a str) -> Self {
+        Self {
+            default_registry_url: Some(url),
+            ..self
+        }
+    }
+
+    /// Limits parallelism and bandwidth of layer downloads.
+    pub fn with_download_options(
+        self,
+        download_options: DownloadOptions,
+    ) -> Self {
+        Self {
+            download_options,
+            ..self
+        }
+    }
+
+    /// Overrides registry mirrors and TLS settings, which
+    /// are otherwise loaded from the default location.
+    pub fn with_registries(self, registries: &'a Registries) -> Self {
+        Self {
+            registries: Some(registries),
+            ..self
+        }
+    }
+
+    /// See [`Fetcher::with_refresh`].
+    pub fn with_refresh(self, refresh: bool) -> Self {
+        Self { refresh, ..self }
+    }
+
+    /// See [`Fetcher::with_cancellation`].
+    pub fn with_cancellation(self, token: CancellationToken) -> Self {
+        Self {
+            cancellation: Some(token),
+            ..self
+        }
+    }
+
+    /// See [`Fetcher::with_signature_policy`].
+    pub fn with_signature_policy(self, policy: &'a SignaturePolicy) -> Self {
+        Self {
+            signature_policy: Some(policy),
+            ..self
+        }
+    }
+
+    /// Pulls the image, reporting layer downloads to
+    /// `updates`.
+    #[fehler::throws]
+    pub async fn pull(
+        &self,
+        reference: &Reference,
+        updates: impl Sink<LayerDownloadStatus> + Clone + Unpin + Send,
+    ) -> Image<'a, T> {
+        let registry_url = match self.default_registry_url {
+            Some(url) if reference.registry == DEFAULT_REGISTRY => url.into(),
+            _ => reference.registry_url(),
+        };
+        let client = build_client(&registry_url, self.registries)?;
+
+        // Platforms override the architecture and OSes
+        let mut fetcher =
+            Fetcher::new(self.storage, client, String::new(), vec![])
+                .with_platforms(self.platforms.clone())
+                .with_download_options(self.download_options)
+                .with_refresh(self.refresh);
+
+        if let Some(token) = &self.cancellation {
+            fetcher = fetcher.with_cancellation(token.clone());
+        }
+
+        if let Some(policy) = self.signature_policy {
+            fetcher = fetcher.with_signature_policy(policy);
+        }
+
+        let digest = fetcher
+            .fetch(reference, updates)
+            .await
+            .with_context(|| format!("Failed to pull {}", reference))?;
+
+        Image::from_digest(self.storage, digest)?
+    }
+}
+
+impl<'a, T: StorageEngine> Image<'a, T> {
+    /// Image the reference points to, which is pulled
+    /// already.
+    #[fehler::throws]
+    pub fn open(storage: &'a Storage<T>, reference: &Reference) -> Self {
+        let digest = ImageStore::new(storage).resolve(reference)?;
+
+        Self::from_digest(storage, digest)?
+    }
+
+    #[fehler::throws]
+    pub(crate) fn from_digest(
+        storage: &'a Storage<T>,
+        digest: String,
+    ) -> Self {
+        let manifest: Manifest = storage
+            .get(BLOBS_STORAGE_KEY, &digest)?
+            .context("Manifest was not found. Possible storage corruption")?;
+        let config: Config = storage
+            .get(BLOBS_STORAGE_KEY, &manifest.config.digest)?
+            .context("Config was not found. Possible storage corruption")?;
+
+        Self {
+            storage,
+            digest,
+            manifest,
+            config,
+        }
+    }
+
+    /// Manifest digest.
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Healthcheck of the image, if it has one.
+    #[fehler::throws]
+    pub fn healthcheck(&self) -> Option<Healthcheck> {
+        let digest = &self.manifest.config.digest;
+
+        self.storage.get(HEALTHCHECKS_STORAGE_KEY, digest)?
+    }
+
+    /// Unpacks layers of the image to `destination`. With
+    /// the layer cache, the destination shares files with the
+    /// cache and mustn't be modified.
+    #[fehler::throws]
+    pub fn unpack(&self, destination: &Path, options: UnpackOptions) {
+        Unpacker::new(self.storage, destination)
+            .with_verify_on_read(options.verify_on_read)
+            .with_parallelism(options.parallelism)
+            .with_extract_flags(options.extract_flags)
+            .with_layer_cache(options.layer_cache)
+            .unpack(self.digest.clone())?;
+    }
+
+    /// OCI runtime config of a container of the image, which
+    /// root filesystem is `rootfs`.
+    #[fehler::throws]
+    pub fn runtime_config(&self, rootfs: &Path) -> RuntimeConfig {
+        RuntimeConfig::try_from((self.config.clone(), rootfs))?
+            .with_healthcheck(self.healthcheck()?.as_ref())?
+    }
+
+    /// Unpacks the image into a new container folder, along
+    /// with the OCI runtime config. Returns the folder, i.e.
+    /// the bundle. Blobs of the image are kept until the
+    /// folder is removed, see `gc::prune`.
+    ///
+    /// With the layer cache, the image is unpacked to the
+    /// `layers` folder instead, mounted read-only on the
+    /// rootfs, see [`RuntimeConfig::with_layers`].
+    #[fehler::throws]
+    pub fn create_bundle(&self, options: UnpackOptions) -> PathBuf {
+        let container_uuid = Uuid::new_v4().to_string();
+        let folder = self
+            .storage
+            .folder()
+            .join(CONTAINERS_FOLDER)
+            .join(&container_uuid);
+        let rootfs = folder.join("rootfs");
+
+        fs::create_dir_all(&rootfs)?;
+
+        self.storage.put(
+            CONTAINERS_STORAGE_KEY,
+            &container_uuid,
+            &self.digest,
+        )?;
+
+        // Cached layers are shared, so that they're mounted
+        // read-only beneath the container's own upper layer
+        let runtime_config = if options.layer_cache {
+            let layers = folder.join("layers");
+
+            self.unpack(&layers, options)?;
+
+            let runtime_config = self.runtime_config(&layers)?;
+
+            runtime_config.create_volumes()?;
+            unshare(&folder.join("volumes"))?;
+            runtime_config.with_layers(&rootfs, &layers, &folder.join("upper"))
+        } else {
+            self.unpack(&rootfs, options)?;
+
+            let runtime_config = self.runtime_config(&rootfs)?;
+
+            runtime_config.create_volumes()?;
+            runtime_config
+        };
+
+        serde_json::to_writer(
+            File::create(folder.join("config.json"))?,
+            &runtime_config,
+        )?;
+
+        folder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TestStorage as Storage;
+
+    #[tokio::test]
+    async fn test_pull() {
+        #[cfg(feature = "integration_testing")]
+        let (url, _mocks) = ("https://registry-1.docker.io", ());
+        #[cfg(not(feature = "integration_testing"))]
+        let (url, _mocks) = test_helpers::mock_server!("unix.yml");
+
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let storage =
+            Storage::new(tempdir.path()).expect("Unable to initialize cache");
+        let reference: Reference = "nginx:1.17.10".parse().unwrap();
+        let (tx, _) = futures::channel::mpsc::channel(1);
+
+        let pulled =
+            ImagePuller::new(&storage, vec![Platform::new("linux", "amd64")])
+                .with_default_registry(&url)
+                .pull(&reference, tx)
+                .await
+                .expect("Failed to pull the image");
+
+        let rootfs = tempdir.path().join("rootfs");
+        let image = Image::open(&storage, &reference)
+            .expect("Pulled image wasn't found");
+
+        assert_eq!(image.digest(), pulled.digest());
+
+        image
+            .unpack(&rootfs, UnpackOptions::default())
+            .expect("Failed to unpack the image");
+
+        assert!(rootfs.join("etc/passwd").exists());
+        assert_eq!(image.config().os, "linux");
+
+        let config = image.runtime_config(&rootfs).unwrap();
+        let command = config.process.unwrap().args.unwrap().join(" ");
+
+        assert_eq!(command, "nginx -g daemon off;");
+
+        let bundle = image
+            .create_bundle(UnpackOptions::default())
+            .expect("Failed to create the bundle");
+
+        assert!(bundle.join("rootfs/etc/passwd").exists());
+        assert!(bundle.join("config.json").exists());
+    }
+}
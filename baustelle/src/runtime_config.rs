@@ -1,13 +1,31 @@
-mod user;
+pub mod user;
+mod validation;
 
 use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use registratur::v2::domain::config;
 use serde::{Deserialize, Serialize};
 
+pub use validation::InvalidConfig;
+
+/// Healthcheck of the container, in the format of the
+/// Docker image config, i.e.
+/// `{"Test": ["CMD", "true"], "Interval": 30000000000}`.
+pub const HEALTHCHECK_ANNOTATION: &str = "org.freebsd.knast.healthcheck";
+/// OS of the image the container is created from.
+pub const OS_ANNOTATION: &str = "org.freebsd.knast.image.os";
+/// Read-only layers the container's rootfs is assembled
+/// of, bottom one first, separated by `:`.
+pub const LAYERS_ANNOTATION: &str = "org.freebsd.knast.rootfs.layers";
+/// Directory, which the container's changes to the layers
+/// go to.
+pub const UPPER_ANNOTATION: &str = "org.freebsd.knast.rootfs.upper";
+
 /// Represents [OCI Container Configuration file](https://github.com/opencontainers/runtime-spec/blob/v1.0.0/config.md)
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RuntimeConfig {
@@ -18,6 +36,8 @@ pub struct RuntimeConfig {
     pub process: Option<Process>,
     pub hooks: Option<Hooks>,
     pub annotations: Option<BTreeMap<String, String>>,
+    pub linux: Option<Linux>,
+    pub freebsd: Option<FreeBSD>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -64,11 +84,18 @@ pub struct Rlimit {
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
+    #[serde(default)]
     pub uid: u32,
+    #[serde(default)]
     pub gid: u32,
     pub umask: Option<u32>,
     #[serde(rename = "additionalGids")]
     pub additional_gids: Option<Vec<u32>>,
+    /// User, i.e. `postgres` or `postgres:staff`, which is
+    /// looked up in the container's `/etc/passwd` and
+    /// `/etc/group` once the process starts, see
+    /// [`user::parse`]. Overrides `uid` and `gid`.
+    pub username: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -92,29 +119,223 @@ pub struct Hook {
     pub timeout: Option<u32>,
 }
 
+/// Subset of OCI [Linux container configuration](https://git.io/JOQal)
+/// which makes sense on FreeBSD.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Linux {
+    pub devices: Option<Vec<LinuxDevice>>,
+    pub seccomp: Option<Seccomp>,
+}
+
+/// OCI seccomp profile. FreeBSD has no seccomp, so the
+/// profile is approximated by jail parameters, see
+/// `libknast::seccomp`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Seccomp {
+    /// Action of the syscalls, which no rule matches, i.e.
+    /// `SCMP_ACT_ERRNO`.
+    pub default_action: String,
+    pub syscalls: Option<Vec<SyscallRule>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SyscallRule {
+    pub names: Vec<String>,
+    pub action: String,
+    /// Conditions on the arguments, the rule matches some
+    /// calls of the syscalls only then.
+    pub args: Option<Vec<SyscallArg>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallArg {
+    pub index: u32,
+    pub value: u64,
+    pub value_two: Option<u64>,
+    pub op: String,
+}
+
+/// Host device node to pass through to the container.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinuxDevice {
+    pub r#type: String,
+    pub path: String,
+    pub major: Option<i64>,
+    pub minor: Option<i64>,
+    pub file_mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// FreeBSD-specific container configuration. Not a part of
+/// OCI spec, mirrors its `linux` section where possible.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FreeBSD {
+    pub devices: Option<Vec<Device>>,
+    /// Permissions of the jail, denied unless given.
+    pub allow: Option<Allow>,
+    /// System V IPC of the jail, see [`SysvMode`].
+    pub sysvmsg: Option<SysvMode>,
+    pub sysvsem: Option<SysvMode>,
+    pub sysvshm: Option<SysvMode>,
+}
+
+/// `allow.*` parameters of the jail, see jail(8).
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Allow {
+    pub sysvipc: Option<bool>,
+    pub mount: Option<bool>,
+    /// Effective together with `mount` only.
+    pub mount_devfs: Option<bool>,
+    pub chflags: Option<bool>,
+}
+
+/// Whether the jail has System V IPC objects, i.e. shared
+/// memory segments, of its own or sees the host's ones.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SysvMode {
+    Disable,
+    New,
+    Inherit,
+}
+
+/// A devfs node to expose to the container, on top of the
+/// default ones.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    /// devfs(8) path pattern, relative to /dev, i.e. `bpf*`.
+    pub path: String,
+    pub file_mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
 impl TryFrom<(config::Config, &Path)> for RuntimeConfig {
     type Error = Error;
 
     #[fehler::throws]
     fn try_from((config, rootfs): (config::Config, &Path)) -> Self {
-        let annotations = generate_annotations();
+        let annotations = generate_annotations(&config.os);
+        let volumes = config
+            .config
+            .as_ref()
+            .and_then(|config| config.volumes.as_ref())
+            .map(|volumes| volumes.keys().cloned().collect())
+            .unwrap_or_else(Vec::new);
         let process = config
             .config
             .map(|config| Process::try_from((config, rootfs)))
             .transpose()?;
+        let mut mounts = generate_mounts(config.os);
+
+        mounts.extend(generate_volume_mounts(volumes, rootfs)?);
 
         Self {
             oci_version: "1.0".into(),
             root: Some(rootfs.into()),
-            mounts: Some(generate_mounts(config.os)),
+            mounts: Some(mounts),
             process,
             hooks: None,
             annotations: Some(annotations),
+            linux: None,
+            freebsd: None,
         }
     }
 }
 
-fn generate_annotations() -> BTreeMap<String, String> {
+impl RuntimeConfig {
+    /// Annotates the config with the image `healthcheck`,
+    /// see [`HEALTHCHECK_ANNOTATION`].
+    #[fehler::throws]
+    pub fn with_healthcheck(
+        mut self,
+        healthcheck: Option<&config::Healthcheck>,
+    ) -> Self {
+        if let Some(healthcheck) = healthcheck {
+            self.annotations.get_or_insert_with(BTreeMap::new).insert(
+                HEALTHCHECK_ANNOTATION.into(),
+                serde_json::to_string(healthcheck)?,
+            );
+        }
+
+        self
+    }
+
+    /// Roots the container at the empty `rootfs`, which the
+    /// read-only `layers` are mounted on, topped by the
+    /// writable `upper` layer, see [`LAYERS_ANNOTATION`].
+    pub fn with_layers(
+        mut self,
+        rootfs: &Path,
+        layers: &Path,
+        upper: &Path,
+    ) -> Self {
+        let annotations = self.annotations.get_or_insert_with(BTreeMap::new);
+
+        annotations
+            .insert(LAYERS_ANNOTATION.into(), layers.display().to_string());
+        annotations
+            .insert(UPPER_ANNOTATION.into(), upper.display().to_string());
+        self.root = Some(rootfs.into());
+
+        self
+    }
+
+    /// Config of a FreeBSD bundle, which runs a shell in the
+    /// `rootfs` folder next to it. Meant to be edited.
+    pub fn spec() -> Self {
+        let mut mounts = generate_mounts("freebsd".into());
+
+        mounts.push(Mount {
+            destination: "/tmp".into(),
+            r#type: "tmpfs".into(),
+            source: Some("tmpfs".into()),
+            options: Some(vec!["nosuid".into(), "mode=1777".into()]),
+        });
+
+        Self {
+            oci_version: "1.0.2".into(),
+            root: Some(Root {
+                path: "rootfs".into(),
+                readonly: Some(false),
+            }),
+            mounts: Some(mounts),
+            process: Some(Process {
+                terminal: Some(true),
+                console_size: None,
+                cwd: "/".into(),
+                env: Some(vec![
+                    "PATH=/sbin:/bin:/usr/sbin:/usr/bin:/usr/local/sbin:\
+                     /usr/local/bin"
+                        .into(),
+                    "TERM=xterm".into(),
+                ]),
+                args: Some(vec!["sh".into()]),
+                rlimits: None,
+                user: User {
+                    uid: 0,
+                    gid: 0,
+                    umask: None,
+                    additional_gids: None,
+                    username: None,
+                },
+                hostname: None,
+            }),
+            hooks: None,
+            annotations: Some(generate_annotations("freebsd")),
+            linux: None,
+            freebsd: None,
+        }
+    }
+}
+
+fn generate_annotations(os: &str) -> BTreeMap<String, String> {
     let mut annotations = BTreeMap::new();
 
     // TODO: something meaningful, or at least adhere to OCI
@@ -122,6 +343,7 @@ fn generate_annotations() -> BTreeMap<String, String> {
     annotations.insert("io.container.manager".into(), "knast".into());
     annotations
         .insert("org.opencontainers.image.stopSignal".into(), "15".into());
+    annotations.insert(OS_ANNOTATION.into(), os.into());
 
     annotations
 }
@@ -174,11 +396,14 @@ impl TryFrom<(Option<String>, &Path)> for User {
             gid,
             umask: None,
             additional_gids: None,
+            username: None,
         }
     }
 }
 
-fn generate_mounts(os: String) -> Vec<Mount> {
+/// Mounts images of the `os` expect, i.e. emulated `/proc`
+/// for Linux ones.
+pub fn generate_mounts(os: String) -> Vec<Mount> {
     let mut mounts = vec![Mount {
         destination: "/dev".into(),
         r#type: "devfs".into(),
@@ -232,6 +457,144 @@ fn generate_mounts(os: String) -> Vec<Mount> {
     mounts
 }
 
+/// Images declare paths which are expected to be backed by
+/// volumes (VOLUME instruction). Unless a volume is
+/// provided, we create an anonymous one: a directory next
+/// to the rootfs, nullfs-mounted onto the declared path.
+/// The directories are created by
+/// [`RuntimeConfig::create_volumes`].
+#[fehler::throws]
+fn generate_volume_mounts(volumes: Vec<String>, rootfs: &Path) -> Vec<Mount> {
+    let volumes_folder = volumes_folder(rootfs);
+    let mut result = vec![];
+
+    for volume in volumes {
+        let components = volume_components(&volume)?;
+
+        if components.is_empty() {
+            log::warn!("Ignoring volume {:?}", volume);
+            continue;
+        }
+
+        let source = volumes_folder.join(volume_name(&components));
+
+        result.push(Mount {
+            destination: volume,
+            r#type: "nullfs".into(),
+            source: Some(source.to_string_lossy().into()),
+            options: None,
+        });
+    }
+
+    result
+}
+
+impl RuntimeConfig {
+    /// Creates the anonymous volumes of the config, see
+    /// [`generate_volume_mounts`]. Just like docker does,
+    /// the volume is populated with the image's content at
+    /// the declared path.
+    ///
+    /// The declared path is resolved beneath the rootfs
+    /// without following symlinks, so that images can't
+    /// make us move or create host paths.
+    #[fehler::throws]
+    pub fn create_volumes(&self) {
+        let rootfs = match &self.root {
+            Some(root) => &root.path,
+            None => return,
+        };
+        let volumes_folder = volumes_folder(rootfs);
+
+        for mount in self.mounts.iter().flatten() {
+            let source = match &mount.source {
+                Some(source) if mount.r#type == "nullfs" => Path::new(source),
+                _ => continue,
+            };
+
+            if source.parent() != Some(volumes_folder.as_path()) {
+                continue;
+            }
+
+            let destination = resolve_beneath(
+                rootfs,
+                &volume_components(&mount.destination)?,
+            )?;
+
+            fs::create_dir_all(&volumes_folder)?;
+
+            if !source.exists() {
+                fs::rename(&destination, &source)?;
+                fs::create_dir(&destination)?;
+            }
+        }
+    }
+}
+
+fn volumes_folder(rootfs: &Path) -> PathBuf {
+    rootfs.with_file_name("volumes")
+}
+
+/// Normal components of the `volume` path. Paths escaping
+/// the rootfs are rejected.
+#[fehler::throws]
+fn volume_components(volume: &str) -> Vec<&str> {
+    let mut result = vec![];
+
+    for component in Path::new(volume).components() {
+        match component {
+            Component::Normal(component) => result.push(
+                component
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Invalid volume {:?}", volume))?,
+            ),
+            Component::ParentDir => {
+                fehler::throw!(anyhow!("Invalid volume {:?}", volume))
+            }
+            _ => (),
+        }
+    }
+
+    result
+}
+
+/// Folder name of the volume, distinct for distinct paths:
+/// components are joined by `_`, while `_` and `%` within
+/// them are percent-encoded.
+fn volume_name(components: &[&str]) -> String {
+    components
+        .iter()
+        .map(|component| component.replace('%', "%25").replace('_', "%5F"))
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Directory `components` beneath the `rootfs`, created if
+/// missing. Fails on symlinks and non-directories along the
+/// way.
+#[fehler::throws]
+fn resolve_beneath(rootfs: &Path, components: &[&str]) -> PathBuf {
+    let mut path = rootfs.to_path_buf();
+
+    for component in components {
+        path.push(component);
+
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => (),
+            Ok(_) => fehler::throw!(anyhow!(
+                "Volume path {:?} is not a directory",
+                path
+            )),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&path)?
+            }
+            Err(error) => fehler::throw!(error),
+        }
+    }
+
+    path
+}
+
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
@@ -258,6 +621,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_freebsd_deserialization() {
+        let freebsd: FreeBSD = serde_json::from_str(
+            r#"{
+                "allow": {"sysvipc": true, "mountDevfs": false},
+                "sysvshm": "new"
+            }"#,
+        )
+        .expect("failed to deserialize freebsd section");
+        let allow = freebsd.allow.unwrap();
+
+        assert_eq!(allow.sysvipc, Some(true));
+        assert_eq!(allow.mount_devfs, Some(false));
+        assert_eq!(allow.mount, None);
+        assert_eq!(freebsd.sysvshm, Some(SysvMode::New));
+        assert_eq!(freebsd.sysvmsg, None);
+    }
+
     #[test]
     #[cfg(not(feature = "integration_testing"))]
     fn test_conversion() {
@@ -276,6 +657,110 @@ mod tests {
         assert_eq!((uid, gid), (977, 13));
     }
 
+    #[test]
+    fn test_spec() {
+        let bundle = tempfile::tempdir().unwrap();
+        let config = RuntimeConfig::spec();
+
+        fs::create_dir(bundle.path().join("rootfs")).unwrap();
+
+        assert_eq!(config.validate(bundle.path()), Ok(()));
+        assert!(config.process.unwrap().env.unwrap()[0]
+            .contains(":/usr/local/sbin:"));
+    }
+
+    #[test]
+    fn test_with_healthcheck() {
+        let healthcheck = config::Healthcheck {
+            test: vec!["CMD".into(), "true".into()],
+            retries: Some(5),
+            ..Default::default()
+        };
+        let config = RuntimeConfig::spec()
+            .with_healthcheck(Some(&healthcheck))
+            .unwrap();
+        let annotation = &config.annotations.unwrap()[HEALTHCHECK_ANNOTATION];
+
+        assert_eq!(
+            serde_json::from_str::<config::Healthcheck>(annotation).unwrap(),
+            healthcheck
+        );
+    }
+
+    fn volumes_config(rootfs: &Path, volumes: Vec<String>) -> RuntimeConfig {
+        RuntimeConfig {
+            root: Some(rootfs.into()),
+            mounts: Some(
+                generate_volume_mounts(volumes, rootfs)
+                    .expect("failed to generate volume mounts"),
+            ),
+            ..RuntimeConfig::spec()
+        }
+    }
+
+    #[test]
+    fn test_volume_mounts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = tempdir.path().join("rootfs");
+
+        fs::create_dir_all(rootfs.join("var/lib/db")).unwrap();
+        fs::write(rootfs.join("var/lib/db/initial"), "data").unwrap();
+
+        let config = volumes_config(
+            &rootfs,
+            vec!["/var/lib/db".into(), "/cache".into()],
+        );
+        let mounts = config.mounts.as_ref().unwrap();
+        let volumes = tempdir.path().join("volumes");
+
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].destination, "/var/lib/db");
+        assert_eq!(mounts[0].r#type, "nullfs");
+        assert_eq!(
+            mounts[0].source.as_deref(),
+            volumes.join("var_lib_db").to_str()
+        );
+        assert!(!volumes.exists());
+
+        config.create_volumes().expect("failed to create volumes");
+
+        assert!(volumes.join("var_lib_db/initial").exists());
+        assert!(volumes.join("cache").is_dir());
+        assert!(rootfs.join("cache").is_dir());
+        assert!(rootfs.join("var/lib/db").is_dir());
+    }
+
+    #[test]
+    fn test_volume_names() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = tempdir.path().join("rootfs");
+        let config =
+            volumes_config(&rootfs, vec!["/a_b".into(), "/a/b".into()]);
+        let mounts = config.mounts.unwrap();
+
+        assert_ne!(mounts[0].source, mounts[1].source);
+        assert!(
+            generate_volume_mounts(vec!["/../etc".into()], &rootfs).is_err()
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_volume_symlinks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = tempdir.path().join("rootfs");
+        let host = tempdir.path().join("host");
+
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::create_dir_all(&host).unwrap();
+        std::os::unix::fs::symlink(&host, rootfs.join("data")).unwrap();
+
+        let config = volumes_config(&rootfs, vec!["/data/db".into()]);
+
+        assert!(config.create_volumes().is_err());
+        assert!(!host.join("db").exists());
+    }
+
     // TODO: I really don't like the body of this test... Like,
     // really.
     #[tokio::test]
@@ -303,9 +788,10 @@ mod tests {
             let fetcher =
                 Fetcher::new(&storage, client, architecture.into(), os);
             let (tx, _) = futures::channel::mpsc::channel(1);
+            let reference = "nginx:1.17.10".parse().unwrap();
 
             fetcher
-                .fetch("nginx", "1.17.10", tx)
+                .fetch(&reference, tx)
                 .await
                 .expect("Failed to fetch the image")
         };
@@ -0,0 +1,223 @@
+/// Checks of runtime configs, which are done upfront, so
+/// that problems don't surface mid-lifecycle.
+use std::{
+    error::Error,
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use common_lib::env;
+
+use super::{user, RuntimeConfig};
+
+/// Every problem of the runtime config.
+#[derive(Debug, PartialEq)]
+pub struct InvalidConfig(pub Vec<String>);
+
+impl fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid runtime config:")?;
+
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for InvalidConfig {}
+
+impl RuntimeConfig {
+    /// Checks that required fields are set, the rootfs of the
+    /// `bundle` exists, mounts make sense, and that the
+    /// process' user and working directory exist in the
+    /// rootfs. All the problems are reported at once.
+    pub fn validate(
+        &self,
+        bundle: impl AsRef<Path>,
+    ) -> Result<(), InvalidConfig> {
+        let mut problems = vec![];
+
+        if self.oci_version.is_empty() {
+            problems.push("ociVersion must be set".into());
+        }
+
+        let rootfs = match &self.root {
+            Some(root) => {
+                let rootfs = bundle.as_ref().join(&root.path);
+
+                if rootfs.is_dir() {
+                    Some(rootfs)
+                } else {
+                    problems
+                        .push(format!("root {:?} is not a directory", rootfs));
+                    None
+                }
+            }
+            None => {
+                problems.push("root must be set".into());
+                None
+            }
+        };
+
+        for mount in self.mounts.as_deref().unwrap_or(&[]) {
+            if !Path::new(&mount.destination).is_absolute() {
+                problems.push(format!(
+                    "mount destination {:?} must be absolute",
+                    mount.destination
+                ));
+            }
+
+            if mount.r#type.is_empty() {
+                problems.push(format!(
+                    "mount {:?} must have a type",
+                    mount.destination
+                ));
+            }
+        }
+
+        let process = match &self.process {
+            Some(process) => process,
+            None => {
+                problems.push("process must be set".into());
+                return Err(InvalidConfig(problems));
+            }
+        };
+
+        if process.args.as_ref().map_or(true, Vec::is_empty) {
+            problems.push("process.args must name a command".into());
+        }
+
+        if let Err(error) = env::parse(process.env.as_deref().unwrap_or(&[])) {
+            problems.push(error.to_string());
+        }
+
+        match inside(&process.cwd) {
+            None => problems.push(format!(
+                "process.cwd {:?} must be an absolute path within rootfs",
+                process.cwd
+            )),
+            Some(cwd) => {
+                if let Some(rootfs) = &rootfs {
+                    if !rootfs.join(cwd).is_dir() {
+                        problems.push(format!(
+                            "process.cwd {:?} doesn't exist in rootfs",
+                            process.cwd
+                        ));
+                    }
+                }
+            }
+        }
+
+        if let (Some(username), Some(rootfs)) =
+            (&process.user.username, &rootfs)
+        {
+            if let Err(error) = user::parse(username.clone(), rootfs) {
+                problems.push(format!(
+                    "process.user {:?} can't be resolved: {}",
+                    username, error
+                ));
+            }
+        }
+
+        let allow = self
+            .freebsd
+            .as_ref()
+            .and_then(|freebsd| freebsd.allow.as_ref());
+
+        if let Some(allow) = allow {
+            if allow.mount_devfs == Some(true) && allow.mount != Some(true) {
+                problems.push(
+                    "freebsd.allow.mountDevfs requires freebsd.allow.mount"
+                        .into(),
+                );
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidConfig(problems))
+        }
+    }
+}
+
+/// Path relative to the rootfs, unless the path is relative
+/// or escapes the rootfs.
+fn inside(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+
+    if !path.is_absolute() {
+        return None;
+    }
+
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(component) => result.push(component),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::runtime_config::{Allow, FreeBSD};
+
+    #[test]
+    fn test_validate() {
+        let fixture = test_helpers::fixture!("runtime_config.json");
+        let mut config: RuntimeConfig = serde_json::from_str(fixture)
+            .expect("failed to deserialize runtime config");
+        let bundle = tempfile::tempdir().unwrap();
+
+        config.root = Some(Path::new("rootfs").into());
+        fs::create_dir_all(bundle.path().join("rootfs/srv")).unwrap();
+
+        let process = config.process.as_mut().unwrap();
+
+        process.cwd = "/srv".into();
+        process.args = Some(vec!["sh".into()]);
+
+        assert_eq!(config.validate(bundle.path()), Ok(()));
+
+        let process = config.process.as_mut().unwrap();
+
+        process.cwd = "/srv/../..".into();
+        process.args = None;
+        process.env = Some(vec!["=oops".into()]);
+        process.user.username = Some("nobody".into());
+
+        let problems = config.validate(bundle.path()).unwrap_err().0;
+
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+
+        config.freebsd = Some(FreeBSD {
+            allow: Some(Allow {
+                mount_devfs: Some(true),
+                ..Allow::default()
+            }),
+            ..FreeBSD::default()
+        });
+
+        let problems = config.validate(bundle.path()).unwrap_err().0;
+
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+    }
+
+    #[test]
+    fn test_inside() {
+        assert_eq!(inside("/"), Some(PathBuf::new()));
+        assert_eq!(inside("/usr/./local"), Some(PathBuf::from("usr/local")));
+        assert_eq!(inside("usr"), None);
+        assert_eq!(inside("/usr/.."), None);
+    }
+}
@@ -0,0 +1,457 @@
+/// Verification of cosign image signatures.
+///
+/// Signatures are looked up via the OCI referrers API first,
+/// falling back to the cosign tag convention, i.e.
+/// `sha256-<hex>.sig`. Either way, a signature is a manifest,
+/// whose layers are the signed payloads, with signatures in
+/// their annotations. Only the key-based signatures, made by
+/// `cosign sign --key`, are verified: keyless ones need the
+/// transparency log and the certificate authority.
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+use registratur::{
+    v2::{
+        client::Client,
+        domain::{layer::Layer, media_type, referrers::Referrers},
+    },
+    verify_digest,
+};
+use reqwest::{header, Method, StatusCode};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+const POLICY_VARIABLE: &str = "KNAST_SIGNATURE_POLICY";
+const DEFAULT_POLICY_PATH: &str = "/usr/local/etc/knast/policy.json";
+
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+const SIGNATURE_ARTIFACT_TYPE: &str =
+    "application/vnd.dev.cosign.artifact.sig.v1+json";
+const SIGNATURE_TYPE: &str = "cosign container image signature";
+
+const PEM_BEGIN: &str = "-----BEGIN PUBLIC KEY-----";
+const PEM_END: &str = "-----END PUBLIC KEY-----";
+/// DER of the `SubjectPublicKeyInfo` preceding the point of
+/// P-256 keys, the only ones cosign generates.
+const P256_SPKI_PREFIX: [u8; 26] = [
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02,
+    0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03,
+    0x42, 0x00,
+];
+
+/// Policy images are pulled under, i.e.
+///
+/// ```json
+/// {
+///     "require": true,
+///     "keys": ["/usr/local/etc/knast/cosign.pub"]
+/// }
+/// ```
+///
+/// Images signed by none of the `keys` are refused, if
+/// signatures are required, and pulled with a warning
+/// otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct SignaturePolicy {
+    require: bool,
+    keys: Vec<Vec<u8>>,
+}
+
+#[derive(serde::Deserialize)]
+struct PolicyConfig {
+    #[serde(default)]
+    require: bool,
+    /// PEM-encoded public keys.
+    #[serde(default)]
+    keys: Vec<PathBuf>,
+}
+
+#[derive(serde::Deserialize)]
+struct SignatureManifest {
+    #[serde(default)]
+    layers: Vec<SignatureLayer>,
+}
+
+#[derive(serde::Deserialize)]
+struct SignatureLayer {
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Payload {
+    critical: Critical,
+}
+
+#[derive(serde::Deserialize)]
+struct Critical {
+    image: PayloadImage,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(serde::Deserialize)]
+struct PayloadImage {
+    #[serde(rename = "docker-manifest-digest")]
+    digest: String,
+}
+
+impl SignaturePolicy {
+    /// Policy verifying signatures against the PEM-encoded
+    /// `keys`.
+    #[fehler::throws]
+    pub fn new(require: bool, keys: &[impl AsRef<[u8]>]) -> Self {
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|key| parse_key(key.as_ref()))
+            .collect::<Result<_, _>>()?;
+
+        if require && keys.is_empty() {
+            fehler::throw!(anyhow!("Signatures are required, but no keys"));
+        }
+
+        Self { require, keys }
+    }
+
+    /// Reads the policy from `KNAST_SIGNATURE_POLICY` or
+    /// `/usr/local/etc/knast/policy.json`. Missing policy
+    /// verifies nothing.
+    #[fehler::throws]
+    pub fn load() -> Self {
+        let path = env::var_os(POLICY_VARIABLE)
+            .map_or_else(|| PathBuf::from(DEFAULT_POLICY_PATH), PathBuf::from);
+
+        if !path.exists() {
+            return Self::default();
+        }
+
+        let config: PolicyConfig =
+            serde_json::from_slice(&fs::read(&path)?)
+                .with_context(|| format!("Failed to parse {:?}", path))?;
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| {
+                fs::read(key)
+                    .with_context(|| format!("Failed to read {:?}", key))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::new(config.require, &keys)?
+    }
+
+    /// Whether the policy verifies anything at all.
+    pub fn is_enabled(&self) -> bool {
+        self.require || !self.keys.is_empty()
+    }
+
+    /// Verifies signatures of the manifest `digest` refers
+    /// to, before any of its layers are pulled.
+    #[fehler::throws]
+    pub async fn verify(
+        &self,
+        client: &Client<'_>,
+        image_name: &str,
+        digest: &str,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut signatures = 0;
+        let manifests =
+            match signature_manifests(client, image_name, digest).await {
+                Ok(manifests) => manifests,
+                Err(error) => {
+                    log::warn!(
+                        "Failed to look up signatures of {}@{}: {:?}",
+                        image_name,
+                        digest,
+                        error
+                    );
+
+                    vec![]
+                }
+            };
+
+        for manifest in manifests {
+            for layer in manifest.layers {
+                let signature =
+                    match layer.annotations.get(SIGNATURE_ANNOTATION) {
+                        Some(signature) => signature,
+                        None => continue,
+                    };
+
+                signatures += 1;
+
+                // Broken signatures don't match, the rest are
+                // still checked
+                let verified = self
+                    .verify_layer(
+                        client, image_name, &layer, signature, digest,
+                    )
+                    .await
+                    .unwrap_or_else(|error| {
+                        log::warn!(
+                            "Signature {} of {}@{} is invalid: {:?}",
+                            layer.digest,
+                            image_name,
+                            digest,
+                            error
+                        );
+
+                        false
+                    });
+
+                if verified {
+                    log::info!(
+                        "Verified signature of {}@{}",
+                        image_name,
+                        digest
+                    );
+
+                    return;
+                }
+            }
+        }
+
+        let reason = if signatures == 0 {
+            format!("{}@{} is not signed", image_name, digest)
+        } else {
+            format!(
+                "None of {} signatures of {}@{} is made by the trusted keys",
+                signatures, image_name, digest
+            )
+        };
+
+        if self.require {
+            fehler::throw!(anyhow!(reason));
+        }
+
+        log::warn!("{}, pulling anyway", reason);
+    }
+
+    /// Whether any of the keys made the base64-encoded
+    /// `signature` of the payload the `layer` holds.
+    #[fehler::throws]
+    async fn verify_layer(
+        &self,
+        client: &Client<'_>,
+        image_name: &str,
+        layer: &SignatureLayer,
+        signature: &str,
+        digest: &str,
+    ) -> bool {
+        let signature = base64::decode(signature)?;
+        let payload =
+            Layer::pull(client, image_name, &layer.digest, |_| {}).await?;
+
+        self.verify_payload(&payload, &signature, digest)?
+    }
+
+    /// Whether any of the keys made the `signature` of the
+    /// `payload`, which in turn names the `digest`.
+    #[fehler::throws]
+    fn verify_payload(
+        &self,
+        payload: &[u8],
+        signature: &[u8],
+        digest: &str,
+    ) -> bool {
+        let trusted = self.keys.iter().any(|key| {
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key)
+                .verify(payload, signature)
+                .is_ok()
+        });
+
+        if !trusted {
+            return false;
+        }
+
+        let payload: Payload = serde_json::from_slice(payload)
+            .context("Failed to parse the signed payload")?;
+
+        // Otherwise signatures of other images could be
+        // replayed
+        if payload.critical.kind != SIGNATURE_TYPE
+            || payload.critical.image.digest != digest
+        {
+            fehler::throw!(anyhow!(
+                "Signature is made for {}, rather than {}",
+                payload.critical.image.digest,
+                digest
+            ));
+        }
+
+        true
+    }
+}
+
+/// Manifests of the signatures, attached to the manifest
+/// `digest` refers to.
+#[fehler::throws]
+async fn signature_manifests(
+    client: &Client<'_>,
+    image_name: &str,
+    digest: &str,
+) -> Vec<SignatureManifest> {
+    let referrers = Referrers::pull(
+        client,
+        image_name,
+        digest,
+        Some(SIGNATURE_ARTIFACT_TYPE),
+    )
+    .await
+    .unwrap_or_else(|error| {
+        log::debug!("Failed to list referrers: {:?}", error);
+
+        vec![]
+    });
+    let mut manifests = vec![];
+
+    for referrer in referrers {
+        let digest = &referrer.descriptor.digest;
+        let path = format!("/v2/{}/manifests/{}", image_name, digest);
+
+        let content = get(client, &path, &media_type::MANIFESTS).await?;
+
+        if let Some(content) = content {
+            verify_digest(&content, digest)?;
+            manifests.push(serde_json::from_slice(&content)?);
+        }
+    }
+
+    if manifests.is_empty() {
+        let path = format!(
+            "/v2/{}/manifests/{}.sig",
+            image_name,
+            digest.replacen(':', "-", 1)
+        );
+
+        let content = get(client, &path, &media_type::MANIFESTS).await?;
+
+        if let Some(content) = content {
+            manifests.push(serde_json::from_slice(&content)?);
+        }
+    }
+
+    manifests
+}
+
+/// Content at the `path`, if it exists.
+#[fehler::throws]
+async fn get(
+    client: &Client<'_>,
+    path: &str,
+    accepted: &[&str],
+) -> Option<Vec<u8>> {
+    let response = client
+        .request(Method::GET, path, |request| {
+            request.header(header::ACCEPT, media_type::accept(accepted))
+        })
+        .await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return None;
+    }
+
+    Some(response.error_for_status()?.bytes().await?.to_vec())
+}
+
+/// Point of the PEM-encoded P-256 public key.
+#[fehler::throws]
+fn parse_key(pem: &[u8]) -> Vec<u8> {
+    let pem = std::str::from_utf8(pem)?;
+    let start = pem
+        .find(PEM_BEGIN)
+        .ok_or_else(|| anyhow!("Public key is not PEM-encoded"))?;
+    let end = pem[start..]
+        .find(PEM_END)
+        .ok_or_else(|| anyhow!("Public key is not PEM-encoded"))?;
+    let encoded: String = pem[start + PEM_BEGIN.len()..start + end]
+        .split_whitespace()
+        .collect();
+    let der = base64::decode(encoded)?;
+
+    match der.strip_prefix(&P256_SPKI_PREFIX[..]) {
+        Some(point) => point.to_vec(),
+        None => fehler::throw!(anyhow!("Only P-256 keys are supported")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0c4b2bbf5a0dbd8d0f6c5d9bd1b8ae4e\
+                          7e0e3e2d4d0f3c1e0d2f5b5a7c8e9f01";
+    const SIGNATURE: &str = "MEQCIBLupq/FAJ0cRH6TkKzJU7xMIPLoEFupCWip28fqFsCk\
+                             AiB8SVMi7rSKH6ojSBF3K5s3TJNLXX0GxGshTief4hu3kg==";
+
+    fn policy() -> SignaturePolicy {
+        let key = test_helpers::fixture!("signatures/cosign.pub");
+
+        SignaturePolicy::new(true, &[key]).unwrap()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = test_helpers::fixture!("signatures/cosign.pub");
+
+        // Uncompressed point
+        assert_eq!(parse_key(key.as_bytes()).unwrap().len(), 65);
+        assert!(parse_key(b"ssh-ed25519 AAAA").is_err());
+        assert!(SignaturePolicy::new(true, &[] as &[&str]).is_err());
+    }
+
+    #[test]
+    fn test_verify_payload() {
+        let payload = test_helpers::fixture!("signatures/payload.json");
+        let signature = base64::decode(SIGNATURE).unwrap();
+        let policy = policy();
+
+        assert!(policy
+            .verify_payload(payload.as_bytes(), &signature, DIGEST)
+            .unwrap());
+        // Tampered payload
+        assert!(!policy.verify_payload(b"{}", &signature, DIGEST).unwrap());
+        // Signature of another image
+        assert!(policy
+            .verify_payload(payload.as_bytes(), &signature, "sha256:00")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_broken_signatures() {
+        use registratur::v2::client::RetryPolicy;
+        use test_helpers::mockito::{mock, server_url};
+
+        let image_name = "knast/broken-signature";
+        let path = format!("/v2/{}/manifests/sha256-00.sig", image_name);
+        let _manifest = mock("GET", path.as_str())
+            .with_body(format!(
+                r#"{{"layers": [{{"digest": "sha256:00",
+                   "annotations": {{"{}": "!"}}}}]}}"#,
+                SIGNATURE_ANNOTATION
+            ))
+            .create();
+        let url = server_url();
+        let client = Client::build(&url)
+            .expect("failed to build the client")
+            .with_retry_policy(RetryPolicy {
+                attempts: 1,
+                ..RetryPolicy::default()
+            });
+        let key = test_helpers::fixture!("signatures/cosign.pub");
+        let optional = SignaturePolicy::new(false, &[key]).unwrap();
+
+        assert!(optional
+            .verify(&client, image_name, "sha256:00")
+            .await
+            .is_ok());
+        assert!(policy()
+            .verify(&client, image_name, "sha256:00")
+            .await
+            .is_err());
+    }
+}
@@ -1,6 +1,34 @@
+/// Manifests and configs, keyed by digest. Layers are kept
+/// in the blob store, see [`Storage::blobs`], layers pulled
+/// before are still found here.
 pub const BLOBS_STORAGE_KEY: &[u8] = b"blobs";
 pub const IMAGES_INDEX_STORAGE_KEY: &[u8] = b"images";
+/// Partially downloaded blobs, keyed by digest. Superseded
+/// by partial files of the blob store, only pruned.
+pub const PARTIAL_BLOBS_STORAGE_KEY: &[u8] = b"partial_blobs";
+/// Manifest digests of images containers are built from,
+/// keyed by container folder name.
+pub const CONTAINERS_STORAGE_KEY: &[u8] = b"containers";
+/// Manifest digests Containerfile steps resulted in, keyed
+/// by step, see [`crate::build_cache::BuildCache`].
+pub const BUILD_CACHE_STORAGE_KEY: &[u8] = b"build_cache";
+/// Artifacts attached to image manifests, i.e. SBOMs,
+/// keyed by manifest digest, see
+/// [`crate::attachments::Attachments`].
+pub const ATTACHMENTS_STORAGE_KEY: &[u8] = b"attachments";
+/// Healthchecks of image configs, keyed by config digest.
+/// Configs themselves are kept as they're pulled, and
+/// healthchecks aren't a part of them.
+pub const HEALTHCHECKS_STORAGE_KEY: &[u8] = b"healthchecks";
+/// Containers are built in this subfolder of the storage.
+pub const CONTAINERS_FOLDER: &str = "containers";
+/// Layers are unpacked to this subfolder of the storage,
+/// when cached.
+pub const LAYERS_FOLDER: &str = "layers";
 
+// Same storage as containers and networks use, so that one
+// database backs them all.
+pub use storage::audit;
 pub use storage::Storage;
 pub use storage::StorageEngine;
 
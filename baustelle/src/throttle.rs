@@ -0,0 +1,84 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Unused bandwidth doesn't accumulate for longer than
+/// this, so that a long pause isn't followed by a burst.
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+/// Limits the download rate. Shared by concurrent
+/// downloads, so that the limit applies to their total.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    started_at: Instant,
+    consumed: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            state: Mutex::new(State {
+                started_at: Instant::now(),
+                consumed: 0,
+            }),
+        }
+    }
+
+    /// Accounts downloaded `bytes`, blocking while the rate
+    /// exceeds the limit.
+    pub fn consume(&self, bytes: u64) {
+        if let Some(delay) = self.delay(bytes) {
+            thread::sleep(delay);
+        }
+    }
+
+    fn delay(&self, bytes: u64) -> Option<Duration> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let credit = self.allowance(state.consumed) + MAX_BURST;
+
+        if state.started_at.elapsed() > credit {
+            state.started_at = Instant::now();
+            state.consumed = 0;
+        }
+
+        state.consumed += bytes;
+
+        self.allowance(state.consumed)
+            .checked_sub(state.started_at.elapsed())
+    }
+
+    /// Time it takes to download `bytes` at the limit.
+    fn allowance(&self, bytes: u64) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(1000);
+
+        let delay = throttle.delay(500).expect("must be throttled");
+        assert!(delay > Duration::from_millis(400));
+        assert!(delay <= Duration::from_millis(500));
+
+        let delay = throttle.delay(500).expect("must be throttled");
+        assert!(delay > Duration::from_millis(900));
+    }
+}
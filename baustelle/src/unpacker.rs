@@ -1,15 +1,66 @@
+mod layer_cache;
+
+use std::collections::{HashSet, VecDeque};
+use std::ffi::CString;
 use std::fs;
+use std::io::{Cursor, ErrorKind, Read};
+use std::os::unix::{
+    ffi::OsStrExt,
+    fs::{symlink, MetadataExt},
+};
 use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
 
 use anyhow::{anyhow, Context, Error, Result};
-use registratur::v2::domain::manifest::Manifest;
-
-use super::archive::Archive;
+use registratur::{
+    v2::domain::{
+        descriptor::Descriptor,
+        manifest::Manifest,
+        media_type::{self, Compression},
+    },
+    verify_reader,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::archive::{Archive, ExtractFlags};
+use super::cancellation::{self, CancellationToken};
 use super::storage::{Storage, StorageEngine, BLOBS_STORAGE_KEY};
+pub(crate) use layer_cache::LayerCache;
+
+/// Hides contents of lower layers in the directory.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// Hides the file or directory of lower layers.
+pub(crate) const WHITEOUT_PREFIX: &str = ".wh.";
+
+type Layer = Box<dyn Read + Send>;
+type StagedHandle = JoinHandle<Result<StagedLayer>>;
+type Progress<'a> = Box<dyn Fn(usize, &str) + 'a>;
 
 pub struct Unpacker<'a, T: StorageEngine> {
     storage: &'a Storage<T>,
     destination: &'a Path,
+    verify_on_read: bool,
+    parallelism: usize,
+    extract_flags: ExtractFlags,
+    layer_cache: bool,
+    progress: Option<Progress<'a>>,
+    cancellation: Option<CancellationToken>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Whiteout {
+    /// Directory, which contents are hidden.
+    Opaque(PathBuf),
+    /// Hidden file or directory.
+    Entry(PathBuf),
+}
+
+/// Layer extracted to a staging folder, whiteouts aren't
+/// applied yet.
+struct StagedLayer {
+    folder: PathBuf,
+    whiteouts: Vec<Whiteout>,
 }
 
 impl<'a, T: StorageEngine> Unpacker<'a, T> {
@@ -17,6 +68,71 @@ impl<'a, T: StorageEngine> Unpacker<'a, T> {
         Self {
             storage,
             destination,
+            verify_on_read: false,
+            parallelism: 1,
+            extract_flags: ExtractFlags::default(),
+            layer_cache: false,
+            progress: None,
+            cancellation: None,
+        }
+    }
+
+    /// Stops before the next layer, once the token is
+    /// cancelled. Layers unpacked so far are left in place.
+    pub fn with_cancellation(self, token: CancellationToken) -> Self {
+        Self {
+            cancellation: Some(token),
+            ..self
+        }
+    }
+
+    /// Overrides the metadata restored on extraction.
+    pub fn with_extract_flags(self, extract_flags: ExtractFlags) -> Self {
+        Self {
+            extract_flags,
+            ..self
+        }
+    }
+
+    /// Rehashes layers before unpacking, so that corrupt
+    /// storage is detected.
+    pub fn with_verify_on_read(self, verify_on_read: bool) -> Self {
+        Self {
+            verify_on_read,
+            ..self
+        }
+    }
+
+    /// Extracts up to `parallelism` layers concurrently to
+    /// staging folders next to the destination. Layers are
+    /// then moved in place one by one, in order, so that
+    /// whiteouts apply to the lower layers only.
+    pub fn with_parallelism(self, parallelism: usize) -> Self {
+        Self {
+            parallelism: parallelism.max(1),
+            ..self
+        }
+    }
+
+    /// Unpacks each layer once into the storage, see
+    /// [`LayerCache`], and hard links its files to the
+    /// destination. Files are shared by containers hence,
+    /// the destination is to be mounted read-only, see
+    /// [`crate::Image::create_bundle`].
+    pub fn with_layer_cache(self, layer_cache: bool) -> Self {
+        Self {
+            layer_cache,
+            ..self
+        }
+    }
+
+    /// Calls `progress` once a layer is unpacked to the
+    /// destination, with the number of layers unpacked so far
+    /// and the layer digest.
+    pub fn with_progress(self, progress: impl Fn(usize, &str) + 'a) -> Self {
+        Self {
+            progress: Some(Box::new(progress)),
+            ..self
         }
     }
 
@@ -24,68 +140,447 @@ impl<'a, T: StorageEngine> Unpacker<'a, T> {
     pub fn unpack(&self, digest: String) {
         let maybe_manifest: Option<Manifest> =
             self.storage.get(BLOBS_STORAGE_KEY, digest)?;
+        let layers = match maybe_manifest {
+            Some(manifest) => manifest.layers,
+            None => fehler::throw!(anyhow!("Image is not cached")),
+        };
 
-        if let Some(manifest) = maybe_manifest {
-            manifest
-                .layers
-                .into_iter()
-                .map(|layer| self.unpack_layer(layer.digest))
-                .collect::<Result<Vec<_>>>()?;
+        if self.layer_cache {
+            self.unpack_cached(layers)?;
+        } else if self.parallelism > 1 {
+            self.unpack_staged(layers)?;
         } else {
-            fehler::throw!(anyhow!("Image is not cached"));
+            for (index, layer) in layers.iter().enumerate() {
+                cancellation::check(self.cancellation.as_ref())?;
+                self.unpack_layer(layer)?;
+                self.report(index, &layer.digest);
+            }
+        }
+    }
+
+    fn report(&self, index: usize, digest: &str) {
+        if let Some(progress) = &self.progress {
+            progress(index + 1, digest);
         }
     }
 
     #[fehler::throws]
-    fn unpack_layer(&self, digest: String) {
-        let maybe_digest: Option<Vec<u8>> =
-            self.storage.get(BLOBS_STORAGE_KEY, digest)?;
+    fn unpack_layer(&self, layer: &Descriptor) {
+        let compression = compression(layer)?;
+        let digest = &layer.digest;
+
+        if self.verify_on_read {
+            verify_layer(self.read_layer(digest)?, digest)?;
+        }
 
-        if let Some(layer) = maybe_digest {
-            let archive = Archive::new(&layer);
+        let archive = Archive::new(self.read_layer(digest)?, compression)
+            .with_flags(self.extract_flags);
+        // Entries of this layer, which whiteouts don't affect
+        let mut extracted = HashSet::new();
 
-            self.handle_whiteouts(&archive)?;
-            archive.extract(&self.destination, |entry| {
-                match Path::new(&entry).file_name() {
-                    None => false,
-                    Some(name) => name.to_string_lossy().starts_with(".wh."),
+        archive.extract(&self.destination, |entry| {
+            let entry = PathBuf::from(entry);
+
+            match whiteout(&entry)? {
+                Some(Whiteout::Opaque(directory)) => {
+                    clear_directory(&directory, &extracted)?
+                }
+                Some(Whiteout::Entry(path)) => {
+                    if !extracted.contains(&path) {
+                        remove(&path)?;
+                    }
                 }
-            })?;
+                None => {
+                    extracted.insert(entry);
+
+                    return Ok(false);
+                }
+            }
+
+            Ok(true)
+        })?;
+    }
+
+    #[fehler::throws]
+    fn unpack_staged(&self, layers: Vec<Descriptor>) {
+        let staging = self.destination.with_extension("staging");
+        let mut pending = VecDeque::new();
+
+        fs::create_dir_all(&self.destination)?;
+
+        let result = self.apply_staged(&staging, layers, &mut pending);
+
+        // Extractions in flight write to the staging folder
+        for (_, _, handle) in pending {
+            let _ = handle.join();
+        }
+
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        result?
+    }
+
+    #[fehler::throws]
+    fn apply_staged(
+        &self,
+        staging: &Path,
+        layers: Vec<Descriptor>,
+        pending: &mut VecDeque<(usize, String, StagedHandle)>,
+    ) {
+        let mut layers = layers.into_iter().enumerate();
+
+        loop {
+            cancellation::check(self.cancellation.as_ref())?;
+
+            while pending.len() < self.parallelism {
+                match layers.next() {
+                    Some((index, layer)) => {
+                        let folder = staging.join(index.to_string());
+                        let digest = layer.digest.clone();
+
+                        pending.push_back((
+                            index,
+                            digest,
+                            self.stage(layer, folder)?,
+                        ));
+                    }
+                    None => break,
+                }
+            }
+
+            let (index, digest, staged) = match pending.pop_front() {
+                Some((index, digest, handle)) => (
+                    index,
+                    digest,
+                    handle
+                        .join()
+                        .map_err(|_| anyhow!("Layer extraction panicked"))??,
+                ),
+                None => break,
+            };
+
+            staged.apply(&self.destination)?;
+            self.report(index, &digest);
+        }
+    }
+
+    #[fehler::throws]
+    fn unpack_cached(&self, layers: Vec<Descriptor>) {
+        let cache = LayerCache::new(&self.storage.folder());
+        let digests: Vec<_> =
+            layers.iter().map(|layer| layer.digest.clone()).collect();
+        let mut missing = vec![];
+
+        for layer in layers {
+            if !cache.contains(&layer.digest)? {
+                missing.push(layer);
+            }
+        }
+
+        let mut missing = missing.into_iter().peekable();
+
+        // Missing layers are extracted `parallelism` at once
+        while missing.peek().is_some() {
+            cancellation::check(self.cancellation.as_ref())?;
+
+            let mut pending = vec![];
+
+            for layer in missing.by_ref().take(self.parallelism) {
+                let digest = layer.digest.clone();
+                let folder = cache.partial(&digest)?;
+
+                pending.push((digest, self.stage(layer, folder)?));
+            }
+
+            for (digest, handle) in pending {
+                let staged = handle
+                    .join()
+                    .map_err(|_| anyhow!("Layer extraction panicked"))??;
+
+                cache.insert(&digest, staged)?;
+            }
+        }
+
+        fs::create_dir_all(&self.destination)?;
+
+        for (index, digest) in digests.iter().enumerate() {
+            cancellation::check(self.cancellation.as_ref())?;
+            cache.get(digest)?.link(&self.destination)?;
+            self.report(index, digest);
+        }
+    }
+
+    /// Extracts the layer to `folder` in the background.
+    #[fehler::throws]
+    fn stage(&self, layer: Descriptor, folder: PathBuf) -> StagedHandle {
+        let compression = compression(&layer)?;
+        let digest = layer.digest;
+        let verification = if self.verify_on_read {
+            Some(self.read_layer(&digest)?)
         } else {
-            fehler::throw!(anyhow!(
-                "Layer is not cached. DB might be corrupted"
-            ));
+            None
+        };
+        let content = self.read_layer(&digest)?;
+        let flags = self.extract_flags;
+
+        thread::spawn(move || {
+            if let Some(verification) = verification {
+                verify_layer(verification, &digest)?;
+            }
+
+            StagedLayer::extract(content, compression, flags, folder)
+        })
+    }
+
+    /// Opens the layer from the blob store, falling back to
+    /// the engine for layers stored before.
+    #[fehler::throws]
+    fn read_layer(&self, digest: &str) -> Layer {
+        if let Some(file) = self.storage.blobs().open(digest)? {
+            return Box::new(file);
         }
+
+        let layer: Vec<u8> = self
+            .storage
+            .get(BLOBS_STORAGE_KEY, digest)?
+            .context("Layer is not cached. DB might be corrupted")?;
+
+        Box::new(Cursor::new(layer))
+    }
+}
+
+impl StagedLayer {
+    #[fehler::throws]
+    fn extract(
+        content: Layer,
+        compression: Compression,
+        flags: ExtractFlags,
+        folder: PathBuf,
+    ) -> Self {
+        let mut whiteouts = vec![];
+
+        fs::create_dir_all(&folder)?;
+        let archive = Archive::new(content, compression).with_flags(flags);
+
+        archive.extract(&folder, |entry| {
+            let entry = PathBuf::from(entry);
+            let relative = |path: PathBuf| {
+                path.strip_prefix(&folder).map(Path::to_path_buf)
+            };
+
+            match whiteout(&entry)? {
+                Some(Whiteout::Opaque(directory)) => {
+                    whiteouts.push(Whiteout::Opaque(relative(directory)?))
+                }
+                Some(Whiteout::Entry(path)) => {
+                    whiteouts.push(Whiteout::Entry(relative(path)?))
+                }
+                None => return Ok(false),
+            }
+
+            Ok(true)
+        })?;
+
+        Self { folder, whiteouts }
     }
 
+    /// Applies whiteouts to the destination, then moves
+    /// the layer there.
     #[fehler::throws]
-    fn handle_whiteouts(&self, archive: &Archive) {
-        archive
-            .entries()?
-            .map(|maybe_entry| {
-                maybe_entry.map::<Result<()>, _>(|entry: PathBuf| {
-                    let filename = entry.file_name().context(
-                        "Failed to extract filename from the archive header",
-                    )?;
-                    let parent = entry.parent().context(
-                        "Failed to extract dirname from the archive header",
-                    )?;
-
-                    let parent = self.destination.join(parent);
-                    let entry = self.destination.join(&entry);
-
-                    match &*filename.to_string_lossy() {
-                        ".wh..wh..opq" => fs::remove_dir_all(parent)?,
-                        item if item.starts_with(".wh.") => {
-                            fs::remove_file(&entry)?
-                        }
-                        _ => (),
-                    };
-
-                    Ok(())
-                })
-            })
-            .collect::<Result<Vec<_>>>()?
+    fn apply(self, destination: &Path) {
+        self.apply_whiteouts(destination)?;
+
+        merge(&self.folder, destination)?;
+        fs::remove_dir_all(&self.folder)?;
+    }
+
+    /// Applies whiteouts to the destination, then hard
+    /// links the layer there, keeping it intact.
+    #[fehler::throws]
+    fn link(&self, destination: &Path) {
+        self.apply_whiteouts(destination)?;
+
+        link(&self.folder, destination)?;
+    }
+
+    #[fehler::throws]
+    fn apply_whiteouts(&self, destination: &Path) {
+        for whiteout in &self.whiteouts {
+            match whiteout {
+                Whiteout::Opaque(directory) => clear_directory(
+                    &destination.join(directory),
+                    &HashSet::new(),
+                )?,
+                Whiteout::Entry(path) => remove(&destination.join(path))?,
+            }
+        }
+    }
+}
+
+/// Compression of the layer, judging by its media type.
+#[fehler::throws]
+fn compression(layer: &Descriptor) -> Compression {
+    media_type::compression(&layer.media_type).with_context(|| {
+        format!("Unsupported layer media type {}", layer.media_type)
+    })?
+}
+
+#[fehler::throws]
+fn verify_layer(layer: Layer, digest: &str) {
+    verify_reader(layer, digest).with_context(|| {
+        format!("Layer {} is corrupt, check the storage", digest)
+    })?;
+}
+
+/// Whiteout the entry of the layer stands for, if any.
+#[fehler::throws]
+fn whiteout(entry: &Path) -> Option<Whiteout> {
+    let filename = entry
+        .file_name()
+        .context("Failed to extract filename from the archive header")?
+        .to_string_lossy();
+    let parent = entry
+        .parent()
+        .context("Failed to extract dirname from the archive header")?;
+
+    if filename == OPAQUE_WHITEOUT {
+        Some(Whiteout::Opaque(parent.to_path_buf()))
+    } else if let Some(name) = filename.strip_prefix(WHITEOUT_PREFIX) {
+        Some(Whiteout::Entry(parent.join(name)))
+    } else {
+        None
+    }
+}
+
+/// Moves entries of `source` to `target`. Directories
+/// present in both are merged, taking permissions of the
+/// `source` ones, other entries are replaced.
+#[fehler::throws]
+fn merge(source: &Path, target: &Path) {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let destination = target.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() && is_directory(&destination) {
+            let permissions = entry.metadata()?.permissions();
+
+            merge(&entry.path(), &destination)?;
+            fs::set_permissions(&destination, permissions)?;
+        } else {
+            remove(&destination)?;
+            fs::rename(entry.path(), &destination)?;
+        }
+    }
+}
+
+/// Hard links entries of `source` to `target`, recreating
+/// directories and symbolic links. Entries present in both
+/// are replaced, directories are merged.
+#[fehler::throws]
+fn link(source: &Path, target: &Path) {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let destination = target.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        let metadata = entry.metadata()?;
+
+        if file_type.is_dir() {
+            if !is_directory(&destination) {
+                remove(&destination)?;
+                fs::create_dir(&destination)?;
+                copy_owner(&metadata, &destination)?;
+            }
+
+            link(&entry.path(), &destination)?;
+            fs::set_permissions(&destination, metadata.permissions())?;
+        } else if file_type.is_symlink() {
+            remove(&destination)?;
+            symlink(fs::read_link(entry.path())?, &destination)?;
+            copy_owner(&metadata, &destination)?;
+        } else {
+            remove(&destination)?;
+            fs::hard_link(entry.path(), &destination)?;
+        }
+    }
+}
+
+/// Restores the owner of the entry, which only root can do.
+#[fehler::throws]
+fn copy_owner(metadata: &fs::Metadata, path: &Path) {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    if unsafe { libc::lchown(path.as_ptr(), metadata.uid(), metadata.gid()) }
+        != 0
+    {
+        fehler::throw!(std::io::Error::last_os_error());
+    }
+}
+
+/// Whether the path is a directory, not a link to one.
+fn is_directory(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|metadata| metadata.is_dir())
+        .unwrap_or_default()
+}
+
+/// Removes contents of lower layers from the directory,
+/// keeping the `extracted` ones.
+#[fehler::throws]
+fn clear_directory(directory: &Path, extracted: &HashSet<PathBuf>) {
+    if !directory.is_dir() {
+        return;
+    }
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+
+        if !extracted.contains(&path) {
+            remove(&path)?;
+        } else if path.is_dir() {
+            clear_directory(&path, extracted)?;
+        }
+    }
+}
+
+/// Replaces hard links beneath `path`, i.e. to the cached
+/// layers, with copies, so that they're modified safely.
+#[fehler::throws]
+pub(crate) fn unshare(path: &Path) {
+    if !is_directory(path) {
+        return;
+    }
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let metadata = entry.metadata()?;
+
+        if file_type.is_dir() {
+            unshare(&entry.path())?;
+        } else if file_type.is_file() && metadata.nlink() > 1 {
+            let copy = path.join(Uuid::new_v4().to_string());
+
+            fs::copy(entry.path(), &copy)?;
+            copy_owner(&metadata, &copy)?;
+            fs::rename(&copy, entry.path())?;
+        }
+    }
+}
+
+/// Removes the file or the directory, if it exists.
+#[fehler::throws]
+fn remove(path: &Path) {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(error) if error.kind() == ErrorKind::NotFound => (),
+        Err(error) => fehler::throw!(error),
     }
 }
 
@@ -95,7 +590,7 @@ mod test {
 
     use registratur::v2::client::Client;
 
-    use super::Unpacker;
+    use super::{unshare, LayerCache, Unpacker};
     use crate::{fetcher::Fetcher, storage::TestStorage as Storage};
 
     #[tokio::test]
@@ -115,9 +610,10 @@ mod test {
             let fetcher =
                 Fetcher::new(&storage, client, architecture.into(), os);
             let (tx, _) = futures::channel::mpsc::channel(1);
+            let reference = "nginx:1.17.10".parse().unwrap();
 
             fetcher
-                .fetch("nginx", "1.17.10", tx)
+                .fetch(&reference, tx)
                 .await
                 .expect("Failed to fetch the image")
         };
@@ -168,30 +664,77 @@ mod test {
             let fetcher =
                 Fetcher::new(&storage, client, architecture.into(), os);
             let (tx, _) = futures::channel::mpsc::channel(1);
+            let reference = "nginx:1.17.10".parse().unwrap();
 
             fetcher
-                .fetch("nginx", "1.17.10", tx)
+                .fetch(&reference, tx)
                 .await
                 .expect("Failed to fetch the image")
         };
 
-        let destination = tempdir.into_path().join(&digest);
-        let unpacker = Unpacker::new(&storage, &destination);
+        let root = tempdir.into_path();
+        let expected: Vec<PathBuf> =
+            test_helpers::code_fixture!("unpacked_layers");
 
-        unpacker
-            .unpack(digest)
-            .expect("Failed to unpack the archive");
+        for parallelism in &[1, 3] {
+            let destination = root.join(parallelism.to_string());
+            let unpacker = Unpacker::new(&storage, &destination)
+                .with_parallelism(*parallelism);
+
+            unpacker
+                .unpack(digest.clone())
+                .expect("Failed to unpack the archive");
+
+            let mut result = visit_dirs(&destination, vec![])
+                .expect("Failed to read the directory")
+                .into_iter()
+                .map(|x| x.strip_prefix(&destination).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+
+            result.sort();
+
+            assert_eq!(result, expected);
+            assert!(!destination.with_extension("staging").exists());
+        }
+
+        // Second container reuses the unpacked layers
+        for name in &["cached", "cached_again"] {
+            let destination = root.join(name);
+
+            Unpacker::new(&storage, &destination)
+                .with_layer_cache(true)
+                .unpack(digest.clone())
+                .expect("Failed to unpack the archive");
+
+            let mut result = visit_dirs(&destination, vec![])
+                .expect("Failed to read the directory")
+                .into_iter()
+                .map(|x| x.strip_prefix(&destination).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+
+            result.sort();
 
-        let mut result = visit_dirs(&destination, vec![])
-            .expect("Failed to read the directory")
-            .into_iter()
-            .map(|x| x.strip_prefix(&destination).unwrap().to_path_buf())
-            .collect::<Vec<_>>();
+            assert_eq!(result, expected);
+        }
+
+        let cache = LayerCache::new(&storage.folder());
+        assert_eq!(cache.digests().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_unshare() {
+        let tempdir = tempfile::tempdir().expect("Failed to create a tempdir");
+        let cached = tempdir.path().join("cached");
+        let volume = tempdir.path().join("volume");
 
-        let expected = test_helpers::code_fixture!("unpacked_layers");
+        fs::create_dir(&volume).unwrap();
+        fs::write(&cached, "cached").unwrap();
+        fs::hard_link(&cached, volume.join("file")).unwrap();
 
-        result.sort();
+        unshare(&volume).expect("Failed to unshare the files");
+        fs::write(volume.join("file"), "modified").unwrap();
 
-        assert_eq!(result, expected);
+        assert_eq!(fs::read_to_string(&cached).unwrap(), "cached");
+        assert_eq!(fs::read_dir(&volume).unwrap().count(), 1);
     }
 }
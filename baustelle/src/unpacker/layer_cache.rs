@@ -0,0 +1,183 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Error};
+use uuid::Uuid;
+
+use super::{StagedLayer, Whiteout};
+use crate::storage::LAYERS_FOLDER;
+
+const ROOTFS: &str = "rootfs";
+const WHITEOUTS: &str = "whiteouts.json";
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Layers unpacked once per digest, `<algorithm>/<hex>`
+/// under the storage folder. Each holds the extracted
+/// `rootfs` and the whiteouts, which are yet to be applied
+/// to the lower layers.
+pub(crate) struct LayerCache {
+    folder: PathBuf,
+}
+
+impl LayerCache {
+    pub fn new(storage_folder: &Path) -> Self {
+        Self {
+            folder: storage_folder.join(LAYERS_FOLDER),
+        }
+    }
+
+    #[fehler::throws]
+    pub fn contains(&self, digest: &str) -> bool {
+        self.path(digest)?.is_dir()
+    }
+
+    /// Unique folder to extract the layer to, before it's
+    /// inserted.
+    #[fehler::throws]
+    pub fn partial(&self, digest: &str) -> PathBuf {
+        let path = self.path(digest)?;
+        let name = format!(
+            "{}.{}.{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            Uuid::new_v4(),
+            PARTIAL_EXTENSION
+        );
+
+        path.with_file_name(name).join(ROOTFS)
+    }
+
+    /// Moves the layer staged in the partial folder in place.
+    /// The layer might have been inserted concurrently, the
+    /// staged one is dropped then.
+    #[fehler::throws]
+    pub fn insert(&self, digest: &str, staged: StagedLayer) {
+        let partial = staged
+            .folder
+            .parent()
+            .ok_or_else(|| anyhow!("Layer {} isn't staged", digest))?;
+
+        fs::write(
+            partial.join(WHITEOUTS),
+            serde_json::to_vec(&staged.whiteouts)?,
+        )?;
+
+        if let Err(error) = fs::rename(partial, self.path(digest)?) {
+            if !self.contains(digest)? {
+                fehler::throw!(error);
+            }
+
+            fs::remove_dir_all(partial)?;
+        }
+    }
+
+    #[fehler::throws]
+    pub fn get(&self, digest: &str) -> StagedLayer {
+        let path = self.path(digest)?;
+        let whiteouts: Vec<Whiteout> =
+            serde_json::from_slice(&fs::read(path.join(WHITEOUTS))?)?;
+
+        StagedLayer {
+            folder: path.join(ROOTFS),
+            whiteouts,
+        }
+    }
+
+    /// Digests of cached layers, in ascending order.
+    #[fehler::throws]
+    pub fn digests(&self) -> Vec<String> {
+        let mut result = vec![];
+
+        for (algorithm, path) in self.entries()? {
+            let is_partial = path
+                .extension()
+                .map_or(false, |extension| extension == PARTIAL_EXTENSION);
+
+            if let (false, Some(hex)) = (is_partial, path.file_name()) {
+                let hex = hex.to_string_lossy();
+
+                result.push(format!("{}:{}", algorithm, hex));
+            }
+        }
+
+        result.sort();
+
+        result
+    }
+
+    #[fehler::throws]
+    pub fn remove(&self, digest: &str) {
+        remove_dir(&self.path(digest)?)?;
+    }
+
+    /// Removes layers, which extraction was interrupted.
+    #[fehler::throws]
+    pub fn remove_partials(&self) {
+        for (_, path) in self.entries()? {
+            if path
+                .extension()
+                .map_or(false, |ext| ext == PARTIAL_EXTENSION)
+            {
+                remove_dir(&path)?;
+            }
+        }
+    }
+
+    /// Folders of the layers, along with the digest
+    /// algorithm.
+    #[fehler::throws]
+    fn entries(&self) -> Vec<(String, PathBuf)> {
+        let mut result = vec![];
+
+        if !self.folder.is_dir() {
+            return result;
+        }
+
+        for algorithm in fs::read_dir(&self.folder)? {
+            let algorithm = algorithm?;
+
+            if !algorithm.file_type()?.is_dir() {
+                continue;
+            }
+
+            let name = algorithm.file_name().to_string_lossy().into_owned();
+
+            for layer in fs::read_dir(algorithm.path())? {
+                result.push((name.clone(), layer?.path()));
+            }
+        }
+
+        result
+    }
+
+    /// Folder of the layer. Digest is validated, since it
+    /// makes up the path.
+    #[fehler::throws]
+    fn path(&self, digest: &str) -> PathBuf {
+        let mut parts = digest.splitn(2, ':');
+        let algorithm = parts.next().unwrap_or_default();
+        let hex = parts.next().unwrap_or_default();
+        let valid = !algorithm.is_empty()
+            && !hex.is_empty()
+            && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+            && hex.chars().all(|c| c.is_ascii_hexdigit());
+
+        if !valid {
+            fehler::throw!(anyhow!("Invalid digest {}", digest));
+        }
+
+        self.folder.join(algorithm).join(hex)
+    }
+}
+
+#[fehler::throws]
+fn remove_dir(path: &Path) {
+    match fs::remove_dir_all(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => {
+            fehler::throw!(error)
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,71 @@
+/// Environment variables of processes, which are given as
+/// `NAME=value` entries.
+use std::{error::Error, fmt};
+
+/// Entry, which isn't `NAME=value`.
+#[derive(Debug, PartialEq)]
+pub struct MalformedEnv(pub String);
+
+impl fmt::Display for MalformedEnv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Malformed environment variable '{}', expected NAME=value",
+            self.0
+        )
+    }
+}
+
+impl Error for MalformedEnv {}
+
+/// Splits the entry at the first `=`, so that values keep
+/// theirs, i.e. `JAVA_OPTS=-Dfoo=bar`. Values may be empty,
+/// names may not.
+pub fn split(entry: &str) -> Result<(&str, &str), MalformedEnv> {
+    match entry.find('=') {
+        Some(index) if index > 0 => Ok((&entry[..index], &entry[index + 1..])),
+        _ => Err(MalformedEnv(entry.into())),
+    }
+}
+
+/// Splits every entry, see [`split`].
+pub fn parse(
+    entries: &[String],
+) -> Result<Vec<(String, String)>, MalformedEnv> {
+    entries
+        .iter()
+        .map(|entry| {
+            let (name, value) = split(entry)?;
+
+            Ok((name.into(), value.into()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("PATH=/bin"), Ok(("PATH", "/bin")));
+        assert_eq!(
+            split("JAVA_OPTS=-Dfoo=bar"),
+            Ok(("JAVA_OPTS", "-Dfoo=bar"))
+        );
+        assert_eq!(split("EMPTY="), Ok(("EMPTY", "")));
+        assert_eq!(split("=value"), Err(MalformedEnv("=value".into())));
+        assert!(split("NAME").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let entries = vec!["A=1".into(), "B==".into()];
+
+        assert_eq!(
+            parse(&entries).unwrap(),
+            vec![("A".into(), "1".into()), ("B".into(), "=".into())]
+        );
+        assert!(parse(&["A=1".into(), "B".into()]).is_err());
+    }
+}
@@ -1,3 +1,6 @@
+pub mod env;
+pub mod metrics;
+
 pub trait AsSignedBytes {
     fn as_signed_bytes(&self) -> &[i8] {
         let bytes = unsafe { self.bytes().align_to() };
@@ -0,0 +1,283 @@
+/// Process-wide runtime metrics, exposed in Prometheus text
+/// format.
+///
+/// Metric kind is determined by the first update: counters
+/// are incremented, gauges are added to, histograms are
+/// observed. Operation latencies are recorded in seconds.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    sync::Mutex,
+    time::Instant,
+};
+
+use once_cell::sync::Lazy;
+
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+type Labels = Vec<(String, String)>;
+type Registry = BTreeMap<&'static str, BTreeMap<Labels, Series>>;
+
+#[derive(Debug)]
+enum Series {
+    Counter(f64),
+    Gauge(f64),
+    Histogram {
+        buckets: [u64; BUCKETS.len()],
+        sum: f64,
+        count: u64,
+    },
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn increment(name: &'static str, labels: &[(&str, &str)]) {
+    update(
+        name,
+        labels,
+        || Series::Counter(0.0),
+        |series| {
+            if let Series::Counter(value) = series {
+                *value += 1.0;
+            }
+        },
+    );
+}
+
+pub fn add(name: &'static str, labels: &[(&str, &str)], delta: f64) {
+    update(
+        name,
+        labels,
+        || Series::Gauge(0.0),
+        |series| {
+            if let Series::Gauge(value) = series {
+                *value += delta;
+            }
+        },
+    );
+}
+
+pub fn observe(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let histogram = || Series::Histogram {
+        buckets: [0; BUCKETS.len()],
+        sum: 0.0,
+        count: 0,
+    };
+
+    update(name, labels, histogram, |series| {
+        if let Series::Histogram {
+            buckets,
+            sum,
+            count,
+        } = series
+        {
+            for (bucket, bound) in buckets.iter_mut().zip(BUCKETS.iter()) {
+                if value <= *bound {
+                    *bucket += 1;
+                }
+            }
+
+            *sum += value;
+            *count += 1;
+        }
+    });
+}
+
+/// Observes the time elapsed until the timer is dropped.
+pub struct Timer {
+    name: &'static str,
+    labels: Labels,
+    started_at: Instant,
+}
+
+pub fn timer(name: &'static str, labels: &[(&str, &str)]) -> Timer {
+    Timer {
+        name,
+        labels: owned(labels),
+        started_at: Instant::now(),
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let labels: Vec<_> = self
+            .labels
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+
+        observe(self.name, &labels, self.started_at.elapsed().as_secs_f64());
+    }
+}
+
+/// Renders all the metrics in Prometheus text format.
+pub fn render() -> String {
+    let registry = match REGISTRY.lock() {
+        Ok(registry) => registry,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut output = String::new();
+
+    for (name, family) in registry.iter() {
+        let kind = match family.values().next() {
+            Some(Series::Counter(_)) => "counter",
+            Some(Series::Gauge(_)) => "gauge",
+            Some(Series::Histogram { .. }) => "histogram",
+            None => continue,
+        };
+
+        let _ = writeln!(output, "# TYPE {} {}", name, kind);
+
+        for (labels, series) in family {
+            match series {
+                Series::Counter(value) | Series::Gauge(value) => {
+                    let _ = writeln!(
+                        output,
+                        "{}{} {}",
+                        name,
+                        format_labels(labels, None),
+                        value
+                    );
+                }
+                Series::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    let bounds = BUCKETS.iter().map(|bound| bound.to_string());
+                    let counts = buckets.iter().chain(Some(count));
+
+                    for (bound, bucket) in
+                        bounds.chain(Some("+Inf".into())).zip(counts)
+                    {
+                        let _ = writeln!(
+                            output,
+                            "{}_bucket{} {}",
+                            name,
+                            format_labels(labels, Some(&bound)),
+                            bucket
+                        );
+                    }
+
+                    let labels = format_labels(labels, None);
+                    let _ = writeln!(output, "{}_sum{} {}", name, labels, sum);
+                    let _ =
+                        writeln!(output, "{}_count{} {}", name, labels, count);
+                }
+            }
+        }
+    }
+
+    output
+}
+
+/// Serves metrics over HTTP on connections accepted by
+/// either TCP or unix listener, every request gets the
+/// rendered metrics. Blocks forever.
+pub fn serve<S: Read + Write>(incoming: impl Iterator<Item = io::Result<S>>) {
+    for stream in incoming {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let mut reader = BufReader::new(&mut stream);
+
+        // Skip the request headers
+        let mut line = String::new();
+        while let Ok(read) = reader.read_line(&mut line) {
+            if read == 0 || line == "\r\n" || line == "\n" {
+                break;
+            }
+
+            line.clear();
+        }
+
+        let body = render();
+        let _ = write!(
+            stream,
+            "HTTP/1.0 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+    }
+}
+
+fn update(
+    name: &'static str,
+    labels: &[(&str, &str)],
+    new: impl FnOnce() -> Series,
+    f: impl FnOnce(&mut Series),
+) {
+    let mut registry = match REGISTRY.lock() {
+        Ok(registry) => registry,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    f(registry
+        .entry(name)
+        .or_insert_with(BTreeMap::new)
+        .entry(owned(labels))
+        .or_insert_with(new));
+}
+
+fn owned(labels: &[(&str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .chain(le.map(|le| ("le", le)))
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        increment("test_pulls_total", &[("result", "success")]);
+        increment("test_pulls_total", &[("result", "success")]);
+        add("test_containers", &[("state", "running")], 1.0);
+        observe("test_duration_seconds", &[("operation", "create")], 0.3);
+
+        let output = render();
+
+        assert!(output.contains("# TYPE test_pulls_total counter\n"));
+        assert!(output.contains(r#"test_pulls_total{result="success"} 2"#));
+        assert!(output.contains(r#"test_containers{state="running"} 1"#));
+        assert!(output.contains(
+            r#"test_duration_seconds_bucket{operation="create",le="0.25"} 0"#
+        ));
+        assert!(output.contains(
+            r#"test_duration_seconds_bucket{operation="create",le="0.5"} 1"#
+        ));
+        assert!(output
+            .contains(r#"test_duration_seconds_count{operation="create"} 1"#));
+    }
+}
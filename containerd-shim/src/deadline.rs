@@ -0,0 +1,141 @@
+/// Deadlines of the requests.
+///
+/// Blocking operations run on the worker pool, so that the
+/// handler answers containerd once the deadline passes, even
+/// if the operation hangs, i.e. on a stuck mount. Operations
+/// are told to give up via [`Cancellation`]; the ones which
+/// can't check it finish in the background.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use tokio::runtime::{Builder, Runtime};
+use ttrpc::TtrpcContext;
+
+/// Operations running at once, the rest are queued.
+const MAX_WORKERS: usize = 64;
+
+#[derive(Debug)]
+pub struct Workers {
+    runtime: Runtime,
+}
+
+/// Tells the operation, that nobody waits for it anymore.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fails, if the operation is cancelled.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            anyhow::bail!("Operation is cancelled");
+        }
+
+        Ok(())
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+}
+
+/// Operation, which didn't complete in time.
+#[derive(Debug)]
+pub struct DeadlineExceeded {
+    pub operation: &'static str,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} didn't complete in {:?}",
+            self.operation, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+impl Workers {
+    pub fn new() -> Result<Self, Error> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(MAX_WORKERS)
+            .thread_name("knast-worker")
+            .build()?;
+
+        Ok(Self { runtime })
+    }
+
+    /// Runs the `operation` on the pool. Gives up once the
+    /// `timeout`, if any, passes, cancelling the operation.
+    pub fn run<R: Send + 'static>(
+        &self,
+        operation: &'static str,
+        timeout: Option<Duration>,
+        f: impl FnOnce(&Cancellation) -> Result<R, Error> + Send + 'static,
+    ) -> Result<R, Error> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let cancellation = Cancellation::default();
+        let token = cancellation.clone();
+        let span = tracing::Span::current();
+
+        self.runtime.spawn_blocking(move || {
+            let _entered = span.enter();
+
+            // Nobody listens, if the deadline has passed
+            let _ = sender.send(f(&token));
+        });
+
+        let result = match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout),
+            None => receiver.recv().map_err(RecvTimeoutError::from),
+        };
+
+        match result {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                let timeout = timeout.unwrap_or_default();
+
+                tracing::error!("{} timed out after {:?}", operation, timeout);
+                cancellation.cancel();
+
+                Err(DeadlineExceeded { operation, timeout }.into())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(anyhow!("{} panicked", operation))
+            }
+        }
+    }
+}
+
+/// Timeout of the request: containerd's deadline, if any,
+/// capped by the operation's own `limit`.
+pub fn timeout(
+    ctx: &TtrpcContext,
+    limit: Option<Duration>,
+) -> Option<Duration> {
+    let requested = if ctx.timeout_nano > 0 {
+        Some(Duration::from_nanos(ctx.timeout_nano as u64))
+    } else {
+        None
+    };
+
+    match (requested, limit) {
+        (Some(requested), Some(limit)) => Some(requested.min(limit)),
+        (requested, limit) => requested.or(limit),
+    }
+}
@@ -0,0 +1,147 @@
+/// containerd log drivers, selected by the scheme of the
+/// stdout URI.
+///
+/// `binary://path?arg=value` spawns a logger binary, which
+/// reads process' stdout and stderr from fds 3 and 4 and
+/// closes fd 5 once ready. `file:///path` appends both
+/// streams to the file.
+use std::{
+    fs::{File, OpenOptions},
+    iter,
+    os::unix::{io::FromRawFd, process::CommandExt},
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::Error;
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    unistd::{close, dup2, pipe2, read},
+};
+use url::Url;
+
+/// Logger fds, as defined by containerd.
+const LOGGER_FDS: [i32; 3] = [3, 4, 5];
+/// Logger's fds are moved out of the way before being
+/// placed, so that they don't clobber each other.
+const LOGGER_FDS_TEMPORARY_BASE: i32 = 10;
+
+/// Sets up `command` output according to the URI, if it
+/// names a log driver. Returns `false` for plain fifos.
+pub fn setup(
+    command: &mut Command,
+    uri: &str,
+    id: &str,
+) -> Result<bool, Error> {
+    let url = match Url::parse(uri) {
+        Ok(url) => url,
+        Err(_) => return Ok(false),
+    };
+
+    match url.scheme() {
+        "binary" => binary(command, &url, id)?,
+        "file" => file(command, &url)?,
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+fn binary(command: &mut Command, url: &Url, id: &str) -> Result<(), Error> {
+    let (stdout_read, stdout_write) = pipe2(OFlag::O_CLOEXEC)?;
+    let (stderr_read, stderr_write) = pipe2(OFlag::O_CLOEXEC)?;
+    let (ready_read, ready_write) = pipe2(OFlag::O_CLOEXEC)?;
+    let args = url.query_pairs().flat_map(|(key, value)| {
+        let value = Some(value.to_string()).filter(|value| !value.is_empty());
+
+        iter::once(key.to_string()).chain(value)
+    });
+    let namespace =
+        std::env::var("NAMESPACE").unwrap_or_else(|_| "default".into());
+    let mut logger = Command::new(url.path());
+
+    logger
+        .args(args)
+        .env("CONTAINER_ID", id)
+        .env("CONTAINER_NAMESPACE", namespace)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    unsafe {
+        logger.pre_exec(move || {
+            let mut temporary = [0; 3];
+
+            for (index, fd) in
+                [stdout_read, stderr_read, ready_write].iter().enumerate()
+            {
+                temporary[index] = fcntl(
+                    *fd,
+                    FcntlArg::F_DUPFD_CLOEXEC(LOGGER_FDS_TEMPORARY_BASE),
+                )
+                .map_err(to_io_error)?;
+            }
+
+            for (fd, target) in temporary.iter().zip(LOGGER_FDS.iter()) {
+                dup2(*fd, *target).map_err(to_io_error)?;
+            }
+
+            Ok(())
+        });
+    }
+
+    let spawned = logger.spawn();
+
+    for fd in &[stdout_read, stderr_read, ready_write] {
+        close(*fd)?;
+    }
+
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(error) => {
+            close(stdout_write)?;
+            close(stderr_write)?;
+            close(ready_read)?;
+
+            return Err(error.into());
+        }
+    };
+
+    // Logger closes its end once it's ready to consume output
+    let ready = read(ready_read, &mut [0]);
+    close(ready_read)?;
+    ready?;
+
+    tracing::info!("Logger {} is ready", child.id());
+    thread::spawn(move || {
+        // Logger exits once the process' output is closed
+        let status = child.wait();
+        tracing::info!("Logger exited with {:?}", status);
+    });
+
+    let stdout = unsafe { File::from_raw_fd(stdout_write) };
+    let stderr = unsafe { File::from_raw_fd(stderr_write) };
+
+    command.stdout(stdout).stderr(stderr);
+
+    Ok(())
+}
+
+fn file(command: &mut Command, url: &Url) -> Result<(), Error> {
+    let stdout = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(url.path())?;
+    let stderr = stdout.try_clone()?;
+
+    command.stdout(stdout).stderr(stderr);
+
+    Ok(())
+}
+
+fn to_io_error(error: nix::Error) -> std::io::Error {
+    match error.as_errno() {
+        Some(errno) => std::io::Error::from_raw_os_error(errno as i32),
+        None => std::io::Error::new(std::io::ErrorKind::Other, error),
+    }
+}
@@ -1,62 +1,120 @@
+mod deadline;
 mod filesystem;
+mod log_driver;
 mod oci_extensions;
+mod options;
 mod protocols;
+mod pty_proxy;
+mod reaper;
+mod recovery;
 mod task_service;
 
 use std::{
-    fs::remove_file,
-    io::Error as StdError,
-    sync::mpsc::{self, Receiver},
+    fs::{self, remove_file},
+    io::{self, Error as StdError},
+    net::TcpListener,
+    os::unix::net::UnixListener,
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
     thread, time,
 };
 
 use anyhow::Error;
+use common_lib::metrics;
 use libc::{rfork, RFCFDG, RFPROC};
-use libknast::operations::OciOperations;
-use storage::TestStorage;
+use libknast::{
+    logging::{self, LogConfig},
+    namespace,
+    operations::{self, OciOperations},
+};
+use protobuf::Message;
+use storage::DynamicStorage;
 use ttrpc::{client::Client, context, server::Server};
 
-use protocols::{shim::ConnectRequest, shim_ttrpc::TaskClient};
-use task_service::TaskService;
+use deadline::Workers;
+use options::Options;
+use protocols::{
+    shim::{ConnectRequest, DeleteResponse},
+    shim_ttrpc::TaskClient,
+};
+use reaper::Reaper;
+use task_service::{system_time_to_timestamp, TaskService};
 
 const CONNECTION_RETRY_ATTEMPTS: u32 = 3;
 const CONNECTION_TIMEOUT_NANOS: i64 = 1_000_000_000;
+const DEFAULT_LOG_PATH: &str = "/var/log/knast.log";
+/// Socket of the shim, which serves every container.
+const SOCKET_PATH: &str = "/var/run/knast/shim.sock";
+/// File of the bundle, which containerd reads the address of
+/// the shim from.
+const ADDRESS_FILE: &str = "address";
+/// Exit status of a task, which is gone along with its
+/// shim, as if it was killed.
+const KILLED_EXIT_STATUS: u32 = 128 + libc::SIGKILL as u32;
+/// Prometheus endpoint: either a local TCP address, i.e.
+/// `127.0.0.1:9707`, or a unix socket path.
+const METRICS_ADDRESS_VARIABLE: &str = "KNAST_METRICS_ADDRESS";
 
 fn main() {
-    let (command, id) = parse_opts();
-    match &command[..] {
-        "start" => start_command(),
-        "delete" => delete_command(id),
-        _ => panic!("Unknown command {:?}", command),
+    let options = Options::from_args().expect("Invalid command line");
+
+    match &options.command[..] {
+        "start" => start_command(&options),
+        "delete" => delete_command(&options),
+        command => panic!("Unknown command {:?}", command),
     }
 }
 
-fn start_command() {
-    if parent_process().is_ok() {
+/// Starts the shim, unless it's running already, and
+/// publishes its address to containerd.
+fn start_command(options: &Options) {
+    if parent_process(options).is_ok() {
         return;
     }
 
     match unsafe { rfork(RFPROC | RFCFDG) } {
         0 => {
-            child_process();
+            child_process(options);
         }
         -1 => {
             eprintln!("rfork failed {:?}", StdError::last_os_error());
         }
-        _pid => parent_process().expect("Server is not running"),
+        _pid => parent_process(options).expect("Server is not running"),
     }
 }
 
-fn delete_command(id: String) {
+/// Cleans up after the task, whose shim is gone, and
+/// reports the task's exit to containerd.
+fn delete_command(options: &Options) {
     let _guard = setup_logging();
-    let storage = storage();
-    let ops = OciOperations::new(&storage, id)
+    let storage = storage(options);
+    let key = namespace::key(&options.namespace, &options.id);
+    let ops = OciOperations::new(&storage, key)
         .expect("Failed to initialize runtime");
+    let state = ops.state().ok();
+
+    ops.delete();
+
+    let response = DeleteResponse {
+        pid: state.as_ref().map_or(0, |state| state.pid as _),
+        exit_status: state
+            .and_then(|state| state.exit_status)
+            .map_or(KILLED_EXIT_STATUS, |status| status as _),
+        exited_at: system_time_to_timestamp(time::SystemTime::now())
+            .ok()
+            .into(),
+        ..Default::default()
+    };
 
-    ops.delete()
+    response
+        .write_to_writer(&mut io::stdout())
+        .expect("Failed to write delete response");
 }
 
-fn parent_process() -> Result<(), Error> {
+fn parent_process(options: &Options) -> Result<(), Error> {
     client().and_then(|client| {
         let request = ConnectRequest::new();
         Ok(client.connect(
@@ -67,15 +125,16 @@ fn parent_process() -> Result<(), Error> {
 
     let server_address = server_address()?;
 
+    fs::write(options.bundle.join(ADDRESS_FILE), server_address.as_str())?;
     println!("{}", server_address.as_str());
 
     Ok(())
 }
 
-fn child_process() {
+fn child_process(options: &Options) {
     let _guard = setup_logging();
 
-    match server() {
+    match server(options) {
         Ok((mut server, shutdown_notification)) => {
             server.start().expect("failed to start server");
 
@@ -98,17 +157,27 @@ fn child_process() {
     }
 }
 
-fn server() -> Result<(Server, Receiver<()>), Error> {
+fn server(options: &Options) -> Result<(Server, Receiver<()>), Error> {
     let (sender, shutdown_notification) = mpsc::sync_channel(1);
     let nat_interface =
         std::env::var("NAT_INTERFACE").unwrap_or_else(|_| "lagg0".into());
+    let storage = Arc::new(storage(options));
+    let reaper = Reaper::spawn(storage.clone())?;
+    recovery::recover(&storage, &reaper);
+    let workers = Workers::new()?;
+    serve_metrics()?;
     let service = protocols::shim_ttrpc::create_task(TaskService::new(
-        storage(),
+        storage,
         sender,
         nat_interface,
+        reaper,
+        workers,
     ));
     tracing::info!("Initializing server");
     let address = server_address()?;
+    if let Some(directory) = Path::new(address.path()).parent() {
+        fs::create_dir_all(directory)?;
+    }
     if let Err(error) = remove_file(address.path()) {
         tracing::info!("Previous socket wasn't deleted due to {}", error)
     };
@@ -119,6 +188,29 @@ fn server() -> Result<(Server, Receiver<()>), Error> {
     Ok((server, shutdown_notification))
 }
 
+fn serve_metrics() -> Result<(), Error> {
+    let address = match std::env::var(METRICS_ADDRESS_VARIABLE) {
+        Ok(address) => address,
+        Err(_) => return Ok(()),
+    };
+
+    tracing::info!("Serving metrics at {}", address);
+
+    if address.starts_with('/') {
+        if let Err(error) = remove_file(&address) {
+            tracing::info!("Previous socket wasn't deleted due to {}", error)
+        };
+
+        let listener = UnixListener::bind(&address)?;
+        thread::spawn(move || metrics::serve(listener.incoming()));
+    } else {
+        let listener = TcpListener::bind(&address)?;
+        thread::spawn(move || metrics::serve(listener.incoming()));
+    }
+
+    Ok(())
+}
+
 fn client() -> Result<TaskClient, Error> {
     use nix::sys::socket::*;
 
@@ -154,41 +246,25 @@ fn client() -> Result<TaskClient, Error> {
     Ok(TaskClient::new(client))
 }
 
-fn storage() -> TestStorage {
-    let home = std::env::var("HOME").unwrap();
-    TestStorage::new(home).unwrap()
-}
+/// Storage of the engine `KNAST_STORAGE_ENGINE` names.
+fn storage(options: &Options) -> DynamicStorage {
+    let storage =
+        DynamicStorage::new(storage::root(options.root.as_deref())).unwrap();
 
-fn setup_logging() -> tracing_appender::non_blocking::WorkerGuard {
-    let file_appender =
-        tracing_appender::rolling::never("/var/log", "knast.log");
-    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-    tracing_subscriber::fmt().with_writer(non_blocking).init();
+    operations::migrate(&storage).expect("Failed to migrate the storage");
 
-    guard
+    storage
 }
 
-/// Returns command and container id
-fn parse_opts() -> (String, String) {
-    // Spike: this relies on arguments order.
-    let mut args = std::env::args().rev();
-    let command = args.next().expect("COMMAND is required");
-
-    let id = if command == "start" {
-        args.next()
-    } else {
-        args.next();
-        args.next();
-        args.next()
-    }
-    .expect("ID is required");
+fn setup_logging() -> tracing_appender::non_blocking::WorkerGuard {
+    let config = LogConfig::from_env(Some(Path::new(DEFAULT_LOG_PATH)))
+        .expect("Invalid logging configuration");
 
-    (command, id)
+    logging::init(&config).expect("Failed to set up logging")
 }
 
-// TODO: this should be static variable.
 fn server_address() -> Result<url::Url, Error> {
-    let address = url::Url::parse("unix:///tmp/knast.sock")?;
+    let address = url::Url::parse(&format!("unix://{}", SOCKET_PATH))?;
 
     Ok(address)
 }
@@ -1,23 +1,26 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{copy, Error as StdError, ErrorKind},
-    os::unix::{io::{FromRawFd, AsRawFd}, process::CommandExt},
-    process::{self, Command, Stdio},
-    thread,
+    fs::OpenOptions,
+    io::{Error as StdError, ErrorKind},
+    os::unix::process::CommandExt,
+    process::{self, Command},
 };
 
 use anyhow::Error;
-use libknast::operations::{OciOperations, Process};
+use libknast::operations::{ConsoleSize, OciOperations, Process};
 use nix::{
     pty::{openpty, OpenptyResult, Winsize},
     unistd::{close, dup2},
 };
 use serde::{Deserialize, Serialize};
-use storage::StorageEngine;
-use url::Url;
+use storage::{Collection, StorageEngine};
 
-const CONTAINER_STDIO_STORAGE_KEY: &[u8] = b"CONTAINER_STDIO";
-const CONTAINER_PTY_STATE_KEY: &[u8] = b"CONTAINER_PTY_STATE";
+use super::{log_driver, pty_proxy::PtyProxy};
+
+/// Keyed by container and exec id, as processes are.
+const CONTAINER_STDIO: Collection<(String, String), StdioTriple> =
+    Collection::new(b"CONTAINER_STDIO");
+const CONTAINER_PTY_STATE: Collection<(String, String), (i32, i32)> =
+    Collection::new(b"CONTAINER_PTY_STATE");
 
 extern "C" {
     /// Sets winsize, used for ResizePty call
@@ -48,13 +51,21 @@ pub trait ContainerdExtension {
         exec_id: &str,
         triple: StdioTriple,
     ) -> Result<(), Error>;
+    /// Forgets stdio triple of the container.
+    fn remove_stdio_triple(&self, exec_id: &str) -> Result<(), Error>;
     /// Resizes container's PTY
     fn resize_pty(&self, exec_id: &str, winsize: Winsize)
         -> Result<(), Error>;
     /// Persists PTY master side
-    fn save_pty_state(&self, exec_id: &str, pty: (i32, i32)) -> Result<(), Error>;
+    fn save_pty_state(
+        &self,
+        exec_id: &str,
+        pty: (i32, i32),
+    ) -> Result<(), Error>;
     /// Returns PTY state
     fn pty_state(&self, exec_id: &str) -> Result<(i32, i32), Error>;
+    /// Forgets PTY state, i.e. once its descriptors are gone
+    fn remove_pty_state(&self, exec_id: &str) -> Result<(), Error>;
 }
 
 impl<'a, T: StorageEngine> ContainerdExtension for OciOperations<'a, T> {
@@ -77,8 +88,11 @@ impl<'a, T: StorageEngine> ContainerdExtension for OciOperations<'a, T> {
 
     fn exec(self, exec_id: &str, process: Process) -> Result<(), Error> {
         let triple = self.stdio_triple(exec_id)?;
-        self.do_exec(&exec_id, process, |command| {
-            if let Some(pty) = setup_io(command, &triple)? {
+        let console_size = process.console_size.clone();
+        self.exec_process(&exec_id, process, |command| {
+            if let Some(pty) =
+                setup_io(command, self.key(), &triple, console_size.as_ref())?
+            {
                 self.save_pty_state(exec_id, pty)?;
             }
 
@@ -96,8 +110,14 @@ impl<'a, T: StorageEngine> ContainerdExtension for OciOperations<'a, T> {
 
     fn start(self, exec_id: &str) -> Result<(), Error> {
         let triple = self.stdio_triple(exec_id)?;
-        self.do_start(&exec_id, |command| {
-            if let Some(pty) = setup_io(command, &triple)? {
+        let console_size = self
+            .config()?
+            .process
+            .and_then(|process| process.console_size);
+        self.do_start(&exec_id, Default::default(), |command| {
+            if let Some(pty) =
+                setup_io(command, self.key(), &triple, console_size.as_ref())?
+            {
                 self.save_pty_state(exec_id, pty)?;
             }
 
@@ -114,11 +134,8 @@ impl<'a, T: StorageEngine> ContainerdExtension for OciOperations<'a, T> {
     }
 
     fn stdio_triple(&self, exec_id: &str) -> Result<StdioTriple, Error> {
-        self.storage()
-            .get(
-                CONTAINER_STDIO_STORAGE_KEY,
-                [self.key().as_bytes(), b"/", exec_id.as_bytes()].concat(),
-            )?
+        CONTAINER_STDIO
+            .get(self.storage(), &(self.key(), exec_id))?
             .ok_or_else(|| anyhow::anyhow!("Container IO triple wasn't found"))
     }
 
@@ -127,20 +144,26 @@ impl<'a, T: StorageEngine> ContainerdExtension for OciOperations<'a, T> {
         exec_id: &str,
         triple: StdioTriple,
     ) -> Result<(), Error> {
-        self.storage().put(
-            CONTAINER_STDIO_STORAGE_KEY,
-            [self.key().as_bytes(), b"/", exec_id.as_bytes()].concat(),
-            triple,
-        )?;
+        CONTAINER_STDIO.put(self.storage(), &(self.key(), exec_id), triple)?;
+
+        Ok(())
+    }
+
+    fn remove_stdio_triple(&self, exec_id: &str) -> Result<(), Error> {
+        CONTAINER_STDIO.remove(self.storage(), &(self.key(), exec_id))?;
 
         Ok(())
     }
 
-    fn save_pty_state(&self, exec_id: &str, pty: (i32, i32)) -> Result<(), Error> {
+    fn save_pty_state(
+        &self,
+        exec_id: &str,
+        pty: (i32, i32),
+    ) -> Result<(), Error> {
         tracing::info!("PTY for {}/{} is {:?}", self.key(), exec_id, pty);
-        self.storage().put(
-            CONTAINER_PTY_STATE_KEY,
-            [self.key().as_bytes(), b"/", exec_id.as_bytes()].concat(),
+        CONTAINER_PTY_STATE.put(
+            self.storage(),
+            &(self.key(), exec_id),
             pty,
         )?;
 
@@ -148,20 +171,23 @@ impl<'a, T: StorageEngine> ContainerdExtension for OciOperations<'a, T> {
     }
 
     fn pty_state(&self, exec_id: &str) -> Result<(i32, i32), Error> {
-        self.storage()
-            .get(
-                CONTAINER_PTY_STATE_KEY,
-                [self.key().as_bytes(), b"/", exec_id.as_bytes()].concat(),
-            )?
-            .ok_or_else(|| {
-                anyhow::anyhow!("Container's PTY wasn't found")
-            })
+        CONTAINER_PTY_STATE
+            .get(self.storage(), &(self.key(), exec_id))?
+            .ok_or_else(|| anyhow::anyhow!("Container's PTY wasn't found"))
+    }
+
+    fn remove_pty_state(&self, exec_id: &str) -> Result<(), Error> {
+        CONTAINER_PTY_STATE.remove(self.storage(), &(self.key(), exec_id))?;
+
+        Ok(())
     }
 }
 
 fn setup_io(
     command: &mut Command,
+    id: &str,
     triple: &StdioTriple,
+    console_size: Option<&ConsoleSize>,
 ) -> Result<Option<(i32, i32)>, Error> {
     tracing::info!("Initializing process IO");
     let StdioTriple {
@@ -173,20 +199,23 @@ fn setup_io(
 
     tracing::info!("Openning file descriptors");
     if *terminal {
-        let mut stdin = OpenOptions::new().read(true).open(stdin)?;
-        let mut stdout = OpenOptions::new().write(true).open(stdout)?;
-        let OpenptyResult { master, slave } = openpty(None, None)?;
-        tracing::info!("Setting up pty <-> containerd fifo pipe");
-        thread::spawn(move || {
-            let mut writer = unsafe { File::from_raw_fd(master) };
-            let result = copy(&mut stdin, &mut writer);
-            tracing::info!("Finished piping stdin with {:?}", result);
-        });
-        thread::spawn(move || {
-            let mut reader = unsafe { File::from_raw_fd(master) };
-            let result = copy(&mut reader, &mut stdout);
-            tracing::info!("Finished piping stdin with {:?}", result);
+        let stdin = if stdin.is_empty() {
+            None
+        } else {
+            Some(OpenOptions::new().read(true).open(stdin)?)
+        };
+        let stdout = OpenOptions::new().write(true).open(stdout)?;
+        // Sized upfront, so that TUIs start with proper
+        // dimensions
+        let winsize = console_size.map(|size| Winsize {
+            ws_row: size.height as _,
+            ws_col: size.width as _,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
         });
+        let OpenptyResult { master, slave } = openpty(winsize.as_ref(), None)?;
+        tracing::info!("Setting up pty <-> containerd fifo pipe");
+        PtyProxy::new(master, stdin, stdout).spawn();
 
         unsafe {
             command.pre_exec(move || {
@@ -217,38 +246,18 @@ fn setup_io(
             command.stdin(stdin);
         }
 
-        if stdout.starts_with("binary") {
-            let url = Url::parse(&stdout)?;
-            let path = url.path();
-
-            let child = Command::new(path)
-                .envs(
-                    url.query_pairs()
-                        .map(|(k, v)| (k.to_string(), v.to_string())),
-                )
-                .stdin(Stdio::piped())
-                .spawn()?;
-
-            // Unwrap: stdin set previously.
-            let result = child.stdin.as_ref().unwrap();
-            let raw_fd = result.as_raw_fd();
-
-            // thread::spawn(move || {
-            //     child.wait().unwrap();
-            // });
-
-            let stdout = unsafe { File::from_raw_fd(raw_fd) };
-            let stderr = unsafe { File::from_raw_fd(raw_fd) };
-
-            command.stdout(stdout).stderr(stderr);
-
+        if log_driver::setup(command, stdout, id)? {
             return Ok(None);
         }
 
         let stdout = OpenOptions::new().write(true).open(stdout)?;
-        let stderr = OpenOptions::new().write(true).open(stderr)?;
+        command.stdout(stdout);
+
+        if !stderr.is_empty() {
+            let stderr = OpenOptions::new().write(true).open(stderr)?;
+            command.stderr(stderr);
+        }
 
-        command.stdout(stdout).stderr(stderr);
         Ok(None)
     }
 }
@@ -0,0 +1,88 @@
+/// Command line of the shim, as containerd's runtime v2
+/// passes it: Go-style flags followed by the command, i.e.
+///
+/// `-namespace default -address /run/containerd/containerd.sock
+/// -publish-binary /usr/local/bin/containerd -id nginx start`
+///
+/// The bundle is the working directory, unless `-bundle` is
+/// given.
+use std::{env, path::PathBuf};
+
+use anyhow::{anyhow, Error};
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub command: String,
+    pub namespace: String,
+    pub id: String,
+    /// Address of containerd's socket.
+    pub address: String,
+    pub publish_binary: String,
+    pub bundle: PathBuf,
+    /// Folder of the storage, see [`storage::root`].
+    pub root: Option<String>,
+    pub debug: bool,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Self, Error> {
+        let mut options = Self::parse(env::args().skip(1))?;
+
+        if options.bundle.as_os_str().is_empty() {
+            options.bundle = env::current_dir()?;
+        }
+
+        Ok(options)
+    }
+
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') {
+                options.command = arg;
+                continue;
+            }
+
+            let flag = arg.trim_start_matches('-');
+            let (name, value) = match flag.find('=') {
+                Some(index) => (&flag[..index], Some(&flag[index + 1..])),
+                None => (flag, None),
+            };
+
+            if name == "debug" {
+                options.debug = value.map_or(true, |value| value == "true");
+                continue;
+            }
+
+            let value = match value {
+                Some(value) => value.to_string(),
+                None => args
+                    .next()
+                    .ok_or_else(|| anyhow!("Flag -{} needs a value", name))?,
+            };
+
+            match name {
+                "namespace" => options.namespace = value,
+                "id" => options.id = value,
+                "address" => options.address = value,
+                "publish-binary" => options.publish_binary = value,
+                "bundle" => options.bundle = value.into(),
+                "root" => options.root = Some(value),
+                // Flags of other containerd versions
+                _ => tracing::debug!("Ignoring flag -{}", name),
+            }
+        }
+
+        if options.command.is_empty() {
+            anyhow::bail!("COMMAND is required");
+        }
+
+        if options.id.is_empty() {
+            anyhow::bail!("-id is required");
+        }
+
+        Ok(options)
+    }
+}
@@ -0,0 +1,117 @@
+/// Proxies containerd's stdio fifos to the container's
+/// pty.
+///
+/// A single thread owns the pty master and polls both
+/// directions: stdin fifo -> master, master -> stdout fifo.
+/// Once the container exits, its side of the pty is closed,
+/// the remaining output is drained, and both the master and
+/// the fifos are closed, so that containerd sees EOF.
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    thread,
+};
+
+use anyhow::Error;
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+
+const BUFFER_SIZE: usize = 4096;
+
+pub struct PtyProxy {
+    master: File,
+    stdin: Option<File>,
+    stdout: File,
+}
+
+impl PtyProxy {
+    /// Takes ownership of the `master` fd.
+    pub fn new(master: RawFd, stdin: Option<File>, stdout: File) -> Self {
+        Self {
+            master: unsafe { File::from_raw_fd(master) },
+            stdin,
+            stdout,
+        }
+    }
+
+    pub fn spawn(self) {
+        thread::spawn(move || {
+            let result = self.run();
+            tracing::info!("Finished proxying pty with {:?}", result);
+        });
+    }
+
+    fn run(mut self) -> Result<(), Error> {
+        let mut buffer = [0; BUFFER_SIZE];
+
+        loop {
+            let mut fds =
+                vec![PollFd::new(self.master.as_raw_fd(), PollFlags::POLLIN)];
+
+            if let Some(stdin) = &self.stdin {
+                fds.push(PollFd::new(stdin.as_raw_fd(), PollFlags::POLLIN));
+            }
+
+            match poll(&mut fds, -1) {
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                result => result?,
+            };
+
+            let events = |index: usize| {
+                fds.get(index)
+                    .and_then(PollFd::revents)
+                    .unwrap_or_else(PollFlags::empty)
+            };
+            let master_events = events(0);
+            let stdin_events = events(1);
+
+            if master_events.contains(PollFlags::POLLIN) {
+                match self.master.read(&mut buffer) {
+                    // Container's side of the pty is closed
+                    Ok(0) => return Ok(()),
+                    Ok(read) => self.stdout.write_all(&buffer[..read])?,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                    Err(error) if error.raw_os_error() == Some(libc::EIO) => {
+                        return Ok(())
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            } else if master_events.intersects(
+                PollFlags::POLLHUP | PollFlags::POLLERR | PollFlags::POLLNVAL,
+            ) {
+                return Ok(());
+            }
+
+            if stdin_events.intersects(PollFlags::POLLIN | PollFlags::POLLHUP)
+            {
+                self.forward_stdin(&mut buffer)?;
+            } else if !stdin_events.is_empty() {
+                self.stdin = None;
+            }
+        }
+    }
+
+    /// Forwards a chunk of stdin to the pty. Stops polling
+    /// stdin once containerd closes it.
+    fn forward_stdin(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let stdin = match &mut self.stdin {
+            Some(stdin) => stdin,
+            None => return Ok(()),
+        };
+
+        match stdin.read(buffer) {
+            Ok(0) => self.stdin = None,
+            Ok(read) => self.master.write_all(&buffer[..read])?,
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+            Err(error) => {
+                tracing::error!("Failed to read stdin: {}", error);
+                self.stdin = None;
+            }
+        }
+
+        Ok(())
+    }
+}
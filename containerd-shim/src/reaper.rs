@@ -0,0 +1,206 @@
+/// Reaps container processes spawned by the shim.
+///
+/// containerd doesn't have to call wait for every process,
+/// and unwaited processes would linger as zombies. The
+/// reaper waits for the watched processes as soon as
+/// SIGCHLD arrives and records their exit statuses, which
+/// `wait` picks up later.
+///
+/// Only the watched pids are reaped: other children of
+/// the shim (i.e. forks attaching to jails) are waited for
+/// by their parents. Container processes, which have process
+/// descriptors, don't send SIGCHLD: their exits wake the
+/// reaper up via [`procdesc::on_exit`].
+///
+/// Processes, which survived a restart of the shim, aren't
+/// its children anymore. Their exits are reported by kqueue
+/// once they are adopted, see [`Reaper::adopt`].
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::Read,
+    os::unix::io::{FromRawFd, RawFd},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::Error;
+use libknast::{
+    operations::{record_exit, record_lost},
+    procdesc,
+};
+use nix::{
+    errno::Errno,
+    sys::{
+        event::{
+            kevent, kevent_ts, kqueue, EventFilter, EventFlag, FilterFlag,
+            KEvent,
+        },
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{pipe, Pid},
+};
+use storage::{Storage, StorageEngine};
+
+/// Write end of the pipe waking the reaper up.
+static WAKEUP_FD: AtomicI32 = AtomicI32::new(-1);
+
+#[derive(Debug, Clone)]
+pub struct Reaper {
+    pids: Arc<Mutex<BTreeSet<i32>>>,
+    /// kqueue of the adopted processes.
+    adopted: RawFd,
+}
+
+impl Reaper {
+    /// Installs SIGCHLD handler and starts the reaper thread.
+    pub fn spawn<T: StorageEngine + Send + Sync + 'static>(
+        storage: Arc<Storage<T>>,
+    ) -> Result<Self, Error> {
+        let (read, write) = pipe()?;
+        let reaper = Self {
+            pids: Arc::new(Mutex::new(BTreeSet::new())),
+            adopted: kqueue()?,
+        };
+
+        WAKEUP_FD.store(write, Ordering::SeqCst);
+
+        let action = SigAction::new(
+            SigHandler::Handler(handle_sigchld),
+            SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGCHLD, &action)? };
+        procdesc::on_exit(|_| wake_up());
+
+        let (adopted, adopted_storage) = (reaper.adopted, storage.clone());
+        thread::spawn(move || reap_adopted(&adopted_storage, adopted));
+
+        let pids = reaper.pids.clone();
+        thread::spawn(move || {
+            let mut wakeups = unsafe { File::from_raw_fd(read) };
+            let mut buffer = [0; 64];
+
+            loop {
+                if let Err(error) = wakeups.read(&mut buffer) {
+                    tracing::error!("Reaper failed to wake up: {}", error);
+                    continue;
+                }
+
+                reap(&storage, &pids);
+            }
+        });
+
+        Ok(reaper)
+    }
+
+    /// Starts reaping `pid` once it exits.
+    pub fn watch(&self, pid: i32) {
+        if let Ok(mut pids) = self.pids.lock() {
+            pids.insert(pid);
+        }
+
+        // The process might have exited already
+        wake_up();
+    }
+
+    /// Records the exit status of `pid`, which isn't a child
+    /// of the shim, once it exits.
+    pub fn adopt(
+        &self,
+        storage: &Storage<impl StorageEngine>,
+        pid: i32,
+    ) -> Result<(), Error> {
+        let event = KEvent::new(
+            pid as _,
+            EventFilter::EVFILT_PROC,
+            EventFlag::EV_ADD | EventFlag::EV_ONESHOT,
+            FilterFlag::NOTE_EXIT,
+            0,
+            0,
+        );
+
+        match kevent(self.adopted, &[event], &mut [], 0) {
+            Ok(_) => Ok(()),
+            // Gone already
+            Err(nix::Error::Sys(Errno::ESRCH)) => record_lost(storage, pid),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+fn reap_adopted(storage: &Storage<impl StorageEngine>, adopted: RawFd) {
+    let empty = KEvent::new(
+        0,
+        EventFilter::EVFILT_PROC,
+        EventFlag::empty(),
+        FilterFlag::empty(),
+        0,
+        0,
+    );
+    let mut events = [empty; 16];
+
+    loop {
+        let count = match kevent_ts(adopted, &[], &mut events, None) {
+            Ok(count) => count,
+            Err(error) => {
+                tracing::error!("Failed to watch adopted: {}", error);
+                continue;
+            }
+        };
+
+        for event in &events[..count] {
+            let pid = Pid::from_raw(event.ident() as _);
+            let result = WaitStatus::from_raw(pid, event.data() as _)
+                .map_err(Error::from)
+                .and_then(|status| record_exit(storage, status));
+
+            if let Err(err) = result {
+                tracing::error!("Failed to record exit of {}: {}", pid, err);
+            }
+        }
+    }
+}
+
+fn reap(storage: &Storage<impl StorageEngine>, pids: &Mutex<BTreeSet<i32>>) {
+    let mut pids = match pids.lock() {
+        Ok(pids) => pids,
+        Err(_) => return,
+    };
+    let watched: Vec<_> = pids.iter().cloned().collect();
+
+    for pid in watched {
+        match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => (),
+            Ok(status) => {
+                pids.remove(&pid);
+                procdesc::release(pid);
+
+                if let Err(err) = record_exit(storage, status) {
+                    tracing::error!("Failed to record {:?}: {}", status, err);
+                }
+            }
+            // Already waited for by someone else
+            Err(_) => {
+                pids.remove(&pid);
+            }
+        }
+    }
+}
+
+extern "C" fn handle_sigchld(_: libc::c_int) {
+    wake_up();
+}
+
+/// Async-signal-safe.
+fn wake_up() {
+    let fd = WAKEUP_FD.load(Ordering::SeqCst);
+
+    if fd >= 0 {
+        unsafe { libc::write(fd, b"\0" as *const _ as _, 1) };
+    }
+}
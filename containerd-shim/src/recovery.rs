@@ -0,0 +1,90 @@
+/// Recovery of the containers after a restart of the shim.
+///
+/// Processes of the containers survive the shim, but they
+/// aren't its children anymore: the reaper adopts them, so
+/// that their exits are recorded. Processes, which exited
+/// while the shim was down, are marked as stopped with an
+/// unknown exit status.
+///
+/// Non-terminal IO goes to containerd's fifos directly and
+/// keeps working. PTY masters are gone with the previous
+/// shim though, so terminal processes lose their IO.
+use anyhow::Error;
+use libknast::operations::{
+    self, record_lost, OciOperations, OciStatus, ProcessStatus,
+};
+use storage::{Storage, StorageEngine};
+
+use super::{oci_extensions::ContainerdExtension, reaper::Reaper};
+
+/// Adopts the running processes of every container.
+pub fn recover(storage: &Storage<impl StorageEngine>, reaper: &Reaper) {
+    let containers = match operations::containers(storage) {
+        Ok(containers) => containers,
+        Err(error) => {
+            tracing::error!("Failed to list containers: {}", error);
+            return;
+        }
+    };
+
+    for key in containers {
+        let ops = OciOperations::new(storage, &key);
+        let exec_ids = match ops.and_then(|ops| ops.exec_ids()) {
+            Ok(exec_ids) => exec_ids,
+            Err(error) => {
+                tracing::error!("Failed to recover {}: {}", key, error);
+                continue;
+            }
+        };
+
+        for exec_id in exec_ids {
+            let result = recover_process(storage, reaper, &key, &exec_id);
+
+            if let Err(error) = result {
+                tracing::error!(
+                    "Failed to recover {}/{}: {:#}",
+                    key,
+                    exec_id,
+                    error
+                );
+            }
+        }
+    }
+}
+
+fn recover_process(
+    storage: &Storage<impl StorageEngine>,
+    reaper: &Reaper,
+    key: &str,
+    exec_id: &str,
+) -> Result<(), Error> {
+    let ops = OciOperations::new(storage, key)?;
+    let OciStatus { status, pid, .. } = ops.get_state(exec_id)?;
+
+    if status != ProcessStatus::Running {
+        return Ok(());
+    }
+
+    if ops
+        .stdio_triple(exec_id)
+        .map_or(false, |triple| triple.terminal)
+    {
+        tracing::warn!("Terminal of {}/{} is lost", key, exec_id);
+        // Descriptors of the previous shim
+        ops.remove_pty_state(exec_id)?;
+    }
+
+    let alive =
+        ops.retrieve_jail().is_ok() && unsafe { libc::kill(pid, 0) } == 0;
+
+    if alive {
+        tracing::info!("Adopting {}/{} ({})", key, exec_id, pid);
+        reaper.adopt(storage, pid)?;
+    } else {
+        tracing::info!("{}/{} exited meanwhile", key, exec_id);
+        record_lost(storage, pid)?;
+        ops.do_wait(exec_id)?;
+    }
+
+    Ok(())
+}
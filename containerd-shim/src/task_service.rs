@@ -3,79 +3,137 @@ use std::{
     path::Path,
     process,
     sync::{mpsc::SyncSender, Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Error;
 use libknast::{
+    error::{self, ErrorKind, KnastError},
     filesystem::Mountable,
-    operations::{OciOperations, Process, ProcessStatus},
+    namespace,
+    operations::{OciOperations, OciStatus, Process, ProcessStatus},
 };
 use protobuf::well_known_types::Timestamp;
 use storage::{Storage, StorageEngine};
 use ttrpc::TtrpcContext;
 
 use super::{
+    deadline::{self, Cancellation, DeadlineExceeded, Workers},
     oci_extensions::{ContainerdExtension, StdioTriple},
     protocols::{
         empty::Empty,
+        mount::Mount,
         shim::{
             ConnectRequest, ConnectResponse, CreateTaskRequest,
             CreateTaskResponse, DeleteRequest, DeleteResponse,
-            ExecProcessRequest, ResizePtyRequest, ShutdownRequest,
-            StartRequest, StartResponse, StateRequest, StateResponse,
-            WaitRequest, WaitResponse,
+            ExecProcessRequest, KillRequest, ResizePtyRequest,
+            ShutdownRequest, StartRequest, StartResponse, StateRequest,
+            StateResponse, WaitRequest, WaitResponse,
         },
         shim_ttrpc::Task,
         task::Status,
     },
+    reaper::Reaper,
 };
 
+/// Metadata of requests, which names their namespace.
+const NAMESPACE_METADATA: &str = "containerd-namespace";
+/// How long the container is given to handle its stop
+/// signal before it's killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Caps of the operations, so that a hung one doesn't stall
+/// containerd, which may give no deadline of its own.
+const CREATE_TIMEOUT: Duration = Duration::from_secs(60);
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+const DELETE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Graceful stop, followed by SIGKILL.
+const KILL_TIMEOUT: Duration = Duration::from_secs(20);
+/// How often waits check whether containerd still waits.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Exit status of the stopped processes, which status is
+/// lost, as containerd reports it.
+const UNKNOWN_EXIT_STATUS: u32 = 255;
+
 #[derive(Debug)]
 pub struct TaskService<T: StorageEngine + Send + Sync> {
-    storage: Storage<T>,
+    storage: Arc<Storage<T>>,
     shutdown_notifier: SyncSender<()>,
     nat_interface: String,
     start_mutex: Mutex<()>,
+    reaper: Reaper,
+    workers: Workers,
 }
 
 impl<T: StorageEngine + Send + Sync + 'static> TaskService<T> {
     pub fn new(
-        storage: Storage<T>,
+        storage: Arc<Storage<T>>,
         sender: SyncSender<()>,
         nat_interface: String,
+        reaper: Reaper,
+        workers: Workers,
     ) -> Arc<Box<dyn Task + Send + Sync>> {
         Arc::new(Box::new(Self {
             storage,
             shutdown_notifier: sender.clone(),
             nat_interface,
             start_mutex: Mutex::new(()),
+            reaper,
+            workers,
         }))
     }
 
-    fn operations(&self, id: String) -> Result<OciOperations<T>, Error> {
-        OciOperations::new(&self.storage, id)
+    /// Operations on the container `id` of the request's
+    /// namespace.
+    fn operations(
+        &self,
+        ctx: &TtrpcContext,
+        id: &str,
+    ) -> Result<OciOperations<T>, KnastError> {
+        OciOperations::new(&self.storage, namespace::key(namespace(ctx), id))
+    }
+
+    /// Runs `f` on the operations of the container `id`
+    /// in the worker pool, see [`deadline`]. Gives up once
+    /// the request's deadline or the `limit` passes.
+    fn run<R, F>(
+        &self,
+        ctx: &TtrpcContext,
+        id: &str,
+        operation: &'static str,
+        limit: Option<Duration>,
+        f: F,
+    ) -> ttrpc::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&OciOperations<'_, T>, &Cancellation) -> Result<R, Error>
+            + Send
+            + 'static,
+    {
+        let storage = self.storage.clone();
+        let key = namespace::key(namespace(ctx), id);
+        let timeout = deadline::timeout(ctx, limit);
+
+        self.workers
+            .run(operation, timeout, move |cancellation| {
+                f(&OciOperations::new(&storage, key)?, cancellation)
+            })
+            .map_err(error_response)
     }
 }
 
 impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
-    #[tracing::instrument(err, skip(self, _ctx), fields(id = request.id.as_str()))]
+    #[tracing::instrument(err, skip(self, ctx), fields(id = request.id.as_str()))]
     fn state(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: StateRequest,
     ) -> ttrpc::Result<StateResponse> {
-        let ops = self
-            .operations(request.id.clone())
-            .map_err(error_response)?;
+        let ops = self.operations(ctx, &request.id).map_err(error_response)?;
         let stdio =
             ops.stdio_triple(&request.exec_id).map_err(error_response)?;
         let state = ops.get_state(&request.exec_id).map_err(error_response)?;
-        let exit_status: u32 = state
-            .exit_status
-            .unwrap_or(0)
-            .try_into()
-            .map_err(error_response)?;
+        let exit_status = exit_status(&state).map_err(error_response)?;
         let exited_at = system_time_to_timestamp(state.exited_at)
             .map(Option::Some)
             .map_err(error_response)?
@@ -88,8 +146,7 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
             stdin: stdio.stdin,
             stdout: stdio.stdout,
             stderr: stdio.stderr,
-            // TODO: need terminal flag implementation in libknast
-            terminal: false,
+            terminal: stdio.terminal,
             exit_status,
             exited_at,
             exec_id: request.exec_id,
@@ -98,7 +155,7 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
     }
 
     #[tracing::instrument(
-        err, skip(self, _ctx),
+        err, skip(self, ctx),
         fields(
             id = request.id.as_str(),
             bundle = request.bundle.as_str()
@@ -106,37 +163,64 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
     )]
     fn create(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: CreateTaskRequest,
     ) -> ttrpc::Result<CreateTaskResponse> {
         tracing::info!("Creating container");
-        let ops = self.operations(request.id).map_err(error_response)?;
-        ops.save_stdio_triple(
-            "",
-            StdioTriple {
-                stdin: request.stdin,
-                stdout: request.stdout,
-                stderr: request.stderr,
-                terminal: request.terminal,
-            },
-        )
-        .map_err(error_response)?;
-        for mountpoint in request.rootfs {
-            let rootfs = Path::new(&request.bundle).join("rootfs");
+        let id = request.id.clone();
+        let nat_interface = self.nat_interface.clone();
 
-            mountpoint.mount(rootfs).map_err(error_response)?;
-        }
+        self.run(
+            ctx,
+            &id,
+            "create",
+            Some(CREATE_TIMEOUT),
+            move |ops, token| {
+                // Rollback would destroy the existing container
+                if ops.config().is_ok() {
+                    return Err(KnastError::ContainerExists(request.id).into());
+                }
 
-        ops.create(&request.bundle, Some(&self.nat_interface))
-            .map_err(error_response)?;
+                let rootfs = Path::new(&request.bundle).join("rootfs");
+                let mut rollback = CreateRollback {
+                    ops,
+                    rootfs: &rootfs,
+                    mounts: vec![],
+                    armed: true,
+                };
+
+                ops.save_stdio_triple(
+                    "",
+                    StdioTriple {
+                        stdin: request.stdin,
+                        stdout: request.stdout,
+                        stderr: request.stderr,
+                        terminal: request.terminal,
+                    },
+                )?;
+                for mountpoint in request.rootfs {
+                    token.check()?;
+                    mountpoint.mount(&rootfs)?;
+                    rollback.mounts.push(mountpoint);
+                }
+
+                OciOperations::new(ops.storage(), ops.key())?
+                    .create(&request.bundle, Some(&nat_interface))?;
+                // containerd has given up on the container already
+                token.check()?;
+                rollback.armed = false;
+
+                Ok(())
+            },
+        )?;
 
         Ok(CreateTaskResponse::new())
     }
 
-    #[tracing::instrument(err, skip(self, _ctx))]
+    #[tracing::instrument(err, skip(self, ctx))]
     fn start(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: StartRequest,
     ) -> ttrpc::Result<StartResponse> {
         let _guard = self.start_mutex.lock();
@@ -146,93 +230,159 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
         }
 
         tracing::info!("Starting container");
-        let ops = self
-            .operations(request.id.clone())
-            .map_err(error_response)?;
-        <OciOperations<T> as ContainerdExtension>::start(
-            ops,
-            &request.exec_id,
-        )
-        .map_err(error_response)?;
+        let id = request.id.clone();
+        let reaper = self.reaper.clone();
+
+        self.run(ctx, &id, "start", Some(START_TIMEOUT), move |ops, _| {
+            <OciOperations<T> as ContainerdExtension>::start(
+                OciOperations::new(ops.storage(), ops.key())?,
+                &request.exec_id,
+            )?;
+            reaper.watch(ops.get_state(&request.exec_id)?.pid);
+
+            Ok(())
+        })?;
+
+        let storage = self.storage.clone();
+        let key = namespace::key(namespace(ctx), &id);
+
+        // Probes run for as long as the container exists
+        let monitor = thread::Builder::new()
+            .name("knast-health".into())
+            .spawn(move || {
+                let result = OciOperations::new(&storage, key)
+                    .and_then(|ops| ops.monitor_health());
+
+                if let Err(error) = result {
+                    tracing::error!("Failed to check health: {}", error);
+                }
+            });
+
+        if let Err(error) = monitor {
+            tracing::error!("Failed to spawn the health monitor: {}", error);
+        }
 
         Ok(StartResponse::new())
     }
 
-    #[tracing::instrument(err, skip(self, _ctx), fields(id = request.id.as_str()))]
+    #[tracing::instrument(err, skip(self, ctx), fields(id = request.id.as_str()))]
     fn connect(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: ConnectRequest,
     ) -> ttrpc::Result<ConnectResponse> {
         tracing::info!("Connection test");
-        let pid = process::id();
+        let task_pid = match self.operations(ctx, &request.id) {
+            Ok(ops) => ops.state().map_or(0, |state| state.pid as _),
+            Err(_) => 0,
+        };
+
         Ok(ConnectResponse {
-            shim_pid: pid,
-            task_pid: pid,
+            shim_pid: process::id(),
+            task_pid,
             ..Default::default()
         })
     }
 
-    #[tracing::instrument(err, skip(self, _ctx), fields(id = request.id.as_str()))]
+    #[tracing::instrument(err, skip(self, ctx), fields(id = request.id.as_str()))]
     fn delete(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: DeleteRequest,
     ) -> ttrpc::Result<DeleteResponse> {
         tracing::info!("Deleting container");
-        let ops = self.operations(request.id).map_err(error_response)?;
-        let state = ops.state().map_err(error_response)?;
-        let exit_status: u32 = state
-            .exit_status
-            .unwrap_or(0)
-            .try_into()
-            .map_err(error_response)?;
-        let exited_at = system_time_to_timestamp(state.exited_at)
-            .map(Option::Some)
-            .map_err(error_response)?
-            .into();
-        ops.delete_process(&request.exec_id)
-            .map_err(error_response)?;
+        let id = request.id.clone();
 
-        Ok(DeleteResponse {
-            pid: state.pid.try_into().map_err(error_response)?,
-            exit_status,
-            exited_at,
-            ..Default::default()
+        self.run(ctx, &id, "delete", Some(DELETE_TIMEOUT), move |ops, _| {
+            let state = ops.get_state(&request.exec_id)?;
+            let exit_status = exit_status(&state)?;
+            let exited_at =
+                Some(system_time_to_timestamp(state.exited_at)?).into();
+            // Deleting the task deletes its execs too
+            let exec_ids = if request.exec_id.is_empty() {
+                ops.exec_ids()?
+            } else {
+                vec![request.exec_id.clone()]
+            };
+
+            for exec_id in &exec_ids {
+                ops.remove_stdio_triple(exec_id)?;
+                ops.remove_pty_state(exec_id)?;
+                ops.delete_process(exec_id)?;
+            }
+
+            Ok(DeleteResponse {
+                pid: state.pid.try_into()?,
+                exit_status,
+                exited_at,
+                ..Default::default()
+            })
         })
     }
 
-    #[tracing::instrument(err, skip(self, _ctx), fields(id = request.id.as_str()))]
+    #[tracing::instrument(err, skip(self, ctx), fields(id = request.id.as_str()))]
     fn wait(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: WaitRequest,
     ) -> ttrpc::Result<WaitResponse> {
         {
             let _guard = self.start_mutex.lock();
         }
-        let ops = self
-            .operations(request.id.clone())
-            .map_err(error_response)?;
-        ops.do_wait(&request.exec_id).map_err(error_response)?;
-        let state = ops.get_state(&request.exec_id).map_err(error_response)?;
-        let exit_status: u32 = state
-            .exit_status
-            .unwrap_or(0)
-            .try_into()
-            .map_err(error_response)?;
-        let exited_at = system_time_to_timestamp(state.exited_at)
-            .map(Option::Some)
-            .map_err(error_response)?
-            .into();
-        ops.delete();
-        Ok(WaitResponse {
-            exit_status,
-            exited_at,
-            ..Default::default()
+        let id = request.id.clone();
+
+        // Processes may run for as long as they like
+        self.run(ctx, &id, "wait", None, move |ops, token| {
+            // Polled, so that the worker is freed once containerd
+            // gives up
+            let state = loop {
+                token.check()?;
+
+                if let Some(state) =
+                    ops.wait_timeout(&request.exec_id, WAIT_POLL_INTERVAL)?
+                {
+                    break state;
+                }
+            };
+            let exit_status = exit_status(&state)?;
+            let exited_at =
+                Some(system_time_to_timestamp(state.exited_at)?).into();
+            ops.delete();
+
+            Ok(WaitResponse {
+                exit_status,
+                exited_at,
+                ..Default::default()
+            })
         })
     }
 
+    #[tracing::instrument(err, skip(self, ctx), fields(id = request.id.as_str()))]
+    fn kill(
+        &self,
+        ctx: &TtrpcContext,
+        request: KillRequest,
+    ) -> ttrpc::Result<Empty> {
+        tracing::info!("Killing process");
+        let id = request.id.clone();
+
+        self.run(ctx, &id, "kill", Some(KILL_TIMEOUT), move |ops, _| {
+            let signal = request.signal as i32;
+            let stop_signal = ops.stop_signal()?;
+
+            // Graceful stop of the container escalates to SIGKILL
+            if request.exec_id.is_empty() && signal == stop_signal as i32 {
+                ops.stop(STOP_TIMEOUT)?;
+            } else {
+                ops.do_kill(&request.exec_id, signal)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Empty::default())
+    }
+
     #[tracing::instrument(err, skip(self, _ctx))]
     fn shutdown(
         &self,
@@ -244,10 +394,10 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
         Ok(Empty::default())
     }
 
-    #[tracing::instrument(err, skip(self, _ctx))]
+    #[tracing::instrument(err, skip(self, ctx))]
     fn exec(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: ExecProcessRequest,
     ) -> ttrpc::Result<Empty> {
         tracing::info!("Exec process");
@@ -261,27 +411,35 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
             .and_then(|spec| Ok(serde_json::from_slice(&spec.value)?))
             .map_err(error_response)?;
 
-        let ops = self.operations(request.id).map_err(error_response)?;
-        ops.save_stdio_triple(
-            &request.exec_id,
-            StdioTriple {
-                stdin: request.stdin,
-                stdout: request.stdout,
-                stderr: request.stderr,
-                terminal: request.terminal,
-            },
-        )
-        .map_err(error_response)?;
-        ops.exec(&request.exec_id, process)
-            .map_err(error_response)?;
+        let id = request.id.clone();
+        let reaper = self.reaper.clone();
+
+        self.run(ctx, &id, "exec", Some(START_TIMEOUT), move |ops, _| {
+            // IO of the existing process mustn't be overwritten
+            ops.validate_exec(&request.exec_id)?;
+            ops.save_stdio_triple(
+                &request.exec_id,
+                StdioTriple {
+                    stdin: request.stdin,
+                    stdout: request.stdout,
+                    stderr: request.stderr,
+                    terminal: request.terminal,
+                },
+            )?;
+            OciOperations::new(ops.storage(), ops.key())?
+                .exec(&request.exec_id, process)?;
+            reaper.watch(ops.get_state(&request.exec_id)?.pid);
+
+            Ok(())
+        })?;
 
         Ok(Empty::default())
     }
 
-    #[tracing::instrument(err, skip(self, _ctx), fields(id = request.id.as_str()))]
+    #[tracing::instrument(err, skip(self, ctx), fields(id = request.id.as_str()))]
     fn resize_pty(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         request: ResizePtyRequest,
     ) -> ttrpc::Result<Empty> {
         tracing::info!("Resizing pty");
@@ -293,7 +451,7 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
             ws_ypixel: 0,
         };
 
-        self.operations(request.id)
+        self.operations(ctx, &request.id)
             .map_err(error_response)?
             .resize_pty(&request.exec_id, winsize)
             .map_err(error_response)?;
@@ -302,6 +460,38 @@ impl<T: StorageEngine + Send + Sync + 'static> Task for TaskService<T> {
     }
 }
 
+/// Undoes partial creation of the container: frees whatever
+/// `OciOperations::create` got to allocate, unmounts the
+/// rootfs and forgets the stdio. Disarmed once the container
+/// is created.
+struct CreateRollback<'a, T: StorageEngine> {
+    ops: &'a OciOperations<'a, T>,
+    rootfs: &'a Path,
+    mounts: Vec<Mount>,
+    armed: bool,
+}
+
+impl<'a, T: StorageEngine> Drop for CreateRollback<'a, T> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        tracing::info!("Rolling back the creation");
+        self.ops.do_delete();
+
+        for mount in self.mounts.iter().rev() {
+            if let Err(error) = mount.unmount(self.rootfs) {
+                tracing::error!("Failed to unmount rootfs: {}", error);
+            }
+        }
+
+        if let Err(error) = self.ops.remove_stdio_triple("") {
+            tracing::error!("Failed to remove stdio: {}", error);
+        }
+    }
+}
+
 impl From<ProcessStatus> for Status {
     fn from(status: ProcessStatus) -> Self {
         match status {
@@ -313,11 +503,53 @@ impl From<ProcessStatus> for Status {
     }
 }
 
-fn error_response(err: impl ToString) -> ttrpc::Error {
-    ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::INTERNAL, err))
+/// Namespace of the request, if any.
+fn namespace(ctx: &TtrpcContext) -> &str {
+    ctx.metadata
+        .get(NAMESPACE_METADATA)
+        .and_then(|values| values.first())
+        .map_or("", String::as_str)
+}
+
+/// Status of the failed request, containerd tells missing
+/// containers and retryable failures apart by the code.
+fn error_response(err: impl Into<Error>) -> ttrpc::Error {
+    let err = err.into();
+
+    if err.is::<DeadlineExceeded>() {
+        return ttrpc::Error::RpcStatus(ttrpc::get_status(
+            ttrpc::Code::DEADLINE_EXCEEDED,
+            err.to_string(),
+        ));
+    }
+
+    let code = match error::kind(&err) {
+        Some(ErrorKind::NotFound) => ttrpc::Code::NOT_FOUND,
+        Some(ErrorKind::AlreadyExists) => ttrpc::Code::ALREADY_EXISTS,
+        Some(ErrorKind::InvalidArgument) => ttrpc::Code::INVALID_ARGUMENT,
+        Some(ErrorKind::FailedPrecondition) => {
+            ttrpc::Code::FAILED_PRECONDITION
+        }
+        Some(ErrorKind::Unavailable) => ttrpc::Code::UNAVAILABLE,
+        Some(ErrorKind::PermissionDenied) => ttrpc::Code::PERMISSION_DENIED,
+        None => ttrpc::Code::INTERNAL,
+    };
+
+    ttrpc::Error::RpcStatus(ttrpc::get_status(code, format!("{:#}", err)))
+}
+
+/// Exit status of the process, as containerd expects it: 0
+/// for the running ones, 128 plus the signal number for the
+/// killed ones.
+fn exit_status(state: &OciStatus) -> Result<u32, Error> {
+    match (state.exit_status, state.status) {
+        (Some(code), _) => Ok(code.try_into()?),
+        (None, ProcessStatus::Stopped) => Ok(UNKNOWN_EXIT_STATUS),
+        (None, _) => Ok(0),
+    }
 }
 
-fn system_time_to_timestamp(time: SystemTime) -> Result<Timestamp, Error> {
+pub fn system_time_to_timestamp(time: SystemTime) -> Result<Timestamp, Error> {
     let duration = time.duration_since(UNIX_EPOCH)?;
 
     Ok(Timestamp {
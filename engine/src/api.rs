@@ -0,0 +1,413 @@
+/// Routing of the Docker Engine API requests. Failures are
+/// responded with `{"message": ...}`, the way Docker does,
+/// and statuses of the kinds of libknast's errors.
+use std::{collections::HashMap, convert::Infallible, fmt, sync::Arc};
+
+use anyhow::Error;
+use hyper::{
+    body, header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode,
+};
+use libknast::{
+    error::{self, ErrorKind, KnastError},
+    nonblocking::Reaper,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use storage::{DynamicEngine, DynamicStorage};
+use tokio::task;
+
+use super::{containers, images};
+
+/// Version of the API responses follow, clients negotiate
+/// down to it.
+pub const API_VERSION: &str = "1.41";
+const MIN_API_VERSION: &str = "1.24";
+
+pub type ApiResult = Result<Response<Body>, ApiError>;
+
+/// State shared by the requests.
+pub struct Engine {
+    pub storage: Arc<DynamicStorage>,
+    pub reaper: Reaper<DynamicEngine>,
+    /// Interface the ports of the containers are published
+    /// on.
+    pub nat_interface: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Ping,
+    Version,
+    ListContainers,
+    CreateContainer,
+    InspectContainer(String),
+    StartContainer(String),
+    StopContainer(String),
+    ContainerLogs(String),
+    RemoveContainer(String),
+    ListImages,
+    PullImage,
+}
+
+/// Query parameters of the request.
+pub struct Query(HashMap<String, String>);
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Version {
+    version: &'static str,
+    api_version: &'static str,
+    #[serde(rename = "MinAPIVersion")]
+    min_api_version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+}
+
+#[derive(Serialize)]
+struct Message {
+    message: String,
+}
+
+impl Engine {
+    pub fn new(
+        storage: Arc<DynamicStorage>,
+        reaper: Reaper<DynamicEngine>,
+        nat_interface: Option<String>,
+    ) -> Self {
+        Self {
+            storage,
+            reaper,
+            nat_interface,
+        }
+    }
+
+    /// Runs `f` on the blocking pool, as storage access and
+    /// container operations block.
+    pub async fn blocking<R, F>(&self, f: F) -> Result<R, ApiError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&DynamicStorage) -> Result<R, ApiError> + Send + 'static,
+    {
+        let storage = self.storage.clone();
+
+        task::spawn_blocking(move || f(&storage))
+            .await
+            .map_err(Error::from)?
+    }
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let body = serde_json::to_vec(&Message {
+            message: self.message,
+        })
+        .unwrap_or_default();
+
+        Response::builder()
+            .status(self.status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl From<KnastError> for ApiError {
+    fn from(error: KnastError) -> Self {
+        Self::new(status(error.kind()), error.to_string())
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        Self::new(status(error::kind(&error)), format!("{:#}", error))
+    }
+}
+
+impl From<hyper::http::Error> for ApiError {
+    fn from(error: hyper::http::Error) -> Self {
+        Error::from(error).into()
+    }
+}
+
+impl Route {
+    /// Route of the request. Paths are either versioned, i.e.
+    /// `/v1.41/containers/json`, or not.
+    fn parse(method: &Method, path: &str) -> Option<Self> {
+        let mut segments: Vec<&str> =
+            path.trim_matches('/').split('/').collect();
+
+        if segments
+            .first()
+            .map_or(false, |segment| is_version(segment))
+        {
+            segments.remove(0);
+        }
+
+        let id = |id: &str| id.to_string();
+        let route = match (method, segments.as_slice()) {
+            (&Method::GET, ["_ping"]) | (&Method::HEAD, ["_ping"]) => {
+                Route::Ping
+            }
+            (&Method::GET, ["version"]) => Route::Version,
+            (&Method::GET, ["containers", "json"]) => Route::ListContainers,
+            (&Method::POST, ["containers", "create"]) => {
+                Route::CreateContainer
+            }
+            (&Method::GET, ["containers", container, "json"]) => {
+                Route::InspectContainer(id(container))
+            }
+            (&Method::POST, ["containers", container, "start"]) => {
+                Route::StartContainer(id(container))
+            }
+            (&Method::POST, ["containers", container, "stop"]) => {
+                Route::StopContainer(id(container))
+            }
+            (&Method::GET, ["containers", container, "logs"]) => {
+                Route::ContainerLogs(id(container))
+            }
+            (&Method::DELETE, ["containers", container]) => {
+                Route::RemoveContainer(id(container))
+            }
+            (&Method::GET, ["images", "json"]) => Route::ListImages,
+            (&Method::POST, ["images", "create"]) => Route::PullImage,
+            _ => return None,
+        };
+
+        Some(route)
+    }
+}
+
+impl Query {
+    fn parse(query: Option<&str>) -> Self {
+        let query = query.unwrap_or_default().as_bytes();
+
+        Self(url::form_urlencoded::parse(query).into_owned().collect())
+    }
+
+    /// Non-empty value of the parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .get(name)
+            .map(String::as_str)
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Boolean parameter, which clients pass as `1` or `true`.
+    pub fn flag(&self, name: &str) -> bool {
+        matches!(self.get(name), Some("1") | Some("true") | Some("True"))
+    }
+
+    /// Numeric parameter, if it's given.
+    pub fn number(&self, name: &str) -> Result<Option<u64>, ApiError> {
+        self.get(name)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        format!("{} must be a number", name),
+                    )
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Serves the request.
+pub async fn handle(
+    engine: Arc<Engine>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let response = dispatch(engine, request).await.unwrap_or_else(|error| {
+        tracing::info!("{} {} failed: {}", method, path, error);
+
+        error.into_response()
+    });
+
+    Ok(response)
+}
+
+async fn dispatch(engine: Arc<Engine>, request: Request<Body>) -> ApiResult {
+    let route = Route::parse(request.method(), request.uri().path())
+        .ok_or_else(|| {
+            ApiError::new(StatusCode::NOT_FOUND, "page not found")
+        })?;
+    let query = Query::parse(request.uri().query());
+
+    match route {
+        Route::Ping => Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .header("Api-Version", API_VERSION)
+            .body("OK".into())?),
+        Route::Version => json(StatusCode::OK, &version()),
+        Route::ListContainers => {
+            containers::list(&engine, query.flag("all")).await
+        }
+        Route::CreateContainer => {
+            let name = query.get("name").map(String::from);
+            let request = read_json(request).await?;
+
+            containers::create(&engine, name, request).await
+        }
+        Route::InspectContainer(id) => containers::inspect(&engine, &id).await,
+        Route::StartContainer(id) => containers::start(&engine, &id).await,
+        Route::StopContainer(id) => {
+            containers::stop(&engine, &id, query.number("t")?).await
+        }
+        Route::ContainerLogs(id) => {
+            let (stdout, stderr) =
+                (query.flag("stdout"), query.flag("stderr"));
+
+            containers::logs(&engine, &id, stdout, stderr).await
+        }
+        Route::RemoveContainer(id) => {
+            containers::remove(&engine, &id, query.flag("force")).await
+        }
+        Route::ListImages => images::list(&engine).await,
+        Route::PullImage => {
+            let image = query.get("fromImage").ok_or_else(|| {
+                ApiError::new(StatusCode::BAD_REQUEST, "fromImage is required")
+            })?;
+            let reference = images::reference(image, query.get("tag"));
+
+            images::pull(engine, &reference, query.get("platform")).await
+        }
+    }
+}
+
+pub fn json(status: StatusCode, value: &impl Serialize) -> ApiResult {
+    let body = serde_json::to_vec(value).map_err(Error::from)?;
+
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.into())?)
+}
+
+pub fn empty(status: StatusCode) -> ApiResult {
+    Ok(Response::builder().status(status).body(Body::empty())?)
+}
+
+/// Architecture of the host, named the way images name it.
+pub fn architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64",
+        architecture => architecture,
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(
+    request: Request<Body>,
+) -> Result<T, ApiError> {
+    let body = body::to_bytes(request.into_body())
+        .await
+        .map_err(Error::from)?;
+
+    serde_json::from_slice(&body).map_err(|error| {
+        ApiError::new(StatusCode::BAD_REQUEST, error.to_string())
+    })
+}
+
+fn version() -> Version {
+    Version {
+        version: env!("CARGO_PKG_VERSION"),
+        api_version: API_VERSION,
+        min_api_version: MIN_API_VERSION,
+        os: "freebsd",
+        arch: architecture(),
+    }
+}
+
+fn status(kind: Option<ErrorKind>) -> StatusCode {
+    match kind {
+        Some(ErrorKind::NotFound) => StatusCode::NOT_FOUND,
+        Some(ErrorKind::AlreadyExists) => StatusCode::CONFLICT,
+        Some(ErrorKind::InvalidArgument) => StatusCode::BAD_REQUEST,
+        Some(ErrorKind::FailedPrecondition) => StatusCode::CONFLICT,
+        Some(ErrorKind::Unavailable) => StatusCode::SERVICE_UNAVAILABLE,
+        Some(ErrorKind::PermissionDenied) => StatusCode::FORBIDDEN,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Whether the path segment is an API version, i.e. `v1.41`.
+fn is_version(segment: &str) -> bool {
+    segment.strip_prefix('v').map_or(false, |version| {
+        version
+            .split('.')
+            .all(|part| !part.is_empty() && part.parse::<u32>().is_ok())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(
+            Route::parse(&Method::GET, "/v1.41/containers/json"),
+            Some(Route::ListContainers)
+        );
+        assert_eq!(
+            Route::parse(&Method::POST, "/containers/nginx/start"),
+            Some(Route::StartContainer("nginx".into()))
+        );
+        assert_eq!(
+            Route::parse(&Method::DELETE, "/v1.24/containers/nginx"),
+            Some(Route::RemoveContainer("nginx".into()))
+        );
+        assert_eq!(Route::parse(&Method::HEAD, "/_ping"), Some(Route::Ping));
+        assert_eq!(Route::parse(&Method::GET, "/containers/create"), None);
+        assert_eq!(Route::parse(&Method::GET, "/v1.x/version"), None);
+    }
+
+    #[test]
+    fn test_query() {
+        let query = Query::parse(Some("all=1&name=web&t=&stdout=false"));
+
+        assert!(query.flag("all"));
+        assert!(!query.flag("stdout"));
+        assert_eq!(query.get("name"), Some("web"));
+        assert_eq!(query.get("t"), None);
+        assert_eq!(query.number("t").unwrap(), None);
+        assert!(Query::parse(Some("t=soon")).number("t").is_err());
+    }
+
+    #[test]
+    fn test_error_status() {
+        let error: ApiError =
+            KnastError::ContainerNotFound("nginx".into()).into();
+
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+
+        let error: ApiError =
+            Error::from(KnastError::InvalidState("stopped".into()))
+                .context("Starting nginx")
+                .into();
+
+        assert_eq!(error.status, StatusCode::CONFLICT);
+        assert_eq!(error.message, "Starting nginx: stopped");
+    }
+}
@@ -0,0 +1,865 @@
+/// Containers of the Docker API. Containers are keyed as
+/// `docker:<id>`, see [`namespace::key`], and their Docker
+/// names and commands, which runtime configs lack, are kept
+/// along with them. Bundles are created from the images, and
+/// removed along with the containers.
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use baustelle::{Image, Reference, UnpackOptions};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use libknast::{
+    error::KnastError,
+    namespace,
+    nonblocking::AsyncOperations,
+    operations::{
+        OciOperations, OciStatus, OutputCapture, PortMapping,
+        ProcessOverrides, ProcessStatus, Protocol, MAIN_PROCESS_EXEC_ID,
+    },
+};
+use serde::{Deserialize, Serialize};
+use storage::{Collection, DynamicStorage};
+use uuid::Uuid;
+
+use super::{
+    api::{empty, json, ApiError, ApiResult, Engine},
+    logs::{self, Log},
+};
+
+/// Namespace of the containers.
+const NAMESPACE: &str = "docker";
+const CONTAINERS: Collection<str, Container> =
+    Collection::new(b"ENGINE_CONTAINERS");
+/// Ids of the containers, keyed by their names. Names are
+/// reserved by compare-and-swap, so that concurrent creates
+/// can't take the same one.
+const NAMES: Collection<str, String> =
+    Collection::new(b"ENGINE_CONTAINER_NAMES");
+const DEFAULT_STOP_TIMEOUT: u64 = 10;
+/// Time the exit of the killed process takes to be recorded.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Docker's zero time, which unknown times are given as.
+const ZERO_TIME: &str = "0001-01-01T00:00:00Z";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Container {
+    id: String,
+    name: String,
+    /// Image reference, as the container was created with.
+    image: String,
+    /// Manifest digest of the image.
+    image_id: String,
+    /// Command and its arguments, the image's one unless
+    /// the container overrides it.
+    args: Vec<String>,
+    /// Variables added to the image's environment.
+    env: Vec<String>,
+    working_dir: Option<String>,
+    created: SystemTime,
+    bundle: PathBuf,
+}
+
+/// Body of the create request, the fields which are
+/// supported.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct CreateRequest {
+    image: String,
+    cmd: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    env: Option<Vec<String>>,
+    working_dir: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Created {
+    id: String,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Summary {
+    id: String,
+    names: Vec<String>,
+    image: String,
+    #[serde(rename = "ImageID")]
+    image_id: String,
+    command: String,
+    created: u64,
+    state: &'static str,
+    status: String,
+    ports: Vec<Port>,
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Port {
+    #[serde(rename = "IP")]
+    ip: &'static str,
+    private_port: u16,
+    public_port: u16,
+    #[serde(rename = "Type")]
+    protocol: Protocol,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Details {
+    id: String,
+    name: String,
+    created: String,
+    path: String,
+    args: Vec<String>,
+    state: StateDetails,
+    image: String,
+    config: ConfigDetails,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct StateDetails {
+    status: &'static str,
+    running: bool,
+    paused: bool,
+    restarting: bool,
+    #[serde(rename = "OOMKilled")]
+    oom_killed: bool,
+    dead: bool,
+    pid: i32,
+    exit_code: i32,
+    error: String,
+    started_at: &'static str,
+    finished_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<HealthDetails>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthDetails {
+    status: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConfigDetails {
+    image: String,
+    cmd: Vec<String>,
+    env: Vec<String>,
+    working_dir: String,
+    labels: BTreeMap<String, String>,
+}
+
+impl Container {
+    fn key(&self) -> String {
+        namespace::key(NAMESPACE, &self.id)
+    }
+
+    fn overrides(&self) -> ProcessOverrides {
+        ProcessOverrides {
+            env: self.env.clone(),
+            args: Some(self.args.clone()).filter(|args| !args.is_empty()),
+            cwd: self.working_dir.clone(),
+        }
+    }
+}
+
+/// Waits for the containers, which were started before the
+/// engine, so that their exits are recorded. Their processes
+/// aren't children of the engine, so that the reaper adopts
+/// them, see [`libknast::nonblocking::Reaper::adopt`].
+pub async fn adopt(engine: &Engine) -> Result<(), ApiError> {
+    let reaper = engine.reaper.clone();
+    let running = engine
+        .blocking(move |storage| {
+            let mut running = vec![];
+
+            for container in load(storage)? {
+                let state = match state(storage, &container.key()) {
+                    Some(state) if is_running(Some(&state)) => state,
+                    _ => continue,
+                };
+                let capture = log_capture(&container.bundle)?;
+
+                reaper.adopt(state.pid)?;
+                running.push((container.key(), capture));
+            }
+
+            Ok(running)
+        })
+        .await?;
+
+    for (key, capture) in running {
+        spawn_wait(engine, key, capture);
+    }
+
+    Ok(())
+}
+
+pub async fn list(engine: &Engine, all: bool) -> ApiResult {
+    let summaries = engine
+        .blocking(move |storage| {
+            let mut containers = load(storage)?;
+            let mut summaries = vec![];
+
+            // Newest first
+            containers.sort_by(|a, b| b.created.cmp(&a.created));
+
+            for container in containers {
+                let ops = OciOperations::new(storage, container.key())?;
+                let state = ops.state().ok();
+
+                if all || is_running(state.as_ref()) {
+                    let ports = ops.ports().unwrap_or_default();
+
+                    summaries.push(summary(container, state, ports));
+                }
+            }
+
+            Ok(summaries)
+        })
+        .await?;
+
+    json(StatusCode::OK, &summaries)
+}
+
+pub async fn create(
+    engine: &Engine,
+    name: Option<String>,
+    request: CreateRequest,
+) -> ApiResult {
+    let id = Uuid::new_v4().to_simple().to_string();
+    let name = match name {
+        Some(name) => name.trim_start_matches('/').to_string(),
+        None => id[..12].to_string(),
+    };
+
+    if !valid_name(&name) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid container name {}", name),
+        ));
+    }
+
+    let nat_interface = engine.nat_interface.clone();
+    let created = engine
+        .blocking(move |storage| {
+            reserve_name(storage, &name, &id)?;
+
+            let result = do_create(
+                storage,
+                id,
+                name.clone(),
+                request,
+                nat_interface.as_deref(),
+            );
+
+            if result.is_err() {
+                let _ = NAMES.remove(storage, name.as_str());
+            }
+
+            result
+        })
+        .await?;
+
+    json(StatusCode::CREATED, &created)
+}
+
+/// Takes the `name` for the container `id`, unless another
+/// container has it.
+fn reserve_name(
+    storage: &DynamicStorage,
+    name: &str,
+    id: &str,
+) -> Result<(), ApiError> {
+    // Containers created before the names were reserved
+    let taken = load(storage)?
+        .iter()
+        .any(|container| container.name == name);
+
+    if taken
+        || NAMES
+            .compare_and_swap(storage, name, None, Some(id.to_string()))
+            .is_err()
+    {
+        return Err(KnastError::ContainerExists(name.into()).into());
+    }
+
+    Ok(())
+}
+
+fn do_create(
+    storage: &DynamicStorage,
+    id: String,
+    name: String,
+    request: CreateRequest,
+    nat_interface: Option<&str>,
+) -> Result<Created, ApiError> {
+    let image = request
+        .image
+        .parse::<Reference>()
+        .and_then(|reference| Image::open(storage, &reference))
+        .map_err(|_| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("No such image: {}", request.image),
+            )
+        })?;
+    let configured = image.config().config.as_ref();
+    let args = command(
+        &request,
+        configured.and_then(|config| config.entrypoint.as_deref()),
+        configured.and_then(|config| config.cmd.as_deref()),
+    );
+    let container = Container {
+        id: id.clone(),
+        name,
+        image: request.image,
+        image_id: image.digest().into(),
+        args,
+        env: request.env.unwrap_or_default(),
+        working_dir: request.working_dir.filter(|dir| !dir.is_empty()),
+        created: SystemTime::now(),
+        bundle: image.create_bundle(UnpackOptions::default())?,
+    };
+    let ops = OciOperations::new(storage, container.key())?;
+
+    if let Err(error) = ops.create(&container.bundle, nat_interface) {
+        let _ = fs::remove_dir_all(&container.bundle);
+
+        return Err(error.into());
+    }
+
+    CONTAINERS.put(storage, id.as_str(), container)?;
+
+    Ok(Created {
+        id,
+        warnings: vec![],
+    })
+}
+
+pub async fn inspect(engine: &Engine, reference: &str) -> ApiResult {
+    let container = find(engine, reference).await?;
+    let (state, config) = engine
+        .blocking({
+            let key = container.key();
+
+            move |storage| {
+                let ops = OciOperations::new(storage, key)?;
+
+                Ok((ops.state().ok(), ops.config()?))
+            }
+        })
+        .await?;
+    let process = config.process.as_ref();
+    let (status, _) = status(state.as_ref());
+    let running = is_running(state.as_ref());
+    let stopped = state
+        .as_ref()
+        .filter(|state| state.status == ProcessStatus::Stopped);
+    let details = Details {
+        id: container.id.clone(),
+        name: format!("/{}", container.name),
+        created: timestamp(container.created),
+        path: container.args.first().cloned().unwrap_or_default(),
+        args: container.args.iter().skip(1).cloned().collect(),
+        state: StateDetails {
+            status,
+            running,
+            paused: false,
+            restarting: false,
+            oom_killed: false,
+            dead: state.is_none(),
+            pid: state.as_ref().filter(|_| running).map_or(0, |s| s.pid),
+            exit_code: stopped.map_or(0, exit_code),
+            error: String::new(),
+            started_at: ZERO_TIME,
+            finished_at: stopped
+                .map_or_else(|| ZERO_TIME.into(), |s| timestamp(s.exited_at)),
+            health: state.as_ref().and_then(|state| state.health).map(
+                |health| HealthDetails {
+                    status: health.as_ref().into(),
+                },
+            ),
+        },
+        image: container.image_id.clone(),
+        config: ConfigDetails {
+            image: container.image.clone(),
+            cmd: container.args.clone(),
+            env: process
+                .and_then(|process| process.env.clone())
+                .unwrap_or_default(),
+            working_dir: process
+                .map(|process| process.cwd.clone())
+                .unwrap_or_default(),
+            labels: config.annotations.clone().unwrap_or_default(),
+        },
+    };
+
+    json(StatusCode::OK, &details)
+}
+
+/// Starts the container, capturing its output to the log.
+/// Stopped containers are started anew.
+pub async fn start(engine: &Engine, reference: &str) -> ApiResult {
+    let container = find(engine, reference).await?;
+    let key = container.key();
+    let started = engine
+        .blocking(move |storage| {
+            let ops = OciOperations::new(storage, container.key())?;
+
+            match ops.get_state(MAIN_PROCESS_EXEC_ID)?.status {
+                ProcessStatus::Created => (),
+                ProcessStatus::Stopped => {
+                    ops.delete_process(MAIN_PROCESS_EXEC_ID)?
+                }
+                ProcessStatus::Starting | ProcessStatus::Running => {
+                    return Ok(None)
+                }
+            }
+
+            let capture = log_capture(&container.bundle)?;
+
+            ops.do_start_captured(
+                MAIN_PROCESS_EXEC_ID,
+                container.overrides(),
+                capture.clone(),
+            )?;
+
+            Ok(Some(capture))
+        })
+        .await?;
+
+    let capture = match started {
+        Some(capture) => capture,
+        None => return empty(StatusCode::NOT_MODIFIED),
+    };
+
+    spawn_wait(engine, key, capture);
+
+    empty(StatusCode::NO_CONTENT)
+}
+
+/// Stops the container gracefully, see
+/// [`OciOperations::stop`]. Waits for the exit to be
+/// recorded.
+pub async fn stop(
+    engine: &Engine,
+    reference: &str,
+    timeout: Option<u64>,
+) -> ApiResult {
+    let container = find(engine, reference).await?;
+    let timeout = Duration::from_secs(timeout.unwrap_or(DEFAULT_STOP_TIMEOUT));
+
+    if !do_stop(engine, &container, timeout).await? {
+        return empty(StatusCode::NOT_MODIFIED);
+    }
+
+    empty(StatusCode::NO_CONTENT)
+}
+
+/// Output of the container, the way Docker multiplexes it.
+/// Logs aren't followed.
+pub async fn logs(
+    engine: &Engine,
+    reference: &str,
+    stdout: bool,
+    stderr: bool,
+) -> ApiResult {
+    if !stdout && !stderr {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "You must choose at least one stream",
+        ));
+    }
+
+    let container = find(engine, reference).await?;
+    let content = engine
+        .blocking(move |_| Ok(logs::read(&container.bundle, stdout, stderr)?))
+        .await?;
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/vnd.docker.raw-stream")
+        .body(Body::from(content))?)
+}
+
+/// Deletes the container along with its bundle. Running
+/// containers are killed beforehand, if it's forced.
+pub async fn remove(
+    engine: &Engine,
+    reference: &str,
+    force: bool,
+) -> ApiResult {
+    let container = find(engine, reference).await?;
+    let running = {
+        let key = container.key();
+
+        engine
+            .blocking(move |storage| {
+                Ok(is_running(state(storage, &key).as_ref()))
+            })
+            .await?
+    };
+
+    if running && !force {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!(
+                "You cannot remove a running container {}. Stop the \
+                 container before attempting removal or force remove",
+                container.id
+            ),
+        ));
+    }
+
+    if running {
+        do_stop(engine, &container, Duration::from_secs(0)).await?;
+    }
+
+    engine
+        .blocking(move |storage| {
+            OciOperations::new(storage, container.key())?.delete();
+
+            if container.bundle.exists() {
+                fs::remove_dir_all(&container.bundle)
+                    .map_err(anyhow::Error::from)?;
+            }
+
+            CONTAINERS.remove(storage, container.id.as_str())?;
+            NAMES.remove(storage, container.name.as_str())?;
+
+            Ok(())
+        })
+        .await?;
+
+    empty(StatusCode::NO_CONTENT)
+}
+
+/// Stops the running container. Returns whether it was
+/// running.
+async fn do_stop(
+    engine: &Engine,
+    container: &Container,
+    timeout: Duration,
+) -> Result<bool, ApiError> {
+    let key = container.key();
+    let stopped = engine
+        .blocking({
+            let key = key.clone();
+
+            move |storage| {
+                let ops = OciOperations::new(storage, key)?;
+
+                if !is_running(Some(&ops.get_state(MAIN_PROCESS_EXEC_ID)?)) {
+                    return Ok(false);
+                }
+
+                ops.stop(timeout)?;
+
+                Ok(true)
+            }
+        })
+        .await?;
+
+    if !stopped {
+        return Ok(false);
+    }
+
+    // Exit is recorded by the task waiting for the process
+    let deadline = Instant::now() + EXIT_TIMEOUT;
+
+    loop {
+        let key = key.clone();
+        let running = engine
+            .blocking(move |storage| {
+                Ok(is_running(state(storage, &key).as_ref()))
+            })
+            .await?;
+
+        if !running {
+            return Ok(true);
+        }
+
+        if Instant::now() >= deadline {
+            return Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Container {} didn't stop", container.id),
+            ));
+        }
+
+        tokio::time::sleep(EXIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Waits for the main process to exit, so that the reaper
+/// records its exit. Restarted processes keep logging the
+/// output through the `capture`.
+fn spawn_wait(engine: &Engine, key: String, capture: OutputCapture) {
+    let operations = AsyncOperations::new(&engine.reaper, &key);
+
+    tokio::spawn(async move {
+        if let Err(error) = operations.wait_captured(capture).await {
+            tracing::error!("Failed to wait for {}: {}", key, error);
+        }
+    });
+}
+
+/// Container the `reference` names: its name, id or the
+/// unique prefix of the id.
+async fn find(
+    engine: &Engine,
+    reference: &str,
+) -> Result<Container, ApiError> {
+    let reference = reference.to_string();
+
+    engine
+        .blocking(move |storage| {
+            Ok(lookup(&load(storage)?, &reference)?.clone())
+        })
+        .await
+}
+
+fn lookup<'a>(
+    containers: &'a [Container],
+    reference: &str,
+) -> Result<&'a Container, ApiError> {
+    let name = reference.trim_start_matches('/');
+    let exact = containers
+        .iter()
+        .find(|container| container.id == reference || container.name == name);
+
+    if let Some(container) = exact {
+        return Ok(container);
+    }
+
+    let mut matching = containers.iter().filter(|container| {
+        !reference.is_empty() && container.id.starts_with(reference)
+    });
+
+    match (matching.next(), matching.next()) {
+        (Some(container), None) => Ok(container),
+        (Some(_), Some(_)) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Multiple containers match {}", reference),
+        )),
+        (None, _) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No such container: {}", reference),
+        )),
+    }
+}
+
+fn load(storage: &DynamicStorage) -> Result<Vec<Container>, ApiError> {
+    let mut containers = vec![];
+
+    for key in CONTAINERS.keys(storage)? {
+        let key = String::from_utf8_lossy(&key).into_owned();
+
+        containers.extend(CONTAINERS.get(storage, key.as_str())?);
+    }
+
+    Ok(containers)
+}
+
+/// Capture of the output to the log of the container of the
+/// `bundle`.
+fn log_capture(bundle: &Path) -> Result<OutputCapture, ApiError> {
+    let log = Log::open(bundle)?;
+
+    Ok(OutputCapture::Callback(Arc::new(
+        move |stream, chunk: &[u8]| log.write(stream, chunk),
+    )))
+}
+
+/// State of the container, unless it's gone, i.e. deleted
+/// via runc.
+fn state(storage: &DynamicStorage, key: &str) -> Option<OciStatus> {
+    OciOperations::new(storage, key).ok()?.state().ok()
+}
+
+fn is_running(state: Option<&OciStatus>) -> bool {
+    matches!(
+        state.map(|state| state.status),
+        Some(ProcessStatus::Starting) | Some(ProcessStatus::Running)
+    )
+}
+
+/// Command of the container, the way Docker makes it up:
+/// given entrypoint drops the command of the image.
+fn command(
+    request: &CreateRequest,
+    entrypoint: Option<&[String]>,
+    cmd: Option<&[String]>,
+) -> Vec<String> {
+    let cmd = match (&request.cmd, &request.entrypoint) {
+        (Some(cmd), _) => Some(cmd.as_slice()),
+        (None, Some(_)) => None,
+        (None, None) => cmd,
+    };
+
+    request
+        .entrypoint
+        .as_deref()
+        .or(entrypoint)
+        .into_iter()
+        .chain(cmd)
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+/// Whether the name is the one Docker would accept.
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars.next().map_or(false, |c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+}
+
+/// Docker's state of the container, and its status line.
+fn status(state: Option<&OciStatus>) -> (&'static str, String) {
+    let state = match state {
+        Some(state) => state,
+        None => return ("dead", "Dead".into()),
+    };
+
+    match state.status {
+        ProcessStatus::Created => ("created", "Created".into()),
+        ProcessStatus::Starting | ProcessStatus::Running => {
+            let status = match state.health {
+                Some(health) => format!("Up ({})", health.as_ref()),
+                None => "Up".into(),
+            };
+
+            ("running", status)
+        }
+        ProcessStatus::Stopped => {
+            ("exited", format!("Exited ({})", exit_code(state)))
+        }
+    }
+}
+
+/// Exit code of the stopped process, -1 if it's unknown.
+fn exit_code(state: &OciStatus) -> i32 {
+    state.exit_status.unwrap_or(-1)
+}
+
+fn summary(
+    container: Container,
+    state: Option<OciStatus>,
+    ports: Vec<PortMapping>,
+) -> Summary {
+    let (state_name, status) = status(state.as_ref());
+    let created = container
+        .created
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |created| created.as_secs());
+
+    Summary {
+        names: vec![format!("/{}", container.name)],
+        image_id: container.image_id,
+        command: container.args.join(" "),
+        created,
+        state: state_name,
+        status,
+        ports: ports
+            .into_iter()
+            .map(|port| Port {
+                ip: "0.0.0.0",
+                private_port: port.container_port,
+                public_port: port.host_port,
+                protocol: port.protocol,
+            })
+            .collect(),
+        labels: state.map(|state| state.annotations).unwrap_or_default(),
+        id: container.id,
+        image: container.image,
+    }
+}
+
+fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(id: &str, name: &str) -> Container {
+        Container {
+            id: id.into(),
+            name: name.into(),
+            image: "nginx".into(),
+            image_id: "sha256:abc".into(),
+            args: vec![],
+            env: vec![],
+            working_dir: None,
+            created: UNIX_EPOCH,
+            bundle: PathBuf::new(),
+        }
+    }
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|string| string.to_string()).collect()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let containers =
+            vec![container("abc123", "web"), container("abd456", "db")];
+        let id = |reference| lookup(&containers, reference).map(|c| &c.id);
+
+        assert_eq!(id("/web").unwrap(), "abc123");
+        assert_eq!(id("abd").unwrap(), "abd456");
+        assert_eq!(id("abc123").unwrap(), "abc123");
+        assert_eq!(id("ab").unwrap_err().status, StatusCode::BAD_REQUEST);
+        assert_eq!(id("cache").unwrap_err().status, StatusCode::NOT_FOUND);
+        assert_eq!(id("").unwrap_err().status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_command() {
+        let entrypoint = strings(&["docker-entrypoint.sh"]);
+        let cmd = strings(&["postgres"]);
+        let image = |request: &CreateRequest| {
+            command(request, Some(&entrypoint), Some(&cmd))
+        };
+
+        assert_eq!(
+            image(&CreateRequest::default()),
+            strings(&["docker-entrypoint.sh", "postgres"])
+        );
+        assert_eq!(
+            image(&CreateRequest {
+                cmd: Some(strings(&["postgres", "-c", "fsync=off"])),
+                ..CreateRequest::default()
+            }),
+            strings(&["docker-entrypoint.sh", "postgres", "-c", "fsync=off"])
+        );
+        assert_eq!(
+            image(&CreateRequest {
+                entrypoint: Some(strings(&["/bin/sh"])),
+                ..CreateRequest::default()
+            }),
+            strings(&["/bin/sh"])
+        );
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("web-1.example_2"));
+        assert!(!valid_name("-web"));
+        assert!(!valid_name("web/1"));
+        assert!(!valid_name(""));
+    }
+}
@@ -0,0 +1,205 @@
+/// Images of the Docker API. Pulls report their progress
+/// the way Docker does, as a stream of JSON messages, and
+/// failures of the pull are reported by the stream too.
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Error;
+use baustelle::{
+    image_store::ImageStore, platform::Platform, Image, ImagePuller,
+    LayerDownloadStatus, Reference,
+};
+use futures::{channel::mpsc, future, StreamExt};
+use hyper::{body::Bytes, header::CONTENT_TYPE, Body, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+
+use super::api::{architecture, json, ApiError, ApiResult, Engine};
+
+/// Platforms images are pulled for, unless the request
+/// names one, i.e. `freebsd/amd64,linux/amd64`.
+const PLATFORM_VARIABLE: &str = "KNAST_PLATFORM";
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Summary {
+    id: String,
+    parent_id: String,
+    repo_tags: Vec<String>,
+    repo_digests: Vec<String>,
+    created: i64,
+    size: usize,
+    virtual_size: usize,
+    shared_size: i64,
+    labels: BTreeMap<String, String>,
+    containers: i64,
+}
+
+/// Pulled images, the ones of several references are listed
+/// once.
+pub async fn list(engine: &Engine) -> ApiResult {
+    let summaries = engine
+        .blocking(|storage| {
+            let mut summaries: Vec<Summary> = vec![];
+
+            for image in ImageStore::new(storage).list()? {
+                let reference = image.reference.parse::<Reference>()?;
+                let opened = Image::open(storage, &reference)?;
+                let id = opened.manifest().config.digest.clone();
+
+                if let Some(summary) =
+                    summaries.iter_mut().find(|summary| summary.id == id)
+                {
+                    summary.repo_tags.push(image.reference);
+                    continue;
+                }
+
+                summaries.push(Summary {
+                    id,
+                    parent_id: String::new(),
+                    repo_digests: vec![format!(
+                        "{}/{}@{}",
+                        reference.registry, reference.repository, image.digest
+                    )],
+                    repo_tags: vec![image.reference],
+                    created: opened
+                        .config()
+                        .created
+                        .map_or(0, |created| created.timestamp()),
+                    size: image.size,
+                    virtual_size: image.size,
+                    shared_size: -1,
+                    labels: BTreeMap::new(),
+                    containers: -1,
+                });
+            }
+
+            Ok(summaries)
+        })
+        .await?;
+
+    json(StatusCode::OK, &summaries)
+}
+
+/// Pulls the image, streaming the progress of the pull.
+pub async fn pull(
+    engine: Arc<Engine>,
+    reference: &str,
+    platform: Option<&str>,
+) -> ApiResult {
+    let parsed = reference.parse::<Reference>().map_err(invalid)?;
+    let platforms = match platform {
+        Some(platform) => Platform::parse_list(platform).map_err(invalid)?,
+        None => platforms().map_err(invalid)?,
+    };
+    let reference = reference.to_string();
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let (updates, mut progress) = mpsc::channel(16);
+        let puller = ImagePuller::new(&engine.storage, platforms);
+        let pull = async {
+            let image = puller.pull(&parsed, updates).await?;
+
+            Ok::<_, Error>(image.digest().to_string())
+        };
+        let forward = async {
+            while let Some(status) = progress.next().await {
+                let _ =
+                    sender.send_data(message(&status_message(status))).await;
+            }
+        };
+        let (result, _) = future::join(pull, forward).await;
+        let messages = match result {
+            Ok(digest) => vec![
+                json!({ "status": format!("Digest: {}", digest) }),
+                json!({
+                    "status": format!("Status: Pulled {}", reference)
+                }),
+            ],
+            Err(error) => {
+                let error = format!("{:#}", error);
+
+                vec![json!({
+                    "errorDetail": { "message": error },
+                    "error": error,
+                })]
+            }
+        };
+
+        for value in messages {
+            let _ = sender.send_data(message(&value)).await;
+        }
+    });
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)?)
+}
+
+/// Reference of the image `fromImage` and `tag` parameters
+/// name, tags are either tags or digests.
+pub fn reference(image: &str, tag: Option<&str>) -> String {
+    match tag {
+        Some(tag) if tag.contains(':') => format!("{}@{}", image, tag),
+        Some(tag) => format!("{}:{}", image, tag),
+        None => image.into(),
+    }
+}
+
+fn platforms() -> Result<Vec<Platform>, Error> {
+    match std::env::var(PLATFORM_VARIABLE) {
+        Ok(platforms) => Platform::parse_list(&platforms),
+        Err(_) => Ok(vec![
+            Platform::new("freebsd", architecture()),
+            Platform::new("linux", architecture()),
+        ]),
+    }
+}
+
+fn status_message(status: LayerDownloadStatus) -> serde_json::Value {
+    match status {
+        LayerDownloadStatus::Cached(digest) => json!({
+            "status": "Already exists",
+            "id": short_id(&digest),
+        }),
+        LayerDownloadStatus::InProgress(digest, current, total) => json!({
+            "status": "Downloading",
+            "progressDetail": { "current": current, "total": total },
+            "id": short_id(&digest),
+        }),
+    }
+}
+
+/// The message, as a line of the stream.
+fn message(value: &serde_json::Value) -> Bytes {
+    format!("{}\r\n", value).into()
+}
+
+/// Digest, shortened the way Docker shortens layer ids.
+fn short_id(digest: &str) -> &str {
+    let hex = digest.split(':').last().unwrap_or(digest);
+
+    &hex[..hex.len().min(12)]
+}
+
+fn invalid(error: Error) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, format!("{:#}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference() {
+        assert_eq!(reference("nginx", Some("1.21")), "nginx:1.21");
+        assert_eq!(reference("nginx:1.21", None), "nginx:1.21");
+        assert_eq!(reference("nginx", Some("sha256:abc")), "nginx@sha256:abc");
+    }
+
+    #[test]
+    fn test_short_id() {
+        assert_eq!(short_id("sha256:0123456789abcdef"), "0123456789ab");
+        assert_eq!(short_id("abc"), "abc");
+    }
+}
@@ -0,0 +1,141 @@
+/// Output of the containers, kept in their bundles the way
+/// Docker multiplexes the streams, so that logs are served as
+/// they are: each chunk is preceded by the 8-byte header of
+/// its stream and length.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::Error;
+use libknast::operations::OutputStream;
+
+const LOG_FILE: &str = "container.log";
+const HEADER_SIZE: usize = 8;
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+
+/// Log the output of the container is appended to.
+pub struct Log(Mutex<File>);
+
+impl Log {
+    /// Log of the container of the `bundle`.
+    pub fn open(bundle: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(bundle.join(LOG_FILE))?;
+
+        Ok(Self(Mutex::new(file)))
+    }
+
+    /// Appends the chunk. Failures are logged, so that the
+    /// output of the process isn't blocked.
+    pub fn write(&self, stream: OutputStream, chunk: &[u8]) {
+        let result = match self.0.lock() {
+            Ok(mut file) => file.write_all(&frame(stream, chunk)),
+            Err(_) => return,
+        };
+
+        if let Err(error) = result {
+            tracing::warn!("Failed to log the output: {}", error);
+        }
+    }
+}
+
+/// Frames of the streams, read from the log of the `bundle`.
+pub fn read(
+    bundle: &Path,
+    stdout: bool,
+    stderr: bool,
+) -> Result<Vec<u8>, Error> {
+    let content = match fs::read(bundle.join(LOG_FILE)) {
+        Ok(content) => content,
+        // Never started
+        Err(error) if error.kind() == ErrorKind::NotFound => vec![],
+        Err(error) => return Err(error.into()),
+    };
+
+    Ok(filter(&content, stdout, stderr))
+}
+
+/// The chunk, preceded by the header.
+fn frame(stream: OutputStream, chunk: &[u8]) -> Vec<u8> {
+    let kind = match stream {
+        OutputStream::Stdout => STDOUT,
+        OutputStream::Stderr => STDERR,
+    };
+    let mut frame = Vec::with_capacity(HEADER_SIZE + chunk.len());
+
+    frame.extend_from_slice(&[kind, 0, 0, 0]);
+    frame.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    frame.extend_from_slice(chunk);
+
+    frame
+}
+
+/// Frames of the streams asked for. The truncated frame,
+/// which is being written, is dropped.
+fn filter(content: &[u8], stdout: bool, stderr: bool) -> Vec<u8> {
+    let mut result = vec![];
+    let mut rest = content;
+
+    while rest.len() >= HEADER_SIZE {
+        let length = [rest[4], rest[5], rest[6], rest[7]];
+        let end = HEADER_SIZE + u32::from_be_bytes(length) as usize;
+
+        if rest.len() < end {
+            break;
+        }
+
+        let wanted = match rest[0] {
+            STDOUT => stdout,
+            STDERR => stderr,
+            _ => false,
+        };
+
+        if wanted {
+            result.extend_from_slice(&rest[..end]);
+        }
+
+        rest = &rest[end..];
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let bundle = tempfile::tempdir().unwrap();
+        let log = Log::open(bundle.path()).unwrap();
+
+        log.write(OutputStream::Stdout, b"ready\n");
+        log.write(OutputStream::Stderr, b"warning\n");
+
+        assert_eq!(
+            read(bundle.path(), true, false).unwrap(),
+            b"\x01\0\0\0\0\0\0\x06ready\n"
+        );
+        assert_eq!(
+            read(bundle.path(), true, true).unwrap(),
+            [
+                frame(OutputStream::Stdout, b"ready\n"),
+                frame(OutputStream::Stderr, b"warning\n")
+            ]
+            .concat()
+        );
+
+        // Being written
+        let mut content = frame(OutputStream::Stdout, b"ready\n");
+
+        content.extend_from_slice(&frame(OutputStream::Stdout, b"gone")[..9]);
+
+        assert_eq!(filter(&content, true, true), content[..14]);
+    }
+}
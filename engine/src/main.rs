@@ -0,0 +1,69 @@
+mod api;
+mod containers;
+mod images;
+mod logs;
+
+use std::{fs::remove_file, path::PathBuf, sync::Arc};
+
+use anyhow::Error;
+use hyper::{server::conn::Http, service::service_fn};
+use libknast::{
+    logging::{self, LogConfig},
+    nonblocking::Reaper,
+    operations,
+};
+use storage::DynamicStorage;
+use tokio::net::UnixListener;
+
+use api::Engine;
+
+const DEFAULT_SOCKET_PATH: &str = "/var/run/knast.sock";
+const NAT_INTERFACE_VARIABLE: &str = "NAT_INTERFACE";
+
+/// Docker Engine API subset, so that Docker clients manage
+/// knast's containers and images:
+///
+/// ```sh
+/// DOCKER_HOST=unix:///var/run/knast.sock lazydocker
+/// ```
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let _guard = logging::init(&LogConfig::from_env(None)?)?;
+
+    let socket = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| DEFAULT_SOCKET_PATH.into());
+    let storage = DynamicStorage::new(storage::root(None))?;
+    operations::migrate(&storage)?;
+    let storage = Arc::new(storage);
+    let reaper = Reaper::spawn(storage.clone())?;
+    let nat_interface = std::env::var(NAT_INTERFACE_VARIABLE).ok();
+    let engine = Arc::new(Engine::new(storage, reaper, nat_interface));
+
+    containers::adopt(&engine).await?;
+
+    if let Err(error) = remove_file(&socket) {
+        tracing::info!("Previous socket wasn't deleted due to {}", error)
+    };
+
+    let listener = UnixListener::bind(&socket)?;
+    tracing::info!("Server is listening at {:?}", socket);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let engine = engine.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                api::handle(engine.clone(), request)
+            });
+
+            if let Err(error) =
+                Http::new().serve_connection(stream, service).await
+            {
+                tracing::info!("Connection failed: {}", error);
+            }
+        });
+    }
+}
@@ -0,0 +1,178 @@
+/// Errors of libknast's public API. Consumers branch on
+/// [`KnastError`] variants, i.e. containerd retries storage
+/// failures, but not the invalid configs.
+///
+/// Internally, errors are thrown via `anyhow`. Errors of
+/// known kinds are thrown as [`KnastError`] and recovered
+/// at the API boundary, others become [`KnastError::Other`]
+/// along with their context.
+use anyhow::Error;
+use baustelle::runtime_config::InvalidConfig;
+use common_lib::env::MalformedEnv;
+
+#[derive(Debug, thiserror::Error)]
+pub enum KnastError {
+    #[error("Container '{0}' doesn't exist!")]
+    ContainerNotFound(String),
+    #[error("Container '{0}' already exists!")]
+    ContainerExists(String),
+    #[error("Process '{0}' doesn't exist!")]
+    ProcessNotFound(String),
+    #[error("Process '{0}' already exists!")]
+    ProcessExists(String),
+    /// Runtime config or overrides of the process are
+    /// invalid.
+    #[error(transparent)]
+    ConfigInvalid(Error),
+    /// Container or process isn't in the right state, i.e.
+    /// stopped containers can't be killed.
+    #[error("{0}")]
+    InvalidState(String),
+    /// Jail of the container can't be started or attached to.
+    #[error("Jail error: {0:#}")]
+    JailError(Error),
+    /// Storage failed, retrying may help.
+    #[error("Storage is unavailable: {0:#}")]
+    StorageError(Error),
+    /// Operation requires privileges knast lacks, i.e. root.
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error(transparent)]
+    Other(Error),
+}
+
+/// Kinds of failures, which callers handle differently.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    NotFound,
+    AlreadyExists,
+    InvalidArgument,
+    FailedPrecondition,
+    Unavailable,
+    PermissionDenied,
+}
+
+impl KnastError {
+    pub fn kind(&self) -> Option<ErrorKind> {
+        match self {
+            KnastError::ContainerNotFound(_)
+            | KnastError::ProcessNotFound(_) => Some(ErrorKind::NotFound),
+            KnastError::ContainerExists(_) | KnastError::ProcessExists(_) => {
+                Some(ErrorKind::AlreadyExists)
+            }
+            KnastError::ConfigInvalid(_) => Some(ErrorKind::InvalidArgument),
+            KnastError::InvalidState(_) => Some(ErrorKind::FailedPrecondition),
+            KnastError::StorageError(_) => Some(ErrorKind::Unavailable),
+            KnastError::PermissionDenied(_) => {
+                Some(ErrorKind::PermissionDenied)
+            }
+            KnastError::JailError(_) => None,
+            KnastError::Other(error) => kind(error),
+        }
+    }
+}
+
+impl From<Error> for KnastError {
+    fn from(error: Error) -> Self {
+        if error.is::<InvalidConfig>() || error.is::<MalformedEnv>() {
+            return KnastError::ConfigInvalid(error);
+        }
+
+        match error.downcast::<KnastError>() {
+            Ok(error) => error,
+            Err(error) => KnastError::Other(error),
+        }
+    }
+}
+
+impl From<InvalidConfig> for KnastError {
+    fn from(error: InvalidConfig) -> Self {
+        KnastError::ConfigInvalid(error.into())
+    }
+}
+
+impl From<MalformedEnv> for KnastError {
+    fn from(error: MalformedEnv) -> Self {
+        KnastError::ConfigInvalid(error.into())
+    }
+}
+
+/// Kind of the error, or of the first of its causes of a
+/// known kind.
+pub fn kind(error: &Error) -> Option<ErrorKind> {
+    if let Some(error) = error.downcast_ref::<KnastError>() {
+        return error.kind();
+    }
+
+    error.chain().find_map(|cause| {
+        if let Some(error) = cause.downcast_ref::<KnastError>() {
+            error.kind()
+        } else if cause.is::<InvalidConfig>() || cause.is::<MalformedEnv>() {
+            Some(ErrorKind::InvalidArgument)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_from_anyhow() {
+        let error =
+            Error::from(KnastError::ContainerNotFound("debian".into()))
+                .context("Starting container");
+
+        assert!(matches!(
+            KnastError::from(error),
+            KnastError::ContainerNotFound(id) if id == "debian"
+        ));
+
+        let error = Error::from(MalformedEnv("=".into()))
+            .context("Parsing environment");
+
+        match KnastError::from(error) {
+            KnastError::ConfigInvalid(error) => {
+                assert_eq!(error.to_string(), "Parsing environment")
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let error = KnastError::from(anyhow!("Disk is on fire"));
+
+        assert!(matches!(error, KnastError::Other(_)));
+        assert_eq!(error.to_string(), "Disk is on fire");
+    }
+
+    #[test]
+    fn test_kind() {
+        let not_found = Error::from(KnastError::ContainerNotFound("a".into()));
+
+        assert_eq!(kind(&not_found), Some(ErrorKind::NotFound));
+        assert_eq!(
+            kind(&not_found.context("Starting container")),
+            Some(ErrorKind::NotFound)
+        );
+        assert_eq!(
+            kind(&Error::from(MalformedEnv("=".into()))),
+            Some(ErrorKind::InvalidArgument)
+        );
+        assert_eq!(kind(&anyhow!("Disk is on fire")), None);
+        assert_eq!(
+            KnastError::ProcessExists("exec".into()).kind(),
+            Some(ErrorKind::AlreadyExists)
+        );
+        assert_eq!(
+            KnastError::StorageError(anyhow!("connection refused")).kind(),
+            Some(ErrorKind::Unavailable)
+        );
+        assert_eq!(
+            KnastError::PermissionDenied("create".into()).kind(),
+            Some(ErrorKind::PermissionDenied)
+        );
+    }
+}
@@ -3,6 +3,8 @@
 /// i.e. devfs nodes need to be hidden using the rule
 /// subsystem, and so on.
 mod devfs;
+mod kind;
+mod layered;
 mod mount;
 
 use std::{
@@ -10,28 +12,34 @@ use std::{
     path::{Component, Path, PathBuf},
 };
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 
-use baustelle::runtime_config::Mount;
+use baustelle::runtime_config::{Device, LinuxDevice, Mount};
+
+pub use kind::FilesystemKind;
+pub use layered::{LayeredRootfs, Upper};
 
 pub trait Mountable {
     #[fehler::throws]
     fn mount(&self, rootfs: impl AsRef<Path>) {
-        let kind = self.kind();
+        let kind: FilesystemKind = self.kind().parse()?;
         let source = self.source();
         let destination = prefixed_destination(&rootfs, self.destination());
+        let options = self.options();
+
+        kind.validate(source, &options)?;
 
         tracing::info!(
             "Mounting {} fs {:?} -> {:?}",
-            kind,
+            kind.as_ref(),
             source,
             destination
         );
         mount::mount(
-            kind,
+            &kind.as_ref(),
             source,
             &destination,
-            self.options().iter().map(|x| x as &dyn AsRef<str>),
+            kind.options(options).iter().map(|x| x as &dyn AsRef<str>),
         )?;
 
         self.post_mount_hooks(rootfs)?;
@@ -42,6 +50,11 @@ pub trait Mountable {
         mount::unmount(&prefixed_destination(rootfs, self.destination()))?;
     }
 
+    #[fehler::throws]
+    fn is_mounted(&self, rootfs: impl AsRef<Path>) -> bool {
+        mount::is_mounted(&prefixed_destination(rootfs, self.destination()))?
+    }
+
     fn post_mount_hooks(&self, rootfs: impl AsRef<Path>) -> Result<(), Error>;
 
     fn kind(&self) -> &String;
@@ -97,6 +110,145 @@ fn prepare_devfs(path: impl AsRef<Path>) {
     }
 }
 
+/// Exposes devices requested by the runtime config in
+/// devfs mounted at `path`, on top of the default ones.
+#[fehler::throws]
+pub fn expose_devices(path: impl AsRef<Path>, devices: &[Device]) {
+    use devfs::{apply, Operation};
+
+    for device in devices {
+        let pattern = device_pattern(device)?;
+
+        tracing::info!("Exposing {:?} in {:?}", pattern, path.as_ref());
+        apply(&path, Operation::Unhide(pattern))?;
+
+        if device.uid.is_some()
+            || device.gid.is_some()
+            || device.file_mode.is_some()
+        {
+            apply(
+                &path,
+                Operation::Permissions {
+                    pattern,
+                    uid: device.uid,
+                    gid: device.gid,
+                    mode: device.file_mode.map(|mode| mode as _),
+                },
+            )?;
+        }
+    }
+}
+
+/// Hides devices exposed by [`expose_devices`]. Rules
+/// vanish along with devfs mount anyway, but the devices
+/// shouldn't stay exposed if devfs fails to unmount.
+#[fehler::throws]
+pub fn hide_devices(path: impl AsRef<Path>, devices: &[Device]) {
+    use devfs::{apply, Operation};
+
+    for device in devices {
+        apply(&path, Operation::Hide(device_pattern(device)?))?;
+    }
+}
+
+/// Checks that devices requested by the runtime config
+/// can be exposed.
+#[fehler::throws]
+pub fn validate_devices(devices: &[Device]) {
+    for device in devices {
+        device_pattern(device)?;
+    }
+}
+
+/// Converts OCI `linux.devices` into devices to expose.
+/// devfs can't create nodes, so the devices must exist on
+/// the host under the same path; major and minor numbers
+/// are ignored.
+#[fehler::throws]
+pub fn passthrough_devices(devices: &[LinuxDevice]) -> Vec<Device> {
+    let mut result = vec![];
+
+    for device in devices {
+        // FreeBSD has no block devices
+        if device.r#type != "c" && device.r#type != "u" {
+            fehler::throw!(anyhow!(
+                "Runtime config: device {:?} has unsupported type {:?}",
+                device.path,
+                device.r#type
+            ));
+        }
+
+        if !device.path.starts_with("/dev/")
+            || device.path.contains(&['*', '?', '['][..])
+        {
+            fehler::throw!(anyhow!(
+                "Runtime config: invalid device path {:?}",
+                device.path
+            ));
+        }
+
+        if !Path::new(&device.path).exists() {
+            fehler::throw!(anyhow!(
+                "Runtime config: device {:?} doesn't exist",
+                device.path
+            ));
+        }
+
+        result.push(Device {
+            path: device.path.clone(),
+            file_mode: device.file_mode,
+            uid: device.uid,
+            gid: device.gid,
+        });
+    }
+
+    result
+}
+
+/// Checks that none of the devices is reachable from the
+/// container's rootfs anymore.
+#[fehler::throws]
+pub fn verify_devices_hidden(rootfs: impl AsRef<Path>, devices: &[Device]) {
+    for device in devices {
+        let path = prefixed_destination(&rootfs, &device.path);
+
+        if path.exists() {
+            fehler::throw!(anyhow!(
+                "Device {:?} is still exposed at {:?}",
+                device.path,
+                path
+            ));
+        }
+    }
+}
+
+/// Turns device path into devfs rule pattern, which is
+/// relative to the devfs mountpoint.
+#[fehler::throws]
+fn device_pattern(device: &Device) -> &str {
+    let pattern = device.path.trim_start_matches("/dev/");
+
+    if pattern.is_empty()
+        || pattern.starts_with('/')
+        || pattern.split('/').any(|component| component == "..")
+    {
+        fehler::throw!(anyhow!(
+            "Runtime config: invalid device path {:?}",
+            device.path
+        ));
+    }
+
+    if let Some(mode) = device.file_mode.filter(|mode| *mode > 0o7777) {
+        fehler::throw!(anyhow!(
+            "Runtime config: invalid mode {:o} for device {:?}",
+            mode,
+            device.path
+        ));
+    }
+
+    pattern
+}
+
 /// For args, cwd, and mountpoints runtime config specifies
 /// paths inside containers Therefore, we need to prefix
 /// these paths with the rootfs of the container.
@@ -122,6 +274,53 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_device_validation() {
+        let device = |path: &str, file_mode| Device {
+            path: path.into(),
+            file_mode,
+            uid: None,
+            gid: None,
+        };
+
+        assert_eq!(
+            device_pattern(&device("/dev/bpf*", None)).unwrap(),
+            "bpf*"
+        );
+        assert_eq!(device_pattern(&device("dri/*", None)).unwrap(), "dri/*");
+        assert!(device_pattern(&device("/etc/passwd", None)).is_err());
+        assert!(device_pattern(&device("/dev/../etc", None)).is_err());
+        assert!(device_pattern(&device("tun0", Some(0o10000))).is_err());
+    }
+
+    #[test]
+    fn test_passthrough_devices() {
+        let device = |r#type: &str, path: &str| LinuxDevice {
+            r#type: r#type.into(),
+            path: path.into(),
+            major: None,
+            minor: None,
+            file_mode: Some(0o660),
+            uid: Some(1001),
+            gid: None,
+        };
+
+        assert_eq!(
+            passthrough_devices(&[device("c", "/dev/null")]).unwrap(),
+            vec![Device {
+                path: "/dev/null".into(),
+                file_mode: Some(0o660),
+                uid: Some(1001),
+                gid: None,
+            }]
+        );
+        assert!(passthrough_devices(&[device("b", "/dev/null")]).is_err());
+        assert!(passthrough_devices(&[device("c", "/dev/tty*")]).is_err());
+        assert!(
+            passthrough_devices(&[device("c", "/dev/nonexistent")]).is_err()
+        );
+    }
+
     #[test]
     fn test_mounting_nullfs() {
         let source = tempfile::tempdir().unwrap();
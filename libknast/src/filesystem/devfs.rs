@@ -17,6 +17,9 @@ use libc::{c_char, c_int, gid_t, ioctl, mode_t, uid_t};
 
 const MAGIC: u32 = 0xdb0a087a;
 const DRA_BACTS: c_int = 0x1;
+const DRA_UID: c_int = 0x2;
+const DRA_GID: c_int = 0x4;
+const DRA_MODE: c_int = 0x8;
 const DRB_HIDE: c_int = 0x1;
 const DRB_UNHIDE: c_int = 0x2;
 const DRC_PATHPTRN: c_int = 0x2;
@@ -39,7 +42,15 @@ struct DevfsRule {
 
 pub enum Operation<'a> {
     HideAll,
+    Hide(&'a str),
     Unhide(&'a str),
+    /// Sets owner and mode of the nodes matching the pattern.
+    Permissions {
+        pattern: &'a str,
+        uid: Option<uid_t>,
+        gid: Option<gid_t>,
+        mode: Option<mode_t>,
+    },
 }
 
 #[fehler::throws]
@@ -47,17 +58,44 @@ pub fn apply(path: impl AsRef<Path>, operation: Operation) {
     let file = File::open(path.as_ref())?;
     let mut rule: DevfsRule = unsafe { mem::zeroed() };
     rule.magic = MAGIC;
-    rule.iacts = DRA_BACTS;
 
     match operation {
         Operation::HideAll => {
+            rule.iacts = DRA_BACTS;
             rule.bacts = DRB_HIDE;
         }
+        Operation::Hide(node) => {
+            rule.iacts = DRA_BACTS;
+            rule.bacts = DRB_HIDE;
+            set_pattern(&mut rule, node)?;
+        }
         Operation::Unhide(node) => {
+            rule.iacts = DRA_BACTS;
             rule.bacts = DRB_UNHIDE;
-            rule.icond = DRC_PATHPTRN;
-            rule.pathptrn[0..node.len()]
-                .copy_from_slice(node.as_signed_bytes());
+            set_pattern(&mut rule, node)?;
+        }
+        Operation::Permissions {
+            pattern,
+            uid,
+            gid,
+            mode,
+        } => {
+            set_pattern(&mut rule, pattern)?;
+
+            if let Some(uid) = uid {
+                rule.iacts |= DRA_UID;
+                rule.uid = uid;
+            }
+
+            if let Some(gid) = gid {
+                rule.iacts |= DRA_GID;
+                rule.gid = gid;
+            }
+
+            if let Some(mode) = mode {
+                rule.iacts |= DRA_MODE;
+                rule.mode = mode;
+            }
         }
     }
 
@@ -69,8 +107,24 @@ pub fn apply(path: impl AsRef<Path>, operation: Operation) {
     };
 }
 
+#[fehler::throws]
+fn set_pattern(rule: &mut DevfsRule, pattern: &str) {
+    // Pattern must be NUL-terminated
+    if pattern.len() >= rule.pathptrn.len() {
+        fehler::throw!(anyhow!(
+            "devfs rule: pattern {:?} is too long",
+            pattern
+        ));
+    }
+
+    rule.icond = DRC_PATHPTRN;
+    rule.pathptrn[0..pattern.len()].copy_from_slice(pattern.as_signed_bytes());
+}
+
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
     use super::*;
 
     use crate::filesystem::mount::{mount, unmount};
@@ -120,5 +174,31 @@ mod tests {
             tmpdir.path().join("null").exists(),
             "unhide(null) unhides /dev/null"
         );
+
+        apply(
+            tmpdir.path(),
+            Operation::Permissions {
+                pattern: "null",
+                uid: None,
+                gid: None,
+                mode: Some(0o600),
+            },
+        )
+        .expect("Failed to change /dev/null mode");
+
+        let mode = std::fs::metadata(tmpdir.path().join("null"))
+            .expect("Failed to stat /dev/null")
+            .permissions()
+            .mode();
+
+        assert_eq!(mode & 0o7777, 0o600, "/dev/null mode is changed");
+
+        apply(tmpdir.path(), Operation::Hide("null"))
+            .expect("Failed to hide /dev/null");
+
+        assert!(
+            !tmpdir.path().join("null").exists(),
+            "hide(null) hides /dev/null"
+        );
     }
 }
@@ -0,0 +1,156 @@
+/// Filesystem types containers are allowed to mount.
+///
+/// Mount configs come from untrusted bundles and images,
+/// and nmount(2) happily mounts anything the kernel
+/// supports, including disk-backed filesystems pointing at
+/// host devices. Hence, only an explicit list of types is
+/// accepted, each with its sensible defaults.
+use std::{convert::AsRef, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, strum_macros::AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum FilesystemKind {
+    Devfs,
+    Fdescfs,
+    Linprocfs,
+    Linsysfs,
+    Nullfs,
+    Procfs,
+    Tmpfs,
+    Unionfs,
+}
+
+impl FromStr for FilesystemKind {
+    type Err = Error;
+
+    #[fehler::throws]
+    fn from_str(kind: &str) -> Self {
+        match kind {
+            "devfs" => FilesystemKind::Devfs,
+            "fdescfs" => FilesystemKind::Fdescfs,
+            "linprocfs" => FilesystemKind::Linprocfs,
+            "linsysfs" => FilesystemKind::Linsysfs,
+            "nullfs" => FilesystemKind::Nullfs,
+            "procfs" => FilesystemKind::Procfs,
+            "tmpfs" => FilesystemKind::Tmpfs,
+            "unionfs" => FilesystemKind::Unionfs,
+            kind => fehler::throw!(anyhow!(
+                "mount: filesystem type {:?} is not supported",
+                kind
+            )),
+        }
+    }
+}
+
+impl FilesystemKind {
+    /// Checks that mount parameters make sense for the
+    /// filesystem type.
+    #[fehler::throws]
+    pub fn validate(&self, source: impl AsRef<Path>, options: &[String]) {
+        let source = source.as_ref();
+
+        if let FilesystemKind::Nullfs | FilesystemKind::Unionfs = self {
+            if !source.is_absolute() || !source.is_dir() {
+                fehler::throw!(anyhow!(
+                    "mount: {} source {:?} must be an existing directory",
+                    self.as_ref(),
+                    source
+                ));
+            }
+        }
+
+        for option in options {
+            let key = option_key(option);
+
+            if key.is_empty() {
+                fehler::throw!(anyhow!(
+                    "mount: malformed {} option {:?}",
+                    self.as_ref(),
+                    option
+                ));
+            }
+
+            if key == "fstype" || key == "fspath" || key == "from" {
+                fehler::throw!(anyhow!(
+                    "mount: {} option {:?} is reserved",
+                    self.as_ref(),
+                    option
+                ));
+            }
+        }
+    }
+
+    /// Options every mount of this type gets, unless
+    /// overridden by the config.
+    pub fn default_options(&self) -> &'static [&'static str] {
+        match self {
+            FilesystemKind::Procfs
+            | FilesystemKind::Linprocfs
+            | FilesystemKind::Linsysfs => &["nosuid", "noexec"],
+            FilesystemKind::Tmpfs => &["nosuid", "mode=1777"],
+            FilesystemKind::Fdescfs => &["nosuid"],
+            FilesystemKind::Devfs
+            | FilesystemKind::Nullfs
+            | FilesystemKind::Unionfs => &[],
+        }
+    }
+
+    /// Merges config options with defaults. Config options
+    /// take precedence.
+    pub fn options(&self, options: Vec<String>) -> Vec<String> {
+        let defaults = self.default_options().iter().filter(|default| {
+            !options
+                .iter()
+                .any(|option| option_key(option) == option_key(default))
+        });
+
+        defaults
+            .map(|default| default.to_string())
+            .chain(options.iter().cloned())
+            .collect()
+    }
+}
+
+fn option_key(option: &str) -> &str {
+    option.split('=').next().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_kind() {
+        let error = "zfs".parse::<FilesystemKind>().unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            r#"mount: filesystem type "zfs" is not supported"#
+        );
+    }
+
+    #[test]
+    fn test_default_options() {
+        let kind: FilesystemKind = "tmpfs".parse().unwrap();
+
+        assert_eq!(
+            kind.options(vec!["mode=0700".into(), "size=1g".into()]),
+            vec!["nosuid", "mode=0700", "size=1g"]
+        );
+        assert_eq!(
+            FilesystemKind::Linprocfs.options(vec![]),
+            vec!["nosuid", "noexec"]
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(FilesystemKind::Nullfs.validate("relative", &[]).is_err());
+        assert!(FilesystemKind::Tmpfs
+            .validate("tmpfs", &["fspath=/".into()])
+            .is_err());
+        assert!(FilesystemKind::Procfs.validate("procfs", &[]).is_ok());
+    }
+}
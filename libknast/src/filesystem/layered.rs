@@ -0,0 +1,230 @@
+/// Assembles container rootfs out of unpacked image layers
+/// instead of flattening them into a single directory.
+///
+/// The bottom layer is nullfs-mounted read-only onto the
+/// rootfs, every following layer is stacked on top of it
+/// with unionfs(8). Finally, a writable upper layer is
+/// stacked on top, so that all the container's changes end
+/// up there and image layers stay intact. Upper layer is
+/// either a directory, or a tmpfs mounted for the purpose.
+///
+/// Layers are expected to be unpacked without OCI
+/// whiteouts, as unionfs has its own notion of these.
+use std::{
+    collections::BTreeMap,
+    convert::AsRef,
+    fs, iter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Error};
+pub use baustelle::runtime_config::{LAYERS_ANNOTATION, UPPER_ANNOTATION};
+
+use super::mount::{is_mounted, mount, unmount};
+
+const LAYERS_SEPARATOR: char = ':';
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upper {
+    /// Container's changes are persisted in the directory.
+    Directory(PathBuf),
+    /// Container's changes are kept in a tmpfs mounted on
+    /// the directory and discarded on unmount.
+    Tmpfs(PathBuf),
+}
+
+impl Upper {
+    fn path(&self) -> &Path {
+        match self {
+            Upper::Directory(path) | Upper::Tmpfs(path) => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayeredRootfs {
+    /// Layers, ordered from the bottom to the top one.
+    layers: Vec<PathBuf>,
+    upper: Upper,
+}
+
+impl LayeredRootfs {
+    #[fehler::throws]
+    pub fn new(layers: Vec<PathBuf>, upper: Upper) -> Self {
+        if layers.is_empty() {
+            fehler::throw!(anyhow!("Layered rootfs requires a layer"));
+        }
+
+        Self { layers, upper }
+    }
+
+    /// Reads layers from runtime config annotations. Layers
+    /// are separated by `:`, bottom layer goes first. Unless
+    /// upper layer directory is specified, changes go to a
+    /// tmpfs mounted next to the rootfs.
+    #[fehler::throws]
+    pub fn from_annotations(
+        annotations: Option<&BTreeMap<String, String>>,
+        rootfs: impl AsRef<Path>,
+    ) -> Option<Self> {
+        let annotations = match annotations {
+            Some(annotations) => annotations,
+            None => return None,
+        };
+        let layers = match annotations.get(LAYERS_ANNOTATION) {
+            Some(layers) => layers
+                .split(LAYERS_SEPARATOR)
+                .filter(|layer| !layer.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+            None => return None,
+        };
+        let upper = annotations
+            .get(UPPER_ANNOTATION)
+            .map(|path| Upper::Directory(path.into()))
+            .unwrap_or_else(|| {
+                Upper::Tmpfs(rootfs.as_ref().with_file_name("upper"))
+            });
+
+        Some(Self::new(layers, upper)?)
+    }
+
+    #[fehler::throws]
+    pub fn mount(&self, rootfs: impl AsRef<Path>) {
+        let rootfs = rootfs.as_ref();
+        let upper = self.upper.path();
+
+        fs::create_dir_all(rootfs)?;
+        fs::create_dir_all(upper)?;
+
+        if let Upper::Tmpfs(path) = &self.upper {
+            mount(&"tmpfs", &"tmpfs", path, iter::empty())?;
+        }
+
+        let (bottom, layers) = self.layers.split_first().unwrap();
+
+        tracing::info!("Mounting bottom layer {:?} -> {:?}", bottom, rootfs);
+        mount(
+            &"nullfs",
+            bottom,
+            &rootfs,
+            iter::once(&"ro" as &dyn AsRef<str>),
+        )?;
+
+        for layer in layers.iter().map(PathBuf::as_path).chain(Some(upper)) {
+            tracing::info!("Stacking layer {:?} -> {:?}", layer, rootfs);
+            mount(
+                &"unionfs",
+                &layer,
+                &rootfs,
+                iter::once(&"copymode=transparent" as &dyn AsRef<str>),
+            )?;
+        }
+    }
+
+    /// Unmounts layers in reverse order. Every mount is
+    /// attempted, the first error is reported. Layers which
+    /// are already unmounted are skipped.
+    #[fehler::throws]
+    pub fn unmount(&self, rootfs: impl AsRef<Path>) {
+        let rootfs = rootfs.as_ref();
+        let mut result = Ok(());
+
+        // Each layer, including the upper one, is mounted on
+        // rootfs
+        for _ in 0..=self.layers.len() {
+            if !is_mounted(&rootfs)? {
+                break;
+            }
+
+            result = result.and(unmount(&rootfs));
+        }
+
+        if let Upper::Tmpfs(path) = &self.upper {
+            if is_mounted(path)? {
+                result = result.and(unmount(path));
+            }
+        }
+
+        result?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn test_from_annotations() {
+        let mut annotations = BTreeMap::new();
+        annotations.insert(
+            LAYERS_ANNOTATION.into(),
+            "/layers/base:/layers/app".into(),
+        );
+
+        let rootfs = LayeredRootfs::from_annotations(
+            Some(&annotations),
+            "/bundle/rootfs",
+        )
+        .unwrap()
+        .expect("layered rootfs wasn't configured");
+
+        assert_eq!(
+            rootfs,
+            LayeredRootfs {
+                layers: vec!["/layers/base".into(), "/layers/app".into()],
+                upper: Upper::Tmpfs("/bundle/upper".into()),
+            }
+        );
+
+        assert_eq!(
+            LayeredRootfs::from_annotations(None, "/bundle/rootfs").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_layered_rootfs() {
+        let bottom = tempfile::tempdir().unwrap();
+        let top = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let rootfs = tempfile::tempdir().unwrap();
+
+        fs::write(bottom.path().join("bottom"), "bottom").unwrap();
+        fs::write(bottom.path().join("shadowed"), "bottom").unwrap();
+        fs::write(top.path().join("shadowed"), "top").unwrap();
+
+        let layered = LayeredRootfs::new(
+            vec![bottom.path().into(), top.path().into()],
+            Upper::Directory(upper.path().into()),
+        )
+        .unwrap();
+
+        layered
+            .mount(rootfs.path())
+            .expect("failed to mount layers");
+
+        fs::write(rootfs.path().join("written"), "upper").unwrap();
+
+        let shadowed = fs::read_to_string(rootfs.path().join("shadowed"));
+        let bottom_exists = rootfs.path().join("bottom").exists();
+
+        layered
+            .unmount(rootfs.path())
+            .expect("failed to unmount layers");
+
+        let mount_output = Command::new("/sbin/mount")
+            .output()
+            .expect("Failed to execute mount");
+        let output_string = String::from_utf8(mount_output.stdout).unwrap();
+
+        assert_eq!(shadowed.unwrap(), "top");
+        assert!(bottom_exists);
+        assert!(upper.path().join("written").exists());
+        assert!(!top.path().join("written").exists());
+        assert!(!output_string
+            .contains(&format!("on {} (unionfs", rootfs.path().display())));
+    }
+}
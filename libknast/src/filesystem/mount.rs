@@ -1,5 +1,8 @@
 /// Bindings around mount and umount(2) syscalls.
-use std::{convert::AsRef, io::Error as StdError, io::IoSlice, path::Path};
+use std::{
+    convert::AsRef, ffi::CStr, io::Error as StdError, io::IoSlice, mem,
+    path::Path,
+};
 
 use anyhow::{anyhow, Error};
 
@@ -72,6 +75,30 @@ pub fn unmount(destination: &dyn AsRef<Path>) {
     }
 }
 
+/// Checks whether a filesystem is mounted on
+/// `destination`.
+#[fehler::throws]
+pub fn is_mounted(destination: &dyn AsRef<Path>) -> bool {
+    if !destination.as_ref().exists() {
+        return false;
+    }
+
+    let canonical = destination.as_ref().canonicalize()?;
+    let path = (&canonical as &dyn AsRef<Path>).as_bytes()?;
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+
+    if unsafe { libc::statfs(path.as_ptr() as _, &mut stat) } < 0 {
+        fehler::throw!(anyhow!(
+            "mount: statfs failed: {}",
+            StdError::last_os_error()
+        ))
+    }
+
+    let mountpoint = unsafe { CStr::from_ptr(stat.f_mntonname.as_ptr()) };
+
+    mountpoint.to_bytes_with_nul() == path.as_slice()
+}
+
 trait AsBytes {
     #[fehler::throws]
     fn as_bytes(&self) -> Vec<u8>;
@@ -114,7 +141,10 @@ mod tests {
             source.path().display(),
             dest.path().display()
         )));
+        assert!(is_mounted(&dest.path()).unwrap());
 
         unmount(&dest.path()).expect("failed to unmount nullfs");
+
+        assert!(!is_mounted(&dest.path()).unwrap());
     }
 }
@@ -1,2 +1,10 @@
+pub mod error;
 pub mod filesystem;
+pub mod linux;
+pub mod logging;
+pub mod namespace;
+pub mod nonblocking;
 pub mod operations;
+pub mod procdesc;
+pub mod seccomp;
+pub mod zfs;
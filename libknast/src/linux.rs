@@ -0,0 +1,202 @@
+/// Linux binary compatibility for containers of Linux images.
+///
+/// FreeBSD runs Linux binaries via the Linuxulator, which
+/// is provided by kernel modules. Linux images also expect
+/// emulated `/proc`, `/sys` and `/dev/fd`. The container is
+/// considered a Linux one when the image's OS, recorded in
+/// `org.freebsd.knast.image.os` annotation, is `linux`.
+/// Bundles lacking the annotation are detected by their
+/// emulated filesystems.
+///
+/// The kernel release reported to the container defaults to
+/// the host's `compat.linux.osrelease` and is overridden via
+/// `org.freebsd.knast.linux.osrelease` annotation.
+use std::process::Command;
+
+use anyhow::{anyhow, Error};
+use baustelle::runtime_config::{
+    generate_mounts, RuntimeConfig, OS_ANNOTATION,
+};
+use jail::param::Value;
+
+pub const OSRELEASE_ANNOTATION: &str = "org.freebsd.knast.linux.osrelease";
+const KLDLOAD_BINARY: &str = "/sbin/kldload";
+const SYSCTL_BINARY: &str = "/sbin/sysctl";
+const OSRELEASE_SYSCTL: &str = "compat.linux.osrelease";
+const EMULATED_FILESYSTEMS: [&str; 2] = ["linprocfs", "linsysfs"];
+
+#[cfg(target_pointer_width = "64")]
+const ABI_MODULE: &str = "linux64";
+#[cfg(not(target_pointer_width = "64"))]
+const ABI_MODULE: &str = "linux";
+
+/// Kernel modules the emulation needs.
+const MODULES: [&str; 4] = [ABI_MODULE, "linprocfs", "linsysfs", "fdescfs"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinuxEmulation {
+    osrelease: Option<String>,
+}
+
+impl LinuxEmulation {
+    /// Emulation the container needs, if any.
+    pub fn from_config(config: &RuntimeConfig) -> Option<Self> {
+        let annotations = config.annotations.as_ref();
+        let os = annotations.and_then(|map| map.get(OS_ANNOTATION));
+        let is_linux = match os {
+            Some(os) => os == "linux",
+            None => config.mounts.iter().flatten().any(|mount| {
+                EMULATED_FILESYSTEMS.contains(&mount.r#type.as_str())
+            }),
+        };
+
+        if !is_linux {
+            return None;
+        }
+
+        Some(Self {
+            osrelease: annotations
+                .and_then(|map| map.get(OSRELEASE_ANNOTATION))
+                .cloned(),
+        })
+    }
+
+    /// Loads the kernel modules, unless they're loaded
+    /// already.
+    #[fehler::throws]
+    pub fn load(&self) {
+        for module in &MODULES {
+            let output = Command::new(KLDLOAD_BINARY)
+                .args(&["-n", module])
+                .output()?;
+
+            if !output.status.success() {
+                fehler::throw!(anyhow!(
+                    "Linux emulation is unavailable: failed to load {} \
+                     kernel module: {}. Consider setting \
+                     linux_enable=\"YES\" in rc.conf(5)",
+                    module,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+        }
+    }
+
+    /// Adds emulated filesystems, which the container
+    /// lacks. Mounts configured in the bundle are kept.
+    pub fn add_mounts(&self, config: &mut RuntimeConfig) {
+        let mounts = config.mounts.get_or_insert_with(Vec::new);
+
+        for mount in generate_mounts("linux".into()) {
+            let configured = mounts
+                .iter()
+                .any(|existing| existing.destination == mount.destination);
+
+            if !configured {
+                mounts.push(mount);
+            }
+        }
+    }
+
+    /// Jail parameters of the emulation.
+    #[fehler::throws]
+    pub fn jail_params(&self) -> Vec<(&'static str, Value)> {
+        let osrelease = match &self.osrelease {
+            Some(osrelease) => osrelease.clone(),
+            None => host_osrelease()?,
+        };
+
+        vec![
+            ("linux.osname", Value::String("Linux".into())),
+            ("linux.osrelease", Value::String(osrelease)),
+        ]
+    }
+}
+
+#[fehler::throws]
+fn host_osrelease() -> String {
+    let output = Command::new(SYSCTL_BINARY)
+        .args(&["-n", OSRELEASE_SYSCTL])
+        .output()?;
+
+    if !output.status.success() {
+        fehler::throw!(anyhow!(
+            "Linux emulation is unavailable: {} is not set",
+            OSRELEASE_SYSCTL
+        ));
+    }
+
+    String::from_utf8(output.stdout)?.trim().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn config(mounts: Vec<&str>, os: Option<&str>) -> RuntimeConfig {
+        let mounts = generate_mounts("linux".into())
+            .into_iter()
+            .filter(|mount| mounts.contains(&mount.r#type.as_str()))
+            .collect();
+        let annotations: BTreeMap<_, _> = os
+            .map(|os| (OS_ANNOTATION.to_string(), os.to_string()))
+            .into_iter()
+            .collect();
+
+        RuntimeConfig {
+            oci_version: "1.0".into(),
+            root: None,
+            mounts: Some(mounts),
+            process: None,
+            hooks: None,
+            annotations: Some(annotations),
+            linux: None,
+            freebsd: None,
+        }
+    }
+
+    #[test]
+    fn test_from_config() {
+        let detect =
+            |mounts, os| LinuxEmulation::from_config(&config(mounts, os));
+
+        assert!(detect(vec!["devfs"], Some("linux")).is_some());
+        assert!(detect(vec!["devfs", "linprocfs"], None).is_some());
+        assert!(detect(vec!["devfs", "fdescfs"], None).is_none());
+        assert!(detect(vec!["linprocfs"], Some("freebsd")).is_none());
+
+        let mut config = config(vec![], Some("linux"));
+
+        config
+            .annotations
+            .as_mut()
+            .unwrap()
+            .insert(OSRELEASE_ANNOTATION.into(), "5.15.0".into());
+
+        let emulation = LinuxEmulation::from_config(&config).unwrap();
+        let params = emulation.jail_params().unwrap();
+
+        assert_eq!(params[1].1, Value::String("5.15.0".into()));
+    }
+
+    #[test]
+    fn test_add_mounts() {
+        let mut config = config(vec!["devfs", "linprocfs"], Some("linux"));
+
+        config.mounts.as_mut().unwrap()[1].options = None;
+        LinuxEmulation::from_config(&config)
+            .unwrap()
+            .add_mounts(&mut config);
+
+        let mounts = config.mounts.unwrap();
+        let destinations: Vec<_> = mounts
+            .iter()
+            .map(|mount| mount.destination.as_str())
+            .collect();
+
+        assert_eq!(destinations, vec!["/dev", "/proc", "/sys", "/dev/fd"]);
+        // Configured mounts are kept intact
+        assert_eq!(mounts[1].options, None);
+    }
+}
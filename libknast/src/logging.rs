@@ -0,0 +1,203 @@
+/// Logging setup shared by knast binaries.
+///
+/// Configured via environment:
+///
+/// * `KNAST_LOG` — `tracing_subscriber::EnvFilter`
+///   directives, i.e. `info,libknast::operations=debug`;
+/// * `KNAST_LOG_FORMAT` — `text` (default) or `json`;
+/// * `KNAST_LOG_PATH` — log file, `-` stands for stderr;
+/// * `KNAST_LOG_ROTATION` — `never` (default), `daily`,
+///   `hourly` or `minutely`.
+use std::{
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Error};
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::EnvFilter;
+
+const FILTER_VARIABLE: &str = "KNAST_LOG";
+const FORMAT_VARIABLE: &str = "KNAST_LOG_FORMAT";
+const PATH_VARIABLE: &str = "KNAST_LOG_PATH";
+const ROTATION_VARIABLE: &str = "KNAST_LOG_ROTATION";
+const DEFAULT_FILTER: &str = "info";
+const STDERR_PATH: &str = "-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    #[fehler::throws]
+    fn from_str(format: &str) -> Self {
+        match format {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            format => fehler::throw!(anyhow!(
+                "{}: unknown log format {:?}",
+                FORMAT_VARIABLE,
+                format
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Never,
+    Daily,
+    Hourly,
+    Minutely,
+}
+
+impl FromStr for Rotation {
+    type Err = Error;
+
+    #[fehler::throws]
+    fn from_str(rotation: &str) -> Self {
+        match rotation {
+            "never" => Rotation::Never,
+            "daily" => Rotation::Daily,
+            "hourly" => Rotation::Hourly,
+            "minutely" => Rotation::Minutely,
+            rotation => fehler::throw!(anyhow!(
+                "{}: unknown log rotation {:?}",
+                ROTATION_VARIABLE,
+                rotation
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub filter: String,
+    pub format: Format,
+    /// Log file, stderr if not set.
+    pub path: Option<PathBuf>,
+    pub rotation: Rotation,
+}
+
+impl LogConfig {
+    /// Reads the config from environment. `default_path` is
+    /// used unless `KNAST_LOG_PATH` is set.
+    #[fehler::throws]
+    pub fn from_env(default_path: Option<&Path>) -> Self {
+        Self::from_variables(default_path, |name| env::var(name).ok())?
+    }
+
+    #[fehler::throws]
+    fn from_variables(
+        default_path: Option<&Path>,
+        variable: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let path = match variable(PATH_VARIABLE) {
+            Some(path) if path == STDERR_PATH => None,
+            Some(path) => Some(path.into()),
+            None => default_path.map(Path::to_path_buf),
+        };
+
+        Self {
+            filter: variable(FILTER_VARIABLE)
+                .unwrap_or_else(|| DEFAULT_FILTER.into()),
+            format: variable(FORMAT_VARIABLE)
+                .map(|format| format.parse())
+                .transpose()?
+                .unwrap_or(Format::Text),
+            path,
+            rotation: variable(ROTATION_VARIABLE)
+                .map(|rotation| rotation.parse())
+                .transpose()?
+                .unwrap_or(Rotation::Never),
+        }
+    }
+}
+
+/// Installs the global subscriber. Logs are written until
+/// the returned guard is dropped.
+#[fehler::throws]
+pub fn init(config: &LogConfig) -> WorkerGuard {
+    let filter = EnvFilter::try_new(&config.filter)
+        .map_err(|error| anyhow!("{}: {}", FILTER_VARIABLE, error))?;
+    let (writer, guard) = match &config.path {
+        Some(path) => {
+            let directory = path.parent().unwrap_or_else(|| Path::new("/"));
+            let file = path
+                .file_name()
+                .ok_or_else(|| anyhow!("{}: not a file", PATH_VARIABLE))?;
+            let appender = match config.rotation {
+                Rotation::Never => rolling::never(directory, file),
+                Rotation::Daily => rolling::daily(directory, file),
+                Rotation::Hourly => rolling::hourly(directory, file),
+                Rotation::Minutely => rolling::minutely(directory, file),
+            };
+
+            tracing_appender::non_blocking(appender)
+        }
+        None => tracing_appender::non_blocking(std::io::stderr()),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    let result = match config.format {
+        Format::Text => builder.try_init(),
+        Format::Json => builder.json().try_init(),
+    };
+
+    result.map_err(|error| anyhow!("Failed to set up logging: {}", error))?;
+
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_log_config() {
+        let mut variables = HashMap::new();
+        let default_path = Path::new("/var/log/knast.log");
+        let config = |variables: &HashMap<&str, &str>| {
+            LogConfig::from_variables(Some(default_path), |name| {
+                variables.get(name).map(|value| value.to_string())
+            })
+        };
+
+        assert_eq!(
+            config(&variables).unwrap(),
+            LogConfig {
+                filter: "info".into(),
+                format: Format::Text,
+                path: Some(default_path.into()),
+                rotation: Rotation::Never,
+            }
+        );
+
+        variables.insert(FILTER_VARIABLE, "warn,libknast=debug");
+        variables.insert(FORMAT_VARIABLE, "json");
+        variables.insert(PATH_VARIABLE, "-");
+        variables.insert(ROTATION_VARIABLE, "daily");
+
+        assert_eq!(
+            config(&variables).unwrap(),
+            LogConfig {
+                filter: "warn,libknast=debug".into(),
+                format: Format::Json,
+                path: None,
+                rotation: Rotation::Daily,
+            }
+        );
+
+        variables.insert(FORMAT_VARIABLE, "xml");
+
+        assert!(config(&variables).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+/// containerd namespaces, i.e. `moby` or `k8s.io`.
+///
+/// Ids are unique within a namespace only, so containers of
+/// namespaces are keyed by `namespace:id`. Jails are named
+/// by a hash of the key, see `operations::jails`. containerd
+/// allows `:` neither in namespaces, nor in ids.
+const SEPARATOR: char = ':';
+
+/// Key of the container `id` of the `namespace`. Containers
+/// of no namespace, i.e. created by `runc`, are keyed by id.
+pub fn key(namespace: &str, id: &str) -> String {
+    if namespace.is_empty() {
+        id.into()
+    } else {
+        format!("{}{}{}", namespace, SEPARATOR, id)
+    }
+}
+
+/// Namespace, if any, and id of the container.
+pub fn split(key: &str) -> (Option<&str>, &str) {
+    match key.find(SEPARATOR) {
+        Some(index) => (Some(&key[..index]), &key[index + 1..]),
+        None => (None, key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        assert_eq!(key("k8s.io", "nginx"), "k8s.io:nginx");
+        assert_eq!(key("", "nginx"), "nginx");
+        assert_eq!(split("k8s.io:nginx"), (Some("k8s.io"), "nginx"));
+        assert_eq!(split("nginx"), (None, "nginx"));
+    }
+}
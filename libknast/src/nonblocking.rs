@@ -0,0 +1,485 @@
+/// Non-blocking facade of [`OciOperations`] for tokio-based
+/// consumers, i.e. a daemon managing dozens of containers.
+///
+/// Storage access, forks and mounts run on tokio's blocking
+/// pool. Waits don't occupy a thread: the [`Reaper`] task
+/// reaps the waited for processes as they exit, see
+/// [`procdesc::on_exit`], and notifies the waiters.
+/// Processes, which survived a restart of the consumer,
+/// aren't its children: their exits are reported by kqueue
+/// once they are adopted, see [`Reaper::adopt`].
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    os::unix::io::RawFd,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Error;
+use futures::stream::Stream;
+use nix::{
+    errno::Errno,
+    sys::{
+        event::{
+            kevent, kevent_ts, kqueue, EventFilter, EventFlag, FilterFlag,
+            KEvent,
+        },
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::Pid,
+};
+use storage::{Storage, StorageEngine};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+    task,
+};
+
+use crate::{
+    error::KnastError,
+    operations::{
+        record_exit, record_lost, subscribe, Annotations, ContainerEvent,
+        OciOperations, OciStatus, OutputCapture, ProcessOverrides,
+        ProcessStatus, MAIN_PROCESS_EXEC_ID,
+    },
+    procdesc,
+};
+
+type Waiters = Arc<Mutex<BTreeMap<i32, Vec<oneshot::Sender<()>>>>>;
+
+/// Reaps processes, which are waited for, and records their
+/// exit statuses.
+pub struct Reaper<T: StorageEngine> {
+    storage: Arc<Storage<T>>,
+    waiters: Waiters,
+    /// Pids, which kqueue reports the exits of.
+    adopted: Arc<Mutex<BTreeSet<i32>>>,
+    /// kqueue of the adopted processes.
+    kqueue: RawFd,
+}
+
+impl<T: StorageEngine> Clone for Reaper<T> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            waiters: self.waiters.clone(),
+            adopted: self.adopted.clone(),
+            kqueue: self.kqueue,
+        }
+    }
+}
+
+impl<T: StorageEngine + Send + Sync + 'static> Reaper<T> {
+    /// Spawns the reaper task on the current runtime.
+    pub fn spawn(storage: Arc<Storage<T>>) -> Result<Self, Error> {
+        let mut sigchld = signal(SignalKind::child())?;
+        let (sender, mut exits) = mpsc::unbounded_channel();
+        let reaper = Self {
+            storage,
+            waiters: Arc::default(),
+            adopted: Arc::default(),
+            kqueue: kqueue()?,
+        };
+        let task_reaper = reaper.clone();
+        let adopted_reaper = reaper.clone();
+
+        // A single thread, which blocks in kevent, watches all
+        // the adopted processes
+        thread::Builder::new()
+            .name("knast-adopted".into())
+            .spawn(move || adopted_reaper.reap_adopted())?;
+
+        procdesc::on_exit(move |_| {
+            let _ = sender.send(());
+        });
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(_) = sigchld.recv() => (),
+                    Some(_) = exits.recv() => (),
+                    else => break,
+                }
+
+                task_reaper.reap().await;
+            }
+        });
+
+        Ok(reaper)
+    }
+
+    /// Resolves once `pid` exits. Its status is recorded
+    /// then, see [`crate::operations::take_exit`].
+    pub async fn exited(&self, pid: i32) {
+        let (sender, receiver) = oneshot::channel();
+
+        if let Ok(mut waiters) = self.waiters.lock() {
+            waiters.entry(pid).or_insert_with(Vec::new).push(sender);
+        }
+
+        // The process might have exited already
+        self.reap().await;
+
+        // Sender is dropped along with the runtime only
+        let _ = receiver.await;
+    }
+
+    /// Records the exit status of `pid`, which isn't a child
+    /// of the process, once it exits. Waiters are notified
+    /// then, see [`Self::exited`].
+    pub fn adopt(&self, pid: i32) -> Result<(), Error> {
+        if let Ok(mut adopted) = self.adopted.lock() {
+            adopted.insert(pid);
+        }
+
+        let event = KEvent::new(
+            pid as _,
+            EventFilter::EVFILT_PROC,
+            EventFlag::EV_ADD | EventFlag::EV_ONESHOT,
+            FilterFlag::NOTE_EXIT,
+            0,
+            0,
+        );
+
+        match kevent(self.kqueue, &[event], &mut [], 0) {
+            Ok(_) => Ok(()),
+            // Gone already
+            Err(nix::Error::Sys(Errno::ESRCH)) => {
+                let result = record_lost(&self.storage, pid);
+
+                self.release(pid);
+                result
+            }
+            Err(error) => {
+                self.release(pid);
+                Err(error.into())
+            }
+        }
+    }
+
+    fn reap_adopted(&self) {
+        let empty = KEvent::new(
+            0,
+            EventFilter::EVFILT_PROC,
+            EventFlag::empty(),
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+        let mut events = [empty; 16];
+
+        loop {
+            let count = match kevent_ts(self.kqueue, &[], &mut events, None) {
+                Ok(count) => count,
+                Err(error) => {
+                    tracing::error!("Failed to watch adopted: {}", error);
+                    continue;
+                }
+            };
+
+            for event in &events[..count] {
+                let pid = Pid::from_raw(event.ident() as _);
+                let result = WaitStatus::from_raw(pid, event.data() as _)
+                    .map_err(Error::from)
+                    .and_then(|status| record_exit(&self.storage, status));
+
+                if let Err(err) = result {
+                    tracing::error!(
+                        "Failed to record exit of {}: {}",
+                        pid,
+                        err
+                    );
+                }
+
+                self.release(pid.as_raw());
+            }
+        }
+    }
+
+    /// Stops watching the adopted `pid`, notifying its
+    /// waiters. The exit is recorded by then.
+    fn release(&self, pid: i32) {
+        if let Ok(mut adopted) = self.adopted.lock() {
+            adopted.remove(&pid);
+        }
+
+        let senders = match self.waiters.lock() {
+            Ok(mut waiters) => waiters.remove(&pid).unwrap_or_else(Vec::new),
+            Err(_) => return,
+        };
+
+        for sender in senders {
+            let _ = sender.send(());
+        }
+    }
+
+    async fn reap(&self) {
+        let reaper = self.clone();
+        let result = task::spawn_blocking(move || reaper.do_reap()).await;
+
+        if let Err(error) = result {
+            tracing::error!("Reaper failed: {}", error);
+        }
+    }
+
+    fn do_reap(&self) {
+        let mut waiters = match self.waiters.lock() {
+            Ok(waiters) => waiters,
+            Err(_) => return,
+        };
+        let adopted = match self.adopted.lock() {
+            Ok(adopted) => adopted.clone(),
+            Err(_) => return,
+        };
+        // Adopted pids aren't children, kqueue reports them
+        let pids: Vec<_> = waiters
+            .keys()
+            .filter(|pid| !adopted.contains(pid))
+            .cloned()
+            .collect();
+
+        for pid in pids {
+            match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => continue,
+                Ok(status) => {
+                    procdesc::release(pid);
+
+                    if let Err(err) = record_exit(&self.storage, status) {
+                        tracing::error!(
+                            "Failed to record {:?}: {}",
+                            status,
+                            err
+                        );
+                    }
+                }
+                // Already waited for by someone else
+                Err(_) => (),
+            }
+
+            for sender in waiters.remove(&pid).unwrap_or_else(Vec::new) {
+                let _ = sender.send(());
+            }
+        }
+    }
+}
+
+/// [`OciOperations`] on the container `key`, see the
+/// module's docs.
+pub struct AsyncOperations<T: StorageEngine> {
+    reaper: Reaper<T>,
+    key: String,
+}
+
+impl<T: StorageEngine + Send + Sync + 'static> AsyncOperations<T> {
+    pub fn new(reaper: &Reaper<T>, key: impl AsRef<str>) -> Self {
+        Self {
+            reaper: reaper.clone(),
+            key: key.as_ref().into(),
+        }
+    }
+
+    /// See [`OciOperations::create`].
+    pub async fn create(
+        &self,
+        bundle: impl Into<PathBuf>,
+        nat_interface: Option<String>,
+    ) -> Result<(), KnastError> {
+        let bundle = bundle.into();
+
+        self.blocking(move |ops| ops.create(bundle, nat_interface))
+            .await
+    }
+
+    /// See [`OciOperations::start`].
+    pub async fn start(
+        &self,
+        overrides: ProcessOverrides,
+    ) -> Result<(), KnastError> {
+        self.blocking(move |ops| ops.start(overrides)).await
+    }
+
+    /// See [`OciOperations::kill`].
+    pub async fn kill(&self, signal: i32) -> Result<(), KnastError> {
+        self.blocking(move |ops| ops.kill(signal)).await
+    }
+
+    /// See [`OciOperations::stop`].
+    pub async fn stop(&self, timeout: Duration) -> Result<(), KnastError> {
+        self.blocking(move |ops| ops.stop(timeout)).await
+    }
+
+    /// See [`OciOperations::state`].
+    pub async fn state(&self) -> Result<OciStatus, KnastError> {
+        self.blocking(|ops| ops.state()).await
+    }
+
+    /// See [`OciOperations::delete`].
+    pub async fn delete(&self) -> Result<(), KnastError> {
+        self.blocking(|ops| {
+            ops.delete();
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Waits for the main process to exit, restarting it as
+    /// long as the container's restart policy says so, see
+    /// [`OciOperations::wait`].
+    pub async fn wait(&self) -> Result<OciStatus, KnastError> {
+        self.wait_restarting(None).await
+    }
+
+    /// See [`OciOperations::wait_captured`].
+    pub async fn wait_captured(
+        &self,
+        capture: OutputCapture,
+    ) -> Result<OciStatus, KnastError> {
+        self.wait_restarting(Some(capture)).await
+    }
+
+    async fn wait_restarting(
+        &self,
+        capture: Option<OutputCapture>,
+    ) -> Result<OciStatus, KnastError> {
+        let policy = self
+            .blocking(|ops| Ok(Annotations::parse(&ops.config()?)?.restart))
+            .await?;
+        let mut restarts = 0;
+
+        loop {
+            let state = self.wait_process(MAIN_PROCESS_EXEC_ID).await?;
+
+            if !policy.restarts(state.exit_status, restarts) {
+                return Ok(state);
+            }
+
+            restarts += 1;
+            tracing::info!("Restarting the process, attempt {}", restarts);
+            let capture = capture.clone();
+
+            self.blocking(move |ops| ops.restart(capture)).await?;
+        }
+    }
+
+    /// Waits for the process to exit, without occupying a
+    /// thread meanwhile. Returns the state of the stopped
+    /// process.
+    pub async fn wait_process(
+        &self,
+        exec_id: &str,
+    ) -> Result<OciStatus, KnastError> {
+        let exec_id = exec_id.to_owned();
+        let state = {
+            let exec_id = exec_id.clone();
+
+            self.blocking(move |ops| ops.get_state(&exec_id)).await?
+        };
+
+        if state.status != ProcessStatus::Running {
+            return Ok(state);
+        }
+
+        self.reaper.exited(state.pid).await;
+
+        self.blocking(move |ops| {
+            ops.do_wait(&exec_id)?;
+            ops.get_state(&exec_id)
+        })
+        .await
+    }
+
+    /// Lifecycle events of the container, emitted after the
+    /// call, see [`subscribe`].
+    pub fn events(
+        &self,
+    ) -> Result<
+        impl Stream<Item = Result<ContainerEvent, Error>> + Unpin,
+        KnastError,
+    > {
+        subscribe(&self.reaper.storage, &self.key)
+            .map_err(KnastError::StorageError)
+    }
+
+    /// Runs `f` on the blocking pool.
+    async fn blocking<R, F>(&self, f: F) -> Result<R, KnastError>
+    where
+        R: Send + 'static,
+        F: FnOnce(OciOperations<'_, T>) -> Result<R, KnastError>
+            + Send
+            + 'static,
+    {
+        let storage = self.reaper.storage.clone();
+        let key = self.key.clone();
+
+        task::spawn_blocking(move || f(OciOperations::new(&storage, key)?))
+            .await
+            .map_err(|error| KnastError::Other(error.into()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use storage::TestStorage;
+
+    use super::*;
+    use crate::operations::take_exit;
+
+    #[test]
+    fn test_reaper() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage: Arc<TestStorage> =
+            Arc::new(TestStorage::new(tmpdir.path()).unwrap());
+        let pid = runtime.block_on(async {
+            let reaper = Reaper::spawn(storage.clone()).unwrap();
+            let child = Command::new("sh")
+                .args(&["-c", "sleep 0.1; exit 3"])
+                .spawn()
+                .unwrap();
+            let pid = child.id() as i32;
+
+            reaper.exited(pid).await;
+
+            pid
+        });
+        let status = take_exit(&storage, pid).unwrap();
+
+        assert_eq!(status.map(|status| status.code), Some(Some(3)));
+    }
+
+    #[test]
+    fn test_adopt() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage: Arc<TestStorage> =
+            Arc::new(TestStorage::new(tmpdir.path()).unwrap());
+        let mut child = Command::new("sh")
+            .args(&["-c", "sleep 0.1; exit 4"])
+            .spawn()
+            .unwrap();
+        let pid = child.id() as i32;
+
+        runtime.block_on(async {
+            let reaper = Reaper::spawn(storage.clone()).unwrap();
+
+            // Reported by kqueue, rather than waited for
+            reaper.adopt(pid).unwrap();
+            reaper.exited(pid).await;
+        });
+
+        let status = take_exit(&storage, pid).unwrap();
+
+        assert_eq!(status.map(|status| status.code), Some(Some(4)));
+        child.wait().unwrap();
+    }
+}
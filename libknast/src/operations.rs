@@ -1,34 +1,101 @@
+mod annotations;
 mod command_ext;
-mod network;
+mod events;
+mod exits;
+pub mod health;
+pub mod init;
+mod jails;
+mod locale;
+mod migrations;
+pub mod network;
+mod output;
+mod privileges;
 mod utils;
 
 use std::{
-    convert::AsRef,
-    fs::File,
-    io::{BufReader, Error as IoError},
-    path::Path,
+    collections::BTreeMap,
+    convert::{AsRef, TryFrom},
+    fmt::Display,
+    fs::{self, DirBuilder, File},
+    io::{BufReader, Error as IoError, ErrorKind},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
     process::Command,
-    time::{SystemTime, UNIX_EPOCH},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::filesystem::{prefixed_destination, Mountable};
-use anyhow::{anyhow, Error};
-pub use baustelle::runtime_config::{Process, Root, RuntimeConfig};
+use crate::{
+    error::KnastError,
+    filesystem::{
+        expose_devices, hide_devices, passthrough_devices,
+        prefixed_destination, validate_devices, verify_devices_hidden,
+        FilesystemKind, LayeredRootfs, Mountable,
+    },
+    linux::LinuxEmulation,
+    procdesc, seccomp,
+    zfs::{ContainerClone, Dataset},
+};
+use anyhow::{anyhow, Context, Error};
+use baustelle::runtime_config::{user, InvalidConfig};
+pub use baustelle::runtime_config::{
+    Allow, ConsoleSize, Device, FreeBSD, LinuxDevice, Process, Root,
+    RuntimeConfig, Seccomp, SysvMode,
+};
+use common_lib::{env, metrics};
 use jail::{param::Value, process::Jailed};
 use jail::{RunningJail, StoppedJail};
-use nix::{
-    sys::wait::{waitpid, WaitStatus},
-    unistd::Pid,
-};
+use nix::{errno::Errno, sys::signal::Signal};
 use serde::{Deserialize, Serialize};
-use storage::{Storage, StorageEngine};
+use storage::{
+    audit::{self, Action},
+    Collection, Storage, StorageEngine,
+};
 
+pub use annotations::{
+    Annotations, NetworkMode, PortMapping, Protocol, RestartPolicy,
+};
+use annotations::{INIT_ANNOTATION, NETWORK_ANNOTATION};
 use command_ext::CommandExt;
-
-const CONTAINER_CONFIG_STORAGE_KEY: &[u8] = b"CONTAINER_CONFIG";
-const CONTAINER_PROCESSES_STORAGE_KEY: &[u8] = b"CONTAINER_PROCESSES";
+pub use events::{subscribe, ContainerEvent, EventKind};
+pub use exits::{record_exit, record_lost, take_exit, ExitStatus};
+pub use health::{HealthCheck, HealthStatus};
+use init::Init;
+pub use migrations::migrate;
+pub use output::{CapturedOutput, OutputCapture, OutputStream};
+pub use privileges::Privileges;
+use utils::Errors;
+
+const CONTAINER_CONFIGS: Collection<String, RuntimeConfig> =
+    Collection::new(b"CONTAINER_CONFIG");
+/// Keyed by container and exec id.
+const CONTAINER_PROCESSES: Collection<(String, String), OciStatus> =
+    Collection::json(b"CONTAINER_PROCESSES");
+const CONTAINER_DEVICES: Collection<String, Vec<Device>> =
+    Collection::new(b"CONTAINER_DEVICES");
+/// Overrides the main process was started with, reapplied
+/// on restarts.
+const CONTAINER_OVERRIDES: Collection<String, ProcessOverrides> =
+    Collection::new(b"CONTAINER_OVERRIDES");
+/// ZFS datasets cloned for the containers, the only ones
+/// destroyed along with them.
+const CONTAINER_DATASETS: Collection<String, String> =
+    Collection::new(b"CONTAINER_DATASETS");
 const OCI_VERSION: &str = "1.0.2-dev-freebsd";
-const MAIN_PROCESS_EXEC_ID: &str = "";
+/// Directory of the containers' scratch data, i.e. lock
+/// files, see [`OciOperations::state_dir`].
+pub const STATE_ROOT_VARIABLE: &str = "KNAST_STATE_ROOT";
+const DEFAULT_STATE_ROOT: &str = "/var/run/knast";
+/// Prefix of the generated exec ids, see
+/// [`OciOperations::new_exec_id`].
+const EXEC_ID_PREFIX: &str = "exec-";
+/// Exec id of the main process of the container.
+pub const MAIN_PROCESS_EXEC_ID: &str = "";
+const STOP_SIGNAL_ANNOTATION: &str = "org.opencontainers.image.stopSignal";
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const TERMINATION_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a reaped process' status may take to show up.
+const REAPED_STATUS_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(
     Deserialize,
@@ -54,48 +121,157 @@ pub struct OciStatus {
     pub status: ProcessStatus,
     pub pid: i32,
     pub jid: i32,
+    /// Exit code of the process, 128 plus the signal number,
+    /// if a signal killed it.
     pub exit_status: Option<i32>,
     pub exited_at: SystemTime,
+    /// Signal, which killed the process, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub core_dumped: bool,
+    /// Annotations of the container, given in its state only.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// Health of the container, given in its state only, if
+    /// it's checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthStatus>,
+}
+
+/// Overrides of the configured process, given at start or exec
+/// time.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct ProcessOverrides {
+    /// `NAME=value` entries, added to the environment. Entries
+    /// replace configured variables of the same name.
+    pub env: Vec<String>,
+    /// Replacement of the command and its arguments.
+    pub args: Option<Vec<String>>,
+    /// Replacement of the working directory.
+    pub cwd: Option<String>,
+}
+
+impl ProcessOverrides {
+    #[fehler::throws(KnastError)]
+    pub fn apply(self, process: &mut Process) {
+        let overridden = self
+            .env
+            .iter()
+            .map(|entry| Ok(env::split(entry)?.0))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut envs = process.env.take().unwrap_or_else(Vec::new);
+
+        envs.retain(|entry| match env::split(entry) {
+            Ok((name, _)) => !overridden.contains(&name),
+            // Reported once the process is started
+            Err(_) => true,
+        });
+        envs.extend(self.env.iter().cloned());
+        process.env = Some(envs);
+
+        if let Some(args) = self.args {
+            process.args = Some(args);
+        }
+        if let Some(cwd) = self.cwd {
+            process.cwd = cwd;
+        }
+    }
 }
 
 pub struct OciOperations<'a, T: StorageEngine> {
     storage: &'a Storage<T>,
     key: String,
+    state_root: PathBuf,
+    init: bool,
 }
 
 impl<'a, T: StorageEngine> OciOperations<'a, T> {
-    #[fehler::throws]
+    #[fehler::throws(KnastError)]
     pub fn new(storage: &'a Storage<T>, key: impl AsRef<str>) -> Self {
+        let state_root = std::env::var_os(STATE_ROOT_VARIABLE)
+            .map_or_else(|| DEFAULT_STATE_ROOT.into(), PathBuf::from);
+
         Self {
             storage,
             key: key.as_ref().into(),
+            state_root,
+            init: false,
         }
     }
 
+    /// Keeps the scratch data under `state_root` instead of
+    /// [`STATE_ROOT_VARIABLE`] or `/var/run/knast`.
+    pub fn with_state_root(self, state_root: impl Into<PathBuf>) -> Self {
+        Self {
+            state_root: state_root.into(),
+            ..self
+        }
+    }
+
+    /// Runs the main process of the created container under
+    /// knast's init, as if `org.freebsd.knast.init` was set.
+    pub fn with_init(self, init: bool) -> Self {
+        Self { init, ..self }
+    }
+
+    /// Directory of the container's scratch data, i.e.
+    /// generated files, which don't belong to the storage.
+    /// Exists from creation until deletion of the container.
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_root.join(&self.key)
+    }
+
     /// Creates a container according to runtime
     /// configuration in bundle. Fails if container
     /// already exists, or configuration is invalid.
     /// Locks the configuration by creating a copy of
     /// configuration in the storage.
-    #[fehler::throws]
+    #[fehler::throws(KnastError)]
     pub fn create(
         self,
         path: impl AsRef<Path>,
         nat_interface: Option<impl AsRef<str>>,
     ) {
+        let result = self.do_create(path, nat_interface);
+
+        self.audit(Action::ContainerCreate, &result);
+
+        result?
+    }
+
+    #[fehler::throws(KnastError)]
+    fn do_create(
+        &self,
+        path: impl AsRef<Path>,
+        nat_interface: Option<impl AsRef<str>>,
+    ) {
+        let _timer = operation_timer("create");
+        let privileges = Privileges::detect();
+
+        privileges.require_root("Creating a container")?;
+
         if self.get_process(MAIN_PROCESS_EXEC_ID).is_ok() {
-            anyhow::bail!("Container '{}' already exists!", self.key);
+            fehler::throw!(KnastError::ContainerExists(self.key.clone()));
+        }
+
+        let mut config = validate(&path)?;
+
+        if self.init {
+            config
+                .annotations
+                .get_or_insert_with(BTreeMap::new)
+                .insert(INIT_ANNOTATION.into(), "true".into());
         }
 
-        let config_file = File::open(path.as_ref().join("config.json"))?;
-        let reader = BufReader::new(config_file);
-        let mut config: RuntimeConfig = serde_json::from_reader(reader)?;
         let rootfs_path = config
             .root
             .as_ref()
             .map(|root| path.as_ref().join(root.path.clone()))
             .ok_or_else(|| {
-                anyhow!("Runtime config: root field must be set")
+                KnastError::ConfigInvalid(anyhow!(
+                    "Runtime config: root field must be set"
+                ))
             })?;
 
         config.root = Some(Root {
@@ -103,65 +279,271 @@ impl<'a, T: StorageEngine> OciOperations<'a, T> {
             readonly: None,
         });
 
-        self.storage.put(
-            CONTAINER_CONFIG_STORAGE_KEY,
-            self.key.as_bytes(),
-            config,
-        )?;
+        let linux = LinuxEmulation::from_config(&config);
+
+        if let Some(linux) = &linux {
+            linux.load()?;
+            linux.add_mounts(&mut config);
+        }
+
+        let mut annotations = Annotations::parse(&config)?;
+        let network_given = config
+            .annotations
+            .as_ref()
+            .map_or(false, |given| given.contains_key(NETWORK_ANNOTATION));
+        // Bridge-only annotations ask for the bridge as well
+        let explicit = network_given
+            || annotations.address.is_some()
+            || !annotations.ports.is_empty();
+
+        annotations.network =
+            privileges.network(annotations.network, explicit)?;
+
+        if annotations.init {
+            let init = init::path()?;
+
+            if !init.is_file() {
+                fehler::throw!(KnastError::ConfigInvalid(anyhow!(
+                    "Runtime config: {} requires init at {:?}",
+                    INIT_ANNOTATION,
+                    init
+                )));
+            }
+        }
+
+        if privileges.degraded() {
+            tracing::warn!("Degraded mode, privileges: {:?}", privileges);
+        }
+
+        let passthrough = passthrough_devices(linux_devices(&config))?;
+        let mut devices = freebsd_devices(&config).to_vec();
+
+        devices.extend(passthrough.iter().cloned());
+        devices.extend(annotations.devices.iter().cloned());
+        validate_devices(&devices)?;
+
+        let mut batch = self.storage.batch();
+
+        CONTAINER_CONFIGS
+            .put_batched(&mut batch, &self.key, &config)
+            .map_err(storage_error)?;
+        // Recorded, so that delete can verify they are gone
+        CONTAINER_DEVICES
+            .put_batched(&mut batch, &self.key, &passthrough)
+            .map_err(storage_error)?;
+        batch.commit().map_err(storage_error)?;
+
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(self.state_dir())
+            .context("Failed to create the state directory")?;
 
         let rootfs = self.rootfs()?;
 
+        if let Some(clone) = ContainerClone::from_annotations(
+            config.annotations.as_ref(),
+            &self.key,
+        ) {
+            clone.create(&rootfs)?;
+
+            let dataset = clone.dataset().name().to_string();
+
+            if let Err(error) =
+                CONTAINER_DATASETS.put(self.storage, &self.key, dataset)
+            {
+                clone.dataset().destroy()?;
+                fehler::throw!(storage_error(error));
+            }
+        }
+
+        if let Some(layers) = LayeredRootfs::from_annotations(
+            config.annotations.as_ref(),
+            &rootfs,
+        )? {
+            layers.mount(&rootfs)?;
+        }
+
         // Mountpoints validity check.
         for mountpoint in self.mounts()? {
+            if mountpoint.kind() == "devfs" && !privileges.mount_devfs {
+                tracing::warn!(
+                    "devfs can't be mounted, skipping {}",
+                    mountpoint.destination()
+                );
+                continue;
+            }
+
             mountpoint.mount(&rootfs)?;
+
+            if mountpoint.kind() == "devfs" {
+                expose_devices(
+                    prefixed_destination(&rootfs, mountpoint.destination()),
+                    &devices,
+                )?;
+            }
         }
 
-        let stopped_jail = StoppedJail::new(&rootfs.as_ref())
-            .name(&self.key)
-            .param("vnet", Value::Int(1))
+        locale::install(
+            &annotations,
+            Path::new("/"),
+            rootfs.as_ref(),
+            linux.is_none(),
+        )?;
+
+        let jail_name = jails::assign(self.storage, &self.key)?;
+        let mut stopped_jail = StoppedJail::new(&rootfs.as_ref())
+            .name(&jail_name)
+            .hostname(hostname(&config, &self.key))
             .param("allow.raw_sockets", Value::Int(1))
             .param("enforce_statfs", Value::Int(1));
 
+        stopped_jail = match annotations.network {
+            NetworkMode::Host => stopped_jail
+                .param("ip4", Value::String("inherit".into()))
+                .param("ip6", Value::String("inherit".into())),
+            NetworkMode::Bridge | NetworkMode::None | NetworkMode::Cni => {
+                stopped_jail.param("vnet", Value::Int(1))
+            }
+        };
+
+        if annotations.children > 0 {
+            stopped_jail = stopped_jail.param(
+                "children.max",
+                Value::Int(annotations.children as i32),
+            );
+        }
+
+        for (name, value) in freebsd_jail_params(&config) {
+            stopped_jail = stopped_jail.param(name, value);
+        }
+
+        // Revocations override the permissions given above
+        if let Some(seccomp) = linux_seccomp(&config) {
+            for (name, value) in seccomp::jail_params(seccomp) {
+                stopped_jail = stopped_jail.param(name, value);
+            }
+        }
+
+        if let Some(linux) = &linux {
+            for (name, value) in linux.jail_params()? {
+                stopped_jail = stopped_jail.param(name, value);
+            }
+        }
+
         tracing::info!("Starting a jail for the process");
-        let jail = stopped_jail.start()?;
+        let jail = stopped_jail
+            .start()
+            .map_err(|error| KnastError::JailError(error.into()))?;
 
-        network::setup(self.storage, &self.key, jail, nat_interface)?;
+        jails::started(self.storage, &self.key, jail)?;
+
+        let nat_interface = nat_interface.as_ref().map(|name| name.as_ref());
+
+        network::provider(&annotations, nat_interface).setup(
+            self.storage,
+            &self.key,
+            jail,
+            &annotations,
+        )?;
+
+        self.emit(MAIN_PROCESS_EXEC_ID, EventKind::Created);
     }
 
-    /// Starts previously created container.
-    #[fehler::throws]
-    pub fn start(self) {
+    /// Starts previously created container, applying the
+    /// overrides to the configured process.
+    #[fehler::throws(KnastError)]
+    pub fn start(self, overrides: ProcessOverrides) {
         tracing::info!("START command issued");
+        let _timer = operation_timer("start");
 
-        self.do_start(MAIN_PROCESS_EXEC_ID, |_| Ok(()))?
+        self.do_start(MAIN_PROCESS_EXEC_ID, overrides, |_| Ok(()))?
     }
 
     /// Frees resources allocated by Runtime for the
     /// container. [OCI lifecycle steps 11-12](https://git.io/JO7NY).
+    /// Running containers are left intact, leftover
+    /// processes of stopped ones are killed.
     pub fn delete(&self) {
-        if self.retrieve_jail().is_err() {
-            self.do_delete();
+        if self.is_running(MAIN_PROCESS_EXEC_ID).unwrap_or(false) {
+            tracing::error!("Cannot delete running container");
+            return;
         }
+
+        self.do_delete();
     }
 
     pub fn do_delete(&self) {
-        if let Err(err) = self.cleanup() {
+        let _timer = operation_timer("delete");
+        let result = self.cleanup();
+
+        self.audit(Action::ContainerDelete, &result);
+
+        if let Err(err) = result {
             tracing::error!("Failed to delete process: {}", err);
         }
     }
 
     /// Sends a signal to the process
-    #[fehler::throws]
+    #[fehler::throws(KnastError)]
     pub fn kill(self, signal: i32) {
         self.do_kill(MAIN_PROCESS_EXEC_ID, signal)?;
     }
 
-    #[fehler::throws]
-    pub fn do_kill(self, exec_id: &str, signal: i32) {
+    /// Stops the container gracefully: sends the image's
+    /// stop signal and, if the main process doesn't exit
+    /// within `timeout`, kills it.
+    #[fehler::throws(KnastError)]
+    pub fn stop(&self, timeout: Duration) {
+        let _timer = operation_timer("stop");
+        let state = self.get_state(MAIN_PROCESS_EXEC_ID)?;
+
+        if state.status != ProcessStatus::Running {
+            tracing::info!("Container is {}", state.status.as_ref());
+            return;
+        }
+
+        let signal = self.stop_signal()?;
+        self.do_kill(MAIN_PROCESS_EXEC_ID, signal as i32)?;
+
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            if !self.is_running(MAIN_PROCESS_EXEC_ID)? {
+                return;
+            }
+
+            thread::sleep(STOP_POLL_INTERVAL);
+        }
+
+        tracing::info!("Container didn't stop in {:?}, killing", timeout);
+        self.do_kill(MAIN_PROCESS_EXEC_ID, libc::SIGKILL)?;
+    }
+
+    #[fehler::throws(KnastError)]
+    pub fn do_kill(&self, exec_id: &str, signal: i32) {
+        let result = self.send_signal(exec_id, signal);
+
+        self.audit(Action::ContainerKill, &result);
+
+        result?
+    }
+
+    #[fehler::throws(KnastError)]
+    fn send_signal(&self, exec_id: &str, signal: i32) {
         tracing::info!("killing container with {}", signal);
+        let _timer = operation_timer("kill");
         let state = &self.get_process(exec_id)?;
         if state.status != ProcessStatus::Running {
-            anyhow::bail!("Cannot kill {} container.", state.status.as_ref());
+            fehler::throw!(KnastError::InvalidState(format!(
+                "Cannot kill {} container.",
+                state.status.as_ref()
+            )));
+        }
+
+        // Descriptor can't refer to a recycled pid
+        if let Some(result) = procdesc::kill(state.pid, signal) {
+            return result?;
         }
 
         let jail = self.retrieve_jail()?;
@@ -180,12 +562,28 @@ impl<'a, T: StorageEngine> OciOperations<'a, T> {
         })?
     }
 
-    #[fehler::throws]
+    /// State of the container, along with its annotations.
+    #[fehler::throws(KnastError)]
     pub fn state(&self) -> OciStatus {
-        self.get_state(MAIN_PROCESS_EXEC_ID)?
+        let mut state = self.get_state(MAIN_PROCESS_EXEC_ID)?;
+
+        state.annotations = self.config()?.annotations.unwrap_or_default();
+        state.health = health::get(self.storage, &self.key)
+            .map_err(storage_error)?
+            .map(|health| health.status);
+
+        state
     }
 
-    #[fehler::throws]
+    /// Ports the container publishes on the NAT interface.
+    #[fehler::throws(KnastError)]
+    pub fn ports(&self) -> Vec<PortMapping> {
+        self.get_process(MAIN_PROCESS_EXEC_ID)?;
+
+        network::ports(self.storage, &self.key).map_err(storage_error)?
+    }
+
+    #[fehler::throws(KnastError)]
     pub fn get_state(&self, exec_id: &str) -> OciStatus {
         let mut process = self.get_process(exec_id)?;
         let jail = self.retrieve_jail();
@@ -199,83 +597,244 @@ impl<'a, T: StorageEngine> OciOperations<'a, T> {
         process
     }
 
-    pub fn storage(&'a self) -> &'a Storage<T> {
+    /// Signal that stops the container gracefully.
+    #[fehler::throws(KnastError)]
+    pub fn stop_signal(&self) -> Signal {
+        parse_stop_signal(&self.config()?)?
+    }
+
+    /// Whether the process is still alive. Processes are
+    /// reaped by whoever waits for them, so the recorded
+    /// status may lag behind.
+    #[fehler::throws]
+    fn is_running(&self, exec_id: &str) -> bool {
+        let process = self.get_process(exec_id)?;
+
+        process.status == ProcessStatus::Running
+            && unsafe { libc::kill(process.pid, 0) } == 0
+    }
+
+    /// Exec ids of the container's processes, the main
+    /// process included.
+    #[fehler::throws(KnastError)]
+    pub fn exec_ids(&self) -> Vec<String> {
+        let prefix = format!("{}/", self.key);
+
+        CONTAINER_PROCESSES
+            .keys(self.storage)
+            .map_err(storage_error)?
+            .into_iter()
+            .filter_map(|key| String::from_utf8(key).ok())
+            .filter_map(|key| key.strip_prefix(&prefix).map(String::from))
+            .collect()
+    }
+
+    pub fn storage(&self) -> &'a Storage<T> {
         self.storage
     }
 
-    pub fn key(&'a self) -> &'a String {
+    pub fn key(&self) -> &String {
         &self.key
     }
 
-    #[fehler::throws]
+    #[fehler::throws(KnastError)]
     pub fn do_start(
         &self,
         exec_id: &str,
+        overrides: ProcessOverrides,
         f: impl FnOnce(&mut Command) -> Result<(), Error>,
     ) {
         let config = self.config()?;
         let process = config.process.clone().ok_or_else(|| {
-            anyhow!("Runtime config: process field must be set")
+            KnastError::ConfigInvalid(anyhow!(
+                "Runtime config: process field must be set"
+            ))
+        })?;
+        if exec_id == MAIN_PROCESS_EXEC_ID {
+            CONTAINER_OVERRIDES
+                .put(self.storage, &self.key, overrides.clone())
+                .map_err(storage_error)?;
+        }
+
+        let result = self.do_exec(exec_id, process, overrides, f);
+
+        self.audit(Action::ContainerStart, &result);
+
+        result?
+    }
+
+    /// Starts the process like [`Self::do_start`], capturing
+    /// its stdout and stderr. Returns the pipes of the output,
+    /// unless the callback of the capture reads them.
+    #[fehler::throws(KnastError)]
+    pub fn do_start_captured(
+        &self,
+        exec_id: &str,
+        overrides: ProcessOverrides,
+        capture: OutputCapture,
+    ) -> Option<CapturedOutput> {
+        let mut captured = None;
+
+        self.do_start(exec_id, overrides, |command| {
+            captured = capture.attach(command)?;
+
+            Ok(())
         })?;
 
-        self.do_exec(exec_id, process, f)?
+        captured
     }
 
-    #[fehler::throws]
-    pub fn do_exec(
+    /// Executes the process like [`Self::do_exec`], capturing
+    /// its output, see [`Self::do_start_captured`].
+    #[fehler::throws(KnastError)]
+    pub fn do_exec_captured(
+        &self,
+        exec_id: &str,
+        process: Process,
+        overrides: ProcessOverrides,
+        capture: OutputCapture,
+    ) -> Option<CapturedOutput> {
+        let mut captured = None;
+
+        self.do_exec(exec_id, process, overrides, |command| {
+            captured = capture.attach(command)?;
+
+            Ok(())
+        })?;
+
+        captured
+    }
+
+    /// Executes an additional process in the running
+    /// container. Exec ids are unique per container, the main
+    /// process' one (empty) is reserved.
+    #[fehler::throws(KnastError)]
+    pub fn exec_process(
         &self,
         exec_id: &str,
         process: Process,
         f: impl FnOnce(&mut Command) -> Result<(), Error>,
     ) {
+        self.validate_exec(exec_id)?;
+        self.do_exec(exec_id, process, Default::default(), f)?
+    }
+
+    /// Fails, unless a process can be executed as `exec_id`.
+    #[fehler::throws(KnastError)]
+    pub fn validate_exec(&self, exec_id: &str) {
+        if exec_id == MAIN_PROCESS_EXEC_ID {
+            fehler::throw!(KnastError::ConfigInvalid(anyhow!(
+                "Exec id must not be empty"
+            )));
+        }
+
+        let status = match self.get_process(MAIN_PROCESS_EXEC_ID) {
+            Ok(process) => process.status,
+            Err(_) => ProcessStatus::Created,
+        };
+
+        if !self.is_running(MAIN_PROCESS_EXEC_ID).unwrap_or(false) {
+            fehler::throw!(KnastError::InvalidState(format!(
+                "Cannot exec in {} container '{}'",
+                status.as_ref(),
+                self.key
+            )));
+        }
+
+        let exists = CONTAINER_PROCESSES
+            .exists(self.storage, &self.process_key(exec_id))
+            .map_err(storage_error)?;
+
+        if exists {
+            fehler::throw!(KnastError::ProcessExists(exec_id.into()));
+        }
+    }
+
+    /// Generates an exec id, which isn't taken yet.
+    #[fehler::throws(KnastError)]
+    pub fn new_exec_id(&self) -> String {
+        let taken = self.exec_ids()?;
+
+        (1..)
+            .map(|n| format!("{}{}", EXEC_ID_PREFIX, n))
+            .find(|exec_id| !taken.contains(exec_id))
+            .expect("Exec ids are exhausted")
+    }
+
+    #[fehler::throws(KnastError)]
+    pub fn do_exec(
+        &self,
+        exec_id: &str,
+        mut process: Process,
+        overrides: ProcessOverrides,
+        f: impl FnOnce(&mut Command) -> Result<(), Error>,
+    ) {
+        Privileges::detect().require_root("Executing a process")?;
+        overrides.apply(&mut process)?;
         self.new_process(exec_id)?;
         let process_status = self.get_process(exec_id)?.status;
         // According to OCI spec & runc implementation, we can only
         // start created containers, not even stopped: https://git.io/JO0pb
         if process_status != ProcessStatus::Created {
-            anyhow::bail!("Cannot start {} process", process_status.as_ref());
+            fehler::throw!(KnastError::InvalidState(format!(
+                "Cannot start {} process",
+                process_status.as_ref()
+            )));
         }
         let rootfs = self.rootfs()?;
         let path = rootfs.as_ref();
-        let envs: Vec<(String, String)> = process
-            .env
-            .and_then(|envs| {
-                envs.iter()
-                    .map(|x| {
-                        let mut params = x.split("=");
-
-                        Some((params.next()?.into(), params.next()?.into()))
-                    })
-                    .collect()
-            })
-            .unwrap_or_else(Vec::new);
+        let annotations = Annotations::parse(&self.config()?)?;
+        let mut envs = env::parse(process.env.as_deref().unwrap_or(&[]))?;
+
+        locale::add_env(&annotations, Path::new("/"), path, &mut envs);
+
         let cwd = prefixed_destination(&path, &process.cwd);
-        let uid = process.user.uid;
-        let gid = process.user.gid;
+        let (uid, gid) = match &process.user.username {
+            Some(username) => user::parse(username.clone(), path)
+                .with_context(|| {
+                    format!("Failed to resolve user {}", username)
+                })?,
+            None => (process.user.uid, process.user.gid),
+        };
         let mut args = process.args.unwrap_or_else(Vec::new).into_iter();
-        let command = args
-            .next()
-            .ok_or_else(|| anyhow!("Runtime config: command is required"))?;
+        let command = args.next().ok_or_else(|| {
+            KnastError::ConfigInvalid(anyhow!(
+                "Runtime config: command is required"
+            ))
+        })?;
         let args: Vec<_> = args.collect();
+        let init = if exec_id == MAIN_PROCESS_EXEC_ID && annotations.init {
+            Some(Init::new(&init::path()?, &command, &args, &envs)?)
+        } else {
+            None
+        };
+        let exec = match init {
+            Some(_) => None,
+            None => Some(procdesc::Exec::new(&command, &args, &envs)?),
+        };
 
         self.update_process(exec_id, |process| {
             process.status = ProcessStatus::Starting;
         })?;
 
         let jail = self.retrieve_jail()?;
-        let mut process = Command::new(command);
+        let mut process = Command::new(&command);
         f(&mut process)?;
 
-        let result = process
-            .jail(&jail)
-            .args(args)
-            .env_clear()
-            .envs(envs)
-            .current_dir(cwd)
-            .uid(uid)
-            .gid(gid)
-            .spawn();
-        jail.defer_cleanup()?;
+        process.jail(&jail).current_dir(cwd).uid(uid).gid(gid);
+
+        // Executed once the process is attached to the jail
+        if let Some(init) = init {
+            init.attach(&mut process);
+        }
+
+        if let Some(exec) = exec {
+            exec.attach(&mut process);
+        }
+
+        let result = procdesc::spawn(&mut process);
+        jail.defer_cleanup()
+            .map_err(|error| KnastError::JailError(error.into()))?;
 
         match result {
             Err(error) => {
@@ -283,43 +842,244 @@ impl<'a, T: StorageEngine> OciOperations<'a, T> {
                 self.update_process(exec_id, |process| {
                     process.status = ProcessStatus::Stopped;
                 })?;
-                fehler::throw!(error);
+                fehler::throw!(error.context("Failed to spawn the process"));
             }
-            Ok(handle) => {
-                tracing::info!("Started child process {:?}", handle);
+            Ok(pid) => {
+                tracing::info!("Started child process {}", pid);
                 self.update_process(exec_id, |process| {
                     process.status = ProcessStatus::Running;
-                    process.pid = handle.id() as _;
+                    process.pid = pid;
                     process.jid = jail.jid;
                 })?;
+
+                if exec_id == MAIN_PROCESS_EXEC_ID {
+                    self.emit(exec_id, EventKind::Started);
+                } else {
+                    self.emit(exec_id, EventKind::ExecAdded);
+                }
             }
         }
     }
 
-    #[fehler::throws]
+    /// Waits for the main process to exit, restarting it as
+    /// long as the container's [`RestartPolicy`] says so.
+    #[fehler::throws(KnastError)]
     pub fn wait(&self) {
-        self.do_wait(MAIN_PROCESS_EXEC_ID)?
+        self.wait_restarting(None)?
     }
 
-    #[fehler::throws]
+    /// Waits like [`Self::wait`], capturing the output of the
+    /// restarted processes, see [`Self::do_start_captured`].
+    /// Meant for [`OutputCapture::Callback`]: pipes of the
+    /// restarted processes have no reader.
+    #[fehler::throws(KnastError)]
+    pub fn wait_captured(&self, capture: OutputCapture) {
+        self.wait_restarting(Some(capture))?
+    }
+
+    #[fehler::throws(KnastError)]
+    fn wait_restarting(&self, capture: Option<OutputCapture>) {
+        let policy = Annotations::parse(&self.config()?)?.restart;
+        let mut restarts = 0;
+
+        loop {
+            self.do_wait(MAIN_PROCESS_EXEC_ID)?;
+
+            let exit_status =
+                self.get_process(MAIN_PROCESS_EXEC_ID)?.exit_status;
+
+            if !policy.restarts(exit_status, restarts) {
+                break;
+            }
+
+            restarts += 1;
+            tracing::info!("Restarting the process, attempt {}", restarts);
+            self.restart(capture.clone())?;
+        }
+    }
+
+    /// Starts the stopped main process anew, with the
+    /// overrides it was started with. The output is captured,
+    /// if `capture` is given.
+    #[fehler::throws(KnastError)]
+    pub fn restart(&self, capture: Option<OutputCapture>) {
+        let overrides = CONTAINER_OVERRIDES
+            .get(self.storage, &self.key)
+            .map_err(storage_error)?
+            .unwrap_or_default();
+
+        self.delete_process(MAIN_PROCESS_EXEC_ID)?;
+
+        match capture {
+            Some(capture) => {
+                self.do_start_captured(
+                    MAIN_PROCESS_EXEC_ID,
+                    overrides,
+                    capture,
+                )?;
+            }
+            None => {
+                self.do_start(MAIN_PROCESS_EXEC_ID, overrides, |_| Ok(()))?
+            }
+        }
+    }
+
+    #[fehler::throws(KnastError)]
     pub fn do_wait(&self, exec_id: &str) {
         let process = self.get_process(exec_id)?;
         tracing::info!("Waiting for child {:?}", process.pid);
 
-        let exit_status = waitpid(Pid::from_raw(process.pid), None)
-            .map(|status| match status {
-                WaitStatus::Exited(_, code) => Some(code),
-                _ => None,
-            })
-            .map_err(Error::from)?;
+        let exit = match exits::take_exit(self.storage, process.pid)? {
+            Some(exit) => exit,
+            None => self.wait_for_exit(process.pid)?,
+        };
+
+        self.stopped(exec_id, exit)?;
+    }
+
+    /// Stops the process, if it has exited, without blocking.
+    /// Returns its state then, `None` while it's running.
+    /// Processes, which aren't running, are returned as is.
+    #[fehler::throws(KnastError)]
+    pub fn try_wait(&self, exec_id: &str) -> Option<OciStatus> {
+        let process = self.get_process(exec_id)?;
+
+        if process.status != ProcessStatus::Running {
+            return Some(process);
+        }
+
+        let exit = match exits::take_exit(self.storage, process.pid)? {
+            Some(exit) => exit,
+            None => match procdesc::try_wait(process.pid) {
+                Ok(Some(status)) => status.into(),
+                Ok(None) => return None,
+                // Reaped by someone else, see `wait_for_exit`
+                Err(nix::Error::Sys(Errno::ECHILD)) => return None,
+                Err(error) => fehler::throw!(Error::from(error)),
+            },
+        };
+
+        self.stopped(exec_id, exit)?;
+
+        Some(self.get_process(exec_id)?)
+    }
+
+    /// Waits for the process to exit for up to `timeout`, see
+    /// [`OciOperations::try_wait`]. Returns `None` if it's
+    /// still running then.
+    #[fehler::throws(KnastError)]
+    pub fn wait_timeout(
+        &self,
+        exec_id: &str,
+        timeout: Duration,
+    ) -> Option<OciStatus> {
+        let deadline = Instant::now() + timeout;
+        let mut gone_since = None;
+
+        loop {
+            if let Some(process) = self.try_wait(exec_id)? {
+                return Some(process);
+            }
+
+            let pid = self.get_process(exec_id)?.pid;
+            let now = Instant::now();
 
+            // Reaped by someone else, who never recorded it
+            if unsafe { libc::kill(pid, 0) } < 0 {
+                let since = *gone_since.get_or_insert(now);
+
+                if now > since + REAPED_STATUS_TIMEOUT {
+                    tracing::info!("Exit status of {} is unknown", pid);
+                    self.stopped(exec_id, ExitStatus::now(None))?;
+
+                    return Some(self.get_process(exec_id)?);
+                }
+            }
+
+            if now >= deadline {
+                return None;
+            }
+
+            thread::sleep(STOP_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    #[fehler::throws]
+    fn stopped(&self, exec_id: &str, exit: ExitStatus) {
         self.update_process(exec_id, |process| {
             process.pid = 0;
             process.status = ProcessStatus::Stopped;
-            process.exit_status = exit_status;
-            process.exited_at = SystemTime::now();
+            process.exit_status = exit.code;
+            process.exited_at = exit.exited_at;
+            process.signal = exit.signal;
+            process.core_dumped = exit.core_dumped;
         })?;
-        tracing::info!("Process exited with {:?}", exit_status);
+        tracing::info!(
+            "Process exited with {:?}, signal {:?}, core dumped: {}",
+            exit.code,
+            exit.signal,
+            exit.core_dumped
+        );
+        self.emit(
+            exec_id,
+            EventKind::Stopped {
+                exit_status: exit.code,
+            },
+        );
+    }
+
+    /// Events are best-effort, failing to emit one doesn't
+    /// fail the operation.
+    fn emit(&self, exec_id: &str, kind: EventKind) {
+        let result = events::emit(self.storage, &self.key, exec_id, kind);
+
+        if let Err(error) = result {
+            tracing::error!("Failed to emit an event: {}", error);
+        }
+    }
+
+    /// Appends the outcome of the operation to the audit
+    /// trail, see [`storage::audit`].
+    fn audit<R, E: Display>(&self, action: Action, result: &Result<R, E>) {
+        let error = result.as_ref().err().map(ToString::to_string);
+        let result = audit::record(self.storage, action, &self.key, error);
+
+        if let Err(error) = result {
+            tracing::error!("Failed to audit {:?}: {}", action, error);
+        }
+    }
+
+    /// Waits for the process to exit. If the process is
+    /// reaped by someone else, waits for its status to be
+    /// recorded.
+    #[fehler::throws]
+    fn wait_for_exit(&self, pid: i32) -> ExitStatus {
+        match procdesc::wait(pid) {
+            Ok(status) => return status.into(),
+            Err(nix::Error::Sys(Errno::ECHILD)) => (),
+            Err(error) => fehler::throw!(error),
+        }
+
+        let mut deadline = None;
+
+        loop {
+            if let Some(exit) = exits::take_exit(self.storage, pid)? {
+                return exit;
+            }
+
+            if unsafe { libc::kill(pid, 0) } < 0 {
+                let deadline = *deadline.get_or_insert_with(|| {
+                    Instant::now() + REAPED_STATUS_TIMEOUT
+                });
+
+                if Instant::now() > deadline {
+                    tracing::info!("Exit status of {} is unknown", pid);
+                    return ExitStatus::now(None);
+                }
+            }
+
+            thread::sleep(STOP_POLL_INTERVAL);
+        }
     }
 
     #[fehler::throws]
@@ -344,24 +1104,26 @@ impl<'a, T: StorageEngine> OciOperations<'a, T> {
         result
     }
 
-    #[fehler::throws]
-    fn config(&self) -> RuntimeConfig {
-        self.storage
-            .get(CONTAINER_CONFIG_STORAGE_KEY, self.key.as_bytes())?
-            .ok_or_else(|| {
-                anyhow!("Container '{}' doesn't exist!", self.key)
-            })?
+    /// Runtime config the container was created with.
+    #[fehler::throws(KnastError)]
+    pub fn config(&self) -> RuntimeConfig {
+        CONTAINER_CONFIGS
+            .get(self.storage, &self.key)
+            .map_err(storage_error)?
+            .ok_or_else(|| KnastError::ContainerNotFound(self.key.clone()))?
     }
 
-    pub fn process_id(&self, exec_id: &str) -> Vec<u8> {
-        [self.key.as_bytes(), b"/", exec_id.as_bytes()].concat()
+    /// Key of the process in the storage.
+    pub fn process_key<'b>(&'b self, exec_id: &'b str) -> (&'b str, &'b str) {
+        (&self.key, exec_id)
     }
 
     #[fehler::throws]
     fn get_process(&self, exec_id: &str) -> OciStatus {
-        self.storage
-            .get(CONTAINER_PROCESSES_STORAGE_KEY, self.process_id(exec_id))?
-            .ok_or_else(|| anyhow!("Process '{}' doesn't exist!", exec_id))?
+        CONTAINER_PROCESSES
+            .get(self.storage, &self.process_key(exec_id))
+            .map_err(storage_error)?
+            .ok_or_else(|| KnastError::ProcessNotFound(exec_id.into()))?
     }
 
     #[fehler::throws]
@@ -371,73 +1133,512 @@ impl<'a, T: StorageEngine> OciOperations<'a, T> {
 
         f(&mut new_process);
 
-        self.storage.compare_and_swap(
-            CONTAINER_PROCESSES_STORAGE_KEY,
-            self.process_id(exec_id),
-            Some(process),
-            Some(new_process),
-        )?;
+        let (from, to) = (process.status, new_process.status);
+
+        CONTAINER_PROCESSES
+            .compare_and_swap(
+                self.storage,
+                &self.process_key(exec_id),
+                Some(process),
+                Some(new_process),
+            )
+            .map_err(storage_error)?;
+
+        if exec_id == MAIN_PROCESS_EXEC_ID {
+            track_state(Some(from), Some(to));
+        }
     }
 
     #[fehler::throws]
     fn new_process(&self, exec_id: &str) {
-        self.storage.compare_and_swap(
-            CONTAINER_PROCESSES_STORAGE_KEY,
-            self.process_id(exec_id),
-            None,
-            Some(OciStatus {
-                oci_version: OCI_VERSION.into(),
-                status: ProcessStatus::Created,
-                pid: 0,
-                jid: 0,
-                exit_status: None,
-                exited_at: UNIX_EPOCH,
-            }),
-        )?;
+        if let Ok(process) = self.get_process(exec_id) {
+            if exec_id == MAIN_PROCESS_EXEC_ID {
+                fehler::throw!(KnastError::InvalidState(format!(
+                    "Cannot start {} process",
+                    process.status.as_ref()
+                )));
+            }
+
+            fehler::throw!(KnastError::ProcessExists(exec_id.into()));
+        }
+
+        // Guards against the concurrent creations
+        CONTAINER_PROCESSES
+            .compare_and_swap(
+                self.storage,
+                &self.process_key(exec_id),
+                None,
+                Some(OciStatus {
+                    oci_version: OCI_VERSION.into(),
+                    status: ProcessStatus::Created,
+                    pid: 0,
+                    jid: 0,
+                    exit_status: None,
+                    exited_at: UNIX_EPOCH,
+                    signal: None,
+                    core_dumped: false,
+                    annotations: BTreeMap::new(),
+                    health: None,
+                }),
+            )
+            .map_err(storage_error)?;
+
+        if exec_id == MAIN_PROCESS_EXEC_ID {
+            track_state(None, Some(ProcessStatus::Created));
+        }
     }
 
-    #[fehler::throws]
+    #[fehler::throws(KnastError)]
     pub fn delete_process(&self, exec_id: &str) {
-        self.storage.remove(
-            CONTAINER_PROCESSES_STORAGE_KEY,
-            self.process_id(exec_id),
-        )?
+        let status = self.get_process(exec_id).ok().map(|p| p.status);
+
+        CONTAINER_PROCESSES
+            .remove(self.storage, &self.process_key(exec_id))
+            .map_err(storage_error)?;
+
+        if exec_id == MAIN_PROCESS_EXEC_ID {
+            track_state(status, None);
+        }
     }
 
-    #[fehler::throws]
+    #[fehler::throws(KnastError)]
     pub fn retrieve_jail(&'a self) -> RunningJail {
-        RunningJail::from_name(&self.key)
-            .map_err(|_| anyhow!("Container is not running"))?
+        jails::find(self.storage, &self.key)?.ok_or_else(|| {
+            KnastError::InvalidState("Container is not running".into())
+        })?
     }
 
+    /// Frees every resource of the container, even if some
+    /// of them fail to be freed. Errors are reported
+    /// together. Container's config is kept until the
+    /// cleanup succeeds, so that it can be retried.
     #[fehler::throws]
     fn cleanup(&self) {
+        let config = match CONTAINER_CONFIGS.get(self.storage, &self.key)? {
+            Some(config) => config,
+            None => {
+                tracing::info!("Container '{}' is already deleted", self.key);
+                return;
+            }
+        };
+        let mut errors = Errors::default();
+
+        // Orphaned processes would keep the mounts busy
+        if let Some(jail) = errors
+            .collect("terminate jail", jails::find(self.storage, &self.key))
+            .flatten()
+        {
+            errors.collect(
+                "terminate jail",
+                utils::terminate_jail(jail, TERMINATION_TIMEOUT),
+            );
+        }
+
         let rootfs = self.rootfs()?;
+        let passthrough = CONTAINER_DEVICES
+            .get(self.storage, &self.key)?
+            .unwrap_or_else(Vec::new);
+        let annotations = Annotations::parse(&config)?;
+        let mut devices = freebsd_devices(&config).to_vec();
+
+        devices.extend(passthrough.iter().cloned());
+        devices.extend(annotations.devices.iter().cloned());
+
         for mount in self.mounts()?.iter().rev() {
-            mount.unmount(&rootfs)?;
+            let context = format!("unmount {}", mount.destination());
+
+            match errors.collect(&context, mount.is_mounted(&rootfs)) {
+                Some(true) => (),
+                _ => continue,
+            }
+
+            if mount.kind() == "devfs" {
+                errors.collect(
+                    &context,
+                    hide_devices(
+                        prefixed_destination(&rootfs, mount.destination()),
+                        &devices,
+                    ),
+                );
+            }
+
+            errors.collect(&context, mount.unmount(&rootfs));
+        }
+
+        if errors
+            .collect("devices", verify_devices_hidden(&rootfs, &passthrough))
+            .is_some()
+        {
+            errors.collect(
+                "devices",
+                CONTAINER_DEVICES.remove(self.storage, &self.key),
+            );
+        }
+
+        errors.collect(
+            "network",
+            network::provider(&annotations, None)
+                .teardown(self.storage, &self.key),
+        );
+        errors.collect(
+            "state directory",
+            match fs::remove_dir_all(self.state_dir()) {
+                Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+                result => result.map_err(Error::from),
+            },
+        );
+
+        if let Some(layers) = errors
+            .collect(
+                "layers",
+                LayeredRootfs::from_annotations(
+                    config.annotations.as_ref(),
+                    &rootfs,
+                ),
+            )
+            .flatten()
+        {
+            errors.collect("layers", layers.unmount(&rootfs));
+        }
+
+        // Datasets are destroyed, if the container created them
+        if let Some(dataset) = errors
+            .collect(
+                "ZFS clone",
+                CONTAINER_DATASETS.get(self.storage, &self.key),
+            )
+            .flatten()
+        {
+            errors.collect(
+                "ZFS clone",
+                Dataset::new(dataset).destroy_existing(),
+            );
         }
 
-        network::teardown(self.storage, self.key.clone())?;
+        if errors.is_empty() {
+            // Execs along with the main process
+            for exec_id in self.exec_ids()? {
+                self.delete_process(&exec_id)?;
+            }
+
+            jails::forget(self.storage, &self.key)?;
+            health::forget(self.storage, &self.key)?;
+            CONTAINER_OVERRIDES.remove(self.storage, &self.key)?;
+            CONTAINER_DATASETS.remove(self.storage, &self.key)?;
+            CONTAINER_CONFIGS.remove(self.storage, &self.key)?;
+            self.emit(MAIN_PROCESS_EXEC_ID, EventKind::Deleted);
+        }
+
+        errors.into_result(format!("Deleting container '{}'", self.key))?;
     }
 }
 
+/// Keys of the created containers, see
+/// [`crate::namespace::key`].
+#[fehler::throws(KnastError)]
+pub fn containers(storage: &Storage<impl StorageEngine>) -> Vec<String> {
+    CONTAINER_CONFIGS
+        .keys(storage)
+        .map_err(storage_error)?
+        .into_iter()
+        .map(|key| Ok(String::from_utf8(key)?))
+        .collect::<Result<_, Error>>()?
+}
+
+/// Reads the runtime config of the bundle, reporting all
+/// of its problems at once, see [`RuntimeConfig::validate`].
+/// On top of that, mounts must be of the supported types.
+#[fehler::throws(KnastError)]
+pub fn validate(bundle: impl AsRef<Path>) -> RuntimeConfig {
+    let path = bundle.as_ref().join("config.json");
+    let config: RuntimeConfig = File::open(&path)
+        .map_err(Error::from)
+        .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?))
+        .with_context(|| format!("Cannot read {:?}", path))
+        .map_err(KnastError::ConfigInvalid)?;
+    let mut problems = match config.validate(&bundle) {
+        Ok(()) => vec![],
+        Err(InvalidConfig(problems)) => problems,
+    };
+
+    for mount in config.mounts.as_deref().unwrap_or(&[]) {
+        let result = mount
+            .kind()
+            .parse::<FilesystemKind>()
+            .and_then(|kind| kind.validate(mount.source(), &mount.options()));
+
+        if let Err(error) = result {
+            problems.push(error.to_string());
+        }
+    }
+
+    if !problems.is_empty() {
+        fehler::throw!(InvalidConfig(problems));
+    }
+
+    config
+}
+
+fn operation_timer(operation: &str) -> metrics::Timer {
+    metrics::timer(
+        "knast_operation_duration_seconds",
+        &[("operation", operation)],
+    )
+}
+
+/// Keeps `knast_containers` gauge in sync with main
+/// processes' status transitions.
+fn track_state(from: Option<ProcessStatus>, to: Option<ProcessStatus>) {
+    if from == to {
+        return;
+    }
+
+    if let Some(status) = from {
+        metrics::add("knast_containers", &[("state", status.as_ref())], -1.0);
+    }
+
+    if let Some(status) = to {
+        metrics::add("knast_containers", &[("state", status.as_ref())], 1.0);
+    }
+}
+
+fn storage_error(error: Error) -> KnastError {
+    metrics::increment("knast_storage_errors_total", &[]);
+
+    KnastError::StorageError(error)
+}
+
+/// Signal that stops the container gracefully, SIGTERM
+/// unless the image says otherwise. Both numbers and names
+/// are accepted.
+#[fehler::throws]
+fn parse_stop_signal(config: &RuntimeConfig) -> Signal {
+    let signal = match config
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(STOP_SIGNAL_ANNOTATION))
+    {
+        Some(signal) => signal.trim().to_uppercase(),
+        None => return Signal::SIGTERM,
+    };
+
+    if let Ok(number) = signal.parse::<i32>() {
+        return Signal::try_from(number)?;
+    }
+
+    if signal.starts_with("SIG") {
+        signal.parse()?
+    } else {
+        format!("SIG{}", signal).parse()?
+    }
+}
+
+/// Hostname the container sees, defaults to the container
+/// id.
+fn hostname(config: &RuntimeConfig, key: &str) -> String {
+    config
+        .process
+        .as_ref()
+        .and_then(|process| process.hostname.clone())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| key.into())
+}
+
+/// Devices the container requests on top of the default
+/// ones.
+fn freebsd_devices(config: &RuntimeConfig) -> &[Device] {
+    config
+        .freebsd
+        .as_ref()
+        .and_then(|freebsd| freebsd.devices.as_deref())
+        .unwrap_or(&[])
+}
+
+/// Jail parameters of the `freebsd` section of the config,
+/// the given ones only.
+fn freebsd_jail_params(config: &RuntimeConfig) -> Vec<(&'static str, Value)> {
+    let freebsd = match &config.freebsd {
+        Some(freebsd) => freebsd,
+        None => return Vec::new(),
+    };
+    let allow = freebsd.allow.clone().unwrap_or_default();
+    let toggles = [
+        ("allow.sysvipc", allow.sysvipc),
+        ("allow.mount", allow.mount),
+        ("allow.mount.devfs", allow.mount_devfs),
+        ("allow.chflags", allow.chflags),
+    ];
+    let modes = [
+        ("sysvmsg", freebsd.sysvmsg),
+        ("sysvsem", freebsd.sysvsem),
+        ("sysvshm", freebsd.sysvshm),
+    ];
+    let mut params = Vec::new();
+
+    for (name, toggle) in &toggles {
+        if let Some(toggle) = toggle {
+            params.push((*name, Value::Int(*toggle as i32)));
+        }
+    }
+
+    // JAIL_SYS_* of sys/jail.h
+    for (name, mode) in &modes {
+        let mode = match mode {
+            Some(SysvMode::Disable) => 0,
+            Some(SysvMode::New) => 1,
+            Some(SysvMode::Inherit) => 2,
+            None => continue,
+        };
+
+        params.push((*name, Value::Int(mode)));
+    }
+
+    params
+}
+
+/// Host devices passed through to the container.
+fn linux_devices(config: &RuntimeConfig) -> &[LinuxDevice] {
+    config
+        .linux
+        .as_ref()
+        .and_then(|linux| linux.devices.as_deref())
+        .unwrap_or(&[])
+}
+
+/// Seccomp profile of the container, if any.
+fn linux_seccomp(config: &RuntimeConfig) -> Option<&Seccomp> {
+    config
+        .linux
+        .as_ref()
+        .and_then(|linux| linux.seccomp.as_ref())
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::BTreeMap,
         fs::OpenOptions,
         io::{Read, Seek, SeekFrom, Write},
         process::Command,
-        sync::Arc,
+        sync::{mpsc, Arc, Mutex},
     };
 
-    use gag::BufferRedirect;
     use storage::TestStorage;
     use tempfile::TempDir;
 
-    use super::*;
+    use super::{annotations::RESTART_ANNOTATION, *};
+
+    #[test]
+    fn test_stop_signal() {
+        let (_storage, tempdir) = prepare_bundle("id");
+        let file = File::open(tempdir.path().join("container/config.json"))
+            .expect("failed to open config file");
+        let mut config: RuntimeConfig =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("failed to parse config");
+
+        config.annotations = None;
+        assert_eq!(parse_stop_signal(&config).unwrap(), Signal::SIGTERM);
+
+        for (annotation, signal) in &[
+            ("2", Signal::SIGINT),
+            ("SIGQUIT", Signal::SIGQUIT),
+            ("usr1", Signal::SIGUSR1),
+        ] {
+            let mut annotations = BTreeMap::new();
+            annotations
+                .insert(STOP_SIGNAL_ANNOTATION.into(), annotation.to_string());
+            config.annotations = Some(annotations);
+
+            assert_eq!(parse_stop_signal(&config).unwrap(), *signal);
+        }
+
+        let mut annotations = BTreeMap::new();
+        annotations.insert(STOP_SIGNAL_ANNOTATION.into(), "SIGNOPE".into());
+        config.annotations = Some(annotations);
+
+        assert!(parse_stop_signal(&config).is_err());
+    }
+
+    #[test]
+    fn test_process_overrides() {
+        let mut process: Process = serde_json::from_str(
+            r#"{
+                "cwd": "/",
+                "env": ["PATH=/bin", "TERM=xterm"],
+                "args": ["sh"],
+                "user": {}
+            }"#,
+        )
+        .expect("failed to parse process");
+        let overrides = ProcessOverrides {
+            env: vec!["TERM=vt100".into(), "DEBUG=1".into()],
+            args: Some(vec!["ls".into(), "-l".into()]),
+            cwd: None,
+        };
+
+        overrides.apply(&mut process).unwrap();
+
+        assert_eq!(
+            process.env.as_ref().unwrap(),
+            &vec!["PATH=/bin", "TERM=vt100", "DEBUG=1"]
+        );
+        assert_eq!(process.args.as_ref().unwrap(), &vec!["ls", "-l"]);
+        assert_eq!(process.cwd, "/");
+
+        let overrides = ProcessOverrides {
+            env: vec!["=1".into()],
+            ..Default::default()
+        };
+
+        assert!(overrides.apply(&mut process).is_err());
+    }
+
+    #[test]
+    fn test_hostname() {
+        let (_storage, tempdir) = prepare_bundle("id");
+        let file = File::open(tempdir.path().join("container/config.json"))
+            .expect("failed to open config file");
+        let mut config: RuntimeConfig =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("failed to parse config");
+
+        assert_eq!(hostname(&config, "nginx"), "nginx");
+
+        if let Some(process) = config.process.as_mut() {
+            process.hostname = Some("web".into());
+        }
+
+        assert_eq!(hostname(&config, "nginx"), "web");
+    }
+
+    #[test]
+    fn test_freebsd_jail_params() {
+        let mut config = RuntimeConfig::spec();
+
+        assert!(freebsd_jail_params(&config).is_empty());
+
+        config.freebsd = Some(FreeBSD {
+            allow: Some(Allow {
+                sysvipc: Some(false),
+                chflags: Some(true),
+                ..Allow::default()
+            }),
+            sysvshm: Some(SysvMode::Inherit),
+            ..FreeBSD::default()
+        });
+
+        let params = freebsd_jail_params(&config);
+
+        assert_eq!(
+            params.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            vec!["allow.sysvipc", "allow.chflags", "sysvshm"]
+        );
+        assert!(matches!(params[0].1, Value::Int(0)));
+        assert!(matches!(params[1].1, Value::Int(1)));
+        assert!(matches!(params[2].1, Value::Int(2)));
+    }
 
-    /// Some tests are capturing output, we can't run them
-    /// in parallel.
     #[test]
     fn test_linux_container_lifecycle() {
         test_lifecycle();
@@ -455,6 +1656,59 @@ mod tests {
         kill_container(storage.clone(), "container3", libc::SIGBUS);
     }
 
+    #[test]
+    fn test_output_callback_restart() {
+        let (storage, tempdir) = prepare_bundle("id");
+        let config_path = tempdir.path().join("container/config.json");
+        let mut config: RuntimeConfig = serde_json::from_reader(
+            BufReader::new(File::open(&config_path).unwrap()),
+        )
+        .expect("failed to parse config");
+
+        config
+            .annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(RESTART_ANNOTATION.into(), "on-failure:1".into());
+        fs::write(&config_path, serde_json::to_vec(&config).unwrap()).unwrap();
+        create_container(storage.clone(), "restarting", tempdir.path());
+
+        // Senders are dropped, once the streams are read up
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let capture =
+            OutputCapture::Callback(Arc::new(move |stream, chunk: &[u8]| {
+                if stream == OutputStream::Stdout {
+                    sender.lock().unwrap().send(chunk.to_vec()).unwrap();
+                }
+            }));
+        let overrides = ProcessOverrides {
+            args: Some(vec!["sh".into(), "-c".into(), "id; exit 1".into()]),
+            ..Default::default()
+        };
+        let operations = OciOperations::new(&storage, "restarting")
+            .expect("failed to init OCI lifecycle struct");
+
+        operations
+            .do_start_captured(
+                MAIN_PROCESS_EXEC_ID,
+                overrides,
+                capture.clone(),
+            )
+            .expect("failed to start container");
+        operations
+            .wait_captured(capture)
+            .expect("failed to wait container");
+
+        let output: Vec<u8> = receiver.iter().flatten().collect();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            test_helpers::fixture!("commands_output/id").repeat(2)
+        );
+
+        delete_container(storage, "restarting");
+    }
+
     fn test_lifecycle() {
         run_container(
             "linux",
@@ -481,9 +1735,7 @@ mod tests {
         create_container(storage.clone(), "trapster", tempdir.path());
         let storage_copy = storage.clone();
         let thread = thread::spawn(move || {
-            let output = capture_output(|| {
-                start_container(storage_copy.clone(), "trapster");
-            });
+            let output = start_container(storage_copy.clone(), "trapster");
             assert_eq!(
                 output,
                 test_helpers::fixture!("commands_output/trapster_sigbus")
@@ -508,7 +1760,7 @@ mod tests {
 
         create_container(storage.clone(), name, path);
 
-        let output = capture_output(|| start_container(storage.clone(), name));
+        let output = start_container(storage.clone(), name);
         assert_eq!(output, expected_output);
 
         delete_container(storage, name);
@@ -521,16 +1773,30 @@ mod tests {
             .expect("failed to create container");
     }
 
-    fn start_container(storage: Arc<TestStorage>, name: &str) {
-        OciOperations::new(&storage.clone(), name)
+    /// Runs the container to completion, returns its stdout.
+    fn start_container(storage: Arc<TestStorage>, name: &str) -> String {
+        let mut captured = OciOperations::new(&storage.clone(), name)
             .expect("failed to init OCI lifecycle struct")
-            .start()
-            .expect("failed to start container");
+            .do_start_captured(
+                MAIN_PROCESS_EXEC_ID,
+                ProcessOverrides::default(),
+                OutputCapture::Pipes,
+            )
+            .expect("failed to start container")
+            .expect("output isn't captured");
+        let mut output = String::new();
+
+        captured
+            .stdout
+            .read_to_string(&mut output)
+            .expect("failed to read output");
 
         OciOperations::new(&storage.clone(), name)
             .expect("failed to init OCI lifecycle struct")
             .wait()
             .expect("failed to wait container");
+
+        output
     }
 
     fn kill_container(storage: Arc<TestStorage>, name: &str, signal: i32) {
@@ -555,16 +1821,6 @@ mod tests {
         assert!(!output_string.contains(name));
     }
 
-    /// Captures stdout.
-    fn capture_output(fun: impl FnOnce()) -> String {
-        let mut buf = BufferRedirect::stdout().unwrap();
-        fun();
-        let mut output = String::new();
-        buf.read_to_string(&mut output).unwrap();
-
-        output
-    }
-
     fn prepare_bundle(cmd: &str) -> (Arc<TestStorage>, TempDir) {
         let tmpdir = tempfile::tempdir().unwrap();
         let storage = TestStorage::new(tmpdir.path()).unwrap();
@@ -0,0 +1,584 @@
+/// Behavior of the container, selected via runtime config
+/// annotations:
+///
+/// - `org.freebsd.knast.network`: `bridge` (the default)
+///   attaches the container to the `knast0` bridge, `host`
+///   shares the host's network stack, `none` leaves the
+///   container with a loopback only, `cni` delegates to the
+///   CNI plugin of `org.freebsd.knast.network.cni`, the path
+///   of the network config. See [`super::network::provider`].
+/// - `org.freebsd.knast.network.address`: IPv4 address of a
+///   `bridge` container, picked from the pool otherwise.
+/// - `org.freebsd.knast.network.interface`: name of the
+///   `bridge` or `cni` container's interface, the epair's
+///   one or `eth0` otherwise.
+/// - `org.freebsd.knast.network.allow`: comma separated
+///   containers and IPv4 networks, i.e. `db,10.0.0.0/8`,
+///   the `bridge` container talks to while containers are
+///   isolated, see [`super::network::setup`].
+/// - `org.freebsd.knast.network.egress`,
+///   `org.freebsd.knast.network.ingress`: bandwidth of the
+///   traffic a `bridge` container sends and receives, i.e.
+///   `10Mbit` or `512Kbit`, see [`rate`].
+/// - `org.freebsd.knast.network.ports`: comma separated
+///   ports of the NAT interface, forwarded to the `bridge`
+///   container's ones, i.e. `8080:80,5353:53/udp`, see
+///   [`PortMapping`].
+/// - `org.freebsd.knast.network.probe`: `true` (the default)
+///   fails the `bridge` container's creation unless it
+///   reaches its gateway, `false` skips the probe, i.e. on
+///   hosts, which drop the probes. See
+///   [`super::network::setup`].
+/// - `org.freebsd.knast.devfs.unhide`: comma separated
+///   devfs(8) patterns, i.e. `bpf*,pf`, exposed on top of the
+///   default devices.
+/// - `org.freebsd.knast.restart`: `no` (the default),
+///   `always`, `on-failure` or `on-failure:N` to give up
+///   after N restarts, see [`RestartPolicy`].
+/// - `org.freebsd.knast.jail.children`: number of jails the
+///   container may create, i.e. for CI runners. Nested jails
+///   are torn down along with the container.
+/// - `org.freebsd.knast.init`: `true` runs the process under
+///   knast's init, which reaps zombies and forwards signals,
+///   see [`super::init`].
+/// - `org.freebsd.knast.timezone`: zone of the container,
+///   i.e. `Europe/Berlin`, or `host` for the host's one.
+/// - `org.freebsd.knast.locale`: locale of the container's
+///   processes, i.e. `en_US.UTF-8`. See [`super::locale`].
+/// - `org.freebsd.knast.healthcheck`: healthcheck of the
+///   image, in the format of its config, i.e.
+///   `{"Test": ["CMD", "true"]}`. See [`super::health`].
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    net::Ipv4Addr,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Error};
+use baustelle::runtime_config::{Device, RuntimeConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::error::KnastError;
+
+pub const NETWORK_ANNOTATION: &str = "org.freebsd.knast.network";
+pub const ADDRESS_ANNOTATION: &str = "org.freebsd.knast.network.address";
+pub const INTERFACE_ANNOTATION: &str = "org.freebsd.knast.network.interface";
+pub const CNI_ANNOTATION: &str = "org.freebsd.knast.network.cni";
+pub const ALLOW_ANNOTATION: &str = "org.freebsd.knast.network.allow";
+pub const EGRESS_ANNOTATION: &str = "org.freebsd.knast.network.egress";
+pub const INGRESS_ANNOTATION: &str = "org.freebsd.knast.network.ingress";
+pub const PORTS_ANNOTATION: &str = "org.freebsd.knast.network.ports";
+pub const PROBE_ANNOTATION: &str = "org.freebsd.knast.network.probe";
+pub const DEVFS_UNHIDE_ANNOTATION: &str = "org.freebsd.knast.devfs.unhide";
+pub const RESTART_ANNOTATION: &str = "org.freebsd.knast.restart";
+pub const CHILDREN_ANNOTATION: &str = "org.freebsd.knast.jail.children";
+pub const INIT_ANNOTATION: &str = "org.freebsd.knast.init";
+pub const TIMEZONE_ANNOTATION: &str = "org.freebsd.knast.timezone";
+pub const LOCALE_ANNOTATION: &str = "org.freebsd.knast.locale";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NetworkMode {
+    Bridge,
+    Host,
+    None,
+    Cni,
+}
+
+/// Port of the host, which is forwarded to the container's
+/// one, i.e. `8080:80/tcp`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct PortMapping {
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl PortMapping {
+    /// Mapping of `value`, i.e. `8080:80` or `5353:53/udp`.
+    /// Ports are positive, TCP is the default protocol.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(2, '/');
+        let mut ports = parts.next()?.splitn(2, ':');
+        let port = |port: Option<&str>| -> Option<u16> {
+            match port?.trim().parse() {
+                Ok(0) | Err(_) => None,
+                Ok(port) => Some(port),
+            }
+        };
+        let host_port = port(ports.next())?;
+        let container_port = port(ports.next())?;
+        let protocol = match parts.next().map(str::trim) {
+            None | Some("tcp") => Protocol::Tcp,
+            Some("udp") => Protocol::Udp,
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            host_port,
+            container_port,
+            protocol,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "{}:{}/{}",
+            self.host_port, self.container_port, self.protocol
+        )
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => formatter.write_str("tcp"),
+            Protocol::Udp => formatter.write_str("udp"),
+        }
+    }
+}
+
+/// Whether the main process is started again once it
+/// exits, see [`super::OciOperations::wait`].
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RestartPolicy {
+    No,
+    Always,
+    /// Restarts processes exiting with non-zero status, at
+    /// most the given number of times, if any.
+    OnFailure(Option<u32>),
+}
+
+impl RestartPolicy {
+    /// Whether the process, which exited with `exit_status`
+    /// after being restarted `restarts` times, is restarted.
+    pub fn restarts(&self, exit_status: Option<i32>, restarts: u32) -> bool {
+        match self {
+            RestartPolicy::No => false,
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure(limit) => {
+                exit_status != Some(0)
+                    && limit.map_or(true, |limit| restarts < limit)
+            }
+        }
+    }
+}
+
+/// Annotations of the runtime config, which knast honors.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Annotations {
+    pub network: NetworkMode,
+    /// Network config of the `cni` network.
+    pub cni: Option<PathBuf>,
+    pub address: Option<Ipv4Addr>,
+    pub interface: Option<String>,
+    /// Containers and networks, see [`ALLOW_ANNOTATION`].
+    pub allow: Vec<String>,
+    /// Bandwidth limits, bits per second.
+    pub egress: Option<u32>,
+    pub ingress: Option<u32>,
+    /// Published ports, see [`PORTS_ANNOTATION`].
+    pub ports: Vec<PortMapping>,
+    /// Whether the container probes its gateway, see
+    /// [`PROBE_ANNOTATION`].
+    pub probe: bool,
+    pub devices: Vec<Device>,
+    pub restart: RestartPolicy,
+    /// `children.max` of the jail, nested jails are denied
+    /// unless it's positive.
+    pub children: u32,
+    /// Whether the main process runs under knast's init.
+    pub init: bool,
+    /// Zone name, relative to the zoneinfo directory, or
+    /// `host`.
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+}
+
+impl Annotations {
+    #[fehler::throws]
+    pub fn parse(config: &RuntimeConfig) -> Self {
+        let empty = BTreeMap::new();
+        let annotations = config.annotations.as_ref().unwrap_or(&empty);
+        let get = |name: &str| annotations.get(name).map(|value| value.trim());
+
+        let network = match get(NETWORK_ANNOTATION) {
+            None | Some("bridge") => NetworkMode::Bridge,
+            Some("host") => NetworkMode::Host,
+            Some("none") => NetworkMode::None,
+            Some("cni") => NetworkMode::Cni,
+            Some(other) => fehler::throw!(invalid(NETWORK_ANNOTATION, other)),
+        };
+        let cni = get(CNI_ANNOTATION).map(PathBuf::from);
+
+        if cni.is_some() != (network == NetworkMode::Cni) {
+            fehler::throw!(KnastError::ConfigInvalid(anyhow!(
+                "Runtime config: {} is required by cni network only",
+                CNI_ANNOTATION
+            )));
+        }
+
+        let address = match get(ADDRESS_ANNOTATION) {
+            Some(address) => Some(
+                address
+                    .parse()
+                    .map_err(|_| invalid(ADDRESS_ANNOTATION, address))?,
+            ),
+            None => None,
+        };
+        // IFNAMSIZ, along with the NUL
+        let interface = match get(INTERFACE_ANNOTATION) {
+            Some(name) if name.is_empty() || name.len() >= 16 => {
+                fehler::throw!(invalid(INTERFACE_ANNOTATION, name))
+            }
+            name => name.map(String::from),
+        };
+        let allow: Vec<String> =
+            list(get(ALLOW_ANNOTATION)).map(String::from).collect();
+        let limit = |annotation: &str| match get(annotation) {
+            Some(value) => match rate(value) {
+                Some(rate) => Ok(Some(rate)),
+                None => Err(invalid(annotation, value)),
+            },
+            None => Ok(None),
+        };
+        let (egress, ingress) =
+            (limit(EGRESS_ANNOTATION)?, limit(INGRESS_ANNOTATION)?);
+        let ports = list(get(PORTS_ANNOTATION))
+            .map(|port| {
+                PortMapping::parse(port)
+                    .ok_or_else(|| invalid(PORTS_ANNOTATION, port))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let probe = match get(PROBE_ANNOTATION) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| invalid(PROBE_ANNOTATION, value))?,
+            None => true,
+        };
+
+        for (annotation, given) in &[
+            (ADDRESS_ANNOTATION, address.is_some()),
+            (ALLOW_ANNOTATION, !allow.is_empty()),
+            (EGRESS_ANNOTATION, egress.is_some()),
+            (INGRESS_ANNOTATION, ingress.is_some()),
+            (PORTS_ANNOTATION, !ports.is_empty()),
+            (PROBE_ANNOTATION, !probe),
+        ] {
+            if *given && network != NetworkMode::Bridge {
+                fehler::throw!(KnastError::ConfigInvalid(anyhow!(
+                    "Runtime config: {} requires bridge network",
+                    annotation
+                )));
+            }
+        }
+
+        let attached =
+            matches!(network, NetworkMode::Bridge | NetworkMode::Cni);
+
+        if interface.is_some() && !attached {
+            fehler::throw!(KnastError::ConfigInvalid(anyhow!(
+                "Runtime config: {} requires bridge or cni network",
+                INTERFACE_ANNOTATION
+            )));
+        }
+
+        let devices = list(get(DEVFS_UNHIDE_ANNOTATION))
+            .map(|pattern| Device {
+                path: pattern.into(),
+                file_mode: None,
+                uid: None,
+                gid: None,
+            })
+            .collect();
+        let restart = match get(RESTART_ANNOTATION) {
+            None | Some("no") => RestartPolicy::No,
+            Some("always") => RestartPolicy::Always,
+            Some("on-failure") => RestartPolicy::OnFailure(None),
+            Some(other) => match other.strip_prefix("on-failure:") {
+                Some(limit) => RestartPolicy::OnFailure(Some(
+                    limit
+                        .parse()
+                        .map_err(|_| invalid(RESTART_ANNOTATION, other))?,
+                )),
+                None => fehler::throw!(invalid(RESTART_ANNOTATION, other)),
+            },
+        };
+        // Jail parameters are C ints
+        let children = match get(CHILDREN_ANNOTATION) {
+            Some(value) => value
+                .parse::<i32>()
+                .ok()
+                .and_then(|children| u32::try_from(children).ok())
+                .ok_or_else(|| invalid(CHILDREN_ANNOTATION, value))?,
+            None => 0,
+        };
+        let init = match get(INIT_ANNOTATION) {
+            Some(value) => {
+                value.parse().map_err(|_| invalid(INIT_ANNOTATION, value))?
+            }
+            None => false,
+        };
+        // Zones and locales name files of the host
+        let timezone = match get(TIMEZONE_ANNOTATION) {
+            Some(zone)
+                if zone.is_empty()
+                    || !Path::new(zone).components().all(|component| {
+                        matches!(component, Component::Normal(_))
+                    }) =>
+            {
+                fehler::throw!(invalid(TIMEZONE_ANNOTATION, zone))
+            }
+            zone => zone.map(String::from),
+        };
+        let locale = match get(LOCALE_ANNOTATION) {
+            Some(locale) if locale.is_empty() || locale.contains('/') => {
+                fehler::throw!(invalid(LOCALE_ANNOTATION, locale))
+            }
+            locale => locale.map(String::from),
+        };
+
+        Self {
+            network,
+            cni,
+            address,
+            interface,
+            allow,
+            egress,
+            ingress,
+            ports,
+            probe,
+            devices,
+            restart,
+            children,
+            init,
+            timezone,
+            locale,
+        }
+    }
+}
+
+/// Defaults of the unannotated config.
+impl Default for Annotations {
+    fn default() -> Self {
+        Self {
+            network: NetworkMode::Bridge,
+            cni: None,
+            address: None,
+            interface: None,
+            allow: Vec::new(),
+            egress: None,
+            ingress: None,
+            ports: Vec::new(),
+            probe: true,
+            devices: Vec::new(),
+            restart: RestartPolicy::No,
+            children: 0,
+            init: false,
+            timezone: None,
+            locale: None,
+        }
+    }
+}
+
+/// Bits per second of `value`, i.e. `10Mbit`, `512Kbit` or
+/// `1Gbit`, as dnctl(8) takes them. Rates are positive and
+/// fit dummynet(4) pipes.
+pub fn rate(value: &str) -> Option<u32> {
+    let value = value.to_lowercase();
+    let number = value.trim_end_matches(char::is_alphabetic);
+    let multiplier: u64 = match &value[number.len()..] {
+        "" | "bit" => 1,
+        "kbit" => 1_000,
+        "mbit" => 1_000_000,
+        "gbit" => 1_000_000_000,
+        _ => return None,
+    };
+    let rate = number.trim().parse::<u64>().ok()?.checked_mul(multiplier)?;
+
+    match rate {
+        0 => None,
+        rate => u32::try_from(rate).ok(),
+    }
+}
+
+/// Non-empty items of the comma separated `value`.
+fn list(value: Option<&str>) -> impl Iterator<Item = &str> {
+    value
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn invalid(annotation: &str, value: &str) -> Error {
+    KnastError::ConfigInvalid(anyhow!(
+        "Runtime config: invalid {} {:?}",
+        annotation,
+        value
+    ))
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader};
+
+    use super::*;
+
+    fn config(annotations: &[(&str, &str)]) -> RuntimeConfig {
+        let file =
+            File::open(test_helpers::fixture_path!("container/config.json"))
+                .expect("failed to open config file");
+        let mut config: RuntimeConfig =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("failed to parse config");
+
+        config.annotations = Some(
+            annotations
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        );
+
+        config
+    }
+
+    #[test]
+    fn test_defaults() {
+        let annotations = Annotations::parse(&config(&[])).unwrap();
+
+        assert_eq!(annotations, Annotations::default());
+
+        assert_eq!(annotations.network, NetworkMode::Bridge);
+        assert_eq!(annotations.address, None);
+        assert!(annotations.allow.is_empty());
+        assert_eq!((annotations.egress, annotations.ingress), (None, None));
+        assert!(annotations.probe);
+        assert!(annotations.devices.is_empty());
+        assert_eq!(annotations.restart, RestartPolicy::No);
+        assert_eq!(annotations.children, 0);
+        assert!(!annotations.init);
+    }
+
+    #[test]
+    fn test_parse() {
+        let annotations = Annotations::parse(&config(&[
+            (ADDRESS_ANNOTATION, "172.24.0.42"),
+            (INTERFACE_ANNOTATION, "eth0"),
+            (ALLOW_ANNOTATION, "db, 10.0.0.0/8,"),
+            (EGRESS_ANNOTATION, "10Mbit"),
+            (PORTS_ANNOTATION, "8080:80, 5353:53/udp"),
+            (PROBE_ANNOTATION, "false"),
+            (DEVFS_UNHIDE_ANNOTATION, "bpf*, pf"),
+            (RESTART_ANNOTATION, "on-failure:3"),
+            (CHILDREN_ANNOTATION, "8"),
+            (INIT_ANNOTATION, "true"),
+            (TIMEZONE_ANNOTATION, "Europe/Berlin"),
+            (LOCALE_ANNOTATION, "de_DE.UTF-8"),
+        ]))
+        .unwrap();
+
+        assert_eq!(annotations.address, Some(Ipv4Addr::new(172, 24, 0, 42)));
+        assert_eq!(annotations.interface.as_deref(), Some("eth0"));
+        assert_eq!(annotations.allow, vec!["db", "10.0.0.0/8"]);
+        assert_eq!(annotations.egress, Some(10_000_000));
+        assert_eq!(
+            annotations
+                .ports
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["8080:80/tcp", "5353:53/udp"]
+        );
+        assert!(!annotations.probe);
+        assert_eq!(
+            annotations
+                .devices
+                .iter()
+                .map(|device| device.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bpf*", "pf"]
+        );
+        assert_eq!(annotations.restart, RestartPolicy::OnFailure(Some(3)));
+        assert_eq!(annotations.children, 8);
+        assert!(annotations.init);
+        assert_eq!(annotations.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(annotations.locale.as_deref(), Some("de_DE.UTF-8"));
+
+        for invalid in &[
+            &[(NETWORK_ANNOTATION, "vlan")][..],
+            &[(ADDRESS_ANNOTATION, "172.24.0")],
+            &[
+                (NETWORK_ANNOTATION, "host"),
+                (ADDRESS_ANNOTATION, "10.0.0.1"),
+            ],
+            &[(NETWORK_ANNOTATION, "none"), (ALLOW_ANNOTATION, "db")],
+            &[(INGRESS_ANNOTATION, "fast")],
+            &[(PORTS_ANNOTATION, "8080")],
+            &[(PORTS_ANNOTATION, "8080:80/sctp")],
+            &[(NETWORK_ANNOTATION, "host"), (PORTS_ANNOTATION, "80:80")],
+            &[(PROBE_ANNOTATION, "no")],
+            &[(NETWORK_ANNOTATION, "none"), (PROBE_ANNOTATION, "false")],
+            &[(INTERFACE_ANNOTATION, "a_very_long_name0")],
+            &[(NETWORK_ANNOTATION, "cni")],
+            &[(CNI_ANNOTATION, "/usr/local/etc/cni/knast.conf")],
+            &[(RESTART_ANNOTATION, "on-failure:many")],
+            &[(CHILDREN_ANNOTATION, "-1")],
+            &[(CHILDREN_ANNOTATION, "4294967295")],
+            &[(INIT_ANNOTATION, "yes")],
+            &[(TIMEZONE_ANNOTATION, "../../etc/master.passwd")],
+            &[(TIMEZONE_ANNOTATION, "/etc/localtime")],
+            &[(LOCALE_ANNOTATION, "../C")],
+        ] {
+            assert!(Annotations::parse(&config(invalid)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cni() {
+        let annotations = Annotations::parse(&config(&[
+            (NETWORK_ANNOTATION, "cni"),
+            (CNI_ANNOTATION, "/usr/local/etc/cni/knast.conf"),
+            (INTERFACE_ANNOTATION, "eth0"),
+        ]))
+        .unwrap();
+
+        assert_eq!(annotations.network, NetworkMode::Cni);
+        assert_eq!(
+            annotations.cni,
+            Some(PathBuf::from("/usr/local/etc/cni/knast.conf"))
+        );
+    }
+
+    #[test]
+    fn test_rate() {
+        assert_eq!(rate("512Kbit"), Some(512_000));
+        assert_eq!(rate("1Gbit"), Some(1_000_000_000));
+        assert_eq!(rate("64000"), Some(64_000));
+        assert_eq!(rate("0Mbit"), None);
+        assert_eq!(rate("10Gbit"), None);
+        assert_eq!(rate("10MB"), None);
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        assert!(!RestartPolicy::No.restarts(Some(1), 0));
+        assert!(RestartPolicy::Always.restarts(Some(0), 100));
+        assert!(!RestartPolicy::OnFailure(None).restarts(Some(0), 0));
+        assert!(RestartPolicy::OnFailure(None).restarts(None, 100));
+        assert!(RestartPolicy::OnFailure(Some(2)).restarts(Some(1), 1));
+        assert!(!RestartPolicy::OnFailure(Some(2)).restarts(Some(1), 2));
+    }
+}
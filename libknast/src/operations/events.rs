@@ -0,0 +1,133 @@
+/// Lifecycle events of the containers. Events are put to the
+/// storage, so that subscribers in other processes, i.e.
+/// `runc events`, follow the containers the shim runs.
+/// Events expire shortly, subscribers get the ones emitted
+/// after they subscribe only.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use futures::{
+    future,
+    stream::{Stream, StreamExt},
+};
+use serde::{Deserialize, Serialize};
+use storage::{Collection, Event, Storage, StorageEngine};
+
+use super::health::HealthStatus;
+
+/// Keyed by container and the time of the event.
+const CONTAINER_EVENTS: Collection<(String, String), ContainerEvent> =
+    Collection::new(b"CONTAINER_EVENTS");
+/// Events outlive the polls of the storage engines, which
+/// don't notify of changes.
+const EVENT_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ContainerEvent {
+    pub id: String,
+    pub exec_id: String,
+    pub kind: EventKind,
+    pub at: SystemTime,
+}
+
+/// Limits being hit are to be reported once rctl(8) limits
+/// are applied.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Created,
+    Started,
+    ExecAdded,
+    Stopped {
+        exit_status: Option<i32>,
+    },
+    Deleted,
+    /// Health of the container changed, see
+    /// [`super::health`].
+    Health {
+        status: HealthStatus,
+    },
+}
+
+#[fehler::throws]
+pub fn emit(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+    exec_id: &str,
+    kind: EventKind,
+) {
+    let at = SystemTime::now();
+    let nanos = at.duration_since(UNIX_EPOCH)?.as_nanos();
+    // Zero-padded, so that keys are ordered by time
+    let time = format!("{:024}", nanos);
+    let event = ContainerEvent {
+        id: key.into(),
+        exec_id: exec_id.into(),
+        kind,
+        at,
+    };
+
+    tracing::debug!("Emitting {:?}", event);
+    CONTAINER_EVENTS.put_with_ttl(
+        storage,
+        &(key, &*time),
+        event,
+        EVENT_TTL,
+    )?;
+}
+
+/// Events of the container `key`, emitted after the call.
+#[fehler::throws]
+pub fn subscribe(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+) -> impl Stream<Item = Result<ContainerEvent, Error>> + Unpin {
+    let prefix = format!("{}/", key);
+
+    storage
+        .watch(CONTAINER_EVENTS.name(), prefix)?
+        .filter_map(|event| {
+            future::ready(match event {
+                Ok(Event::Put { value, .. }) => Some(Ok(value)),
+                // Expired events
+                Ok(Event::Remove { .. }) => None,
+                Err(error) => Some(Err(error)),
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use storage::TestStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_subscribe() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage = TestStorage::new(tmpdir.path()).unwrap();
+        let mut events = subscribe(&storage, "debian").unwrap();
+
+        emit(&storage, "ubuntu", "", EventKind::Created).unwrap();
+        emit(
+            &storage,
+            "debian",
+            "",
+            EventKind::Stopped {
+                exit_status: Some(3),
+            },
+        )
+        .unwrap();
+
+        let event = block_on(events.next()).unwrap().unwrap();
+
+        assert_eq!(event.id, "debian");
+        assert_eq!(
+            event.kind,
+            EventKind::Stopped {
+                exit_status: Some(3)
+            }
+        );
+    }
+}
@@ -0,0 +1,134 @@
+/// Exit statuses of processes reaped by someone else than
+/// `wait`, i.e. by the shim's reaper. Statuses are keyed by
+/// pid and consumed once read, since pids are reused.
+use std::time::SystemTime;
+
+use anyhow::Error;
+use nix::sys::wait::WaitStatus;
+use serde::{Deserialize, Serialize};
+use storage::{Collection, Storage, StorageEngine};
+
+pub(super) const EXIT_STATUSES: Collection<i32, ExitStatus> =
+    Collection::json(b"EXIT_STATUSES");
+
+/// Base of the exit codes of the processes killed by a
+/// signal, as shells report them, i.e. 137 for SIGKILL.
+const SIGNALED_EXIT_BASE: i32 = 128;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ExitStatus {
+    /// Exit code, 128 plus the signal number for the processes
+    /// killed by a signal. Unknown, if the status is lost.
+    pub code: Option<i32>,
+    pub exited_at: SystemTime,
+    /// Signal, which killed the process, if any.
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub core_dumped: bool,
+}
+
+impl ExitStatus {
+    pub fn now(code: Option<i32>) -> Self {
+        Self {
+            code,
+            exited_at: SystemTime::now(),
+            signal: None,
+            core_dumped: false,
+        }
+    }
+}
+
+impl From<WaitStatus> for ExitStatus {
+    fn from(status: WaitStatus) -> Self {
+        match status {
+            WaitStatus::Exited(_, code) => Self::now(Some(code)),
+            WaitStatus::Signaled(_, signal, core_dumped) => Self {
+                signal: Some(signal as i32),
+                core_dumped,
+                ..Self::now(Some(SIGNALED_EXIT_BASE + signal as i32))
+            },
+            _ => Self::now(None),
+        }
+    }
+}
+
+/// Records the status of the reaped process. Statuses other
+/// than exits (i.e. stops) are ignored.
+#[fehler::throws]
+pub fn record_exit(storage: &Storage<impl StorageEngine>, status: WaitStatus) {
+    let pid = match status {
+        WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _) => pid,
+        _ => return,
+    };
+
+    tracing::info!("Recording exit status {:?}", status);
+    EXIT_STATUSES.put(storage, &pid.as_raw(), ExitStatus::from(status))?;
+}
+
+/// Records an unknown status of `pid`, which exited while
+/// nobody watched, i.e. while the shim was restarting.
+#[fehler::throws]
+pub fn record_lost(storage: &Storage<impl StorageEngine>, pid: i32) {
+    tracing::info!("Exit status of {} is lost", pid);
+    EXIT_STATUSES.put(storage, &pid, ExitStatus::now(None))?;
+}
+
+/// Returns and forgets the recorded status of `pid`.
+#[fehler::throws]
+pub fn take_exit(
+    storage: &Storage<impl StorageEngine>,
+    pid: i32,
+) -> Option<ExitStatus> {
+    let status = EXIT_STATUSES.get(storage, &pid)?;
+
+    if status.is_some() {
+        EXIT_STATUSES.remove(storage, &pid)?;
+    }
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{sys::signal::Signal, unistd::Pid};
+    use storage::TestStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_recorded_exit() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage = TestStorage::new(tmpdir.path()).unwrap();
+
+        record_exit(&storage, WaitStatus::Exited(Pid::from_raw(42), 3))
+            .expect("failed to record exit status");
+
+        let status = take_exit(&storage, 42).unwrap();
+
+        assert_eq!(status.map(|status| status.code), Some(Some(3)));
+        assert_eq!(take_exit(&storage, 42).unwrap(), None);
+
+        record_lost(&storage, 42).expect("failed to record lost status");
+
+        let status = take_exit(&storage, 42).unwrap();
+
+        assert_eq!(status.map(|status| status.code), Some(None));
+    }
+
+    #[test]
+    fn test_signaled_exit() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage = TestStorage::new(tmpdir.path()).unwrap();
+        let status =
+            WaitStatus::Signaled(Pid::from_raw(42), Signal::SIGKILL, true);
+
+        record_exit(&storage, status).expect("failed to record exit status");
+
+        let status = take_exit(&storage, 42).unwrap().unwrap();
+
+        assert_eq!(status.code, Some(137));
+        assert_eq!(status.signal, Some(libc::SIGKILL));
+        assert!(status.core_dumped);
+    }
+}
@@ -0,0 +1,445 @@
+/// Health checks of the containers, the way Docker runs
+/// `HEALTHCHECK`s: the command runs in the jail every
+/// interval, the container is `healthy` once it succeeds and
+/// `unhealthy` once it fails `retries` times in a row.
+/// Failures within the start period don't count, while the
+/// container is `starting`.
+///
+/// Checks are given by the `org.freebsd.knast.healthcheck`
+/// annotation, which healthchecks of the images are copied
+/// to, see [`HEALTHCHECK_ANNOTATION`].
+use std::{
+    io::{self, Read},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Error};
+use baustelle::runtime_config::{user, RuntimeConfig, HEALTHCHECK_ANNOTATION};
+use common_lib::env;
+use jail::process::Jailed;
+use registratur::v2::domain::config::Healthcheck;
+use serde::{Deserialize, Serialize};
+use storage::{Collection, Storage, StorageEngine};
+
+use super::{
+    command_ext::CommandExt, output, prefixed_destination, storage_error,
+    EventKind, KnastError, OciOperations, MAIN_PROCESS_EXEC_ID,
+};
+
+/// Keyed by container.
+const CONTAINER_HEALTH: Collection<str, Health> =
+    Collection::new(b"CONTAINER_HEALTH");
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRIES: u32 = 3;
+/// Probes kept in the log, as many as Docker keeps.
+const LOG_LENGTH: usize = 5;
+/// Bytes of the probe output kept in the log.
+const OUTPUT_LIMIT: usize = 4096;
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(
+    Deserialize,
+    Serialize,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    strum_macros::AsRefStr,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "lowercase")]
+pub enum HealthStatus {
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Health {
+    pub status: HealthStatus,
+    /// Probes failed in a row.
+    pub failing_streak: u32,
+    /// The latest probes, oldest first.
+    pub log: Vec<Probe>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// Exit code of the command, -1 if it failed to run or
+    /// timed out.
+    pub exit_code: i32,
+    /// Combined stdout and stderr, truncated.
+    pub output: String,
+}
+
+/// Health check of the container.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    /// Command and its arguments, `CMD-SHELL` ones are run
+    /// by sh(1).
+    pub command: Vec<String>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub start_period: Duration,
+    pub retries: u32,
+}
+
+impl HealthCheck {
+    /// Health check of the container, unless it has none or
+    /// it's disabled, i.e. by `["NONE"]`.
+    #[fehler::throws]
+    pub fn parse(config: &RuntimeConfig) -> Option<Self> {
+        let annotation = config
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(HEALTHCHECK_ANNOTATION));
+        let healthcheck: Healthcheck = match annotation {
+            Some(annotation) => serde_json::from_str(annotation)
+                .with_context(|| {
+                    format!("Failed to parse {}", HEALTHCHECK_ANNOTATION)
+                })?,
+            None => return None,
+        };
+        let (kind, arguments) = match healthcheck.test.split_first() {
+            Some(test) => test,
+            None => return None,
+        };
+        let command = match kind.as_str() {
+            "NONE" => return None,
+            "CMD" => arguments.to_vec(),
+            "CMD-SHELL" => {
+                vec!["/bin/sh".into(), "-c".into(), arguments.join(" ")]
+            }
+            kind => fehler::throw!(anyhow!("Unknown healthcheck {}", kind)),
+        };
+
+        if command.is_empty() {
+            fehler::throw!(anyhow!("Healthcheck command is empty"));
+        }
+
+        // Zeroes stand for the defaults, as they do in images
+        let duration = |nanos: Option<u64>, default| {
+            nanos
+                .filter(|nanos| *nanos > 0)
+                .map_or(default, Duration::from_nanos)
+        };
+
+        Some(Self {
+            command,
+            interval: duration(healthcheck.interval, DEFAULT_INTERVAL),
+            timeout: duration(healthcheck.timeout, DEFAULT_TIMEOUT),
+            start_period: duration(
+                healthcheck.start_period,
+                Duration::from_secs(0),
+            ),
+            retries: healthcheck
+                .retries
+                .filter(|retries| *retries > 0)
+                .unwrap_or(DEFAULT_RETRIES),
+        })
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            status: HealthStatus::Starting,
+            failing_streak: 0,
+            log: vec![],
+        }
+    }
+}
+
+impl Health {
+    /// Accounts for the `probe`, made `elapsed` after the
+    /// monitoring started.
+    fn record(
+        &mut self,
+        probe: Probe,
+        check: &HealthCheck,
+        elapsed: Duration,
+    ) {
+        let starting = self.status == HealthStatus::Starting
+            && elapsed < check.start_period;
+
+        if probe.exit_code == 0 {
+            self.status = HealthStatus::Healthy;
+            self.failing_streak = 0;
+        } else if !starting {
+            self.failing_streak += 1;
+
+            if self.failing_streak >= check.retries {
+                self.status = HealthStatus::Unhealthy;
+            }
+        }
+
+        self.log.push(probe);
+        self.log.drain(..self.log.len().saturating_sub(LOG_LENGTH));
+    }
+}
+
+impl Probe {
+    fn failed(start: SystemTime, output: String) -> Self {
+        Self {
+            start,
+            end: SystemTime::now(),
+            exit_code: -1,
+            output,
+        }
+    }
+}
+
+/// Health of the container `key`, unless it isn't checked.
+#[fehler::throws]
+pub fn get(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+) -> Option<Health> {
+    CONTAINER_HEALTH.get(storage, key)?
+}
+
+#[fehler::throws]
+pub(super) fn forget(storage: &Storage<impl StorageEngine>, key: &str) {
+    CONTAINER_HEALTH.remove(storage, key)?;
+}
+
+impl<'a, T: StorageEngine> OciOperations<'a, T> {
+    /// Checks the health of the container, until it's
+    /// deleted. Probes are skipped while the main process
+    /// isn't running, i.e. is being restarted. Blocks, so
+    /// it's meant to run in a thread of its own.
+    #[fehler::throws(KnastError)]
+    pub fn monitor_health(&self) {
+        let check = match HealthCheck::parse(&self.config()?)? {
+            Some(check) => check,
+            None => return,
+        };
+        let started = Instant::now();
+        let mut health = Health::default();
+
+        CONTAINER_HEALTH
+            .put(self.storage, self.key.as_str(), health.clone())
+            .map_err(storage_error)?;
+        self.emit(
+            MAIN_PROCESS_EXEC_ID,
+            EventKind::Health {
+                status: health.status,
+            },
+        );
+
+        loop {
+            thread::sleep(check.interval);
+
+            match self.config() {
+                Err(KnastError::ContainerNotFound(_)) => return,
+                result => result.map(drop)?,
+            }
+
+            if !self.is_running(MAIN_PROCESS_EXEC_ID).unwrap_or(false) {
+                continue;
+            }
+
+            let start = SystemTime::now();
+            let probe = self.probe(&check).unwrap_or_else(|error| {
+                Probe::failed(start, format!("{:#}", error))
+            });
+            let status = health.status;
+
+            health.record(probe, &check, started.elapsed());
+            CONTAINER_HEALTH
+                .put(self.storage, self.key.as_str(), health.clone())
+                .map_err(storage_error)?;
+
+            if health.status != status {
+                tracing::info!("Container is {}", health.status.as_ref());
+                self.emit(
+                    MAIN_PROCESS_EXEC_ID,
+                    EventKind::Health {
+                        status: health.status,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Runs the command of the `check` in the jail, as the
+    /// main process is run, but without stdin.
+    #[fehler::throws]
+    fn probe(&self, check: &HealthCheck) -> Probe {
+        let process = self
+            .config()?
+            .process
+            .ok_or_else(|| anyhow!("Runtime config: process is required"))?;
+        let rootfs = self.rootfs()?;
+        let path = rootfs.as_ref();
+        let envs = env::parse(process.env.as_deref().unwrap_or(&[]))?;
+        let cwd = prefixed_destination(&path, &process.cwd);
+        let (uid, gid) = match &process.user.username {
+            Some(username) => user::parse(username.clone(), path)?,
+            None => (process.user.uid, process.user.gid),
+        };
+        let jail = self.retrieve_jail()?;
+        let (reader, writer) = output::pipe()?;
+        let mut command = Command::new(&check.command[0]);
+
+        command
+            .jail(&jail)
+            .args(&check.command[1..])
+            .env_clear()
+            .envs(envs)
+            .current_dir(cwd)
+            .uid(uid)
+            .gid(gid)
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(writer.try_clone()?))
+            .stderr(Stdio::from(writer));
+
+        let start = SystemTime::now();
+        let mut child = command.spawn()?;
+        // Otherwise the writable ends stay open, and the
+        // output never ends
+        drop(command);
+
+        let (sender, receiver) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("knast-health".into())
+            .spawn(move || sender.send(read_output(reader)))?;
+
+        let deadline = Instant::now() + check.timeout;
+        let exit_code = loop {
+            if let Some(status) = child.try_wait()? {
+                break status.code().unwrap_or(-1);
+            }
+
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+
+                return Probe::failed(
+                    start,
+                    format!(
+                        "Health check exceeded timeout ({:?})",
+                        check.timeout
+                    ),
+                );
+            }
+
+            thread::sleep(PROBE_POLL_INTERVAL);
+        };
+        // Descendants of the command might hold the output
+        let output = receiver
+            .recv_timeout(PROBE_POLL_INTERVAL)
+            .unwrap_or_default();
+
+        Probe {
+            start,
+            end: SystemTime::now(),
+            exit_code,
+            output,
+        }
+    }
+}
+
+/// Output of the probe, up to the limit. The rest is read
+/// and dropped, so that the command doesn't block on it.
+fn read_output(reader: impl Read) -> String {
+    let mut reader = reader.take(OUTPUT_LIMIT as u64);
+    let mut output = vec![];
+
+    if let Err(error) = reader.read_to_end(&mut output) {
+        tracing::error!("Failed to read health check output: {}", error);
+    }
+
+    let _ = io::copy(&mut reader.into_inner(), &mut io::sink());
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(healthcheck: &str) -> RuntimeConfig {
+        let mut config = RuntimeConfig::spec();
+
+        config
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(HEALTHCHECK_ANNOTATION.into(), healthcheck.into());
+
+        config
+    }
+
+    fn probe(exit_code: i32) -> Probe {
+        Probe {
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            exit_code,
+            output: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let check = HealthCheck::parse(&config(
+            r#"{"Test": ["CMD-SHELL", "pgrep nginx"], "Retries": 0,
+                "Interval": 5000000000}"#,
+        ))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(check.command, ["/bin/sh", "-c", "pgrep nginx"]);
+        assert_eq!(check.interval, Duration::from_secs(5));
+        assert_eq!(check.timeout, DEFAULT_TIMEOUT);
+        assert_eq!(check.retries, DEFAULT_RETRIES);
+
+        assert_eq!(HealthCheck::parse(&RuntimeConfig::spec()).unwrap(), None);
+        assert_eq!(
+            HealthCheck::parse(&config(r#"{"Test": ["NONE"]}"#)).unwrap(),
+            None
+        );
+        assert!(HealthCheck::parse(&config(r#"{"Test": ["CMD"]}"#)).is_err());
+        assert!(HealthCheck::parse(&config(r#"{"Test": ["RUN"]}"#)).is_err());
+    }
+
+    #[test]
+    fn test_record() {
+        let check = HealthCheck {
+            command: vec!["true".into()],
+            interval: DEFAULT_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+            start_period: Duration::from_secs(60),
+            retries: 2,
+        };
+        let mut health = Health::default();
+
+        // Within the start period
+        health.record(probe(1), &check, Duration::from_secs(30));
+        assert_eq!(health.status, HealthStatus::Starting);
+        assert_eq!(health.failing_streak, 0);
+
+        health.record(probe(0), &check, Duration::from_secs(40));
+        assert_eq!(health.status, HealthStatus::Healthy);
+
+        // Failures count once the container is healthy
+        health.record(probe(1), &check, Duration::from_secs(50));
+        assert_eq!(health.status, HealthStatus::Healthy);
+        health.record(probe(1), &check, Duration::from_secs(80));
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert_eq!(health.failing_streak, 2);
+
+        for _ in 0..LOG_LENGTH {
+            health.record(probe(0), &check, Duration::from_secs(90));
+        }
+
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert_eq!(health.log.len(), LOG_LENGTH);
+    }
+}
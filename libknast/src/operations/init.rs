@@ -0,0 +1,136 @@
+/// Init of the containers, for images lacking a proper one:
+/// `knast-init` runs as the main process of the jail, runs
+/// the configured process, forwards signals to it and reaps
+/// the orphans. Asked for by `org.freebsd.knast.init`.
+///
+/// The binary is executed from the host via fexecve(2), once
+/// the process is attached to the jail, so that it's neither
+/// copied nor mounted into the container. Containers of
+/// other OSes need it to be linked statically.
+use std::{
+    env,
+    ffi::CString,
+    fs::File,
+    io::Error as IoError,
+    os::unix::{io::AsRawFd, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Error};
+
+use crate::procdesc::pointers;
+
+/// Path of the init binary, `knast-init` next to the
+/// current executable by default.
+pub const INIT_VARIABLE: &str = "KNAST_INIT";
+const INIT_BINARY: &str = "knast-init";
+
+/// Init, which is executed in place of the process.
+pub(super) struct Init {
+    binary: File,
+    args: Vec<CString>,
+    envs: Vec<CString>,
+    /// NULL-terminated pointers to the strings, prepared
+    /// beforehand: the forked child mustn't allocate.
+    arg_pointers: Vec<*const libc::c_char>,
+    env_pointers: Vec<*const libc::c_char>,
+}
+
+// Pointers refer to the strings the init owns, which are
+// never modified.
+unsafe impl Send for Init {}
+unsafe impl Sync for Init {}
+
+/// Path of the init binary.
+#[fehler::throws]
+pub fn path() -> PathBuf {
+    match env::var_os(INIT_VARIABLE) {
+        Some(path) => path.into(),
+        None => env::current_exe()?.with_file_name(INIT_BINARY),
+    }
+}
+
+impl Init {
+    /// Init at `path`, which runs the `command` with `args`
+    /// and `envs`.
+    #[fehler::throws]
+    pub(super) fn new(
+        path: &Path,
+        command: &str,
+        args: &[String],
+        envs: &[(String, String)],
+    ) -> Self {
+        let binary = File::open(path)
+            .with_context(|| format!("Failed to open init {:?}", path))?;
+        let args = [INIT_BINARY, "--", command]
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain(args.iter().cloned())
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let envs = envs
+            .iter()
+            .map(|(name, value)| CString::new(format!("{}={}", name, value)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self {
+            binary,
+            arg_pointers: pointers(&args),
+            env_pointers: pointers(&envs),
+            args,
+            envs,
+        }
+    }
+
+    /// Makes the `command` execute the init instead. Hooks
+    /// of the command, i.e. attaching to the jail, are to be
+    /// added beforehand.
+    pub(super) fn attach(self, command: &mut Command) {
+        unsafe {
+            command.pre_exec(move || {
+                libc::fexecve(
+                    self.binary.as_raw_fd(),
+                    self.arg_pointers.as_ptr(),
+                    self.env_pointers.as_ptr(),
+                );
+
+                // Returns on failure only
+                Err(IoError::last_os_error())
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let binary = tempfile::NamedTempFile::new().unwrap();
+        let init = Init::new(
+            binary.path(),
+            "nginx",
+            &["-g".into(), "daemon off;".into()],
+            &[("PATH".into(), "/bin".into())],
+        )
+        .unwrap();
+
+        assert_eq!(
+            init.args,
+            ["knast-init", "--", "nginx", "-g", "daemon off;"]
+                .iter()
+                .map(|arg| CString::new(*arg).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(init.envs, vec![CString::new("PATH=/bin").unwrap()]);
+        assert_eq!(init.arg_pointers.len(), 6);
+        assert!(init.arg_pointers[5].is_null());
+        assert_eq!(init.env_pointers[0], init.envs[0].as_ptr());
+
+        assert!(
+            Init::new(Path::new("/nonexistent"), "nginx", &[], &[]).is_err()
+        );
+    }
+}
@@ -0,0 +1,125 @@
+/// Jails of the containers. Container keys, i.e. 64 hex
+/// digits of containerd ids prefixed by their namespace,
+/// are too long for jail names and may hold characters
+/// jails reject, so jails are named by a hash of the key.
+use anyhow::Error;
+use jail::RunningJail;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use storage::{Collection, Storage, StorageEngine};
+
+const JAIL_NAMES: Collection<str, JailName> = Collection::new(b"JAIL_NAMES");
+const PREFIX: &str = "knast-";
+/// Hex digits of the hash, collisions are unlikely enough.
+const HASH_LENGTH: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct JailName {
+    name: String,
+    /// Known once the jail is started.
+    jid: Option<i32>,
+}
+
+/// Name of the container's jail, `knast-` followed by the
+/// truncated SHA-256 of the `key`.
+pub fn derive(key: &str) -> String {
+    let hash = hex::encode(digest(&SHA256, key.as_bytes()));
+
+    [PREFIX, &hash[..HASH_LENGTH]].concat()
+}
+
+/// Names the jail of the container, before it's started.
+#[fehler::throws]
+pub fn assign(storage: &Storage<impl StorageEngine>, key: &str) -> String {
+    let name = derive(key);
+    let jail_name = JailName {
+        name: name.clone(),
+        jid: None,
+    };
+
+    JAIL_NAMES.put(storage, key, jail_name)?;
+
+    name
+}
+
+/// Records the jid of the started jail, so that it's looked
+/// up by the jid rather than by the name.
+#[fehler::throws]
+pub fn started(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+    jail: RunningJail,
+) {
+    let jail_name = JailName {
+        name: jail.name()?,
+        jid: Some(jail.jid),
+    };
+
+    JAIL_NAMES.put(storage, key, jail_name)?;
+}
+
+/// The running jail of the container, if any. Jails of the
+/// containers created before the jails were named by hash
+/// are named by the key.
+#[fehler::throws]
+pub fn find(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+) -> Option<RunningJail> {
+    let jail_name = match JAIL_NAMES.get(storage, key)? {
+        Some(jail_name) => jail_name,
+        None => return RunningJail::from_name(key).ok(),
+    };
+
+    // Jids are reused, so the name is checked too
+    let by_jid = jail_name
+        .jid
+        .and_then(|jid| RunningJail::from_jid(jid).ok())
+        .filter(|jail| jail.name().ok().as_ref() == Some(&jail_name.name));
+
+    by_jid.or_else(|| RunningJail::from_name(&jail_name.name).ok())
+}
+
+/// Forgets the jail of the deleted container.
+#[fehler::throws]
+pub fn forget(storage: &Storage<impl StorageEngine>, key: &str) {
+    JAIL_NAMES.remove(storage, key)?;
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::TestStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_derive() {
+        let id = "4bc1f47a0e3b4d7c9e8f1a2b3c4d5e6f\
+                  7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c2d";
+        let name = derive(&format!("default:{}", id));
+
+        assert_eq!(name.len(), PREFIX.len() + HASH_LENGTH);
+        assert!(name.starts_with(PREFIX));
+        assert!(name[PREFIX.len()..].chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(name, derive(&format!("default:{}", id)));
+        assert_ne!(name, derive(&format!("k8s.io:{}", id)));
+    }
+
+    #[test]
+    fn test_assign() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let storage = TestStorage::new(tmpdir.path()).unwrap();
+
+        let name = assign(&storage, "default:nginx").unwrap();
+
+        assert_eq!(name, derive("default:nginx"));
+        assert_eq!(
+            JAIL_NAMES.get(&storage, "default:nginx").unwrap(),
+            Some(JailName { name, jid: None })
+        );
+
+        forget(&storage, "default:nginx").unwrap();
+
+        assert_eq!(JAIL_NAMES.get(&storage, "default:nginx").unwrap(), None);
+    }
+}
@@ -0,0 +1,273 @@
+/// Timezone and locale of the containers, which images
+/// frequently lack, so that containers log in UTC.
+///
+/// The zone of `org.freebsd.knast.timezone`, the host's one
+/// if it's `host`, is copied to the container's
+/// `/etc/localtime` at creation, and processes get `TZ`.
+/// Processes get `LANG` of `org.freebsd.knast.locale`, and
+/// FreeBSD containers lacking the locale get its data from
+/// the host.
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Error};
+
+use super::annotations::Annotations;
+use crate::filesystem::prefixed_destination;
+
+const LOCALTIME: &str = "etc/localtime";
+const ZONEINFO: &str = "usr/share/zoneinfo";
+/// Name of the host's zone, as tzsetup(8) records it.
+const ZONE_NAME: &str = "var/db/zoneinfo";
+const LOCALES: &str = "usr/share/locale";
+/// Zone of the container's `/etc/localtime`, for zones the
+/// container lacks data of.
+const LOCALTIME_ZONE: &str = ":/etc/localtime";
+
+/// Copies the zone and the locale data from the `host`
+/// root to the `rootfs` of the container. Locale data is
+/// copied to FreeBSD containers only.
+#[fehler::throws]
+pub(super) fn install(
+    annotations: &Annotations,
+    host: &Path,
+    rootfs: &Path,
+    freebsd: bool,
+) {
+    if let Some(timezone) = &annotations.timezone {
+        let zone = match timezone.as_str() {
+            "host" => host.join(LOCALTIME),
+            name => host.join(ZONEINFO).join(name),
+        };
+
+        if !zone.is_file() {
+            fehler::throw!(anyhow!("Timezone {} is unknown", timezone));
+        }
+
+        let destination = destination(rootfs, LOCALTIME)?;
+
+        fs::copy(&zone, &destination).with_context(|| {
+            format!("Failed to copy {:?} to {:?}", zone, destination)
+        })?;
+    }
+
+    match &annotations.locale {
+        Some(locale) if freebsd => {
+            let relative = Path::new(LOCALES).join(locale);
+
+            if prefixed_destination(rootfs, &relative).is_dir() {
+                return;
+            }
+
+            let source = host.join(&relative);
+
+            // POSIX and C locales are built in
+            if !source.is_dir() {
+                tracing::warn!("Host lacks data of {} locale", locale);
+                return;
+            }
+
+            let directory = destination(rootfs, &relative)?;
+
+            fs::create_dir(&directory)?;
+
+            // Categories, some of which are links to the
+            // ones of other locales
+            for entry in fs::read_dir(&source)? {
+                let entry = entry?;
+
+                fs::copy(entry.path(), directory.join(entry.file_name()))?;
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Adds `TZ` and `LANG` to the environment of the process,
+/// unless they're set already.
+pub(super) fn add_env(
+    annotations: &Annotations,
+    host: &Path,
+    rootfs: &Path,
+    envs: &mut Vec<(String, String)>,
+) {
+    let zone = annotations.timezone.as_ref().map(|timezone| {
+        let name = match timezone.as_str() {
+            "host" => host_zone(host),
+            name => Some(name.into()),
+        };
+
+        // Names of zones the container lacks data of stand
+        // for UTC, so the copied zone is named instead
+        let zoneinfo = prefixed_destination(rootfs, ZONEINFO);
+
+        name.filter(|name| zoneinfo.join(name).is_file())
+            .unwrap_or_else(|| LOCALTIME_ZONE.into())
+    });
+    let variables = vec![("TZ", zone), ("LANG", annotations.locale.clone())];
+
+    for (name, value) in variables {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if !envs.iter().any(|(existing, _)| existing == name) {
+            envs.push((name.into(), value));
+        }
+    }
+}
+
+/// Name of the host's zone, if it's known.
+fn host_zone(host: &Path) -> Option<String> {
+    let recorded = fs::read_to_string(host.join(ZONE_NAME))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+
+    recorded.or_else(|| {
+        let target = fs::read_link(host.join(LOCALTIME)).ok()?;
+        let zoneinfo = Path::new("/").join(ZONEINFO);
+
+        Some(target.strip_prefix(zoneinfo).ok()?.to_str()?.into())
+    })
+}
+
+/// Path of the `relative` file in the `rootfs`, which is
+/// written to. Symbolic links of the image would lead out of
+/// the container: existing file is removed, and its parents
+/// mustn't be links.
+#[fehler::throws]
+fn destination(rootfs: &Path, relative: impl AsRef<Path>) -> PathBuf {
+    let destination = prefixed_destination(rootfs, relative);
+    let mut parent = rootfs.to_path_buf();
+    let parents = destination
+        .strip_prefix(rootfs)?
+        .parent()
+        .map(Path::components)
+        .into_iter()
+        .flatten();
+
+    for component in parents {
+        if let Component::Normal(component) = component {
+            parent.push(component);
+        }
+
+        match fs::symlink_metadata(&parent) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                fehler::throw!(anyhow!("{:?} is a symbolic link", parent));
+            }
+            Ok(_) => (),
+            Err(_) => fs::create_dir(&parent)?,
+        }
+    }
+
+    if fs::symlink_metadata(&destination).is_ok() {
+        if destination.is_dir() {
+            fehler::throw!(anyhow!("{:?} is a directory", destination));
+        }
+
+        fs::remove_file(&destination)?;
+    }
+
+    destination
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn annotations(timezone: &str, locale: &str) -> Annotations {
+        Annotations {
+            timezone: Some(timezone.into()),
+            locale: Some(locale.into()),
+            ..Annotations::default()
+        }
+    }
+
+    #[test]
+    fn test_install() {
+        let host = tempfile::tempdir().unwrap();
+        let rootfs = tempfile::tempdir().unwrap();
+        let zone = host.path().join(ZONEINFO).join("Europe");
+        let locale = host.path().join(LOCALES).join("de_DE.UTF-8");
+
+        fs::create_dir_all(&zone).unwrap();
+        fs::write(zone.join("Berlin"), "TZif").unwrap();
+        fs::create_dir_all(&locale).unwrap();
+        fs::write(locale.join("LC_TIME"), "Mo").unwrap();
+        fs::create_dir(rootfs.path().join("etc")).unwrap();
+        // Image links to its zone, which mustn't be followed
+        symlink(zone.join("Berlin"), rootfs.path().join(LOCALTIME)).unwrap();
+
+        let annotations = annotations("Europe/Berlin", "de_DE.UTF-8");
+
+        install(&annotations, host.path(), rootfs.path(), true).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(rootfs.path().join(LOCALTIME)).unwrap(),
+            "TZif"
+        );
+        assert_eq!(
+            fs::read_to_string(
+                rootfs.path().join(LOCALES).join("de_DE.UTF-8/LC_TIME")
+            )
+            .unwrap(),
+            "Mo"
+        );
+
+        let unknown = annotations("Mars/Olympus", "de_DE.UTF-8");
+
+        assert!(install(&unknown, host.path(), rootfs.path(), true).is_err());
+    }
+
+    #[test]
+    fn test_add_env() {
+        let host = tempfile::tempdir().unwrap();
+        let rootfs = tempfile::tempdir().unwrap();
+        let mut envs = vec![("LANG".into(), "C".into())];
+
+        fs::create_dir_all(host.path().join("var/db")).unwrap();
+        fs::write(host.path().join(ZONE_NAME), "Asia/Tokyo\n").unwrap();
+
+        add_env(
+            &annotations("host", "ja_JP.UTF-8"),
+            host.path(),
+            rootfs.path(),
+            &mut envs,
+        );
+
+        // The container lacks the zone data
+        assert_eq!(
+            envs,
+            vec![
+                ("LANG".into(), "C".into()),
+                ("TZ".into(), LOCALTIME_ZONE.into())
+            ]
+        );
+
+        fs::create_dir_all(rootfs.path().join(ZONEINFO).join("Asia")).unwrap();
+        fs::write(rootfs.path().join(ZONEINFO).join("Asia/Tokyo"), "")
+            .unwrap();
+        envs.clear();
+
+        add_env(
+            &annotations("host", "ja_JP.UTF-8"),
+            host.path(),
+            rootfs.path(),
+            &mut envs,
+        );
+
+        assert_eq!(
+            envs,
+            vec![
+                ("TZ".into(), "Asia/Tokyo".into()),
+                ("LANG".into(), "ja_JP.UTF-8".into())
+            ]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+/// Migrations of the collections, which are critical to
+/// upgrades. Structs here are frozen copies of the schema
+/// the migration starts from, they mustn't follow changes of
+/// the originals.
+use std::{collections::BTreeMap, net::Ipv4Addr, time::SystemTime};
+
+use anyhow::Error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use storage::{schema::Migration, Storage, StorageEngine};
+
+use super::{
+    exits::EXIT_STATUSES,
+    network::{CONTAINER_ADDRESSES, CONTAINER_ADDRESS_KEY},
+    CONTAINER_PROCESSES,
+};
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        collection: CONTAINER_PROCESSES.name(),
+        version: 1,
+        migrate: processes_to_json,
+    },
+    Migration {
+        collection: EXIT_STATUSES.name(),
+        version: 1,
+        migrate: exit_statuses_to_json,
+    },
+    Migration {
+        collection: CONTAINER_ADDRESSES.name(),
+        version: 1,
+        migrate: network_state_to_json,
+    },
+];
+
+#[derive(Deserialize, Serialize)]
+enum ProcessStatusV0 {
+    Created,
+    Starting,
+    Running,
+    Stopped,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OciStatusV0 {
+    oci_version: String,
+    status: ProcessStatusV0,
+    pid: i32,
+    jid: i32,
+    exit_status: Option<i32>,
+    exited_at: SystemTime,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ExitStatusV0 {
+    code: Option<i32>,
+    exited_at: SystemTime,
+}
+
+type ContainerAddressesV0 = BTreeMap<String, (String, Ipv4Addr, Ipv4Addr)>;
+
+/// Migrates collections of containers to the current
+/// schema. Tools are to migrate once the storage is opened,
+/// before containers are operated on.
+#[fehler::throws]
+pub fn migrate(storage: &Storage<impl StorageEngine>) {
+    storage.migrate(MIGRATIONS)?;
+}
+
+#[fehler::throws]
+fn processes_to_json(_: &[u8], value: &[u8]) -> Vec<u8> {
+    to_json::<OciStatusV0>(value)?
+}
+
+#[fehler::throws]
+fn exit_statuses_to_json(_: &[u8], value: &[u8]) -> Vec<u8> {
+    to_json::<ExitStatusV0>(value)?
+}
+
+#[fehler::throws]
+fn network_state_to_json(key: &[u8], value: &[u8]) -> Vec<u8> {
+    if key == CONTAINER_ADDRESS_KEY.as_bytes() {
+        to_json::<ContainerAddressesV0>(value)?
+    } else {
+        // Pools are heaps, kept in the order they're stored
+        to_json::<Vec<Ipv4Addr>>(value)?
+    }
+}
+
+/// Re-encodes bincode value as JSON. Values, which are JSON
+/// already, are kept.
+#[fehler::throws]
+fn to_json<T: DeserializeOwned + Serialize>(value: &[u8]) -> Vec<u8> {
+    if serde_json::from_slice::<serde_json::Value>(value).is_ok() {
+        return value.into();
+    }
+
+    serde_json::to_vec(&bincode::deserialize::<T>(value)?)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::{schema, TestStorage};
+
+    #[test]
+    fn test_legacy_values() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let status = ExitStatusV0 {
+            code: Some(1),
+            exited_at: SystemTime::UNIX_EPOCH,
+        };
+        let storage =
+            TestStorage::new(dir.path()).expect("Unable to initialize cache");
+        let engine = storage.engine();
+        let collection = EXIT_STATUSES.name();
+
+        // Written before JSON encoding, i.e. by an older version
+        StorageEngine::put(
+            engine,
+            schema::SCHEMA_STORAGE_KEY,
+            collection,
+            bincode::serialize(&0u32).unwrap(),
+        )
+        .unwrap();
+        StorageEngine::put(
+            engine,
+            collection,
+            b"42",
+            bincode::serialize(&status).unwrap(),
+        )
+        .unwrap();
+
+        migrate(&storage).expect("failed to migrate");
+
+        let migrated = EXIT_STATUSES.get(&storage, &42).unwrap().unwrap();
+        let raw = StorageEngine::get(engine, collection, b"42")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(migrated.code, Some(1));
+        assert!(serde_json::from_slice::<serde_json::Value>(&raw).is_ok());
+        assert_eq!(schema::version(engine, collection).unwrap(), 1);
+    }
+}
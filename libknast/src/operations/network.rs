@@ -1,58 +1,704 @@
+mod cni;
+mod provider;
+
 use std::{
-    collections::{BTreeMap, BinaryHeap},
-    net::Ipv4Addr,
+    collections::{BTreeMap, BTreeSet},
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    thread,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use ipnetwork::Ipv4Network;
 use jail::RunningJail;
 use netzwerk::{
+    arp, dummynet,
     interface::Interface,
     nat::Nat,
-    pf::Pf,
-    range::{broadcast, mask, range as ip_range},
+    pf::{self, Filter, NatConfig, Pf, Pool, Redirect, Shaping},
+    range::{broadcast, mask},
     route,
 };
-use storage::{Storage, StorageEngine};
+use serde::{Deserialize, Serialize};
+use storage::{Collection, Storage, StorageEngine};
+
+use super::{utils::Errors, Annotations, PortMapping, Protocol};
+
+pub use cni::Cni;
+pub use provider::{provider, Bridge, NetworkProvider, NoNetwork};
 
-const NETWORK_STATE_STORAGE_KEY: &[u8] = b"NETWORK_STATE";
-const CONTAINER_ADDRESS_STORAGE_KEY: &[u8] = b"CONTAINER_ADDRESS";
 const DEFAULT_NETWORK: &str = "172.24.0.0/16";
 const DEFAULT_BRIDGE: &str = "knast0";
+/// NAT options, so that hosts with pf rulesets of their own
+/// avoid collisions, see [`nat_config`].
+const NAT_ANCHOR_VARIABLE: &str = "KNAST_NAT_ANCHOR";
+const NAT_TABLE_VARIABLE: &str = "KNAST_NAT_TABLE";
+const NAT_PORTS_VARIABLE: &str = "KNAST_NAT_PORTS";
+const NAT_STATIC_PORT_VARIABLE: &str = "KNAST_NAT_STATIC_PORT";
+const NAT_POOL_VARIABLE: &str = "KNAST_NAT_POOL";
+/// `false` isolates the containers from each other, like
+/// Docker's `--icc=false`.
+const ICC_VARIABLE: &str = "KNAST_ICC";
+/// Leases of the containers being set up expire after the
+/// timeout, so that addresses of failed setups are reused.
+const LEASE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Addresses in use on the bridge are skipped for a while.
+const CONFLICT_TIMEOUT: Duration = Duration::from_secs(600);
+/// ARP probes of an address, and the wait for answers.
+const PROBES: u32 = 2;
+const PROBE_WAIT: Duration = Duration::from_millis(250);
+/// Attempts to connect to the gateway, and the timeout of
+/// each. The gateway is local, so that it answers at once,
+/// unless pf drops the probes: creation stalls for under a
+/// second then. `org.freebsd.knast.network.probe=false`
+/// skips the probe, see
+/// [`super::annotations::PROBE_ANNOTATION`].
+const REACHABILITY_ATTEMPTS: u32 = 3;
+const REACHABILITY_TIMEOUT: Duration = Duration::from_millis(200);
+const REACHABILITY_DELAY: Duration = Duration::from_millis(100);
+/// Port of the gateway, which the probes connect to.
+const REACHABILITY_PORT: u16 = 9;
 
 type ContainerAddressStorage = BTreeMap<String, (String, Ipv4Addr, Ipv4Addr)>;
 
+/// Leases of the addresses, keyed by address.
+const ADDRESS_LEASES: Collection<str, Lease> =
+    Collection::json(b"NETWORK_LEASES");
+/// Free addresses of the previous allocator, keyed by
+/// network. Imported into the leases, see [`import_pool`].
+const LEGACY_POOLS: Collection<str, Vec<Ipv4Addr>> =
+    Collection::json(b"NETWORK_STATE");
+/// Addresses of containers, kept under `CONTAINER_ADDRESS`
+/// key of the same collection.
+pub(super) const CONTAINER_ADDRESSES: Collection<
+    str,
+    ContainerAddressStorage,
+> = Collection::json(b"NETWORK_STATE");
+pub(super) const CONTAINER_ADDRESS_KEY: &str = "CONTAINER_ADDRESS";
+/// Containers and networks the isolated containers talk to,
+/// keyed by container.
+const ISOLATED_CONTAINERS: Collection<str, Vec<String>> =
+    Collection::new(b"NETWORK_ISOLATION");
+/// dummynet(4) pipes of the containers, kept under
+/// `CONTAINER_PIPES` key, so that pipes are allocated
+/// atomically.
+const CONTAINER_PIPES: Collection<str, BTreeMap<String, Pipes>> =
+    Collection::json(b"NETWORK_STATE");
+const CONTAINER_PIPES_KEY: &str = "CONTAINER_PIPES";
+
+/// Ports of the containers, kept under `PUBLISHED_PORTS`
+/// key, so that host ports are taken atomically.
+const PUBLISHED_PORTS: Collection<str, BTreeMap<String, Published>> =
+    Collection::json(b"NETWORK_STATE");
+const PUBLISHED_PORTS_KEY: &str = "PUBLISHED_PORTS";
+
+/// Names of the containers' interfaces, keyed by container.
+const CONTAINER_INTERFACES: Collection<str, String> =
+    Collection::new(b"NETWORK_INTERFACES");
+
+/// The container's end of the bridge network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    /// Host's end of the epair, a member of the bridge.
+    pub host_interface: String,
+    /// Container's end of the epair.
+    pub interface: String,
+    pub address: Ipv4Network,
+    /// Host's address, the default route of the container.
+    pub gateway: Ipv4Addr,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Lease {
+    /// Container, or the bridge. Addresses in use on the
+    /// bridge, which aren't leased, have no owner.
+    owner: Option<String>,
+    /// Leases are confirmed once the setup is complete,
+    /// confirmed leases don't expire.
+    expires: Option<SystemTime>,
+}
+
+impl Lease {
+    fn pending(owner: &str) -> Self {
+        Self {
+            owner: Some(owner.into()),
+            expires: Some(SystemTime::now() + LEASE_TIMEOUT),
+        }
+    }
+
+    fn confirmed(owner: &str) -> Self {
+        Self {
+            owner: Some(owner.into()),
+            expires: None,
+        }
+    }
+
+    fn conflict() -> Self {
+        Self {
+            owner: None,
+            expires: Some(SystemTime::now() + CONFLICT_TIMEOUT),
+        }
+    }
+
+    fn expired(&self) -> bool {
+        self.expires
+            .map_or(false, |expires| expires <= SystemTime::now())
+    }
+
+    fn owned_by(&self, owner: &str) -> bool {
+        self.owner.as_deref() == Some(owner)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Published {
+    /// NAT interface, which the ports belong to.
+    interface: String,
+    ports: Vec<PortMapping>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Pipes {
+    egress: Option<u16>,
+    ingress: Option<u16>,
+}
+
+/// Attaches the container to the bridge. The container gets
+/// the address of the annotations, if given, or any free
+/// one.
+///
+/// With `KNAST_ICC=false` the container is isolated from the
+/// other containers, except for the ones and the networks it
+/// allows, see [`isolate`]. Its bandwidth is limited, if
+/// annotated, see [`shape`]. Its ports are published on the
+/// NAT interface, see [`publish`]. Fails, unless the
+/// container reaches the gateway, see [`probe_gateway`],
+/// or opts out of the probe, see
+/// [`super::annotations::PROBE_ANNOTATION`].
 #[fehler::throws]
 pub fn setup(
     storage: &Storage<impl StorageEngine>,
     key: impl AsRef<str>,
     jail: RunningJail,
+    annotations: &Annotations,
     nat_interface: Option<impl AsRef<str>>,
-) {
+) -> Attachment {
+    let key = key.as_ref();
     let bridge = setup_bridge(storage)?;
-    let host = setup_pair(storage, key, jail)?;
+    let host = setup_pair(
+        storage,
+        key,
+        jail,
+        annotations.address,
+        annotations.interface.as_deref(),
+    )?;
     let host_name = host.get_name()?;
 
     bridge.bridge_addm(&[host_name])?;
 
+    let nat_interface = nat_interface.as_ref().map(|name| name.as_ref());
+
     if let Some(nat_interface) = nat_interface {
-        let nat = Pf::new(nat_interface.as_ref())?;
+        let nat = Pf::new(nat_config(nat_interface)?)?;
         nat.add(DEFAULT_NETWORK)?;
     }
+
+    if std::env::var(ICC_VARIABLE).as_deref() == Ok("false") {
+        isolate(storage, key, &annotations.allow)?;
+    }
+
+    if annotations.egress.is_some() || annotations.ingress.is_some() {
+        shape(storage, key, annotations.egress, annotations.ingress)?;
+    }
+
+    if !annotations.ports.is_empty() {
+        let interface = nat_interface.ok_or_else(|| {
+            anyhow!("Publishing ports of {} requires NAT interface", key)
+        })?;
+
+        publish(storage, key, interface, &annotations.ports)?;
+    }
+
+    let attachment = attachment(storage, key)?
+        .ok_or_else(|| anyhow!("{} isn't attached to the bridge", key))?;
+
+    if annotations.probe {
+        probe_gateway(jail, attachment.gateway).map_err(|error| {
+            anyhow!("{} can't reach the gateway: {}", key, error)
+        })?;
+    }
+
+    attachment
+}
+
+/// The container's attachment, unless its network is torn
+/// down.
+#[fehler::throws]
+pub fn attachment(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+) -> Option<Attachment> {
+    let cache = CONTAINER_ADDRESSES
+        .get(storage, CONTAINER_ADDRESS_KEY)?
+        .unwrap_or_else(BTreeMap::new);
+    let (host_interface, gateway, container) = match cache.get(key) {
+        Some(entry) => entry.clone(),
+        None => return None,
+    };
+    let interface = match CONTAINER_INTERFACES.get(storage, key)? {
+        Some(interface) => interface,
+        None => pair_name(&host_interface),
+    };
+    let prefix = DEFAULT_NETWORK.parse::<Ipv4Network>()?.prefix();
+
+    Some(Attachment {
+        host_interface,
+        interface,
+        address: Ipv4Network::new(container, prefix)?,
+        gateway,
+    })
 }
 
+/// Destroys the container's epair and returns its addresses
+/// to the pool. Every step is attempted; does nothing if
+/// the network is already torn down.
 #[fehler::throws]
 pub fn teardown(storage: &Storage<impl StorageEngine>, key: impl AsRef<str>) {
-    let cache: ContainerAddressStorage = storage
-        .get(NETWORK_STATE_STORAGE_KEY, CONTAINER_ADDRESS_STORAGE_KEY)?
-        .ok_or_else(|| anyhow::anyhow!("Failed to read network state data"))?;
+    let cache = CONTAINER_ADDRESSES
+        .get(storage, CONTAINER_ADDRESS_KEY)?
+        .unwrap_or_else(BTreeMap::new);
     let key: String = key.as_ref().into();
-    let (iface, host, container) = cache
-        .get(&key)
-        .ok_or_else(|| anyhow::anyhow!("Failed to read network state data"))?;
-    Interface::new(iface)?.destroy()?;
-    release_addresses(storage, key)?;
-    free_address(&storage, *host)?;
-    free_address(&storage, *container)?;
+    let (iface, host, container) = match cache.get(&key) {
+        Some(entry) => entry.clone(),
+        None => return,
+    };
+    let mut errors = Errors::default();
+
+    errors.collect(
+        format!("destroy {}", iface),
+        Interface::new(&iface).and_then(|interface| {
+            if interface.exists()? {
+                interface.destroy()?;
+            }
+
+            Ok(())
+        }),
+    );
+    if ISOLATED_CONTAINERS.exists(storage, &*key).unwrap_or(true) {
+        errors.collect(
+            "remove isolation",
+            release_isolation(storage, &key, container),
+        );
+    }
+
+    errors.collect("remove bandwidth limits", unshape(storage, &key));
+    errors.collect("unpublish ports", unpublish(storage, &key));
+    errors.collect(
+        "forget interface",
+        CONTAINER_INTERFACES.remove(storage, &*key),
+    );
+
+    // Leases are released first, so that a retried teardown
+    // finds the addresses
+    let released = [host, container]
+        .iter()
+        .filter_map(|address| {
+            let result = release_lease(storage, *address, &key);
+
+            errors.collect("release lease", result)
+        })
+        .count();
+
+    if released == 2 {
+        errors.collect("release addresses", release_addresses(storage, key));
+    }
+
+    errors.into_result("network teardown")?;
+}
+
+/// Blocks the traffic of the containers' network to the
+/// container, except for the `allow`ed containers and
+/// networks, both ways. Containers are named by their keys.
+/// The policy is recomputed for every isolated container,
+/// since allowed containers come and go.
+#[fehler::throws]
+fn isolate(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+    allow: &[String],
+) {
+    let address = container_address(storage, key)?
+        .ok_or_else(|| anyhow!("{} has no address", key))?;
+
+    ISOLATED_CONTAINERS.put(storage, key, allow.to_vec())?;
+
+    let pf = filter_pf()?;
+
+    // Containers' networks, the NAT table, without NAT too
+    pf.add(DEFAULT_NETWORK)?;
+    pf.add_isolated(address)?;
+    pf.filter(&filter_policy(storage)?)?;
+}
+
+#[fehler::throws]
+fn release_isolation(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+    address: Ipv4Addr,
+) {
+    let pf = filter_pf()?;
+
+    pf.remove_isolated(address)?;
+    ISOLATED_CONTAINERS.remove(storage, key)?;
+    pf.filter(&filter_policy(storage)?)?;
+}
+
+/// Limits the bandwidth of the container's traffic, bits
+/// per second, passing it through dummynet(4) pipes.
+#[fehler::throws]
+fn shape(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+    egress: Option<u32>,
+    ingress: Option<u32>,
+) {
+    let pipes = allocate_pipes(storage, key, egress, ingress)?;
+
+    for (pipe, rate) in &[(pipes.egress, egress), (pipes.ingress, ingress)] {
+        if let (Some(pipe), Some(rate)) = (pipe, rate) {
+            dummynet::configure_pipe(*pipe, *rate)?;
+        }
+    }
+
+    filter_pf()?.filter(&filter_policy(storage)?)?;
+}
+
+/// Removes the limits of the container, if any.
+#[fehler::throws]
+fn unshape(storage: &Storage<impl StorageEngine>, key: &str) {
+    let pipes = loop {
+        let old = CONTAINER_PIPES.get(storage, CONTAINER_PIPES_KEY)?;
+        let mut new = old.clone().unwrap_or_else(BTreeMap::new);
+        let pipes = match new.remove(key) {
+            Some(pipes) => pipes,
+            None => return,
+        };
+        let result = CONTAINER_PIPES.compare_and_swap(
+            storage,
+            CONTAINER_PIPES_KEY,
+            old,
+            Some(new),
+        );
+
+        if result.is_ok() {
+            break pipes;
+        }
+    };
+
+    // Pipes are used by the rules until they're gone
+    filter_pf()?.filter(&filter_policy(storage)?)?;
+
+    for pipe in pipes.egress.iter().chain(&pipes.ingress) {
+        dummynet::delete_pipe(*pipe)?;
+    }
+}
+
+/// Picks the lowest free pipe numbers for the limited
+/// directions.
+#[fehler::throws]
+fn allocate_pipes(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+    egress: Option<u32>,
+    ingress: Option<u32>,
+) -> Pipes {
+    loop {
+        let old = CONTAINER_PIPES.get(storage, CONTAINER_PIPES_KEY)?;
+        let mut new = old.clone().unwrap_or_else(BTreeMap::new);
+        let mut used: Vec<u16> = new
+            .values()
+            .flat_map(|pipes| pipes.egress.iter().chain(&pipes.ingress))
+            .cloned()
+            .collect();
+        let mut next = |limited: Option<u32>| -> Result<_, Error> {
+            if limited.is_none() {
+                return Ok(None);
+            }
+
+            let pipe = (1..=u16::MAX)
+                .find(|pipe| !used.contains(pipe))
+                .ok_or_else(|| anyhow!("No dummynet pipes left"))?;
+
+            used.push(pipe);
+
+            Ok(Some(pipe))
+        };
+        let pipes = Pipes {
+            egress: next(egress)?,
+            ingress: next(ingress)?,
+        };
+
+        new.insert(key.into(), pipes);
+
+        let result = CONTAINER_PIPES.compare_and_swap(
+            storage,
+            CONTAINER_PIPES_KEY,
+            old,
+            Some(new),
+        );
+
+        if result.is_ok() {
+            break pipes;
+        }
+    }
+}
+
+/// Ports the container publishes, none once its network is
+/// torn down.
+#[fehler::throws]
+pub fn ports(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+) -> Vec<PortMapping> {
+    PUBLISHED_PORTS
+        .get(storage, PUBLISHED_PORTS_KEY)?
+        .and_then(|mut published| published.remove(key))
+        .map_or_else(Vec::new, |published| published.ports)
+}
+
+/// Redirects the `ports` of the NAT `interface` to the
+/// container. Fails, if another container has published any
+/// of the ports.
+#[fehler::throws]
+fn publish(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+    interface: &str,
+    ports: &[PortMapping],
+) {
+    loop {
+        let old = PUBLISHED_PORTS.get(storage, PUBLISHED_PORTS_KEY)?;
+        let mut new = old.clone().unwrap_or_else(BTreeMap::new);
+        let taken = new
+            .iter()
+            .filter(|(other, _)| other.as_str() != key)
+            .flat_map(|(other, published)| {
+                published.ports.iter().map(move |port| (other, port))
+            })
+            .find(|(_, taken)| {
+                ports.iter().any(|port| {
+                    (port.host_port, port.protocol)
+                        == (taken.host_port, taken.protocol)
+                })
+            });
+
+        if let Some((other, port)) = taken {
+            anyhow::bail!(
+                "Port {}/{} is published by {}",
+                port.host_port,
+                port.protocol,
+                other
+            );
+        }
+
+        new.insert(
+            key.into(),
+            Published {
+                interface: interface.into(),
+                ports: ports.to_vec(),
+            },
+        );
+
+        let result = PUBLISHED_PORTS.compare_and_swap(
+            storage,
+            PUBLISHED_PORTS_KEY,
+            old,
+            Some(new),
+        );
+
+        if result.is_ok() {
+            break;
+        }
+    }
+
+    filter_pf()?.redirect(&redirects(storage)?)?;
+}
+
+/// Removes the redirections of the container, if any.
+#[fehler::throws]
+fn unpublish(storage: &Storage<impl StorageEngine>, key: &str) {
+    loop {
+        let old = match PUBLISHED_PORTS.get(storage, PUBLISHED_PORTS_KEY)? {
+            Some(old) if old.contains_key(key) => old,
+            _ => return,
+        };
+        let mut new = old.clone();
+
+        new.remove(key);
+
+        let result = PUBLISHED_PORTS.compare_and_swap(
+            storage,
+            PUBLISHED_PORTS_KEY,
+            Some(old),
+            Some(new),
+        );
+
+        if result.is_ok() {
+            break;
+        }
+    }
+
+    filter_pf()?.redirect(&redirects(storage)?)?;
+}
+
+/// Redirections of the published ports. Containers, which
+/// aren't set up (yet), are skipped.
+#[fehler::throws]
+fn redirects(storage: &Storage<impl StorageEngine>) -> Vec<Redirect> {
+    let published = PUBLISHED_PORTS
+        .get(storage, PUBLISHED_PORTS_KEY)?
+        .unwrap_or_else(BTreeMap::new);
+    let mut redirects = Vec::new();
+
+    for (key, published) in published {
+        let address = match container_address(storage, &key)? {
+            Some(address) => address,
+            None => continue,
+        };
+
+        redirects.extend(published.ports.iter().map(|port| Redirect {
+            interface: published.interface.clone(),
+            protocol: match port.protocol {
+                Protocol::Tcp => pf::Protocol::Tcp,
+                Protocol::Udp => pf::Protocol::Udp,
+            },
+            host_port: port.host_port,
+            address,
+            port: port.container_port,
+        }));
+    }
+
+    redirects
+}
+
+/// Filter rules of the containers: the isolation and the
+/// bandwidth limits.
+#[fehler::throws]
+fn filter_policy(storage: &Storage<impl StorageEngine>) -> Filter {
+    let pipes = CONTAINER_PIPES
+        .get(storage, CONTAINER_PIPES_KEY)?
+        .unwrap_or_else(BTreeMap::new);
+    let mut shaped = Vec::new();
+
+    for (key, pipes) in pipes {
+        if let Some(address) = container_address(storage, &key)? {
+            shaped.push(Shaping {
+                address,
+                egress: pipes.egress,
+                ingress: pipes.ingress,
+            });
+        }
+    }
+
+    Filter {
+        allowed: allowed_pairs(storage)?,
+        shaped,
+    }
+}
+
+/// Neither the filter nor the redirections depend on the
+/// NAT interface of the config, any valid name will do.
+#[fehler::throws]
+fn filter_pf() -> Pf {
+    Pf::open(nat_config(DEFAULT_BRIDGE)?)?
+}
+
+/// Pairs of the isolated containers and what they allow.
+/// Containers, which aren't set up (yet), are skipped.
+#[fehler::throws]
+fn allowed_pairs(
+    storage: &Storage<impl StorageEngine>,
+) -> Vec<(Ipv4Network, Ipv4Network)> {
+    let mut pairs = Vec::new();
+
+    for key in ISOLATED_CONTAINERS.keys(storage)? {
+        let key = String::from_utf8(key)?;
+        let allow = ISOLATED_CONTAINERS
+            .get(storage, &*key)?
+            .unwrap_or_else(Vec::new);
+        let address = match container_address(storage, &key)? {
+            Some(address) => Ipv4Network::from(address),
+            None => continue,
+        };
+
+        for allowed in allow {
+            let network = match allowed.parse::<Ipv4Network>() {
+                Ok(network) => Some(network),
+                Err(_) => container_address(storage, &allowed)?
+                    .map(Ipv4Network::from),
+            };
+
+            if let Some(network) = network {
+                pairs.push((address, network));
+            }
+        }
+    }
+
+    pairs
+}
+
+/// Address of the container's end of the epair.
+#[fehler::throws]
+fn container_address(
+    storage: &Storage<impl StorageEngine>,
+    key: &str,
+) -> Option<Ipv4Addr> {
+    CONTAINER_ADDRESSES
+        .get(storage, CONTAINER_ADDRESS_KEY)?
+        .and_then(|cache| cache.get(key).map(|(_, _, container)| *container))
+}
+
+/// NAT of the `interface`, tuned by the environment, i.e.
+/// `KNAST_NAT_PORTS=40000:49999` or
+/// `KNAST_NAT_POOL=round-robin`.
+#[fehler::throws]
+fn nat_config(interface: &str) -> NatConfig {
+    let variable = |name| std::env::var(name).ok();
+    let mut config = NatConfig::new(interface);
+
+    if let Some(anchor) = variable(NAT_ANCHOR_VARIABLE) {
+        config.anchor = anchor;
+    }
+
+    if let Some(table) = variable(NAT_TABLE_VARIABLE) {
+        config.table = table;
+    }
+
+    if let Some(ports) = variable(NAT_PORTS_VARIABLE) {
+        let invalid = || anyhow!("{} must be LOW:HIGH", NAT_PORTS_VARIABLE);
+        let index = ports.find(':').ok_or_else(invalid)?;
+        let (low, high) = (&ports[..index], &ports[index + 1..]);
+
+        config.port_range = (
+            low.parse().map_err(|_| invalid())?,
+            high.parse().map_err(|_| invalid())?,
+        );
+    }
+
+    config.static_port = variable(NAT_STATIC_PORT_VARIABLE).is_some();
+    config.pool = match variable(NAT_POOL_VARIABLE).as_deref() {
+        None => Pool::Default,
+        Some("bitmask") => Pool::Bitmask,
+        Some("round-robin") => Pool::RoundRobin,
+        Some(pool) => fehler::throw!(anyhow!(
+            "{} must be bitmask or round-robin, not {}",
+            NAT_POOL_VARIABLE,
+            pool
+        )),
+    };
+
+    config
 }
 
 #[fehler::throws]
@@ -60,9 +706,15 @@ fn setup_pair(
     storage: &Storage<impl StorageEngine>,
     key: impl AsRef<str>,
     jail: RunningJail,
+    address: Option<Ipv4Addr>,
+    interface: Option<&str>,
 ) -> Interface {
-    let host_address = get_address(&storage)?;
-    let container_address = get_address(&storage)?;
+    let key = key.as_ref();
+    let container_address = match address {
+        Some(address) => take_address(storage, key, address)?,
+        None => lease_address(storage, key)?,
+    };
+    let host_address = lease_address(storage, key)?;
     let broadcast = broadcast(DEFAULT_NETWORK)?.to_string();
     let mask = mask(DEFAULT_NETWORK)?.to_string();
     let pair_a = Interface::new("epair")?.create()?.address(
@@ -71,111 +723,326 @@ fn setup_pair(
         &mask,
     )?;
     let name = pair_a.get_name()?;
-    let len = name.len();
-    let name_b = &[&name[..len - 1], "b"].join("");
+    let name_b = &pair_name(name);
     reserve_addresses(storage, key, name, (host_address, container_address))?;
 
+    if let Some(interface) = interface {
+        CONTAINER_INTERFACES.put(storage, key, interface.into())?;
+    }
+
     let pair_b = Interface::new(name_b)?;
     pair_b.vnet(jail.jid)?;
 
     super::utils::run_in_fork(|| {
         jail.attach()?;
-        let pair_b = Interface::new(name_b)?;
-        pair_b.address(&container_address.to_string(), &broadcast, &mask)?;
+        let pair_b = Interface::new(name_b)?.address(
+            &container_address.to_string(),
+            &broadcast,
+            &mask,
+        )?;
+
+        if let Some(interface) = interface {
+            pair_b.name(interface)?;
+        }
+
         route::add_default(&host_address.to_string())
     })?;
 
+    confirm_lease(storage, host_address, key)?;
+    confirm_lease(storage, container_address, key)?;
+
     pair_a
 }
 
+/// Connects to the gateway from the container's vnet, so
+/// that broken networks fail the setup, rather than the
+/// workloads later. Connections are retried, since links
+/// take a while to come up.
+#[fehler::throws]
+fn probe_gateway(jail: RunningJail, gateway: Ipv4Addr) {
+    let address = SocketAddr::from((gateway, REACHABILITY_PORT));
+
+    super::utils::run_in_fork(|| {
+        jail.attach()?;
+
+        let mut attempt = 1;
+
+        loop {
+            let error = match connect(&address) {
+                Ok(()) => break Ok(()),
+                Err(error) => error,
+            };
+
+            if attempt == REACHABILITY_ATTEMPTS {
+                anyhow::bail!(
+                    "{} attempts to connect to {} failed: {}",
+                    attempt,
+                    address,
+                    error
+                );
+            }
+
+            tracing::debug!("probe of {} failed: {}", address, error);
+            thread::sleep(REACHABILITY_DELAY);
+            attempt += 1;
+        }
+    })?;
+}
+
+/// Refused connections prove the reachability as well.
+fn connect(address: &SocketAddr) -> std::io::Result<()> {
+    match TcpStream::connect_timeout(address, REACHABILITY_TIMEOUT) {
+        Err(error) if error.kind() != ErrorKind::ConnectionRefused => {
+            Err(error)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The other end of the epair.
+fn pair_name(name: &str) -> String {
+    [&name[..name.len() - 1], "b"].join("")
+}
+
 #[fehler::throws]
 fn setup_bridge(storage: &Storage<impl StorageEngine>) -> Interface {
     let mut bridge = Interface::new(DEFAULT_BRIDGE)?;
 
     if !bridge.exists()? {
-        let bridge_address = get_address(storage)?.to_string();
+        let bridge_address = lease_address(storage, DEFAULT_BRIDGE)?;
         let broadcast = broadcast(DEFAULT_NETWORK)?.to_string();
         let mask = mask(DEFAULT_NETWORK)?.to_string();
 
         bridge = Interface::new("bridge")?
             .create()?
             .name(DEFAULT_BRIDGE)?
-            .address(&bridge_address, &broadcast, &mask)?;
+            .address(&bridge_address.to_string(), &broadcast, &mask)?;
+        confirm_lease(storage, bridge_address, DEFAULT_BRIDGE)?;
     }
 
     bridge
 }
 
+/// Leases the lowest address of the network to the `owner`,
+/// unless it's leased or in use on the bridge. The lease is
+/// pending until confirmed, see [`confirm_lease`].
 #[fehler::throws]
 #[tracing::instrument(err)]
-fn get_address(storage: &Storage<impl StorageEngine>) -> Ipv4Addr {
-    let maybe_heap: Option<BinaryHeap<Ipv4Addr>> =
-        storage.get(NETWORK_STATE_STORAGE_KEY, DEFAULT_NETWORK.as_bytes())?;
-
-    if let Some(heap) = maybe_heap {
-        let mut new_heap = heap.clone();
-
-        let mut address = new_heap
-            .pop()
-            .ok_or_else(|| anyhow::anyhow!("No addresses left"))?;
-        if address.is_broadcast() {
-            address = new_heap
-                .pop()
-                .ok_or_else(|| anyhow::anyhow!("No addresses left"))?;
-        }
-
-        if let Err(_) = storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            DEFAULT_NETWORK.as_bytes(),
-            Some(heap),
-            Some(new_heap),
-        ) {
-            return get_address(&storage)?;
-        };
+fn lease_address(
+    storage: &Storage<impl StorageEngine>,
+    owner: &str,
+) -> Ipv4Addr {
+    import_pool(storage)?;
 
-        address
-    } else {
-        let range = ip_range(DEFAULT_NETWORK)?;
+    let leases = leases(storage)?;
+    let network = DEFAULT_NETWORK.parse::<Ipv4Network>()?;
 
-        storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            DEFAULT_NETWORK.as_bytes(),
-            None,
-            Some(range),
-        )?;
-        get_address(&storage)?
+    // Addresses, which other processes lease concurrently,
+    // are skipped
+    for address in hosts(network) {
+        let lease = leases.get(&address).cloned();
+
+        if lease.as_ref().map_or(false, |lease| !lease.expired()) {
+            continue;
+        }
+
+        if claim(storage, address, lease, owner)
+            && !conflicted(storage, address)?
+        {
+            return address;
+        }
+    }
+
+    anyhow::bail!("No addresses left in {}", DEFAULT_NETWORK);
+}
+
+/// Leases the particular address to the `owner`.
+#[fehler::throws]
+#[tracing::instrument(err)]
+fn take_address(
+    storage: &Storage<impl StorageEngine>,
+    owner: &str,
+    address: Ipv4Addr,
+) -> Ipv4Addr {
+    import_pool(storage)?;
+
+    let network = DEFAULT_NETWORK.parse::<Ipv4Network>()?;
+
+    if !hosts(network).any(|host| host == address) {
+        anyhow::bail!("Address {} is outside of {}", address, network);
+    }
+
+    loop {
+        let lease = ADDRESS_LEASES.get(storage, &*address.to_string())?;
+
+        if lease.as_ref().map_or(false, |lease| !lease.expired()) {
+            anyhow::bail!("Address {} is taken", address);
+        }
+
+        if !claim(storage, address, lease, owner) {
+            continue;
+        }
+
+        if conflicted(storage, address)? {
+            anyhow::bail!(
+                "Address {} is in use on {}",
+                address,
+                DEFAULT_BRIDGE
+            );
+        }
+
+        break address;
+    }
+}
+
+/// Swaps the `lease`, expired or none, for the pending lease
+/// of the `owner`, unless the lease has changed meanwhile.
+fn claim(
+    storage: &Storage<impl StorageEngine>,
+    address: Ipv4Addr,
+    lease: Option<Lease>,
+    owner: &str,
+) -> bool {
+    ADDRESS_LEASES
+        .compare_and_swap(
+            storage,
+            &*address.to_string(),
+            lease,
+            Some(Lease::pending(owner)),
+        )
+        .is_ok()
+}
+
+/// Probes the address on the bridge, if it's up. Addresses
+/// in use, i.e. by the containers of the lost leases, are
+/// leased to none for [`CONFLICT_TIMEOUT`].
+#[fehler::throws]
+fn conflicted(
+    storage: &Storage<impl StorageEngine>,
+    address: Ipv4Addr,
+) -> bool {
+    if !Interface::new(DEFAULT_BRIDGE)?.exists()?
+        || !arp::probe(DEFAULT_BRIDGE, address, PROBES, PROBE_WAIT)?
+    {
+        return false;
+    }
+
+    tracing::warn!("{} is in use on {}", address, DEFAULT_BRIDGE);
+    ADDRESS_LEASES.put(storage, &*address.to_string(), Lease::conflict())?;
+
+    true
+}
+
+/// Confirms the pending lease of the `owner`, so that it
+/// doesn't expire.
+#[fehler::throws]
+fn confirm_lease(
+    storage: &Storage<impl StorageEngine>,
+    address: Ipv4Addr,
+    owner: &str,
+) {
+    let key = address.to_string();
+
+    loop {
+        let lease = ADDRESS_LEASES
+            .get(storage, &*key)?
+            .filter(|lease| lease.owned_by(owner))
+            .ok_or_else(|| anyhow!("Lease of {} is lost", address))?;
+        let result = ADDRESS_LEASES.compare_and_swap(
+            storage,
+            &*key,
+            Some(lease),
+            Some(Lease::confirmed(owner)),
+        );
+
+        if result.is_ok() {
+            break;
+        }
+    }
+}
+
+/// Releases the lease of the `owner`, if it's still theirs.
+#[fehler::throws]
+fn release_lease(
+    storage: &Storage<impl StorageEngine>,
+    address: Ipv4Addr,
+    owner: &str,
+) {
+    let key = address.to_string();
+
+    loop {
+        let lease = match ADDRESS_LEASES.get(storage, &*key)? {
+            Some(lease) if lease.owned_by(owner) => lease,
+            _ => break,
+        };
+
+        if ADDRESS_LEASES
+            .compare_and_swap(storage, &*key, Some(lease), None)
+            .is_ok()
+        {
+            break;
+        }
     }
 }
 
+/// Leases, keyed by address.
 #[fehler::throws]
-fn free_address(storage: &Storage<impl StorageEngine>, address: Ipv4Addr) {
-    let maybe_heap: Option<BinaryHeap<Ipv4Addr>> =
-        storage.get(NETWORK_STATE_STORAGE_KEY, DEFAULT_NETWORK.as_bytes())?;
+fn leases(storage: &Storage<impl StorageEngine>) -> BTreeMap<Ipv4Addr, Lease> {
+    let mut leases = BTreeMap::new();
 
-    if let Some(heap) = maybe_heap {
-        let mut new_heap = heap.clone();
+    for key in ADDRESS_LEASES.keys(storage)? {
+        let key = String::from_utf8(key)?;
 
-        new_heap.push(address);
+        if let Some(lease) = ADDRESS_LEASES.get(storage, &*key)? {
+            leases.insert(key.parse()?, lease);
+        }
+    }
 
-        if let Err(_) = storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            DEFAULT_NETWORK.as_bytes(),
-            Some(heap),
-            Some(new_heap),
-        ) {
-            free_address(&storage, address)?;
+    leases
+}
+
+/// Addresses of the network, but the network's and the
+/// broadcast ones.
+fn hosts(network: Ipv4Network) -> impl Iterator<Item = Ipv4Addr> {
+    network.iter().filter(move |address| {
+        *address != network.network() && *address != network.broadcast()
+    })
+}
+
+/// Leases the addresses, which the pool of the previous
+/// allocator has given out, to the containers having them,
+/// and the rest to the bridge.
+#[fehler::throws]
+fn import_pool(storage: &Storage<impl StorageEngine>) {
+    let pool: BTreeSet<Ipv4Addr> =
+        match LEGACY_POOLS.get(storage, DEFAULT_NETWORK)? {
+            Some(pool) => pool.into_iter().collect(),
+            None => return,
         };
-    } else {
-        let range = ip_range(DEFAULT_NETWORK)?;
+    let containers = CONTAINER_ADDRESSES
+        .get(storage, CONTAINER_ADDRESS_KEY)?
+        .unwrap_or_else(BTreeMap::new);
+    let owners: BTreeMap<Ipv4Addr, &str> = containers
+        .iter()
+        .flat_map(|(key, (_, host, container))| {
+            vec![(*host, key.as_str()), (*container, key.as_str())]
+        })
+        .collect();
+    let network = DEFAULT_NETWORK.parse::<Ipv4Network>()?;
 
-        storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            DEFAULT_NETWORK.as_bytes(),
+    for address in hosts(network).filter(|host| !pool.contains(host)) {
+        let owner = owners.get(&address).unwrap_or(&DEFAULT_BRIDGE);
+
+        // Leased already, if another process imports as well
+        let _ = ADDRESS_LEASES.compare_and_swap(
+            storage,
+            &*address.to_string(),
             None,
-            Some(range),
-        )?;
-        free_address(&storage, address)?;
+            Some(Lease::confirmed(owner)),
+        );
     }
+
+    LEGACY_POOLS.remove(storage, DEFAULT_NETWORK)?;
 }
 
 #[fehler::throws]
@@ -185,33 +1052,25 @@ fn reserve_addresses(
     interface: impl AsRef<str>,
     addresses: (Ipv4Addr, Ipv4Addr),
 ) {
-    let maybe_cache: Option<ContainerAddressStorage> = storage
-        .get(NETWORK_STATE_STORAGE_KEY, CONTAINER_ADDRESS_STORAGE_KEY)?;
+    loop {
+        let old = CONTAINER_ADDRESSES.get(storage, CONTAINER_ADDRESS_KEY)?;
+        let mut new = old.clone().unwrap_or_else(BTreeMap::new);
 
-    if let Some(cache) = maybe_cache {
-        let mut new_cache = cache.clone();
-        new_cache.insert(
+        new.insert(
             key.as_ref().into(),
             (interface.as_ref().into(), addresses.0, addresses.1),
         );
 
-        if let Err(_) = storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            CONTAINER_ADDRESS_STORAGE_KEY,
-            Some(cache),
-            Some(new_cache),
-        ) {
-            reserve_addresses(storage, key, interface, addresses)?;
-        };
-    } else {
-        let empty_cache: ContainerAddressStorage = BTreeMap::new();
-        storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            CONTAINER_ADDRESS_STORAGE_KEY,
-            None,
-            Some(empty_cache),
-        )?;
-        reserve_addresses(storage, key, interface, addresses)?;
+        let result = CONTAINER_ADDRESSES.compare_and_swap(
+            storage,
+            CONTAINER_ADDRESS_KEY,
+            old,
+            Some(new),
+        );
+
+        if result.is_ok() {
+            break;
+        }
     }
 }
 
@@ -220,30 +1079,21 @@ fn release_addresses(
     storage: &Storage<impl StorageEngine>,
     key: impl AsRef<str>,
 ) {
-    let maybe_cache: Option<ContainerAddressStorage> = storage
-        .get(NETWORK_STATE_STORAGE_KEY, CONTAINER_ADDRESS_STORAGE_KEY)?;
-
-    if let Some(cache) = maybe_cache {
-        let mut new_cache = cache.clone();
-        let key: String = key.as_ref().into();
-        new_cache.remove(&key);
-
-        if let Err(_) = storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            CONTAINER_ADDRESS_STORAGE_KEY,
-            Some(cache),
-            Some(new_cache),
-        ) {
-            release_addresses(storage, key)?;
-        };
-    } else {
-        let empty_cache: ContainerAddressStorage = BTreeMap::new();
-        storage.compare_and_swap(
-            NETWORK_STATE_STORAGE_KEY,
-            CONTAINER_ADDRESS_STORAGE_KEY,
-            None,
-            Some(empty_cache),
-        )?;
-        release_addresses(storage, key)?;
+    loop {
+        let old = CONTAINER_ADDRESSES.get(storage, CONTAINER_ADDRESS_KEY)?;
+        let mut new = old.clone().unwrap_or_else(BTreeMap::new);
+
+        new.remove(key.as_ref());
+
+        let result = CONTAINER_ADDRESSES.compare_and_swap(
+            storage,
+            CONTAINER_ADDRESS_KEY,
+            old,
+            Some(new),
+        );
+
+        if result.is_ok() {
+            break;
+        }
     }
 }
@@ -0,0 +1,221 @@
+use std::{
+    env,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Error};
+use ipnetwork::Ipv4Network;
+use jail::RunningJail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use storage::{Collection, Storage, StorageEngine};
+
+use super::{provider::NetworkProvider, Attachment};
+use crate::operations::Annotations;
+
+const CNI_PATH_VARIABLE: &str = "CNI_PATH";
+const DEFAULT_CNI_PATH: &str = "/usr/local/libexec/cni";
+const DEFAULT_INTERFACE: &str = "eth0";
+
+/// Networks of the containers, kept until they're deleted,
+/// since DEL takes the config and the result of ADD.
+const CNI_NETWORKS: Collection<str, CniNetwork> =
+    Collection::new(b"CNI_NETWORKS");
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct CniNetwork {
+    /// JSON of the network config and of the ADD result.
+    config: String,
+    result: String,
+    netns: String,
+    interface: String,
+}
+
+/// Delegates to the CNI plugin of the network config, i.e.
+/// `knast-cni` or a plugin of the users. The jail's name is
+/// the network namespace of the plugin.
+pub struct Cni;
+
+impl<T: StorageEngine> NetworkProvider<T> for Cni {
+    fn setup(
+        &self,
+        storage: &Storage<T>,
+        key: &str,
+        jail: RunningJail,
+        annotations: &Annotations,
+    ) -> Result<Option<Attachment>, Error> {
+        let path = annotations
+            .cni
+            .as_ref()
+            .ok_or_else(|| anyhow!("CNI network config isn't given"))?;
+        let config = std::fs::read_to_string(path)?;
+        let mut network = CniNetwork {
+            config,
+            result: String::new(),
+            netns: jail.name()?,
+            interface: annotations
+                .interface
+                .clone()
+                .unwrap_or_else(|| DEFAULT_INTERFACE.into()),
+        };
+
+        network.result = execute("ADD", key, &network, None)?;
+        CNI_NETWORKS.put(storage, key, network.clone())?;
+
+        Ok(Some(parse_result(&network.result)?))
+    }
+
+    fn teardown(&self, storage: &Storage<T>, key: &str) -> Result<(), Error> {
+        let network = match CNI_NETWORKS.get(storage, key)? {
+            Some(network) => network,
+            None => return Ok(()),
+        };
+
+        execute("DEL", key, &network, Some(&network.result))?;
+        CNI_NETWORKS.remove(storage, key)?;
+
+        Ok(())
+    }
+
+    fn inspect(
+        &self,
+        storage: &Storage<T>,
+        key: &str,
+    ) -> Result<Option<Attachment>, Error> {
+        CNI_NETWORKS
+            .get(storage, key)?
+            .map(|network| parse_result(&network.result))
+            .transpose()
+    }
+}
+
+/// Runs the plugin of the config's `type`, returning its
+/// output.
+#[fehler::throws]
+fn execute(
+    command: &str,
+    key: &str,
+    network: &CniNetwork,
+    previous: Option<&str>,
+) -> String {
+    let mut config: Value = serde_json::from_str(&network.config)?;
+    let plugin = config["type"]
+        .as_str()
+        .ok_or_else(|| anyhow!("CNI network config has no type"))?
+        .to_owned();
+    let cni_path = env::var(CNI_PATH_VARIABLE)
+        .unwrap_or_else(|_| DEFAULT_CNI_PATH.into());
+    let binary = env::split_paths(&cni_path)
+        .map(|directory| directory.join(&plugin))
+        .find(|binary| binary.exists())
+        .ok_or_else(|| anyhow!("CNI plugin {} isn't found", plugin))?;
+
+    if let Some(previous) = previous {
+        config["prevResult"] = serde_json::from_str(previous)?;
+    }
+
+    tracing::info!("CNI {} of {} by {:?}", command, key, binary);
+
+    let mut child = Command::new(&binary)
+        .env("CNI_COMMAND", command)
+        .env("CNI_CONTAINERID", key)
+        .env("CNI_NETNS", &network.netns)
+        .env("CNI_IFNAME", &network.interface)
+        .env(CNI_PATH_VARIABLE, &cni_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(config.to_string().as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+
+    if !output.status.success() {
+        let message = serde_json::from_str::<Value>(&stdout)
+            .ok()
+            .and_then(|error| error["msg"].as_str().map(String::from))
+            .unwrap_or_else(|| output.status.to_string());
+
+        fehler::throw!(anyhow!(
+            "CNI {} by {}: {}",
+            command,
+            binary.display(),
+            message
+        ));
+    }
+
+    stdout
+}
+
+/// The first IPv4 address of the result, and the interfaces
+/// of the epair, if any.
+#[fehler::throws]
+fn parse_result(result: &str) -> Attachment {
+    let result: Value = serde_json::from_str(result)?;
+    let interfaces = result["interfaces"].as_array().cloned();
+    let interfaces = interfaces.unwrap_or_else(Vec::new);
+    let ipv4 = |ip: &&Value| {
+        let address = ip["address"].as_str().unwrap_or_default();
+
+        address.parse::<Ipv4Network>().is_ok()
+    };
+    let ip = result["ips"]
+        .as_array()
+        .and_then(|ips| ips.iter().find(ipv4))
+        .ok_or_else(|| anyhow!("CNI result has no IPv4 address"))?;
+    let name = |interface: Option<&Value>| {
+        interface
+            .and_then(|interface| interface["name"].as_str())
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let host = interfaces
+        .iter()
+        .find(|interface| interface.get("sandbox").is_none());
+    let container = ip["interface"]
+        .as_u64()
+        .and_then(|index| interfaces.get(index as usize));
+
+    Attachment {
+        host_interface: name(host),
+        interface: name(container),
+        address: ip["address"].as_str().unwrap_or_default().parse()?,
+        gateway: ip["gateway"]
+            .as_str()
+            .ok_or_else(|| anyhow!("CNI result has no gateway"))?
+            .parse()?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_result() {
+        let attachment = parse_result(
+            r#"{
+                "cniVersion": "1.0.0",
+                "interfaces": [
+                    {"name": "epair0a"},
+                    {"name": "eth0", "sandbox": "debian"}
+                ],
+                "ips": [{
+                    "address": "172.24.0.5/16",
+                    "gateway": "172.24.0.1",
+                    "interface": 1
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(attachment.host_interface, "epair0a");
+        assert_eq!(attachment.interface, "eth0");
+        assert_eq!(attachment.address, "172.24.0.5/16".parse().unwrap());
+        assert_eq!(attachment.gateway.to_string(), "172.24.0.1");
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Error;
+use jail::RunningJail;
+use storage::{Storage, StorageEngine};
+
+use super::{attachment, cni::Cni, setup, teardown, Attachment};
+use crate::operations::{Annotations, NetworkMode};
+
+/// Networking of the containers, so that it's brought by
+/// the users, if they wish. Selected per container by the
+/// `org.freebsd.knast.network` annotation, see [`provider`].
+pub trait NetworkProvider<T: StorageEngine> {
+    /// Connects the container's vnet jail, returning the
+    /// container's end of the network, if any.
+    fn setup(
+        &self,
+        storage: &Storage<T>,
+        key: &str,
+        jail: RunningJail,
+        annotations: &Annotations,
+    ) -> Result<Option<Attachment>, Error>;
+
+    /// Releases the network of the container. Does nothing,
+    /// if it's already released, since teardown is retried.
+    fn teardown(&self, storage: &Storage<T>, key: &str) -> Result<(), Error>;
+
+    fn inspect(
+        &self,
+        storage: &Storage<T>,
+        key: &str,
+    ) -> Result<Option<Attachment>, Error>;
+}
+
+/// The provider of the annotated network. NAT interface
+/// matters for the setup of the bridge only.
+pub fn provider<T: StorageEngine>(
+    annotations: &Annotations,
+    nat_interface: Option<&str>,
+) -> Box<dyn NetworkProvider<T>> {
+    match annotations.network {
+        NetworkMode::Bridge => Box::new(Bridge {
+            nat_interface: nat_interface.map(String::from),
+        }),
+        NetworkMode::Cni => Box::new(Cni),
+        NetworkMode::Host | NetworkMode::None => Box::new(NoNetwork),
+    }
+}
+
+/// The `knast0` bridge, the default.
+pub struct Bridge {
+    pub nat_interface: Option<String>,
+}
+
+impl<T: StorageEngine> NetworkProvider<T> for Bridge {
+    fn setup(
+        &self,
+        storage: &Storage<T>,
+        key: &str,
+        jail: RunningJail,
+        annotations: &Annotations,
+    ) -> Result<Option<Attachment>, Error> {
+        let nat_interface = self.nat_interface.as_ref();
+
+        Ok(Some(setup(storage, key, jail, annotations, nat_interface)?))
+    }
+
+    fn teardown(&self, storage: &Storage<T>, key: &str) -> Result<(), Error> {
+        teardown(storage, key)
+    }
+
+    fn inspect(
+        &self,
+        storage: &Storage<T>,
+        key: &str,
+    ) -> Result<Option<Attachment>, Error> {
+        attachment(storage, key)
+    }
+}
+
+/// Leaves the container as it is: either with a loopback
+/// only, or with the host's network stack.
+pub struct NoNetwork;
+
+impl<T: StorageEngine> NetworkProvider<T> for NoNetwork {
+    fn setup(
+        &self,
+        _: &Storage<T>,
+        _: &str,
+        _: RunningJail,
+        _: &Annotations,
+    ) -> Result<Option<Attachment>, Error> {
+        Ok(None)
+    }
+
+    fn teardown(&self, _: &Storage<T>, _: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn inspect(
+        &self,
+        _: &Storage<T>,
+        _: &str,
+    ) -> Result<Option<Attachment>, Error> {
+        Ok(None)
+    }
+}
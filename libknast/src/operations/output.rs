@@ -0,0 +1,108 @@
+/// Output of the processes, which applications embedding
+/// the library collect, rather than the processes inheriting
+/// their stdio. See [`super::OciOperations::do_start_captured`].
+use std::{
+    fs::File,
+    io::Read,
+    os::unix::io::FromRawFd,
+    process::{Command, Stdio},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::Error;
+use nix::{fcntl::OFlag, unistd::pipe2};
+
+const CHUNK_SIZE: usize = 8192;
+
+/// How the output of the process is captured.
+#[derive(Clone)]
+pub enum OutputCapture {
+    /// The output is read through [`CapturedOutput`].
+    Pipes,
+    /// The output is passed to the callback in chunks, as
+    /// it's read by a thread per stream. Threads exit once
+    /// the process closes its stdio.
+    Callback(Arc<dyn Fn(OutputStream, &[u8]) + Send + Sync>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Readable ends of the process' stdout and stderr pipes.
+#[derive(Debug)]
+pub struct CapturedOutput {
+    pub stdout: File,
+    pub stderr: File,
+}
+
+impl OutputCapture {
+    /// Redirects stdout and stderr of the command to pipes.
+    /// Returns their readable ends, unless the callback reads
+    /// them.
+    pub(crate) fn attach(
+        self,
+        command: &mut Command,
+    ) -> Result<Option<CapturedOutput>, Error> {
+        let (stdout, stdout_writer) = pipe()?;
+        let (stderr, stderr_writer) = pipe()?;
+
+        command
+            .stdout(Stdio::from(stdout_writer))
+            .stderr(Stdio::from(stderr_writer));
+
+        let callback = match self {
+            OutputCapture::Pipes => {
+                return Ok(Some(CapturedOutput { stdout, stderr }))
+            }
+            OutputCapture::Callback(callback) => callback,
+        };
+
+        let streams = vec![
+            (OutputStream::Stdout, stdout),
+            (OutputStream::Stderr, stderr),
+        ];
+
+        for (stream, reader) in streams {
+            let callback = callback.clone();
+
+            thread::Builder::new()
+                .name("knast-output".into())
+                .spawn(move || forward(reader, stream, &*callback))?;
+        }
+
+        Ok(None)
+    }
+}
+
+/// Passes chunks of the stream to the callback until EOF.
+fn forward(
+    mut reader: File,
+    stream: OutputStream,
+    callback: &(dyn Fn(OutputStream, &[u8]) + Send + Sync),
+) {
+    let mut buffer = [0; CHUNK_SIZE];
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(length) => callback(stream, &buffer[..length]),
+            Err(error) => {
+                tracing::error!("Failed to read {:?}: {}", stream, error);
+
+                break;
+            }
+        }
+    }
+}
+
+/// Pipe, which isn't inherited by other processes: the
+/// writable end is duplicated onto the process' stdio only.
+pub(super) fn pipe() -> Result<(File, File), Error> {
+    let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+
+    Ok(unsafe { (File::from_raw_fd(reader), File::from_raw_fd(writer)) })
+}
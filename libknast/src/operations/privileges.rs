@@ -0,0 +1,162 @@
+/// Privileges knast runs with. Jails are created and entered
+/// by root only, so unprivileged users are refused upfront.
+/// Root of a jail, which may create jails of its own (see
+/// `children.max`), runs containers in the degraded mode:
+/// unless its jail has a vnet, containers share its network
+/// without NAT, and unless it may mount devfs, containers
+/// have no devfs.
+use std::ffi::CString;
+
+use libc::{c_int, sysctlbyname};
+
+use super::NetworkMode;
+use crate::error::KnastError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Privileges {
+    pub root: bool,
+    /// Whether knast runs in a jail.
+    pub jailed: bool,
+    /// Whether the jail has a vnet, so that pf and the bridge
+    /// are available.
+    pub vnet: bool,
+    pub mount_devfs: bool,
+}
+
+impl Privileges {
+    pub fn detect() -> Self {
+        let jailed = sysctl("security.jail.jailed") == Some(1);
+
+        Self {
+            root: unsafe { libc::geteuid() } == 0,
+            jailed,
+            vnet: !jailed || sysctl("security.jail.vnet") == Some(1),
+            mount_devfs: !jailed
+                || sysctl("security.jail.mount_devfs_allowed") == Some(1),
+        }
+    }
+
+    pub fn degraded(&self) -> bool {
+        !self.vnet || !self.mount_devfs
+    }
+
+    /// Refuses the `operation` to the unprivileged users.
+    #[fehler::throws(KnastError)]
+    pub fn require_root(&self, operation: &str) {
+        if !self.root {
+            fehler::throw!(KnastError::PermissionDenied(format!(
+                "{} requires root, since jails are created and entered by \
+                 root only; run knast as root, i.e. via doas(1)",
+                operation
+            )));
+        }
+    }
+
+    /// Network of the container. Without vnet, containers of
+    /// the default network share the host's one, and
+    /// explicitly requested networks are refused.
+    #[fehler::throws(KnastError)]
+    pub fn network(
+        &self,
+        network: NetworkMode,
+        explicit: bool,
+    ) -> NetworkMode {
+        match network {
+            NetworkMode::Bridge | NetworkMode::Cni if !self.vnet => {
+                if explicit {
+                    fehler::throw!(KnastError::PermissionDenied(format!(
+                        "{:?} network requires a vnet, which the jail \
+                         knast runs in lacks; use host or none network",
+                        network
+                    )));
+                }
+
+                tracing::warn!("No vnet, the container shares the network");
+
+                NetworkMode::Host
+            }
+            network => network,
+        }
+    }
+}
+
+/// Integer sysctl, if it exists.
+fn sysctl(name: &str) -> Option<c_int> {
+    let name = CString::new(name).ok()?;
+    let mut value: c_int = 0;
+    let mut size = std::mem::size_of::<c_int>();
+
+    let result = unsafe {
+        sysctlbyname(
+            name.as_ptr(),
+            &mut value as *mut _ as _,
+            &mut size,
+            std::ptr::null(),
+            0,
+        )
+    };
+
+    if result < 0 {
+        tracing::debug!(
+            "sysctl({:?}) failed: {}",
+            name,
+            std::io::Error::last_os_error()
+        );
+
+        return None;
+    }
+
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAILED: Privileges = Privileges {
+        root: true,
+        jailed: true,
+        vnet: false,
+        mount_devfs: false,
+    };
+
+    #[test]
+    fn test_network() {
+        assert_eq!(
+            JAILED.network(NetworkMode::Bridge, false).unwrap(),
+            NetworkMode::Host
+        );
+        assert_eq!(
+            JAILED.network(NetworkMode::None, true).unwrap(),
+            NetworkMode::None
+        );
+        assert!(matches!(
+            JAILED.network(NetworkMode::Bridge, true),
+            Err(KnastError::PermissionDenied(_))
+        ));
+
+        let vnet = Privileges {
+            vnet: true,
+            ..JAILED
+        };
+
+        assert_eq!(
+            vnet.network(NetworkMode::Bridge, true).unwrap(),
+            NetworkMode::Bridge
+        );
+    }
+
+    #[test]
+    fn test_require_root() {
+        let user = Privileges {
+            root: false,
+            ..JAILED
+        };
+
+        assert!(JAILED.require_root("create").is_ok());
+        assert!(matches!(
+            user.require_root("create"),
+            Err(KnastError::PermissionDenied(_))
+        ));
+    }
+}
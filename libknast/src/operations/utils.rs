@@ -1,15 +1,60 @@
 use std::{
-    io::{BufRead, BufReader, Write},
+    io::{BufRead, BufReader, Error as IoError, Write},
     os::unix::net::UnixStream,
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Error};
+use jail::{param::Value, RunningJail};
 use nix::{
     sys::wait::{waitpid, WaitStatus},
     unistd::{fork, ForkResult},
 };
 use serde::{de::DeserializeOwned, ser::Serialize};
 
+const TERMINATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Collects errors of best-effort operations, so that
+/// one failed step doesn't prevent the rest.
+#[derive(Debug, Default)]
+pub struct Errors(Vec<String>);
+
+impl Errors {
+    /// Records the error, if any, under `context`.
+    pub fn collect<T>(
+        &mut self,
+        context: impl AsRef<str>,
+        result: Result<T, Error>,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                tracing::error!("{} failed: {}", context.as_ref(), error);
+                self.0.push(format!("{}: {}", context.as_ref(), error));
+
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reports all the collected errors together.
+    #[fehler::throws]
+    pub fn into_result(self, operation: impl AsRef<str>) {
+        if !self.is_empty() {
+            anyhow::bail!(
+                "{} failed: {}",
+                operation.as_ref(),
+                self.0.join("; ")
+            );
+        }
+    }
+}
+
 /// Executes closure in a forked process
 pub fn run_in_fork<T: DeserializeOwned + Serialize>(
     f: impl FnOnce() -> Result<T, Error>,
@@ -67,3 +112,80 @@ pub fn run_in_fork<T: DeserializeOwned + Serialize>(
         }
     };
 }
+
+/// Kills every process in the jail and removes the jail,
+/// along with the jails nested in it. Waits up to `timeout`
+/// for the processes to exit, as they keep the container's
+/// mounts busy.
+#[fehler::throws]
+pub fn terminate_jail(jail: RunningJail, timeout: Duration) {
+    tracing::info!("Terminating processes of jail {}", jail.jid);
+
+    run_in_fork(|| {
+        jail.attach()?;
+
+        // Inside a jail, -1 stands for every process of
+        // the jail and of its children, except the caller
+        if unsafe { libc::kill(-1, libc::SIGKILL) } < 0 {
+            let error = IoError::last_os_error();
+
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                anyhow::bail!("kill failed: {:?}", error);
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        while unsafe { libc::kill(-1, 0) } == 0 {
+            if Instant::now() > deadline {
+                anyhow::bail!("jail processes didn't exit in {:?}", timeout);
+            }
+
+            thread::sleep(TERMINATION_POLL_INTERVAL);
+        }
+
+        Ok(())
+    })?;
+
+    // Children keep the jail, even when it's not persistent
+    for child in descendants(jail)? {
+        tracing::info!("Removing nested jail {}", child.jid);
+        remove_jail(child)?;
+    }
+
+    remove_jail(jail)?;
+}
+
+/// Non-persistent jail goes away along with its last
+/// process, so that it may be gone already.
+#[fehler::throws]
+fn remove_jail(jail: RunningJail) {
+    if let Err(error) = jail.kill() {
+        if RunningJail::from_jid(jail.jid).is_ok() {
+            fehler::throw!(error);
+        }
+    }
+}
+
+/// Jails nested in the `jail`, the deepest ones first.
+#[fehler::throws]
+fn descendants(jail: RunningJail) -> Vec<RunningJail> {
+    let mut result = Vec::new();
+    let mut parents = vec![jail.jid];
+
+    while let Some(parent) = parents.pop() {
+        for child in RunningJail::all() {
+            if let Value::Int(jid) = child.param("parent")? {
+                if jid == parent {
+                    parents.push(child.jid);
+                    result.push(child);
+                }
+            }
+        }
+    }
+
+    // Parents precede their children
+    result.reverse();
+
+    result
+}
@@ -0,0 +1,390 @@
+/// Process descriptors of the container processes, see
+/// pdfork(2).
+///
+/// Pid of a process can't be recycled while its descriptor is
+/// open, so signals and waits can't hit a stranger. Processes
+/// outlive their descriptors (`PD_DAEMON`), which are gone
+/// along with the process spawning the containers, i.e. the
+/// shim: plain pids are used then.
+///
+/// Processes with descriptors don't send SIGCHLD, their exits
+/// are reported to [`on_exit`] listeners instead.
+///
+/// The forked child of the (multi-threaded) spawner mustn't
+/// allocate, so the program is executed by a prepared
+/// [`Exec`].
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::File,
+    io::{Error as IoError, Read},
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    process::Command,
+    ptr,
+    sync::Mutex,
+    thread,
+};
+
+use anyhow::{anyhow, Error};
+use nix::{
+    fcntl::OFlag,
+    sys::{
+        event::{
+            kevent, kevent_ts, kqueue, EventFilter, EventFlag, FilterFlag,
+            KEvent,
+        },
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{close, pipe2, write, Pid},
+};
+use once_cell::sync::Lazy;
+
+/// Process survives closing of its descriptor.
+const PD_DAEMON: libc::c_int = 0x01;
+const PD_CLOEXEC: libc::c_int = 0x02;
+/// Exits reported by the watcher at once.
+const EXIT_EVENTS: usize = 16;
+/// Search path of the programs, unless `PATH` is set, see
+/// `_PATH_DEFPATH` of paths.h.
+const DEFAULT_PATH: &str = "/usr/bin:/bin";
+
+extern "C" {
+    fn pdfork(fdp: *mut libc::c_int, flags: libc::c_int) -> libc::pid_t;
+    fn pdkill(fd: libc::c_int, signum: libc::c_int) -> libc::c_int;
+}
+
+type Listener = Box<dyn Fn(i32) + Send + Sync>;
+
+/// Descriptors, keyed by pid.
+static DESCRIPTORS: Lazy<Mutex<BTreeMap<i32, RawFd>>> =
+    Lazy::new(Default::default);
+static LISTENERS: Lazy<Mutex<Vec<Listener>>> = Lazy::new(Default::default);
+/// kqueue of the descriptors, watched for exits.
+static EXITS: Lazy<Option<RawFd>> = Lazy::new(|| match kqueue() {
+    Ok(kqueue) => {
+        thread::spawn(move || report_exits(kqueue));
+        Some(kqueue)
+    }
+    Err(error) => {
+        tracing::error!("Exits of processes won't be reported: {}", error);
+        None
+    }
+});
+
+/// Program along with its arguments and environment, which
+/// are prepared beforehand for execve(2).
+pub struct Exec {
+    /// Candidate paths of the program, from its `PATH`.
+    paths: Vec<CString>,
+    args: Vec<CString>,
+    envs: Vec<CString>,
+    /// NULL-terminated pointers to the strings.
+    arg_pointers: Vec<*const libc::c_char>,
+    env_pointers: Vec<*const libc::c_char>,
+}
+
+// Pointers refer to the strings the exec owns, which are
+// never modified.
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+impl Exec {
+    /// Exec of the `program` with `args` and `envs`. The
+    /// program is looked up in the `PATH` of `envs`, unless it
+    /// contains a slash, just like execvp(3) does.
+    pub fn new(
+        program: &str,
+        args: &[String],
+        envs: &[(String, String)],
+    ) -> Result<Self, Error> {
+        let paths = if program.contains('/') {
+            vec![CString::new(program)?]
+        } else {
+            let path = envs
+                .iter()
+                .find(|(name, _)| name == "PATH")
+                .map(|(_, value)| value.as_str())
+                .unwrap_or(DEFAULT_PATH);
+
+            path.split(':')
+                .map(|folder| match folder {
+                    "" => CString::new(program),
+                    folder => CString::new(format!("{}/{}", folder, program)),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let args = Some(program)
+            .into_iter()
+            .chain(args.iter().map(String::as_str))
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let envs = envs
+            .iter()
+            .map(|(name, value)| CString::new(format!("{}={}", name, value)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            paths,
+            arg_pointers: pointers(&args),
+            env_pointers: pointers(&envs),
+            args,
+            envs,
+        })
+    }
+
+    /// Makes the `command` execute the program, once the rest
+    /// of its hooks, i.e. attaching to the jail, are done.
+    /// Arguments and environment of the `command` itself are
+    /// to be left untouched, so that it has nothing to
+    /// prepare in the child.
+    pub fn attach(self, command: &mut Command) {
+        unsafe {
+            command.pre_exec(move || {
+                let mut error = IoError::from_raw_os_error(libc::ENOENT);
+
+                for path in &self.paths {
+                    libc::execve(
+                        path.as_ptr(),
+                        self.arg_pointers.as_ptr(),
+                        self.env_pointers.as_ptr(),
+                    );
+
+                    // Returns on failure only
+                    let last_error = IoError::last_os_error();
+
+                    match last_error.raw_os_error() {
+                        Some(libc::ENOENT) | Some(libc::ENOTDIR) => (),
+                        Some(libc::EACCES) => error = last_error,
+                        _ => return Err(last_error),
+                    }
+                }
+
+                Err(error)
+            });
+        }
+    }
+}
+
+/// NULL-terminated pointers to the `strings`, i.e. argv.
+pub(crate) fn pointers(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|string| string.as_ptr())
+        .chain(Some(ptr::null()))
+        .collect()
+}
+
+/// Spawns the command, keeping the descriptor of the process.
+/// Returns its pid. The command is expected to be executed by
+/// an attached [`Exec`], see [`Exec::attach`].
+pub fn spawn(command: &mut Command) -> Result<i32, Error> {
+    // Closed on exec, carries the errno if exec fails
+    let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC)?;
+    let mut errors = unsafe { File::from_raw_fd(read_end) };
+    let mut fd = -1;
+
+    let pid = match unsafe { pdfork(&mut fd, PD_DAEMON | PD_CLOEXEC) } {
+        -1 => {
+            let _ = close(write_end);
+            anyhow::bail!("pdfork failed: {}", IoError::last_os_error());
+        }
+        0 => {
+            // Runs the hooks only: neither the environment nor
+            // the arguments of the command are set
+            let error = command.exec();
+            let errno = error.raw_os_error().unwrap_or(libc::EINVAL);
+
+            let _ = write(write_end, &errno.to_ne_bytes());
+            unsafe { libc::_exit(127) };
+        }
+        pid => pid,
+    };
+
+    let _ = close(write_end);
+    let mut errno = [0; 4];
+
+    if errors.read(&mut errno)? > 0 {
+        let _ = waitpid(Pid::from_raw(pid), None);
+        let _ = close(fd);
+
+        return Err(
+            IoError::from_raw_os_error(i32::from_ne_bytes(errno)).into()
+        );
+    }
+
+    if let Ok(mut descriptors) = DESCRIPTORS.lock() {
+        descriptors.insert(pid, fd);
+    }
+
+    if let Some(kqueue) = *EXITS {
+        let event = KEvent::new(
+            fd as _,
+            EventFilter::EVFILT_PROCDESC,
+            EventFlag::EV_ADD | EventFlag::EV_ONESHOT,
+            FilterFlag::NOTE_EXIT,
+            0,
+            pid as _,
+        );
+
+        kevent(kqueue, &[event], &mut [], 0)?;
+    }
+
+    Ok(pid)
+}
+
+/// Sends the signal via the descriptor of `pid`, if any.
+pub fn kill(pid: i32, signal: i32) -> Option<Result<(), Error>> {
+    let descriptors = DESCRIPTORS.lock().ok()?;
+    let fd = *descriptors.get(&pid)?;
+
+    if unsafe { pdkill(fd, signal) } < 0 {
+        return Some(Err(anyhow!(
+            "pdkill failed: {}",
+            IoError::last_os_error()
+        )));
+    }
+
+    Some(Ok(()))
+}
+
+/// Waits for the process to exit, and closes its descriptor.
+/// Fails with `ECHILD`, if the process is reaped by someone
+/// else already.
+pub fn wait(pid: i32) -> Result<WaitStatus, nix::Error> {
+    let result = waitpid(Pid::from_raw(pid), None);
+
+    release(pid);
+
+    result
+}
+
+/// Reaps the process, if it has exited, closing its
+/// descriptor then. Returns `None` while it's running.
+pub fn try_wait(pid: i32) -> Result<Option<WaitStatus>, nix::Error> {
+    match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::StillAlive) => Ok(None),
+        result => {
+            release(pid);
+
+            result.map(Some)
+        }
+    }
+}
+
+/// Closes the descriptor of the reaped process.
+pub fn release(pid: i32) {
+    let fd = match DESCRIPTORS.lock() {
+        Ok(mut descriptors) => descriptors.remove(&pid),
+        Err(_) => None,
+    };
+
+    if let Some(fd) = fd {
+        let _ = close(fd);
+    }
+}
+
+/// Calls `listener` with pids of the exited processes.
+pub fn on_exit(listener: impl Fn(i32) + Send + Sync + 'static) {
+    if let Ok(mut listeners) = LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+fn report_exits(kqueue: RawFd) {
+    let empty = KEvent::new(
+        0,
+        EventFilter::EVFILT_PROCDESC,
+        EventFlag::empty(),
+        FilterFlag::empty(),
+        0,
+        0,
+    );
+    let mut events = [empty; EXIT_EVENTS];
+
+    loop {
+        let count = match kevent_ts(kqueue, &[], &mut events, None) {
+            Ok(count) => count,
+            Err(error) => {
+                tracing::error!("Failed to watch exits: {}", error);
+                continue;
+            }
+        };
+        let listeners = match LISTENERS.lock() {
+            Ok(listeners) => listeners,
+            Err(_) => return,
+        };
+
+        for event in &events[..count] {
+            for listener in listeners.iter() {
+                listener(event.udata() as _);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(program: &str, args: &[&str]) -> Command {
+        let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut command = Command::new(program);
+
+        Exec::new(program, &args, &[("PATH".into(), "/bin".into())])
+            .expect("failed to prepare exec")
+            .attach(&mut command);
+
+        command
+    }
+
+    #[test]
+    fn test_spawn() {
+        let pid = spawn(&mut command("sh", &["-c", "exit 3"]))
+            .expect("failed to spawn process");
+
+        assert!(matches!(wait(pid), Ok(WaitStatus::Exited(_, 3))));
+        assert!(kill(pid, libc::SIGKILL).is_none());
+        assert!(spawn(&mut command("/nonexistent", &[])).is_err());
+        assert!(spawn(&mut command("nonexistent", &[])).is_err());
+    }
+
+    #[test]
+    fn test_exec() {
+        let exec = Exec::new(
+            "nginx",
+            &["-g".into()],
+            &[("PATH".into(), "/usr/sbin:".into())],
+        )
+        .unwrap();
+
+        assert_eq!(
+            exec.paths,
+            vec![
+                CString::new("/usr/sbin/nginx").unwrap(),
+                CString::new("nginx").unwrap()
+            ]
+        );
+        assert_eq!(exec.args[0], CString::new("nginx").unwrap());
+        assert_eq!(exec.arg_pointers.len(), 3);
+        assert!(exec.arg_pointers[2].is_null());
+        assert_eq!(exec.env_pointers[0], exec.envs[0].as_ptr());
+        assert_eq!(
+            Exec::new("/bin/sh", &[], &[]).unwrap().paths,
+            vec![CString::new("/bin/sh").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_try_wait() {
+        let pid = spawn(&mut command("sh", &["-c", "sleep 0.2; exit 3"]))
+            .expect("failed to spawn process");
+
+        assert!(matches!(try_wait(pid), Ok(None)));
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(matches!(try_wait(pid), Ok(Some(WaitStatus::Exited(_, 3)))));
+        assert!(kill(pid, libc::SIGKILL).is_none());
+    }
+}
@@ -0,0 +1,123 @@
+/// Approximation of OCI seccomp profiles.
+///
+/// FreeBSD has no seccomp: jails deny most of the privileged
+/// syscalls anyway, and the rest are guarded by jail
+/// parameters. A permission is revoked, once the profile of
+/// the `linux` section denies any of the syscalls it guards,
+/// i.e. denying `mount` revokes `allow.mount`. Other rules of
+/// the profile are ignored, since finer restrictions, i.e.
+/// of the syscalls' arguments, need MAC policies.
+use baustelle::runtime_config::Seccomp;
+use jail::param::Value;
+
+/// Actions, which let the syscall through.
+const ALLOWING_ACTIONS: [&str; 2] = ["SCMP_ACT_ALLOW", "SCMP_ACT_LOG"];
+
+/// Jail parameters and the syscalls they guard. Revoked
+/// parameters are set to 0, which also disables System V
+/// IPC (JAIL_SYS_DISABLE).
+const PERMISSIONS: &[(&str, &[&str])] = &[
+    ("allow.mount", &["mount", "umount", "umount2"]),
+    ("allow.mount.devfs", &["mount"]),
+    ("allow.mlock", &["mlock", "mlock2", "mlockall"]),
+    ("allow.set_hostname", &["sethostname", "setdomainname"]),
+    ("allow.unprivileged_proc_debug", &["ptrace"]),
+    ("allow.quotas", &["quotactl"]),
+    ("allow.read_msgbuf", &["syslog"]),
+    ("sysvmsg", &["msgget", "msgsnd", "msgrcv", "msgctl"]),
+    ("sysvsem", &["semget", "semop", "semtimedop", "semctl"]),
+    ("sysvshm", &["shmget", "shmat", "shmdt", "shmctl"]),
+];
+
+/// Jail parameters, which the profile revokes.
+pub fn jail_params(seccomp: &Seccomp) -> Vec<(&'static str, Value)> {
+    PERMISSIONS
+        .iter()
+        .filter(|(_, syscalls)| {
+            !syscalls.iter().all(|name| allowed(seccomp, name))
+        })
+        .map(|(param, _)| {
+            tracing::info!("Seccomp profile revokes {}", param);
+
+            (*param, Value::Int(0))
+        })
+        .collect()
+}
+
+/// Whether the profile lets the syscall through, at least
+/// for some of its arguments.
+fn allowed(seccomp: &Seccomp, syscall: &str) -> bool {
+    let rules = seccomp.syscalls.as_deref().unwrap_or(&[]);
+    let mut actions = rules
+        .iter()
+        .filter(|rule| rule.names.iter().any(|name| name == syscall))
+        .map(|rule| rule.action.as_str())
+        .peekable();
+
+    match actions.peek() {
+        None => allows(&seccomp.default_action),
+        Some(_) => actions.any(allows),
+    }
+}
+
+fn allows(action: &str) -> bool {
+    ALLOWING_ACTIONS.contains(&action)
+}
+
+#[cfg(test)]
+mod tests {
+    use baustelle::runtime_config::SyscallRule;
+
+    use super::*;
+
+    fn rule(names: &[&str], action: &str) -> SyscallRule {
+        SyscallRule {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            action: action.into(),
+            args: None,
+        }
+    }
+
+    fn revoked(seccomp: &Seccomp) -> Vec<&'static str> {
+        jail_params(seccomp)
+            .into_iter()
+            .map(|(param, _)| param)
+            .collect()
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let seccomp = Seccomp {
+            default_action: "SCMP_ACT_ERRNO".into(),
+            syscalls: Some(vec![
+                rule(&["mlock", "mlock2", "mlockall"], "SCMP_ACT_ALLOW"),
+                rule(&["ptrace", "sethostname"], "SCMP_ACT_ALLOW"),
+                rule(&["setdomainname", "quotactl"], "SCMP_ACT_ALLOW"),
+                rule(&["syslog"], "SCMP_ACT_LOG"),
+                rule(&["msgget", "msgsnd", "msgrcv"], "SCMP_ACT_ALLOW"),
+                rule(&["msgctl", "semget", "semop"], "SCMP_ACT_ALLOW"),
+                rule(&["semtimedop", "semctl"], "SCMP_ACT_ALLOW"),
+                rule(&["shmget", "shmat", "shmdt"], "SCMP_ACT_ALLOW"),
+            ]),
+        };
+
+        assert_eq!(
+            revoked(&seccomp),
+            vec!["allow.mount", "allow.mount.devfs", "sysvshm"]
+        );
+    }
+
+    #[test]
+    fn test_denylist() {
+        let seccomp = Seccomp {
+            default_action: "SCMP_ACT_ALLOW".into(),
+            syscalls: Some(vec![
+                rule(&["ptrace"], "SCMP_ACT_ERRNO"),
+                rule(&["umount2"], "SCMP_ACT_KILL"),
+                rule(&["umount2"], "SCMP_ACT_ALLOW"),
+            ]),
+        };
+
+        assert_eq!(revoked(&seccomp), vec!["allow.unprivileged_proc_debug"]);
+    }
+}
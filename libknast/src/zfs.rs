@@ -0,0 +1,293 @@
+/// ZFS-backed container root filesystems.
+///
+/// Unpacking image layers for every container is slow and
+/// wastes disk space. When the storage pool is ZFS, an image
+/// can be unpacked once into a dataset and snapshotted.
+/// Containers then get a writable clone of that snapshot,
+/// which is instant and copy-on-write.
+///
+/// The feature is selected via runtime config annotations:
+/// `org.freebsd.knast.zfs.origin` names the image snapshot
+/// to clone, and optional `org.freebsd.knast.zfs.parent`
+/// names the dataset under which per-container clones are
+/// created (`<pool>/knast/containers` by default).
+use std::{
+    collections::BTreeMap,
+    convert::AsRef,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Error};
+
+const ZFS_BINARY: &str = "/sbin/zfs";
+pub const ORIGIN_ANNOTATION: &str = "org.freebsd.knast.zfs.origin";
+pub const PARENT_ANNOTATION: &str = "org.freebsd.knast.zfs.parent";
+const IMAGE_SNAPSHOT_NAME: &str = "image";
+const DEFAULT_CONTAINERS_DATASET: &str = "knast/containers";
+
+/// A ZFS dataset, identified by its full name,
+/// i.e. `zroot/knast/containers/nginx`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dataset {
+    name: String,
+}
+
+impl Dataset {
+    pub fn new(name: impl AsRef<str>) -> Self {
+        Self {
+            name: name.as_ref().into(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the pool the dataset belongs to.
+    pub fn pool(&self) -> &str {
+        self.name.split('/').next().unwrap_or(&self.name)
+    }
+
+    /// Child of the pool the dataset belongs to.
+    pub fn pool_child(&self, name: impl AsRef<str>) -> Self {
+        Dataset::new(self.pool()).child(name)
+    }
+
+    /// Name of the dataset's child.
+    pub fn child(&self, name: impl AsRef<str>) -> Self {
+        Self::new(format!("{}/{}", self.name, name.as_ref()))
+    }
+
+    /// Name of the dataset's snapshot.
+    pub fn snapshot_name(&self, snapshot: impl AsRef<str>) -> String {
+        format!("{}@{}", self.name, snapshot.as_ref())
+    }
+
+    #[fehler::throws]
+    pub fn exists(&self) -> bool {
+        Command::new(ZFS_BINARY)
+            .args(&["list", "-H", "-o", "name", self.name.as_str()])
+            .output()?
+            .status
+            .success()
+    }
+
+    /// Creates the dataset along with its missing parents.
+    #[fehler::throws]
+    pub fn create(&self, mountpoint: Option<&Path>) {
+        let mut args = vec!["create".to_string(), "-p".into()];
+
+        if let Some(mountpoint) = mountpoint {
+            args.push("-o".into());
+            args.push(format!("mountpoint={}", mountpoint.display()));
+        }
+
+        args.push(self.name.clone());
+
+        zfs(&args.iter().map(String::as_str).collect::<Vec<_>>())?;
+    }
+
+    #[fehler::throws]
+    pub fn snapshot(&self, snapshot: impl AsRef<str>) -> String {
+        let name = self.snapshot_name(snapshot);
+
+        zfs(&["snapshot", name.as_str()])?;
+
+        name
+    }
+
+    /// Clones `origin` snapshot into this dataset, mounting it
+    /// at `mountpoint`.
+    #[fehler::throws]
+    pub fn clone_from(&self, origin: impl AsRef<str>, mountpoint: &Path) {
+        let mountpoint = format!("mountpoint={}", mountpoint.display());
+
+        zfs(&[
+            "clone",
+            "-p",
+            "-o",
+            mountpoint.as_str(),
+            origin.as_ref(),
+            self.name.as_str(),
+        ])?;
+    }
+
+    #[fehler::throws]
+    pub fn mountpoint(&self) -> PathBuf {
+        let name = self.name.as_str();
+
+        zfs(&["get", "-H", "-o", "value", "mountpoint", name])?
+            .trim()
+            .into()
+    }
+
+    /// Destroys the dataset, forcibly unmounting it.
+    #[fehler::throws]
+    pub fn destroy(&self) {
+        zfs(&["destroy", "-f", "-r", self.name.as_str()])?;
+    }
+
+    /// Destroys the dataset, unless it's gone already.
+    #[fehler::throws]
+    pub fn destroy_existing(&self) {
+        if self.exists()? {
+            tracing::info!("Destroying {}", self.name);
+            self.destroy()?;
+        }
+    }
+}
+
+/// Returns the image snapshot for `digest` under `parent`,
+/// creating it when missing. `populate` receives the
+/// mountpoint of the freshly created image dataset and is
+/// expected to unpack the image there.
+#[fehler::throws]
+pub fn image_snapshot(
+    parent: &Dataset,
+    digest: impl AsRef<str>,
+    populate: impl FnOnce(&Path) -> Result<(), Error>,
+) -> String {
+    let dataset = parent.child(dataset_component(digest.as_ref()));
+    let snapshot = dataset.snapshot_name(IMAGE_SNAPSHOT_NAME);
+
+    if Dataset::new(&snapshot).exists()? {
+        return snapshot;
+    }
+
+    if !dataset.exists()? {
+        dataset.create(None)?;
+    }
+
+    if let Err(error) = populate(&dataset.mountpoint()?) {
+        dataset.destroy()?;
+        fehler::throw!(error);
+    }
+
+    dataset.snapshot(IMAGE_SNAPSHOT_NAME)?
+}
+
+/// Per-container clone of an image snapshot, as requested by
+/// runtime config annotations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerClone {
+    origin: String,
+    dataset: Dataset,
+}
+
+impl ContainerClone {
+    /// Reads the clone settings from runtime config
+    /// annotations. Returns `None` if the container doesn't
+    /// use ZFS.
+    pub fn from_annotations(
+        annotations: Option<&BTreeMap<String, String>>,
+        key: impl AsRef<str>,
+    ) -> Option<Self> {
+        let annotations = annotations?;
+        let origin = annotations.get(ORIGIN_ANNOTATION)?.clone();
+        let parent = annotations
+            .get(PARENT_ANNOTATION)
+            .map(Dataset::new)
+            .unwrap_or_else(|| {
+                Dataset::new(&origin).pool_child(DEFAULT_CONTAINERS_DATASET)
+            });
+
+        Some(Self {
+            origin,
+            dataset: parent.child(dataset_component(key.as_ref())),
+        })
+    }
+
+    pub fn dataset(&self) -> &Dataset {
+        &self.dataset
+    }
+
+    /// Clones the origin, mounting the clone at `rootfs`. The
+    /// clone fails, if the dataset exists already, so that
+    /// datasets of others are never taken over.
+    #[fehler::throws]
+    pub fn create(&self, rootfs: impl AsRef<Path>) {
+        if !Dataset::new(&self.origin).exists()? {
+            fehler::throw!(anyhow!(
+                "ZFS snapshot {} doesn't exist",
+                self.origin
+            ));
+        }
+
+        tracing::info!("Cloning {} into {}", self.origin, self.dataset.name());
+        self.dataset.clone_from(&self.origin, rootfs.as_ref())?;
+    }
+}
+
+/// Dataset names can't contain ':' (i.e. in digests) and
+/// other special characters. These are escaped as `_` and
+/// two hex digits, `_` itself included, so that distinct
+/// names never share a dataset.
+fn dataset_component(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("_{:02x}", byte),
+        })
+        .collect()
+}
+
+#[fehler::throws]
+fn zfs(args: &[&str]) -> String {
+    let output = Command::new(ZFS_BINARY).args(args).output()?;
+
+    if !output.status.success() {
+        fehler::throw!(anyhow!(
+            "zfs failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    String::from_utf8(output.stdout)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clone_from_annotations() {
+        let mut annotations = BTreeMap::new();
+        annotations.insert(
+            ORIGIN_ANNOTATION.into(),
+            "zroot/knast/images/sha256_abc@image".into(),
+        );
+
+        let clone =
+            ContainerClone::from_annotations(Some(&annotations), "nginx")
+                .expect("ZFS clone wasn't configured");
+
+        assert_eq!(clone.dataset().name(), "zroot/knast/containers/nginx");
+
+        annotations.insert(PARENT_ANNOTATION.into(), "tank/jails".into());
+
+        let clone =
+            ContainerClone::from_annotations(Some(&annotations), "nginx")
+                .expect("ZFS clone wasn't configured");
+
+        assert_eq!(clone.dataset().name(), "tank/jails/nginx");
+    }
+
+    #[test]
+    fn test_clone_is_not_configured() {
+        assert_eq!(ContainerClone::from_annotations(None, "nginx"), None);
+        assert_eq!(
+            ContainerClone::from_annotations(Some(&BTreeMap::new()), "nginx"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_dataset_component() {
+        assert_eq!(dataset_component("sha256:abcd"), "sha256_3aabcd");
+        assert_eq!(dataset_component("k8s.io/nginx"), "k8s.io_2fnginx");
+        assert_ne!(dataset_component("a_b:c"), dataset_component("a:b_c"));
+    }
+}
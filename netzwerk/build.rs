@@ -1,5 +1,10 @@
+use std::{env, fs, process::Command};
+
 use bindgen::builder;
 
+/// sys/param.h of the sysroot the bindings are generated from.
+const PARAM_HEADER: &str = "/usr/include/sys/param.h";
+
 fn main() {
     let bindings = builder()
         .header("ffi/ffi.h")
@@ -9,4 +14,34 @@ fn main() {
     bindings
         .write_to_file("src/bindings.rs")
         .expect("failed to write bindings on disk");
+
+    println!(
+        "cargo:rustc-env=NETZWERK_FREEBSD_VERSION={}",
+        freebsd_version().unwrap_or(0)
+    );
+}
+
+/// `__FreeBSD_version` the structures are laid out for.
+/// Falls back to the version of the build host, when the
+/// header doesn't tell.
+fn freebsd_version() -> Option<u32> {
+    let sysroot = env::var("SYSROOT").unwrap_or_default();
+    let header = format!("{}{}", sysroot, PARAM_HEADER);
+
+    fs::read_to_string(&header)
+        .ok()
+        .and_then(|header| {
+            header
+                .lines()
+                .map(|line| line.split_whitespace().collect::<Vec<_>>())
+                .find(|words| {
+                    words.starts_with(&["#define", "__FreeBSD_version"])
+                })
+                .and_then(|words| words.get(2)?.parse().ok())
+        })
+        .or_else(|| {
+            let output = Command::new("uname").arg("-U").output().ok()?;
+
+            String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+        })
 }
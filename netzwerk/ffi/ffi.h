@@ -1,5 +1,11 @@
+#include <sys/param.h>
+#include <sys/ioccom.h>
 #include <net/if.h>
+#include <net/bpf.h>
 #include <net/pfvar.h>
 #include <net/ethernet.h>
 #include <net/if_bridgevar.h>
+#include <net/route.h>
+#include <netinet/in.h>
+#include <netinet/ip_dummynet.h>
 #include <sys/sockio.h>
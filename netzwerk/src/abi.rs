@@ -0,0 +1,79 @@
+//! Sanity check of the ABI. Layouts of the pf, interface
+//! and routing structures change between major releases of
+//! FreeBSD, and the kernel rejects, or worse, misreads the
+//! requests built for a different one.
+use std::{ffi::CStr, io::Error as StdError, mem};
+
+use anyhow::{anyhow, Error};
+use libc::{c_int, sysctlbyname};
+
+/// `__FreeBSD_version` the bindings are generated for, 0 if
+/// the build failed to detect it.
+const BUILT_VERSION: &str = env!("NETZWERK_FREEBSD_VERSION");
+
+/// Fails if the running kernel is of a major release other
+/// than the one knast is built for.
+#[fehler::throws]
+pub(crate) fn check() {
+    let built: u32 = BUILT_VERSION.parse()?;
+
+    if built == 0 {
+        return;
+    }
+
+    let running = running_version()?;
+
+    if major(built) != major(running) {
+        fehler::throw!(anyhow!(
+            "ABI mismatch: netzwerk is built against FreeBSD {}, \
+             running {}. Rebuild knast on this host",
+            built,
+            running
+        ));
+    }
+}
+
+/// `kern.osreldate`, i.e. `__FreeBSD_version` of the
+/// running kernel.
+#[fehler::throws]
+fn running_version() -> u32 {
+    let name = CStr::from_bytes_with_nul(b"kern.osreldate\0")?;
+    let mut version: c_int = 0;
+    let mut size = mem::size_of::<c_int>();
+
+    let result = unsafe {
+        sysctlbyname(
+            name.as_ptr(),
+            &mut version as *mut _ as _,
+            &mut size,
+            std::ptr::null(),
+            0,
+        )
+    };
+
+    if result < 0 {
+        fehler::throw!(anyhow!(
+            "sysctl(kern.osreldate) failed: {}",
+            StdError::last_os_error()
+        ));
+    }
+
+    version as u32
+}
+
+/// 1300139 is 13.0
+fn major(version: u32) -> u32 {
+    version / 100_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(running_version().unwrap() > 0);
+        assert!(check().is_ok());
+        assert_eq!(major(1300139), 13);
+    }
+}
@@ -0,0 +1,296 @@
+//! ARP probes of IPv4 addresses, so that addresses used on
+//! the link are detected before they're assigned, see RFC
+//! 5227. Probes are sent and answers are read through
+//! bpf(4).
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{Error as StdError, Read, Write},
+    mem,
+    net::Ipv4Addr,
+    os::unix::io::AsRawFd,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use common_lib::AsSignedBytes;
+use libc::{c_long, c_void, ioctl, sockaddr, sockaddr_dl, timeval, AF_LINK};
+
+use crate::{
+    bindings::{bpf_hdr, ifreq},
+    ioccom::{ior, iow},
+};
+
+// net/bpf.h
+const BIOCGBLEN: u64 = ior(b'B', 102, mem::size_of::<u32>());
+const BIOCSETIF: u64 = iow(b'B', 108, mem::size_of::<ifreq>());
+const BIOCSRTIMEOUT: u64 = iow(b'B', 109, mem::size_of::<timeval>());
+const BIOCIMMEDIATE: u64 = iow(b'B', 112, mem::size_of::<u32>());
+const BIOCSHDRCMPLT: u64 = iow(b'B', 117, mem::size_of::<u32>());
+
+const BPF_DEVICE: &str = "/dev/bpf";
+/// Reads return this often, so that probes are resent.
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IP: u16 = 0x0800;
+const ARPHRD_ETHER: u16 = 1;
+const ARPOP_REQUEST: u16 = 1;
+/// Ethernet header and ARP packet of IPv4 over Ethernet.
+const FRAME_LENGTH: usize = 42;
+
+/// Whether a host on the link of the `interface` uses the
+/// `address`, i.e. answers its probes or probes it itself.
+/// Sends `probes` probes, `wait` apart.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+///
+/// netzwerk::arp::probe(
+///     "knast0",
+///     "172.24.0.5".parse().unwrap(),
+///     2,
+///     Duration::from_millis(250),
+/// )
+/// .expect("Failed to probe the address");
+/// ```
+#[fehler::throws]
+pub fn probe(
+    interface: &str,
+    address: Ipv4Addr,
+    probes: u32,
+    wait: Duration,
+) -> bool {
+    let source = link_address(interface)?;
+    let mut device = open(interface)?;
+    let mut buffer = vec![0; buffer_length(&device)?];
+    let request = request(source, address);
+
+    for _ in 0..probes {
+        device.write_all(&request)?;
+
+        let deadline = Instant::now() + wait;
+
+        while Instant::now() < deadline {
+            let length = device.read(&mut buffer)?;
+
+            if frames(&buffer[..length])
+                .filter_map(sender)
+                .any(|(mac, ip)| ip == address && mac != source)
+            {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// Probe of the `target`: a broadcast request, which has no
+/// sender address, so that caches of the hosts are intact.
+fn request(source: [u8; 6], target: Ipv4Addr) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_LENGTH);
+
+    frame.extend_from_slice(&[0xff; 6]);
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    frame.extend_from_slice(&ARPHRD_ETHER.to_be_bytes());
+    frame.extend_from_slice(&ETHERTYPE_IP.to_be_bytes());
+    frame.extend_from_slice(&[6, 4]);
+    frame.extend_from_slice(&ARPOP_REQUEST.to_be_bytes());
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&[0; 4]);
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&target.octets());
+
+    frame
+}
+
+/// Sender of the ARP packet of IPv4 over Ethernet, if the
+/// frame is one.
+fn sender(frame: &[u8]) -> Option<([u8; 6], Ipv4Addr)> {
+    if frame.len() < FRAME_LENGTH
+        || frame[12..14] != ETHERTYPE_ARP.to_be_bytes()
+        || frame[16..18] != ETHERTYPE_IP.to_be_bytes()
+    {
+        return None;
+    }
+
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&frame[22..28]);
+
+    Some((
+        mac,
+        Ipv4Addr::new(frame[28], frame[29], frame[30], frame[31]),
+    ))
+}
+
+/// Frames of the buffer bpf(4) has read, each following its
+/// `bpf_hdr`.
+fn frames(buffer: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let alignment = mem::size_of::<c_long>();
+    let mut offset = 0;
+
+    std::iter::from_fn(move || {
+        if offset + mem::size_of::<bpf_hdr>() > buffer.len() {
+            return None;
+        }
+
+        let header: bpf_hdr = unsafe {
+            ptr::read_unaligned(buffer[offset..].as_ptr() as *const _)
+        };
+        let start = offset + header.bh_hdrlen as usize;
+        let end = (start + header.bh_caplen as usize).min(buffer.len());
+
+        offset = (end + alignment - 1) & !(alignment - 1);
+
+        buffer.get(start..end)
+    })
+}
+
+/// bpf(4) device, attached to the `interface`. Reads return
+/// as soon as frames arrive, or after [`READ_TIMEOUT`].
+#[fehler::throws]
+fn open(interface: &str) -> File {
+    crate::abi::check()?;
+
+    let device = OpenOptions::new().read(true).write(true).open(BPF_DEVICE)?;
+    let mut request: ifreq = unsafe { mem::zeroed() };
+    let name = [interface, "\0"].concat();
+    let mut timeout = timeval {
+        tv_sec: 0,
+        tv_usec: READ_TIMEOUT.as_micros() as _,
+    };
+    let mut enable: u32 = 1;
+
+    if name.len() > request.ifr_name.len() {
+        fehler::throw!(anyhow!("interface name {} is too long", interface));
+    }
+
+    request.ifr_name[..name.len()]
+        .copy_from_slice(name.as_str().as_signed_bytes());
+
+    control(&device, BIOCSETIF, &mut request, "BIOCSETIF")?;
+    control(&device, BIOCIMMEDIATE, &mut enable, "BIOCIMMEDIATE")?;
+    control(&device, BIOCSHDRCMPLT, &mut enable, "BIOCSHDRCMPLT")?;
+    control(&device, BIOCSRTIMEOUT, &mut timeout, "BIOCSRTIMEOUT")?;
+
+    device
+}
+
+/// Reads of the device take buffers of exactly this length.
+#[fehler::throws]
+fn buffer_length(device: &File) -> usize {
+    let mut length: u32 = 0;
+
+    control(device, BIOCGBLEN, &mut length, "BIOCGBLEN")?;
+
+    length as usize
+}
+
+#[fehler::throws]
+fn control<T>(device: &File, command: u64, argument: &mut T, name: &str) {
+    let argument = argument as *mut T as *mut c_void;
+
+    if unsafe { ioctl(device.as_raw_fd(), command, argument) } < 0 {
+        fehler::throw!(anyhow!(
+            "arp probe: ioctl({}) failed: {}",
+            name,
+            StdError::last_os_error()
+        ))
+    }
+}
+
+/// Ethernet address of the `interface`.
+#[fehler::throws]
+fn link_address(interface: &str) -> [u8; 6] {
+    let mut addresses = ptr::null_mut();
+
+    if unsafe { libc::getifaddrs(&mut addresses) } < 0 {
+        fehler::throw!(anyhow!(
+            "arp probe: getifaddrs failed: {}",
+            StdError::last_os_error()
+        ))
+    }
+
+    let mut result = None;
+    let mut current = addresses;
+
+    while let Some(address) = unsafe { current.as_ref() } {
+        current = address.ifa_next;
+
+        let name = unsafe { CStr::from_ptr(address.ifa_name) };
+        let link = match unsafe { address.ifa_addr.as_ref() } {
+            Some(link) if link.sa_family as i32 == AF_LINK => unsafe {
+                &*(link as *const sockaddr as *const sockaddr_dl)
+            },
+            _ => continue,
+        };
+
+        if name.to_bytes() == interface.as_bytes() && link.sdl_alen == 6 {
+            let start = link.sdl_nlen as usize;
+            let mut mac = [0; 6];
+
+            for (byte, data) in mac.iter_mut().zip(&link.sdl_data[start..]) {
+                *byte = *data as u8;
+            }
+
+            result = Some(mac);
+            break;
+        }
+    }
+
+    unsafe { libc::freeifaddrs(addresses) };
+
+    result.ok_or_else(|| {
+        anyhow!("arp probe: {} has no Ethernet address", interface)
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let source = [2, 0, 0, 0, 0, 0x0a];
+        let frame = request(source, Ipv4Addr::new(172, 24, 0, 5));
+
+        assert_eq!(frame.len(), FRAME_LENGTH);
+        assert_eq!(sender(&frame), Some((source, Ipv4Addr::UNSPECIFIED)));
+        assert_eq!(&frame[38..], &[172, 24, 0, 5]);
+    }
+
+    #[test]
+    fn test_frames() {
+        let header = mem::size_of::<bpf_hdr>();
+        let alignment = mem::size_of::<c_long>();
+        let frame = request([2, 0, 0, 0, 0, 0x0a], Ipv4Addr::LOCALHOST);
+        let mut buffer = vec![0; 2 * (header + 48)];
+        let mut offset = 0;
+
+        for _ in 0..2 {
+            let mut bpf: bpf_hdr = unsafe { mem::zeroed() };
+            bpf.bh_hdrlen = header as _;
+            bpf.bh_caplen = frame.len() as _;
+            bpf.bh_datalen = frame.len() as _;
+
+            unsafe {
+                ptr::write_unaligned(
+                    buffer[offset..].as_mut_ptr() as *mut bpf_hdr,
+                    bpf,
+                )
+            };
+            buffer[offset + header..][..frame.len()].copy_from_slice(&frame);
+            offset += header + frame.len();
+            offset = (offset + alignment - 1) & !(alignment - 1);
+        }
+
+        let frames: Vec<_> = frames(&buffer[..offset]).collect();
+
+        assert_eq!(frames, vec![&frame[..], &frame[..]]);
+    }
+}
@@ -13,6 +13,8 @@ pub struct Socket(pub i32);
 impl Socket {
     #[fehler::throws]
     pub fn new(domain: c_int, r#type: c_int) -> Self {
+        crate::abi::check()?;
+
         let sock = unsafe { socket(domain, r#type, 0) };
 
         if sock < 0 {
@@ -0,0 +1,118 @@
+//! dummynet(4) pipes, limiting the bandwidth of the traffic
+//! pf passes to them, see [`crate::pf::Shaping`]. Pipes are
+//! configured the way ipfw(8) does, i.e. `dnctl pipe 1
+//! config bw 10Mbit/s`.
+use std::{io::Error as StdError, mem};
+
+use anyhow::{anyhow, Error};
+use libc::{c_void, setsockopt, AF_INET, IPPROTO_IP, SOCK_RAW};
+
+use crate::{
+    bindings::{
+        dn_fs, dn_id, dn_link, dn_sch, DN_API_VERSION, DN_CMD_CONFIG,
+        DN_CMD_DELETE, DN_FS, DN_LINK, DN_MAX_ID, DN_PIPE_CMD, DN_SCH,
+        IP_DUMMYNET3,
+    },
+    common_bindings::Socket,
+};
+
+/// Objects of the command, back to back.
+#[repr(C)]
+struct PipeConfig {
+    command: dn_id,
+    scheduler: dn_sch,
+    link: dn_link,
+    flowset: dn_fs,
+}
+
+#[repr(C)]
+struct PipeDelete {
+    command: dn_id,
+    numbers: [usize; 1],
+}
+
+/// Configures the pipe `number`, creating it if needed, to
+/// pass `bandwidth` bits per second.
+#[fehler::throws]
+pub fn configure_pipe(number: u16, bandwidth: u32) {
+    let number = number as u32;
+    let mut config: PipeConfig = unsafe { mem::zeroed() };
+
+    config.command = object::<dn_id>(DN_CMD_CONFIG);
+    config.command.id = DN_API_VERSION as _;
+
+    // Pipe commands get the FIFO scheduler and flowset of
+    // their own, numbered past the pipes
+    config.scheduler.oid = object::<dn_sch>(DN_SCH);
+    config.scheduler.sched_nr = number;
+    config.scheduler.flags = DN_PIPE_CMD as _;
+
+    config.link.oid = object::<dn_link>(DN_LINK);
+    config.link.link_nr = number;
+    config.link.bandwidth = bandwidth as _;
+
+    config.flowset.oid = object::<dn_fs>(DN_FS);
+    config.flowset.fs_nr = number + 2 * DN_MAX_ID;
+    config.flowset.sched_nr = number + DN_MAX_ID;
+
+    command(&config, "configure pipe")?;
+}
+
+/// Deletes the pipe `number`, along with its scheduler and
+/// flowset.
+#[fehler::throws]
+pub fn delete_pipe(number: u16) {
+    let mut delete = PipeDelete {
+        command: object::<PipeDelete>(DN_CMD_DELETE),
+        numbers: [number as _],
+    };
+
+    delete.command.subtype = DN_LINK as _;
+    delete.command.id = DN_API_VERSION as _;
+
+    command(&delete, "delete pipe")?;
+}
+
+/// Header of the object `T`.
+fn object<T>(r#type: u32) -> dn_id {
+    let mut id: dn_id = unsafe { mem::zeroed() };
+
+    id.len = mem::size_of::<T>() as _;
+    id.type_ = r#type as _;
+
+    id
+}
+
+#[fehler::throws]
+fn command<T>(command: &T, name: &str) {
+    let socket = Socket::new(AF_INET, SOCK_RAW)?;
+    let result = unsafe {
+        setsockopt(
+            socket.0,
+            IPPROTO_IP,
+            IP_DUMMYNET3 as _,
+            command as *const _ as *const c_void,
+            mem::size_of::<T>() as _,
+        )
+    };
+
+    if result < 0 {
+        fehler::throw!(anyhow!(
+            "{}: setsockopt(IP_DUMMYNET3) failed: {}",
+            name,
+            StdError::last_os_error()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_helpers::jailed_test]
+    fn test_pipe() {
+        configure_pipe(4242, 10_000_000).expect("failed to configure pipe");
+        configure_pipe(4242, 20_000_000).expect("failed to reconfigure");
+        delete_pipe(4242).expect("failed to delete pipe");
+    }
+}
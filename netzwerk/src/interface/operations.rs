@@ -6,21 +6,19 @@ use common_lib::AsSignedBytes;
 use libc::ioctl;
 
 use crate::{
-    bindings::{ifaliasreq, ifbreq, ifdrv, ifreq},
+    bindings::{ifaliasreq, ifbreq, ifdrv, ifreq, BRDGADD, BRDGDEL},
     common_bindings::{get_address, Socket},
+    ioccom::{iow, iowr},
 };
 
-// FreeBSD 13.0-CURRENT r361779
-const SIOCAIFADDR: u64 = 0x8044692b;
-const SIOCIFCREATE: u64 = 0xc020697a;
-const SIOCSIFNAME: u64 = 0x80206928;
-const SIOCIFDESTROY: u64 = 0x80206979;
-const SIOCSDRVSPEC: u64 = 0x8028697b;
-const SIOCSIFVNET: u64 = 0xc020695a;
-const SIOCGIFCAP: u64 = 0xc020691f;
-
-const BRDGADD: u64 = 0x0;
-const BRDGDEL: u64 = 0x1;
+// sys/sockio.h
+const SIOCAIFADDR: u64 = iow(b'i', 43, mem::size_of::<ifaliasreq>());
+const SIOCIFCREATE: u64 = iowr(b'i', 122, mem::size_of::<ifreq>());
+const SIOCSIFNAME: u64 = iow(b'i', 40, mem::size_of::<ifreq>());
+const SIOCIFDESTROY: u64 = iow(b'i', 121, mem::size_of::<ifreq>());
+const SIOCSDRVSPEC: u64 = iow(b'i', 123, mem::size_of::<ifdrv>());
+const SIOCSIFVNET: u64 = iowr(b'i', 90, mem::size_of::<ifreq>());
+const SIOCGIFCAP: u64 = iowr(b'i', 31, mem::size_of::<ifreq>());
 
 #[fehler::throws]
 pub fn destroy_interface(socket: &Socket, request: &ifreq) {
@@ -115,7 +113,7 @@ macro_rules! bridge_request {
 
             let mut request: ifdrv = unsafe { mem::zeroed() };
             request.ifd_name[0..name.len()].copy_from_slice(name);
-            request.ifd_cmd = $cmd;
+            request.ifd_cmd = $cmd as _;
             request.ifd_len = mem::size_of::<ifbreq>() as _;
             request.ifd_data = &bridge_request as *const _ as _;
 
@@ -0,0 +1,39 @@
+//! Request codes of ioctl(2), mirroring the macros of
+//! sys/ioccom.h, which bindgen doesn't expand. Codes are
+//! derived from the generated structures, so that they
+//! follow the layouts of the headers knast is built against.
+use crate::bindings::{IOCPARM_MASK, IOC_IN, IOC_INOUT, IOC_OUT};
+
+const fn ioc(inout: u32, group: u8, nr: u8, size: usize) -> u64 {
+    let length = (size as u32 & IOCPARM_MASK) << 16;
+
+    (inout | length | (group as u32) << 8 | nr as u32) as u64
+}
+
+/// `_IOR(group, nr, T)`
+pub(crate) const fn ior(group: u8, nr: u8, size: usize) -> u64 {
+    ioc(IOC_OUT, group, nr, size)
+}
+
+/// `_IOW(group, nr, T)`
+pub(crate) const fn iow(group: u8, nr: u8, size: usize) -> u64 {
+    ioc(IOC_IN, group, nr, size)
+}
+
+/// `_IOWR(group, nr, T)`
+pub(crate) const fn iowr(group: u8, nr: u8, size: usize) -> u64 {
+    ioc(IOC_INOUT, group, nr, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioc() {
+        // FreeBSD 13.0-CURRENT r361779
+        assert_eq!(iowr(b'D', 81, 0x10), 0xc0104451);
+        assert_eq!(iow(b'i', 121, 0x20), 0x80206979);
+        assert_eq!(ior(b'i', 31, 0x20), 0x4020691f);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod arp;
+pub mod dummynet;
 pub mod interface;
 pub mod nat;
 pub mod pf;
@@ -10,5 +12,7 @@ pub mod route;
     dead_code,
     non_upper_case_globals
 )]
+mod abi;
 mod bindings;
 mod common_bindings;
+mod ioccom;
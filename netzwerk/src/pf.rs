@@ -7,71 +7,356 @@ use std::{
 };
 
 use crate::bindings::{
-    pfioc_pooladdr, pfioc_rule, pfioc_table, pfioc_trans,
-    pfioc_trans_pfioc_trans_e, pfr_addr, pfr_table, PFI_AFLAG_NOALIAS,
-    PFR_TFLAG_PERSIST, PF_ADDR_DYNIFTL, PF_NAT, PF_RULESET_NAT,
+    pf_rule, pf_rule_addr, pf_status, pfioc_pooladdr, pfioc_rule, pfioc_table,
+    pfioc_trans, pfioc_trans_pfioc_trans_e, pfr_addr, pfr_table,
+    FCNT_STATE_INSERT, FCNT_STATE_REMOVALS, FCNT_STATE_SEARCH, IFNAMSIZ,
+    MAXPATHLEN, PFI_AFLAG_NOALIAS, PFRULE_DN_IS_PIPE, PFR_TFLAG_PERSIST,
+    PF_ADDR_ADDRMASK, PF_ADDR_DYNIFTL, PF_ADDR_TABLE, PF_CHANGE_ADD_HEAD,
+    PF_CHANGE_GET_TICKET, PF_DROP, PF_NAT, PF_OP_EQ, PF_PASS, PF_POOL_BITMASK,
+    PF_POOL_NONE, PF_POOL_ROUNDROBIN, PF_RDR, PF_RULESET_FILTER,
+    PF_RULESET_NAT, PF_RULESET_RDR, PF_STATE_NORMAL, PF_TABLE_NAME_SIZE,
+    PF_TAG_NAME_SIZE,
 };
 use anyhow::{anyhow, Error};
 use common_lib::AsSignedBytes;
 use ipnetwork::Ipv4Network;
-use libc::{ioctl, AF_INET};
+use libc::{ioctl, AF_INET, IPPROTO_TCP, IPPROTO_UDP};
 
-use super::nat::Nat;
+use super::{abi, ioccom::iowr, nat::Nat};
 
 const PF_DEVICE_PATH: &str = "/dev/pf";
-const ANCHOR: [i8; 12] = unsafe { mem::transmute(*b"knast_anker\0") };
-const TABLE_NAME: [i8; 6] = unsafe { mem::transmute(*b"jails\0") };
-
-const DIOCXBEGIN: u64 = 0xc0104451;
-const DIOCXCOMMIT: u64 = 0xc0104452;
-const DIOCXROLLBACK: u64 = 0xc0104453;
-const DIOCBEGINADDRS: u64 = 0xc4704433;
-const DIOCADDADDR: u64 = 0xc4704434;
-const DIOCADDRULE: u64 = 0xcbe04404;
-const DIOCRADDTABLES: u64 = 0xc450443d;
-const DIOCRADDADDRS: u64 = 0xc4504443;
+const DEFAULT_ANCHOR: &str = "knast_anker";
+const DEFAULT_TABLE: &str = "jails";
+const DEFAULT_ISOLATION_TABLE: &str = "isolated";
+const DEFAULT_ISOLATION_ANCHOR: &str = "knast_isolation";
+/// Tag of the traffic between the allowed pairs, which
+/// isolation doesn't block.
+const ALLOWED_TAG: &str = "knast_allowed";
+
+// net/pfvar.h
+const DIOCXBEGIN: u64 = iowr(b'D', 81, mem::size_of::<pfioc_trans>());
+const DIOCXCOMMIT: u64 = iowr(b'D', 82, mem::size_of::<pfioc_trans>());
+const DIOCXROLLBACK: u64 = iowr(b'D', 83, mem::size_of::<pfioc_trans>());
+const DIOCBEGINADDRS: u64 = iowr(b'D', 51, mem::size_of::<pfioc_pooladdr>());
+const DIOCADDADDR: u64 = iowr(b'D', 52, mem::size_of::<pfioc_pooladdr>());
+const DIOCADDRULE: u64 = iowr(b'D', 4, mem::size_of::<pfioc_rule>());
+const DIOCGETRULES: u64 = iowr(b'D', 6, mem::size_of::<pfioc_rule>());
+const DIOCGETRULE: u64 = iowr(b'D', 7, mem::size_of::<pfioc_rule>());
+const DIOCCHANGERULE: u64 = iowr(b'D', 26, mem::size_of::<pfioc_rule>());
+const DIOCGETSTATUS: u64 = iowr(b'D', 21, mem::size_of::<pf_status>());
+const DIOCRGETADDRS: u64 = iowr(b'D', 70, mem::size_of::<pfioc_table>());
+const DIOCRADDTABLES: u64 = iowr(b'D', 61, mem::size_of::<pfioc_table>());
+const DIOCRADDADDRS: u64 = iowr(b'D', 67, mem::size_of::<pfioc_table>());
+const DIOCRDELADDRS: u64 = iowr(b'D', 68, mem::size_of::<pfioc_table>());
 
 // https://github.com/freebsd/freebsd-src/blob/098dbd7ff7f3da9dda03802cdb2d8755f816eada/sbin/pfctl/pfctl_parser.h
 const PF_NAT_PORT_RANGE: [u16; 2] = [50001, 65535];
 
 pub struct Pf {
     pf_device: File,
+    config: NatConfig,
+    /// NUL-terminated names of the config.
+    anchor: Vec<i8>,
+    table: Vec<i8>,
+    isolation_table: Vec<i8>,
+    isolation_anchor: Vec<i8>,
+}
+
+/// NAT of the containers. Anchor and table are named, so
+/// that they don't collide with other rulesets of the host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatConfig {
+    /// Interface the containers' traffic leaves through.
+    pub interface: String,
+    pub anchor: String,
+    /// Table of the containers' networks.
+    pub table: String,
+    /// Source ports of the translated connections.
+    pub port_range: (u16, u16),
+    /// Keeps source ports as they are, `port_range` is
+    /// ignored then.
+    pub static_port: bool,
+    pub pool: Pool,
+    /// Addresses of the containers isolated from each other,
+    /// see [`Pf::filter`].
+    pub isolation_table: String,
+    /// Anchor of the isolation rules, which holds block rules
+    /// only and is called before the host's rules.
+    pub isolation_anchor: String,
+}
+
+/// How translation addresses are picked from the pool, see
+/// pf.conf(5).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pool {
+    Default,
+    Bitmask,
+    RoundRobin,
+}
+
+impl NatConfig {
+    pub fn new(interface: impl Into<String>) -> Self {
+        Self {
+            interface: interface.into(),
+            anchor: DEFAULT_ANCHOR.into(),
+            table: DEFAULT_TABLE.into(),
+            port_range: (PF_NAT_PORT_RANGE[0], PF_NAT_PORT_RANGE[1]),
+            static_port: false,
+            pool: Pool::Default,
+            isolation_table: DEFAULT_ISOLATION_TABLE.into(),
+            isolation_anchor: DEFAULT_ISOLATION_ANCHOR.into(),
+        }
+    }
+}
+
+/// NAT rule of the knast anchor, along with its counters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatRule {
+    pub interface: String,
+    pub evaluations: u64,
+    /// Packets and bytes, both directions together.
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Filter rules of the anchor, see [`Pf::filter`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    /// Networks, which talk to each other despite the
+    /// isolation, both ways.
+    pub allowed: Vec<(Ipv4Network, Ipv4Network)>,
+    pub shaped: Vec<Shaping>,
+}
+
+/// dummynet(4) pipes of the container, see
+/// [`crate::dummynet`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shaping {
+    pub address: Ipv4Addr,
+    /// Pipe of the traffic the container sends, if limited.
+    pub egress: Option<u16>,
+    /// Pipe of the traffic the container receives.
+    pub ingress: Option<u16>,
+}
+
+/// Port of the interface, which pf redirects to the port of
+/// the container, see [`Pf::redirect`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub interface: String,
+    pub protocol: Protocol,
+    pub host_port: u16,
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// State table of pf.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub running: bool,
+    pub states: u32,
+    pub searches: u64,
+    pub inserts: u64,
+    pub removals: u64,
 }
 
 impl Pf {
     #[fehler::throws]
-    pub fn new(interface: &str) -> Self {
+    pub fn new(config: NatConfig) -> Self {
+        Self::open(config)?.initialize()?
+    }
+
+    /// Opens pf, leaving the rules as they are, i.e. to
+    /// inspect them.
+    #[fehler::throws]
+    pub fn open(config: NatConfig) -> Self {
+        let (low, high) = config.port_range;
+
+        if low == 0 || low > high {
+            fehler::throw!(anyhow!("Invalid NAT port range {}:{}", low, high));
+        }
+
+        // Interface is a name too, though it isn't kept
+        c_name(&config.interface, IFNAMSIZ as _)?;
+        abi::check()?;
+
         Self {
             pf_device: OpenOptions::new().write(true).open(&PF_DEVICE_PATH)?,
+            anchor: c_name(&config.anchor, MAXPATHLEN as _)?,
+            table: c_name(&config.table, PF_TABLE_NAME_SIZE as _)?,
+            isolation_table: c_name(
+                &config.isolation_table,
+                PF_TABLE_NAME_SIZE as _,
+            )?,
+            isolation_anchor: c_name(
+                &config.isolation_anchor,
+                MAXPATHLEN as _,
+            )?,
+            config,
         }
-        .initialize(interface)?
     }
 
-    /// Initializes NAT rule
-    fn initialize(self, interface: &str) -> Result<Self, Error> {
-        self.transaction(None, |handle, ticket, pool_ticket| {
-            add_rule(handle, ticket, pool_ticket, |mut result| {
-                result.anchor_call[0..ANCHOR.len()].copy_from_slice(&ANCHOR);
+    /// NAT rules of the knast anchor.
+    #[fehler::throws]
+    pub fn rules(&self) -> Vec<NatRule> {
+        rules(self.pf_device.as_raw_fd(), PF_NAT, Some(&self.anchor))?
+            .iter()
+            .map(|request| NatRule {
+                interface: name(&request.rule.ifname),
+                evaluations: request.rule.evaluations,
+                packets: request.rule.packets.iter().sum(),
+                bytes: request.rule.bytes.iter().sum(),
+            })
+            .collect()
+    }
 
-                result
+    /// Networks of the NAT table, the containers' ones.
+    #[fehler::throws]
+    pub fn table_addresses(&self) -> Vec<Ipv4Network> {
+        let handle = self.pf_device.as_raw_fd();
+        let mut addresses: Vec<pfr_addr> = Vec::new();
+
+        // Sized by the kernel, the table may grow meanwhile
+        loop {
+            let mut request: pfioc_table = unsafe { mem::zeroed() };
+
+            request.pfrio_table = self.table_struct(&self.table);
+            request.pfrio_esize = mem::size_of::<pfr_addr>() as _;
+            request.pfrio_size = addresses.len() as _;
+            request.pfrio_buffer = addresses.as_mut_ptr() as _;
+
+            if unsafe { ioctl(handle, DIOCRGETADDRS, &mut request) } < 0 {
+                let error = StdError::last_os_error();
+
+                // Table doesn't exist yet
+                if error.raw_os_error() == Some(libc::ESRCH) {
+                    return Vec::new();
+                }
+
+                fehler::throw!(anyhow!(
+                    "inspect NAT: ioctl(DIOCRGETADDRS) failed: {}",
+                    error
+                ))
+            };
+
+            let size = request.pfrio_size as usize;
+
+            if size <= addresses.len() {
+                addresses.truncate(size);
+
+                break;
+            }
+
+            addresses.resize(size, unsafe { mem::zeroed() });
+        }
+
+        addresses
+            .iter()
+            .filter(|address| address.pfra_af == AF_INET as _)
+            .map(|address| {
+                let ip = unsafe { address.pfra_u._pfra_ip4addr.s_addr };
+
+                Ok(Ipv4Network::new(
+                    u32::from_be(ip).into(),
+                    address.pfra_net,
+                )?)
             })
-        })?
-        .transaction(
-            Some(&ANCHOR),
+            .collect::<Result<_, Error>>()?
+    }
+
+    /// State table of pf, shared with other rulesets.
+    #[fehler::throws]
+    pub fn status(&self) -> Status {
+        let mut status: pf_status = unsafe { mem::zeroed() };
+        let handle = self.pf_device.as_raw_fd();
+
+        if unsafe { ioctl(handle, DIOCGETSTATUS, &mut status) } < 0 {
+            fehler::throw!(anyhow!(
+                "inspect NAT: ioctl(DIOCGETSTATUS) failed: {}",
+                StdError::last_os_error()
+            ))
+        };
+
+        Status {
+            running: status.running != 0,
+            states: status.states,
+            searches: status.fcounters[FCNT_STATE_SEARCH as usize],
+            inserts: status.fcounters[FCNT_STATE_INSERT as usize],
+            removals: status.fcounters[FCNT_STATE_REMOVALS as usize],
+        }
+    }
+
+    /// Initializes NAT rule, unless it exists already, i.e.
+    /// once per boot. Rules are shared by the containers,
+    /// which add their networks to the table only.
+    fn initialize(self) -> Result<Self, Error> {
+        let handle = self.pf_device.as_raw_fd();
+        let anchor = self.anchor.clone();
+        let has_anchor = rules(handle, PF_NAT, None)?
+            .iter()
+            .any(|rule| names(&rule.anchor_call, &anchor));
+        let pf = if has_anchor {
+            self
+        } else {
+            self.transaction(
+                None,
+                PF_RULESET_NAT,
+                |handle, ticket, pool_ticket| {
+                    add_rule(handle, ticket, pool_ticket, |mut result| {
+                        result.anchor_call[0..anchor.len()]
+                            .copy_from_slice(&anchor);
+
+                        result
+                    })
+                },
+            )?
+        };
+        let config = pf.config.clone();
+        let ifname = c_name(&config.interface, IFNAMSIZ as _)?;
+        let has_rule = rules(handle, PF_NAT, Some(&anchor))?
+            .iter()
+            .any(|rule| names(&rule.rule.ifname, &ifname));
+
+        if has_rule {
+            return Ok(pf);
+        }
+
+        let table = pf.table.clone();
+        let proxy_port = if config.static_port {
+            [0, 0]
+        } else {
+            [config.port_range.0, config.port_range.1]
+        };
+        let pool = match config.pool {
+            Pool::Default => PF_POOL_NONE,
+            Pool::Bitmask => PF_POOL_BITMASK,
+            Pool::RoundRobin => PF_POOL_ROUNDROBIN,
+        };
+
+        pf.transaction(
+            Some(&anchor),
+            PF_RULESET_NAT,
             |handle, ticket, pool_ticket| {
-                add_address(handle, pool_ticket, interface)?;
+                add_address(handle, pool_ticket, &config.interface)?;
 
                 add_rule(handle, ticket, pool_ticket, |mut result| {
-                    result.anchor[0..ANCHOR.len()].copy_from_slice(&ANCHOR);
-                    result.rule.ifname[0..interface.len()]
-                        .copy_from_slice(interface.as_signed_bytes());
+                    result.anchor[0..anchor.len()].copy_from_slice(&anchor);
+                    result.rule.ifname[0..ifname.len()]
+                        .copy_from_slice(&ifname);
                     result.rule.src.addr.type_ = 3; // tblname
                     result.rule.af = AF_INET as _;
-                    result.rule.rpool.proxy_port = PF_NAT_PORT_RANGE;
+                    result.rule.rpool.proxy_port = proxy_port;
+                    result.rule.rpool.opts = pool as _;
 
                     unsafe {
-                        result.rule.src.addr.v.tblname[0..TABLE_NAME.len()]
-                            .copy_from_slice(&TABLE_NAME)
+                        result.rule.src.addr.v.tblname[0..table.len()]
+                            .copy_from_slice(&table)
                     };
 
                     result
@@ -80,13 +365,222 @@ impl Pf {
         )
     }
 
+    /// Replaces the filter rules of the anchors, so that the
+    /// policy is applied as a whole.
+    ///
+    /// Containers of the isolation table are isolated from
+    /// the containers' networks, mirroring Docker's
+    /// `icc=false`, except for the allowed pairs. Isolation
+    /// rules live in an anchor of their own, which only blocks
+    /// traffic: allowed pairs are tagged by rules, which
+    /// aren't quick, so that the host's rules still decide on
+    /// them. Then the connections of the shaped containers go
+    /// through their dummynet(4) pipes. Traffic between
+    /// allowed containers isn't shaped.
+    #[fehler::throws]
+    pub fn filter(self, policy: &Filter) -> Self {
+        let handle = self.pf_device.as_raw_fd();
+        let (anchor, isolation_anchor) =
+            (self.anchor.clone(), self.isolation_anchor.clone());
+        let (table, isolation_table) =
+            (self.table.clone(), self.isolation_table.clone());
+        let tag = c_name(ALLOWED_TAG, PF_TAG_NAME_SIZE as _)?;
+        let pf = self.transaction(
+            Some(&isolation_anchor),
+            PF_RULESET_FILTER,
+            |handle, ticket, pool_ticket| {
+                let isolation_rule = |overrides: &dyn Fn(&mut pf_rule)| {
+                    add_rule(handle, ticket, pool_ticket, |mut result| {
+                        result.anchor[0..isolation_anchor.len()]
+                            .copy_from_slice(&isolation_anchor);
+                        result.rule.af = AF_INET as _;
+                        overrides(&mut result.rule);
+
+                        result
+                    })
+                };
+
+                for (from, to) in &policy.allowed {
+                    for (from, to) in &[(from, to), (to, from)] {
+                        isolation_rule(&|rule: &mut pf_rule| {
+                            rule.action = PF_PASS as _;
+                            rule.tagname[0..tag.len()].copy_from_slice(&tag);
+                            network_address(&mut rule.src, from);
+                            network_address(&mut rule.dst, to);
+                        })?;
+                    }
+                }
+
+                isolation_rule(&|rule: &mut pf_rule| {
+                    rule.action = PF_DROP as _;
+                    rule.quick = 1;
+                    rule.match_tagname[0..tag.len()].copy_from_slice(&tag);
+                    rule.match_tag_not = 1;
+                    table_address(&mut rule.src, &table);
+                    table_address(&mut rule.dst, &isolation_table);
+                })
+            },
+        )?;
+        let pf = pf.transaction(
+            Some(&anchor),
+            PF_RULESET_FILTER,
+            |handle, ticket, pool_ticket| {
+                let filter_rule = |overrides: &dyn Fn(&mut pf_rule)| {
+                    add_rule(handle, ticket, pool_ticket, |mut result| {
+                        result.anchor[0..anchor.len()]
+                            .copy_from_slice(&anchor);
+                        result.rule.action = PF_PASS as _;
+                        result.rule.quick = 1;
+                        result.rule.af = AF_INET as _;
+                        overrides(&mut result.rule);
+
+                        result
+                    })
+                };
+
+                for shaping in &policy.shaped {
+                    let address = Ipv4Network::from(shaping.address);
+                    let (egress, ingress) = (
+                        shaping.egress.unwrap_or(0),
+                        shaping.ingress.unwrap_or(0),
+                    );
+
+                    // Connections of the container, then the ones
+                    // to it; replies go through the other pipe
+                    filter_rule(&|rule: &mut pf_rule| {
+                        network_address(&mut rule.src, &address);
+                        pipes(rule, egress, ingress);
+                    })?;
+                    filter_rule(&|rule: &mut pf_rule| {
+                        network_address(&mut rule.dst, &address);
+                        pipes(rule, ingress, egress);
+                    })?;
+                }
+
+                Ok(())
+            },
+        )?;
+
+        create_table(handle, pf.isolation_table_struct(&pf.table))?;
+        create_table(handle, pf.isolation_table_struct(&pf.isolation_table))?;
+
+        // Filter rules of the host are kept, unlike NAT ones.
+        // Isolation goes first, so that the traffic it blocks
+        // isn't passed by the knast anchor.
+        for anchor in &[&anchor, &isolation_anchor] {
+            let has_anchor = rules(handle, PF_PASS, None)?
+                .iter()
+                .any(|rule| names(&rule.anchor_call, anchor));
+
+            if !has_anchor {
+                prepend_anchor_call(handle, PF_PASS, anchor)?;
+            }
+        }
+
+        pf
+    }
+
+    /// Replaces the redirections of the anchor, so that the
+    /// connections to the ports of the interfaces reach the
+    /// containers, i.e. `rdr on em0 proto tcp to port 8080 ->
+    /// 172.24.0.5 port 80`.
+    #[fehler::throws]
+    pub fn redirect(self, redirects: &[Redirect]) -> Self {
+        let handle = self.pf_device.as_raw_fd();
+        let anchor = self.anchor.clone();
+        let pf = self.transaction(
+            Some(&anchor),
+            PF_RULESET_RDR,
+            |handle, ticket, _| {
+                for redirect in redirects {
+                    let ifname = c_name(&redirect.interface, IFNAMSIZ as _)?;
+                    let protocol = match redirect.protocol {
+                        Protocol::Tcp => IPPROTO_TCP,
+                        Protocol::Udp => IPPROTO_UDP,
+                    };
+                    // Rules take the pools of their own
+                    let pool_ticket = begin_addresses(handle)?.ticket;
+
+                    add_pool_address(handle, pool_ticket, redirect.address)?;
+                    add_rule(handle, ticket, pool_ticket, |mut result| {
+                        result.anchor[0..anchor.len()]
+                            .copy_from_slice(&anchor);
+                        result.rule.action = PF_RDR as _;
+                        result.rule.ifname[0..ifname.len()]
+                            .copy_from_slice(&ifname);
+                        result.rule.af = AF_INET as _;
+                        result.rule.proto = protocol as _;
+                        result.rule.dst.port = [redirect.host_port.to_be(), 0];
+                        result.rule.dst.port_op = PF_OP_EQ as _;
+                        result.rule.rpool.proxy_port = [redirect.port, 0];
+
+                        result
+                    })?;
+                }
+
+                Ok(())
+            },
+        )?;
+        let has_anchor = rules(handle, PF_RDR, None)?
+            .iter()
+            .any(|rule| names(&rule.anchor_call, &anchor));
+
+        if !has_anchor {
+            prepend_anchor_call(handle, PF_RDR, &anchor)?;
+        }
+
+        pf
+    }
+
+    /// Adds the container's `address` to the isolated ones.
+    #[fehler::throws]
+    pub fn add_isolated(&self, address: Ipv4Addr) {
+        let handle = self.pf_device.as_raw_fd();
+        let address = Ipv4Network::from(address).to_string();
+
+        create_table(
+            handle,
+            self.isolation_table_struct(&self.isolation_table),
+        )?;
+        add_address_to_table(
+            handle,
+            self.isolation_table_struct(&self.isolation_table),
+            &address,
+        )?;
+    }
+
+    /// Removes the container's `address` from the isolated
+    /// ones, i.e. on teardown.
+    #[fehler::throws]
+    pub fn remove_isolated(&self, address: Ipv4Addr) {
+        let handle = self.pf_device.as_raw_fd();
+        let address = Ipv4Network::from(address).to_string();
+
+        delete_address_from_table(
+            handle,
+            self.isolation_table_struct(&self.isolation_table),
+            &address,
+        )?;
+    }
+
+    fn table_struct(&self, name: &[i8]) -> pfr_table {
+        anchor_table(&self.anchor, name)
+    }
+
+    /// Table of the isolation anchor, which rules don't see
+    /// the tables of other anchors.
+    fn isolation_table_struct(&self, name: &[i8]) -> pfr_table {
+        anchor_table(&self.isolation_anchor, name)
+    }
+
     #[fehler::throws]
     fn transaction<T>(
         self,
         anchor: Option<&[i8]>,
+        ruleset: u32,
         body: impl FnOnce(i32, u32, u32) -> Result<T, Error>,
     ) -> Self {
-        let (data, nat_request) = transaction_struct(anchor);
+        let (data, nat_request) = transaction_struct(anchor, ruleset);
         let handle = self.pf_device.as_raw_fd();
 
         begin_transaction(handle, &data)?;
@@ -105,19 +599,35 @@ impl Pf {
 }
 
 impl Nat for Pf {
+    /// Adds the `subnet` to the NAT table, and to the one
+    /// isolation rules block the traffic of.
     #[fehler::throws]
     fn add(&self, subnet: &str) {
         let handle = self.pf_device.as_raw_fd();
+        let tables = [
+            self.table_struct(&self.table),
+            self.isolation_table_struct(&self.table),
+        ];
 
-        create_table(handle)?;
-        add_address_to_table(handle, subnet)?;
+        for table in &tables {
+            create_table(handle, *table)?;
+            add_address_to_table(handle, *table, subnet)?;
+        }
     }
 }
 
+fn anchor_table(anchor: &[i8], name: &[i8]) -> pfr_table {
+    let mut table: pfr_table = unsafe { mem::zeroed() };
+
+    table.pfrt_anchor[0..anchor.len()].copy_from_slice(anchor);
+    table.pfrt_name[0..name.len()].copy_from_slice(name);
+
+    table
+}
+
 #[fehler::throws]
-fn create_table(handle: i32) {
+fn create_table(handle: i32, mut table: pfr_table) {
     let mut result: pfioc_table = unsafe { mem::zeroed() };
-    let mut table = table_struct();
     table.pfrt_flags = PFR_TFLAG_PERSIST;
 
     result.pfrio_esize = mem::size_of::<pfr_table>() as _;
@@ -133,11 +643,10 @@ fn create_table(handle: i32) {
 }
 
 #[fehler::throws]
-fn add_address_to_table(handle: i32, address: &str) {
+fn add_address_to_table(handle: i32, table: pfr_table, address: &str) {
     let parsed_address: Ipv4Network = address.parse()?;
     let mut result: pfioc_table = unsafe { mem::zeroed() };
     let mut address: pfr_addr = unsafe { mem::zeroed() };
-    let table = table_struct();
 
     address.pfra_af = AF_INET as _;
     address.pfra_net = parsed_address.prefix();
@@ -157,13 +666,93 @@ fn add_address_to_table(handle: i32, address: &str) {
     };
 }
 
-fn table_struct() -> pfr_table {
-    let mut table: pfr_table = unsafe { mem::zeroed() };
+#[fehler::throws]
+fn delete_address_from_table(handle: i32, table: pfr_table, address: &str) {
+    let parsed_address: Ipv4Network = address.parse()?;
+    let mut result: pfioc_table = unsafe { mem::zeroed() };
+    let mut address: pfr_addr = unsafe { mem::zeroed() };
+
+    address.pfra_af = AF_INET as _;
+    address.pfra_net = parsed_address.prefix();
+    address.pfra_u._pfra_ip4addr.s_addr =
+        u32::from_be(parsed_address.network().into());
+
+    result.pfrio_table = table;
+    result.pfrio_esize = mem::size_of::<pfr_addr>() as _;
+    result.pfrio_size = 1;
+    result.pfrio_buffer = &address as *const _ as _;
 
-    table.pfrt_anchor[0..ANCHOR.len()].copy_from_slice(&ANCHOR);
-    table.pfrt_name[0..TABLE_NAME.len()].copy_from_slice(&TABLE_NAME);
+    if unsafe { ioctl(handle, DIOCRDELADDRS, &result) } < 0 {
+        let error = StdError::last_os_error();
 
-    table
+        // Table is gone already
+        if error.raw_os_error() == Some(libc::ESRCH) {
+            return;
+        }
+
+        fehler::throw!(anyhow!(
+            "remove isolation: ioctl(DIOCRDELADDRS) failed: {}",
+            error
+        ))
+    };
+}
+
+/// Calls the `anchor` first in the main ruleset, which the
+/// `action` selects, i.e. `PF_PASS` or `PF_RDR`, leaving
+/// the rest of the ruleset as it is.
+#[fehler::throws]
+fn prepend_anchor_call(handle: i32, action: u32, anchor: &[i8]) {
+    let mut request: pfioc_rule = unsafe { mem::zeroed() };
+
+    request.action = PF_CHANGE_GET_TICKET as _;
+    request.rule.action = action as _;
+
+    if unsafe { ioctl(handle, DIOCCHANGERULE, &mut request) } < 0 {
+        fehler::throw!(anyhow!(
+            "call anchor: ioctl(DIOCCHANGERULE) failed: {}",
+            StdError::last_os_error()
+        ))
+    };
+
+    request.pool_ticket = begin_addresses(handle)?.ticket;
+    request.action = PF_CHANGE_ADD_HEAD as _;
+    request.rule.rtableid = -1;
+    request.anchor_call[0..anchor.len()].copy_from_slice(anchor);
+
+    if unsafe { ioctl(handle, DIOCCHANGERULE, &request) } < 0 {
+        fehler::throw!(anyhow!(
+            "call anchor: ioctl(DIOCCHANGERULE) failed: {}",
+            StdError::last_os_error()
+        ))
+    };
+}
+
+fn network_address(address: &mut pf_rule_addr, network: &Ipv4Network) {
+    address.addr.type_ = PF_ADDR_ADDRMASK as _;
+
+    unsafe {
+        address.addr.v.a.addr.pfa.v4.s_addr =
+            u32::from_be(network.network().into());
+        address.addr.v.a.mask.pfa.v4.s_addr =
+            u32::from_be(network.mask().into());
+    }
+}
+
+/// Stateful rule, which passes packets to the `forward`
+/// pipe, and replies to the `reply` one. 0 is no pipe.
+fn pipes(rule: &mut pf_rule, forward: u16, reply: u16) {
+    rule.keep_state = PF_STATE_NORMAL as _;
+    rule.dnpipe = forward;
+    rule.dnrpipe = reply;
+    rule.free_flags |= PFRULE_DN_IS_PIPE as u32;
+}
+
+fn table_address(address: &mut pf_rule_addr, table: &[i8]) {
+    address.addr.type_ = PF_ADDR_TABLE as _;
+
+    unsafe {
+        address.addr.v.tblname[0..table.len()].copy_from_slice(table);
+    }
 }
 
 #[fehler::throws]
@@ -240,6 +829,29 @@ fn add_address(
     result
 }
 
+/// Adds the `address` to the pool of the rule, i.e. the
+/// target of a redirection.
+#[fehler::throws]
+fn add_pool_address(handle: i32, pool_ticket: u32, address: Ipv4Addr) {
+    let mut request: pfioc_pooladdr = unsafe { mem::zeroed() };
+
+    request.ticket = pool_ticket;
+    request.af = AF_INET as _;
+    request.addr.addr.type_ = PF_ADDR_ADDRMASK as _;
+    unsafe {
+        request.addr.addr.v.a.addr.pfa.v4.s_addr =
+            u32::from_be(address.into());
+        request.addr.addr.v.a.mask.pfa.v4.s_addr = u32::MAX;
+    }
+
+    if unsafe { ioctl(handle, DIOCADDADDR, &request) } < 0 {
+        fehler::throw!(anyhow!(
+            "redirect ports: ioctl(DIOCADDADDR) failed: {}",
+            StdError::last_os_error()
+        ))
+    };
+}
+
 #[fehler::throws]
 fn add_rule(
     handle: i32,
@@ -265,8 +877,82 @@ fn add_rule(
     result
 }
 
+/// Rules of the anchor, or of the main ruleset. `action`
+/// selects the ruleset, i.e. `PF_NAT` or `PF_PASS`.
+#[fehler::throws]
+fn rules(handle: i32, action: u32, anchor: Option<&[i8]>) -> Vec<pfioc_rule> {
+    let mut request: pfioc_rule = unsafe { mem::zeroed() };
+
+    request.rule.action = action as _;
+
+    if let Some(anchor) = anchor {
+        request.anchor[0..anchor.len()].copy_from_slice(anchor);
+    }
+
+    if unsafe { ioctl(handle, DIOCGETRULES, &mut request) } < 0 {
+        let error = StdError::last_os_error();
+
+        // Anchor doesn't exist yet
+        if error.raw_os_error() == Some(libc::EINVAL) && anchor.is_some() {
+            return Vec::new();
+        }
+
+        fehler::throw!(anyhow!(
+            "initialize NAT: ioctl(DIOCGETRULES) failed: {}",
+            error
+        ))
+    };
+
+    let mut rules = Vec::with_capacity(request.nr as _);
+
+    for nr in 0..request.nr {
+        let mut rule = request;
+
+        rule.nr = nr;
+
+        if unsafe { ioctl(handle, DIOCGETRULE, &mut rule) } < 0 {
+            fehler::throw!(anyhow!(
+                "initialize NAT: ioctl(DIOCGETRULE) failed: {}",
+                StdError::last_os_error()
+            ))
+        };
+
+        rules.push(rule);
+    }
+
+    rules
+}
+
+/// Whether the NUL-terminated `name` is `expected`, which
+/// includes the NUL.
+fn names(name: &[i8], expected: &[i8]) -> bool {
+    name.starts_with(expected)
+}
+
+/// NUL-terminated `name`, which fits `capacity` along with
+/// the NUL.
+#[fehler::throws]
+fn c_name(name: &str, capacity: usize) -> Vec<i8> {
+    if name.is_empty() || name.len() >= capacity || name.contains('\0') {
+        fehler::throw!(anyhow!("Invalid pf name {:?}", name));
+    }
+
+    name.as_signed_bytes().iter().chain(&[0]).cloned().collect()
+}
+
+fn name(name: &[i8]) -> String {
+    let bytes: Vec<u8> = name
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| *byte as u8)
+        .collect();
+
+    String::from_utf8_lossy(&bytes).into()
+}
+
 fn transaction_struct(
     anchor_name: Option<&[i8]>,
+    ruleset: u32,
 ) -> (pfioc_trans, Box<pfioc_trans_pfioc_trans_e>) {
     let mut anchor = [0; 1024];
 
@@ -275,7 +961,7 @@ fn transaction_struct(
     }
 
     let boxed_nat_request = Box::new(pfioc_trans_pfioc_trans_e {
-        rs_num: PF_RULESET_NAT as _,
+        rs_num: ruleset as _,
         anchor,
         ticket: 0,
     });
@@ -315,6 +1001,26 @@ mod tests {
         )));
     }
 
+    #[test_helpers::jailed_test]
+    fn test_rules_are_not_duplicated() {
+        let interface = "wlan0";
+        create_nat(interface, "172.24.0.0/24");
+
+        let (anchors, rules) =
+            (get_anchors(), get_anchor_rules("knast_anker"));
+
+        create_nat(interface, "172.24.1.0/24");
+        create_nat(interface, "172.24.2.0/24");
+
+        assert_eq!(get_anchors().lines().count(), anchors.lines().count());
+        assert_eq!(
+            get_anchor_rules("knast_anker").lines().count(),
+            rules.lines().count()
+        );
+        assert!(get_table_entries("knast_anker", "jails")
+            .contains("172.24.2.0/24"));
+    }
+
     #[test_helpers::jailed_test]
     fn test_table_contents() {
         let subnet = "172.24.0.0/24";
@@ -322,8 +1028,138 @@ mod tests {
         assert!(get_table_entries("knast_anker", "jails").contains(subnet));
     }
 
+    #[test_helpers::jailed_test]
+    fn test_inspection() {
+        let subnet = "172.24.0.0/24";
+        create_nat("wlan0", subnet);
+
+        let pf = Pf::open(NatConfig::new("wlan0")).expect("failed to open pf");
+        let rules = pf.rules().expect("failed to list rules");
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].interface, "wlan0");
+        assert_eq!(
+            pf.table_addresses().expect("failed to list addresses"),
+            vec![subnet.parse::<Ipv4Network>().unwrap()]
+        );
+        assert!(pf.status().is_ok());
+    }
+
+    #[test_helpers::jailed_test]
+    fn test_nat_config() {
+        let config = NatConfig {
+            anchor: "knast_test".into(),
+            table: "containers".into(),
+            static_port: true,
+            ..NatConfig::new("wlan0")
+        };
+
+        Pf::new(config)
+            .and_then(|nat| nat.add("172.24.0.0/24"))
+            .expect("failed to create NAT");
+
+        assert!(get_anchors().contains("knast_test"));
+        assert!(get_anchor_rules("knast_test").contains(
+            "nat on wlan0 inet from <containers> to any -> (wlan0:0) \
+             static-port"
+        ));
+    }
+
+    #[test_helpers::jailed_test]
+    fn test_isolation() {
+        create_nat("wlan0", "172.24.0.0/24");
+
+        let allowed = (
+            "172.24.0.2/32".parse().unwrap(),
+            "172.24.0.3/32".parse().unwrap(),
+        );
+        let policy = Filter {
+            allowed: vec![allowed],
+            ..Filter::default()
+        };
+        let pf = Pf::open(NatConfig::new("wlan0"))
+            .and_then(|pf| pf.filter(&policy))
+            .expect("failed to isolate containers");
+
+        pf.add_isolated(Ipv4Addr::new(172, 24, 0, 2))
+            .expect("failed to isolate container");
+
+        let rules = pfctl(&["-a", "knast_isolation", "-sr"]).unwrap();
+
+        assert!(rules.contains(
+            "pass inet from 172.24.0.2 to 172.24.0.3 no state \
+             tag knast_allowed"
+        ));
+        assert!(rules.contains(
+            "block drop quick inet from <jails> to <isolated> \
+             ! tagged knast_allowed"
+        ));
+        assert!(!rules.contains("pass quick"));
+        assert!(get_table_entries("knast_isolation", "jails")
+            .contains("172.24.0.0/24"));
+        assert!(get_table_entries("knast_isolation", "isolated")
+            .contains("172.24.0.2"));
+
+        let main_rules = pfctl(&["-sr"]).unwrap();
+        let calls = main_rules
+            .lines()
+            .filter(|line| line.starts_with("anchor"))
+            .collect::<Vec<_>>();
+
+        // Isolation is called before the knast anchor
+        assert_eq!(
+            calls[0..2],
+            [
+                r#"anchor "knast_isolation" all"#,
+                r#"anchor "knast_anker" all"#
+            ]
+        );
+
+        pf.remove_isolated(Ipv4Addr::new(172, 24, 0, 2))
+            .expect("failed to remove isolation");
+
+        assert!(!get_table_entries("knast_isolation", "isolated")
+            .contains("172.24.0.2"));
+    }
+
+    #[test_helpers::jailed_test]
+    fn test_redirect() {
+        let redirect = Redirect {
+            interface: "wlan0".into(),
+            protocol: Protocol::Tcp,
+            host_port: 8080,
+            address: Ipv4Addr::new(172, 24, 0, 2),
+            port: 80,
+        };
+        let pf = Pf::open(NatConfig::new("wlan0"))
+            .and_then(|pf| pf.redirect(&[redirect]))
+            .expect("failed to redirect port");
+
+        assert!(get_anchor_rules("knast_anker").contains(
+            "rdr on wlan0 inet proto tcp from any to any port = http-alt \
+             -> 172.24.0.2 port 80"
+        ));
+
+        pf.redirect(&[]).expect("failed to remove redirection");
+
+        assert!(!get_anchor_rules("knast_anker").contains("rdr"));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = NatConfig {
+            port_range: (65535, 50001),
+            ..NatConfig::new("wlan0")
+        };
+
+        assert!(Pf::open(config).is_err());
+        assert!(c_name("jails", 32).is_ok());
+        assert!(c_name(&"a".repeat(32), 32).is_err());
+        assert!(c_name("", 32).is_err());
+    }
+
     fn create_nat(interface: &str, subnet: &str) {
-        Pf::new(interface)
+        Pf::new(NatConfig::new(interface))
             .and_then(|nat| nat.add(subnet))
             .expect("failed to create NAT");
     }
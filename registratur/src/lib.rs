@@ -2,3 +2,5 @@
 
 mod reqwest_ext;
 pub mod v2;
+
+pub use reqwest_ext::{verify_digest, verify_reader};
@@ -1,6 +1,8 @@
+use std::io::{Read, Write};
+
 use anyhow::{anyhow, Result};
 use futures::stream::TryStreamExt;
-use reqwest::Response;
+use reqwest::{header, Response};
 use ring::digest::{self, SHA256};
 
 #[async_trait::async_trait]
@@ -13,31 +15,133 @@ pub trait ReqwestResponseExt {
         mut f: Option<impl FnMut(usize) + Send + 'async_trait>,
         digest: Option<&str>,
     ) -> Result<Vec<u8>>;
+
+    /// Appends the content to `buffer`, reporting the
+    /// buffer length as the download progresses. Content
+    /// received before a failure is kept in the buffer.
+    async fn read_into(
+        self,
+        buffer: &mut Vec<u8>,
+        mut f: Option<impl FnMut(usize) + Send + 'async_trait>,
+    ) -> Result<()>;
+
+    /// Writes the content to `writer`, reporting `offset`
+    /// plus the number of bytes written as the download
+    /// progresses.
+    async fn write_into(
+        self,
+        writer: &mut (dyn Write + Send),
+        offset: usize,
+        mut f: Option<impl FnMut(usize) + Send + 'async_trait>,
+    ) -> Result<()>;
+
+    /// Next page of a paginated list, taken from the
+    /// `Link` header, i.e. `</v2/_catalog?last=b&n=2>;
+    /// rel="next"`.
+    fn next_page(&self) -> Option<String>;
 }
 
 #[async_trait::async_trait]
 impl ReqwestResponseExt for Response {
     async fn read(
         self,
-        mut f: Option<impl FnMut(usize) + Send + 'async_trait>,
+        f: Option<impl FnMut(usize) + Send + 'async_trait>,
         digest: Option<&str>,
     ) -> Result<Vec<u8>> {
-        let result = self
-            .bytes_stream()
-            .try_fold(vec![], move |mut agg, bytes| {
-                /* https://github.com/tokio-rs/bytes/issues/ */
-                agg.extend(bytes);
-                f.as_mut().map(|x| x(agg.len()));
-                futures::future::ok(agg)
-            })
-            .await?;
-
-        let res = digest::digest(&SHA256, &result);
-
-        if &digest.unwrap()[7..] != hex::encode(&res) {
-            Err(anyhow!("Content hash mismatch."))
-        } else {
-            Ok(result)
+        let mut result = vec![];
+
+        self.read_into(&mut result, f).await?;
+        verify_digest(&result, digest.unwrap())?;
+
+        Ok(result)
+    }
+
+    async fn read_into(
+        self,
+        buffer: &mut Vec<u8>,
+        f: Option<impl FnMut(usize) + Send + 'async_trait>,
+    ) -> Result<()> {
+        let offset = buffer.len();
+
+        self.write_into(buffer, offset, f).await
+    }
+
+    async fn write_into(
+        self,
+        writer: &mut (dyn Write + Send),
+        offset: usize,
+        mut f: Option<impl FnMut(usize) + Send + 'async_trait>,
+    ) -> Result<()> {
+        let mut stream = self.bytes_stream();
+        let mut written = offset;
+
+        while let Some(bytes) = stream.try_next().await? {
+            writer.write_all(&bytes)?;
+            written += bytes.len();
+            f.as_mut().map(|x| x(written));
+        }
+
+        Ok(())
+    }
+
+    fn next_page(&self) -> Option<String> {
+        let links = self.headers().get(header::LINK)?.to_str().ok()?;
+
+        links.split(',').find_map(|link| {
+            let mut parts = link.split(';');
+            let target = parts.next()?.trim();
+            let next = parts.any(|param| {
+                param.replace(' ', "").replace('"', "") == "rel=next"
+            });
+
+            if !next {
+                return None;
+            }
+
+            target
+                .strip_prefix('<')
+                .and_then(|target| target.strip_suffix('>'))
+                .map(str::to_string)
+        })
+    }
+}
+
+/// Validates that the content matches `sha256:...` digest.
+///
+/// # Errors
+///
+/// Fails if the content hash doesn't match.
+pub fn verify_digest(content: &[u8], digest: &str) -> Result<()> {
+    let res = digest::digest(&SHA256, content);
+
+    if digest.get(7..) != Some(&hex::encode(&res)[..]) {
+        Err(anyhow!("Content hash mismatch."))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validates that the content read from `reader` matches
+/// `sha256:...` digest, without reading it into memory.
+///
+/// # Errors
+///
+/// Fails if reading fails or the content hash doesn't
+/// match.
+pub fn verify_reader(mut reader: impl Read, digest: &str) -> Result<()> {
+    let mut context = digest::Context::new(&SHA256);
+    let mut buffer = [0; 64 * 1024];
+
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => break,
+            read => context.update(&buffer[..read]),
         }
     }
+
+    if digest.get(7..) != Some(&hex::encode(context.finish())[..]) {
+        Err(anyhow!("Content hash mismatch."))
+    } else {
+        Ok(())
+    }
 }
@@ -1,36 +1,215 @@
-use anyhow::Error;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
 use reqwest;
-use reqwest::Method;
+use reqwest::{header, Method, StatusCode};
 use url::Url;
 
+mod config;
+mod credentials;
+mod registries;
 mod www_authenticate;
 
-const USER_AGENT: &str =
-    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+pub use config::{ClientConfig, Identity};
+pub use credentials::Credentials;
+pub use registries::{Registries, RegistryConfig};
+use www_authenticate::{Scheme, WwwAuthenticate};
+
+/// Token lifetime, unless the token server says otherwise.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+/// Tokens are refreshed a bit earlier than they expire, so
+/// that they don't expire in flight.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(5);
+
+/// How failed requests are retried. Connection errors,
+/// timeouts, server errors and rate limiting are considered
+/// transient.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the `retry`-th retry, doubled every
+    /// time.
+    #[must_use]
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(2_u32.saturating_pow(retry))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
 
 /// Distribution client implementation, according to
 /// [spec](https://docs.docker.com/registry/spec/auth/jwt)
+///
+/// Authorizations are cached per repository until they
+/// expire or the registry rejects them.
 pub struct Client<'a> {
     registry_url: &'a str,
     client: reqwest::Client,
+    credentials: Option<Credentials>,
+    /// Credentials of the mirrors, keyed by their hosts.
+    /// Mirrors are never given the registry's ones.
+    mirror_credentials: HashMap<String, Credentials>,
+    authorizations: Mutex<HashMap<String, CachedAuthorization>>,
+    retry_policy: RetryPolicy,
+    config: ClientConfig,
+    registry: RegistryConfig,
 }
 
+/// Registries return either `token`, or `access_token`
+/// (OAuth 2.0 compatible), or both.
 #[derive(serde::Deserialize)]
 struct TokenResponse {
-    access_token: String,
+    token: Option<String>,
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Clone)]
+enum Authorization {
+    Anonymous,
+    Basic(Credentials),
+    Bearer(String),
+}
+
+struct CachedAuthorization {
+    authorization: Authorization,
+    /// `None` for authorizations which don't expire.
+    expires_at: Option<Instant>,
+}
+
+impl Authorization {
+    fn apply(
+        self,
+        builder: reqwest::RequestBuilder,
+    ) -> reqwest::RequestBuilder {
+        match self {
+            Authorization::Anonymous => builder,
+            Authorization::Basic(Credentials { username, password }) => {
+                builder.basic_auth(username, Some(password))
+            }
+            Authorization::Bearer(token) => builder.bearer_auth(token),
+        }
+    }
 }
 
 impl<'a> Client<'a> {
-    /// Builds an OCI registry API client
+    /// Builds an OCI registry API client. Credentials are
+    /// looked up in environment and Docker config, see
+    /// [`Credentials::lookup`], mirrors and TLS settings
+    /// are taken from [`Registries::load`], proxy from
+    /// [`ClientConfig::from_env`].
     #[fehler::throws]
     pub fn build(registry_url: &'a str) -> Self {
-        let client =
-            reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+        let config = ClientConfig::from_env();
+        let registry = RegistryConfig::default();
+        let credentials =
+            Credentials::lookup(registry_url).unwrap_or_else(|error| {
+                log::warn!("Failed to look up credentials: {:?}", error);
+                None
+            });
+        let registries = Registries::load().unwrap_or_else(|error| {
+            log::warn!("Failed to load registries config: {:?}", error);
+            Registries::default()
+        });
 
         Self {
             registry_url,
-            client,
+            client: config.http_client(&registry)?,
+            credentials,
+            mirror_credentials: HashMap::new(),
+            authorizations: Mutex::new(HashMap::new()),
+            retry_policy: RetryPolicy::default(),
+            config,
+            registry,
         }
+        .with_registries(&registries)?
+    }
+
+    /// Applies the settings `registries` have for this
+    /// registry. Credentials of the mirrors are looked up in
+    /// Docker config, see [`Credentials::lookup_config`].
+    #[fehler::throws]
+    pub fn with_registries(self, registries: &Registries) -> Self {
+        let registry = registries.get(self.registry_url);
+        let mut mirror_credentials = HashMap::new();
+
+        for mirror in &registry.mirrors {
+            match Credentials::lookup_config(mirror) {
+                Ok(Some(credentials)) => {
+                    mirror_credentials.insert(
+                        credentials::registry_host(mirror),
+                        credentials,
+                    );
+                }
+                Ok(None) => (),
+                Err(error) => log::warn!(
+                    "Failed to look up credentials of {}: {:?}",
+                    mirror,
+                    error
+                ),
+            }
+        }
+
+        Self {
+            client: self.config.http_client(&registry)?,
+            registry,
+            mirror_credentials,
+            ..self
+        }
+    }
+
+    /// Replaces HTTP settings, including the proxy taken
+    /// from the environment.
+    #[fehler::throws]
+    pub fn with_config(self, config: ClientConfig) -> Self {
+        Self {
+            client: config.http_client(&self.registry)?,
+            config,
+            ..self
+        }
+    }
+
+    /// Authenticates against the registry with given
+    /// credentials.
+    #[must_use]
+    pub fn with_credentials(self, credentials: Credentials) -> Self {
+        Self {
+            credentials: Some(credentials),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self {
+            retry_policy,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
     }
 
     /// Performs an authenticated HTTP request against the
@@ -40,7 +219,10 @@ impl<'a> Client<'a> {
     /// function prepares a request and authorizes the
     /// client. Any modifications to the request can
     /// be done via the [`reqwest::RequestBuilder`]
-    /// parameter of the `f` closure.
+    /// parameter of the `f` closure. Transient failures
+    /// are retried according to the [`RetryPolicy`].
+    /// Configured mirrors are tried first, falling back to
+    /// the next one unless the request succeeds.
     ///
     /// # Example
     ///
@@ -73,50 +255,294 @@ impl<'a> Client<'a> {
         f: F,
     ) -> reqwest::Response
     where
-        F: FnOnce(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+        F: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
     {
-        let base = Url::parse(self.registry_url)?;
-        let url = base.join(&path)?;
+        for mirror in &self.registry.mirrors {
+            let url = Url::parse(mirror).and_then(|base| base.join(path));
+            let result = match url {
+                Ok(url) => {
+                    self.request_endpoint(method.clone(), url, &f).await
+                }
+                Err(error) => Err(error.into()),
+            };
+
+            match result {
+                Ok(response) if response.status().is_success() => {
+                    return response
+                }
+                Ok(response) => log::warn!(
+                    "Mirror {} responded with {}, falling back",
+                    mirror,
+                    response.status()
+                ),
+                Err(error) => log::warn!(
+                    "Mirror {} failed ({:?}), falling back",
+                    mirror,
+                    error
+                ),
+            }
+        }
+
+        let url = Url::parse(&self.base_url())?.join(path)?;
+
+        self.request_endpoint(method, url, &f).await?
+    }
 
+    /// Registry URL, accounting for plain HTTP registries.
+    fn base_url(&self) -> String {
+        if self.registry.http {
+            self.registry_url.replacen("https://", "http://", 1)
+        } else {
+            self.registry_url.to_string()
+        }
+    }
+
+    /// Performs the request against one of the endpoints,
+    /// retrying transient failures.
+    #[fehler::throws]
+    async fn request_endpoint<F>(
+        &self,
+        method: Method,
+        url: Url,
+        f: &F,
+    ) -> reqwest::Response
+    where
+        F: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+    {
         log::debug!("{} {}", &method, url);
 
         let builder = self.client.request(method, url.clone());
-        let builder = f(builder);
+        let mut builder = f(builder);
+        let mut retry = 0;
+
+        loop {
+            // Requests with streaming bodies can't be retried
+            let next = builder
+                .try_clone()
+                .filter(|_| retry + 1 < self.retry_policy.attempts);
+            let result = self.send(&url, builder).await;
+            let next = match next {
+                Some(next) => next,
+                None => return result?,
+            };
+
+            let transient = match &result {
+                Ok(response) => is_transient_status(response.status()),
+                Err(error) => is_transient_error(error),
+            };
+
+            if !transient {
+                return result?;
+            }
+
+            log::warn!(
+                "Request to {} failed ({:?}), retrying",
+                url,
+                result.as_ref().map(reqwest::Response::status)
+            );
+            tokio::time::sleep(self.retry_policy.backoff(retry)).await;
+            builder = next;
+            retry += 1;
+        }
+    }
+
+    /// Sends the request, authorizing it first.
+    #[fehler::throws]
+    async fn send(
+        &self,
+        url: &Url,
+        mut builder: reqwest::RequestBuilder,
+    ) -> reqwest::Response {
+        let repository = repository(url);
+
+        if let Some(authorization) = self.cached_authorization(&repository) {
+            let retry = builder.try_clone();
+            let response = authorization.apply(builder).send().await?;
+            let rejected = response.status() == StatusCode::UNAUTHORIZED;
+
+            match retry {
+                Some(retry) if rejected => {
+                    log::debug!("Authorization for {} expired", repository);
+                    builder = retry;
+                }
+                _ => return response,
+            }
+        }
+
+        let cached = self.authenticate(url.clone()).await?;
+        let authorization = cached.authorization.clone();
 
-        let token = self.authenticate(url).await?;
+        if let Ok(mut authorizations) = self.authorizations.lock() {
+            authorizations.insert(repository, cached);
+        }
 
-        builder.bearer_auth(token).send().await?
+        authorization.apply(builder).send().await?
     }
 
-    #[fehler::throws]
-    async fn authenticate(&self, url: Url) -> String {
-        // TODO: test against non-docker registries
-        // TODO: login / password auth.
-        let challenge_response = self.client.head(url).send().await?;
+    fn cached_authorization(&self, repository: &str) -> Option<Authorization> {
+        let authorizations = self.authorizations.lock().ok()?;
+        let cached = authorizations.get(repository)?;
 
-        let headers = challenge_response.headers();
+        match cached.expires_at {
+            Some(expires_at) if expires_at <= Instant::now() => None,
+            _ => Some(cached.authorization.clone()),
+        }
+    }
 
-        let challenge = headers.get("www-authenticate").unwrap().to_str()?;
+    /// Figures out the authorization, which the registry
+    /// requires for the `url`.
+    #[fehler::throws]
+    async fn authenticate(&self, url: Url) -> CachedAuthorization {
+        let challenge_response = self.client.head(url.clone()).send().await?;
+
+        let challenge =
+            match challenge_response.headers().get(header::WWW_AUTHENTICATE) {
+                Some(challenge) => challenge.to_str()?,
+                None => {
+                    return CachedAuthorization {
+                        authorization: Authorization::Anonymous,
+                        expires_at: None,
+                    }
+                }
+            };
+
+        let challenge = WwwAuthenticate::parse(challenge)?;
+
+        match challenge.scheme {
+            Scheme::Basic => CachedAuthorization {
+                authorization: Authorization::Basic(
+                    self.credentials(&url).cloned().ok_or_else(|| {
+                        anyhow!("Registry requires username and password")
+                    })?,
+                ),
+                expires_at: None,
+            },
+            Scheme::Bearer => {
+                let (token, lifetime) = self.token(&challenge, &url).await?;
+                let lifetime = lifetime.saturating_sub(TOKEN_EXPIRY_MARGIN);
+
+                CachedAuthorization {
+                    authorization: Authorization::Bearer(token),
+                    expires_at: Some(Instant::now() + lifetime),
+                }
+            }
+        }
+    }
 
-        let challenge = www_authenticate::WwwAuthenticate::parse(challenge)?;
+    /// Credentials of the endpoint the `url` belongs to: the
+    /// registry's ones, or the ones of the mirror.
+    fn credentials(&self, url: &Url) -> Option<&Credentials> {
+        if self.is_registry(url) {
+            self.credentials.as_ref()
+        } else {
+            self.mirror_credentials
+                .get(&credentials::registry_host(url.as_str()))
+        }
+    }
 
-        let query =
-            [("scope", challenge.scope), ("service", challenge.service)];
+    /// Whether the `url` belongs to the registry, rather than
+    /// to one of its mirrors.
+    fn is_registry(&self, url: &Url) -> bool {
+        credentials::registry_host(url.as_str())
+            == credentials::registry_host(self.registry_url)
+    }
+
+    /// Fetches a bearer token for the endpoint of the `url`,
+    /// anonymous unless it has credentials.
+    #[fehler::throws]
+    async fn token(
+        &self,
+        challenge: &WwwAuthenticate<'_>,
+        url: &Url,
+    ) -> (String, Duration) {
+        let realm = challenge
+            .realm
+            .ok_or_else(|| anyhow!("Bearer challenge has no realm"))?;
+        let query: Vec<_> =
+            [("scope", challenge.scope), ("service", challenge.service)]
+                .iter()
+                .filter_map(|(key, value)| value.map(|value| (*key, value)))
+                .collect();
+
+        let mut request = self.client.get(realm).query(&query);
+
+        // Token servers of the registry aren't trusted with the
+        // credentials of the mirrors
+        let auth_hosts: &[String] = if self.is_registry(url) {
+            &self.registry.auth_hosts
+        } else {
+            &[]
+        };
+
+        match self.credentials(url) {
+            Some(Credentials { username, password })
+                if credentials::trusts_realm(
+                    url.as_str(),
+                    realm,
+                    auth_hosts,
+                ) =>
+            {
+                request = request.basic_auth(username, Some(password));
+            }
+            Some(_) => log::warn!(
+                "Token server {} isn't trusted with the credentials",
+                realm
+            ),
+            None => (),
+        }
 
-        self.client
-            .get(challenge.realm)
-            .query(&query)
+        let response = request
             .send()
             .await?
+            .error_for_status()?
             .json::<TokenResponse>()
-            .await?
-            .access_token
+            .await?;
+
+        let lifetime = response
+            .expires_in
+            .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+        let token = response
+            .token
+            .or(response.access_token)
+            .ok_or_else(|| anyhow!("Token response has no token"))?;
+
+        (token, lifetime)
     }
 }
 
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_transient_error(error: &Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|error| error.is_connect() || error.is_timeout())
+}
+
+/// Repository part of the API URL, i.e.
+/// `https://ghcr.io/v2/library/nginx` for
+/// `https://ghcr.io/v2/library/nginx/manifests/latest`.
+/// Mirrors authorize separately, hence the origin.
+fn repository(url: &Url) -> String {
+    let path = url.path();
+    let path = ["/manifests/", "/blobs/", "/tags/"]
+        .iter()
+        .filter_map(|endpoint| path.rfind(endpoint))
+        .max()
+        .map_or(path, |index| &path[..index]);
+
+    format!("{}{}", url.origin().ascii_serialization(), path)
+}
+
 #[cfg(test)]
 mod test {
-    use super::Client;
+    use std::time::Duration;
+
+    use url::Url;
+
+    use super::{Client, Credentials, Registries, RetryPolicy};
     use crate::v2::domain::{
         config::Config,
         layer::Layer,
@@ -188,6 +614,126 @@ mod test {
         assert_eq!(manifested_layer.size, actual_layer.len());
     }
 
+    #[tokio::test]
+    async fn test_token_auth_with_credentials() {
+        let (url, _mocks) = test_helpers::mock_server!("credentials.yml");
+
+        let client = Client::build(&url)
+            .expect("Failed to build registry client")
+            .with_credentials(Credentials::new("user", "pass"));
+
+        ManifestIndex::pull(&client, "library/nginx", "latest")
+            .await
+            .expect("Failed to fetch manifest index");
+    }
+
+    #[tokio::test]
+    async fn test_token_reuse() {
+        use test_helpers::mockito::{mock, server_url, Matcher};
+
+        // Paths of its own, so that requests of the tests
+        // running in parallel don't hit the mocks
+        let repository = "knast/token-reuse";
+        let manifest_path = format!("/v2/{}/manifests/latest", repository);
+        let challenge = format!(
+            r#"Bearer realm="{}/token-reuse/auth",scope="repository:{}:pull""#,
+            server_url(),
+            repository
+        );
+        let challenge_mock = mock("HEAD", manifest_path.as_str())
+            .with_header("WWW-Authenticate", &challenge)
+            .expect(1)
+            .create();
+        let token_mock =
+            mock("GET", Matcher::Regex("^/token-reuse/auth".into()))
+                .with_body(r#"{"token": "cached", "expires_in": 300}"#)
+                .expect(1)
+                .create();
+        let manifest_mock = mock("GET", manifest_path.as_str())
+            .match_header("Authorization", "Bearer cached")
+            .with_body_from_file(test_helpers::fixture_path!(
+                "server_mocks/basic/manifest_index.json"
+            ))
+            .expect(2)
+            .create();
+
+        let url = server_url();
+        let client =
+            Client::build(&url).expect("Failed to build registry client");
+
+        for _ in 0..2 {
+            ManifestIndex::pull(&client, repository, "latest")
+                .await
+                .expect("Failed to fetch manifest index");
+        }
+
+        challenge_mock.assert();
+        token_mock.assert();
+        manifest_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_mirror_fallback() {
+        let (url, _mocks) = test_helpers::mock_server!("basic.yml");
+
+        // Nothing listens on the mirror port
+        let config = format!(
+            r#"{{"registries": {{"{}": {{"mirrors": ["{}"]}}}}}}"#,
+            url, "http://127.0.0.1:1"
+        );
+        let registries: Registries =
+            serde_json::from_str(&config).expect("Failed to parse config");
+
+        let client = Client::build(&url)
+            .and_then(|client| client.with_registries(&registries))
+            .expect("Failed to build registry client")
+            .with_retry_policy(RetryPolicy {
+                attempts: 1,
+                ..RetryPolicy::default()
+            });
+
+        ManifestIndex::pull(&client, "library/nginx", "latest")
+            .await
+            .expect("Failed to fall back to the registry");
+    }
+
+    #[test]
+    fn test_mirror_credentials() {
+        let config = r#"{"registries": {"ghcr.io": {
+            "mirrors": ["https://mirror.example.com"]
+        }}}"#;
+        let registries: Registries =
+            serde_json::from_str(config).expect("Failed to parse config");
+        let client = Client::build("https://ghcr.io")
+            .and_then(|client| client.with_registries(&registries))
+            .expect("Failed to build registry client")
+            .with_credentials(Credentials::new("user", "pass"));
+        let url = |url| Url::parse(url).unwrap();
+
+        assert_eq!(
+            client.credentials(&url("https://ghcr.io/v2/library/nginx")),
+            Some(&Credentials::new("user", "pass"))
+        );
+        assert_eq!(
+            client.credentials(&url("https://mirror.example.com/v2/nginx")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            attempts: 10,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(3),
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(3));
+        assert_eq!(policy.backoff(64), Duration::from_secs(3));
+    }
+
     #[tokio::test]
     async fn test_hashsum_mismatch() {
         let (url, _mocks) = test_helpers::mock_server!("basic.yml");
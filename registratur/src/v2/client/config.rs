@@ -0,0 +1,255 @@
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Error};
+use url::Url;
+
+use super::registries::RegistryConfig;
+
+const USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+/// Lowercase variants are checked as well.
+const PROXY_VARIABLES: [&str; 3] = ["HTTPS_PROXY", "HTTP_PROXY", "ALL_PROXY"];
+const NO_PROXY_VARIABLE: &str = "NO_PROXY";
+
+/// HTTP settings of the registry client.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+///
+/// use registratur::v2::client::{Client, ClientConfig};
+///
+/// let config = ClientConfig::default()
+///     .with_proxy("http://proxy.example.com:3128")
+///     .with_root_certificate("/usr/local/etc/ssl/corporate.pem")
+///     .with_connect_timeout(Duration::from_secs(10));
+///
+/// let client = Client::build("https://registry-1.docker.io")
+///     .and_then(|client| client.with_config(config))
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    proxy: Option<String>,
+    no_proxy: Vec<String>,
+    root_certificates: Vec<PathBuf>,
+    identity: Option<Identity>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+}
+
+/// Client certificate, PKCS #12 archive and its password.
+#[derive(Clone)]
+pub struct Identity {
+    pub archive: PathBuf,
+    pub password: String,
+}
+
+impl ClientConfig {
+    /// Settings from the environment, that is proxy from
+    /// `HTTPS_PROXY`, `HTTP_PROXY` or `ALL_PROXY`, except
+    /// for hosts listed in `NO_PROXY`.
+    #[must_use]
+    pub fn from_env() -> Self {
+        let proxy = PROXY_VARIABLES.iter().find_map(|name| variable(name));
+        let no_proxy = variable(NO_PROXY_VARIABLE)
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            proxy,
+            no_proxy,
+            ..Self::default()
+        }
+    }
+
+    /// Sends requests through the proxy.
+    #[must_use]
+    pub fn with_proxy(self, proxy: impl Into<String>) -> Self {
+        Self {
+            proxy: Some(proxy.into()),
+            ..self
+        }
+    }
+
+    /// Trusts PEM-encoded certificates from the file, in
+    /// addition to the system ones.
+    #[must_use]
+    pub fn with_root_certificate(mut self, path: impl Into<PathBuf>) -> Self {
+        self.root_certificates.push(path.into());
+
+        self
+    }
+
+    /// Authenticates with the client certificate.
+    #[must_use]
+    pub fn with_identity(
+        self,
+        archive: impl Into<PathBuf>,
+        password: impl Into<String>,
+    ) -> Self {
+        Self {
+            identity: Some(Identity {
+                archive: archive.into(),
+                password: password.into(),
+            }),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self {
+        Self {
+            connect_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Limits the duration of the whole request, including
+    /// the body. Mind it for layer downloads.
+    #[must_use]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// HTTP client with these settings, trusting the CA
+    /// bundle the registry is configured with.
+    #[fehler::throws]
+    pub(super) fn http_client(
+        &self,
+        registry: &RegistryConfig,
+    ) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .danger_accept_invalid_certs(registry.insecure);
+
+        builder = match &self.proxy {
+            Some(proxy) => builder.proxy(self.proxy(proxy)?),
+            None => builder.no_proxy(),
+        };
+
+        for path in self.root_certificates.iter().chain(&registry.ca_bundle) {
+            for certificate in certificates(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some(Identity { archive, password }) = &self.identity {
+            let identity = reqwest::Identity::from_pkcs12_der(
+                &fs::read(archive).with_context(|| {
+                    format!("Failed to read {:?}", archive)
+                })?,
+                password,
+            )?;
+
+            builder = builder.identity(identity);
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        builder.build()?
+    }
+
+    #[fehler::throws]
+    fn proxy(&self, proxy: &str) -> reqwest::Proxy {
+        let proxy = Url::parse(proxy)
+            .with_context(|| format!("Invalid proxy {}", proxy))?;
+        let no_proxy = self.no_proxy.clone();
+
+        reqwest::Proxy::custom(move |url| {
+            let bypass = url
+                .host_str()
+                .map_or(false, |host| is_excluded(&no_proxy, host));
+
+            if bypass {
+                None
+            } else {
+                Some(proxy.clone())
+            }
+        })
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("archive", &self.archive)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+fn variable(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_lowercase()))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// Whether `NO_PROXY` entries cover the host. Entries match
+/// the host itself and its subdomains, `*` matches all.
+fn is_excluded(no_proxy: &[String], host: &str) -> bool {
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim_start_matches('.');
+
+        entry == "*" || host == entry || host.ends_with(&format!(".{}", entry))
+    })
+}
+
+/// Certificates of the PEM bundle, parsed one by one.
+#[fehler::throws]
+fn certificates(path: &Path) -> Vec<reqwest::Certificate> {
+    let bundle = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+
+    bundle
+        .split(PEM_END)
+        .filter(|block| block.contains(PEM_BEGIN))
+        .map(|block| {
+            let pem = format!("{}{}", block, PEM_END);
+
+            reqwest::Certificate::from_pem(pem.as_bytes())
+                .with_context(|| format!("Invalid certificate in {:?}", path))
+        })
+        .collect::<Result<_, _>>()?
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_excluded;
+
+    #[test]
+    fn test_no_proxy() {
+        let no_proxy = vec!["localhost".into(), ".example.com".into()];
+
+        assert!(is_excluded(&no_proxy, "localhost"));
+        assert!(is_excluded(&no_proxy, "registry.example.com"));
+        assert!(is_excluded(&no_proxy, "example.com"));
+        assert!(!is_excluded(&no_proxy, "badexample.com"));
+        assert!(!is_excluded(&no_proxy, "ghcr.io"));
+        assert!(is_excluded(&["*".into()], "ghcr.io"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{collections::HashMap, env, fmt, fs, path::PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+use url::Url;
+
+const USERNAME_VARIABLE: &str = "KNAST_REGISTRY_USERNAME";
+const PASSWORD_VARIABLE: &str = "KNAST_REGISTRY_PASSWORD";
+const DOCKER_CONFIG_VARIABLE: &str = "DOCKER_CONFIG";
+/// Docker stores Docker Hub credentials under the legacy
+/// index address.
+const DOCKER_HUB_HOST: &str = "index.docker.io";
+const DOCKER_HUB_ALIASES: [&str; 3] = [
+    "docker.io",
+    "registry-1.docker.io",
+    "registry.hub.docker.com",
+];
+/// Token server of Docker Hub, which lives on a host of its
+/// own.
+const DOCKER_HUB_AUTH_HOST: &str = "auth.docker.io";
+
+/// Registry login and password.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+#[derive(serde::Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl Credentials {
+    #[must_use]
+    pub fn new(
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Looks up credentials for the registry.
+    ///
+    /// `KNAST_REGISTRY_USERNAME` and `KNAST_REGISTRY_PASSWORD`
+    /// environment variables take precedence over the
+    /// Docker-style `config.json`, which is read from
+    /// `$DOCKER_CONFIG` or `~/.docker`.
+    #[fehler::throws]
+    pub fn lookup(registry_url: &str) -> Option<Self> {
+        if let (Ok(username), Ok(password)) =
+            (env::var(USERNAME_VARIABLE), env::var(PASSWORD_VARIABLE))
+        {
+            return Some(Self::new(username, password));
+        }
+
+        Self::lookup_config(registry_url)?
+    }
+
+    /// Looks up credentials for the registry in the
+    /// Docker-style `config.json` only, see [`Self::lookup`].
+    #[fehler::throws]
+    pub fn lookup_config(registry_url: &str) -> Option<Self> {
+        let path = match docker_config_path() {
+            Some(path) if path.exists() => path,
+            _ => return None,
+        };
+        let config: DockerConfig =
+            serde_json::from_slice(&fs::read(&path)?)
+                .with_context(|| format!("Failed to parse {:?}", path))?;
+
+        Self::from_docker_config(&config, registry_url)?
+    }
+
+    #[fehler::throws]
+    fn from_docker_config(
+        config: &DockerConfig,
+        registry_url: &str,
+    ) -> Option<Self> {
+        let host = registry_host(registry_url);
+        let auth = config
+            .auths
+            .iter()
+            .find(|(registry, _)| registry_host(registry) == host)
+            .map(|(_, auth)| auth);
+
+        match auth {
+            Some(DockerAuth {
+                username: Some(username),
+                password: Some(password),
+                ..
+            }) => Some(Self::new(username, password)),
+            Some(DockerAuth {
+                auth: Some(auth), ..
+            }) => Some(decode_auth(auth)?),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Decodes base64-encoded `username:password` pair.
+#[fehler::throws]
+fn decode_auth(auth: &str) -> Credentials {
+    let decoded = String::from_utf8(base64::decode(auth)?)?;
+    let mut parts = decoded.splitn(2, ':');
+
+    match (parts.next(), parts.next()) {
+        (Some(username), Some(password)) => {
+            Credentials::new(username, password)
+        }
+        _ => fehler::throw!(anyhow!("Malformed registry auth")),
+    }
+}
+
+fn docker_config_path() -> Option<PathBuf> {
+    let directory = env::var_os(DOCKER_CONFIG_VARIABLE)
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker"))
+        })?;
+
+    Some(directory.join("config.json"))
+}
+
+/// Registry address as `host[:port]`, i.e. both
+/// `https://ghcr.io/v2/` and `ghcr.io` become `ghcr.io`.
+pub(super) fn registry_host(registry: &str) -> String {
+    let host = Url::parse(registry)
+        .ok()
+        .and_then(|url| {
+            let host = url.host_str()?.to_string();
+
+            Some(match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host,
+            })
+        })
+        .unwrap_or_else(|| {
+            registry.split('/').next().unwrap_or(registry).to_string()
+        });
+
+    if DOCKER_HUB_ALIASES.contains(&host.as_str()) {
+        DOCKER_HUB_HOST.into()
+    } else {
+        host
+    }
+}
+
+/// Whether credentials of the registry may be sent to the
+/// token server at `realm`: it has to be on the registry host
+/// or on one of the `auth_hosts`, see
+/// [`super::RegistryConfig::auth_hosts`].
+pub(super) fn trusts_realm(
+    registry_url: &str,
+    realm: &str,
+    auth_hosts: &[String],
+) -> bool {
+    let registry = registry_host(registry_url);
+    let realm = match Url::parse(realm) {
+        Ok(url) => registry_host(url.as_str()),
+        Err(_) => return false,
+    };
+
+    realm == registry
+        || auth_hosts.iter().any(|host| registry_host(host) == realm)
+        || (registry == DOCKER_HUB_HOST && realm == DOCKER_HUB_AUTH_HOST)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{trusts_realm, Credentials, DockerConfig};
+
+    #[test]
+    fn test_docker_config() {
+        let config: DockerConfig = serde_json::from_str(
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": {
+                        "auth": "aHViOnNlY3JldDpwYXNz"
+                    },
+                    "localhost:5000": {
+                        "username": "local",
+                        "password": "password"
+                    },
+                    "quay.io": {}
+                }
+            }"#,
+        )
+        .expect("Failed to parse config");
+        let lookup = |registry| {
+            Credentials::from_docker_config(&config, registry)
+                .expect("Failed to look up credentials")
+        };
+
+        assert_eq!(
+            lookup("https://registry-1.docker.io"),
+            Some(Credentials::new("hub", "secret:pass"))
+        );
+        assert_eq!(
+            lookup("http://localhost:5000"),
+            Some(Credentials::new("local", "password"))
+        );
+        assert_eq!(lookup("https://quay.io"), None);
+        assert_eq!(lookup("https://ghcr.io"), None);
+    }
+
+    #[test]
+    fn test_trusts_realm() {
+        let auth_hosts = vec!["auth.example.com".to_string()];
+
+        assert!(trusts_realm(
+            "https://registry.example.com",
+            "https://registry.example.com/token",
+            &[]
+        ));
+        assert!(trusts_realm(
+            "https://registry.example.com",
+            "https://auth.example.com/token",
+            &auth_hosts
+        ));
+        assert!(trusts_realm(
+            "https://registry-1.docker.io",
+            "https://auth.docker.io/token",
+            &[]
+        ));
+        assert!(!trusts_realm(
+            "https://registry.example.com",
+            "https://evil.example.org/token",
+            &auth_hosts
+        ));
+        assert!(!trusts_realm(
+            "https://registry.example.com",
+            "https://auth.docker.io/token",
+            &[]
+        ));
+        assert!(!trusts_realm("https://registry.example.com", "/token", &[]));
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+use anyhow::{Context, Error};
+
+use super::credentials::registry_host;
+
+const CONFIG_VARIABLE: &str = "KNAST_REGISTRIES_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "/usr/local/etc/knast/registries.json";
+
+/// Per-registry settings, keyed by registry address, i.e.
+///
+/// ```json
+/// {
+///     "registries": {
+///         "docker.io": {
+///             "mirrors": ["https://mirror.example.com"]
+///         },
+///         "registry.example.com:5000": {
+///             "ca_bundle": "/usr/local/etc/ssl/example.pem"
+///         },
+///         "localhost:5000": { "http": true }
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct Registries {
+    #[serde(default)]
+    registries: HashMap<String, RegistryConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct RegistryConfig {
+    /// Tried in order before the registry itself.
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// Registry is served over plain HTTP.
+    #[serde(default)]
+    pub http: bool,
+    /// Registry certificates aren't verified.
+    #[serde(default)]
+    pub insecure: bool,
+    /// Additional PEM-encoded CA certificates.
+    pub ca_bundle: Option<PathBuf>,
+    /// Hosts of the token servers, which are given the
+    /// credentials of the registry besides the registry host
+    /// itself, i.e. `auth.example.com`.
+    #[serde(default)]
+    pub auth_hosts: Vec<String>,
+}
+
+impl Registries {
+    /// Reads the configuration from `KNAST_REGISTRIES_CONFIG`
+    /// or `/usr/local/etc/knast/registries.json`. Missing
+    /// configuration is the same as an empty one.
+    #[fehler::throws]
+    pub fn load() -> Self {
+        let path = env::var_os(CONFIG_VARIABLE)
+            .map_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH), PathBuf::from);
+
+        if !path.exists() {
+            return Self::default();
+        }
+
+        serde_json::from_slice(&fs::read(&path)?)
+            .with_context(|| format!("Failed to parse {:?}", path))?
+    }
+
+    /// Settings of the registry, default ones if it isn't
+    /// configured.
+    #[must_use]
+    pub fn get(&self, registry_url: &str) -> RegistryConfig {
+        let host = registry_host(registry_url);
+
+        self.registries
+            .iter()
+            .find(|(registry, _)| registry_host(registry) == host)
+            .map(|(_, config)| config.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Registries, RegistryConfig};
+
+    #[test]
+    fn test_lookup() {
+        let registries: Registries = serde_json::from_str(
+            r#"{
+                "registries": {
+                    "docker.io": {
+                        "mirrors": ["https://mirror.example.com"]
+                    },
+                    "localhost:5000": { "http": true, "insecure": true }
+                }
+            }"#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(
+            registries.get("https://registry-1.docker.io").mirrors,
+            vec!["https://mirror.example.com"]
+        );
+
+        let config = registries.get("http://localhost:5000");
+        assert!(config.http);
+        assert!(config.insecure);
+
+        assert_eq!(
+            registries.get("https://ghcr.io"),
+            RegistryConfig::default()
+        );
+    }
+}
@@ -1,55 +1,128 @@
 use anyhow::{anyhow, Error};
 
 use nom::{
-    bytes::complete::take_while,
-    character::complete::char,
-    sequence::{delimited, preceded, tuple},
+    branch::alt,
+    bytes::complete::{take_while, take_while1},
+    character::complete::{alpha1, char, space0, space1},
+    combinator::opt,
+    multi::separated_list,
+    sequence::{delimited, preceded, separated_pair, tuple},
     IResult,
 };
 
 const QUOTE: char = '"';
 
+/// Authentication scheme requested by the registry.
+#[derive(Debug, PartialEq)]
+pub enum Scheme {
+    Basic,
+    Bearer,
+}
+
 /// Represents WWW-Authenticate header
 /// Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull"
+/// Basic realm="Registry Realm"
 #[derive(Debug)]
 pub struct WwwAuthenticate<'a> {
-    pub realm: &'a str,
-    pub service: &'a str,
-    pub scope: &'a str,
+    pub scheme: Scheme,
+    pub realm: Option<&'a str>,
+    pub service: Option<&'a str>,
+    pub scope: Option<&'a str>,
 }
 
 impl<'a> WwwAuthenticate<'a> {
     pub fn parse(input: &'a str) -> Result<Self, Error> {
-        tuple((term, term, term))(input)
-            .map(|(_, (realm, service, scope))| Self {
-                realm,
-                service,
-                scope,
-            })
-            .map_err(|err| {
-                anyhow!("Failed to parse WWW-Authenticate header: {:?}", err)
-            })
+        let (_, (scheme, params)) = challenge(input).map_err(|err| {
+            anyhow!("Failed to parse WWW-Authenticate header: {:?}", err)
+        })?;
+
+        let scheme = if scheme.eq_ignore_ascii_case("basic") {
+            Scheme::Basic
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            Scheme::Bearer
+        } else {
+            return Err(anyhow!("Unsupported auth scheme {}", scheme));
+        };
+
+        let params = params.unwrap_or_default();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| *value)
+        };
+
+        Ok(Self {
+            scheme,
+            realm: param("realm"),
+            service: param("service"),
+            scope: param("scope"),
+        })
     }
 }
 
-pub fn term(input: &str) -> IResult<&str, &str> {
-    delimited(preceded(string, char(QUOTE)), string, char(QUOTE))(input)
+type Params<'a> = Vec<(&'a str, &'a str)>;
+
+fn challenge(input: &str) -> IResult<&str, (&str, Option<Params>)> {
+    tuple((
+        alpha1,
+        opt(preceded(space1, separated_list(char(','), param))),
+    ))(input)
 }
 
-fn string(input: &str) -> IResult<&str, &str> {
-    take_while(|c| c != QUOTE)(input)
+fn param(input: &str) -> IResult<&str, (&str, &str)> {
+    separated_pair(
+        preceded(space0, take_while1(is_token)),
+        char('='),
+        alt((quoted, take_while1(is_token))),
+    )(input)
+}
+
+fn quoted(input: &str) -> IResult<&str, &str> {
+    delimited(char(QUOTE), take_while(|c| c != QUOTE), char(QUOTE))(input)
+}
+
+fn is_token(c: char) -> bool {
+    !c.is_whitespace() && c != ',' && c != '=' && c != QUOTE
 }
 
 #[cfg(test)]
 mod test {
+    use super::{Scheme, WwwAuthenticate};
+
     #[test]
     fn test_parsing() {
         let header = test_helpers::fixture!("www_authenticate");
-        let parsed_header = super::WwwAuthenticate::parse(header)
+        let parsed_header = WwwAuthenticate::parse(header)
+            .expect("Failed to parse WwwAuthenticate header");
+
+        assert_eq!(parsed_header.scheme, Scheme::Bearer);
+        assert_eq!(parsed_header.realm, Some("https://auth.docker.io/token"));
+        assert_eq!(parsed_header.service, Some("registry.docker.io"));
+        assert_eq!(parsed_header.scope, Some("repository:library/nginx:pull"));
+    }
+
+    #[test]
+    fn test_parsing_params_order() {
+        let header = concat!(
+            r#"Bearer scope="repository:user/app:pull", "#,
+            r#"realm="https://ghcr.io/token",service=ghcr.io"#
+        );
+        let parsed_header = WwwAuthenticate::parse(header)
+            .expect("Failed to parse WwwAuthenticate header");
+
+        assert_eq!(parsed_header.realm, Some("https://ghcr.io/token"));
+        assert_eq!(parsed_header.service, Some("ghcr.io"));
+        assert_eq!(parsed_header.scope, Some("repository:user/app:pull"));
+    }
+
+    #[test]
+    fn test_parsing_basic() {
+        let parsed_header = WwwAuthenticate::parse(r#"Basic realm="Harbor""#)
             .expect("Failed to parse WwwAuthenticate header");
 
-        assert_eq!(parsed_header.realm, "https://auth.docker.io/token");
-        assert_eq!(parsed_header.service, "registry.docker.io");
-        assert_eq!(parsed_header.scope, "repository:library/nginx:pull");
+        assert_eq!(parsed_header.scheme, Scheme::Basic);
+        assert_eq!(parsed_header.realm, Some("Harbor"));
+        assert_eq!(parsed_header.service, None);
     }
 }
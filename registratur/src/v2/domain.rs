@@ -1,5 +1,11 @@
+pub mod catalog;
 pub mod config;
 pub mod descriptor;
+pub mod image_manifest;
 pub mod layer;
 pub mod manifest;
 pub mod manifest_index;
+pub mod media_type;
+mod pagination;
+pub mod referrers;
+pub mod tags;
@@ -0,0 +1,46 @@
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use super::pagination;
+use crate::v2::client::Client;
+
+/// Represents the repository catalog. Not part of OCI
+/// distribution spec, but commonly supported, although
+/// not by Docker Hub.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Catalog {
+    pub repositories: Vec<String>,
+}
+
+impl Catalog {
+    /// Lists repositories of the registry, following the
+    /// pagination.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use registratur::v2::client::Client;
+    /// use registratur::v2::domain::catalog::Catalog;
+    ///
+    /// let ref client = Client::build("https://ghcr.io").unwrap();
+    ///
+    /// async {
+    ///     let repositories = Catalog::pull(client, Some(100));
+    ///     println!("Got repositories: {:?}", repositories.await.unwrap());
+    /// };
+    /// ```
+    #[fehler::throws]
+    pub async fn pull(
+        client: &Client<'_>,
+        page_size: Option<usize>,
+    ) -> Vec<String> {
+        log::debug!("Listing repositories");
+
+        let path = "/v2/_catalog".into();
+
+        pagination::pull_all(client, path, page_size, |catalog: Self| {
+            catalog.repositories
+        })
+        .await?
+    }
+}
@@ -4,18 +4,14 @@ use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
 
+use super::media_type;
 use crate::reqwest_ext::ReqwestResponseExt;
 use crate::v2::client::Client;
 
 type Empty = HashMap<(), ()>;
 
-/// Diverges from OCI spec.
-/// OCI media type is
-/// "application/vnd.oci.image.config.v1+json"
-const MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
-
 /// Represents [OCI Image Configuration](https://git.io/Jfv42)
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub created: Option<DateTime<Local>>,
     pub author: Option<String>,
@@ -26,7 +22,7 @@ pub struct Config {
     pub history: Vec<HistoryItem>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Container {
     #[serde(rename = "User")]
     pub user: Option<String>,
@@ -48,13 +44,42 @@ pub struct Container {
     pub stop_signal: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Represents the Docker `Healthcheck` of the image config,
+/// which isn't a part of [`Container`]: durations are in
+/// nanoseconds, unset ones fall back to the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Healthcheck {
+    /// `["NONE"]`, `["CMD", args..]` or `["CMD-SHELL", command]`.
+    #[serde(rename = "Test", default)]
+    pub test: Vec<String>,
+    #[serde(rename = "Interval")]
+    pub interval: Option<u64>,
+    #[serde(rename = "Timeout")]
+    pub timeout: Option<u64>,
+    #[serde(rename = "StartPeriod")]
+    pub start_period: Option<u64>,
+    #[serde(rename = "Retries")]
+    pub retries: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct HealthcheckConfig {
+    config: Option<HealthcheckContainer>,
+}
+
+#[derive(Deserialize)]
+struct HealthcheckContainer {
+    #[serde(rename = "Healthcheck")]
+    healthcheck: Option<Healthcheck>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RootFs {
     pub r#type: String,
     pub diff_ids: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HistoryItem {
     pub created: Option<DateTime<Local>>,
     pub author: Option<String>,
@@ -85,19 +110,44 @@ impl Config {
     /// ```
     #[fehler::throws]
     pub async fn pull(client: &Client<'_>, name: &str, digest: &str) -> Self {
+        Self::pull_with_healthcheck(client, name, digest).await?.0
+    }
+
+    /// Pull an OCI Image config along with its healthcheck,
+    /// if the image has one.
+    #[fehler::throws]
+    pub async fn pull_with_healthcheck(
+        client: &Client<'_>,
+        name: &str,
+        digest: &str,
+    ) -> (Self, Option<Healthcheck>) {
         use reqwest::{header, Method};
 
         let path = format!("/v2/{}/blobs/{}", name, digest);
 
         let result = client
             .request(Method::GET, &path, |request| {
-                request.header(header::ACCEPT, MEDIA_TYPE)
+                request.header(
+                    header::ACCEPT,
+                    media_type::accept(&media_type::CONFIGS),
+                )
             })
             .await?
             .read(None::<fn(usize)>, Some(digest))
             .await?;
 
-        serde_json::from_slice(&result)?
+        (
+            serde_json::from_slice(&result)?,
+            Self::healthcheck(&result)?,
+        )
+    }
+
+    /// Healthcheck of the config `content`.
+    #[fehler::throws]
+    pub fn healthcheck(content: &[u8]) -> Option<Healthcheck> {
+        let config: HealthcheckConfig = serde_json::from_slice(content)?;
+
+        config.config.and_then(|container| container.healthcheck)
     }
 }
 
@@ -106,7 +156,7 @@ mod tests {
     use chrono::prelude::*;
     use serde_json;
 
-    use super::Config;
+    use super::{Config, Healthcheck};
 
     #[test]
     fn test_deserialization() {
@@ -123,5 +173,28 @@ mod tests {
         volumes.sort();
 
         assert_eq!(volumes, ["/var/job-result-data", "/var/log/my-app-logs"]);
+        assert_eq!(Config::healthcheck(fixture.as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_healthcheck() {
+        let content = br#"{"config": {"Healthcheck": {
+            "Test": ["CMD-SHELL", "fetch -qo /dev/null http://localhost"],
+            "Interval": 5000000000,
+            "Retries": 2
+        }}}"#;
+
+        assert_eq!(
+            Config::healthcheck(content).unwrap(),
+            Some(Healthcheck {
+                test: vec![
+                    "CMD-SHELL".into(),
+                    "fetch -qo /dev/null http://localhost".into()
+                ],
+                interval: Some(5_000_000_000),
+                retries: Some(2),
+                ..Default::default()
+            })
+        );
     }
 }
@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Error};
+use reqwest::{header, Method};
+use ring::digest::{self, SHA256};
+
+use super::{manifest::Manifest, manifest_index::ManifestIndex, media_type};
+use crate::reqwest_ext::{verify_digest, ReqwestResponseExt};
+use crate::v2::client::Client;
+
+const DIGEST_PREFIX: &str = "sha256:";
+
+/// Either an image manifest or an index, whichever the
+/// registry serves for a reference.
+#[derive(Debug)]
+pub enum ImageManifest {
+    Manifest(Manifest),
+    Index(ManifestIndex),
+}
+
+/// Fields telling manifests and indexes apart, for
+/// registries which don't set `Content-Type`.
+#[derive(serde::Deserialize)]
+struct Probe {
+    #[serde(rename = "mediaType")]
+    media_type: Option<String>,
+    manifests: Option<serde_json::Value>,
+}
+
+impl ImageManifest {
+    /// Pulls a manifest or an index by tag or digest,
+    /// accepting both OCI and Docker media types. Returns
+    /// the content digest along with the content.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use registratur::v2::client::Client;
+    /// use registratur::v2::domain::image_manifest::ImageManifest;
+    ///
+    /// let ref client = Client::build("registry-1.docker.io").unwrap();
+    ///
+    /// async {
+    ///     let (manifest, digest) =
+    ///         ImageManifest::pull(client, "library/nginx", "latest")
+    ///             .await
+    ///             .unwrap();
+    ///     println!("Got {}: {:?}", digest, manifest);
+    /// };
+    /// ```
+    #[fehler::throws]
+    pub async fn pull(
+        client: &Client<'_>,
+        name: &str,
+        reference: &str,
+    ) -> (Self, String) {
+        let path = format!("/v2/{}/manifests/{}", name, reference);
+        let accepted: Vec<_> = media_type::MANIFESTS
+            .iter()
+            .chain(media_type::INDEXES.iter())
+            .copied()
+            .collect();
+
+        let response = client
+            .request(Method::GET, &path, |request| {
+                request.header(header::ACCEPT, media_type::accept(&accepted))
+            })
+            .await?
+            .error_for_status()?;
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_string());
+
+        let mut content = vec![];
+        response.read_into(&mut content, None::<fn(usize)>).await?;
+
+        let digest = if reference.starts_with(DIGEST_PREFIX) {
+            verify_digest(&content, reference)?;
+            reference.to_string()
+        } else {
+            let digest = digest::digest(&SHA256, &content);
+            format!("{}{}", DIGEST_PREFIX, hex::encode(digest))
+        };
+
+        (Self::parse(content_type.as_deref(), &content)?, digest)
+    }
+
+    #[fehler::throws]
+    fn parse(content_type: Option<&str>, content: &[u8]) -> Self {
+        let media_type = match content_type {
+            Some(content_type)
+                if media_type::is_manifest(content_type)
+                    || media_type::is_index(content_type) =>
+            {
+                content_type.to_string()
+            }
+            _ => {
+                let probe: Probe = serde_json::from_slice(content)?;
+
+                match probe {
+                    Probe {
+                        media_type: Some(media_type),
+                        ..
+                    } => media_type,
+                    Probe {
+                        manifests: Some(_), ..
+                    } => media_type::OCI_INDEX.into(),
+                    _ => media_type::OCI_MANIFEST.into(),
+                }
+            }
+        };
+
+        if media_type::is_index(&media_type) {
+            ImageManifest::Index(serde_json::from_slice(content)?)
+        } else if media_type::is_manifest(&media_type) {
+            ImageManifest::Manifest(serde_json::from_slice(content)?)
+        } else {
+            fehler::throw!(anyhow!("Unsupported manifest type {}", media_type))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{media_type, ImageManifest};
+
+    #[test]
+    fn test_parse() {
+        let index = test_helpers::fixture!("manifest_index.json");
+        let manifest = test_helpers::fixture!("manifest.json");
+
+        let parsed = ImageManifest::parse(
+            Some(media_type::OCI_INDEX),
+            index.as_bytes(),
+        )
+        .expect("Failed to parse index");
+        assert!(matches!(parsed, ImageManifest::Index(_)));
+
+        // Content type is detected from the content
+        let parsed = ImageManifest::parse(None, index.as_bytes())
+            .expect("Failed to parse index");
+        assert!(matches!(parsed, ImageManifest::Index(_)));
+
+        let parsed = ImageManifest::parse(
+            Some("application/octet-stream"),
+            manifest.as_bytes(),
+        )
+        .expect("Failed to parse manifest");
+        assert!(matches!(parsed, ImageManifest::Manifest(_)));
+    }
+}
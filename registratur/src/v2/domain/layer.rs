@@ -1,15 +1,87 @@
-use anyhow::Error;
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+};
 
-use crate::reqwest_ext::ReqwestResponseExt;
-use crate::v2::client::Client;
+use anyhow::{anyhow, Error};
+use reqwest::{header, Method, StatusCode};
 
-const MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+use super::media_type;
+use crate::{
+    reqwest_ext::{verify_digest, verify_reader, ReqwestResponseExt},
+    v2::client::Client,
+};
 
 /// Represents [Image Layer Filesystem Changeset](https://git.io/JfkAk)
 pub struct Layer;
 
+/// Destination of a layer download, which might be resumed,
+/// i.e. a buffer or a file.
+pub trait LayerSink: Write + Send {
+    /// Length of the content downloaded so far.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the length can't be determined.
+    fn offset(&mut self) -> io::Result<usize>;
+
+    /// Discards the content downloaded so far.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the content can't be discarded.
+    fn reset(&mut self) -> io::Result<()>;
+
+    /// Validates the content against `sha256:...` digest.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the content hash doesn't match.
+    fn verify(&mut self, digest: &str) -> Result<(), Error>;
+}
+
+impl LayerSink for Vec<u8> {
+    fn offset(&mut self) -> io::Result<usize> {
+        Ok(self.len())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.clear();
+
+        Ok(())
+    }
+
+    fn verify(&mut self, digest: &str) -> Result<(), Error> {
+        verify_digest(self, digest)
+    }
+}
+
+/// Files are expected to be opened for reading and
+/// appending.
+impl LayerSink for File {
+    fn offset(&mut self) -> io::Result<usize> {
+        usize::try_from(self.metadata()?.len())
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.set_len(0)?;
+        self.seek(SeekFrom::Start(0))?;
+
+        Ok(())
+    }
+
+    fn verify(&mut self, digest: &str) -> Result<(), Error> {
+        self.seek(SeekFrom::Start(0))?;
+
+        verify_reader(&*self, digest)
+    }
+}
+
 impl Layer {
-    /// Pull an OCI Layer FS Changesetfrom a registry
+    /// Pull an OCI Layer FS Changeset from a registry. Layers
+    /// might be uncompressed, gzip or zstd compressed.
     ///
     /// # Example
     ///
@@ -39,18 +111,72 @@ impl Layer {
     where
         F: FnMut(usize) + Send,
     {
-        use reqwest::{header, Method};
+        let mut layer = vec![];
+
+        Self::resume(client, name, digest, &mut layer, progress_callback)
+            .await?;
 
+        layer
+    }
+
+    /// Continues pulling the layer, `partial` holds the
+    /// content downloaded so far, see [`LayerSink`].
+    ///
+    /// The rest of the layer is requested via a `Range`
+    /// header. Registries, which don't support ranges,
+    /// send the whole layer, and the download is started
+    /// over. Content downloaded before a failure is kept in
+    /// `partial`, unless it turns out to be corrupted.
+    #[fehler::throws]
+    pub async fn resume<F, S>(
+        client: &Client<'_>,
+        name: &str,
+        digest: &str,
+        partial: &mut S,
+        progress_callback: F,
+    ) where
+        F: FnMut(usize) + Send,
+        S: LayerSink,
+    {
         let path = format!("/v2/{}/blobs/{}", name, digest);
+        let offset = partial.offset()?;
 
-        let result = &*client
+        let response = client
             .request(Method::GET, &path, |request| {
-                request.header(header::ACCEPT, MEDIA_TYPE)
+                let request = request.header(
+                    header::ACCEPT,
+                    media_type::accept(&media_type::LAYERS),
+                );
+
+                if offset > 0 {
+                    request.header(header::RANGE, format!("bytes={}-", offset))
+                } else {
+                    request
+                }
             })
-            .await?
-            .read(Some(progress_callback), Some(&digest))
             .await?;
 
-        result.into()
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            partial.reset()?;
+            fehler::throw!(anyhow!("Partial layer {} is invalid", digest));
+        }
+
+        let response = response.error_for_status()?;
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            partial.reset()?;
+        }
+
+        let offset = partial.offset()?;
+
+        response
+            .write_into(partial, offset, Some(progress_callback))
+            .await?;
+        partial.flush()?;
+
+        if let Err(error) = partial.verify(digest) {
+            partial.reset()?;
+            fehler::throw!(error);
+        }
     }
 }
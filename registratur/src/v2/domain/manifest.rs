@@ -3,21 +3,16 @@ use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
 
-use super::descriptor::Descriptor;
+use super::{descriptor::Descriptor, media_type};
 use crate::reqwest_ext::ReqwestResponseExt;
 use crate::v2::client::Client;
 
-/// Diverges from OCI spec.
-/// OCI media type is
-/// application/vnd.oci.image.manifest.v1+json
-const MEDIA_TYPE: &str =
-    "application/vnd.docker.distribution.manifest.v2+json";
-
 /// Represents [OCI Image Manifest](https://git.io/JvptH)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Manifest {
     #[serde(rename = "schemaVersion")]
     schema_version: u32,
+    #[serde(rename = "mediaType")]
     media_type: Option<String>,
     pub config: Descriptor,
     pub layers: Vec<Descriptor>,
@@ -25,7 +20,20 @@ pub struct Manifest {
 }
 
 impl Manifest {
-    /// Pull an OCI manifest from a registry
+    /// OCI manifest of the image, which is built locally
+    /// rather than pulled.
+    pub fn new(config: Descriptor, layers: Vec<Descriptor>) -> Self {
+        Self {
+            schema_version: 2,
+            media_type: Some(media_type::OCI_MANIFEST.into()),
+            config,
+            layers,
+            annotations: None,
+        }
+    }
+
+    /// Pull an OCI manifest from a registry, either OCI or
+    /// Docker flavoured.
     /// This function operates +only+ on digests.
     ///
     /// # Example
@@ -50,7 +58,10 @@ impl Manifest {
 
         let result = client
             .request(Method::GET, &path, |request| {
-                request.header(header::ACCEPT, MEDIA_TYPE)
+                request.header(
+                    header::ACCEPT,
+                    media_type::accept(&media_type::MANIFESTS),
+                )
             })
             .await?
             .read(None::<fn(usize)>, Some(digest))
@@ -64,7 +75,7 @@ impl Manifest {
 mod tests {
     use serde_json;
 
-    use super::Manifest;
+    use super::{media_type, Descriptor, Manifest};
 
     #[test]
     fn test_deserialization() {
@@ -81,4 +92,23 @@ mod tests {
             Some(String::from("value1"))
         );
     }
+
+    #[test]
+    fn test_new() {
+        let descriptor = |media_type: &str, digest: &str| Descriptor {
+            media_type: media_type.into(),
+            digest: digest.into(),
+            size: 1,
+            urls: None,
+        };
+        let manifest = Manifest::new(
+            descriptor(media_type::OCI_CONFIG, "sha256:c"),
+            vec![descriptor(media_type::OCI_LAYER, "sha256:l")],
+        );
+        let json = serde_json::to_value(&manifest).unwrap();
+
+        assert_eq!(json["schemaVersion"], 2);
+        assert_eq!(json["mediaType"], media_type::OCI_MANIFEST);
+        assert_eq!(json["layers"][0]["digest"], "sha256:l");
+    }
 }
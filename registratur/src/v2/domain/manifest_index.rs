@@ -3,15 +3,9 @@ use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
 
-use super::descriptor::Descriptor;
+use super::{descriptor::Descriptor, media_type};
 use crate::v2::client::Client;
 
-/// Diverges from OCI spec.
-/// OCI media type is
-/// application/vnd.oci.image.index.v1+json
-const MEDIA_TYPE: &str =
-    "application/vnd.docker.distribution.manifest.list.v2+json";
-
 /// Represents [OCI Image Manifest Index](https://git.io/JfLGL)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ManifestIndex {
@@ -65,7 +59,10 @@ impl ManifestIndex {
 
         client
             .request(Method::GET, &path, |request| {
-                request.header(header::ACCEPT, MEDIA_TYPE)
+                request.header(
+                    header::ACCEPT,
+                    media_type::accept(&media_type::INDEXES),
+                )
             })
             .await?
             .json()
@@ -0,0 +1,87 @@
+//! Media types of both
+//! [OCI](https://github.com/opencontainers/image-spec/blob/main/media-types.md)
+//! and Docker image formats.
+
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+pub const OCI_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
+pub const DOCKER_MANIFEST: &str =
+    "application/vnd.docker.distribution.manifest.v2+json";
+pub const DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const DOCKER_CONFIG: &str =
+    "application/vnd.docker.container.image.v1+json";
+pub const DOCKER_LAYER_GZIP: &str =
+    "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+pub const MANIFESTS: [&str; 2] = [OCI_MANIFEST, DOCKER_MANIFEST];
+pub const INDEXES: [&str; 2] = [OCI_INDEX, DOCKER_MANIFEST_LIST];
+pub const CONFIGS: [&str; 2] = [OCI_CONFIG, DOCKER_CONFIG];
+pub const LAYERS: [&str; 4] =
+    [OCI_LAYER_GZIP, OCI_LAYER_ZSTD, OCI_LAYER, DOCKER_LAYER_GZIP];
+
+/// Compression of a layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    /// Not a layer compression, but the one of FreeBSD
+    /// distribution sets.
+    Xz,
+}
+
+#[must_use]
+pub fn is_manifest(media_type: &str) -> bool {
+    MANIFESTS.contains(&media_type)
+}
+
+#[must_use]
+pub fn is_index(media_type: &str) -> bool {
+    INDEXES.contains(&media_type)
+}
+
+/// Compression of the layer of the media type, `None` if it
+/// isn't a layer media type. Non-distributable and foreign
+/// layers are recognized as well.
+#[must_use]
+pub fn compression(media_type: &str) -> Option<Compression> {
+    if media_type.ends_with("tar+gzip") || media_type.ends_with("tar.gzip") {
+        Some(Compression::Gzip)
+    } else if media_type.ends_with("tar+zstd") {
+        Some(Compression::Zstd)
+    } else if media_type.ends_with(".tar") {
+        Some(Compression::None)
+    } else {
+        None
+    }
+}
+
+/// `Accept` header value for the given media types.
+#[must_use]
+pub fn accept(media_types: &[&str]) -> String {
+    media_types.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression() {
+        assert_eq!(compression(OCI_LAYER), Some(Compression::None));
+        assert_eq!(compression(OCI_LAYER_ZSTD), Some(Compression::Zstd));
+        assert_eq!(compression(DOCKER_LAYER_GZIP), Some(Compression::Gzip));
+        assert_eq!(
+            compression(
+                "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip"
+            ),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(compression(OCI_CONFIG), None);
+    }
+}
@@ -0,0 +1,39 @@
+use anyhow::Error;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+
+use crate::reqwest_ext::ReqwestResponseExt;
+use crate::v2::client::Client;
+
+/// Pulls every page of a paginated list, starting from
+/// `path`. `page_size` hints the registry how many items to
+/// return per page.
+#[fehler::throws]
+pub async fn pull_all<T, F>(
+    client: &Client<'_>,
+    path: String,
+    page_size: Option<usize>,
+    items: F,
+) -> Vec<String>
+where
+    T: DeserializeOwned,
+    F: Fn(T) -> Vec<String>,
+{
+    let mut next = Some(match page_size {
+        Some(size) => format!("{}?n={}", path, size),
+        None => path,
+    });
+    let mut result = vec![];
+
+    while let Some(path) = next {
+        let response = client
+            .request(Method::GET, &path, |request| request)
+            .await?
+            .error_for_status()?;
+
+        next = response.next_page();
+        result.extend(items(response.json().await?));
+    }
+
+    result
+}
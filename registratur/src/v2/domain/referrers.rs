@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use reqwest::{header, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::{descriptor::Descriptor, media_type};
+use crate::reqwest_ext::ReqwestResponseExt;
+use crate::v2::client::Client;
+
+/// Represents [referrers list](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-referrers),
+/// an index of the artifacts, i.e. SBOMs, signatures and
+/// attestations, referring to the subject manifest.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Referrers {
+    #[serde(default)]
+    pub manifests: Vec<Referrer>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Referrer {
+    #[serde(flatten)]
+    pub descriptor: Descriptor,
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl Referrers {
+    /// Lists the artifacts referring to the manifest
+    /// `digest`, of the `artifact_type` if given. Registries
+    /// without the referrers API are asked for the fallback
+    /// `<algorithm>-<hex>` tag instead.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use registratur::v2::client::Client;
+    /// use registratur::v2::domain::referrers::Referrers;
+    ///
+    /// let ref client = Client::build("registry-1.docker.io").unwrap();
+    ///
+    /// async {
+    ///     let referrers =
+    ///         Referrers::pull(client, "library/nginx", "sha256:60", None);
+    ///     println!("Got referrers: {:?}", referrers.await.unwrap());
+    /// };
+    /// ```
+    #[fehler::throws]
+    pub async fn pull(
+        client: &Client<'_>,
+        name: &str,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Vec<Referrer> {
+        log::debug!("Listing referrers of {}@{}", name, digest);
+
+        let path = match artifact_type {
+            Some(artifact_type) => format!(
+                "/v2/{}/referrers/{}?artifactType={}",
+                name, digest, artifact_type
+            ),
+            None => format!("/v2/{}/referrers/{}", name, digest),
+        };
+        let fallback =
+            format!("/v2/{}/manifests/{}", name, digest.replacen(':', "-", 1));
+
+        let referrers = match Self::get(client, &path).await? {
+            Some(referrers) => referrers,
+            None => Self::get(client, &fallback).await?.unwrap_or_default(),
+        };
+
+        // Registries might ignore the filter
+        referrers
+            .manifests
+            .into_iter()
+            .filter(|referrer| {
+                artifact_type.map_or(true, |artifact_type| {
+                    referrer.artifact_type.as_deref() == Some(artifact_type)
+                })
+            })
+            .collect()
+    }
+
+    /// Index at the `path`, if it exists.
+    #[fehler::throws]
+    async fn get(client: &Client<'_>, path: &str) -> Option<Self> {
+        let response = client
+            .request(Method::GET, path, |request| {
+                request.header(
+                    header::ACCEPT,
+                    media_type::accept(&[media_type::OCI_INDEX]),
+                )
+            })
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return None;
+        }
+
+        let content = response
+            .error_for_status()?
+            .read(None::<fn(usize)>, None)
+            .await?;
+
+        Some(serde_json::from_slice(&content)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_helpers::mockito::{mock, server_url, Matcher};
+
+    use super::Referrers;
+    use crate::v2::client::Client;
+
+    const SBOM: &str = "application/spdx+json";
+
+    #[tokio::test]
+    async fn test_fallback_tag() {
+        let _referrers = mock("GET", "/v2/library/nginx/referrers/sha256:a")
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .create();
+        let _tag = mock("GET", "/v2/library/nginx/manifests/sha256-a")
+            .with_body(
+                r#"{"schemaVersion": 2, "manifests": [{
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:b",
+                    "size": 512,
+                    "artifactType": "application/spdx+json"
+                }, {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:c",
+                    "size": 512,
+                    "artifactType": "application/vnd.in-toto+json"
+                }]}"#,
+            )
+            .create();
+
+        let url = server_url();
+        let client =
+            Client::build(&url).expect("Failed to build registry client");
+
+        let referrers =
+            Referrers::pull(&client, "library/nginx", "sha256:a", Some(SBOM))
+                .await
+                .expect("Failed to list referrers");
+
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(referrers[0].descriptor.digest, "sha256:b");
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use super::pagination;
+use crate::v2::client::Client;
+
+/// Represents [tag list](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-tags)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagList {
+    pub name: String,
+    /// Registries return `null` for repositories without
+    /// tags.
+    pub tags: Option<Vec<String>>,
+}
+
+impl TagList {
+    /// Lists tags of the repository, following the
+    /// pagination.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use registratur::v2::client::Client;
+    /// use registratur::v2::domain::tags::TagList;
+    ///
+    /// let ref client = Client::build("registry-1.docker.io").unwrap();
+    ///
+    /// async {
+    ///     let tags = TagList::pull(client, "library/nginx", None);
+    ///     println!("Got tags: {:?}", tags.await.unwrap());
+    /// };
+    /// ```
+    #[fehler::throws]
+    pub async fn pull(
+        client: &Client<'_>,
+        name: &str,
+        page_size: Option<usize>,
+    ) -> Vec<String> {
+        log::debug!("Listing tags of {}", name);
+
+        let path = format!("/v2/{}/tags/list", name);
+
+        pagination::pull_all(client, path, page_size, |list: Self| {
+            list.tags.unwrap_or_default()
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_helpers::mockito::{mock, server_url, Matcher};
+
+    use super::TagList;
+    use crate::v2::client::Client;
+
+    #[tokio::test]
+    async fn test_pagination() {
+        let path = Matcher::Regex("/v2/library/nginx/tags/list".into());
+        let _first = mock("GET", path.clone())
+            .match_query(Matcher::Exact("n=2".into()))
+            .with_header(
+                "Link",
+                r#"</v2/library/nginx/tags/list?last=1.19&n=2>; rel="next""#,
+            )
+            .with_body(r#"{"name": "nginx", "tags": ["1.18", "1.19"]}"#)
+            .create();
+        let _last = mock("GET", path)
+            .match_query(Matcher::Exact("last=1.19&n=2".into()))
+            .with_body(r#"{"name": "nginx", "tags": ["latest"]}"#)
+            .create();
+
+        let url = server_url();
+        let client =
+            Client::build(&url).expect("Failed to build registry client");
+
+        let tags = TagList::pull(&client, "library/nginx", Some(2))
+            .await
+            .expect("Failed to list tags");
+
+        assert_eq!(tags, vec!["1.18", "1.19", "latest"]);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+/// Registry assumed for references without one.
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_REGISTRY_URL: &str = "https://registry-1.docker.io";
+/// Docker Hub's official images live in `library`.
+const OFFICIAL_NAMESPACE: &str = "library";
+const DEFAULT_TAG: &str = "latest";
+
+/// Image reference, i.e. `nginx`, `nginx@sha256:...` or
+/// `registry.example.com:5000/team/app:tag`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    /// Base URL of the registry API.
+    #[must_use]
+    pub fn registry_url(&self) -> String {
+        if self.registry == DEFAULT_REGISTRY {
+            DEFAULT_REGISTRY_URL.into()
+        } else {
+            format!("https://{}", self.registry)
+        }
+    }
+
+    /// Tag or digest to pull the manifest by. Digest takes
+    /// precedence, references without either point to the
+    /// `latest` tag.
+    #[must_use]
+    pub fn reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or_else(|| self.tag.as_deref())
+            .unwrap_or(DEFAULT_TAG)
+    }
+}
+
+impl FromStr for Reference {
+    type Err = Error;
+
+    #[fehler::throws]
+    fn from_str(input: &str) -> Self {
+        let (name, digest) = match input.find('@') {
+            Some(index) => (&input[..index], Some(&input[index + 1..])),
+            None => (input, None),
+        };
+
+        if let Some(digest) = digest {
+            let mut parts = digest.splitn(2, ':');
+            let valid = match (parts.next(), parts.next()) {
+                (Some(algorithm), Some(hex)) => {
+                    !algorithm.is_empty()
+                        && !hex.is_empty()
+                        && hex.chars().all(|c| c.is_ascii_hexdigit())
+                }
+                _ => false,
+            };
+
+            if !valid {
+                fehler::throw!(anyhow!("Invalid digest in {}", input));
+            }
+        }
+
+        // Tag is separated by the last colon, unless it is
+        // a part of the registry's port.
+        let (name, tag) = match name.rfind(':') {
+            Some(index) if !name[index..].contains('/') => {
+                (&name[..index], Some(&name[index + 1..]))
+            }
+            _ => (name, None),
+        };
+
+        let (registry, repository) = match name.find('/') {
+            Some(index) if is_host(&name[..index]) => {
+                (&name[..index], &name[index + 1..])
+            }
+            _ => (DEFAULT_REGISTRY, name),
+        };
+
+        let valid_repository = !repository.is_empty()
+            && repository.split('/').all(|component| {
+                !component.is_empty()
+                    && component.chars().all(|c| {
+                        c.is_ascii_lowercase()
+                            || c.is_ascii_digit()
+                            || "._-".contains(c)
+                    })
+            });
+
+        if !valid_repository {
+            fehler::throw!(anyhow!("Invalid repository in {}", input));
+        }
+
+        if tag.map_or(false, str::is_empty) {
+            fehler::throw!(anyhow!("Invalid tag in {}", input));
+        }
+
+        let repository =
+            if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+                format!("{}/{}", OFFICIAL_NAMESPACE, repository)
+            } else {
+                repository.to_string()
+            };
+
+        Self {
+            registry: registry.to_string(),
+            repository,
+            tag: tag.map(str::to_string),
+            digest: digest.map(str::to_string),
+        }
+    }
+}
+
+/// First component names the registry only if it looks
+/// like a host.
+fn is_host(component: &str) -> bool {
+    component.contains(|c| c == '.' || c == ':') || component == "localhost"
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reference;
+
+    #[test]
+    fn test_parsing() {
+        let parse = |input: &str| {
+            input
+                .parse::<Reference>()
+                .expect("Failed to parse reference")
+        };
+
+        let reference = parse("nginx");
+        assert_eq!(reference.registry, "docker.io");
+        assert_eq!(reference.repository, "library/nginx");
+        assert_eq!(reference.reference(), "latest");
+        assert_eq!(reference.registry_url(), "https://registry-1.docker.io");
+
+        let reference = parse("nginx:1.17.10");
+        assert_eq!(reference.tag.as_deref(), Some("1.17.10"));
+        assert_eq!(reference.to_string(), "docker.io/library/nginx:1.17.10");
+
+        let reference = parse("nginx@sha256:abcdef0123");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.reference(), "sha256:abcdef0123");
+
+        let reference =
+            parse("registry.example.com:5000/team/app:v1@sha256:abc");
+        assert_eq!(reference.registry, "registry.example.com:5000");
+        assert_eq!(reference.repository, "team/app");
+        assert_eq!(reference.tag.as_deref(), Some("v1"));
+        assert_eq!(reference.reference(), "sha256:abc");
+        assert_eq!(
+            reference.registry_url(),
+            "https://registry.example.com:5000"
+        );
+
+        let reference = parse("localhost/app");
+        assert_eq!(reference.registry, "localhost");
+        assert_eq!(reference.repository, "app");
+
+        let reference = parse("akhramov/knast");
+        assert_eq!(reference.registry, "docker.io");
+        assert_eq!(reference.repository, "akhramov/knast");
+    }
+
+    #[test]
+    fn test_invalid_references() {
+        for input in &["", "Nginx", "nginx:", "nginx@sha256:xyz", "a//b"] {
+            assert!(
+                input.parse::<Reference>().is_err(),
+                "{} must be invalid",
+                input
+            );
+        }
+    }
+}
@@ -1,39 +1,112 @@
 // Fetch & unpack a centos image.
-use baustelle::{Builder, EvaluationUpdate, LayerDownloadStatus};
-use storage::TestStorage;
+use baustelle::{
+    platform::Platform, Builder, DownloadOptions, EvaluationUpdate,
+    LayerDownloadStatus, Reference, SignaturePolicy,
+};
+use libknast::logging::{self, LogConfig};
+use storage::DynamicStorage;
+
+const MAX_PARALLEL_DOWNLOADS_VARIABLE: &str = "KNAST_MAX_PARALLEL_DOWNLOADS";
+const BANDWIDTH_LIMIT_VARIABLE: &str = "KNAST_BANDWIDTH_LIMIT";
+const VERIFY_ON_READ_VARIABLE: &str = "KNAST_VERIFY_ON_READ";
+const UNPACK_PARALLELISM_VARIABLE: &str = "KNAST_UNPACK_PARALLELISM";
+const NO_CACHE_VARIABLE: &str = "KNAST_NO_CACHE";
+const PLATFORM_VARIABLE: &str = "KNAST_PLATFORM";
 
 #[tokio::main]
 async fn main() {
-    let home = std::env::var("HOME").unwrap();
-    let storage = TestStorage::new(home).unwrap();
-    let builder = Builder::new("amd64".into(), vec!["linux".into()], storage)
-        .expect("Failed to build the image builder");
-    tracing_subscriber::fmt().init();
+    let storage = DynamicStorage::new(storage::root(None)).unwrap();
+    let verify_on_read = std::env::var_os(VERIFY_ON_READ_VARIABLE).is_some();
+    let unpack_parallelism = number_variable(UNPACK_PARALLELISM_VARIABLE)
+        .map_or(1, |value| value as usize);
+    let mut builder =
+        Builder::new("amd64".into(), vec!["linux".into()], &storage)
+            .expect("Failed to build the image builder")
+            .with_download_options(download_options())
+            .with_verify_on_read(verify_on_read)
+            .with_unpack_parallelism(unpack_parallelism)
+            .with_no_cache(std::env::var_os(NO_CACHE_VARIABLE).is_some())
+            .with_signature_policy(
+                SignaturePolicy::load()
+                    .expect("Failed to load the signature policy"),
+            );
+
+    // i.e. `freebsd/amd64,linux/amd64`, most preferred first
+    if let Ok(platforms) = std::env::var(PLATFORM_VARIABLE) {
+        let platforms =
+            Platform::parse_list(&platforms).unwrap_or_else(|error| {
+                panic!("{} is invalid: {}", PLATFORM_VARIABLE, error)
+            });
+
+        builder = builder.with_platforms(platforms);
+    }
+    let _guard = LogConfig::from_env(None)
+        .and_then(|config| logging::init(&config))
+        .expect("Failed to set up logging");
 
     let image = std::env::args().nth(1).expect("USAGE: fetch_image IMAGE");
-    let containerfile = format!("FROM {}", image);
-
-    let rootfs = builder
-        .build(
-            "https://registry-1.docker.io",
-            containerfile.as_bytes(),
-            |x| {
-                if let EvaluationUpdate::From(
-                    LayerDownloadStatus::InProgress(name, count, total),
-                ) = x
-                {
-                    tracing::info!(
-                        "{} downloaded {} of {}",
-                        name,
-                        count,
-                        total
-                    );
-                }
-            },
-        )
-        .await
-        .expect("Failed to build the image");
+    let reference: Reference = image.parse().expect("Invalid image");
+    let containerfile = format!("FROM {}", reference);
+
+    let rootfs =
+        builder
+            .build(
+                "https://registry-1.docker.io",
+                containerfile.as_bytes(),
+                |x| match x {
+                    EvaluationUpdate::From(
+                        LayerDownloadStatus::InProgress(name, count, total),
+                    ) => {
+                        tracing::info!(
+                            "{} downloaded {} of {}",
+                            name,
+                            count,
+                            total
+                        );
+                    }
+                    EvaluationUpdate::StepStarted { index, instruction } => {
+                        tracing::info!("Step {}: {}", index + 1, instruction);
+                    }
+                    EvaluationUpdate::UnpackProgress {
+                        digest,
+                        unpacked,
+                        layers,
+                    } => {
+                        tracing::info!(
+                            "{} unpacked ({} of {})",
+                            digest,
+                            unpacked,
+                            layers
+                        );
+                    }
+                    _ => (),
+                },
+            )
+            .await
+            .expect("Failed to build the image");
 
     tracing::info!("Build a container");
     tracing::info!("Bundle located in {:#?}", rootfs);
 }
+
+/// Download limits, `KNAST_BANDWIDTH_LIMIT` is in bytes per
+/// second.
+fn download_options() -> DownloadOptions {
+    let defaults = DownloadOptions::default();
+
+    DownloadOptions {
+        max_parallel_downloads: number_variable(
+            MAX_PARALLEL_DOWNLOADS_VARIABLE,
+        )
+        .map_or(defaults.max_parallel_downloads, |value| value as usize),
+        bytes_per_second: number_variable(BANDWIDTH_LIMIT_VARIABLE),
+    }
+}
+
+fn number_variable(name: &str) -> Option<u64> {
+    std::env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number", name))
+    })
+}
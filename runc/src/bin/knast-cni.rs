@@ -0,0 +1,226 @@
+// CNI plugin, attaching jails to the knast bridge. Runtimes
+// (i.e. containerd's CRI, nerdctl) exec it with the network
+// config on stdin:
+//
+// {"cniVersion": "1.0.0", "name": "knast", "type": "knast-cni",
+//  "natInterface": "em0"}
+//
+// CNI_NETNS names the vnet jail of the container. Address
+// is given by `IP=172.24.0.5` of CNI_ARGS, or allocated.
+use std::{fmt::Display, io, net::Ipv4Addr, path::Path, process::exit};
+
+use jail::RunningJail;
+use libknast::{
+    logging::{self, LogConfig},
+    operations::{
+        self,
+        network::{self, Attachment},
+        Annotations,
+    },
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use storage::DynamicStorage;
+
+const SUPPORTED_VERSIONS: &[&str] = &["0.4.0", "1.0.0"];
+
+// Error codes of the CNI spec
+const INCOMPATIBLE_VERSION: u32 = 1;
+const UNKNOWN_CONTAINER: u32 = 3;
+const INVALID_ENVIRONMENT: u32 = 4;
+const INVALID_CONFIG: u32 = 7;
+/// Codes of the plugin start at 100.
+const FAILURE: u32 = 100;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NetworkConfig {
+    cni_version: String,
+    name: String,
+    /// Containers are NATed through the interface, if given.
+    nat_interface: Option<String>,
+}
+
+struct CniError {
+    code: u32,
+    msg: String,
+    details: String,
+}
+
+impl CniError {
+    fn new(code: u32, msg: &str, details: impl Display) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+            details: details.to_string(),
+        }
+    }
+}
+
+fn main() {
+    let _guard = LogConfig::from_env(None)
+        .and_then(|config| logging::init(&config))
+        .expect("Failed to set up logging");
+    let command = std::env::var("CNI_COMMAND").unwrap_or_default();
+
+    if command == "VERSION" {
+        return print(&version(SUPPORTED_VERSIONS[1]));
+    }
+
+    let config: Result<NetworkConfig, _> =
+        serde_json::from_reader(io::stdin())
+            .map_err(|error| CniError::new(INVALID_CONFIG, "config", error));
+    let cni_version = config
+        .as_ref()
+        .map_or(SUPPORTED_VERSIONS[1], |config| config.cni_version.as_str())
+        .to_owned();
+    let result = config.and_then(|config| run(&command, &config));
+
+    match result {
+        Ok(Some(result)) => print(&result),
+        Ok(None) => (),
+        Err(error) => {
+            tracing::error!("{} failed: {}", command, error.details);
+            print(&json!({
+                "cniVersion": cni_version,
+                "code": error.code,
+                "msg": error.msg,
+                "details": error.details,
+            }));
+            exit(1);
+        }
+    }
+}
+
+fn run(
+    command: &str,
+    config: &NetworkConfig,
+) -> Result<Option<Value>, CniError> {
+    if !SUPPORTED_VERSIONS.contains(&config.cni_version.as_str()) {
+        return Err(CniError::new(
+            INCOMPATIBLE_VERSION,
+            "unsupported CNI version",
+            &config.cni_version,
+        ));
+    }
+
+    let storage = DynamicStorage::new(storage::root(None))
+        .map_err(failure("storage"))?;
+    operations::migrate(&storage).map_err(failure("storage"))?;
+    let key = variable("CNI_CONTAINERID")?;
+
+    tracing::info!("{} {} to {}", command, key, config.name);
+
+    match command {
+        "ADD" => {
+            let netns = variable("CNI_NETNS")?;
+            let jail = RunningJail::from_name(&jail_name(&netns))
+                .map_err(failure("jail"))?;
+            let annotations = Annotations {
+                address: address()?,
+                interface: Some(variable("CNI_IFNAME")?),
+                ..Annotations::default()
+            };
+            let attachment = network::setup(
+                &storage,
+                &key,
+                jail,
+                &annotations,
+                config.nat_interface.as_ref(),
+            )
+            .map_err(failure("setup"))?;
+
+            Ok(Some(add_result(config, &netns, &attachment)))
+        }
+        "DEL" => {
+            network::teardown(&storage, &key).map_err(failure("teardown"))?;
+
+            Ok(None)
+        }
+        "CHECK" => {
+            let attachment = network::attachment(&storage, &key)
+                .map_err(failure("check"))?;
+
+            match attachment {
+                Some(_) => Ok(None),
+                None => Err(CniError::new(
+                    UNKNOWN_CONTAINER,
+                    "container isn't attached",
+                    key,
+                )),
+            }
+        }
+        command => Err(CniError::new(
+            INVALID_ENVIRONMENT,
+            "unknown CNI_COMMAND",
+            command,
+        )),
+    }
+}
+
+/// Result of ADD: the epair's ends, with the container's one
+/// in the jail.
+fn add_result(
+    config: &NetworkConfig,
+    netns: &str,
+    attachment: &Attachment,
+) -> Value {
+    json!({
+        "cniVersion": config.cni_version,
+        "interfaces": [
+            { "name": attachment.host_interface },
+            { "name": attachment.interface, "sandbox": netns },
+        ],
+        "ips": [{
+            "address": attachment.address.to_string(),
+            "gateway": attachment.gateway.to_string(),
+            "interface": 1,
+        }],
+        "routes": [{
+            "dst": "0.0.0.0/0",
+            "gw": attachment.gateway.to_string(),
+        }],
+        "dns": {},
+    })
+}
+
+fn version(cni_version: &str) -> Value {
+    json!({
+        "cniVersion": cni_version,
+        "supportedVersions": SUPPORTED_VERSIONS,
+    })
+}
+
+fn failure<E: Display>(msg: &'static str) -> impl Fn(E) -> CniError {
+    move |error| CniError::new(FAILURE, msg, error)
+}
+
+fn variable(name: &str) -> Result<String, CniError> {
+    std::env::var(name)
+        .map_err(|error| CniError::new(INVALID_ENVIRONMENT, name, error))
+}
+
+/// `IP` of CNI_ARGS, i.e. `IGNORE_UNKNOWN=1;IP=172.24.0.5`.
+fn address() -> Result<Option<Ipv4Addr>, CniError> {
+    let args = std::env::var("CNI_ARGS").unwrap_or_default();
+
+    args.split(';')
+        .find_map(|arg| arg.strip_prefix("IP="))
+        .map(|address| {
+            address.parse().map_err(|error| {
+                CniError::new(INVALID_ENVIRONMENT, "CNI_ARGS", error)
+            })
+        })
+        .transpose()
+}
+
+/// Jail of CNI_NETNS: its name, or a path ending with it.
+fn jail_name(netns: &str) -> String {
+    Path::new(netns)
+        .file_name()
+        .map_or(netns.into(), |name| name.to_string_lossy().into())
+}
+
+fn print(value: &Value) {
+    println!("{}", value);
+}
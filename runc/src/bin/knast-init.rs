@@ -0,0 +1,137 @@
+// Init of the containers, which knast runs as the first
+// process of the jail, when asked to (see
+// `org.freebsd.knast.init`):
+//
+// knast-init -- COMMAND [ARGS...]
+//
+// It runs the command, forwards signals to it and reaps the
+// processes orphaned in the jail, so that zombies don't pile
+// up under images lacking a proper init. Exits once the
+// command does, with its status: 128 plus the signal number,
+// if a signal killed it.
+use std::{
+    env,
+    io::Error as IoError,
+    os::unix::process::CommandExt,
+    process::{exit, Command},
+};
+
+use nix::{
+    sys::{
+        signal::{kill, SigSet, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::Pid,
+};
+
+const USAGE: &str = "Usage: knast-init -- COMMAND [ARGS...]";
+// sysexits(3)
+const EX_USAGE: i32 = 64;
+const EX_OSERR: i32 = 71;
+// procctl(2) command and id type, which libc lacks
+const PROC_REAP_ACQUIRE: libc::c_int = 2;
+const P_PID: libc::c_int = 0;
+
+extern "C" {
+    fn procctl(
+        idtype: libc::c_int,
+        id: libc::id_t,
+        cmd: libc::c_int,
+        data: *mut libc::c_void,
+    ) -> libc::c_int;
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+
+    if args.peek().map(String::as_str) == Some("--") {
+        args.next();
+    }
+
+    let command: Vec<String> = args.collect();
+
+    if command.is_empty() {
+        eprintln!("{}", USAGE);
+        exit(EX_USAGE);
+    }
+
+    // Orphans are reparented to the reaper rather than to
+    // the host's init
+    let id = std::process::id() as libc::id_t;
+    let acquired =
+        unsafe { procctl(P_PID, id, PROC_REAP_ACQUIRE, std::ptr::null_mut()) };
+
+    if acquired < 0 {
+        eprintln!(
+            "knast-init: failed to become a reaper: {}",
+            IoError::last_os_error()
+        );
+    }
+
+    // Signals are waited for, rather than handled
+    let signals = SigSet::all();
+
+    if let Err(error) = signals.thread_block() {
+        eprintln!("knast-init: failed to block signals: {}", error);
+        exit(EX_OSERR);
+    }
+
+    let mut process = Command::new(&command[0]);
+
+    process.args(&command[1..]);
+
+    unsafe {
+        process.pre_exec(|| {
+            SigSet::all()
+                .thread_unblock()
+                .map_err(|_| IoError::last_os_error())
+        });
+    }
+
+    let child = match process.spawn() {
+        Ok(child) => Pid::from_raw(child.id() as i32),
+        Err(error) => {
+            eprintln!("knast-init: failed to run {}: {}", command[0], error);
+            // As sh(1) does
+            exit(127);
+        }
+    };
+
+    loop {
+        let signal = match signals.wait() {
+            Ok(signal) => signal,
+            Err(error) => {
+                eprintln!("knast-init: failed to wait for signals: {}", error);
+                continue;
+            }
+        };
+
+        if signal == Signal::SIGCHLD {
+            if let Some(code) = reap(child) {
+                exit(code);
+            }
+        } else if let Err(error) = kill(child, signal) {
+            eprintln!("knast-init: failed to forward {:?}: {}", signal, error);
+        }
+    }
+}
+
+/// Reaps the exited processes. Returns the exit code of the
+/// command, once it exits.
+fn reap(command: Pid) -> Option<i32> {
+    let mut code = None;
+
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(pid, status)) if pid == command => {
+                code = Some(status);
+            }
+            Ok(WaitStatus::Signaled(pid, signal, _)) if pid == command => {
+                code = Some(128 + signal as i32);
+            }
+            // No more exited processes, or no children at all
+            Ok(WaitStatus::StillAlive) | Err(_) => return code,
+            Ok(_) => (),
+        }
+    }
+}
@@ -1,45 +1,200 @@
-use std::process::exit;
+use std::{
+    fs::OpenOptions,
+    path::Path,
+    process::exit,
+    time::{Duration, UNIX_EPOCH},
+};
 
+use baustelle::{
+    attachments::Attachments, bootstrap::FreeBsdBootstrap, commit::Committer,
+    gc, image_store::ImageStore, inspect, integrity,
+    runtime_config::RuntimeConfig, Reference,
+};
 use clap::{load_yaml, App, ArgMatches};
-use libknast::operations::OciOperations;
-use storage::{StorageEngine, TestStorage};
+use futures::executor::block_on_stream;
+use libknast::{
+    error::{ErrorKind, KnastError},
+    logging::{self, LogConfig},
+    namespace,
+    operations::{self, OciOperations, ProcessOverrides},
+};
+use storage::{
+    audit::{self, AuditFilter},
+    DynamicStorage, Storage, StorageEngine,
+};
 
 fn main() {
     let yaml = load_yaml!("runc.yaml");
     let matches = App::from(yaml).get_matches();
-    let home = std::env::var("HOME").unwrap();
-    let storage = TestStorage::new(home).unwrap();
+    let _guard = LogConfig::from_env(None)
+        .and_then(|config| logging::init(&config))
+        .expect("Failed to set up logging");
+    let root = storage::root(matches.value_of("root"));
+    let storage = DynamicStorage::new(root).unwrap();
+    operations::migrate(&storage).expect("Failed to migrate the storage");
     let container_id =
         |matches: &ArgMatches| matches.value_of("ID").unwrap().to_owned();
+    let state_root = matches.value_of("state-root");
+    let operations = |matches: &ArgMatches| {
+        let ops = OciOperations::new(&storage, container_id(matches)).unwrap();
+
+        match state_root {
+            Some(state_root) => ops.with_state_root(state_root),
+            None => ops,
+        }
+    };
 
     if let Some(matches) = matches.subcommand_matches("state") {
-        let ops = OciOperations::new(&storage, container_id(matches)).unwrap();
+        let ops = operations(matches);
 
         return state(ops);
     }
     if let Some(matches) = matches.subcommand_matches("create") {
-        let ops = OciOperations::new(&storage, container_id(matches)).unwrap();
+        let ops = operations(matches).with_init(matches.is_present("init"));
         let bundle = matches.value_of("BUNDLE").unwrap();
         let interface = matches.value_of("nat-interface").unwrap();
 
         return create(ops, bundle, interface);
     }
+    if let Some(matches) = matches.subcommand_matches("list") {
+        return list(&storage, matches.value_of("namespace"));
+    }
+    if let Some(matches) = matches.subcommand_matches("spec") {
+        return spec(matches.value_of("bundle").unwrap());
+    }
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        return validate(matches.value_of("BUNDLE").unwrap());
+    }
     if let Some(matches) = matches.subcommand_matches("start") {
-        let ops = OciOperations::new(&storage, container_id(matches)).unwrap();
+        let ops = operations(matches);
+        let values = |name| {
+            matches
+                .values_of(name)
+                .map(|values| values.map(String::from).collect())
+        };
+        let overrides = ProcessOverrides {
+            env: values("env").unwrap_or_else(Vec::new),
+            args: values("args"),
+            cwd: matches.value_of("cwd").map(String::from),
+        };
 
-        return start(ops);
+        return start(ops, overrides);
     }
     if let Some(matches) = matches.subcommand_matches("kill") {
-        let ops = OciOperations::new(&storage, container_id(matches)).unwrap();
+        let ops = operations(matches);
         let signal = matches.value_of("SIGNAL").unwrap().parse().unwrap();
 
         return kill(ops, signal);
     }
+    if let Some(matches) = matches.subcommand_matches("stop") {
+        let ops = operations(matches);
+        let timeout = matches.value_of("timeout").unwrap().parse().unwrap();
+
+        return stop(ops, Duration::from_secs(timeout));
+    }
+    if let Some(matches) = matches.subcommand_matches("port") {
+        let ops = operations(matches);
+
+        return port(ops);
+    }
+    if let Some(matches) = matches.subcommand_matches("events") {
+        return events(&storage, &container_id(matches));
+    }
     if let Some(matches) = matches.subcommand_matches("delete") {
-        let ops = OciOperations::new(&storage, container_id(matches)).unwrap();
+        let ops = operations(matches);
 
         return delete(ops);
     }
+    if let Some(matches) = matches.subcommand_matches("commit") {
+        let ops = operations(matches);
+        let reference = matches.value_of("REFERENCE").unwrap();
+        let mut committer = Committer::new(&storage);
+
+        if let Some(author) = matches.value_of("author") {
+            committer = committer.with_author(author);
+        }
+        if let Some(message) = matches.value_of("message") {
+            committer = committer.with_message(message);
+        }
+
+        return commit(ops, committer, reference);
+    }
+    if let Some(matches) = matches.subcommand_matches("image") {
+        return image(&storage, matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("audit") {
+        let number = |name: &str| {
+            matches.value_of(name).map(|value| {
+                value.parse::<u64>().unwrap_or_else(|_| {
+                    println!("--{} must be a number", name);
+                    exit(64); // EX_USAGE
+                })
+            })
+        };
+        let time = |name: &str| {
+            number(name)
+                .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+        };
+        let filter = AuditFilter {
+            since: time("since"),
+            until: time("until"),
+            action: matches
+                .value_of("action")
+                .map(|action| action.parse().unwrap()),
+            target: matches.value_of("target").map(String::from),
+            user: matches.value_of("user").map(String::from),
+            failed_only: matches.is_present("failed"),
+            limit: number("limit").map(|limit| limit as usize),
+        };
+
+        return audit_trail(&storage, &filter);
+    }
+}
+
+fn image(storage: &Storage<impl StorageEngine>, matches: &ArgMatches) {
+    if let Some(matches) = matches.subcommand_matches("inspect") {
+        let reference = matches.value_of("REFERENCE").unwrap();
+
+        return image_inspect(storage, reference);
+    }
+    if matches.subcommand_matches("ls").is_some() {
+        return image_ls(ImageStore::new(storage));
+    }
+    if let Some(matches) = matches.subcommand_matches("tag") {
+        let source = matches.value_of("SOURCE").unwrap();
+        let target = matches.value_of("TARGET").unwrap();
+
+        return image_tag(ImageStore::new(storage), source, target);
+    }
+    if let Some(matches) = matches.subcommand_matches("rm") {
+        let reference = matches.value_of("REFERENCE").unwrap();
+
+        return image_rm(ImageStore::new(storage), reference);
+    }
+    if let Some(matches) = matches.subcommand_matches("prune") {
+        return image_prune(storage, matches.is_present("dry-run"));
+    }
+    if let Some(matches) = matches.subcommand_matches("check") {
+        return image_check(storage, matches.is_present("repair"));
+    }
+    if let Some(matches) = matches.subcommand_matches("sbom") {
+        let reference = matches.value_of("REFERENCE").unwrap();
+
+        return image_sbom(
+            storage,
+            reference,
+            matches.is_present("refresh"),
+            matches.is_present("all"),
+        );
+    }
+    if let Some(matches) = matches.subcommand_matches("bootstrap-freebsd") {
+        let version = matches.value_of("version").unwrap();
+        let bootstrap = FreeBsdBootstrap::new(storage, version)
+            .with_architecture(matches.value_of("arch").unwrap())
+            .with_mirror(matches.value_of("mirror").unwrap());
+
+        return image_bootstrap_freebsd(bootstrap);
+    }
 }
 
 fn state(ops: OciOperations<impl StorageEngine>) {
@@ -49,7 +204,7 @@ fn state(ops: OciOperations<impl StorageEngine>) {
         }
         Err(error) => {
             println!("{}", error);
-            exit(1);
+            exit(exit_code(&error));
         }
     }
 }
@@ -63,17 +218,67 @@ fn create(
         Ok(_) => (),
         Err(error) => {
             println!("{}", error);
-            exit(1);
+            exit(exit_code(&error));
         }
     }
 }
 
-fn start(ops: OciOperations<impl StorageEngine>) {
-    match ops.start() {
+/// Lists containers, namespaced ones are listed as
+/// `namespace:id`, unless the namespace is given.
+fn list(storage: &Storage<impl StorageEngine>, only: Option<&str>) {
+    let containers = match operations::containers(storage) {
+        Ok(containers) => containers,
+        Err(error) => {
+            println!("{}", error);
+            exit(exit_code(&error));
+        }
+    };
+
+    for key in &containers {
+        match (only, namespace::split(key)) {
+            (None, _) => println!("{}", key),
+            (Some(only), (Some(namespace), id)) if only == namespace => {
+                println!("{}", id)
+            }
+            _ => (),
+        }
+    }
+}
+
+fn spec(bundle: &str) {
+    let path = Path::new(bundle).join("config.json");
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|error| format!("Cannot create {:?}: {}", path, error))
+        .and_then(|file| {
+            serde_json::to_writer_pretty(file, &RuntimeConfig::spec())
+                .map_err(|error| error.to_string())
+        });
+
+    if let Err(error) = result {
+        println!("{}", error);
+        exit(1);
+    }
+}
+
+fn validate(bundle: &str) {
+    match operations::validate(bundle) {
+        Ok(_) => println!("Runtime config is valid"),
+        Err(error) => {
+            println!("{}", error);
+            exit(exit_code(&error));
+        }
+    }
+}
+
+fn start(ops: OciOperations<impl StorageEngine>, overrides: ProcessOverrides) {
+    match ops.start(overrides) {
         Ok(_) => (),
         Err(error) => {
             println!("{}", error);
-            exit(1);
+            exit(exit_code(&error));
         }
     }
 }
@@ -81,6 +286,73 @@ fn start(ops: OciOperations<impl StorageEngine>) {
 fn kill(ops: OciOperations<impl StorageEngine>, signal: i32) {
     match ops.kill(signal) {
         Ok(_) => (),
+        Err(error) => {
+            println!("{}", error);
+            exit(exit_code(&error));
+        }
+    }
+}
+
+fn stop(ops: OciOperations<impl StorageEngine>, timeout: Duration) {
+    match ops.stop(timeout) {
+        Ok(_) => (),
+        Err(error) => {
+            println!("{}", error);
+            exit(exit_code(&error));
+        }
+    }
+}
+
+/// Prints the published ports, as `docker port` does, i.e.
+/// `80/tcp -> 0.0.0.0:8080`.
+fn port(ops: OciOperations<impl StorageEngine>) {
+    match ops.ports() {
+        Ok(ports) => {
+            for port in ports {
+                println!(
+                    "{}/{} -> 0.0.0.0:{}",
+                    port.container_port, port.protocol, port.host_port
+                );
+            }
+        }
+        Err(error) => {
+            println!("{}", error);
+            exit(exit_code(&error));
+        }
+    }
+}
+
+/// Prints events of the container, one JSON object per
+/// line, until interrupted.
+fn events(storage: &Storage<impl StorageEngine>, key: &str) {
+    let events = match operations::subscribe(storage, key) {
+        Ok(events) => events,
+        Err(error) => {
+            println!("{}", error);
+            exit(75); // EX_TEMPFAIL
+        }
+    };
+
+    for event in block_on_stream(events) {
+        match event {
+            Ok(event) => {
+                println!("{}", serde_json::to_string(&event).unwrap())
+            }
+            Err(error) => {
+                println!("{}", error);
+                exit(75); // EX_TEMPFAIL
+            }
+        }
+    }
+}
+
+fn audit_trail(storage: &Storage<impl StorageEngine>, filter: &AuditFilter) {
+    match audit::query(storage, filter) {
+        Ok(records) => {
+            for record in records {
+                println!("{}", serde_json::to_string(&record).unwrap())
+            }
+        }
         Err(error) => {
             println!("{}", error);
             exit(1);
@@ -88,6 +360,199 @@ fn kill(ops: OciOperations<impl StorageEngine>, signal: i32) {
     }
 }
 
+/// sysexits(3) code of the failed container operation, so
+/// that scripts can tell missing containers from broken ones.
+fn exit_code(error: &KnastError) -> i32 {
+    match error.kind() {
+        Some(ErrorKind::NotFound) => 66, // EX_NOINPUT
+        Some(ErrorKind::AlreadyExists) => 73, // EX_CANTCREAT
+        Some(ErrorKind::InvalidArgument) => 65, // EX_DATAERR
+        Some(ErrorKind::FailedPrecondition) => 69, // EX_UNAVAILABLE
+        Some(ErrorKind::Unavailable) => 75, // EX_TEMPFAIL
+        Some(ErrorKind::PermissionDenied) => 77, // EX_NOPERM
+        None => 1,
+    }
+}
+
 fn delete(ops: OciOperations<impl StorageEngine>) {
     ops.delete();
 }
+
+fn commit(
+    ops: OciOperations<impl StorageEngine>,
+    committer: Committer<'_, impl StorageEngine>,
+    reference: &str,
+) {
+    let result = reference.parse::<Reference>().and_then(|reference| {
+        let config = ops.config()?;
+
+        committer.commit(&config, &reference)
+    });
+
+    match result {
+        Ok(digest) => println!("{}", digest),
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    }
+}
+
+fn image_inspect(storage: &Storage<impl StorageEngine>, reference: &str) {
+    let result = reference
+        .parse::<Reference>()
+        .and_then(|reference| inspect::inspect(storage, &reference));
+
+    match result {
+        Ok(result) => {
+            println!("{}", serde_json::to_string_pretty(&result).unwrap())
+        }
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    }
+}
+
+fn image_ls(store: ImageStore<impl StorageEngine>) {
+    match store.list() {
+        Ok(images) => {
+            for image in images {
+                println!(
+                    "{}\t{}\t{}",
+                    image.reference, image.digest, image.size
+                )
+            }
+        }
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    }
+}
+
+fn image_tag(
+    store: ImageStore<impl StorageEngine>,
+    source: &str,
+    target: &str,
+) {
+    let result = source.parse().and_then(|source| {
+        target
+            .parse()
+            .and_then(|target| store.tag(&source, &target))
+    });
+
+    if let Err(error) = result {
+        println!("{}", error);
+        exit(1);
+    }
+}
+
+fn image_rm(store: ImageStore<impl StorageEngine>, reference: &str) {
+    match reference
+        .parse()
+        .and_then(|reference| store.remove(&reference))
+    {
+        Ok(digest) => println!("Untagged {} ({})", reference, digest),
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    }
+}
+
+fn image_prune(storage: &Storage<impl StorageEngine>, dry_run: bool) {
+    match gc::prune(storage, dry_run) {
+        Ok(result) => {
+            println!("{}", serde_json::to_string_pretty(&result).unwrap())
+        }
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    }
+}
+
+fn image_check(storage: &Storage<impl StorageEngine>, repair: bool) {
+    match integrity::check(storage, repair) {
+        Ok(result) => {
+            println!("{}", serde_json::to_string_pretty(&result).unwrap());
+
+            if !result.corrupt_blobs.is_empty() && !repair {
+                exit(1);
+            }
+        }
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    }
+}
+
+fn image_sbom(
+    storage: &Storage<impl StorageEngine>,
+    reference: &str,
+    refresh: bool,
+    all: bool,
+) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let attachments = Attachments::new(storage);
+    let result = reference.parse::<Reference>().and_then(|reference| {
+        let digest = ImageStore::new(storage).resolve(&reference)?;
+        let stored = attachments.list(&digest)?;
+
+        if stored.is_empty() || refresh {
+            runtime.block_on(attachments.fetch(&reference))
+        } else {
+            Ok(stored)
+        }
+    });
+    let attached = match result {
+        Ok(attached) => attached,
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    };
+
+    if all {
+        let attached = serde_json::to_string_pretty(&attached).unwrap();
+
+        return println!("{}", attached);
+    }
+
+    let sboms: Vec<_> = attached
+        .iter()
+        .filter(|attachment| attachment.is_sbom())
+        .flat_map(|attachment| &attachment.blobs)
+        .collect();
+
+    if sboms.is_empty() {
+        println!("No SBOM is attached to {}", reference);
+        exit(1);
+    }
+
+    for blob in sboms {
+        match attachments.read(blob) {
+            Ok(content) => println!("{}", String::from_utf8_lossy(&content)),
+            Err(error) => {
+                println!("{}", error);
+                exit(1);
+            }
+        }
+    }
+}
+
+fn image_bootstrap_freebsd(
+    bootstrap: FreeBsdBootstrap<'_, impl StorageEngine>,
+) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    match runtime.block_on(bootstrap.bootstrap()) {
+        Ok(bundle) => println!("{}", bundle.display()),
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    }
+}
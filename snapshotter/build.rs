@@ -0,0 +1,6 @@
+fn main() {
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/snapshots.proto"], &["proto"])
+        .expect("Failed to generate snapshotter gRPC server code");
+}
@@ -0,0 +1,69 @@
+mod protocols;
+mod service;
+mod snapshots;
+
+use std::{fs::remove_file, path::PathBuf};
+
+use anyhow::Error;
+use libknast::{
+    logging::{self, LogConfig},
+    zfs::Dataset,
+};
+use storage::TestStorage;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::Server;
+
+use protocols::containerd::services::snapshots::v1::snapshots_server::SnapshotsServer;
+use service::SnapshotService;
+use snapshots::Snapshotter;
+
+const DEFAULT_SOCKET_PATH: &str = "/var/run/knast-snapshotter.sock";
+const DEFAULT_DATASET: &str = "zroot/knast/snapshots";
+
+/// containerd snapshots proxy plugin. Register it in
+/// containerd's config.toml:
+///
+/// ```toml
+/// [proxy_plugins]
+///   [proxy_plugins.knast]
+///     type = "snapshot"
+///     address = "/var/run/knast-snapshotter.sock"
+/// ```
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let _guard = logging::init(&LogConfig::from_env(None)?)?;
+
+    let socket = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| DEFAULT_SOCKET_PATH.into());
+    let dataset = Dataset::new(
+        std::env::var("ZFS_DATASET")
+            .unwrap_or_else(|_| DEFAULT_DATASET.into()),
+    );
+
+    if !dataset.exists()? {
+        dataset.create(None)?;
+    }
+
+    let service = SnapshotService::new(Snapshotter::new(storage(), dataset));
+
+    if let Err(error) = remove_file(&socket) {
+        tracing::info!("Previous socket wasn't deleted due to {}", error)
+    };
+
+    let listener = UnixListener::bind(&socket)?;
+    tracing::info!("Server is listening at {:?}", socket);
+
+    Server::builder()
+        .add_service(SnapshotsServer::new(service))
+        .serve_with_incoming(UnixListenerStream::new(listener))
+        .await?;
+
+    Ok(())
+}
+
+fn storage() -> TestStorage {
+    TestStorage::new(storage::root(None)).unwrap()
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use futures::stream;
+use storage::StorageEngine;
+use tonic::{Request, Response, Status};
+
+use super::{
+    protocols::containerd::{
+        services::snapshots::v1::{
+            snapshots_server::Snapshots, CleanupRequest,
+            CommitSnapshotRequest, Info, Kind as ProtoKind,
+            ListSnapshotsRequest, ListSnapshotsResponse, MountsRequest,
+            MountsResponse, PrepareSnapshotRequest, PrepareSnapshotResponse,
+            RemoveSnapshotRequest, StatSnapshotRequest, StatSnapshotResponse,
+            UpdateSnapshotRequest, UpdateSnapshotResponse, UsageRequest,
+            UsageResponse, ViewSnapshotRequest, ViewSnapshotResponse,
+        },
+        types::Mount,
+    },
+    snapshots::{Kind, Snapshot, SnapshotError, SnapshotMount, Snapshotter},
+};
+
+pub struct SnapshotService<T: StorageEngine> {
+    snapshotter: Snapshotter<T>,
+}
+
+impl<T: StorageEngine> SnapshotService<T> {
+    pub fn new(snapshotter: Snapshotter<T>) -> Self {
+        Self { snapshotter }
+    }
+}
+
+#[tonic::async_trait]
+impl<T: StorageEngine + Send + Sync + 'static> Snapshots
+    for SnapshotService<T>
+{
+    type ListStream = stream::Empty<Result<ListSnapshotsResponse, Status>>;
+
+    #[tracing::instrument(err, skip(self))]
+    async fn prepare(
+        &self,
+        request: Request<PrepareSnapshotRequest>,
+    ) -> Result<Response<PrepareSnapshotResponse>, Status> {
+        let request = request.into_inner();
+        let mount = self
+            .snapshotter
+            .prepare(
+                &request.key,
+                &request.parent,
+                request.labels.into_iter().collect(),
+                Kind::Active,
+            )
+            .map_err(error_response)?;
+
+        Ok(Response::new(PrepareSnapshotResponse {
+            mounts: vec![mount.into()],
+        }))
+    }
+
+    #[tracing::instrument(err, skip(self))]
+    async fn view(
+        &self,
+        request: Request<ViewSnapshotRequest>,
+    ) -> Result<Response<ViewSnapshotResponse>, Status> {
+        let request = request.into_inner();
+        let mount = self
+            .snapshotter
+            .prepare(
+                &request.key,
+                &request.parent,
+                request.labels.into_iter().collect(),
+                Kind::View,
+            )
+            .map_err(error_response)?;
+
+        Ok(Response::new(ViewSnapshotResponse {
+            mounts: vec![mount.into()],
+        }))
+    }
+
+    #[tracing::instrument(err, skip(self))]
+    async fn mounts(
+        &self,
+        request: Request<MountsRequest>,
+    ) -> Result<Response<MountsResponse>, Status> {
+        let mount = self
+            .snapshotter
+            .mounts(&request.into_inner().key)
+            .map_err(error_response)?;
+
+        Ok(Response::new(MountsResponse {
+            mounts: vec![mount.into()],
+        }))
+    }
+
+    #[tracing::instrument(err, skip(self))]
+    async fn commit(
+        &self,
+        request: Request<CommitSnapshotRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+
+        self.snapshotter
+            .commit(
+                &request.name,
+                &request.key,
+                request.labels.into_iter().collect(),
+            )
+            .map_err(error_response)?;
+
+        Ok(Response::new(()))
+    }
+
+    #[tracing::instrument(err, skip(self))]
+    async fn remove(
+        &self,
+        request: Request<RemoveSnapshotRequest>,
+    ) -> Result<Response<()>, Status> {
+        self.snapshotter
+            .remove(&request.into_inner().key)
+            .map_err(error_response)?;
+
+        Ok(Response::new(()))
+    }
+
+    #[tracing::instrument(err, skip(self))]
+    async fn stat(
+        &self,
+        request: Request<StatSnapshotRequest>,
+    ) -> Result<Response<StatSnapshotResponse>, Status> {
+        let key = request.into_inner().key;
+        let snapshot =
+            self.snapshotter.snapshot(&key).map_err(error_response)?;
+
+        Ok(Response::new(StatSnapshotResponse {
+            info: Some(info(key, snapshot)),
+        }))
+    }
+
+    async fn update(
+        &self,
+        _request: Request<UpdateSnapshotRequest>,
+    ) -> Result<Response<UpdateSnapshotResponse>, Status> {
+        Err(Status::unimplemented("Update is not supported"))
+    }
+
+    async fn list(
+        &self,
+        _request: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        Err(Status::unimplemented("List is not supported"))
+    }
+
+    async fn usage(
+        &self,
+        _request: Request<UsageRequest>,
+    ) -> Result<Response<UsageResponse>, Status> {
+        Err(Status::unimplemented("Usage is not supported"))
+    }
+
+    async fn cleanup(
+        &self,
+        _request: Request<CleanupRequest>,
+    ) -> Result<Response<()>, Status> {
+        Err(Status::unimplemented("Cleanup is not supported"))
+    }
+}
+
+impl From<SnapshotMount> for Mount {
+    fn from(mount: SnapshotMount) -> Self {
+        let mode = if mount.readonly { "ro" } else { "rw" };
+
+        Mount {
+            r#type: "nullfs".into(),
+            source: mount.source.to_string_lossy().into(),
+            target: String::new(),
+            options: vec![mode.into()],
+        }
+    }
+}
+
+fn info(name: String, snapshot: Snapshot) -> Info {
+    let kind = match snapshot.kind {
+        Kind::View => ProtoKind::View,
+        Kind::Active => ProtoKind::Active,
+        Kind::Committed => ProtoKind::Committed,
+    };
+
+    Info {
+        name,
+        parent: snapshot.parent,
+        kind: kind as _,
+        created_at: Some(snapshot.created_at.into()),
+        updated_at: Some(snapshot.updated_at.into()),
+        labels: snapshot.labels.into_iter().collect::<HashMap<_, _>>(),
+    }
+}
+
+fn error_response(err: Error) -> Status {
+    match err.downcast_ref::<SnapshotError>() {
+        Some(SnapshotError::NotFound(_)) => Status::not_found(err.to_string()),
+        Some(SnapshotError::AlreadyExists(_)) => {
+            Status::already_exists(err.to_string())
+        }
+        Some(SnapshotError::InvalidKind(..)) => {
+            Status::failed_precondition(err.to_string())
+        }
+        None => Status::internal(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_conversion() {
+        let mount: Mount = SnapshotMount {
+            source: "/var/db/knast/snapshots/abcd".into(),
+            readonly: true,
+        }
+        .into();
+
+        assert_eq!(mount.r#type, "nullfs");
+        assert_eq!(mount.source, "/var/db/knast/snapshots/abcd");
+        assert_eq!(mount.options, vec!["ro"]);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let not_found = SnapshotError::NotFound("sha256:abcd".into());
+        let exists = SnapshotError::AlreadyExists("sha256:abcd".into());
+
+        assert_eq!(
+            error_response(not_found.into()).code(),
+            tonic::Code::NotFound
+        );
+        assert_eq!(
+            error_response(exists.into()).code(),
+            tonic::Code::AlreadyExists
+        );
+        assert_eq!(
+            error_response(anyhow::anyhow!("zfs failed")).code(),
+            tonic::Code::Internal
+        );
+    }
+}
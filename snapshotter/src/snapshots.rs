@@ -0,0 +1,221 @@
+/// ZFS-backed snapshots.
+///
+/// Every snapshot is a ZFS dataset. Active snapshots
+/// and views without a parent are empty datasets; the ones
+/// with a parent are clones of the parent's `@committed`
+/// snapshot. Committing an active snapshot takes that ZFS
+/// snapshot, so the children of a layer share its blocks.
+///
+/// Datasets are handed to containerd as nullfs mounts of
+/// their mountpoints.
+use std::{collections::BTreeMap, fmt, path::PathBuf, time::SystemTime};
+
+use anyhow::Error;
+use libknast::zfs::Dataset;
+use serde::{Deserialize, Serialize};
+use storage::{Storage, StorageEngine};
+use uuid::Uuid;
+
+const SNAPSHOTS_STORAGE_KEY: &[u8] = b"SNAPSHOTS";
+const COMMITTED_SNAPSHOT_NAME: &str = "committed";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    View,
+    Active,
+    Committed,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub kind: Kind,
+    pub parent: String,
+    pub dataset: String,
+    pub labels: BTreeMap<String, String>,
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+}
+
+/// A mount to be performed by containerd.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotMount {
+    pub source: PathBuf,
+    pub readonly: bool,
+}
+
+/// Errors containerd expects to be reported with specific
+/// status codes.
+#[derive(Debug)]
+pub enum SnapshotError {
+    NotFound(String),
+    AlreadyExists(String),
+    InvalidKind(String, Kind),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::NotFound(key) => {
+                write!(f, "snapshot {} does not exist", key)
+            }
+            SnapshotError::AlreadyExists(key) => {
+                write!(f, "snapshot {} already exists", key)
+            }
+            SnapshotError::InvalidKind(key, kind) => {
+                write!(f, "snapshot {} is {:?}", key, kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+pub struct Snapshotter<T: StorageEngine> {
+    storage: Storage<T>,
+    parent: Dataset,
+}
+
+impl<T: StorageEngine> Snapshotter<T> {
+    pub fn new(storage: Storage<T>, parent: Dataset) -> Self {
+        Self { storage, parent }
+    }
+
+    /// Creates an active snapshot (or a view) `key` on top of
+    /// committed `parent`.
+    #[fehler::throws]
+    pub fn prepare(
+        &self,
+        key: &str,
+        parent: &str,
+        labels: BTreeMap<String, String>,
+        kind: Kind,
+    ) -> SnapshotMount {
+        let origin = if parent.is_empty() {
+            None
+        } else {
+            let parent_snapshot = self.snapshot(parent)?;
+
+            if parent_snapshot.kind != Kind::Committed {
+                fehler::throw!(SnapshotError::InvalidKind(
+                    parent.into(),
+                    parent_snapshot.kind
+                ));
+            }
+
+            Some(
+                Dataset::new(&parent_snapshot.dataset)
+                    .snapshot_name(COMMITTED_SNAPSHOT_NAME),
+            )
+        };
+
+        let id = Uuid::new_v4().to_string();
+        let dataset = self.parent.child(&id);
+        let now = SystemTime::now();
+        let snapshot = Snapshot {
+            kind,
+            parent: parent.into(),
+            dataset: dataset.name().into(),
+            labels,
+            created_at: now,
+            updated_at: now,
+        };
+
+        // Reserved before the dataset is created, so that
+        // concurrent prepares of the key don't leak datasets
+        self.storage
+            .compare_and_swap(SNAPSHOTS_STORAGE_KEY, key, None, Some(snapshot))
+            .map_err(|_| SnapshotError::AlreadyExists(key.into()))?;
+
+        let result = match origin {
+            None => dataset.create(None),
+            Some(origin) => self.parent.mountpoint().and_then(|mountpoint| {
+                dataset.clone_from(origin, &mountpoint.join(&id))
+            }),
+        }
+        .and_then(|()| self.mounts(key));
+
+        if result.is_err() {
+            // Nothing tracks the dataset, but the reservation
+            if let Err(error) = dataset.destroy_existing() {
+                tracing::error!(
+                    "Destroying {} failed: {}",
+                    dataset.name(),
+                    error
+                );
+            }
+
+            self.storage.remove(SNAPSHOTS_STORAGE_KEY, key)?;
+        }
+
+        result?
+    }
+
+    /// Returns mounts for active snapshot or view `key`.
+    #[fehler::throws]
+    pub fn mounts(&self, key: &str) -> SnapshotMount {
+        let snapshot = self.snapshot(key)?;
+
+        if snapshot.kind == Kind::Committed {
+            fehler::throw!(SnapshotError::InvalidKind(
+                key.into(),
+                snapshot.kind
+            ));
+        }
+
+        SnapshotMount {
+            source: Dataset::new(&snapshot.dataset).mountpoint()?,
+            readonly: snapshot.kind == Kind::View,
+        }
+    }
+
+    /// Commits active snapshot `key` as `name`.
+    #[fehler::throws]
+    pub fn commit(
+        &self,
+        name: &str,
+        key: &str,
+        labels: BTreeMap<String, String>,
+    ) {
+        let snapshot = self.snapshot(key)?;
+
+        if snapshot.kind != Kind::Active {
+            fehler::throw!(SnapshotError::InvalidKind(
+                key.into(),
+                snapshot.kind
+            ));
+        }
+
+        if self.storage.exists(SNAPSHOTS_STORAGE_KEY, name)? {
+            fehler::throw!(SnapshotError::AlreadyExists(name.into()));
+        }
+
+        Dataset::new(&snapshot.dataset).snapshot(COMMITTED_SNAPSHOT_NAME)?;
+
+        let committed = Snapshot {
+            kind: Kind::Committed,
+            labels,
+            updated_at: SystemTime::now(),
+            ..snapshot
+        };
+
+        self.storage.put(SNAPSHOTS_STORAGE_KEY, name, committed)?;
+        self.storage.remove(SNAPSHOTS_STORAGE_KEY, key)?;
+    }
+
+    /// Removes snapshot `key` along with its dataset. Fails if
+    /// the snapshot has children.
+    #[fehler::throws]
+    pub fn remove(&self, key: &str) {
+        let snapshot = self.snapshot(key)?;
+
+        Dataset::new(&snapshot.dataset).destroy()?;
+        self.storage.remove(SNAPSHOTS_STORAGE_KEY, key)?;
+    }
+
+    #[fehler::throws]
+    pub fn snapshot(&self, key: &str) -> Snapshot {
+        self.storage
+            .get(SNAPSHOTS_STORAGE_KEY, key)?
+            .ok_or_else(|| SnapshotError::NotFound(key.into()))?
+    }
+}
@@ -0,0 +1,216 @@
+/// Audit trail of image pulls and container lifecycle
+/// operations, for hosts shared by several users. Records
+/// are appended only: each one is put under a key of its
+/// own, which is never reused, and nothing modifies or
+/// removes them. Records are JSON-encoded, so that they
+/// outlive upgrades.
+///
+/// Auditing is on, unless `KNAST_AUDIT` is `off`.
+use std::{
+    env,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use super::{Storage, StorageEngine};
+
+/// Records, keyed by the time they're made and the pid of
+/// the process making them.
+pub const AUDIT_STORAGE_KEY: &[u8] = b"AUDIT";
+pub const AUDIT_VARIABLE: &str = "KNAST_AUDIT";
+/// Users, who invoked knast via doas(1) or sudo(8), are
+/// named by these, rather than by `USER`.
+const USER_VARIABLES: [&str; 3] = ["DOAS_USER", "SUDO_USER", "USER"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    ImagePull,
+    ContainerCreate,
+    ContainerStart,
+    ContainerKill,
+    ContainerDelete,
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    #[fehler::throws]
+    fn from_str(action: &str) -> Self {
+        serde_json::from_value(serde_json::Value::from(action))
+            .map_err(|_| anyhow!("Unknown action {}", action))?
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub at: SystemTime,
+    /// Real uid of the process.
+    pub uid: u32,
+    pub user: Option<String>,
+    pub pid: u32,
+    pub action: Action,
+    /// Image reference or container key.
+    pub target: String,
+    /// Error the operation failed with, if it did.
+    pub error: Option<String>,
+}
+
+/// Conditions records are queried by, all of them are met.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub action: Option<Action>,
+    pub target: Option<String>,
+    /// User name or uid.
+    pub user: Option<String>,
+    pub failed_only: bool,
+    /// The latest records only.
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        let target_matches = |target: &String| record.target == *target;
+        let user_matches = |user: &String| {
+            record.user.as_ref() == Some(user)
+                || record.uid.to_string() == *user
+        };
+
+        self.since.map_or(true, |since| record.at >= since)
+            && self.until.map_or(true, |until| record.at < until)
+            && self.action.map_or(true, |action| record.action == action)
+            && self.target.as_ref().map_or(true, target_matches)
+            && self.user.as_ref().map_or(true, user_matches)
+            && (!self.failed_only || record.error.is_some())
+    }
+}
+
+/// Whether operations are audited.
+pub fn enabled() -> bool {
+    env::var(AUDIT_VARIABLE).map_or(true, |value| value != "off")
+}
+
+/// Appends the record of the `action` on the `target`,
+/// made by the current process.
+#[fehler::throws]
+pub fn record(
+    storage: &Storage<impl StorageEngine>,
+    action: Action,
+    target: &str,
+    error: Option<String>,
+) {
+    if !enabled() {
+        return;
+    }
+
+    let record = AuditRecord {
+        at: SystemTime::now(),
+        uid: nix::unistd::getuid().as_raw(),
+        user: USER_VARIABLES.iter().find_map(|name| env::var(name).ok()),
+        pid: std::process::id(),
+        action,
+        target: target.into(),
+        error,
+    };
+    let nanos = record.at.duration_since(UNIX_EPOCH)?.as_nanos();
+    // Zero-padded, so that keys are ordered by time
+    let key = format!("{:024}/{:010}", nanos, record.pid);
+
+    // Fails rather than overwrites
+    storage.compare_and_swap(AUDIT_STORAGE_KEY, key, None, Some(record))?;
+}
+
+/// Records matching the `filter`, oldest first.
+#[fehler::throws]
+pub fn query(
+    storage: &Storage<impl StorageEngine>,
+    filter: &AuditFilter,
+) -> Vec<AuditRecord> {
+    let mut keys = storage.keys(AUDIT_STORAGE_KEY)?;
+    let mut records = vec![];
+
+    keys.sort();
+
+    for key in keys {
+        let record: Option<AuditRecord> =
+            storage.get(AUDIT_STORAGE_KEY, &key)?;
+
+        records.extend(record.filter(|record| filter.matches(record)));
+    }
+
+    if let Some(limit) = filter.limit {
+        records.drain(..records.len().saturating_sub(limit));
+    }
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestStorage;
+
+    #[test]
+    fn test_query() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage =
+            TestStorage::new(dir.path()).expect("Unable to initialize cache");
+
+        record(&storage, Action::ImagePull, "docker.io/library/nginx", None)
+            .unwrap();
+        record(&storage, Action::ContainerCreate, "default:nginx", None)
+            .unwrap();
+        record(
+            &storage,
+            Action::ContainerStart,
+            "default:nginx",
+            Some("Cannot start stopped process".into()),
+        )
+        .unwrap();
+
+        let all = query(&storage, &AuditFilter::default()).unwrap();
+        let actions: Vec<_> = all.iter().map(|record| record.action).collect();
+
+        assert_eq!(
+            actions,
+            vec![
+                Action::ImagePull,
+                Action::ContainerCreate,
+                Action::ContainerStart
+            ]
+        );
+
+        let filter = AuditFilter {
+            target: Some("default:nginx".into()),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let latest = query(&storage, &filter).unwrap();
+
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].action, Action::ContainerStart);
+
+        let filter = AuditFilter {
+            failed_only: true,
+            user: Some(all[0].uid.to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(query(&storage, &filter).unwrap(), vec![all[2].clone()]);
+    }
+
+    #[test]
+    fn test_action() {
+        assert_eq!(
+            "container_kill".parse::<Action>().unwrap(),
+            Action::ContainerKill
+        );
+        assert!("container_exec".parse::<Action>().is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use serde::Serialize;
+
+use super::{
+    expiry,
+    schema::{self, Encoding},
+    Storage, StorageEngine,
+};
+
+/// Write of a batch, see [`StorageEngine::apply`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Put {
+        collection: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        collection: Vec<u8>,
+        key: Vec<u8>,
+    },
+}
+
+/// When writes are flushed to disk, see
+/// [`Storage::with_flush_policy`]. Flushes are what makes
+/// writes survive power loss, writes survive crashes of the
+/// process regardless.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushPolicy {
+    /// After every write.
+    OnWrite,
+    /// On writes, at most once per interval.
+    Interval(Duration),
+    /// On [`Storage::flush`] only.
+    Explicit,
+}
+
+impl Operation {
+    pub fn collection(&self) -> &[u8] {
+        match self {
+            Self::Put { collection, .. } | Self::Remove { collection, .. } => {
+                collection
+            }
+        }
+    }
+}
+
+impl Default for FlushPolicy {
+    /// Interval sled flushes at by default.
+    fn default() -> Self {
+        Self::Interval(Duration::from_millis(500))
+    }
+}
+
+/// Puts and removes, which are applied together, or not at
+/// all, see [`Storage::batch`].
+pub struct Batch<'a, T: StorageEngine> {
+    storage: &'a Storage<T>,
+    operations: Vec<Operation>,
+}
+
+impl<'a, T: StorageEngine> Batch<'a, T> {
+    pub(crate) fn new(storage: &'a Storage<T>) -> Self {
+        Self {
+            storage,
+            operations: vec![],
+        }
+    }
+
+    /// Puts the value on commit. Values don't expire, the
+    /// way [`Storage::put`] ones don't.
+    #[fehler::throws]
+    pub fn put<S: Serialize>(
+        &mut self,
+        store: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: &S,
+    ) {
+        let encoding = schema::encoding(store.as_ref());
+
+        self.put_encoded(encoding, store, key, value)?;
+    }
+
+    #[fehler::throws]
+    pub(crate) fn put_encoded<S: Serialize>(
+        &mut self,
+        encoding: Encoding,
+        store: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: &S,
+    ) {
+        let collection = store.as_ref();
+        let key = key.as_ref();
+
+        self.persist(collection, key);
+        self.operations.push(Operation::Put {
+            collection: collection.into(),
+            key: key.into(),
+            value: schema::serialize(encoding, value)?,
+        });
+    }
+
+    /// Removes the value on commit.
+    pub fn remove(&mut self, store: impl AsRef<[u8]>, key: impl AsRef<[u8]>) {
+        let collection = store.as_ref();
+        let key = key.as_ref();
+
+        self.persist(collection, key);
+        self.operations.push(Operation::Remove {
+            collection: collection.into(),
+            key: key.into(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Applies the writes atomically, in the order they're
+    /// made.
+    #[fehler::throws]
+    pub fn commit(self) {
+        if self.is_empty() {
+            return;
+        }
+
+        self.storage.engine().apply(self.operations)?;
+        self.storage.after_write()?;
+    }
+
+    fn persist(&mut self, collection: &[u8], key: &[u8]) {
+        self.operations.push(Operation::Remove {
+            collection: expiry::EXPIRY_STORAGE_KEY.into(),
+            key: expiry::expiry_key(collection, key),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestStorage;
+
+    #[test]
+    fn test_batch() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage = TestStorage::new(dir.path())
+            .expect("Unable to initialize cache")
+            .with_flush_policy(FlushPolicy::OnWrite);
+
+        storage.put(b"test", b"lorem", 1).unwrap();
+
+        let mut batch = storage.batch();
+
+        batch.put(b"test", b"ipsum", &2).unwrap();
+        batch.put(b"other", b"dolor", &3).unwrap();
+        batch.remove(b"test", b"lorem");
+        batch.commit().expect("Failed to commit the batch");
+
+        assert_eq!(storage.get::<i32>(b"test", b"lorem").unwrap(), None);
+        assert_eq!(storage.get(b"test", b"ipsum").unwrap(), Some(2));
+        assert_eq!(storage.get(b"other", b"dolor").unwrap(), Some(3));
+    }
+}
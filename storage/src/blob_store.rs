@@ -0,0 +1,236 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Error};
+
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Content-addressed blob files, `<algorithm>/<hex>` under
+/// the folder. Blobs are written to `<hex>.partial` files
+/// first, which are moved in place once complete.
+///
+/// Nothing is verified here, the digest is the caller's
+/// responsibility.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    folder: PathBuf,
+}
+
+/// Handle of the blob being written. Content written before
+/// is kept, so that writes might be resumed.
+#[derive(Debug)]
+pub struct BlobWriter {
+    file: File,
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+impl BlobStore {
+    #[fehler::throws]
+    pub(crate) fn new(folder: impl AsRef<Path>) -> Self {
+        fs::create_dir_all(&folder)?;
+
+        Self {
+            folder: folder.as_ref().into(),
+        }
+    }
+
+    /// Path of the blob, which might not exist.
+    #[fehler::throws]
+    pub fn path(&self, digest: &str) -> PathBuf {
+        let (algorithm, hex) = parse_digest(digest)?;
+
+        self.folder.join(algorithm).join(hex)
+    }
+
+    #[fehler::throws]
+    pub fn exists(&self, digest: &str) -> bool {
+        self.path(digest)?.is_file()
+    }
+
+    /// Opens the blob for reading, `None` if it isn't stored.
+    #[fehler::throws]
+    pub fn open(&self, digest: &str) -> Option<File> {
+        match File::open(self.path(digest)?) {
+            Ok(file) => Some(file),
+            Err(error) if error.kind() == ErrorKind::NotFound => None,
+            Err(error) => fehler::throw!(error),
+        }
+    }
+
+    /// Opens the partial blob for reading and appending,
+    /// creating it if needed.
+    #[fehler::throws]
+    pub fn writer(&self, digest: &str) -> BlobWriter {
+        let path = self.path(digest)?;
+        let partial = path.with_extension(PARTIAL_EXTENSION);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&partial)?;
+
+        BlobWriter {
+            file,
+            partial,
+            path,
+        }
+    }
+
+    /// Removes the blob, if it's stored.
+    #[fehler::throws]
+    pub fn remove(&self, digest: &str) {
+        remove_file(&self.path(digest)?)?;
+    }
+
+    /// Removes the partial blob, if there's one.
+    #[fehler::throws]
+    pub fn remove_partial(&self, digest: &str) {
+        let path = self.path(digest)?.with_extension(PARTIAL_EXTENSION);
+
+        remove_file(&path)?;
+    }
+
+    /// Digests of complete blobs, in ascending order.
+    #[fehler::throws]
+    pub fn digests(&self) -> Vec<String> {
+        self.list(false)?
+    }
+
+    /// Digests of partial blobs, in ascending order.
+    #[fehler::throws]
+    pub fn partial_digests(&self) -> Vec<String> {
+        self.list(true)?
+    }
+
+    #[fehler::throws]
+    fn list(&self, partial: bool) -> Vec<String> {
+        let mut result = vec![];
+
+        for algorithm in fs::read_dir(&self.folder)? {
+            let algorithm = algorithm?;
+
+            if !algorithm.file_type()?.is_dir() {
+                continue;
+            }
+
+            for blob in fs::read_dir(algorithm.path())? {
+                let path = blob?.path();
+                let is_partial = path
+                    .extension()
+                    .map_or(false, |extension| extension == PARTIAL_EXTENSION);
+
+                if is_partial != partial {
+                    continue;
+                }
+
+                if let Some(hex) = path.file_stem() {
+                    result.push(format!(
+                        "{}:{}",
+                        algorithm.file_name().to_string_lossy(),
+                        hex.to_string_lossy()
+                    ));
+                }
+            }
+        }
+
+        result.sort();
+
+        result
+    }
+}
+
+impl BlobWriter {
+    /// Partial blob file, positioned at its end for writing.
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Moves the complete blob in place.
+    #[fehler::throws]
+    pub fn commit(self) {
+        self.file.sync_all()?;
+
+        fs::rename(&self.partial, &self.path)?;
+    }
+}
+
+/// Splits `algorithm:hex` digest. Parts are validated, since
+/// they make up the blob path.
+#[fehler::throws]
+fn parse_digest(digest: &str) -> (&str, &str) {
+    let mut parts = digest.splitn(2, ':');
+    let algorithm = parts.next().unwrap_or_default();
+    let hex = parts.next().unwrap_or_default();
+    let valid = !algorithm.is_empty()
+        && !hex.is_empty()
+        && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+        && hex.chars().all(|c| c.is_ascii_hexdigit());
+
+    if !valid {
+        fehler::throw!(anyhow!("Invalid digest {}", digest));
+    }
+
+    (algorithm, hex)
+}
+
+#[fehler::throws]
+fn remove_file(path: &Path) {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => {
+            fehler::throw!(error)
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::BlobStore;
+
+    #[test]
+    fn test_write_and_read() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let store = BlobStore::new(dir.path()).unwrap();
+        let digest = "sha256:abc";
+
+        let mut writer = store.writer(digest).unwrap();
+        writer.file().write_all(b"lorem").unwrap();
+        drop(writer);
+
+        assert!(!store.exists(digest).unwrap());
+        assert_eq!(store.partial_digests().unwrap(), vec![digest]);
+
+        // Writes are resumed
+        let mut writer = store.writer(digest).unwrap();
+        writer.file().write_all(b" ipsum").unwrap();
+        writer.commit().unwrap();
+
+        let mut content = String::new();
+        store
+            .open(digest)
+            .unwrap()
+            .expect("Blob is not stored")
+            .read_to_string(&mut content)
+            .unwrap();
+
+        assert_eq!(content, "lorem ipsum");
+        assert_eq!(store.digests().unwrap(), vec![digest]);
+        assert!(store.partial_digests().unwrap().is_empty());
+
+        store.remove(digest).unwrap();
+        assert!(store.open(digest).unwrap().is_none());
+        assert!(store.writer("sha256:../../etc").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{marker::PhantomData, time::Duration};
+
+use anyhow::Error;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{schema::Encoding, Batch, Storage, StorageEngine};
+
+/// Key of a [`Collection`]. Composite keys are encoded as
+/// their parts joined with `/`, i.e. `(container, exec_id)`
+/// is `container/exec_id`.
+pub trait Key {
+    fn encode(&self) -> Vec<u8>;
+}
+
+impl Key for str {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().into()
+    }
+}
+
+impl Key for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().into()
+    }
+}
+
+impl Key for [u8] {
+    fn encode(&self) -> Vec<u8> {
+        self.into()
+    }
+}
+
+impl Key for i32 {
+    fn encode(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl<K: Key + ?Sized> Key for &K {
+    fn encode(&self) -> Vec<u8> {
+        (*self).encode()
+    }
+}
+
+impl<A: Key, B: Key> Key for (A, B) {
+    fn encode(&self) -> Vec<u8> {
+        [&self.0.encode()[..], b"/", &self.1.encode()].concat()
+    }
+}
+
+/// Keys values of `K` keys are looked up by, the way
+/// [`std::borrow::Borrow`] works, i.e. `(&str, &str)` for
+/// `(String, String)`.
+pub trait AsKey<K: ?Sized>: Key {}
+
+impl AsKey<str> for str {}
+impl AsKey<String> for str {}
+impl AsKey<String> for String {}
+impl AsKey<[u8]> for [u8] {}
+impl AsKey<i32> for i32 {}
+
+impl<K: ?Sized, Q: AsKey<K> + ?Sized> AsKey<K> for &Q {}
+
+impl<A, B, QA: AsKey<A>, QB: AsKey<B>> AsKey<(A, B)> for (QA, QB) {}
+
+/// Named collection of `V` values, keyed by `K`, so that
+/// neither the collection nor the types of keys and values
+/// are mixed up, i.e.
+///
+/// ```
+/// use storage::Collection;
+///
+/// const PIDS: Collection<(String, String), i32> =
+///     Collection::new(b"PIDS");
+/// ```
+pub struct Collection<K: ?Sized, V> {
+    name: &'static [u8],
+    encoding: Encoding,
+    types: PhantomData<fn(&K) -> V>,
+}
+
+// Derives would bound `K` and `V`
+impl<K: ?Sized, V> Clone for Collection<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: ?Sized, V> Copy for Collection<K, V> {}
+
+impl<K: ?Sized, V> Collection<K, V> {
+    pub const fn new(name: &'static [u8]) -> Self {
+        Self {
+            name,
+            encoding: Encoding::Bincode,
+            types: PhantomData,
+        }
+    }
+
+    /// Collection, which values are encoded as JSON, so that
+    /// they outlive upgrades, see [`crate::schema`].
+    pub const fn json(name: &'static [u8]) -> Self {
+        Self {
+            name,
+            encoding: Encoding::Json,
+            types: PhantomData,
+        }
+    }
+
+    pub const fn name(&self) -> &'static [u8] {
+        self.name
+    }
+}
+
+impl<K, V> Collection<K, V>
+where
+    K: ?Sized,
+    V: Serialize + DeserializeOwned,
+{
+    #[fehler::throws]
+    pub fn get<Q: AsKey<K> + ?Sized>(
+        &self,
+        storage: &Storage<impl StorageEngine>,
+        key: &Q,
+    ) -> Option<V> {
+        storage.get_encoded(self.encoding, self.name, key.encode())?
+    }
+
+    #[fehler::throws]
+    pub fn put<Q: AsKey<K> + ?Sized>(
+        &self,
+        storage: &Storage<impl StorageEngine>,
+        key: &Q,
+        value: V,
+    ) -> V {
+        storage.put_encoded(self.encoding, self.name, key.encode(), value)?
+    }
+
+    /// See [`Storage::put_with_ttl`].
+    #[fehler::throws]
+    pub fn put_with_ttl<Q: AsKey<K> + ?Sized>(
+        &self,
+        storage: &Storage<impl StorageEngine>,
+        key: &Q,
+        value: V,
+        ttl: Duration,
+    ) -> V {
+        storage.put_with_ttl_encoded(
+            self.encoding,
+            self.name,
+            key.encode(),
+            value,
+            ttl,
+        )?
+    }
+
+    /// See [`Storage::compare_and_swap`].
+    #[fehler::throws]
+    pub fn compare_and_swap<Q: AsKey<K> + ?Sized>(
+        &self,
+        storage: &Storage<impl StorageEngine>,
+        key: &Q,
+        old_value: Option<V>,
+        new_value: Option<V>,
+    ) -> Option<V> {
+        storage.compare_and_swap_encoded(
+            self.encoding,
+            self.name,
+            key.encode(),
+            old_value,
+            new_value,
+        )?
+    }
+
+    #[fehler::throws]
+    pub fn remove<Q: AsKey<K> + ?Sized>(
+        &self,
+        storage: &Storage<impl StorageEngine>,
+        key: &Q,
+    ) {
+        storage.remove(self.name, key.encode())?;
+    }
+
+    /// Keys of the values, as [`Key::encode`] encodes them.
+    #[fehler::throws]
+    pub fn keys(&self, storage: &Storage<impl StorageEngine>) -> Vec<Vec<u8>> {
+        storage.keys(self.name)?
+    }
+
+    #[fehler::throws]
+    pub fn exists<Q: AsKey<K> + ?Sized>(
+        &self,
+        storage: &Storage<impl StorageEngine>,
+        key: &Q,
+    ) -> bool {
+        storage.exists(self.name, key.encode())?
+    }
+
+    /// Puts the value once the batch is committed.
+    #[fehler::throws]
+    pub fn put_batched<Q: AsKey<K> + ?Sized>(
+        &self,
+        batch: &mut Batch<'_, impl StorageEngine>,
+        key: &Q,
+        value: &V,
+    ) {
+        batch.put_encoded(self.encoding, self.name, key.encode(), value)?;
+    }
+
+    /// Removes the value once the batch is committed.
+    pub fn remove_batched<Q: AsKey<K> + ?Sized>(
+        &self,
+        batch: &mut Batch<'_, impl StorageEngine>,
+        key: &Q,
+    ) {
+        batch.remove(self.name, key.encode());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestStorage;
+
+    const PROCESSES: Collection<(String, String), i32> =
+        Collection::new(b"PROCESSES");
+
+    #[test]
+    fn test_collection() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage =
+            TestStorage::new(dir.path()).expect("Unable to initialize cache");
+        let key = ("nginx", "exec");
+
+        PROCESSES.put(&storage, &key, 42).unwrap();
+
+        assert_eq!(PROCESSES.get(&storage, &key).unwrap(), Some(42));
+        assert!(PROCESSES.exists(&storage, &key).unwrap());
+        // Same encoding as keys concatenated by hand
+        assert_eq!(
+            storage.get(b"PROCESSES", b"nginx/exec").unwrap(),
+            Some(42)
+        );
+
+        PROCESSES.remove(&storage, &key).unwrap();
+
+        assert_eq!(PROCESSES.get(&storage, &key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_json_collection() {
+        const STATUSES: Collection<str, Option<i32>> =
+            Collection::json(b"STATUSES");
+
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage =
+            TestStorage::new(dir.path()).expect("Unable to initialize cache");
+
+        STATUSES.put(&storage, "nginx", Some(1)).unwrap();
+
+        let raw = storage.engine().get(b"STATUSES", b"nginx").unwrap();
+
+        assert_eq!(raw, Some(b"1".to_vec()));
+        assert_eq!(STATUSES.get(&storage, "nginx").unwrap(), Some(Some(1)));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{fmt, future::Future, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+#[cfg(feature = "sqlite_engine")]
+use super::sqlite_engine::Connection;
+#[cfg(feature = "postgres_engine")]
+use super::PostgresConnection;
+use super::{Operation, StorageEngine, Watch, STORAGE_FILE};
+
+/// Names the engine, see [`EngineKind`].
+pub const ENGINE_VARIABLE: &str = "KNAST_STORAGE_ENGINE";
+
+/// Engine chosen at runtime, so that one binary serves
+/// deployments of either engine.
+pub enum DynamicEngine {
+    #[cfg(feature = "sled_engine")]
+    Sled(sled::Db),
+    #[cfg(feature = "sqlite_engine")]
+    Sqlite(Connection),
+    #[cfg(feature = "postgres_engine")]
+    Postgres(PostgresConnection),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineKind {
+    Sled,
+    Sqlite,
+    /// Shared database, which `KNAST_STORAGE_URL` names.
+    /// It's never chosen automatically.
+    Postgres,
+    /// Engine of the existing database, the default one
+    /// for new databases.
+    Auto,
+}
+
+/// Calls the engine method of the variant. Methods are
+/// called via the trait, since engines have inherent
+/// methods of the same names.
+macro_rules! dispatch {
+    ($engine:expr, $method:ident($($arg:expr),*)) => {
+        match $engine {
+            #[cfg(feature = "sled_engine")]
+            DynamicEngine::Sled(engine) => {
+                StorageEngine::$method(engine, $($arg),*)
+            }
+            #[cfg(feature = "sqlite_engine")]
+            DynamicEngine::Sqlite(engine) => {
+                StorageEngine::$method(engine, $($arg),*)
+            }
+            #[cfg(feature = "postgres_engine")]
+            DynamicEngine::Postgres(engine) => {
+                StorageEngine::$method(engine, $($arg),*)
+            }
+        }
+    };
+}
+
+impl DynamicEngine {
+    #[fehler::throws]
+    pub fn open(cache_dir: impl AsRef<Path>, kind: EngineKind) -> Self {
+        let cache_dir = cache_dir.as_ref();
+
+        match kind.resolve(cache_dir) {
+            #[cfg(feature = "sled_engine")]
+            EngineKind::Sled => Self::Sled(initialize(cache_dir)?),
+            #[cfg(feature = "sqlite_engine")]
+            EngineKind::Sqlite => Self::Sqlite(initialize(cache_dir)?),
+            #[cfg(feature = "postgres_engine")]
+            EngineKind::Postgres => Self::Postgres(initialize(cache_dir)?),
+            kind => fehler::throw!(anyhow!(
+                "Storage engine {} is not compiled in",
+                kind
+            )),
+        }
+    }
+
+    pub fn kind(&self) -> EngineKind {
+        match self {
+            #[cfg(feature = "sled_engine")]
+            Self::Sled(_) => EngineKind::Sled,
+            #[cfg(feature = "sqlite_engine")]
+            Self::Sqlite(_) => EngineKind::Sqlite,
+            #[cfg(feature = "postgres_engine")]
+            Self::Postgres(_) => EngineKind::Postgres,
+        }
+    }
+}
+
+impl EngineKind {
+    /// Engine named by `KNAST_STORAGE_ENGINE`, `auto` if
+    /// it's not set.
+    #[fehler::throws]
+    pub fn from_env() -> Self {
+        match std::env::var(ENGINE_VARIABLE) {
+            Ok(kind) => kind.parse()?,
+            Err(_) => Self::Auto,
+        }
+    }
+
+    /// Whether the engine is single-process, see
+    /// [`StorageEngine::single_process`].
+    pub(crate) fn single_process(self, cache_dir: &Path) -> bool {
+        self.resolve(cache_dir) == Self::Sled
+    }
+
+    /// Resolves `auto` by the database in `cache_dir`: sled
+    /// keeps a folder, SQLite a file. New databases use
+    /// SQLite, if it's compiled in.
+    fn resolve(self, cache_dir: &Path) -> Self {
+        if self != Self::Auto {
+            return self;
+        }
+
+        let database = cache_dir.join(STORAGE_FILE);
+
+        if database.is_dir() {
+            Self::Sled
+        } else if database.is_file() || cfg!(feature = "sqlite_engine") {
+            Self::Sqlite
+        } else {
+            Self::Sled
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = Error;
+
+    #[fehler::throws]
+    fn from_str(kind: &str) -> Self {
+        match kind {
+            "sled" => Self::Sled,
+            "sqlite" => Self::Sqlite,
+            "postgres" => Self::Postgres,
+            "auto" => Self::Auto,
+            _ => fehler::throw!(anyhow!(
+                "Unknown storage engine {}, \
+                 expected sled, sqlite, postgres or auto",
+                kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Sled => "sled",
+            Self::Sqlite => "sqlite",
+            Self::Postgres => "postgres",
+            Self::Auto => "auto",
+        };
+
+        write!(f, "{}", kind)
+    }
+}
+
+#[fehler::throws]
+fn initialize<T: StorageEngine>(cache_dir: &Path) -> T {
+    *T::initialize(cache_dir)?
+}
+
+impl StorageEngine for DynamicEngine {
+    /// Opens the engine `KNAST_STORAGE_ENGINE` names.
+    #[fehler::throws]
+    fn initialize(cache_dir: impl AsRef<Path>) -> Box<Self> {
+        Box::new(Self::open(cache_dir, EngineKind::from_env()?)?)
+    }
+
+    #[fehler::throws]
+    fn single_process(cache_dir: &Path) -> bool {
+        EngineKind::from_env()?.single_process(cache_dir)
+    }
+
+    fn get(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        dispatch!(self, get(collection, key))
+    }
+
+    fn put(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        dispatch!(self, put(collection, key, value))
+    }
+
+    fn compare_and_swap(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        old_value: Option<impl AsRef<[u8]>>,
+        new_value: Option<impl AsRef<[u8]>>,
+    ) -> Result<(), Error> {
+        dispatch!(
+            self,
+            compare_and_swap(collection, key, old_value, new_value)
+        )
+    }
+
+    fn remove(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        dispatch!(self, remove(collection, key))
+    }
+
+    fn exists(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        dispatch!(self, exists(collection, key))
+    }
+
+    fn keys(
+        &self,
+        collection: impl AsRef<[u8]>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        dispatch!(self, keys(collection))
+    }
+
+    fn flush(&self) -> Box<dyn Future<Output = Result<usize, Error>> + Unpin> {
+        dispatch!(self, flush())
+    }
+
+    fn flush_blocking(&self) -> Result<usize, Error> {
+        dispatch!(self, flush_blocking())
+    }
+
+    fn apply(&self, operations: Vec<Operation>) -> Result<(), Error> {
+        dispatch!(self, apply(operations))
+    }
+
+    fn watch(
+        &self,
+        collection: impl AsRef<[u8]>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<Watch, Error> {
+        dispatch!(self, watch(collection, prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DynamicStorage;
+
+    #[test]
+    fn test_engine_selection() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+
+        assert_eq!("sled".parse::<EngineKind>().unwrap(), EngineKind::Sled);
+        assert!("rocksdb".parse::<EngineKind>().is_err());
+
+        let storage =
+            DynamicStorage::with_engine(dir.path(), EngineKind::Auto)
+                .expect("Unable to initialize cache");
+        let kind = storage.engine().kind();
+
+        storage.put(b"test", b"lorem", 1).unwrap();
+        drop(storage);
+
+        // Existing database is opened with its engine
+        let storage =
+            DynamicStorage::with_engine(dir.path(), EngineKind::Auto)
+                .expect("Unable to initialize cache");
+
+        assert_eq!(storage.engine().kind(), kind);
+        assert_eq!(storage.get(b"test", b"lorem").unwrap(), Some(1));
+    }
+}
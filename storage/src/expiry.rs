@@ -0,0 +1,197 @@
+/// Expiry of keys, see [`StorageEngine::put_with_ttl`].
+///
+/// Deadlines are kept in a collection of their own, keyed by
+/// the collection and the key of the value. Expired values
+/// are removed lazily, once they're read through
+/// [`crate::Storage`], and by [`reap`].
+use std::{
+    convert::TryInto,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Error};
+
+use super::StorageEngine;
+
+/// Deadlines, keyed by [`expiry_key`].
+pub const EXPIRY_STORAGE_KEY: &[u8] = b"EXPIRY";
+
+/// Collection length, followed by the collection and the
+/// key, so that keys of collections don't collide.
+pub(crate) fn expiry_key(collection: &[u8], key: &[u8]) -> Vec<u8> {
+    let length = collection.len() as u32;
+
+    [&length.to_be_bytes()[..], collection, key].concat()
+}
+
+#[fehler::throws]
+fn split_expiry_key(expiry_key: &[u8]) -> (&[u8], &[u8]) {
+    let (length, rest) = expiry_key.split_at(4.min(expiry_key.len()));
+    let length: [u8; 4] = length
+        .try_into()
+        .map_err(|_| anyhow!("Malformed expiry key"))?;
+    let length = u32::from_be_bytes(length) as usize;
+
+    if length > rest.len() {
+        fehler::throw!(anyhow!("Malformed expiry key"));
+    }
+
+    rest.split_at(length)
+}
+
+#[fehler::throws]
+pub(crate) fn put_with_ttl<E: StorageEngine + ?Sized>(
+    engine: &E,
+    collection: &[u8],
+    key: &[u8],
+    value: &[u8],
+    ttl: Duration,
+) {
+    let deadline = SystemTime::now() + ttl;
+
+    // Deadline goes first: a value without one would leak
+    engine.put(
+        EXPIRY_STORAGE_KEY,
+        expiry_key(collection, key),
+        bincode::serialize(&deadline)?,
+    )?;
+    engine.put(collection, key, value)?;
+}
+
+/// Whether the value expired. Expired values are removed.
+#[fehler::throws]
+pub(crate) fn expired<E: StorageEngine + ?Sized>(
+    engine: &E,
+    collection: &[u8],
+    key: &[u8],
+) -> bool {
+    let expiry_key = expiry_key(collection, key);
+    let deadline = match engine.get(EXPIRY_STORAGE_KEY, &expiry_key)? {
+        Some(deadline) => deadline,
+        None => return false,
+    };
+
+    if bincode::deserialize::<SystemTime>(&deadline)? > SystemTime::now() {
+        return false;
+    }
+
+    remove_expired(engine, collection, key, &expiry_key, &deadline)?;
+
+    true
+}
+
+/// Removes expired values of all collections, returns the
+/// number of values removed.
+#[fehler::throws]
+pub(crate) fn reap<E: StorageEngine + ?Sized>(engine: &E) -> usize {
+    let now = SystemTime::now();
+    let mut reaped = 0;
+
+    for expiry_key in engine.keys(EXPIRY_STORAGE_KEY)? {
+        // Removed concurrently
+        let deadline = match engine.get(EXPIRY_STORAGE_KEY, &expiry_key)? {
+            Some(deadline) => deadline,
+            None => continue,
+        };
+
+        if bincode::deserialize::<SystemTime>(&deadline)? > now {
+            continue;
+        }
+
+        let (collection, key) = split_expiry_key(&expiry_key)?;
+
+        if remove_expired(engine, collection, key, &expiry_key, &deadline)? {
+            reaped += 1;
+        }
+    }
+
+    reaped
+}
+
+/// Removes the deadline, unless it's renewed concurrently,
+/// and then the value.
+#[fehler::throws]
+fn remove_expired<E: StorageEngine + ?Sized>(
+    engine: &E,
+    collection: &[u8],
+    key: &[u8],
+    expiry_key: &[u8],
+    deadline: &[u8],
+) -> bool {
+    let removed = engine.compare_and_swap(
+        EXPIRY_STORAGE_KEY,
+        expiry_key,
+        Some(deadline),
+        None::<&[u8]>,
+    );
+
+    // Renewed concurrently
+    if removed.is_err() {
+        return false;
+    }
+
+    engine.remove(collection, key)?;
+
+    true
+}
+
+/// Drops the deadline, so that the value no longer expires.
+#[fehler::throws]
+pub(crate) fn persist<E: StorageEngine + ?Sized>(
+    engine: &E,
+    collection: &[u8],
+    key: &[u8],
+) {
+    engine.remove(EXPIRY_STORAGE_KEY, expiry_key(collection, key))?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestStorage;
+
+    #[test]
+    fn test_expiry_key() {
+        let expiry_key = expiry_key(b"tokens", b"docker.io");
+
+        assert_eq!(
+            split_expiry_key(&expiry_key).unwrap(),
+            (&b"tokens"[..], &b"docker.io"[..])
+        );
+        assert!(split_expiry_key(b"\0\0").is_err());
+        assert!(split_expiry_key(b"\0\0\0\x09short").is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage =
+            TestStorage::new(dir.path()).expect("Unable to initialize cache");
+        let tree = b"test";
+
+        storage
+            .put_with_ttl(tree, b"expired", 1, Duration::from_secs(0))
+            .unwrap();
+        storage
+            .put_with_ttl(tree, b"fresh", 1, Duration::from_secs(3600))
+            .unwrap();
+        storage
+            .put_with_ttl(tree, b"persisted", 1, Duration::from_secs(0))
+            .unwrap();
+        storage.put(tree, b"persisted", 2).unwrap();
+        storage
+            .put_with_ttl(tree, b"reaped", 1, Duration::from_secs(0))
+            .unwrap();
+
+        assert_eq!(storage.get::<i32>(tree, b"expired").unwrap(), None);
+        assert!(!storage.exists(tree, b"expired").unwrap());
+        assert_eq!(storage.get(tree, b"fresh").unwrap(), Some(1));
+        assert_eq!(storage.get(tree, b"persisted").unwrap(), Some(2));
+        assert_eq!(storage.reap_expired().unwrap(), 1);
+        assert_eq!(
+            storage.keys(tree).unwrap(),
+            vec![&b"fresh"[..], b"persisted"]
+        );
+    }
+}
@@ -1,19 +1,63 @@
+pub mod audit;
+mod batch;
+mod blob_store;
+mod collection;
+mod dynamic_engine;
+pub mod expiry;
+mod lock;
+#[cfg(any(feature = "sqlite_engine", feature = "postgres_engine"))]
+mod poll;
+#[cfg(feature = "postgres_engine")]
+mod postgres_engine;
+pub mod schema;
 #[cfg(feature = "sled_engine")]
 mod sled_engine;
 #[cfg(feature = "sqlite_engine")]
 mod sqlite_engine;
+mod watch;
 
 use std::{
     future::Future,
     path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use anyhow::Error;
+use futures::stream::{Stream, StreamExt};
 use serde::{de::DeserializeOwned, Serialize};
 
+pub use batch::{Batch, FlushPolicy, Operation};
+pub use blob_store::{BlobStore, BlobWriter};
+pub use collection::{AsKey, Collection, Key};
+pub use dynamic_engine::{DynamicEngine, EngineKind, ENGINE_VARIABLE};
+#[cfg(feature = "postgres_engine")]
+pub use postgres_engine::{PostgresConnection, URL_VARIABLE};
+pub use watch::{Event, Watch};
+
+use lock::{StorageLock, LOCK_TIMEOUT};
+use schema::{Encoding, Migration};
+
+/// Folder of the storage, unless it's given explicitly, see
+/// [`root`].
+pub const ROOT_VARIABLE: &str = "KNAST_ROOT";
+const DEFAULT_ROOT: &str = "/var/db/knast";
+/// Blob files are stored in this subfolder of the cache.
+const BLOBS_FOLDER: &str = "blobs";
+/// Database of the engine in the cache, either a file or a
+/// folder.
+const STORAGE_FILE: &str = "storage.db";
+
 pub trait StorageEngine {
     fn initialize(cache_dir: impl AsRef<Path>) -> Result<Box<Self>, Error>;
 
+    /// Whether the database in `cache_dir` can be opened by
+    /// a single process at a time. Storage of such engines
+    /// waits for other processes to close it.
+    fn single_process(_cache_dir: &Path) -> Result<bool, Error> {
+        Ok(false)
+    }
+
     fn get(
         &self,
         collection: impl AsRef<[u8]>,
@@ -47,37 +91,153 @@ pub trait StorageEngine {
         key: impl AsRef<[u8]>,
     ) -> Result<bool, Error>;
 
+    /// Keys of the collection, in ascending order.
+    fn keys(
+        &self,
+        collection: impl AsRef<[u8]>,
+    ) -> Result<Vec<Vec<u8>>, Error>;
+
     fn flush(&self) -> Box<dyn Future<Output = Result<usize, Error>> + Unpin>;
+
+    /// Flushes without an executor, see [`FlushPolicy`].
+    fn flush_blocking(&self) -> Result<usize, Error>;
+
+    /// Applies the operations in a single transaction.
+    fn apply(&self, operations: Vec<Operation>) -> Result<(), Error>;
+
+    /// Changes of keys of the collection, which start with
+    /// `prefix`, made after the call.
+    fn watch(
+        &self,
+        collection: impl AsRef<[u8]>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<Watch, Error>;
+
+    /// Puts the value, which expires after `ttl`, see
+    /// [`expiry`].
+    fn put_with_ttl(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        expiry::put_with_ttl(
+            self,
+            collection.as_ref(),
+            key.as_ref(),
+            value.as_ref(),
+            ttl,
+        )
+    }
+
+    /// Removes expired values, returns the number of values
+    /// removed.
+    fn reap_expired(&self) -> Result<usize, Error> {
+        expiry::reap(self)
+    }
 }
 
-#[cfg(feature = "sled_engine")]
+#[cfg(all(feature = "sled_engine", not(feature = "sqlite_engine")))]
 pub type TestStorage = Storage<sled::Db>;
 #[cfg(feature = "sqlite_engine")]
 pub type TestStorage = Storage<sqlite_engine::Connection>;
+/// Storage of the engine chosen at runtime, see
+/// [`EngineKind`].
+pub type DynamicStorage = Storage<DynamicEngine>;
 
 pub struct Storage<T: StorageEngine> {
     inner: Box<T>,
+    blobs: BlobStore,
     cache_dir: PathBuf,
+    flush_policy: FlushPolicy,
+    last_flush: Mutex<Instant>,
+    // Released once the engine is closed
+    _lock: Option<StorageLock>,
 }
 
 impl<T: StorageEngine> Storage<T> {
     #[fehler::throws]
     pub fn new(cache_dir: impl AsRef<Path>) -> Self {
+        let cache_dir = cache_dir.as_ref();
+
+        std::fs::create_dir_all(cache_dir)?;
+
+        let lock = lock(cache_dir, T::single_process(cache_dir)?)?;
+
+        Self::with_inner(cache_dir, T::initialize(cache_dir)?, lock)?
+    }
+
+    /// Storage of the engine. Values, which expired while
+    /// the storage was closed, are removed.
+    #[fehler::throws]
+    fn with_inner(
+        cache_dir: &Path,
+        inner: Box<T>,
+        lock: Option<StorageLock>,
+    ) -> Self {
+        inner.reap_expired()?;
+
         Self {
-            cache_dir: cache_dir.as_ref().into(),
-            inner: T::initialize(cache_dir)?,
+            cache_dir: cache_dir.into(),
+            blobs: BlobStore::new(cache_dir.join(BLOBS_FOLDER))?,
+            inner,
+            flush_policy: FlushPolicy::default(),
+            last_flush: Mutex::new(Instant::now()),
+            _lock: lock,
         }
     }
 
+    /// Overrides when writes are flushed, see
+    /// [`FlushPolicy`].
+    pub fn with_flush_policy(self, flush_policy: FlushPolicy) -> Self {
+        Self {
+            flush_policy,
+            ..self
+        }
+    }
+
+    /// Migrates collections to the current schema, see
+    /// [`schema`]. Crates owning the collections apply their
+    /// migrations once the storage is opened.
+    #[fehler::throws]
+    pub fn migrate(&self, migrations: &[Migration]) {
+        schema::migrate(&*self.inner, migrations)?;
+    }
+
+    /// Groups puts and removes, so that they're applied
+    /// atomically, and flushed once.
+    pub fn batch(&self) -> Batch<'_, T> {
+        Batch::new(self)
+    }
+
     #[fehler::throws]
     pub fn get<D: DeserializeOwned>(
         &self,
         store: impl AsRef<[u8]>,
         key: impl AsRef<[u8]>,
     ) -> Option<D> {
+        let encoding = schema::encoding(store.as_ref());
+
+        self.get_encoded(encoding, store, key)?
+    }
+
+    #[fehler::throws]
+    pub(crate) fn get_encoded<D: DeserializeOwned>(
+        &self,
+        encoding: Encoding,
+        store: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+    ) -> Option<D> {
+        let collection = store.as_ref();
+
+        if expiry::expired(&*self.inner, collection, key.as_ref())? {
+            return None;
+        }
+
         self.inner
-            .get(store, key)?
-            .map(|value| bincode::deserialize(&value))
+            .get(collection, key)?
+            .map(|value| schema::deserialize(encoding, &value))
             .transpose()?
     }
 
@@ -88,13 +248,70 @@ impl<T: StorageEngine> Storage<T> {
         key: impl AsRef<[u8]>,
         value: S,
     ) -> S {
-        let serialized_value = bincode::serialize(&value)?;
+        let encoding = schema::encoding(store.as_ref());
 
+        self.put_encoded(encoding, store, key, value)?
+    }
+
+    #[fehler::throws]
+    pub(crate) fn put_encoded<S: Serialize>(
+        &self,
+        encoding: Encoding,
+        store: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: S,
+    ) -> S {
+        let serialized_value = schema::serialize(encoding, &value)?;
+
+        expiry::persist(&*self.inner, store.as_ref(), key.as_ref())?;
         self.inner.put(store, key, serialized_value)?;
+        self.after_write()?;
 
         value
     }
 
+    /// Puts the value, which expires after `ttl`. Expired
+    /// values are neither got nor exist, though their keys
+    /// are listed until [`Self::reap_expired`] removes them.
+    /// Values put afterwards without TTL don't expire.
+    #[fehler::throws]
+    pub fn put_with_ttl<S: Serialize>(
+        &self,
+        store: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: S,
+        ttl: Duration,
+    ) -> S {
+        let encoding = schema::encoding(store.as_ref());
+
+        self.put_with_ttl_encoded(encoding, store, key, value, ttl)?
+    }
+
+    #[fehler::throws]
+    pub(crate) fn put_with_ttl_encoded<S: Serialize>(
+        &self,
+        encoding: Encoding,
+        store: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: S,
+        ttl: Duration,
+    ) -> S {
+        let serialized_value = schema::serialize(encoding, &value)?;
+
+        self.inner.put_with_ttl(store, key, serialized_value, ttl)?;
+        self.after_write()?;
+
+        value
+    }
+
+    /// Removes expired values of all collections, returns
+    /// the number of values removed. Storage reaps on open,
+    /// long-running processes should reap periodically.
+    #[fehler::throws]
+    pub fn reap_expired(&self) -> usize {
+        self.inner.reap_expired()?
+    }
+
     #[fehler::throws]
     pub fn compare_and_swap<S: Serialize>(
         &self,
@@ -102,14 +319,30 @@ impl<T: StorageEngine> Storage<T> {
         key: impl AsRef<[u8]>,
         old_value: Option<S>,
         new_value: Option<S>,
+    ) -> Option<S> {
+        let encoding = schema::encoding(store.as_ref());
+
+        self.compare_and_swap_encoded(
+            encoding, store, key, old_value, new_value,
+        )?
+    }
+
+    #[fehler::throws]
+    pub(crate) fn compare_and_swap_encoded<S: Serialize>(
+        &self,
+        encoding: Encoding,
+        store: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        old_value: Option<S>,
+        new_value: Option<S>,
     ) -> Option<S> {
         let serialized_old_value = if let Some(old_value) = &old_value {
-            Some(bincode::serialize(&old_value)?)
+            Some(schema::serialize(encoding, old_value)?)
         } else {
             None
         };
         let serialized_new_value = if let Some(new_value) = &new_value {
-            Some(bincode::serialize(&new_value)?)
+            Some(schema::serialize(encoding, new_value)?)
         } else {
             None
         };
@@ -120,13 +353,16 @@ impl<T: StorageEngine> Storage<T> {
             serialized_old_value,
             serialized_new_value,
         )?;
+        self.after_write()?;
 
         new_value
     }
 
     #[fehler::throws]
     pub fn remove(&self, store: impl AsRef<[u8]>, key: impl AsRef<[u8]>) {
+        expiry::persist(&*self.inner, store.as_ref(), key.as_ref())?;
         self.inner.remove(store, key)?;
+        self.after_write()?;
     }
 
     #[fehler::throws]
@@ -135,16 +371,109 @@ impl<T: StorageEngine> Storage<T> {
         store: impl AsRef<[u8]>,
         key: impl AsRef<[u8]>,
     ) -> bool {
+        if expiry::expired(&*self.inner, store.as_ref(), key.as_ref())? {
+            return false;
+        }
+
         self.inner.exists(store, key)?
     }
 
+    #[fehler::throws]
+    pub fn keys(&self, store: impl AsRef<[u8]>) -> Vec<Vec<u8>> {
+        self.inner.keys(store)?
+    }
+
     pub async fn flush(&self) -> Result<usize, Error> {
-        Ok(self.inner.flush().await?)
+        let flushed = self.inner.flush().await?;
+
+        *self.last_flush.lock().expect("Poisoned flush lock") = Instant::now();
+
+        Ok(flushed)
+    }
+
+    /// Flushes, if the flush policy asks to.
+    #[fehler::throws]
+    pub(crate) fn after_write(&self) {
+        let interval = match self.flush_policy {
+            FlushPolicy::OnWrite => Duration::from_secs(0),
+            FlushPolicy::Interval(interval) => interval,
+            FlushPolicy::Explicit => return,
+        };
+        let mut last_flush =
+            self.last_flush.lock().expect("Poisoned flush lock");
+
+        if last_flush.elapsed() >= interval {
+            self.inner.flush_blocking()?;
+            *last_flush = Instant::now();
+        }
+    }
+
+    /// Changes of keys of the store, which start with
+    /// `prefix`, so that they're reacted to without polling.
+    /// Expiry of values isn't reported until they're
+    /// removed.
+    #[fehler::throws]
+    pub fn watch<D: DeserializeOwned>(
+        &self,
+        store: impl AsRef<[u8]>,
+        prefix: impl AsRef<[u8]>,
+    ) -> impl Stream<Item = Result<Event<D>, Error>> {
+        let encoding = schema::encoding(store.as_ref());
+
+        self.inner.watch(store, prefix)?.map(move |event| {
+            event?.try_map(|value| schema::deserialize(encoding, &value))
+        })
     }
 
     pub fn folder(&self) -> PathBuf {
         self.cache_dir.clone()
     }
+
+    /// Large blobs, which are kept out of the engine.
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    pub fn engine(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl Storage<DynamicEngine> {
+    /// Storage of the given engine, rather than the one
+    /// `KNAST_STORAGE_ENGINE` names.
+    #[fehler::throws]
+    pub fn with_engine(cache_dir: impl AsRef<Path>, kind: EngineKind) -> Self {
+        let cache_dir = cache_dir.as_ref();
+
+        std::fs::create_dir_all(cache_dir)?;
+
+        let lock = lock(cache_dir, kind.single_process(cache_dir))?;
+        let inner = DynamicEngine::open(cache_dir, kind)?;
+
+        Self::with_inner(cache_dir, Box::new(inner), lock)?
+    }
+}
+
+/// Folder of the storage, shared by the tools, so that they
+/// see the same containers and images: `root`, if given,
+/// i.e. by a flag, otherwise [`ROOT_VARIABLE`] or
+/// `/var/db/knast`.
+pub fn root(root: Option<&str>) -> PathBuf {
+    match root {
+        Some(root) => root.into(),
+        None => std::env::var_os(ROOT_VARIABLE)
+            .map_or_else(|| DEFAULT_ROOT.into(), PathBuf::from),
+    }
+}
+
+#[fehler::throws]
+fn lock(cache_dir: &Path, single_process: bool) -> Option<StorageLock> {
+    if !single_process {
+        return None;
+    }
+
+    Some(StorageLock::acquire(cache_dir, LOCK_TIMEOUT)?)
 }
 
 impl<T: StorageEngine> std::fmt::Debug for Storage<T> {
@@ -159,7 +488,7 @@ impl<T: StorageEngine> std::fmt::Debug for Storage<T> {
 mod test {
     use super::Storage;
 
-    #[cfg(feature = "sled_engine")]
+    #[cfg(all(feature = "sled_engine", not(feature = "sqlite_engine")))]
     type Engine = sled::Db;
     #[cfg(feature = "sqlite_engine")]
     type Engine = super::sqlite_engine::Connection;
@@ -251,4 +580,27 @@ mod test {
         let stored_value: Option<Vec<u8>> = cache.get(tree, key).unwrap();
         assert_eq!(stored_value, None);
     }
+
+    #[test]
+    fn test_keys() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+
+        let cache = Storage::<Engine>::new(dir.path())
+            .expect("Unable to initialize cache");
+
+        let tree = b"test";
+
+        for key in &[&b"lorem"[..], b"dolor", b"ipsum"] {
+            cache
+                .put(tree, key, 1)
+                .expect("Failed to put a value into the cache");
+        }
+        cache
+            .put(b"other", b"sit", 1)
+            .expect("Failed to put a value into the cache");
+
+        let keys = cache.keys(tree).expect("Failed to list keys");
+        assert_eq!(keys, vec![&b"dolor"[..], b"ipsum", b"lorem"]);
+    }
 }
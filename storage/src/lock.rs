@@ -0,0 +1,103 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+
+/// Advisory lock of single-process engines, along with the
+/// pid of its holder.
+const LOCK_FILE: &str = "storage.lock";
+/// Storage, which is in use longer, is reported as such.
+pub(crate) const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Exclusive lock of the storage, released on drop.
+pub(crate) struct StorageLock {
+    _file: File,
+}
+
+impl StorageLock {
+    /// Waits for the storage to be released, backing off
+    /// between attempts.
+    #[fehler::throws]
+    pub(crate) fn acquire(cache_dir: &Path, timeout: Duration) -> Self {
+        let path = cache_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let started = Instant::now();
+        let mut backoff = Duration::from_millis(10);
+
+        loop {
+            match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+                Ok(()) => break,
+                Err(nix::Error::Sys(Errno::EWOULDBLOCK)) => (),
+                Err(error) => fehler::throw!(error),
+            }
+
+            if started.elapsed() >= timeout {
+                fehler::throw!(anyhow!(
+                    "Storage {} is in use by pid {}",
+                    cache_dir.display(),
+                    holder(&mut file)
+                ));
+            }
+
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+
+        Self { _file: file }
+    }
+}
+
+/// Pid the lock file names, it's written once the lock is
+/// acquired.
+fn holder(file: &mut File) -> String {
+    let mut pid = String::new();
+
+    match file.read_to_string(&mut pid) {
+        Ok(_) if !pid.trim().is_empty() => pid.trim().into(),
+        _ => "unknown".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let timeout = Duration::from_millis(50);
+        let lock = StorageLock::acquire(dir.path(), timeout)
+            .expect("Failed to lock the storage");
+        let error = StorageLock::acquire(dir.path(), timeout)
+            .err()
+            .expect("Storage was locked twice");
+
+        assert!(error
+            .to_string()
+            .contains(&format!("in use by pid {}", std::process::id())));
+
+        drop(lock);
+
+        StorageLock::acquire(dir.path(), timeout)
+            .expect("Lock wasn't released");
+    }
+}
@@ -0,0 +1,65 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Error;
+use futures::channel::mpsc;
+
+use super::{Event, Watch};
+
+/// Watched collections are polled at this interval.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Values of a watched collection, keyed by key.
+pub(crate) type Values = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Watch of engines, which don't notify of changes: values
+/// `scan` returns are compared at every poll. Changes made
+/// between polls are coalesced.
+#[fehler::throws]
+pub(crate) fn watch<F>(mut scan: F) -> Watch
+where
+    F: FnMut() -> Result<Values, Error> + Send + 'static,
+{
+    let mut values = scan()?;
+    let (sender, receiver) = mpsc::unbounded();
+
+    std::thread::spawn(move || {
+        while !sender.is_closed() {
+            std::thread::sleep(POLL_INTERVAL);
+
+            let current = match scan() {
+                Ok(current) => current,
+                Err(error) => {
+                    let _ = sender.unbounded_send(Err(error));
+
+                    break;
+                }
+            };
+
+            for event in changes(&values, &current) {
+                let _ = sender.unbounded_send(Ok(event));
+            }
+
+            values = current;
+        }
+    });
+
+    Box::new(receiver)
+}
+
+/// Events, which turn `previous` values into `current`
+/// ones.
+fn changes(previous: &Values, current: &Values) -> Vec<Event> {
+    let removed = previous
+        .keys()
+        .filter(|key| !current.contains_key(*key))
+        .map(|key| Event::Remove { key: key.clone() });
+    let put = current
+        .iter()
+        .filter(|(key, value)| previous.get(*key) != Some(value))
+        .map(|(key, value)| Event::Put {
+            key: key.clone(),
+            value: value.clone(),
+        });
+
+    removed.chain(put).collect()
+}
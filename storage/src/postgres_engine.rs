@@ -0,0 +1,234 @@
+use std::{future::Future, path::Path};
+
+use anyhow::{anyhow, Context, Error};
+use r2d2::Pool;
+use r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
+
+use super::{
+    poll::{self, Values},
+    Operation, StorageEngine, Watch,
+};
+
+/// Connection string of the database, i.e.
+/// `postgresql://knast@db.example.com/knast`.
+pub const URL_VARIABLE: &str = "KNAST_STORAGE_URL";
+
+/// Database several hosts share. Blobs are kept in the
+/// cache folder of each host regardless.
+pub type PostgresConnection = Pool<PostgresConnectionManager<NoTls>>;
+
+impl StorageEngine for PostgresConnection {
+    /// Connects to the database `KNAST_STORAGE_URL` names.
+    #[fehler::throws]
+    fn initialize(_cache_dir: impl AsRef<Path>) -> Box<Self> {
+        let url = std::env::var(URL_VARIABLE).with_context(|| {
+            format!("{} must be set for the postgres engine", URL_VARIABLE)
+        })?;
+        let manager = PostgresConnectionManager::new(url.parse()?, NoTls);
+        let pool = r2d2::Pool::new(manager)?;
+        let mut client = pool.get()?;
+
+        client.batch_execute(include_str!("postgres_engine/migration.sql"))?;
+
+        Box::new(pool)
+    }
+
+    #[fehler::throws]
+    fn get(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+    ) -> Option<Vec<u8>> {
+        let mut client = self.get()?;
+
+        client
+            .query_opt(
+                include_str!("postgres_engine/get.sql"),
+                &[&collection.as_ref(), &key.as_ref()],
+            )?
+            .map(|row| row.get(0))
+    }
+
+    #[fehler::throws]
+    fn put(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) {
+        let mut client = self.get()?;
+
+        client.execute(
+            include_str!("postgres_engine/put.sql"),
+            &[&collection.as_ref(), &key.as_ref(), &value.as_ref()],
+        )?;
+    }
+
+    #[fehler::throws]
+    fn compare_and_swap(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        old_value: Option<impl AsRef<[u8]>>,
+        new_value: Option<impl AsRef<[u8]>>,
+    ) {
+        let mut client = self.get()?;
+        let collection = collection.as_ref();
+        let key = key.as_ref();
+        let old_value: Option<&[u8]> =
+            old_value.as_ref().map(|value| value.as_ref());
+        let new_value: Option<&[u8]> =
+            new_value.as_ref().map(|value| value.as_ref());
+        let mut tx = client.transaction()?;
+        let current: Option<Vec<u8>> = tx
+            .query_opt(
+                include_str!("postgres_engine/select_for_update.sql"),
+                &[&collection, &key],
+            )?
+            .map(|row| row.get(0));
+
+        if current.as_deref() != old_value {
+            fehler::throw!(anyhow!("Compare and swap conflict"));
+        }
+
+        match (current, new_value) {
+            // Inserted concurrently, rows missing aren't locked
+            (None, Some(new_value)) => {
+                let inserted = tx.execute(
+                    include_str!("postgres_engine/try_insert.sql"),
+                    &[&collection, &key, &new_value],
+                )?;
+
+                if inserted == 0 {
+                    fehler::throw!(anyhow!("Compare and swap conflict"));
+                }
+            }
+            (None, None) => (),
+            (Some(_), Some(new_value)) => {
+                tx.execute(
+                    include_str!("postgres_engine/put.sql"),
+                    &[&collection, &key, &new_value],
+                )?;
+            }
+            (Some(_), None) => {
+                tx.execute(
+                    include_str!("postgres_engine/remove.sql"),
+                    &[&collection, &key],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+    }
+
+    #[fehler::throws]
+    fn remove(&self, collection: impl AsRef<[u8]>, key: impl AsRef<[u8]>) {
+        let mut client = self.get()?;
+
+        client.execute(
+            include_str!("postgres_engine/remove.sql"),
+            &[&collection.as_ref(), &key.as_ref()],
+        )?;
+    }
+
+    #[fehler::throws]
+    fn exists(
+        &self,
+        collection: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+    ) -> bool {
+        let mut client = self.get()?;
+
+        client
+            .query_one(
+                include_str!("postgres_engine/exists.sql"),
+                &[&collection.as_ref(), &key.as_ref()],
+            )?
+            .get(0)
+    }
+
+    #[fehler::throws]
+    fn keys(&self, collection: impl AsRef<[u8]>) -> Vec<Vec<u8>> {
+        let mut client = self.get()?;
+
+        client
+            .query(
+                include_str!("postgres_engine/keys.sql"),
+                &[&collection.as_ref()],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    }
+
+    /// Writes are durable once they're committed.
+    fn flush(&self) -> Box<dyn Future<Output = Result<usize, Error>> + Unpin> {
+        Box::new(std::future::ready(Ok(0)))
+    }
+
+    fn flush_blocking(&self) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    #[fehler::throws]
+    fn apply(&self, operations: Vec<Operation>) {
+        let mut client = self.get()?;
+        let mut tx = client.transaction()?;
+
+        for operation in &operations {
+            match operation {
+                Operation::Put {
+                    collection,
+                    key,
+                    value,
+                } => {
+                    tx.execute(
+                        include_str!("postgres_engine/put.sql"),
+                        &[collection, key, value],
+                    )?;
+                }
+                Operation::Remove { collection, key } => {
+                    tx.execute(
+                        include_str!("postgres_engine/remove.sql"),
+                        &[collection, key],
+                    )?;
+                }
+            }
+        }
+
+        tx.commit()?;
+    }
+
+    /// Postgres notifies only of changes made by triggers,
+    /// which would have to be installed per collection.
+    #[fehler::throws]
+    fn watch(
+        &self,
+        collection: impl AsRef<[u8]>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Watch {
+        let pool = self.clone();
+        let collection = collection.as_ref().to_vec();
+        let prefix = prefix.as_ref().to_vec();
+
+        poll::watch(move || scan(&pool, &collection, &prefix))?
+    }
+}
+
+/// Values of the collection, which keys start with the
+/// prefix.
+#[fehler::throws]
+fn scan(
+    pool: &PostgresConnection,
+    collection: &[u8],
+    prefix: &[u8],
+) -> Values {
+    let mut client = pool.get()?;
+
+    client
+        .query(include_str!("postgres_engine/scan.sql"), &[&collection])?
+        .iter()
+        .map(|row| (row.get::<_, Vec<u8>>(0), row.get(1)))
+        .filter(|(key, _)| key.starts_with(prefix))
+        .collect()
+}
@@ -0,0 +1 @@
+SELECT EXISTS(SELECT 1 FROM storage WHERE tree = $1 AND key = $2);
@@ -0,0 +1 @@
+SELECT key FROM storage WHERE tree = $1 ORDER BY key;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS storage(
+    tree BYTEA NOT NULL,
+    key BYTEA NOT NULL,
+    value BYTEA NOT NULL,
+    PRIMARY KEY (tree, key)
+);
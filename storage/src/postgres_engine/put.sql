@@ -0,0 +1,3 @@
+INSERT INTO storage (tree, key, value) VALUES ($1, $2, $3)
+ON CONFLICT (tree, key) DO UPDATE
+SET value = EXCLUDED.value;
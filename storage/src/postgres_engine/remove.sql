@@ -0,0 +1 @@
+DELETE FROM storage WHERE tree = $1 AND key = $2;
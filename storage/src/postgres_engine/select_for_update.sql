@@ -0,0 +1 @@
+SELECT value FROM storage WHERE tree = $1 AND key = $2 FOR UPDATE;
@@ -0,0 +1 @@
+INSERT INTO storage (tree, key, value) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING;
@@ -0,0 +1,171 @@
+/// Schema versions of collections, and migrations between
+/// them.
+///
+/// Values are bincode-encoded, which isn't self-describing:
+/// once a field is added to a struct, values written before
+/// fail to deserialize. Collections, which outlive upgrades,
+/// i.e. state of running containers, are encoded as JSON
+/// instead, see [`crate::Collection::json`]. Fields added to
+/// their structs should be optional or have defaults.
+///
+/// Each collection has a schema version, `0` unless a
+/// migration has been applied. Crates owning the collections
+/// apply their migrations with [`crate::Storage::migrate`],
+/// in order of their versions.
+use anyhow::{anyhow, Error};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{audit::AUDIT_STORAGE_KEY, StorageEngine};
+
+/// Schema versions, keyed by collection.
+pub const SCHEMA_STORAGE_KEY: &[u8] = b"SCHEMA";
+
+/// Upgrades values of the collection to the `version`.
+/// Values are given along with their keys.
+pub struct Migration {
+    pub collection: &'static [u8],
+    pub version: u32,
+    pub migrate: fn(&[u8], &[u8]) -> Result<Vec<u8>, Error>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Bincode,
+    Json,
+}
+
+/// Encoding of the values of the collection, which is
+/// accessed by its name rather than by a
+/// [`crate::Collection`].
+pub fn encoding(collection: &[u8]) -> Encoding {
+    if collection == AUDIT_STORAGE_KEY {
+        Encoding::Json
+    } else {
+        Encoding::Bincode
+    }
+}
+
+#[fehler::throws]
+pub(crate) fn serialize<S: Serialize>(
+    encoding: Encoding,
+    value: &S,
+) -> Vec<u8> {
+    match encoding {
+        Encoding::Bincode => bincode::serialize(value)?,
+        Encoding::Json => serde_json::to_vec(value)?,
+    }
+}
+
+#[fehler::throws]
+pub(crate) fn deserialize<D: DeserializeOwned>(
+    encoding: Encoding,
+    value: &[u8],
+) -> D {
+    match encoding {
+        Encoding::Bincode => bincode::deserialize(value)?,
+        Encoding::Json => serde_json::from_slice(value)?,
+    }
+}
+
+/// Schema version of the collection.
+#[fehler::throws]
+pub fn version(engine: &impl StorageEngine, collection: &[u8]) -> u32 {
+    engine
+        .get(SCHEMA_STORAGE_KEY, collection)?
+        .map(|version| bincode::deserialize(&version))
+        .transpose()?
+        .unwrap_or_default()
+}
+
+/// Applies migrations of versions above the schema version
+/// of their collections. Values written by another process
+/// concurrently are migrated anew, hence migrations should
+/// keep values of their version intact.
+#[fehler::throws]
+pub(crate) fn migrate(engine: &impl StorageEngine, migrations: &[Migration]) {
+    let mut migrations: Vec<_> = migrations.iter().collect();
+
+    migrations
+        .sort_by_key(|migration| (migration.collection, migration.version));
+
+    for migration in migrations {
+        let collection = migration.collection;
+
+        if migration.version <= version(engine, collection)? {
+            continue;
+        }
+
+        for key in engine.keys(collection)? {
+            // Removed concurrently
+            let value = match engine.get(collection, &key)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let migrated =
+                (migration.migrate)(&key, &value).map_err(|error| {
+                    anyhow!(
+                        "Failed to migrate {} of {} to version {}: {}",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(collection),
+                        migration.version,
+                        error
+                    )
+                })?;
+
+            engine.put(collection, &key, migrated)?;
+        }
+
+        engine.put(
+            SCHEMA_STORAGE_KEY,
+            collection,
+            bincode::serialize(&migration.version)?,
+        )?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestStorage;
+
+    #[fehler::throws]
+    fn double(_: &[u8], value: &[u8]) -> Vec<u8> {
+        let value: u32 = bincode::deserialize(value)?;
+
+        bincode::serialize(&(value * 2))?
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage =
+            TestStorage::new(dir.path()).expect("Unable to initialize cache");
+        let engine = storage.engine();
+        let migrations = [
+            Migration {
+                collection: b"numbers",
+                version: 2,
+                migrate: double,
+            },
+            Migration {
+                collection: b"numbers",
+                version: 1,
+                migrate: double,
+            },
+        ];
+
+        storage.put(b"numbers", b"one", 1u32).unwrap();
+        assert_eq!(version(engine, b"numbers").unwrap(), 0);
+
+        migrate(engine, &migrations[1..]).unwrap();
+        migrate(engine, &migrations).unwrap();
+        // Applied already
+        migrate(engine, &migrations).unwrap();
+
+        let value: u32 = storage.get(b"numbers", b"one").unwrap().unwrap();
+
+        assert_eq!(value, 4);
+        assert_eq!(version(engine, b"numbers").unwrap(), 2);
+    }
+}
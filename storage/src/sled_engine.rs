@@ -1,10 +1,13 @@
 use std::{future::Future, path::Path};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use futures::stream;
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionError},
+    Transactional,
+};
 
-use super::StorageEngine;
-
-const STORAGE_FILE: &str = "storage.db";
+use super::{Operation, StorageEngine, Watch, STORAGE_FILE};
 
 impl StorageEngine for sled::Db {
     #[fehler::throws]
@@ -12,6 +15,10 @@ impl StorageEngine for sled::Db {
         Box::new(sled::open(cache_dir.as_ref().join(STORAGE_FILE))?)
     }
 
+    fn single_process(_cache_dir: &Path) -> Result<bool, Error> {
+        Ok(true)
+    }
+
     #[fehler::throws]
     fn get(
         &self,
@@ -75,7 +82,84 @@ impl StorageEngine for sled::Db {
         tree.contains_key(key)?
     }
 
+    #[fehler::throws]
+    fn keys(&self, collection: impl AsRef<[u8]>) -> Vec<Vec<u8>> {
+        let tree = self.open_tree(collection)?;
+
+        tree.iter()
+            .keys()
+            .map(|key| key.map(|key| key.to_vec()))
+            .collect::<Result<_, _>>()?
+    }
+
     fn flush(&self) -> Box<dyn Future<Output = Result<usize, Error>> + Unpin> {
         self.flush_async()
     }
+
+    #[fehler::throws]
+    fn flush_blocking(&self) -> usize {
+        sled::Tree::flush(self)?
+    }
+
+    #[fehler::throws]
+    fn apply(&self, operations: Vec<Operation>) {
+        let mut collections: Vec<&[u8]> = vec![];
+        // Index of the tree of each operation
+        let mut indices = vec![];
+
+        for operation in &operations {
+            let collection = operation.collection();
+            let position =
+                collections.iter().position(|known| *known == collection);
+
+            indices.push(position.unwrap_or_else(|| {
+                collections.push(collection);
+                collections.len() - 1
+            }));
+        }
+
+        let trees = collections
+            .iter()
+            .map(|collection| self.open_tree(collection))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        trees
+            .as_slice()
+            .transaction(|trees| -> ConflictableTransactionResult<()> {
+                for (operation, index) in operations.iter().zip(&indices) {
+                    let tree = &trees[*index];
+
+                    match operation {
+                        Operation::Put { key, value, .. } => {
+                            tree.insert(key.as_slice(), value.as_slice())?;
+                        }
+                        Operation::Remove { key, .. } => {
+                            tree.remove(key.as_slice())?;
+                        }
+                    }
+                }
+
+                Ok(())
+            })
+            .map_err(|error: TransactionError| {
+                anyhow!("Failed to apply the batch: {:?}", error)
+            })?;
+    }
+
+    #[fehler::throws]
+    fn watch(
+        &self,
+        collection: impl AsRef<[u8]>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Watch {
+        let tree = self.open_tree(collection)?;
+        let mut subscriber = Box::pin(tree.watch_prefix(prefix.as_ref()));
+
+        Box::new(stream::poll_fn(move |context| {
+            subscriber
+                .as_mut()
+                .poll(context)
+                .map(|event| event.map(|event| Ok(event.into())))
+        }))
+    }
 }
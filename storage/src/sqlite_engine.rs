@@ -1,21 +1,40 @@
-use std::{future::Future, path::Path};
+use std::{future::Future, path::Path, time::Duration};
 
 use anyhow::Error;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::named_params;
 
-use super::StorageEngine;
-
-const STORAGE_FILE: &str = "storage.db";
+use super::{
+    poll::{self, Values},
+    Operation, StorageEngine, Watch, STORAGE_FILE,
+};
 
 pub type Connection = Pool<SqliteConnectionManager>;
 
+/// Writes wait for other connections to finish theirs at
+/// most this long.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl StorageEngine for Connection {
     #[fehler::throws]
     fn initialize(cache_dir: impl AsRef<Path>) -> Box<Self> {
         let file = cache_dir.as_ref().join(STORAGE_FILE);
-        let manager = SqliteConnectionManager::file(file);
+        // Writes are synced on checkpoints, see `flush`
+        let manager =
+            SqliteConnectionManager::file(file).with_init(|connection| {
+                // Other processes write concurrently
+                connection.busy_timeout(BUSY_TIMEOUT)?;
+                // Journal mode is queried, it returns a row
+                connection.query_row(
+                    include_str!("sqlite_engine/wal.sql"),
+                    [],
+                    |_| Ok(()),
+                )?;
+                connection.execute_batch(include_str!(
+                    "sqlite_engine/synchronous.sql"
+                ))
+            });
         let pool = r2d2::Pool::new(manager)?;
         let connection = pool.get()?;
         connection.execute(include_str!("sqlite_engine/migration.sql"), [])?;
@@ -154,7 +173,125 @@ impl StorageEngine for Connection {
         results.next().transpose()?.unwrap_or_default()
     }
 
+    #[fehler::throws]
+    fn keys(&self, collection: impl AsRef<[u8]>) -> Vec<Vec<u8>> {
+        let connection = self.get()?;
+        let mut keys_statement = connection
+            .prepare_cached(include_str!("sqlite_engine/keys.sql"))?;
+        let params = named_params! {
+            ":tree": collection.as_ref(),
+        };
+
+        let results = keys_statement.query_map(params, |row| {
+            let result: Vec<u8> = row.get(0)?;
+
+            Ok(result)
+        })?;
+
+        results.collect::<Result<_, _>>()?
+    }
+
     fn flush(&self) -> Box<dyn Future<Output = Result<usize, Error>> + Unpin> {
-        Box::new(std::future::ready(Ok(0)))
+        Box::new(std::future::ready(self.flush_blocking()))
+    }
+
+    /// Checkpoints the write-ahead log, returns the number
+    /// of pages written to the database.
+    #[fehler::throws]
+    fn flush_blocking(&self) -> usize {
+        let connection = self.get()?;
+        let pages: i64 = connection.query_row(
+            include_str!("sqlite_engine/checkpoint.sql"),
+            [],
+            |row| row.get(2),
+        )?;
+
+        pages.max(0) as usize
     }
+
+    #[fehler::throws]
+    fn apply(&self, operations: Vec<Operation>) {
+        let mut connection = self.get()?;
+        let tx = connection.transaction()?;
+
+        {
+            let mut put_statement =
+                tx.prepare_cached(include_str!("sqlite_engine/put.sql"))?;
+            let mut remove_statement =
+                tx.prepare_cached(include_str!("sqlite_engine/remove.sql"))?;
+
+            for operation in &operations {
+                match operation {
+                    Operation::Put {
+                        collection,
+                        key,
+                        value,
+                    } => {
+                        let params = named_params! {
+                            ":key": key,
+                            ":tree": collection,
+                            ":value": value,
+                        };
+
+                        put_statement.execute(params)?;
+                    }
+                    Operation::Remove { collection, key } => {
+                        let params = named_params! {
+                            ":key": key,
+                            ":tree": collection,
+                        };
+
+                        remove_statement.execute(params)?;
+                    }
+                }
+            }
+        }
+
+        tx.commit()?;
+    }
+
+    #[fehler::throws]
+    fn watch(
+        &self,
+        collection: impl AsRef<[u8]>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Watch {
+        let pool = self.clone();
+        let collection = collection.as_ref().to_vec();
+        let prefix = prefix.as_ref().to_vec();
+
+        // SQLite doesn't notify other connections of changes
+        poll::watch(move || scan(&pool, &collection, &prefix))?
+    }
+}
+
+/// Values of the collection, which keys start with the
+/// prefix.
+#[fehler::throws]
+fn scan(pool: &Connection, collection: &[u8], prefix: &[u8]) -> Values {
+    let connection = pool.get()?;
+    let mut scan_statement =
+        connection.prepare_cached(include_str!("sqlite_engine/scan.sql"))?;
+    let params = named_params! {
+        ":tree": collection,
+    };
+
+    let results = scan_statement.query_map(params, |row| {
+        let key: Vec<u8> = row.get(0)?;
+        let value: Option<Vec<u8>> = row.get(1)?;
+
+        Ok((key, value))
+    })?;
+    let mut values = Values::new();
+
+    for result in results {
+        // Values are nulled by compare and swap
+        if let (key, Some(value)) = result? {
+            if key.starts_with(prefix) {
+                values.insert(key, value);
+            }
+        }
+    }
+
+    values
 }
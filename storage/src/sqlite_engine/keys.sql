@@ -0,0 +1 @@
+SELECT key FROM storage WHERE tree = :tree ORDER BY key;
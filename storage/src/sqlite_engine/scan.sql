@@ -0,0 +1 @@
+SELECT key, value FROM storage WHERE tree = :tree ORDER BY key;
@@ -0,0 +1 @@
+PRAGMA synchronous = NORMAL;
@@ -0,0 +1,88 @@
+use anyhow::Error;
+use futures::stream::Stream;
+
+/// Change of a watched key, see [`crate::Storage::watch`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event<V = Vec<u8>> {
+    Put { key: Vec<u8>, value: V },
+    Remove { key: Vec<u8> },
+}
+
+/// Changes of keys, in the order they're made. Watch stops
+/// once it's dropped.
+pub type Watch = Box<dyn Stream<Item = Result<Event, Error>> + Send + Unpin>;
+
+impl<V> Event<V> {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Self::Put { key, .. } | Self::Remove { key } => key,
+        }
+    }
+
+    #[fehler::throws]
+    pub(crate) fn try_map<W>(
+        self,
+        map: impl FnOnce(V) -> Result<W, Error>,
+    ) -> Event<W> {
+        match self {
+            Self::Put { key, value } => Event::Put {
+                key,
+                value: map(value)?,
+            },
+            Self::Remove { key } => Event::Remove { key },
+        }
+    }
+}
+
+#[cfg(feature = "sled_engine")]
+impl From<sled::Event> for Event {
+    fn from(event: sled::Event) -> Self {
+        match event {
+            sled::Event::Insert { key, value } => Self::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            sled::Event::Remove { key } => Self::Remove { key: key.to_vec() },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, stream::StreamExt};
+
+    use super::*;
+    use crate::TestStorage;
+
+    #[test]
+    fn test_watch() {
+        let dir =
+            tempfile::tempdir().expect("failed to create a tmp directory");
+        let storage =
+            TestStorage::new(dir.path()).expect("Unable to initialize cache");
+        let tree = b"test";
+        let mut events = storage
+            .watch::<i32>(tree, b"lo")
+            .expect("Failed to watch the collection");
+
+        storage.put(tree, b"ipsum", 1).unwrap();
+        storage.put(tree, b"lorem", 2).unwrap();
+
+        let event = block_on(events.next()).unwrap().unwrap();
+
+        assert_eq!(
+            event,
+            Event::Put {
+                key: b"lorem".to_vec(),
+                value: 2
+            }
+        );
+
+        storage.remove(tree, b"lorem").unwrap();
+
+        let event = block_on(events.next()).unwrap().unwrap();
+
+        assert_eq!(event.key(), b"lorem");
+        assert!(matches!(event, Event::Remove { .. }));
+    }
+}
@@ -26,6 +26,22 @@ pub struct MockHeader {
     pub value: String,
 }
 
+/// Request headers match if they contain the value, i.e.
+/// `Accept` header listing several media types matches any
+/// of them.
+pub fn contains(value: &str) -> mockito::Matcher {
+    let escaped: String = value
+        .chars()
+        .flat_map(|c| {
+            let escape = "\\.+*?()|[]{}^$".contains(c);
+
+            Some('\\').filter(|_| escape).into_iter().chain(Some(c))
+        })
+        .collect();
+
+    mockito::Matcher::Regex(escaped)
+}
+
 #[derive(Deserialize)]
 pub struct MockResponse {
     pub headers: Option<Vec<MockHeader>>,
@@ -105,7 +121,7 @@ macro_rules! mock_server {
 
                 if let Some(headers) = request.headers {
                     for MockHeader { header, value } in headers {
-                        mock = mock.match_header(&header, &value[..]);
+                        mock = mock.match_header(&header, contains(&value));
                     }
                 }
 